@@ -0,0 +1,171 @@
+//! 兼容OpenAI接口的服务商的模型列表
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::HttpStatusError;
+
+/// 服务商提供的一个模型
+///
+/// # 字段
+/// - `id`: 请求中使用的模型名称
+/// - `owned_by`: 模型所属的组织
+/// - `created`: 模型发布时间（Unix时间戳）
+/// - `extra`: 服务商返回的其他字段，原样保留
+#[derive(
+    Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct ModelDescriptor {
+    pub id: String,
+    #[serde(default)]
+    pub owned_by: Option<String>,
+    #[serde(default)]
+    pub created: Option<i64>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Body of a `/models` response
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelDescriptor>,
+}
+
+/// 获取服务商当前可用的模型列表。
+///
+/// 调用兼容OpenAI接口的 `GET {base_url}/models`。
+///
+/// # 参数
+/// - `api_key`: 用于认证的API密钥。
+/// - `base_url`: 接口地址，如 [`crate::models::zhi_pu::ZHI_PU_API_URL`]。
+///
+/// # 返回
+/// 服务商返回顺序的模型列表。服务商不提供该接口（HTTP 404/405/501）
+/// 时返回说明这一点的 [`HttpStatusError`]；其他HTTP错误同样以
+/// [`HttpStatusError`] 为根因。
+pub async fn list_models(
+    api_key: &str,
+    base_url: &str,
+) -> anyhow::Result<Vec<ModelDescriptor>> {
+    let url = format!(
+        "{}/models",
+        base_url.trim_end_matches('/')
+    );
+    let response = reqwest::Client::new()
+        .get(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", api_key),
+        )
+        .send()
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Model list network error: {}",
+                e
+            )
+        })?;
+    let status = response.status().as_u16();
+    let body = response.text().await.map_err(|e| {
+        anyhow::anyhow!("Model list network error: {}", e)
+    })?;
+    parse_models(&url, status, &body)
+}
+
+/// Interprets the status and body of a `/models` response
+fn parse_models(
+    url: &str,
+    status: u16,
+    body: &str,
+) -> anyhow::Result<Vec<ModelDescriptor>> {
+    if matches!(status, 404 | 405 | 501) {
+        return Err(HttpStatusError {
+            status,
+            message: format!(
+                "The provider does not support listing models ({} returned HTTP {})",
+                url, status
+            ),
+        }
+        .into());
+    }
+    if !(200..300).contains(&status) {
+        let body: String = body.chars().take(200).collect();
+        return Err(HttpStatusError {
+            status,
+            message: format!(
+                "Model list error (HTTP {}): {}",
+                status, body
+            ),
+        }
+        .into());
+    }
+    let list: ModelList = serde_json::from_str(body)
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Unexpected model list from {}: {}",
+                url,
+                e
+            )
+        })?;
+    Ok(list.data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const URL: &str = "https://example.com/v4/models";
+
+    #[test]
+    fn test_parse_sample_response() {
+        let body = r#"{
+            "object": "list",
+            "data": [
+                {"id": "glm-4.7", "object": "model", "created": 1700000000, "owned_by": "z-ai"},
+                {"id": "glm-4.7-flash", "object": "model", "context_length": 128000}
+            ]
+        }"#;
+        let models = parse_models(URL, 200, body).unwrap();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].id, "glm-4.7");
+        assert_eq!(
+            models[0].owned_by.as_deref(),
+            Some("z-ai")
+        );
+        assert_eq!(models[0].created, Some(1700000000));
+        assert_eq!(models[1].owned_by, None);
+        assert_eq!(
+            models[1].extra["context_length"],
+            serde_json::json!(128000)
+        );
+        assert_eq!(models[1].extra["object"], "model");
+    }
+
+    #[test]
+    fn test_unsupported_endpoint_is_explained() {
+        let error = parse_models(URL, 404, "Not Found")
+            .unwrap_err();
+        let http = error
+            .downcast_ref::<HttpStatusError>()
+            .unwrap();
+        assert_eq!(http.status, 404);
+        assert!(
+            error.to_string().contains(
+                "does not support listing models"
+            )
+        );
+    }
+
+    #[test]
+    fn test_other_failures() {
+        let error =
+            parse_models(URL, 401, "{\"error\":\"auth\"}")
+                .unwrap_err();
+        assert!(
+            error
+                .downcast_ref::<HttpStatusError>()
+                .unwrap()
+                .is_auth()
+        );
+        assert!(parse_models(URL, 200, "<html>").is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! 与服务商无关的对话消息类型
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::zhi_pu::{
+    ZhiPuMessage, ZhiPuResponseMessage,
+};
+
+/// 消息发送者的角色
+///
+/// 未知角色保存在 `Other` 中，保证与服务商消息之间的往返转换不丢失信息。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+    Other(String),
+}
+
+impl Role {
+    /// The wire name shared by the OpenAI-style providers
+    pub fn as_str(&self) -> &str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+            Role::Other(role) => role,
+        }
+    }
+}
+
+impl From<&str> for Role {
+    fn from(role: &str) -> Self {
+        match role {
+            "system" => Role::System,
+            "user" => Role::User,
+            "assistant" => Role::Assistant,
+            "tool" => Role::Tool,
+            other => Role::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Role {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let role = String::deserialize(deserializer)?;
+        Ok(Role::from(role.as_str()))
+    }
+}
+
+/// 与服务商无关的对话消息
+///
+/// # 字段
+/// - `role`: 消息发送者的角色
+/// - `content`: 消息的文本内容
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    /// 创建消息
+    pub fn new(
+        role: Role,
+        content: impl Into<String>,
+    ) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+
+    /// 创建系统消息
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    /// 创建用户消息
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    /// 创建助手消息
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+}
+
+impl From<ChatMessage> for ZhiPuMessage {
+    fn from(message: ChatMessage) -> Self {
+        Self {
+            role: message.role.as_str().to_string(),
+            content: message.content,
+        }
+    }
+}
+
+impl From<ZhiPuMessage> for ChatMessage {
+    fn from(message: ZhiPuMessage) -> Self {
+        Self::new(
+            Role::from(message.role.as_str()),
+            message.content,
+        )
+    }
+}
+
+/// 响应消息只保留最终回答；`reasoning_content` 是模型的思考过程，
+/// 不属于对话历史，因此被丢弃。
+impl From<ZhiPuResponseMessage> for ChatMessage {
+    fn from(message: ZhiPuResponseMessage) -> Self {
+        Self::new(
+            Role::from(message.role.as_str()),
+            message.content,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_through_zhi_pu() {
+        let messages = vec![
+            ChatMessage::system("你是Anki助手"),
+            ChatMessage::user("猫"),
+            ChatMessage::assistant("ねこ"),
+            ChatMessage::new(
+                Role::Other("observation".to_string()),
+                "raw",
+            ),
+        ];
+        for message in messages {
+            let zhi_pu: ZhiPuMessage =
+                message.clone().into();
+            assert_eq!(zhi_pu.role, message.role.as_str());
+            assert_eq!(ChatMessage::from(zhi_pu), message);
+        }
+    }
+
+    #[test]
+    fn test_reasoning_content_is_not_part_of_the_answer() {
+        let response = ZhiPuResponseMessage {
+            role: "assistant".to_string(),
+            content: String::new(),
+            reasoning_content: Some("先想一想".to_string()),
+        };
+        let message = ChatMessage::from(response);
+        assert_eq!(message, ChatMessage::assistant(""));
+    }
+
+    #[test]
+    fn test_role_serializes_as_wire_name() {
+        let json =
+            serde_json::to_string(&ChatMessage::user("hi"))
+                .unwrap();
+        assert_eq!(
+            json,
+            r#"{"role":"user","content":"hi"}"#
+        );
+        let back: ChatMessage =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(back.role, Role::User);
+    }
+}
@@ -0,0 +1,676 @@
+//! 批改笔记中用户自己写的句子
+//!
+//! 造句练习时，用户把用目标词写的句子填进笔记的一个字段。
+//! [`correct_productions`] 让模型给出结构化的批改结果，把标出改动的
+//! 句子和说明写回笔记；没有问题的句子写入 [`NO_ISSUES_MARKER`]，而
+//! 不是悄悄跳过。
+
+use std::collections::HashMap;
+
+use anki_connect::anki::client::AnkiClient;
+use anki_connect::convert::{escape_html, strip_html};
+use serde::Deserialize;
+
+use crate::chat::ChatMessage;
+use crate::language::LanguageOption;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::structured::strip_outer_fence;
+
+/// 句子没有问题时写入批改字段的内容
+pub const NO_ISSUES_MARKER: &str = "✓ No issues";
+
+/// [`correct_productions`] 的选项
+///
+/// # 字段
+/// - `input_field`: 用户写的句子所在的字段
+/// - `correction_field`: 写入标出改动的句子
+/// - `explanation_field`: 写入问题列表和说明
+/// - `language`: 句子的语言，[`LanguageOption::Auto`] 时按内容判断
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrectionOptions {
+    pub input_field: String,
+    pub correction_field: String,
+    pub explanation_field: String,
+    pub language: LanguageOption,
+}
+
+impl Default for CorrectionOptions {
+    fn default() -> Self {
+        Self {
+            input_field: "Sentence".to_string(),
+            correction_field: "Correction".to_string(),
+            explanation_field: "Explanation".to_string(),
+            language: LanguageOption::Auto,
+        }
+    }
+}
+
+/// 问题的类别，无法识别的类别归为 [`IssueCategory::Other`]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueCategory {
+    Grammar,
+    Vocabulary,
+    Spelling,
+    WordOrder,
+    Particle,
+    Style,
+    #[serde(other)]
+    Other,
+}
+
+impl IssueCategory {
+    /// 显示用的名称
+    pub fn label(self) -> &'static str {
+        match self {
+            IssueCategory::Grammar => "grammar",
+            IssueCategory::Vocabulary => "vocabulary",
+            IssueCategory::Spelling => "spelling",
+            IssueCategory::WordOrder => "word order",
+            IssueCategory::Particle => "particle",
+            IssueCategory::Style => "style",
+            IssueCategory::Other => "other",
+        }
+    }
+}
+
+/// 批改中指出的一个问题
+///
+/// # 字段
+/// - `category`: 问题类别
+/// - `original`: 原句中有问题的部分
+/// - `correction`: 改正后的写法
+/// - `note`: 简短说明
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CorrectionIssue {
+    pub category: IssueCategory,
+    pub original: String,
+    pub correction: String,
+    #[serde(default)]
+    pub note: String,
+}
+
+/// 模型返回的批改结果
+///
+/// # 字段
+/// - `corrected`: 改正后的完整句子
+/// - `issues`: 问题列表，句子正确时为空
+/// - `explanation`: 整体说明
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Correction {
+    pub corrected: String,
+    #[serde(default)]
+    pub issues: Vec<CorrectionIssue>,
+    #[serde(default)]
+    pub explanation: String,
+}
+
+impl Correction {
+    /// 解析模型输出，包裹整个输出的代码围栏会先被去掉
+    ///
+    /// # 返回
+    /// 不是符合格式的 JSON 或 `corrected` 为空时返回错误。
+    pub fn parse(output: &str) -> anyhow::Result<Self> {
+        let correction: Correction = serde_json::from_str(
+            strip_outer_fence(output),
+        )
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "The correction is not valid JSON: {}",
+                e
+            )
+        })?;
+        anyhow::ensure!(
+            !correction.corrected.trim().is_empty(),
+            "The correction has an empty corrected sentence"
+        );
+        Ok(correction)
+    }
+
+    /// 模型是否认为 `original` 没有问题
+    pub fn is_correct(&self, original: &str) -> bool {
+        let squeeze = |text: &str| {
+            text.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        self.issues.is_empty()
+            && squeeze(&self.corrected) == squeeze(original)
+    }
+
+    /// 说明字段的 HTML：问题列表和整体说明
+    pub fn explanation_html(&self) -> String {
+        let mut html = String::new();
+        if !self.issues.is_empty() {
+            html.push_str("<ul>");
+            for issue in &self.issues {
+                html.push_str(&format!(
+                    "<li><b>{}</b>: {} → {}",
+                    issue.category.label(),
+                    escape_html(&issue.original),
+                    escape_html(&issue.correction)
+                ));
+                if !issue.note.trim().is_empty() {
+                    html.push_str(&format!(
+                        " — {}",
+                        escape_html(issue.note.trim())
+                    ));
+                }
+                html.push_str("</li>");
+            }
+            html.push_str("</ul>");
+        }
+        if !self.explanation.trim().is_empty() {
+            html.push_str(&format!(
+                "<p>{}</p>",
+                escape_html(self.explanation.trim())
+            ));
+        }
+        html
+    }
+}
+
+/// [`correct_productions`] 的结果
+///
+/// # 字段
+/// - `corrected`: 写入了批改的笔记
+/// - `no_issues`: 句子没有问题、写入了 [`NO_ISSUES_MARKER`] 的笔记
+/// - `failed`: 缺少字段、句子为空或批改失败的笔记及原因
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorrectionReport {
+    pub corrected: Vec<u64>,
+    pub no_issues: Vec<u64>,
+    pub failed: Vec<(u64, String)>,
+}
+
+/// 批改 `query` 匹配的笔记中用户写的句子
+///
+/// 每条笔记单独请求一次，某条笔记失败（缺少字段、回复无法解析、
+/// 写入失败等）只记入报告，不影响其他笔记。批改字段写入用
+/// `<del>`/`<ins>` 标出改动的句子（见 [`diff_html`]），说明字段写入
+/// [`Correction::explanation_html`]。
+///
+/// # 参数
+/// - `complete`: 发送完整对话并返回模型回复的函数
+/// - `anki_client`: Anki 客户端
+/// - `query`: Anki 搜索语句
+/// - `options`: 字段和语言
+///
+/// # 返回
+/// 只有查询笔记失败时返回错误。
+pub async fn correct_productions(
+    mut complete: impl AsyncFnMut(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    anki_client: &AnkiClient,
+    query: &str,
+    options: &CorrectionOptions,
+) -> anyhow::Result<CorrectionReport> {
+    let mut report = CorrectionReport::default();
+    let note_ids = anki_client.find_notes(query).await?;
+    let notes =
+        anki_client.notes_info_chunked(&note_ids).await?;
+
+    for note in notes {
+        let result = async {
+            let sentence = note
+                .fields
+                .get(&options.input_field)
+                .map(|field| strip_html(&field.value))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Model {} has no field {}",
+                        note.model_name,
+                        options.input_field
+                    )
+                })?;
+            let sentence = sentence.trim();
+            anyhow::ensure!(
+                !sentence.is_empty(),
+                "{} is empty",
+                options.input_field
+            );
+            for field in [
+                &options.correction_field,
+                &options.explanation_field,
+            ] {
+                anyhow::ensure!(
+                    note.fields.contains_key(field),
+                    "Model {} has no field {}",
+                    note.model_name,
+                    field
+                );
+            }
+
+            let language =
+                options.language.resolve(sentence).name();
+            let reply = complete(vec![
+                ChatMessage::system(correction_prompt(
+                    language,
+                )),
+                ChatMessage::user(sentence),
+            ])
+            .await?;
+            let correction = Correction::parse(&reply)?;
+
+            let correct = correction.is_correct(sentence);
+            let corrected_html = if correct {
+                NO_ISSUES_MARKER.to_string()
+            } else {
+                diff_html(
+                    sentence,
+                    correction.corrected.trim(),
+                )
+            };
+            anki_client
+                .update_note_fields(
+                    note.note_id,
+                    HashMap::from([
+                        (
+                            options
+                                .correction_field
+                                .clone(),
+                            corrected_html,
+                        ),
+                        (
+                            options
+                                .explanation_field
+                                .clone(),
+                            correction.explanation_html(),
+                        ),
+                    ]),
+                    None,
+                )
+                .await?;
+            Ok(correct)
+        }
+        .await;
+
+        match result {
+            Ok(true) => report.no_issues.push(note.note_id),
+            Ok(false) => {
+                report.corrected.push(note.note_id)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Correcting note {} failed: {:#}",
+                    note.note_id,
+                    e
+                );
+                report.failed.push((
+                    note.note_id,
+                    format!("{:#}", e),
+                ));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// 使用智谱客户端执行 [`correct_productions`]
+pub async fn correct_productions_with_zhi_pu(
+    client: &ZhiPuClient,
+    anki_client: &AnkiClient,
+    query: &str,
+    options: &CorrectionOptions,
+) -> anyhow::Result<CorrectionReport> {
+    correct_productions(
+        async |messages| {
+            let request = ZhiPuRequest::new(
+                messages
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            );
+            client.complete(request).await?.into_content()
+        },
+        anki_client,
+        query,
+        options,
+    )
+    .await
+}
+
+/// 用 `<del>`/`<ins>` 标出从 `original` 到 `corrected` 的改动
+///
+/// 拉丁字母、谚文等按词比较，汉字和假名逐字比较；文本会转义后再
+/// 加标签。
+pub fn diff_html(
+    original: &str,
+    corrected: &str,
+) -> String {
+    let before = diff_tokens(original);
+    let after = diff_tokens(corrected);
+
+    // lcs[i][j]: longest common subsequence of before[i..], after[j..]
+    let mut lcs = vec![
+        vec![0usize; after.len() + 1];
+        before.len() + 1
+    ];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            lcs[i][j] = if before[i] == after[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut html = String::new();
+    let mut deleted = String::new();
+    let mut inserted = String::new();
+    let flush = |html: &mut String,
+                 deleted: &mut String,
+                 inserted: &mut String| {
+        if !deleted.is_empty() {
+            html.push_str(&format!(
+                "<del>{}</del>",
+                escape_html(deleted)
+            ));
+            deleted.clear();
+        }
+        if !inserted.is_empty() {
+            html.push_str(&format!(
+                "<ins>{}</ins>",
+                escape_html(inserted)
+            ));
+            inserted.clear();
+        }
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() || j < after.len() {
+        if i < before.len()
+            && j < after.len()
+            && before[i] == after[j]
+        {
+            // Keep a lone space inside a change so "a b" → "c d"
+            // reads as one replacement
+            let inside_change = (!deleted.is_empty()
+                || !inserted.is_empty())
+                && before[i].trim().is_empty()
+                && i + 1 < before.len()
+                && j + 1 < after.len()
+                && before[i + 1] != after[j + 1];
+            if inside_change {
+                deleted.push_str(before[i]);
+                inserted.push_str(after[j]);
+            } else {
+                flush(
+                    &mut html,
+                    &mut deleted,
+                    &mut inserted,
+                );
+                html.push_str(&escape_html(before[i]));
+            }
+            i += 1;
+            j += 1;
+        } else if j == after.len()
+            || (i < before.len()
+                && lcs[i + 1][j] >= lcs[i][j + 1])
+        {
+            deleted.push_str(before[i]);
+            i += 1;
+        } else {
+            inserted.push_str(after[j]);
+            j += 1;
+        }
+    }
+    flush(&mut html, &mut deleted, &mut inserted);
+    html
+}
+
+/// Words, whitespace runs and single other characters (each
+/// ideograph, kana and punctuation mark on its own)
+fn diff_tokens(text: &str) -> Vec<&str> {
+    #[derive(PartialEq)]
+    enum Kind {
+        Word,
+        Space,
+        Single,
+    }
+    let kind = |c: char| {
+        if c.is_whitespace() {
+            Kind::Space
+        } else if c.is_alphanumeric() && !is_ideographic(c)
+        {
+            Kind::Word
+        } else {
+            Kind::Single
+        }
+    };
+
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut current: Option<Kind> = None;
+    for (at, c) in text.char_indices() {
+        let next = kind(c);
+        if current.as_ref() != Some(&next)
+            || next == Kind::Single
+        {
+            if at > start {
+                tokens.push(&text[start..at]);
+            }
+            start = at;
+        }
+        current = Some(next);
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
+
+/// Han and kana, which are compared one character at a time
+fn is_ideographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3040}'..='\u{30FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+    )
+}
+
+fn correction_prompt(language: &str) -> String {
+    format!(
+        "You correct sentences written by a learner of {}. The user sends one sentence.\n\
+         Reply with one JSON object and nothing else:\n\
+         {{\"corrected\": \"the corrected sentence\", \"issues\": [{{\"category\": \"grammar | vocabulary | spelling | word_order | particle | style | other\", \"original\": \"the wrong part\", \"correction\": \"the fixed part\", \"note\": \"why, in one short sentence\"}}], \"explanation\": \"one or two sentences for the learner\"}}\n\
+         Change as little as possible. If the sentence is already natural and correct, repeat it unchanged in corrected and leave issues empty.",
+        language
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use anki_connect::anki::mock::{MockAnki, ok};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_html() {
+        assert_eq!(
+            diff_html(
+                "I goes to school.",
+                "I go to school."
+            ),
+            "I <del>goes</del><ins>go</ins> to school."
+        );
+        assert_eq!(
+            diff_html(
+                "He have went home",
+                "He has gone home"
+            ),
+            "He <del>have went</del><ins>has gone</ins> home"
+        );
+        assert_eq!(
+            diff_html(
+                "私は学校を行きます",
+                "私は学校に行きます"
+            ),
+            "私は学校<del>を</del><ins>に</ins>行きます"
+        );
+        assert_eq!(
+            diff_html(
+                "I like cat",
+                "I like cats very much"
+            ),
+            "I like <del>cat</del><ins>cats very much</ins>"
+        );
+        assert_eq!(
+            diff_html("Yes, really", "Yes really"),
+            "Yes<del>,</del> really"
+        );
+        assert_eq!(
+            diff_html("1 < 2", "1 > 2"),
+            "1 <del>&lt;</del><ins>&gt;</ins> 2"
+        );
+        assert_eq!(diff_html("same", "same"), "same");
+    }
+
+    #[test]
+    fn test_parse_correction() {
+        let correction = Correction::parse(
+            "```json\n{\"corrected\": \"I go.\", \"issues\": [{\"category\": \"grammar\", \"original\": \"goes\", \"correction\": \"go\", \"note\": \"Use the base form with I.\"}, {\"category\": \"tense\", \"original\": \"x\", \"correction\": \"y\"}], \"explanation\": \"Subject-verb agreement.\"}\n```",
+        )
+        .unwrap();
+        assert_eq!(correction.corrected, "I go.");
+        assert_eq!(
+            correction.issues[0].category,
+            IssueCategory::Grammar
+        );
+        assert_eq!(
+            correction.issues[1].category,
+            IssueCategory::Other
+        );
+        assert!(!correction.is_correct("I goes."));
+        assert_eq!(
+            correction.explanation_html(),
+            "<ul><li><b>grammar</b>: goes → go — Use the base form with I.</li><li><b>other</b>: x → y</li></ul><p>Subject-verb agreement.</p>"
+        );
+
+        let fine = Correction::parse(
+            r#"{"corrected": "I  go.", "issues": []}"#,
+        )
+        .unwrap();
+        assert!(fine.is_correct("I go."));
+        assert_eq!(fine.explanation_html(), "");
+
+        for broken in [
+            "I go.",
+            r#"{"corrected": ""}"#,
+            r#"{"issues": []}"#,
+            r#"{"corrected": "x", "score": 3}"#,
+            r#"{"corrected": "x", "issues": [{"category": "grammar"}]}"#,
+        ] {
+            assert!(
+                Correction::parse(broken).is_err(),
+                "{}",
+                broken
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_note_fails_on_its_own() {
+        let mock = MockAnki::start(|action, _| match action {
+            "findNotes" => ok(json!([1, 2, 3, 4])),
+            "notesInfo" => {
+                let note = |id: u64, sentence: &str| {
+                    json!({
+                        "noteId": id,
+                        "modelName": "Production",
+                        "tags": [],
+                        "fields": {
+                            "Sentence": {"value": sentence, "order": 0},
+                            "Correction": {"value": "", "order": 1},
+                            "Explanation": {"value": "", "order": 2},
+                        },
+                        "cards": [],
+                    })
+                };
+                ok(json!([
+                    note(1, "I goes to school."),
+                    note(2, "I go to school."),
+                    note(3, "I goed."),
+                    {
+                        "noteId": 4,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {"Front": {"value": "x", "order": 0}},
+                        "cards": [],
+                    },
+                ]))
+            }
+            "updateNoteFields" => ok(json!(null)),
+            other => panic!("unexpected action {}", other),
+        })
+        .await;
+
+        let mut prompts = Vec::new();
+        let report = correct_productions(
+            async |messages: Vec<ChatMessage>| {
+                prompts.push(messages[0].content.clone());
+                Ok(match messages[1].content.as_str() {
+                    "I goes to school." => r#"{"corrected": "I go to school.", "issues": [{"category": "grammar", "original": "goes", "correction": "go"}], "explanation": "Agreement."}"#,
+                    "I go to school." => r#"{"corrected": "I go to school.", "issues": [], "explanation": ""}"#,
+                    _ => "Looks fine to me!",
+                }
+                .to_string())
+            },
+            &mock.client(),
+            "deck:Output",
+            &CorrectionOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.corrected, [1]);
+        assert_eq!(report.no_issues, [2]);
+        let failed: Vec<u64> = report
+            .failed
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(failed, [3, 4]);
+        assert!(
+            report.failed[0].1.contains("not valid JSON")
+        );
+        assert!(
+            report.failed[1]
+                .1
+                .contains("no field Sentence")
+        );
+        assert!(prompts[0].contains("learner of English"));
+        assert_eq!(prompts.len(), 3);
+
+        let updates: Vec<_> = mock
+            .requests()
+            .into_iter()
+            .filter(|request| {
+                request["action"] == "updateNoteFields"
+            })
+            .map(|request| {
+                request["params"]["note"].clone()
+            })
+            .collect();
+        assert_eq!(
+            updates,
+            [
+                json!({"id": 1, "fields": {
+                    "Correction": "I <del>goes</del><ins>go</ins> to school.",
+                    "Explanation": "<ul><li><b>grammar</b>: goes → go</li></ul><p>Agreement.</p>",
+                }}),
+                json!({"id": 2, "fields": {
+                    "Correction": NO_ISSUES_MARKER,
+                    "Explanation": "",
+                }}),
+            ]
+        );
+    }
+}
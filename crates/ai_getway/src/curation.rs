@@ -0,0 +1,979 @@
+//! 找出牌组中值得暂停、删除或改写的卡片
+//!
+//! 太简单、重复或与学习目标无关的卡片会占用每天的复习量。
+//! [`curate_deck`] 把复习数据中的统计信号（间隔、难度系数、遗忘次数
+//! 和“重来”比例）与模型按用户目标对笔记内容的评判结合起来，给出按
+//! 分数排序的建议列表。建议默认只是建议；设置
+//! [`CurationOptions::apply`] 后也只会自动暂停卡片，删除和改写始终
+//! 需要用户自己决定。
+
+use std::collections::{BTreeMap, HashMap};
+
+use anki_connect::anki::client::{
+    AnkiClient, CardInfo, CardReview, CardType, NoteInfo,
+    deck_query,
+};
+use anki_connect::convert::strip_html;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::chat::ChatMessage;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::structured::strip_outer_fence;
+
+/// Notes judged in one model request
+const JUDGE_BATCH: usize = 20;
+
+/// Characters of each field shown to the model
+const MAX_FIELD_CHARS: usize = 300;
+
+/// Lapses from which a card counts as a leech, as in Anki's default
+const LEECH_LAPSES: u32 = 8;
+
+/// Share of "Again" answers from which a card is struggling
+const STRUGGLING_AGAIN_RATE: f64 = 0.4;
+
+/// Review-stage answers needed before the "Again" share counts
+const MIN_ANSWERS_FOR_RATE: usize = 5;
+
+/// Lowest ease Anki assigns, in permille
+const MINIMUM_EASE: u32 = 1300;
+
+/// Score of a note that is easy by its statistics
+const EASY_WEIGHT: f64 = 0.4;
+
+/// Extra score when the interval is twice the easy threshold
+const VERY_EASY_WEIGHT: f64 = 0.1;
+
+/// Score of a note whose cards keep being forgotten
+const STRUGGLING_WEIGHT: f64 = 0.4;
+
+/// [`curate_deck`] 的选项
+///
+/// # 字段
+/// - `goal_description`: 学习目标，如“准备 JLPT N3 的阅读”，模型据此
+///   判断笔记是否相关
+/// - `min_interval_for_easy`: 间隔达到这个天数且从未遗忘的卡片视为
+///   太简单
+/// - `max_suggestions`: 最多返回的建议数
+/// - `apply`: 是否自动暂停建议暂停的卡片；删除和改写的建议永远不会
+///   自动执行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurationOptions {
+    pub goal_description: String,
+    pub min_interval_for_easy: i64,
+    pub max_suggestions: usize,
+    pub apply: bool,
+}
+
+impl Default for CurationOptions {
+    fn default() -> Self {
+        Self {
+            goal_description: String::new(),
+            min_interval_for_easy: 180,
+            max_suggestions: 50,
+            apply: false,
+        }
+    }
+}
+
+/// 建议的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CurationAction {
+    /// 暂停卡片，保留复习记录
+    Suspend,
+    /// 删除笔记
+    Delete,
+    /// 改写笔记内容
+    Rewrite,
+}
+
+/// 模型对一条笔记的评判，无法识别的值归为 [`Verdict::Keep`]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    /// 与学习目标无关
+    Irrelevant,
+    /// 与牌组中另一条笔记重复
+    Duplicate,
+    /// 内容不清楚或有错误，需要改写
+    Unclear,
+    /// 值得保留
+    #[serde(other)]
+    Keep,
+}
+
+/// 模型返回的一条评判
+///
+/// # 字段
+/// - `id`: 笔记 ID
+/// - `verdict`: 评判结果
+/// - `reason`: 简短理由
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Judgement {
+    pub id: u64,
+    pub verdict: Verdict,
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Judgement {
+    /// 解析模型输出的评判列表，包裹整个输出的代码围栏会先被去掉
+    ///
+    /// # 返回
+    /// 不是符合格式的 JSON 数组时返回错误。
+    pub fn parse_all(
+        output: &str,
+    ) -> anyhow::Result<Vec<Self>> {
+        serde_json::from_str(strip_outer_fence(output))
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "The judgements are not valid JSON: {}",
+                    e
+                )
+            })
+    }
+}
+
+/// 一条笔记的卡片的复习统计
+///
+/// # 字段
+/// - `max_interval`: 卡片中最长的间隔（天），没有复习过的卡片时为 0
+/// - `lowest_ease`: 复习过的卡片中最低的难度系数（千分比）
+/// - `lapses`: 所有卡片的遗忘次数之和
+/// - `answers`: 复习阶段的作答次数
+/// - `again`: 其中选择“重来”的次数
+/// - `easy`: 每张卡片都已复习过、间隔达到
+///   [`CurationOptions::min_interval_for_easy`] 且从未遗忘
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoteStats {
+    pub max_interval: i64,
+    pub lowest_ease: Option<u32>,
+    pub lapses: u32,
+    pub answers: usize,
+    pub again: usize,
+    pub easy: bool,
+}
+
+impl NoteStats {
+    /// 由一条笔记的卡片和这些卡片的复习记录计算统计
+    pub fn from_cards(
+        cards: &[&CardInfo],
+        reviews: &[&CardReview],
+        options: &CurationOptions,
+    ) -> Self {
+        let reviewed: Vec<&&CardInfo> = cards
+            .iter()
+            .filter(|card| {
+                matches!(
+                    card.kind(),
+                    CardType::Review | CardType::Relearning
+                )
+            })
+            .collect();
+        let answers: Vec<&&CardReview> = reviews
+            .iter()
+            .filter(|review| review.is_review())
+            .collect();
+        let lapses =
+            cards.iter().map(|card| card.lapses).sum();
+        Self {
+            max_interval: reviewed
+                .iter()
+                .map(|card| card.interval)
+                .max()
+                .unwrap_or(0),
+            lowest_ease: reviewed
+                .iter()
+                .map(|card| card.factor)
+                .filter(|factor| *factor > 0)
+                .min(),
+            lapses,
+            answers: answers.len(),
+            again: answers
+                .iter()
+                .filter(|review| review.is_again())
+                .count(),
+            easy: !cards.is_empty()
+                && reviewed.len() == cards.len()
+                && lapses == 0
+                && reviewed.iter().all(|card| {
+                    card.interval
+                        >= options.min_interval_for_easy
+                }),
+        }
+    }
+
+    /// “重来”的比例，复习阶段作答太少时为 `None`
+    pub fn again_rate(&self) -> Option<f64> {
+        (self.answers >= MIN_ANSWERS_FOR_RATE).then(|| {
+            self.again as f64 / self.answers as f64
+        })
+    }
+
+    /// 卡片是否总被忘记：达到水蛭标准、难度系数降到最低，或“重来”
+    /// 比例过高
+    pub fn struggling(&self) -> bool {
+        self.lapses >= LEECH_LAPSES
+            || self.lowest_ease.is_some_and(|ease| {
+                ease <= MINIMUM_EASE && self.lapses > 0
+            })
+            || self.again_rate().is_some_and(|rate| {
+                rate >= STRUGGLING_AGAIN_RATE
+            })
+    }
+}
+
+/// 一条建议
+///
+/// # 字段
+/// - `note_id`: 笔记 ID
+/// - `card_ids`: 笔记在牌组中的卡片，暂停时作用于这些卡片
+/// - `action`: 建议的处理方式
+/// - `score`: 0 到 1 之间的分数，越高越值得处理
+/// - `reasons`: 统计信号和模型给出的理由
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurationSuggestion {
+    pub note_id: u64,
+    pub card_ids: Vec<u64>,
+    pub action: CurationAction,
+    pub score: f64,
+    pub reasons: Vec<String>,
+}
+
+/// [`curate_deck`] 的结果
+///
+/// # 字段
+/// - `suggestions`: 按分数从高到低排序的建议
+/// - `suspended`: 自动暂停的卡片，只有设置了
+///   [`CurationOptions::apply`] 时才不为空
+/// - `failed`: 模型评判失败的笔记及原因；这些笔记只按统计信号给出
+///   建议
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CurationReport {
+    pub suggestions: Vec<CurationSuggestion>,
+    pub suspended: Vec<u64>,
+    pub failed: Vec<(u64, String)>,
+}
+
+/// 结合统计信号和模型评判，给出一条笔记的建议
+///
+/// 重复或无关的笔记建议删除，内容不清楚的建议改写；模型认为值得
+/// 保留或没有评判时，总被忘记的笔记建议改写，太简单的建议暂停。
+/// 分数是模型评判的权重加上统计信号的权重；模型认为值得保留时，
+/// 统计信号的权重减半。
+///
+/// # 返回
+/// 没有任何信号时返回 `None`。
+pub fn suggest(
+    note_id: u64,
+    card_ids: Vec<u64>,
+    stats: &NoteStats,
+    judgement: Option<&Judgement>,
+    options: &CurationOptions,
+) -> Option<CurationSuggestion> {
+    let verdict = judgement.map(|j| j.verdict);
+    let struggling = stats.struggling();
+    let action = match verdict {
+        Some(Verdict::Duplicate | Verdict::Irrelevant) => {
+            CurationAction::Delete
+        }
+        Some(Verdict::Unclear) => CurationAction::Rewrite,
+        Some(Verdict::Keep) | None if struggling => {
+            CurationAction::Rewrite
+        }
+        Some(Verdict::Keep) | None if stats.easy => {
+            CurationAction::Suspend
+        }
+        Some(Verdict::Keep) | None => return None,
+    };
+
+    let mut reasons = Vec::new();
+    let mut statistics = 0.0;
+    if stats.easy {
+        statistics += EASY_WEIGHT;
+        if stats.max_interval
+            >= 2 * options.min_interval_for_easy
+        {
+            statistics += VERY_EASY_WEIGHT;
+        }
+        reasons.push(format!(
+            "Interval of {} days without a lapse",
+            stats.max_interval
+        ));
+    }
+    if struggling {
+        statistics += STRUGGLING_WEIGHT;
+        reasons.push(match stats.again_rate() {
+            Some(rate) if rate >= STRUGGLING_AGAIN_RATE => {
+                format!(
+                    "Again on {} of {} answers",
+                    stats.again, stats.answers
+                )
+            }
+            _ => format!(
+                "{} lapses, ease {:.2}",
+                stats.lapses,
+                f64::from(stats.lowest_ease.unwrap_or(0))
+                    / 1000.0
+            ),
+        });
+    }
+    let judged = match verdict {
+        Some(Verdict::Duplicate) => 0.6,
+        Some(Verdict::Irrelevant) => 0.5,
+        Some(Verdict::Unclear) => 0.4,
+        Some(Verdict::Keep) => {
+            statistics /= 2.0;
+            0.0
+        }
+        None => 0.0,
+    };
+    if let Some(judgement) = judgement
+        && verdict != Some(Verdict::Keep)
+        && !judgement.reason.trim().is_empty()
+    {
+        reasons.push(judgement.reason.trim().to_string());
+    }
+
+    Some(CurationSuggestion {
+        note_id,
+        card_ids,
+        action,
+        score: (judged + statistics).min(1.0),
+        reasons,
+    })
+}
+
+/// 要自动暂停的卡片：只有设置了 [`CurationOptions::apply`] 时才有，
+/// 且只来自 [`CurationAction::Suspend`] 建议
+pub fn cards_to_suspend(
+    suggestions: &[CurationSuggestion],
+    options: &CurationOptions,
+) -> Vec<u64> {
+    if !options.apply {
+        return Vec::new();
+    }
+    suggestions
+        .iter()
+        .filter(|s| s.action == CurationAction::Suspend)
+        .flat_map(|s| s.card_ids.iter().copied())
+        .collect()
+}
+
+/// 找出 `deck`（含子牌组）中值得暂停、删除或改写的笔记
+///
+/// 读取未暂停的卡片、笔记内容和牌组的复习记录（`cardReviews` 只
+/// 返回牌组本身的记录，子牌组卡片的“重来”比例因此不计），笔记每
+/// [`JUDGE_BATCH`] 条请求模型评判一次。某批评判失败只记入报告，这些
+/// 笔记仍按统计信号给出建议。
+///
+/// # 参数
+/// - `complete`: 发送完整对话并返回模型回复的函数
+/// - `anki_client`: Anki 客户端
+/// - `deck`: 牌组名称
+/// - `options`: 学习目标、阈值和是否自动暂停
+///
+/// # 返回
+/// 读取 Anki 数据或自动暂停失败时返回错误。
+pub async fn curate_deck(
+    mut complete: impl AsyncFnMut(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    anki_client: &AnkiClient,
+    deck: &str,
+    options: &CurationOptions,
+) -> anyhow::Result<CurationReport> {
+    let mut report = CurationReport::default();
+    let cards = anki_client
+        .find_cards_detailed(&format!(
+            "{} -is:suspended",
+            deck_query(deck)
+        ))
+        .await?;
+    let reviews = anki_client.card_reviews(deck, 0).await?;
+
+    let mut by_note: BTreeMap<u64, Vec<&CardInfo>> =
+        BTreeMap::new();
+    for card in &cards {
+        by_note.entry(card.note_id).or_default().push(card);
+    }
+    let mut by_card: HashMap<u64, Vec<&CardReview>> =
+        HashMap::new();
+    for review in &reviews {
+        by_card
+            .entry(review.card_id)
+            .or_default()
+            .push(review);
+    }
+    let note_ids: Vec<u64> =
+        by_note.keys().copied().collect();
+    let notes =
+        anki_client.notes_info_chunked(&note_ids).await?;
+
+    let mut judgements = HashMap::new();
+    for batch in notes.chunks(JUDGE_BATCH) {
+        let result = async {
+            let reply = complete(vec![
+                ChatMessage::system(judge_prompt(
+                    &options.goal_description,
+                )),
+                ChatMessage::user(notes_payload(batch)),
+            ])
+            .await?;
+            Judgement::parse_all(&reply)
+        }
+        .await;
+        match result {
+            Ok(judged) => {
+                for judgement in judged {
+                    judgements
+                        .insert(judgement.id, judgement);
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "Judging {} notes of {} failed: {:#}",
+                    batch.len(),
+                    deck,
+                    e
+                );
+                report.failed.extend(batch.iter().map(
+                    |note| {
+                        (note.note_id, format!("{:#}", e))
+                    },
+                ));
+            }
+        }
+    }
+
+    for (note_id, note_cards) in &by_note {
+        let note_reviews: Vec<&CardReview> = note_cards
+            .iter()
+            .filter_map(|card| by_card.get(&card.card_id))
+            .flatten()
+            .copied()
+            .collect();
+        let stats = NoteStats::from_cards(
+            note_cards,
+            &note_reviews,
+            options,
+        );
+        let card_ids = note_cards
+            .iter()
+            .map(|card| card.card_id)
+            .collect();
+        if let Some(suggestion) = suggest(
+            *note_id,
+            card_ids,
+            &stats,
+            judgements.get(note_id),
+            options,
+        ) {
+            report.suggestions.push(suggestion);
+        }
+    }
+    report.suggestions.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.note_id.cmp(&b.note_id))
+    });
+    report.suggestions.truncate(options.max_suggestions);
+
+    report.suspended =
+        cards_to_suspend(&report.suggestions, options);
+    anki_client
+        .suspend_cards(report.suspended.clone())
+        .await?;
+    Ok(report)
+}
+
+/// 使用智谱客户端执行 [`curate_deck`]
+pub async fn curate_deck_with_zhi_pu(
+    client: &ZhiPuClient,
+    anki_client: &AnkiClient,
+    deck: &str,
+    options: &CurationOptions,
+) -> anyhow::Result<CurationReport> {
+    curate_deck(
+        async |messages| {
+            let request = ZhiPuRequest::new(
+                messages
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            );
+            client.complete(request).await?.into_content()
+        },
+        anki_client,
+        deck,
+        options,
+    )
+    .await
+}
+
+/// The notes as a JSON array of `{"id", "fields"}`, markup
+/// stripped and long fields cut
+fn notes_payload(notes: &[NoteInfo]) -> String {
+    let notes: Vec<_> = notes
+        .iter()
+        .map(|note| {
+            let fields: serde_json::Map<_, _> = note
+                .ordered_fields()
+                .into_iter()
+                .map(|(name, value)| {
+                    let text: String = strip_html(value)
+                        .trim()
+                        .chars()
+                        .take(MAX_FIELD_CHARS)
+                        .collect();
+                    (name.to_string(), json!(text))
+                })
+                .collect();
+            json!({"id": note.note_id, "fields": fields})
+        })
+        .collect();
+    serde_json::Value::Array(notes).to_string()
+}
+
+fn judge_prompt(goal: &str) -> String {
+    let goal = match goal.trim() {
+        "" => "general study".to_string(),
+        goal => goal.to_string(),
+    };
+    format!(
+        "You review flashcards for a learner whose goal is: {}\n\
+         The user sends a JSON array of notes, each with an id and its fields.\n\
+         Reply with one JSON array and nothing else, one object per note:\n\
+         [{{\"id\": 123, \"verdict\": \"keep | irrelevant | duplicate | unclear\", \"reason\": \"one short sentence\"}}]\n\
+         Use irrelevant for notes that do not serve the goal, duplicate for notes that repeat another note in the array, and unclear for notes that are ambiguous, wrong or badly worded. Use keep otherwise; most notes should be kept.",
+        goal
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use anki_connect::anki::mock::{MockAnki, ok};
+    use serde_json::Value;
+
+    use super::*;
+
+    fn card_json(
+        id: u64,
+        note: u64,
+        card_type: u32,
+        interval: i64,
+        factor: u32,
+        lapses: u32,
+    ) -> Value {
+        json!({
+            "cardId": id,
+            "note": note,
+            "deckName": "Words",
+            "modelName": "Basic",
+            "ord": 0,
+            "type": card_type,
+            "queue": card_type,
+            "due": 0,
+            "interval": interval,
+            "factor": factor,
+            "reps": 10,
+            "lapses": lapses,
+        })
+    }
+
+    fn card(
+        interval: i64,
+        factor: u32,
+        lapses: u32,
+    ) -> CardInfo {
+        serde_json::from_value(card_json(
+            1, 10, 2, interval, factor, lapses,
+        ))
+        .unwrap()
+    }
+
+    fn answers(
+        again: usize,
+        total: usize,
+    ) -> Vec<CardReview> {
+        (0..total)
+            .map(|i| CardReview {
+                review_time: i as i64,
+                card_id: 1,
+                usn: -1,
+                button: if i < again { 1 } else { 3 },
+                interval: 1,
+                last_interval: 1,
+                factor: 2500,
+                duration: 3000,
+                review_type: 1,
+            })
+            .collect()
+    }
+
+    fn stats(
+        cards: &[CardInfo],
+        reviews: &[CardReview],
+    ) -> NoteStats {
+        let cards: Vec<&CardInfo> = cards.iter().collect();
+        let reviews: Vec<&CardReview> =
+            reviews.iter().collect();
+        NoteStats::from_cards(
+            &cards,
+            &reviews,
+            &CurationOptions::default(),
+        )
+    }
+
+    fn judged(verdict: Verdict) -> Judgement {
+        Judgement {
+            id: 10,
+            verdict,
+            reason: "Model says so.".to_string(),
+        }
+    }
+
+    /// Action and score of the suggestion, if any
+    fn outcome(
+        stats: &NoteStats,
+        verdict: Option<Verdict>,
+    ) -> Option<(CurationAction, f64)> {
+        let judgement = verdict.map(judged);
+        suggest(
+            10,
+            vec![1],
+            stats,
+            judgement.as_ref(),
+            &CurationOptions::default(),
+        )
+        .map(|s| {
+            (s.action, (s.score * 100.0).round() / 100.0)
+        })
+    }
+
+    #[test]
+    fn test_note_stats() {
+        let easy = stats(&[card(200, 2500, 0)], &[]);
+        assert!(easy.easy);
+        assert!(!easy.struggling());
+        assert_eq!(easy.again_rate(), None);
+
+        assert!(!stats(&[card(100, 2500, 0)], &[]).easy);
+        assert!(!stats(&[card(200, 2500, 1)], &[]).easy);
+        let mut fresh = card(0, 0, 0);
+        fresh.card_type = 0;
+        assert!(
+            !stats(&[card(200, 2500, 0), fresh], &[]).easy
+        );
+
+        let again =
+            stats(&[card(3, 2500, 0)], &answers(2, 5));
+        assert_eq!(again.again_rate(), Some(0.4));
+        assert!(again.struggling());
+        // Too few answers for the rate to count
+        assert!(
+            !stats(&[card(3, 2500, 0)], &answers(2, 4))
+                .struggling()
+        );
+        assert!(
+            stats(&[card(3, 2500, 8)], &[]).struggling()
+        );
+        assert!(
+            stats(&[card(3, 1300, 1)], &[]).struggling()
+        );
+        assert!(
+            !stats(&[card(3, 1300, 0)], &[]).struggling()
+        );
+    }
+
+    #[test]
+    fn test_scores_combine_statistics_and_verdicts() {
+        let plain = stats(&[card(30, 2500, 0)], &[]);
+        let easy = stats(&[card(200, 2500, 0)], &[]);
+        let very_easy = stats(&[card(400, 2500, 0)], &[]);
+        let struggling = stats(&[card(3, 1300, 9)], &[]);
+
+        assert_eq!(outcome(&plain, None), None);
+        assert_eq!(
+            outcome(&plain, Some(Verdict::Keep)),
+            None
+        );
+        assert_eq!(
+            outcome(&easy, None),
+            Some((CurationAction::Suspend, 0.4))
+        );
+        assert_eq!(
+            outcome(&very_easy, None),
+            Some((CurationAction::Suspend, 0.5))
+        );
+        // The model sees value in it: half the weight
+        assert_eq!(
+            outcome(&very_easy, Some(Verdict::Keep)),
+            Some((CurationAction::Suspend, 0.25))
+        );
+        assert_eq!(
+            outcome(&struggling, None),
+            Some((CurationAction::Rewrite, 0.4))
+        );
+        assert_eq!(
+            outcome(&plain, Some(Verdict::Unclear)),
+            Some((CurationAction::Rewrite, 0.4))
+        );
+        assert_eq!(
+            outcome(&struggling, Some(Verdict::Unclear)),
+            Some((CurationAction::Rewrite, 0.8))
+        );
+        assert_eq!(
+            outcome(&plain, Some(Verdict::Irrelevant)),
+            Some((CurationAction::Delete, 0.5))
+        );
+        assert_eq!(
+            outcome(&very_easy, Some(Verdict::Duplicate)),
+            Some((CurationAction::Delete, 1.0))
+        );
+        assert_eq!(
+            outcome(&easy, Some(Verdict::Irrelevant)),
+            Some((CurationAction::Delete, 0.9))
+        );
+
+        let suggestion = suggest(
+            10,
+            vec![1],
+            &stats(&[card(3, 2500, 0)], &answers(3, 5)),
+            Some(&judged(Verdict::Unclear)),
+            &CurationOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            suggestion.reasons,
+            ["Again on 3 of 5 answers", "Model says so."]
+        );
+    }
+
+    #[test]
+    fn test_only_suspensions_are_applied() {
+        let suggestion =
+            |note_id, action| CurationSuggestion {
+                note_id,
+                card_ids: vec![
+                    note_id * 10,
+                    note_id * 10 + 1,
+                ],
+                action,
+                score: 0.5,
+                reasons: Vec::new(),
+            };
+        let suggestions = [
+            suggestion(1, CurationAction::Delete),
+            suggestion(2, CurationAction::Suspend),
+            suggestion(3, CurationAction::Rewrite),
+            suggestion(4, CurationAction::Suspend),
+        ];
+        let mut options = CurationOptions::default();
+        assert!(
+            cards_to_suspend(&suggestions, &options)
+                .is_empty()
+        );
+        options.apply = true;
+        assert_eq!(
+            cards_to_suspend(&suggestions, &options),
+            [20, 21, 40, 41]
+        );
+    }
+
+    #[test]
+    fn test_parse_judgements() {
+        let judgements = Judgement::parse_all(
+            "```json\n[{\"id\": 1, \"verdict\": \"duplicate\", \"reason\": \"Same as 2.\"}, {\"id\": 2, \"verdict\": \"fine\"}]\n```",
+        )
+        .unwrap();
+        assert_eq!(
+            judgements[0].verdict,
+            Verdict::Duplicate
+        );
+        assert_eq!(judgements[1].verdict, Verdict::Keep);
+        assert_eq!(judgements[1].reason, "");
+        assert!(Judgement::parse_all("All good!").is_err());
+        assert!(
+            Judgement::parse_all(
+                r#"[{"verdict": "keep"}]"#
+            )
+            .is_err()
+        );
+    }
+
+    fn mock_deck()
+    -> impl Fn(&str, &Value) -> Value + Send + Sync + 'static
+    {
+        |action, _| match action {
+            "findCards" => ok(json!([1, 2, 3, 4])),
+            "cardsInfo" => ok(json!([
+                // Easy
+                card_json(1, 10, 2, 400, 2500, 0),
+                // Irrelevant by the model
+                card_json(2, 20, 2, 20, 2500, 0),
+                // Nothing to say
+                card_json(3, 30, 2, 20, 2500, 0),
+                // Easy, but the model keeps it
+                card_json(4, 40, 2, 200, 2500, 0),
+            ])),
+            "cardReviews" => ok(json!([])),
+            "notesInfo" => {
+                let note = |id: u64, front: &str| {
+                    json!({
+                        "noteId": id,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {
+                            "Front": {"value": front, "order": 0},
+                            "Back": {"value": "<b>x</b>", "order": 1},
+                        },
+                        "cards": [id / 10],
+                    })
+                };
+                ok(json!([
+                    note(10, "猫"),
+                    note(20, "Capital of Peru"),
+                    note(30, "犬"),
+                    note(40, "鳥"),
+                ]))
+            }
+            "suspend" => ok(json!(true)),
+            other => panic!("unexpected action {}", other),
+        }
+    }
+
+    const JUDGED: &str = r#"[
+        {"id": 20, "verdict": "irrelevant", "reason": "Geography, not Japanese."},
+        {"id": 30, "verdict": "keep"},
+        {"id": 40, "verdict": "keep", "reason": "Core word."}
+    ]"#;
+
+    #[tokio::test]
+    async fn test_curate_deck_suggests_without_acting() {
+        let mock = MockAnki::start(mock_deck()).await;
+        let mut prompts = Vec::new();
+        let report = curate_deck(
+            async |messages: Vec<ChatMessage>| {
+                prompts.push((
+                    messages[0].content.clone(),
+                    messages[1].content.clone(),
+                ));
+                Ok(JUDGED.to_string())
+            },
+            &mock.client(),
+            "Words",
+            &CurationOptions {
+                goal_description: "JLPT N3 vocabulary"
+                    .to_string(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let ranked: Vec<_> = report
+            .suggestions
+            .iter()
+            .map(|s| (s.note_id, s.action))
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                (10, CurationAction::Suspend),
+                (20, CurationAction::Delete),
+                (40, CurationAction::Suspend),
+            ]
+        );
+        assert_eq!(
+            report.suggestions[1].reasons,
+            ["Geography, not Japanese."]
+        );
+        assert!(report.suspended.is_empty());
+        assert!(report.failed.is_empty());
+        assert!(
+            !mock
+                .actions()
+                .contains(&"suspend".to_string())
+        );
+
+        assert_eq!(prompts.len(), 1);
+        assert!(
+            prompts[0].0.contains("JLPT N3 vocabulary")
+        );
+        let payload: Value =
+            serde_json::from_str(&prompts[0].1).unwrap();
+        assert_eq!(
+            payload[1],
+            json!({"id": 20, "fields": {"Front": "Capital of Peru", "Back": "x"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_curate_deck_applies_only_suspensions() {
+        let mock = MockAnki::start(mock_deck()).await;
+        let report = curate_deck(
+            async |_| Ok(JUDGED.to_string()),
+            &mock.client(),
+            "Words",
+            &CurationOptions {
+                max_suggestions: 2,
+                apply: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.suggestions.len(), 2);
+        assert_eq!(report.suspended, [1]);
+        let suspends: Vec<_> = mock
+            .requests()
+            .into_iter()
+            .filter(|r| r["action"] == "suspend")
+            .map(|r| r["params"].clone())
+            .collect();
+        assert_eq!(suspends, [json!({"cards": [1]})]);
+        assert!(
+            !mock
+                .actions()
+                .iter()
+                .any(|a| a.starts_with("delete"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_judging_keeps_statistics() {
+        let mock = MockAnki::start(mock_deck()).await;
+        let report = curate_deck(
+            async |_| {
+                Ok("I cannot help with that.".to_string())
+            },
+            &mock.client(),
+            "Words",
+            &CurationOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let ranked: Vec<_> = report
+            .suggestions
+            .iter()
+            .map(|s| (s.note_id, s.action))
+            .collect();
+        assert_eq!(
+            ranked,
+            [
+                (10, CurationAction::Suspend),
+                (40, CurationAction::Suspend),
+            ]
+        );
+        assert_eq!(report.failed.len(), 4);
+        assert!(
+            report.failed[0].1.contains("not valid JSON")
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! 用模型改写已有笔记的字段
+//!
+//! 读取笔记、把字段内容交给模型改写、再写回 Anki，是“改进已有
+//! 卡片”的基本流程。
+
+use anki_connect::anki::client::AnkiClient;
+use anyhow::Context;
+
+use crate::chat::ChatMessage;
+use crate::language::LanguageOption;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::structured::strip_outer_fence;
+
+/// 用模型改写笔记的一个字段并写回
+///
+/// `prompt` 作为系统提示词，字段当前内容作为用户输入；模型回复
+/// 去掉首尾空白和包裹整个回复的代码围栏后写入该字段，其他字段
+/// 保持不变。`prompt` 中的 `{{language}}` 替换为字段的语言名称，
+/// `language` 为 [`LanguageOption::Auto`] 时按字段内容判断。
+///
+/// # 参数
+/// - `anki_client`: Anki 客户端
+/// - `complete`: 发送完整对话并返回模型回复的函数
+/// - `note_id`: 笔记 ID
+/// - `field`: 要改写的字段名
+/// - `prompt`: 改写要求，如“改正拼写并让释义更简洁”
+/// - `language`: 字段的语言
+///
+/// # 返回
+/// 笔记或字段不存在、调用失败或回复为空时返回错误，此时笔记不会被
+/// 修改。
+pub async fn enhance_note_field(
+    anki_client: &AnkiClient,
+    complete: impl AsyncFnOnce(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    note_id: u64,
+    field: &str,
+    prompt: &str,
+    language: LanguageOption,
+) -> anyhow::Result<()> {
+    let note = anki_client
+        .notes_info(vec![note_id])
+        .await?
+        .into_iter()
+        .find(|note| note.note_id == note_id)
+        .with_context(|| {
+            format!("Note {} not found", note_id)
+        })?;
+    let current =
+        note.fields.get(field).with_context(|| {
+            format!(
+                "Note {} has no field {} (model {})",
+                note_id, field, note.model_name
+            )
+        })?;
+
+    let prompt = prompt.replace(
+        "{{language}}",
+        language.resolve(&current.value).name(),
+    );
+    let reply = complete(vec![
+        ChatMessage::system(prompt),
+        ChatMessage::user(current.value.clone()),
+    ])
+    .await?;
+    let value = strip_outer_fence(&reply);
+    anyhow::ensure!(
+        !value.is_empty(),
+        "The model returned an empty {} for note {}",
+        field,
+        note_id
+    );
+    anki_client
+        .update_note_field(note_id, field, value)
+        .await
+}
+
+/// 使用智谱客户端执行 [`enhance_note_field`]
+pub async fn enhance_note_field_with_zhi_pu(
+    anki_client: &AnkiClient,
+    client: &ZhiPuClient,
+    note_id: u64,
+    field: &str,
+    prompt: &str,
+    language: LanguageOption,
+) -> anyhow::Result<()> {
+    enhance_note_field(
+        anki_client,
+        async |messages| {
+            let request = ZhiPuRequest::new(
+                messages
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            );
+            client.complete(request).await?.into_content()
+        },
+        note_id,
+        field,
+        prompt,
+        language,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use anki_connect::anki::mock::{MockAnki, ok};
+    use serde_json::json;
+
+    use super::*;
+    use crate::language::Lang;
+
+    async fn anki() -> MockAnki {
+        MockAnki::start(|action, _| match action {
+            "notesInfo" => ok(json!([{
+                "noteId": 7,
+                "modelName": "Basic",
+                "tags": [],
+                "fields": {
+                    "Front": {"value": "猫", "order": 0},
+                    "Back": {"value": "a cat, the animal cat", "order": 1},
+                },
+                "cards": [],
+            }])),
+            "updateNoteFields" => ok(json!(null)),
+            other => panic!("unexpected action {}", other),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_field_is_rewritten() {
+        let mock = anki().await;
+        let mut seen = Vec::new();
+        enhance_note_field(
+            &mock.client(),
+            async |messages| {
+                seen = messages;
+                Ok("```\ncat\n```".to_string())
+            },
+            7,
+            "Back",
+            "Make the definition concise.",
+            LanguageOption::Auto,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            seen,
+            vec![
+                ChatMessage::system(
+                    "Make the definition concise."
+                ),
+                ChatMessage::user("a cat, the animal cat"),
+            ]
+        );
+        assert_eq!(
+            mock.actions(),
+            ["notesInfo", "updateNoteFields"]
+        );
+        assert_eq!(
+            mock.requests()[1]["params"]["note"],
+            json!({"id": 7, "fields": {"Back": "cat"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_language_placeholder_is_filled() {
+        let mock = anki().await;
+        let client = mock.client();
+        for (field, language, expected) in [
+            ("Back", LanguageOption::Auto, "English"),
+            ("Front", LanguageOption::Auto, "Chinese"),
+            (
+                "Front",
+                LanguageOption::Fixed(Lang::Japanese),
+                "Japanese",
+            ),
+        ] {
+            let mut system = String::new();
+            enhance_note_field(
+                &client,
+                async |messages| {
+                    system = messages[0].content.clone();
+                    Ok("x".to_string())
+                },
+                7,
+                field,
+                "Polish this {{language}} text.",
+                language,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                system,
+                format!("Polish this {} text.", expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nothing_written_on_failure() {
+        let mock = anki().await;
+        let client = mock.client();
+        let missing = enhance_note_field(
+            &client,
+            async |_| Ok("x".to_string()),
+            7,
+            "Extra",
+            "Rewrite.",
+            LanguageOption::Auto,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            missing.to_string().contains("no field Extra")
+        );
+
+        let empty = enhance_note_field(
+            &client,
+            async |_| Ok("  ".to_string()),
+            7,
+            "Back",
+            "Rewrite.",
+            LanguageOption::Auto,
+        )
+        .await
+        .unwrap_err();
+        assert!(empty.to_string().contains("empty Back"));
+
+        assert!(
+            enhance_note_field(
+                &client,
+                async |_| anyhow::bail!("quota"),
+                7,
+                "Back",
+                "Rewrite.",
+                LanguageOption::Auto,
+            )
+            .await
+            .is_err()
+        );
+        assert!(
+            mock.actions().iter().all(|a| a == "notesInfo")
+        );
+    }
+}
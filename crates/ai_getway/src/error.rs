@@ -0,0 +1,140 @@
+//! Errors shared by the provider implementations
+
+use std::fmt;
+
+/// 服务商返回的非成功HTTP响应
+///
+/// 作为 `anyhow::Error` 的根因返回，调用方可以通过
+/// `error.downcast_ref::<HttpStatusError>()` 获取状态码，
+/// 用于区分配额、认证等错误。
+///
+/// # 字段
+/// - `status`: HTTP状态码
+/// - `message`: 已格式化的错误信息（包含服务商返回的错误体）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl HttpStatusError {
+    /// Whether the provider refused the key for quota or rate
+    /// limiting reasons
+    pub fn is_quota(&self) -> bool {
+        self.status == 429
+    }
+
+    /// Whether the provider rejected the key itself
+    pub fn is_auth(&self) -> bool {
+        matches!(self.status, 401 | 403)
+    }
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// 流式响应在中途断开
+///
+/// 连接建立之后、响应结束之前断开时作为 `anyhow::Error` 的根因返回，
+/// 与连接失败和 [`HttpStatusError`] 区分开，调用方可以据此决定是否
+/// 重试，或保留已收到的内容。
+///
+/// # 字段
+/// - `message`: 断开原因
+/// - `partial`: 断开前已产出的文本
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInterrupted {
+    pub message: String,
+    pub partial: String,
+}
+
+impl StreamInterrupted {
+    /// Whether `error` is caused by a mid-stream disconnect
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<Self>().is_some()
+    }
+}
+
+impl fmt::Display for StreamInterrupted {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "{} (after {} characters)",
+            self.message,
+            self.partial.chars().count()
+        )
+    }
+}
+
+impl std::error::Error for StreamInterrupted {}
+
+/// 模型输出在修复次数用完后仍不符合要求
+///
+/// 由 [`crate::structured::generate_cards`] 作为 `anyhow::Error` 的
+/// 根因返回。
+///
+/// # 字段
+/// - `attempts`: 调用模型的次数
+/// - `issues`: 最后一次输出的校验问题
+/// - `output`: 最后一次输出的原文
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidStructuredOutput {
+    pub attempts: u32,
+    pub issues: Vec<String>,
+    pub output: String,
+}
+
+impl fmt::Display for InvalidStructuredOutput {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "Model output still invalid after {} attempts: {}",
+            self.attempts,
+            self.issues.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidStructuredOutput {}
+
+/// 批量调用被取消，请求没有发出
+///
+/// [`crate::models::zhi_pu::ZhiPuClient::complete_batch`] 中取消之后
+/// 尚未开始的请求以它为根因返回，与请求失败区分开，调用方可以只
+/// 重新提交这些请求。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotAttempted;
+
+impl NotAttempted {
+    /// Whether `error` marks a request skipped after cancellation
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<Self>().is_some()
+    }
+}
+
+impl fmt::Display for NotAttempted {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(
+            "Request not attempted because the batch was cancelled",
+        )
+    }
+}
+
+impl std::error::Error for NotAttempted {}
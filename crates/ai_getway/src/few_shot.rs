@@ -0,0 +1,394 @@
+//! 生成提示词中的示例（few-shot）
+//!
+//! 每个示例是一轮用户输入和一轮助手回复，回复正是
+//! [`CardSpec::parse`] 接受的 JSON，模型因此能直接看到期望的输出
+//! 格式。示例可以直接给出，也可以从 Anki 中已有的笔记（如带
+//! [`EXEMPLAR_TAG`] 标签的笔记）读取。提示词超出 token 预算时先从
+//! 末尾删减示例，真正的请求不会被截断。
+
+use anki_connect::anki::client::{AnkiClient, NoteInfo};
+use anki_connect::convert::strip_html;
+
+use crate::chat::ChatMessage;
+use crate::structured::{
+    CardSpec, GeneratedCard, GeneratedCardSet,
+};
+use crate::tokens::estimate_message_tokens;
+
+/// 约定用来标记示例笔记的标签，读取时不会写入示例卡片
+pub const EXEMPLAR_TAG: &str = "exemplar";
+
+/// 一个示例
+///
+/// # 字段
+/// - `prompt`: 示例的用户输入
+/// - `cards`: 期望模型对该输入给出的卡片
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FewShotExample {
+    pub prompt: String,
+    pub cards: GeneratedCardSet,
+}
+
+impl FewShotExample {
+    /// 以卡片正面作为用户输入的单卡示例
+    pub fn from_card(card: GeneratedCard) -> Self {
+        Self {
+            prompt: card.front.clone(),
+            cards: GeneratedCardSet { cards: vec![card] },
+        }
+    }
+}
+
+/// 一组示例及其 token 预算
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FewShotExamples {
+    examples: Vec<FewShotExample>,
+    max_prompt_tokens: Option<usize>,
+}
+
+impl FewShotExamples {
+    /// 按给定顺序使用示例，越靠前越晚被删减
+    pub fn new(examples: Vec<FewShotExample>) -> Self {
+        Self {
+            examples,
+            max_prompt_tokens: None,
+        }
+    }
+
+    /// 每张卡片作为一个示例，见 [`FewShotExample::from_card`]
+    pub fn from_cards(
+        cards: impl IntoIterator<Item = GeneratedCard>,
+    ) -> Self {
+        Self::new(
+            cards
+                .into_iter()
+                .map(FewShotExample::from_card)
+                .collect(),
+        )
+    }
+
+    /// 从 Anki 中读取示例
+    ///
+    /// 每条笔记成为一个单卡示例：第一个字段作为正面，第二个作为
+    /// 背面，其余非空字段以小写字段名写入 `extra`，字段内容去掉
+    /// HTML；标签中的 [`EXEMPLAR_TAG`] 会被去掉。少于两个字段的
+    /// 笔记被跳过。
+    ///
+    /// # 参数
+    /// - `anki_client`: Anki 客户端
+    /// - `query`: 搜索语句，如 `tag:exemplar`
+    /// - `max`: 最多读取的笔记数
+    pub async fn from_anki(
+        anki_client: &AnkiClient,
+        query: &str,
+        max: usize,
+    ) -> anyhow::Result<Self> {
+        let notes = anki_client
+            .find_notes_detailed(query, Some(max))
+            .await?;
+        Ok(Self::from_cards(
+            notes.iter().filter_map(note_to_card),
+        ))
+    }
+
+    /// 设置整个首次请求（系统提示词、示例和用户输入）的 token 上限
+    pub fn max_prompt_tokens(
+        mut self,
+        tokens: usize,
+    ) -> Self {
+        self.max_prompt_tokens = Some(tokens);
+        self
+    }
+
+    /// 全部示例
+    pub fn examples(&self) -> &[FewShotExample] {
+        &self.examples
+    }
+
+    /// 构造首次请求的完整对话
+    ///
+    /// 顺序为系统提示词、各示例的用户/助手消息、`prompt`。示例中
+    /// `spec` 未声明的额外字段会被去掉，仍不能通过
+    /// [`CardSpec::parse`] 的示例被跳过；超出 token 预算时从末尾
+    /// 删减示例，删完后仍超出时照常返回。
+    pub fn messages(
+        &self,
+        spec: &CardSpec,
+        prompt: &str,
+    ) -> Vec<ChatMessage> {
+        let mut turns: Vec<[ChatMessage; 2]> = self
+            .examples
+            .iter()
+            .filter_map(|example| {
+                example_turns(spec, example)
+            })
+            .collect();
+        let system =
+            ChatMessage::system(spec.system_prompt());
+        let request = ChatMessage::user(prompt);
+        if let Some(budget) = self.max_prompt_tokens {
+            let fixed = estimate_message_tokens(&[
+                system.clone(),
+                request.clone(),
+            ]);
+            while !turns.is_empty()
+                && fixed
+                    + estimate_message_tokens(
+                        turns.as_flattened(),
+                    )
+                    > budget
+            {
+                turns.pop();
+            }
+            if turns.len() < self.examples.len() {
+                log::debug!(
+                    "Using {} of {} few-shot examples within {} tokens",
+                    turns.len(),
+                    self.examples.len(),
+                    budget
+                );
+            }
+        }
+
+        let mut messages = vec![system];
+        messages.extend(turns.into_iter().flatten());
+        messages.push(request);
+        messages
+    }
+}
+
+/// The user and assistant turns of one example, or `None` when its
+/// cards do not satisfy `spec`
+fn example_turns(
+    spec: &CardSpec,
+    example: &FewShotExample,
+) -> Option<[ChatMessage; 2]> {
+    let mut cards = example.cards.clone();
+    for card in &mut cards.cards {
+        card.extra
+            .retain(|name, _| spec.expects_extra(name));
+    }
+    let reply = serde_json::to_string(&cards).ok()?;
+    if let Err(issues) = spec.parse(&reply) {
+        log::warn!(
+            "Skipping few-shot example {:?}: {}",
+            example.prompt,
+            issues.join("; ")
+        );
+        return None;
+    }
+    Some([
+        ChatMessage::user(example.prompt.clone()),
+        ChatMessage::assistant(reply),
+    ])
+}
+
+fn note_to_card(note: &NoteInfo) -> Option<GeneratedCard> {
+    let fields = note.ordered_fields();
+    let [(_, front), (_, back), rest @ ..] =
+        fields.as_slice()
+    else {
+        return None;
+    };
+    Some(GeneratedCard {
+        front: strip_html(front),
+        back: strip_html(back),
+        tags: note
+            .tags
+            .iter()
+            .filter(|tag| *tag != EXEMPLAR_TAG)
+            .cloned()
+            .collect(),
+        extra: rest
+            .iter()
+            .map(|(name, value)| {
+                (name.to_lowercase(), strip_html(value))
+            })
+            .filter(|(_, value)| !value.is_empty())
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use anki_connect::anki::mock::{MockAnki, ok};
+    use serde_json::json;
+
+    use super::*;
+    use crate::chat::Role;
+    use crate::structured::generate_cards_with_examples;
+
+    fn spec() -> CardSpec {
+        CardSpec::new(
+            "Create one vocabulary card per word.",
+        )
+        .extra_field(
+            "reading",
+            "Kana reading",
+            true,
+        )
+    }
+
+    fn card(front: &str, back: &str) -> GeneratedCard {
+        GeneratedCard {
+            front: front.to_string(),
+            back: back.to_string(),
+            tags: Vec::new(),
+            extra: HashMap::from([(
+                "reading".to_string(),
+                front.to_string(),
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_examples_come_between_system_and_request() {
+        let examples = FewShotExamples::from_cards([
+            card("猫", "cat"),
+            card("犬", "dog"),
+        ]);
+        let messages = examples.messages(&spec(), "鳥");
+        let roles: Vec<Role> = messages
+            .iter()
+            .map(|message| message.role.clone())
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                Role::System,
+                Role::User,
+                Role::Assistant,
+                Role::User,
+                Role::Assistant,
+                Role::User,
+            ]
+        );
+        assert_eq!(messages[1].content, "猫");
+        assert_eq!(messages[3].content, "犬");
+        assert_eq!(messages[5].content, "鳥");
+        // Replies are exactly what the parser accepts
+        assert_eq!(
+            spec().parse(&messages[2].content).unwrap(),
+            GeneratedCardSet {
+                cards: vec![card("猫", "cat")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_examples_are_skipped() {
+        let mut noisy = card("猫", "cat");
+        noisy.extra.insert(
+            "mnemonic".to_string(),
+            "x".to_string(),
+        );
+        let mut missing = card("犬", "dog");
+        missing.extra.clear();
+        let messages =
+            FewShotExamples::from_cards([noisy, missing])
+                .messages(&spec(), "鳥");
+        assert_eq!(messages.len(), 4);
+        assert!(!messages[2].content.contains("mnemonic"));
+    }
+
+    #[test]
+    fn test_examples_are_trimmed_to_budget() {
+        let examples = FewShotExamples::from_cards([
+            card("猫", "cat"),
+            card("犬", "dog"),
+            card("鳥", "bird"),
+        ]);
+        let spec = spec();
+        let all = examples.messages(&spec, "魚");
+        let full = estimate_message_tokens(&all);
+
+        let trimmed = examples
+            .clone()
+            .max_prompt_tokens(full - 1)
+            .messages(&spec, "魚");
+        assert_eq!(trimmed.len(), all.len() - 2);
+        assert_eq!(trimmed[3].content, "犬");
+        assert_eq!(trimmed.last(), all.last());
+
+        // The request itself is never cut
+        let none = examples
+            .max_prompt_tokens(1)
+            .messages(&spec, "魚");
+        assert_eq!(none.len(), 2);
+        assert_eq!(none[1].content, "魚");
+    }
+
+    #[tokio::test]
+    async fn test_examples_from_anki() {
+        let mock = MockAnki::start(|action, _| match action {
+            "findNotes" => ok(json!([1, 2])),
+            "notesInfo" => ok(json!([
+                {
+                    "noteId": 1,
+                    "modelName": "Vocab",
+                    "tags": ["exemplar", "noun"],
+                    "fields": {
+                        "Word": {"value": "<b>猫</b>", "order": 0},
+                        "Meaning": {"value": "cat", "order": 1},
+                        "Reading": {"value": "ねこ", "order": 2},
+                        "Notes": {"value": "", "order": 3},
+                    },
+                    "cards": [],
+                },
+                {
+                    "noteId": 2,
+                    "modelName": "Single",
+                    "tags": ["exemplar"],
+                    "fields": {
+                        "Text": {"value": "犬", "order": 0},
+                    },
+                    "cards": [],
+                },
+            ])),
+            other => panic!("unexpected action {}", other),
+        })
+        .await;
+
+        let examples = FewShotExamples::from_anki(
+            &mock.client(),
+            "tag:exemplar",
+            5,
+        )
+        .await
+        .unwrap();
+        let mut expected = card("猫", "cat");
+        expected.extra.insert(
+            "reading".to_string(),
+            "ねこ".to_string(),
+        );
+        expected.tags = vec!["noun".to_string()];
+        assert_eq!(
+            examples.examples(),
+            [FewShotExample::from_card(expected)]
+        );
+        assert_eq!(
+            mock.requests()[0]["params"]["query"],
+            "tag:exemplar"
+        );
+
+        let mut seen = Vec::new();
+        let cards = generate_cards_with_examples(
+            &spec(),
+            "鳥",
+            &examples,
+            |messages| {
+                seen.push(messages);
+                async {
+                    Ok(r#"{"cards": [{"front": "鳥", "back": "bird", "extra": {"reading": "とり"}}]}"#.to_string())
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(cards.cards[0].back, "bird");
+        assert_eq!(seen[0].len(), 4);
+        assert!(seen[0][2].content.contains("ねこ"));
+    }
+}
@@ -0,0 +1,427 @@
+//! 同一服务商多个API密钥的轮换池
+
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use utils::config::secret::Secret;
+
+use crate::error::HttpStatusError;
+
+/// Cool-down applied when a key hits a quota or auth error
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// State of one key inside the pool
+#[derive(Debug)]
+struct KeyState {
+    key: Secret<String>,
+    cooling_until: Option<Instant>,
+    reason: Option<String>,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    keys: Vec<KeyState>,
+    next: usize,
+}
+
+/// API密钥池
+///
+/// 以轮询方式分发密钥；当某个密钥遇到配额或认证错误时进入冷却期，
+/// 冷却期内不会再被分发。池的状态在克隆之间共享，可在多个线程和
+/// 多个克隆出的服务商实例之间安全使用。
+#[derive(Debug, Clone)]
+pub struct KeyPool {
+    state: Arc<Mutex<PoolState>>,
+    cooldown: Duration,
+}
+
+/// 所有密钥都不可用时返回的错误
+///
+/// # 字段
+/// - `statuses`: 每个密钥（已脱敏）的状态描述
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysExhausted {
+    pub statuses: Vec<String>,
+}
+
+impl fmt::Display for KeysExhausted {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "All API keys exhausted: {}",
+            self.statuses.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for KeysExhausted {}
+
+impl KeyPool {
+    /// 创建密钥池
+    ///
+    /// # 参数
+    /// - `keys`: 按优先顺序排列的密钥，空白项会被忽略
+    pub fn new<I, S>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<Secret<String>>,
+    {
+        let keys = keys
+            .into_iter()
+            .map(Into::into)
+            .filter(|key: &Secret<String>| {
+                !key.expose().trim().is_empty()
+            })
+            .map(|key| KeyState {
+                key,
+                cooling_until: None,
+                reason: None,
+            })
+            .collect();
+        Self {
+            state: Arc::new(Mutex::new(PoolState {
+                keys,
+                next: 0,
+            })),
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+
+    /// 设置密钥遇到配额错误后的冷却时长
+    pub fn with_cooldown(
+        mut self,
+        cooldown: Duration,
+    ) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// 池中的密钥数量
+    pub fn len(&self) -> usize {
+        self.lock().keys.len()
+    }
+
+    /// 池中是否没有任何密钥
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 轮询获取下一个可用密钥
+    ///
+    /// # 返回
+    /// 没有可用密钥时返回 [`KeysExhausted`]，其中列出每个密钥的状态。
+    pub fn acquire(
+        &self,
+    ) -> anyhow::Result<Secret<String>> {
+        self.acquire_at(Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        now: Instant,
+    ) -> anyhow::Result<Secret<String>> {
+        let mut state = self.lock();
+        let count = state.keys.len();
+        for offset in 0..count {
+            let index = (state.next + offset) % count;
+            let entry = &mut state.keys[index];
+            if entry.cooling_until.is_some_and(|t| t > now)
+            {
+                continue;
+            }
+            entry.cooling_until = None;
+            entry.reason = None;
+            let key = entry.key.clone();
+            state.next = (index + 1) % count;
+            return Ok(key);
+        }
+        Err(KeysExhausted {
+            statuses: describe(&state.keys, now),
+        }
+        .into())
+    }
+
+    /// 将密钥标记为冷却中
+    ///
+    /// # 参数
+    /// - `key`: 出错的密钥
+    /// - `reason`: 冷却原因，会出现在 [`KeysExhausted`] 中
+    pub fn cool_down(
+        &self,
+        key: &Secret<String>,
+        reason: &str,
+    ) {
+        let until = Instant::now() + self.cooldown;
+        let mut state = self.lock();
+        if let Some(entry) =
+            state.keys.iter_mut().find(|e| &e.key == key)
+        {
+            log::warn!(
+                "API key {} cooling down for {:?}: {}",
+                mask(key),
+                self.cooldown,
+                reason
+            );
+            entry.cooling_until = Some(until);
+            entry.reason = Some(reason.to_string());
+        }
+    }
+
+    /// 每个密钥（已脱敏）的当前状态
+    pub fn statuses(&self) -> Vec<String> {
+        describe(&self.lock().keys, Instant::now())
+    }
+
+    /// 使用池中的密钥执行调用，在配额或认证错误时自动切换密钥。
+    ///
+    /// 遇到配额错误（HTTP 429）或认证错误（HTTP 401/403）时，当前密钥
+    /// 进入冷却期并使用下一个密钥重试；其他错误直接返回。
+    ///
+    /// # 参数
+    /// - `call`: 接收密钥并发起请求的闭包
+    ///
+    /// # 返回
+    /// 第一次成功的结果；所有密钥都不可用时返回 [`KeysExhausted`]。
+    pub async fn run<T, F, Fut>(
+        &self,
+        mut call: F,
+    ) -> anyhow::Result<T>
+    where
+        F: FnMut(Secret<String>) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        loop {
+            let key = self.acquire()?;
+            match call(key.clone()).await {
+                Ok(value) => return Ok(value),
+                Err(e) => match cool_down_reason(&e) {
+                    Some(reason) => {
+                        self.cool_down(&key, reason)
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|poisoned| {
+            poisoned.into_inner()
+        })
+    }
+}
+
+/// Why an error should put its key on cool-down, if it should
+fn cool_down_reason(
+    error: &anyhow::Error,
+) -> Option<&'static str> {
+    let http = error.downcast_ref::<HttpStatusError>()?;
+    if http.is_quota() {
+        Some("quota or rate limit exceeded")
+    } else if http.is_auth() {
+        Some("key rejected")
+    } else {
+        None
+    }
+}
+
+/// Shows only the last four characters of a key
+fn mask(key: &Secret<String>) -> String {
+    let chars: Vec<char> = key.expose().chars().collect();
+    let tail: String = chars
+        [chars.len().saturating_sub(4)..]
+        .iter()
+        .collect();
+    format!("…{}", tail)
+}
+
+fn describe(
+    keys: &[KeyState],
+    now: Instant,
+) -> Vec<String> {
+    if keys.is_empty() {
+        return vec!["no keys configured".to_string()];
+    }
+    keys.iter()
+        .map(|entry| match entry.cooling_until {
+            Some(until) if until > now => format!(
+                "{} cooling down for {}s ({})",
+                mask(&entry.key),
+                (until - now).as_secs_f32().ceil() as u64,
+                entry
+                    .reason
+                    .as_deref()
+                    .unwrap_or("unknown")
+            ),
+            _ => format!("{} available", mask(&entry.key)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn quota_error() -> anyhow::Error {
+        HttpStatusError {
+            status: 429,
+            message: "ZhiPu API error: quota".to_string(),
+        }
+        .into()
+    }
+
+    /// Mock provider call: keys in `exhausted` hit quota errors
+    fn mock_call(
+        exhausted: &'static [&'static str],
+        calls: Arc<Mutex<Vec<String>>>,
+    ) -> impl FnMut(
+        Secret<String>,
+    ) -> std::future::Ready<
+        anyhow::Result<String>,
+    > {
+        move |key| {
+            let key = key.expose().clone();
+            calls.lock().unwrap().push(key.clone());
+            std::future::ready(
+                if exhausted.contains(&key.as_str()) {
+                    Err(quota_error())
+                } else {
+                    Ok(format!("answer from {}", key))
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = KeyPool::new(["key-a", "key-b", " "]);
+        assert_eq!(pool.len(), 2);
+        let keys: Vec<String> = (0..4)
+            .map(|_| {
+                pool.acquire().unwrap().expose().clone()
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            ["key-a", "key-b", "key-a", "key-b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotates_to_next_key_on_quota_error() {
+        let pool = KeyPool::new(["key-a", "key-b"]);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let answer = pool
+            .run(mock_call(&["key-a"], calls.clone()))
+            .await
+            .unwrap();
+        assert_eq!(answer, "answer from key-b");
+
+        // key-a is cooling down, so key-b keeps serving
+        pool.run(mock_call(&["key-a"], calls.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["key-a", "key-b", "key-b"]
+        );
+        assert!(
+            pool.statuses()[0].contains("cooling down")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_all_keys_exhausted() {
+        let pool = KeyPool::new(["key-a", "key-b"]);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let error = pool
+            .run(mock_call(
+                &["key-a", "key-b"],
+                calls.clone(),
+            ))
+            .await
+            .unwrap_err();
+        let exhausted =
+            error.downcast_ref::<KeysExhausted>().unwrap();
+        assert_eq!(exhausted.statuses.len(), 2);
+        assert!(error.to_string().contains(
+            "…ey-a cooling down for 60s (quota or rate limit exceeded)"
+        ));
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cool_down_expires() {
+        let pool = KeyPool::new(["key-a"])
+            .with_cooldown(Duration::from_millis(20));
+        pool.cool_down(&Secret::from("key-a"), "quota");
+        assert!(pool.acquire().is_err());
+        assert!(
+            pool.acquire_at(
+                Instant::now() + Duration::from_millis(50)
+            )
+            .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_do_not_rotate() {
+        let pool = KeyPool::new(["key-a", "key-b"]);
+        let attempts = AtomicUsize::new(0);
+        let result: anyhow::Result<()> = pool
+            .run(|_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                std::future::ready(Err(anyhow::anyhow!(
+                    "ZhiPu API network error"
+                )))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_clones_share_state() {
+        let pool = KeyPool::new(["key-a", "key-b"]);
+        let clone = pool.clone();
+        clone.cool_down(&Secret::from("key-a"), "quota");
+        assert_eq!(
+            pool.acquire().unwrap().expose(),
+            "key-b"
+        );
+        assert_eq!(
+            clone.acquire().unwrap().expose(),
+            "key-b"
+        );
+    }
+
+    #[test]
+    fn test_debug_output_hides_keys() {
+        let pool = KeyPool::new(["sk-secret-key"]);
+        let debug = format!("{:?}", pool);
+        assert!(!debug.contains("sk-secret"), "{}", debug);
+        assert!(
+            !pool.statuses().concat().contains("sk-secret")
+        );
+    }
+
+    #[test]
+    fn test_empty_pool_is_exhausted() {
+        let error = KeyPool::new(Vec::<String>::new())
+            .acquire()
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("no keys configured")
+        );
+    }
+}
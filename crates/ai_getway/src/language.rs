@@ -0,0 +1,514 @@
+//! 判断一段文本的语言
+//!
+//! 卡组中日文、中文、英文内容混杂，改写字段时需要知道字段的语言才能
+//! 选用合适的提示词。[`detect_language`] 只按字符所属的文字判断，不调用
+//! 接口；对很短、难以区分的文本（如只有两个汉字），可以再用
+//! [`detect_language_with_model`] 让模型判断。
+
+use crate::chat::ChatMessage;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+
+/// 达到这么多个计数单位后，长度不再降低置信度
+const CONFIDENT_UNITS: usize = 8;
+
+/// 采用模型判断结果时给出的置信度
+const MODEL_CONFIDENCE: f32 = 0.9;
+
+/// 汉字中假名至少占这个比例才判为日文，避免中文里偶尔出现的
+/// “の”之类被误判
+const MIN_KANA_SHARE: f32 = 0.05;
+
+/// 常见的英文虚词
+const ENGLISH_WORDS: &[&str] = &[
+    "the", "a", "an", "and", "of", "to", "in", "is", "are",
+    "was", "it", "that", "this", "with", "for", "on",
+    "you", "i", "be", "not", "have", "has", "what", "how",
+];
+
+/// 其他拉丁字母语言（法、德、西、意、葡、荷等）的常见虚词
+const OTHER_LATIN_WORDS: &[&str] = &[
+    "le", "la", "les", "des", "et", "est", "une", "der",
+    "die", "das", "und", "ist", "ein", "eine", "nicht",
+    "el", "los", "las", "y", "es", "que", "por", "il",
+    "di", "che", "è", "e", "os", "não", "het", "een",
+    "van", "ich", "je",
+];
+
+/// 可识别的语言
+///
+/// 拉丁字母书写的非英语语言统一归为 [`Lang::OtherLatin`]。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lang {
+    Japanese,
+    Chinese,
+    Korean,
+    English,
+    OtherLatin,
+    Unknown,
+}
+
+impl Lang {
+    /// ISO 639-1 代码，可用于选择朗读语音；无法确定具体语言时为
+    /// `None`
+    pub fn code(self) -> Option<&'static str> {
+        match self {
+            Lang::Japanese => Some("ja"),
+            Lang::Chinese => Some("zh"),
+            Lang::Korean => Some("ko"),
+            Lang::English => Some("en"),
+            Lang::OtherLatin | Lang::Unknown => None,
+        }
+    }
+
+    /// 填入提示词 `{{language}}` 的英文名称
+    pub fn name(self) -> &'static str {
+        match self {
+            Lang::Japanese => "Japanese",
+            Lang::Chinese => "Chinese",
+            Lang::Korean => "Korean",
+            Lang::English => "English",
+            Lang::OtherLatin | Lang::Unknown => {
+                "the same language as the text"
+            }
+        }
+    }
+
+    /// 由 ISO 639-1 代码得到语言，忽略大小写和地区后缀（如 `zh-CN`）
+    pub fn from_code(code: &str) -> Lang {
+        let code = code.trim().to_ascii_lowercase();
+        let primary = code
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default();
+        match primary {
+            "ja" => Lang::Japanese,
+            "zh" => Lang::Chinese,
+            "ko" => Lang::Korean,
+            "en" => Lang::English,
+            "fr" | "de" | "es" | "it" | "pt" | "nl"
+            | "sv" | "da" | "no" | "nb" | "fi" | "pl"
+            | "cs" | "ro" | "hu" | "tr" | "id" | "ms"
+            | "vi" | "la" => Lang::OtherLatin,
+            _ => Lang::Unknown,
+        }
+    }
+}
+
+/// 语言判断结果
+///
+/// # 字段
+/// - `lang`: 判断出的语言
+/// - `confidence`: 置信度，0 到 1；文本越短、各文字混杂越多越低
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LangGuess {
+    pub lang: Lang,
+    pub confidence: f32,
+}
+
+/// 流程中的语言选项：指定语言，或按内容自动判断
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LanguageOption {
+    #[default]
+    Auto,
+    Fixed(Lang),
+}
+
+impl LanguageOption {
+    /// 得到 `text` 使用的语言；[`LanguageOption::Auto`] 时调用
+    /// [`detect_language`]
+    pub fn resolve(self, text: &str) -> Lang {
+        match self {
+            LanguageOption::Auto => {
+                detect_language(text).lang
+            }
+            LanguageOption::Fixed(lang) => lang,
+        }
+    }
+}
+
+/// 按字符所属的文字判断文本的语言，不调用接口
+///
+/// 每个汉字、假名或谚文字符计一个单位，每个拉丁字母单词计一个单位，
+/// 取单位最多的文字：
+/// - 含假名的汉字文本为日文，只有汉字的为中文。短的纯汉字文本在
+///   中日文中都可能出现，置信度较低；
+/// - 谚文为韩文；
+/// - 拉丁字母按常见虚词和变音字母区分英文与其他语言。
+///
+/// # 返回
+/// 没有任何文字（如只有数字和标点）时为置信度 0 的
+/// [`Lang::Unknown`]。
+pub fn detect_language(text: &str) -> LangGuess {
+    let counts = ScriptCounts::of(text);
+    let han_kana = counts.han + counts.kana;
+    let total =
+        han_kana + counts.hangul + counts.latin_words;
+    if total == 0 {
+        return LangGuess {
+            lang: Lang::Unknown,
+            confidence: 0.0,
+        };
+    }
+
+    let (lang, units) = if han_kana >= counts.hangul
+        && han_kana >= counts.latin_words
+    {
+        let kana_share =
+            counts.kana as f32 / han_kana as f32;
+        if counts.kana > 0
+            && (kana_share >= MIN_KANA_SHARE
+                || counts.han == 0)
+        {
+            (Lang::Japanese, han_kana)
+        } else {
+            // Kanji alone cannot tell Japanese from Chinese, so a
+            // pure-Han string counts for half as much
+            (Lang::Chinese, han_kana.div_ceil(2))
+        }
+    } else if counts.hangul >= counts.latin_words {
+        (Lang::Korean, counts.hangul)
+    } else {
+        latin_language(&counts)
+    };
+
+    let dominance = match lang {
+        Lang::Japanese | Lang::Chinese => han_kana,
+        Lang::Korean => counts.hangul,
+        _ => counts.latin_words,
+    } as f32
+        / total as f32;
+    let length = (units.min(CONFIDENT_UNITS) as f32)
+        / CONFIDENT_UNITS as f32;
+    LangGuess {
+        lang,
+        confidence: dominance * (0.3 + 0.7 * length),
+    }
+}
+
+/// 先用 [`detect_language`] 判断，置信度低于 `min_confidence` 时再
+/// 让模型判断
+///
+/// # 参数
+/// - `complete`: 发送完整对话并返回模型回复的函数
+/// - `text`: 要判断的文本
+/// - `min_confidence`: 低于此置信度时才调用模型
+///
+/// # 返回
+/// 调用失败时返回错误；模型的回复无法识别时返回本地判断结果。
+pub async fn detect_language_with_model(
+    complete: impl AsyncFnOnce(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    text: &str,
+    min_confidence: f32,
+) -> anyhow::Result<LangGuess> {
+    let guess = detect_language(text);
+    if guess.confidence >= min_confidence {
+        return Ok(guess);
+    }
+
+    let reply = complete(vec![
+        ChatMessage::system(
+            "Identify the language of the user's text. Reply with only its ISO 639-1 code, such as ja, zh, ko or en.",
+        ),
+        ChatMessage::user(text),
+    ])
+    .await?;
+    let code = reply
+        .trim()
+        .trim_matches(|c: char| !c.is_ascii_alphanumeric());
+    Ok(match Lang::from_code(code) {
+        Lang::Unknown => guess,
+        lang => LangGuess {
+            lang,
+            confidence: MODEL_CONFIDENCE,
+        },
+    })
+}
+
+/// 使用智谱客户端执行 [`detect_language_with_model`]
+pub async fn detect_language_with_zhi_pu(
+    client: &ZhiPuClient,
+    text: &str,
+    min_confidence: f32,
+) -> anyhow::Result<LangGuess> {
+    detect_language_with_model(
+        async |messages| {
+            let request = ZhiPuRequest::new(
+                messages
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            );
+            client.complete(request).await?.into_content()
+        },
+        text,
+        min_confidence,
+    )
+    .await
+}
+
+/// Characters per script, with Latin text counted in words
+#[derive(Default)]
+struct ScriptCounts {
+    han: usize,
+    kana: usize,
+    hangul: usize,
+    latin_words: usize,
+    english_hits: usize,
+    other_hits: usize,
+    accented_words: usize,
+}
+
+impl ScriptCounts {
+    fn of(text: &str) -> Self {
+        let mut counts = ScriptCounts::default();
+        for c in text.chars() {
+            match c {
+                '\u{3040}'..='\u{30FF}'
+                | '\u{31F0}'..='\u{31FF}'
+                | '\u{FF66}'..='\u{FF9F}' => {
+                    counts.kana += 1
+                }
+                '\u{3400}'..='\u{4DBF}'
+                | '\u{4E00}'..='\u{9FFF}'
+                | '\u{F900}'..='\u{FAFF}' => {
+                    counts.han += 1
+                }
+                '\u{1100}'..='\u{11FF}'
+                | '\u{3130}'..='\u{318F}'
+                | '\u{AC00}'..='\u{D7AF}' => {
+                    counts.hangul += 1
+                }
+                _ => {}
+            }
+        }
+
+        for word in text
+            .split(|c: char| {
+                !is_latin_letter(c) && c != '\''
+            })
+            .map(|word| word.trim_matches('\''))
+            .filter(|word| !word.is_empty())
+        {
+            counts.latin_words += 1;
+            let lower = word.to_lowercase();
+            if ENGLISH_WORDS.contains(&lower.as_str()) {
+                counts.english_hits += 1;
+            }
+            if OTHER_LATIN_WORDS.contains(&lower.as_str()) {
+                counts.other_hits += 1;
+            }
+            if !word.is_ascii() {
+                counts.accented_words += 1;
+            }
+        }
+        counts
+    }
+}
+
+/// English unless function words or accented letters point to
+/// another Latin-script language; bare ASCII words without function
+/// words lean English but count for less
+fn latin_language(counts: &ScriptCounts) -> (Lang, usize) {
+    let words = counts.latin_words;
+    let other = counts.other_hits + counts.accented_words;
+    if other > counts.english_hits {
+        (Lang::OtherLatin, words)
+    } else if counts.english_hits > 0 {
+        (Lang::English, words)
+    } else {
+        (Lang::English, words.div_ceil(2))
+    }
+}
+
+fn is_latin_letter(c: char) -> bool {
+    c.is_ascii_alphabetic()
+        || (matches!(c, '\u{00C0}'..='\u{024F}')
+            && c != '\u{00D7}'
+            && c != '\u{00F7}')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lang(text: &str) -> Lang {
+        detect_language(text).lang
+    }
+
+    #[test]
+    fn test_short_samples() {
+        assert_eq!(lang("ねこ"), Lang::Japanese);
+        assert_eq!(lang("カメラ"), Lang::Japanese);
+        assert_eq!(lang("食べる"), Lang::Japanese);
+        assert_eq!(lang("猫"), Lang::Chinese);
+        assert_eq!(lang("你好"), Lang::Chinese);
+        assert_eq!(lang("안녕"), Lang::Korean);
+        assert_eq!(lang("the cat"), Lang::English);
+        assert_eq!(lang("apple"), Lang::English);
+        assert_eq!(lang("le chat"), Lang::OtherLatin);
+        assert_eq!(lang("café"), Lang::OtherLatin);
+        assert_eq!(
+            detect_language("123, 456!"),
+            LangGuess {
+                lang: Lang::Unknown,
+                confidence: 0.0
+            }
+        );
+        assert_eq!(lang(""), Lang::Unknown);
+    }
+
+    #[test]
+    fn test_long_samples() {
+        let samples = [
+            (
+                "吾輩は猫である。名前はまだ無い。どこで生れたかとんと見当がつかぬ。",
+                Lang::Japanese,
+            ),
+            (
+                "我们今天去图书馆学习，晚上一起吃饭。这个问题很难回答。",
+                Lang::Chinese,
+            ),
+            (
+                "오늘은 날씨가 정말 좋네요. 같이 공원에 산책하러 갈까요?",
+                Lang::Korean,
+            ),
+            (
+                "The quick brown fox jumps over the lazy dog and runs into the forest.",
+                Lang::English,
+            ),
+            (
+                "Der schnelle braune Fuchs springt über den faulen Hund und ist weg.",
+                Lang::OtherLatin,
+            ),
+            (
+                "El zorro marrón salta sobre el perro perezoso y se va por el bosque.",
+                Lang::OtherLatin,
+            ),
+        ];
+        for (text, expected) in samples {
+            let guess = detect_language(text);
+            assert_eq!(guess.lang, expected, "{}", text);
+            assert!(guess.confidence > 0.8, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_kana_decides_japanese_vs_chinese() {
+        // Identical Han core; a single particle makes it Japanese
+        assert_eq!(lang("東京大学"), Lang::Chinese);
+        assert_eq!(lang("東京大学の学生"), Lang::Japanese);
+        // A stray の inside a long Chinese text does not
+        assert_eq!(
+            lang(
+                "我的朋友在北京大学学习中国历史和文学，他说这里の风景非常美丽，我们下个月一起去旅行。"
+            ),
+            Lang::Chinese
+        );
+        // Short pure-Han text is ambiguous and says so
+        assert!(detect_language("学生").confidence < 0.5);
+        assert!(
+            detect_language("我们今天去图书馆学习")
+                .confidence
+                > 0.7
+        );
+    }
+
+    #[test]
+    fn test_mixed_strings() {
+        // The dominant script wins, with lower confidence
+        let guess = detect_language(
+            "ラーメンが大好きです (I love ramen)",
+        );
+        assert_eq!(guess.lang, Lang::Japanese);
+        assert!(guess.confidence < 0.9);
+        assert_eq!(
+            lang("The word 猫 means cat in this sentence"),
+            Lang::English
+        );
+        assert_eq!(
+            lang("韩国语 안녕하세요 means hello"),
+            Lang::Korean
+        );
+        assert_eq!(
+            lang("Tokyo 2020 オリンピック"),
+            Lang::Japanese
+        );
+    }
+
+    #[test]
+    fn test_option_and_codes() {
+        assert_eq!(
+            LanguageOption::Auto.resolve("ひらがな"),
+            Lang::Japanese
+        );
+        assert_eq!(
+            LanguageOption::Fixed(Lang::Chinese)
+                .resolve("ひらがな"),
+            Lang::Chinese
+        );
+        assert_eq!(Lang::from_code("zh-CN"), Lang::Chinese);
+        assert_eq!(Lang::from_code(" JA "), Lang::Japanese);
+        assert_eq!(Lang::from_code("fr"), Lang::OtherLatin);
+        assert_eq!(Lang::from_code("xx"), Lang::Unknown);
+        assert_eq!(Lang::Korean.code(), Some("ko"));
+        assert_eq!(Lang::OtherLatin.code(), None);
+    }
+
+    #[tokio::test]
+    async fn test_model_fallback_only_when_unsure() {
+        let mut calls = 0;
+        let guess = detect_language_with_model(
+            async |_| {
+                calls += 1;
+                Ok("ja".to_string())
+            },
+            "我们今天去图书馆学习",
+            0.6,
+        )
+        .await
+        .unwrap();
+        assert_eq!(guess.lang, Lang::Chinese);
+        assert_eq!(calls, 0);
+
+        let mut seen = Vec::new();
+        let guess = detect_language_with_model(
+            async |messages| {
+                seen = messages;
+                Ok("`ja`\n".to_string())
+            },
+            "学生",
+            0.6,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            guess,
+            LangGuess {
+                lang: Lang::Japanese,
+                confidence: MODEL_CONFIDENCE
+            }
+        );
+        assert_eq!(seen[1], ChatMessage::user("学生"));
+
+        // An unusable reply keeps the local guess; failures propagate
+        let guess = detect_language_with_model(
+            async |_| Ok("I am not sure".to_string()),
+            "学生",
+            0.6,
+        )
+        .await
+        .unwrap();
+        assert_eq!(guess.lang, Lang::Chinese);
+        assert!(
+            detect_language_with_model(
+                async |_| anyhow::bail!("quota"),
+                "学生",
+                0.6,
+            )
+            .await
+            .is_err()
+        );
+    }
+}
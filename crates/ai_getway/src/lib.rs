@@ -1,4 +1,26 @@
-mod models;
+pub mod catalog;
+pub mod chat;
+pub mod correction;
+pub mod curation;
+pub mod enhance;
+pub mod error;
+pub mod few_shot;
+pub mod key_pool;
+pub mod language;
+pub mod models;
+pub mod moderation;
+pub mod prompts;
+pub mod rate_limit;
+pub mod readings;
+pub mod retry_budget;
+#[cfg(any(test, feature = "server"))]
+pub mod server;
+pub mod structured;
+pub mod summarize;
+#[cfg(any(test, feature = "metrics"))]
+pub mod telemetry;
+pub mod tokens;
+pub mod usage_journal;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
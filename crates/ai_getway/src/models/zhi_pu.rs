@@ -1,7 +1,29 @@
 use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use utils::config::settings::{
+    Capabilities, Provider, ProviderDefaults, Settings,
+    format_errors,
+};
 
-static ZHI_PU_API_URL: &str =
+use crate::error::{HttpStatusError, NotAttempted};
+use crate::key_pool::KeyPool;
+use crate::rate_limit::{
+    RateLimitStatus, RateLimitTracker,
+};
+use crate::retry_budget::RetryBudget;
+use crate::usage_journal::{
+    CallOutcome, UsageJournal, UsageLabels, UsageRecord,
+};
+
+pub mod stream;
+
+/// 智谱AI接口的默认地址
+pub static ZHI_PU_API_URL: &str =
     "https://api.z.ai/api/coding/paas/v4";
+
+/// 请求和配置都未指定模型时使用的模型
+pub const DEFAULT_ZHI_PU_MODEL: &str = "glm-4.7-flash";
+
 /// 智谱AI消息结构体
 ///
 /// 表示对话中的单条消息，包含发送者角色和消息内容。
@@ -24,6 +46,8 @@ pub struct ZhiPuMessage {
 /// - `messages`: 消息列表，包含对话历史和当前请求
 /// - `stream`: 是否使用流式响应，None 表示不使用
 /// - `temperature`: 控制输出的随机性，0.0-2.0 之间，越高越随机
+/// - `max_tokens`: 生成Token数量的上限，None 表示使用服务端默认值
+/// - `thinking`: 推理模型的思考开关，None 表示使用服务端默认值
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ZhiPuRequest {
     pub model: String,
@@ -32,6 +56,94 @@ pub struct ZhiPuRequest {
     pub stream: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<ThinkingConfig>,
+}
+
+/// 推理模型的思考开关
+///
+/// 序列化为 `{"type": "enabled"}` 或 `{"type": "disabled"}`；
+/// 关闭思考可以为简单的生成节省Token。
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+)]
+pub struct ThinkingConfig {
+    #[serde(rename = "type")]
+    pub kind: ThinkingType,
+}
+
+impl ThinkingConfig {
+    /// 开启思考
+    pub fn enabled() -> Self {
+        Self {
+            kind: ThinkingType::Enabled,
+        }
+    }
+
+    /// 关闭思考
+    pub fn disabled() -> Self {
+        Self {
+            kind: ThinkingType::Disabled,
+        }
+    }
+}
+
+/// [`ThinkingConfig`] 的取值
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ThinkingType {
+    Enabled,
+    Disabled,
+}
+
+impl ZhiPuRequest {
+    /// 创建使用客户端默认模型的请求
+    ///
+    /// `model` 留空，由 [`ZhiPuClient::complete`] 补全为客户端的默认模型。
+    pub fn new(messages: Vec<ZhiPuMessage>) -> Self {
+        Self {
+            model: String::new(),
+            messages,
+            stream: None,
+            temperature: None,
+            max_tokens: None,
+            thinking: None,
+        }
+    }
+
+    /// 仅为本次请求指定模型，优先于客户端的默认模型
+    pub fn with_model(
+        mut self,
+        model: impl Into<String>,
+    ) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// 仅为本次请求设置思考开关
+    pub fn with_thinking(
+        mut self,
+        thinking: ThinkingConfig,
+    ) -> Self {
+        self.thinking = Some(thinking);
+        self
+    }
 }
 
 /// 智谱AI响应结构体
@@ -55,6 +167,31 @@ pub struct ZhiPuResponse {
     pub usage: ZhiPuUsage,
 }
 
+impl ZhiPuResponse {
+    /// 第一个选项的回复文本
+    ///
+    /// # 返回
+    /// 没有任何选项时返回错误；错误信息和日志都带有 `id` 与
+    /// `request_id`，便于向服务商反馈。
+    pub fn into_content(self) -> anyhow::Result<String> {
+        match self.choices.into_iter().next() {
+            Some(choice) => Ok(choice.message.content),
+            None => {
+                log::warn!(
+                    "ZhiPu returned no choices (id {}, request_id {})",
+                    self.id,
+                    self.request_id
+                );
+                anyhow::bail!(
+                    "The model returned no choices (id {}, request_id {})",
+                    self.id,
+                    self.request_id
+                )
+            }
+        }
+    }
+}
+
 /// Token使用情况结构体
 ///
 /// 统计本次请求中Token的使用情况，用于计费和使用量监控。
@@ -63,7 +200,9 @@ pub struct ZhiPuResponse {
 /// - `prompt_tokens`: 输入（提示）部分使用的Token数量
 /// - `completion_tokens`: 输出（完成）部分使用的Token数量
 /// - `total_tokens`: 本次请求使用的总Token数量
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(
+    Debug, Serialize, Deserialize, Clone, PartialEq,
+)]
 pub struct ZhiPuUsage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
@@ -116,21 +255,394 @@ pub async fn zhi_pu_completion(
     request: ZhiPuRequest,
 ) -> anyhow::Result<ZhiPuResponse> {
     let client = reqwest::Client::new();
+    completion_with_client(
+        &client,
+        ZHI_PU_API_URL,
+        api_key,
+        &request,
+        None,
+        None,
+    )
+    .await
+}
+
+/// 用最小的请求检查API密钥是否可用。
+///
+/// 发送一个只生成1个Token的补全请求，不做重试，适合在批量任务或
+/// 配置向导开始前调用。
+///
+/// # 参数
+/// - `api_key`: 待检查的API密钥。
+/// - `base_url`: 接口地址，通常为 [`ZHI_PU_API_URL`]。
+///
+/// # 返回
+/// 密钥被接受时返回 `Ok(true)`，被拒绝（HTTP 401/403）时返回
+/// `Ok(false)`。网络错误以及其他HTTP错误（如配额不足）返回 `Err`，
+/// 以便与无效密钥区分；HTTP错误的根因为 [`HttpStatusError`]。
+pub async fn validate_api_key(
+    api_key: &str,
+    base_url: &str,
+) -> anyhow::Result<bool> {
+    let request = ZhiPuRequest {
+        max_tokens: Some(1),
+        ..ZhiPuRequest::new(vec![ZhiPuMessage {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+        }])
+        .with_model(DEFAULT_ZHI_PU_MODEL)
+    };
+    let response = execute_zhi_pu_request(
+        &reqwest::Client::new(),
+        base_url,
+        api_key,
+        &request,
+    )
+    .await
+    .map_err(|e| {
+        anyhow::anyhow!("ZhiPu API network error: {}", e)
+    })?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(true);
+    }
+    let error = HttpStatusError {
+        status: status.as_u16(),
+        message: format_error_response(response, status)
+            .await?,
+    };
+    if error.is_auth() {
+        return Ok(false);
+    }
+    Err(error.into())
+}
+
+/// 智谱AI客户端
+///
+/// 持有一个 [`KeyPool`]，每次调用轮询使用其中的密钥；某个密钥遇到
+/// 配额或认证错误时自动切换到下一个密钥。克隆出的客户端共享同一个
+/// 密钥池和HTTP连接池。
+///
+/// 请求未指定的模型、`temperature` 和 `max_tokens` 由
+/// [`ProviderDefaults`]（配置中的 `[providers.zhipu]`）补全，
+/// 请求中显式给出的值始终优先。
+#[derive(Debug, Clone)]
+pub struct ZhiPuClient {
+    http: reqwest::Client,
+    keys: KeyPool,
+    defaults: ProviderDefaults,
+    retry_budget: Option<RetryBudget>,
+    rate_limits: RateLimitTracker,
+    usage_journal: Option<UsageJournal>,
+    usage_labels: UsageLabels,
+    system_prompt: Option<String>,
+}
+
+impl ZhiPuClient {
+    /// 使用给定的密钥池创建客户端
+    pub fn new(keys: KeyPool) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            keys,
+            defaults: ProviderDefaults::default(),
+            retry_budget: None,
+            rate_limits: RateLimitTracker::default(),
+            usage_journal: None,
+            usage_labels: UsageLabels::default(),
+            system_prompt: None,
+        }
+    }
+
+    /// 设置请求默认值
+    pub fn with_defaults(
+        mut self,
+        defaults: ProviderDefaults,
+    ) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// 设置共享的重试预算
+    ///
+    /// 设置后，本客户端（及其克隆）的所有调用从同一预算中扣除重试
+    /// 次数，预算用完后失败的调用不再重试。
+    pub fn with_retry_budget(
+        mut self,
+        budget: Option<RetryBudget>,
+    ) -> Self {
+        self.retry_budget = budget;
+        self
+    }
+
+    /// 把每次 [`ZhiPuClient::complete`] 调用记入用量日志
+    ///
+    /// 默认不记录。克隆出的客户端写入同一个日志。
+    pub fn with_usage_journal(
+        mut self,
+        journal: Option<UsageJournal>,
+    ) -> Self {
+        self.usage_journal = journal;
+        self
+    }
+
+    /// 设置写入用量日志的标签，如本次运行的 ID 和调用用途
+    pub fn with_usage_labels(
+        mut self,
+        labels: UsageLabels,
+    ) -> Self {
+        self.usage_labels = labels;
+        self
+    }
+
+    /// 设置系统提示词，自动插入到每次调用的消息列表开头
+    ///
+    /// 消息列表中已有系统消息时不再插入，以调用方提供的为准。
+    pub fn with_system_prompt(
+        mut self,
+        prompt: impl Into<String>,
+    ) -> Self {
+        self.system_prompt = Some(prompt.into());
+        self
+    }
+
+    /// 使用配置中的智谱密钥（可以有多个）创建客户端
+    ///
+    /// 密钥在创建时被复制进密钥池，之后重新加载配置不会影响本客户端；
+    /// 长期运行的进程应在 `SettingsHandle::on_change` 回调中重新创建客户端。
+    ///
+    /// # 返回
+    /// 配置不完整时返回列出所有问题的错误。
+    pub fn from_settings(
+        settings: &Settings,
+    ) -> anyhow::Result<Self> {
+        let required =
+            Capabilities::new().provider(Provider::ZhiPu);
+        settings.validate(&required).map_err(|errors| {
+            anyhow::anyhow!("{}", format_errors(&errors))
+        })?;
+        let keys = settings.zhi_pu_api_key.as_slice();
+        Ok(Self::new(KeyPool::new(keys.iter().cloned()))
+            .with_defaults(settings.provider_defaults(
+                Provider::ZhiPu.section(),
+            )))
+    }
+
+    /// 设置默认模型，覆盖配置中的 `default_model`
+    ///
+    /// 单次调用仍可通过 [`ZhiPuRequest::with_model`] 使用其他模型。
+    pub fn with_default_model(
+        mut self,
+        model: impl Into<String>,
+    ) -> Self {
+        self.defaults.default_model = Some(model.into());
+        self
+    }
+
+    /// 未指定模型的请求使用的模型
+    pub fn default_model(&self) -> &str {
+        self.defaults
+            .default_model
+            .as_deref()
+            .unwrap_or(DEFAULT_ZHI_PU_MODEL)
+    }
+
+    /// 最近一次响应中的限流信息
+    ///
+    /// 密钥池中各密钥共用这一份记录，调度方可以据此在额度用完之前
+    /// 放慢请求，见 [`RateLimitStatus::suggested_delay`]。
+    pub fn rate_limit(&self) -> Option<RateLimitStatus> {
+        self.rate_limits.latest()
+    }
+
+    /// 客户端使用的密钥池
+    pub fn keys(&self) -> &KeyPool {
+        &self.keys
+    }
+
+    /// 请求发往的接口地址，未配置 `base_url` 时为 [`ZHI_PU_API_URL`]
+    pub fn base_url(&self) -> &str {
+        self.defaults
+            .base_url
+            .as_deref()
+            .unwrap_or(ZHI_PU_API_URL)
+    }
+
+    /// Fills the values `request` leaves unspecified from the
+    /// configured defaults
+    fn apply_defaults(
+        &self,
+        mut request: ZhiPuRequest,
+    ) -> ZhiPuRequest {
+        if request.model.is_empty() {
+            request.model =
+                self.default_model().to_string();
+        }
+        request.temperature = request
+            .temperature
+            .or(self.defaults.temperature);
+        request.max_tokens =
+            request.max_tokens.or(self.defaults.max_tokens);
+        if let Some(prompt) = &self.system_prompt
+            && !request
+                .messages
+                .iter()
+                .any(|message| message.role == "system")
+        {
+            request.messages.insert(
+                0,
+                ZhiPuMessage {
+                    role: "system".to_string(),
+                    content: prompt.clone(),
+                },
+            );
+        }
+        request
+    }
+
+    /// 调用Completion API，配额错误时轮换密钥
+    ///
+    /// # 参数
+    /// - `request`: 智谱AI请求体，`model` 为空字符串时使用默认模型。
+    ///
+    /// # 返回
+    /// 成功时返回 `ZhiPuResponse`；所有密钥都不可用时返回
+    /// [`crate::key_pool::KeysExhausted`]。
+    pub async fn complete(
+        &self,
+        request: ZhiPuRequest,
+    ) -> anyhow::Result<ZhiPuResponse> {
+        let request = self.apply_defaults(request);
+        let base_url = self.base_url();
+        let started = std::time::Instant::now();
+        let result = self
+            .keys
+            .run(|key| {
+                let request = &request;
+                async move {
+                    completion_with_client(
+                        &self.http,
+                        base_url,
+                        key.expose(),
+                        request,
+                        self.retry_budget.as_ref(),
+                        Some(&self.rate_limits),
+                    )
+                    .await
+                }
+            })
+            .await;
+        let tokens = result
+            .as_ref()
+            .map(|response| {
+                let count = |tokens: i32| {
+                    u64::try_from(tokens).unwrap_or(0)
+                };
+                (
+                    count(response.usage.prompt_tokens),
+                    count(response.usage.completion_tokens),
+                )
+            })
+            .unwrap_or_default();
+        let outcome = CallOutcome::of(&result);
+        #[cfg(any(test, feature = "metrics"))]
+        crate::telemetry::record_completion(
+            Provider::ZhiPu.name(),
+            &request.model,
+            &outcome,
+            started.elapsed(),
+            tokens,
+        );
+        if let Some(journal) = &self.usage_journal {
+            let record = UsageRecord::completed(
+                Provider::ZhiPu.name(),
+                &request.model,
+                tokens,
+                started.elapsed(),
+                outcome,
+                &self.usage_labels,
+            );
+            if let Err(e) = journal.append(&record) {
+                log::warn!(
+                    "Failed to write the usage journal: {:#}",
+                    e
+                );
+            }
+        }
+        result
+    }
+
+    /// 并发调用Completion API处理一批请求
+    ///
+    /// 需要整批共享重试次数时，先用 [`ZhiPuClient::with_retry_budget`]
+    /// 设置预算，服务不可用时剩余的调用会很快失败。
+    ///
+    /// # 参数
+    /// - `requests`: 请求列表
+    /// - `concurrency`: 同时进行的调用数，0 视为 1
+    /// - `cancel`: 取消后不再发出新的请求，已经发出的请求照常完成
+    ///
+    /// # 返回
+    /// 与 `requests` 顺序一致的结果，单个请求失败不影响其他请求；
+    /// 因取消而没有发出的请求以 [`NotAttempted`] 为根因返回错误。
+    pub async fn complete_batch(
+        &self,
+        requests: Vec<ZhiPuRequest>,
+        concurrency: usize,
+        cancel: Option<&CancellationToken>,
+    ) -> Vec<anyhow::Result<ZhiPuResponse>> {
+        use futures::StreamExt;
+
+        futures::stream::iter(requests)
+            .map(|request| async move {
+                // Checked when the call is scheduled, not when it
+                // is queued
+                if cancel.is_some_and(|c| c.is_cancelled())
+                {
+                    return Err(NotAttempted.into());
+                }
+                self.complete(request).await
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+/// Completion call with transient-error retries on a shared client
+async fn completion_with_client(
+    client: &reqwest::Client,
+    base_url: &str,
+    api_key: &str,
+    request: &ZhiPuRequest,
+    budget: Option<&RetryBudget>,
+    rate_limits: Option<&RateLimitTracker>,
+) -> anyhow::Result<ZhiPuResponse> {
     let mut retry_count = 0; // 初始为0，表示尚未重试
     const MAX_RETRIES: u32 = 3;
+    // 次数未用完且共享预算允许时才重试
+    let may_retry = |retry_count: u32| {
+        retry_count < MAX_RETRIES
+            && budget.is_none_or(RetryBudget::try_take)
+    };
 
     loop {
         let response_result = execute_zhi_pu_request(
-            &client, api_key, &request,
+            client, base_url, api_key, request,
         )
         .await;
 
         let response = match response_result {
             Ok(res) => res,
             Err(e) => {
-                if retry_count < MAX_RETRIES {
+                if may_retry(retry_count) {
                     // 检查是否还能重试
                     retry_count += 1; // 失败后递增
+                    #[cfg(any(test, feature = "metrics"))]
+                    crate::telemetry::record_retry(
+                        Provider::ZhiPu.name(),
+                        &request.model,
+                        "network",
+                    );
                     wait_before_retry(
                         retry_count,
                         &format!("network error: {}", e),
@@ -149,8 +661,8 @@ pub async fn zhi_pu_completion(
 
         match handle_http_response(
             response,
-            retry_count,
-            MAX_RETRIES,
+            || may_retry(retry_count),
+            rate_limits,
         )
         .await?
         {
@@ -159,6 +671,12 @@ pub async fn zhi_pu_completion(
             }
             None => {
                 retry_count += 1; // HTTP 可重试错误，递增后重试
+                #[cfg(any(test, feature = "metrics"))]
+                crate::telemetry::record_retry(
+                    Provider::ZhiPu.name(),
+                    &request.model,
+                    "http_status",
+                );
                 wait_before_retry(
                     retry_count,
                     &format!("transient error {}", status),
@@ -176,36 +694,84 @@ pub async fn zhi_pu_completion(
 ///
 /// # Arguments
 /// - `response`: The `reqwest::Response` received from the API.
-/// - `retry_count`: The current retry attempt number.
-/// - `max_retries`: The maximum number of retries allowed.
+/// - `may_retry`: Called for a retryable error; whether a retry is
+///   still allowed (attempts left and retry budget available).
+/// - `rate_limits`: Receives the rate-limit headers of every response,
+///   successful or not.
 ///
 /// # Returns
 /// `Ok(Some(ZhiPuResponse))`: If the request was successful and the response was parsed.
-/// `Ok(None)`: If the error is retryable and `retry_count` is less than `max_retries`.
-/// `Err(anyhow::Error)`: If the error is not retryable or `retry_count` has exceeded `max_retries`.
+/// `Ok(None)`: If the error is retryable and `may_retry` allows it.
+/// `Err(anyhow::Error)`: If the error is not retryable or no retry is allowed.
 async fn handle_http_response(
     response: reqwest::Response,
-    retry_count: u32,
-    max_retries: u32,
+    may_retry: impl FnOnce() -> bool,
+    rate_limits: Option<&RateLimitTracker>,
 ) -> anyhow::Result<Option<ZhiPuResponse>> {
     let status = response.status();
+    if let Some(tracker) = rate_limits {
+        tracker.record(response.headers());
+    }
 
     if status.is_success() {
-        let zhi_pu_response: ZhiPuResponse =
-            response.json().await?;
-        return Ok(Some(zhi_pu_response));
+        let body = response.text().await?;
+        return match serde_json::from_str::<ZhiPuResponse>(
+            &body,
+        ) {
+            Ok(zhi_pu_response) => {
+                log::debug!(
+                    "ZhiPu response id {} request_id {} ({} tokens)",
+                    zhi_pu_response.id,
+                    zhi_pu_response.request_id,
+                    zhi_pu_response.usage.total_tokens
+                );
+                Ok(Some(zhi_pu_response))
+            }
+            Err(e) => Err(unreadable_response(&body, e)),
+        };
     }
 
-    if is_retryable_error(status.as_u16()) {
-        if retry_count < max_retries {
-            return Ok(None); // Indicate that a retry is needed
-        }
+    if is_retryable_error(status.as_u16()) && may_retry() {
+        return Ok(None); // Indicate that a retry is needed
     }
 
-    anyhow::bail!(
-        "{}",
-        format_error_response(response, status).await?
-    );
+    Err(HttpStatusError {
+        status: status.as_u16(),
+        message: format_error_response(response, status)
+            .await?,
+    }
+    .into())
+}
+
+/// Error for a successful response whose body is not a
+/// [`ZhiPuResponse`], carrying whichever of `id` and `request_id`
+/// the body still has
+fn unreadable_response(
+    body: &str,
+    error: serde_json::Error,
+) -> anyhow::Error {
+    let value: serde_json::Value =
+        serde_json::from_str(body).unwrap_or_default();
+    let ids: Vec<String> = ["id", "request_id"]
+        .into_iter()
+        .filter_map(|key| {
+            value
+                .get(key)
+                .and_then(serde_json::Value::as_str)
+                .map(|id| format!("{} {}", key, id))
+        })
+        .collect();
+    let message = if ids.is_empty() {
+        format!("Unreadable ZhiPu response: {}", error)
+    } else {
+        format!(
+            "Unreadable ZhiPu response ({}): {}",
+            ids.join(", "),
+            error
+        )
+    };
+    log::warn!("{}", message);
+    anyhow::anyhow!(message)
 }
 
 /// Waits for a calculated duration before retrying an API call.
@@ -232,13 +798,14 @@ async fn wait_before_retry(
 
 async fn execute_zhi_pu_request(
     client: &reqwest::Client,
+    base_url: &str,
     api_key: &str,
     request_body: &ZhiPuRequest,
 ) -> Result<reqwest::Response, reqwest::Error> {
     client
         .post(format!(
             "{}/chat/completions",
-            ZHI_PU_API_URL
+            base_url.trim_end_matches('/')
         ))
         .header(
             "Authorization",
@@ -283,31 +850,601 @@ async fn format_error_response(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::chat::ChatMessage;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use utils::config::env::ENV_SETTINGS;
 
     #[tokio::test]
     async fn test_zhi_pu_completion() -> anyhow::Result<()>
     {
-        let api_key = ENV_SETTINGS
-            .zhi_pu_api_key
-            .as_ref()
+        let api_keys = ENV_SETTINGS.zhi_pu_api_keys();
+        let api_key = api_keys
+            .first()
             .expect("ZHI_PU_API_KEY not set");
 
         let request = ZhiPuRequest {
             model: "glm-4.7-flash".to_string(),
             messages: vec![ZhiPuMessage {
                 role: "user".to_string(),
-                content: "简略回答,你怎么看待anki".to_string(),
+                content: "简略回答,你怎么看待anki"
+                    .to_string(),
             }],
             stream: None,
             temperature: None,
+            max_tokens: None,
+            thinking: None,
         };
 
         let response =
-            zhi_pu_completion(api_key, request).await?;
+            zhi_pu_completion(api_key.expose(), request)
+                .await?;
 
         assert!(!response.choices.is_empty());
         dbg!(&response);
         Ok(())
     }
+
+    /// Answers every request with `status`; returns the base URL
+    /// and the number of requests served so far
+    async fn serve_status(
+        status: &'static str,
+    ) -> (String, Arc<AtomicUsize>) {
+        serve_with_headers(status, "").await
+    }
+
+    /// Like [`serve_status`], with `headers` (each ending in `\r\n`)
+    /// added to every response
+    async fn serve_with_headers(
+        status: &'static str,
+        headers: &'static str,
+    ) -> (String, Arc<AtomicUsize>) {
+        serve_response(
+            status,
+            headers,
+            r#"{"error":{"code":"1000","message":"auth"}}"#,
+        )
+        .await
+    }
+
+    /// Answers every request with `status`, `headers` and `body`
+    async fn serve_response(
+        status: &'static str,
+        headers: &'static str,
+        body: &'static str,
+    ) -> (String, Arc<AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) =
+                listener.accept().await
+            {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 64 * 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        headers,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream
+                        .write_all(response.as_bytes())
+                        .await;
+                });
+            }
+        });
+        (format!("http://{}", addr), served)
+    }
+
+    async fn serve_once(status: &'static str) -> String {
+        serve_status(status).await.0
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget_caps_retries_across_batch() {
+        let (url, served) =
+            serve_status("503 Service Unavailable").await;
+        let budget = RetryBudget::new(2);
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(
+                    json!({ "base_url": url }),
+                ))
+                .with_retry_budget(Some(budget.clone()));
+        let requests = (0..4)
+            .map(|_| request("glm-4.7", None))
+            .collect();
+
+        let results =
+            client.complete_batch(requests, 4, None).await;
+        assert_eq!(results.len(), 4);
+        for result in &results {
+            let error = result.as_ref().unwrap_err();
+            assert_eq!(
+                error
+                    .downcast_ref::<HttpStatusError>()
+                    .unwrap()
+                    .status,
+                503
+            );
+        }
+        // One attempt per call plus the two budgeted retries
+        assert_eq!(served.load(Ordering::SeqCst), 6);
+        assert!(budget.is_exhausted());
+    }
+
+    #[tokio::test]
+    async fn test_client_records_rate_limit_headers() {
+        let (url, _) = serve_with_headers(
+            "429 Too Many Requests",
+            "X-RateLimit-Limit: 60\r\nX-RateLimit-Remaining: 0\r\nX-RateLimit-Reset: 20\r\n",
+        )
+        .await;
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(
+                    json!({ "base_url": url }),
+                ))
+                .with_retry_budget(Some(RetryBudget::new(
+                    0,
+                )));
+        assert_eq!(client.rate_limit(), None);
+
+        let result =
+            client.complete(request("glm-4.7", None)).await;
+        assert!(result.is_err());
+        let status = client.rate_limit().unwrap();
+        assert_eq!(status.limit, Some(60));
+        assert_eq!(status.remaining, Some(0));
+        assert_eq!(
+            status.reset,
+            Some(std::time::Duration::from_secs(20))
+        );
+        assert!(
+            status.suggested_delay().unwrap()
+                > std::time::Duration::from_secs(19)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_responses_name_the_request_id() {
+        let client = |url: String| {
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(
+                    json!({ "base_url": url }),
+                ))
+        };
+
+        let (url, _) = serve_response(
+            "200 OK",
+            "",
+            r#"{"id":"chat-1","request_id":"req-1","created":1,"model":"glm-4.7","choices":[],"usage":{"prompt_tokens":1,"completion_tokens":0,"total_tokens":1}}"#,
+        )
+        .await;
+        let error = client(url)
+            .complete(request("glm-4.7", None))
+            .await
+            .unwrap()
+            .into_content()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The model returned no choices (id chat-1, request_id req-1)"
+        );
+
+        let (url, _) = serve_response(
+            "200 OK",
+            "",
+            r#"{"id":"chat-2","request_id":"req-2","choices":null}"#,
+        )
+        .await;
+        let error = client(url)
+            .complete(request("glm-4.7", None))
+            .await
+            .unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(
+            message
+                .contains("(id chat-2, request_id req-2)"),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_usage_journal_records_calls_without_content()
+     {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_usage_client_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let journal = UsageJournal::open(
+            &dir,
+            crate::usage_journal::JournalOptions::default(),
+        )
+        .unwrap();
+        let (ok_url, _) = serve_response(
+            "200 OK",
+            "",
+            r#"{"id":"chat-1","request_id":"req-1","created":1,"model":"glm-4.7","choices":[{"index":0,"message":{"role":"assistant","content":"SECRET ANSWER"},"finish_reason":"stop"}],"usage":{"prompt_tokens":12,"completion_tokens":3,"total_tokens":15}}"#,
+        )
+        .await;
+        let (failing_url, _) =
+            serve_status("400 Bad Request").await;
+        let labels = UsageLabels {
+            run_id: Some("run-7".to_string()),
+            purpose: Some("cards".to_string()),
+        };
+        for url in [ok_url, failing_url] {
+            let client = ZhiPuClient::new(KeyPool::new([
+                "sk-SECRET-KEY",
+            ]))
+            .with_defaults(defaults(
+                json!({ "base_url": url }),
+            ))
+            .with_usage_journal(Some(journal.clone()))
+            .with_usage_labels(labels.clone());
+            let mut request = request("glm-4.7", None);
+            request.messages[0].content =
+                "SECRET PROMPT".to_string();
+            let _ = client.complete(request).await;
+        }
+
+        let text = std::fs::read_to_string(
+            dir.join(crate::usage_journal::USAGE_FILE_NAME),
+        )
+        .unwrap();
+        assert!(!text.contains("SECRET"), "{}", text);
+        let records: Vec<UsageRecord> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].provider, "ZhiPu");
+        assert_eq!(records[0].model, "glm-4.7");
+        assert_eq!(
+            (
+                records[0].prompt_tokens,
+                records[0].completion_tokens
+            ),
+            (12, 3)
+        );
+        assert_eq!(
+            records[0].outcome,
+            CallOutcome::Success
+        );
+        assert_eq!(
+            records[0].run_id.as_deref(),
+            Some("run-7")
+        );
+        assert_eq!(
+            records[1].outcome,
+            CallOutcome::HttpStatus(400)
+        );
+        assert_eq!(records[1].prompt_tokens, 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_calls_are_recorded_as_metrics() {
+        use crate::telemetry::{
+            REQUEST_DURATION, REQUESTS, RETRIES, TOKENS,
+        };
+        use metrics_util::debugging::{
+            DebugValue, DebuggingRecorder,
+        };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // Current-thread runtime, so every call below records here
+        let _guard =
+            metrics::set_default_local_recorder(&recorder);
+        let (ok_url, _) = serve_response(
+            "200 OK",
+            "",
+            r#"{"id":"chat-1","request_id":"req-1","created":1,"model":"glm-4.7","choices":[],"usage":{"prompt_tokens":12,"completion_tokens":3,"total_tokens":15}}"#,
+        )
+        .await;
+        let (busy_url, _) =
+            serve_status("503 Service Unavailable").await;
+        for url in [ok_url, busy_url] {
+            let client =
+                ZhiPuClient::new(KeyPool::new(["key"]))
+                    .with_defaults(defaults(
+                        json!({ "base_url": url }),
+                    ))
+                    .with_retry_budget(Some(
+                        RetryBudget::new(1),
+                    ));
+            let mut request = request("glm-4.7", None);
+            request.messages[0].content =
+                "SECRET PROMPT".to_string();
+            let _ = client.complete(request).await;
+        }
+
+        let mut recorded: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<String> = key
+                    .labels()
+                    .map(|l| {
+                        format!("{}={}", l.key(), l.value())
+                    })
+                    .collect();
+                let value = match value {
+                    DebugValue::Counter(n) => n,
+                    DebugValue::Histogram(samples) => {
+                        samples.len() as u64
+                    }
+                    DebugValue::Gauge(_) => unreachable!(),
+                };
+                (
+                    key.name().to_string(),
+                    labels.join(","),
+                    value,
+                )
+            })
+            .collect();
+        recorded.sort();
+        let zhi_pu = "provider=ZhiPu,model=glm-4.7";
+        let metric = |name: &str, labels: &str, value| {
+            (
+                name.to_string(),
+                format!("{},{}", zhi_pu, labels),
+                value,
+            )
+        };
+        assert_eq!(
+            recorded,
+            [
+                metric(
+                    REQUEST_DURATION,
+                    "outcome=http_503",
+                    1
+                ),
+                metric(
+                    REQUEST_DURATION,
+                    "outcome=success",
+                    1
+                ),
+                metric(REQUESTS, "outcome=http_503", 1),
+                metric(REQUESTS, "outcome=success", 1),
+                metric(RETRIES, "reason=http_status", 1),
+                metric(TOKENS, "kind=completion", 3),
+                metric(TOKENS, "kind=prompt", 12),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_batch_sends_nothing_more() {
+        let (url, served) =
+            serve_status("401 Unauthorized").await;
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(
+                    json!({ "base_url": url }),
+                ));
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let requests = (0..3)
+            .map(|_| request("glm-4.7", None))
+            .collect();
+
+        let results = client
+            .complete_batch(requests, 1, Some(&cancel))
+            .await;
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| {
+            NotAttempted::is_cause_of(
+                result.as_ref().unwrap_err(),
+            )
+        }));
+        assert_eq!(served.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_validate_api_key_rejected_vs_accepted() {
+        let url = serve_once("401 Unauthorized").await;
+        assert!(
+            !validate_api_key("bad", &url).await.unwrap()
+        );
+        let url = serve_once("200 OK").await;
+        assert!(
+            validate_api_key("good", &url).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_api_key_other_failures_are_errors()
+     {
+        let url =
+            serve_once("503 Service Unavailable").await;
+        let error = validate_api_key("key", &url)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<HttpStatusError>()
+                .unwrap()
+                .status,
+            503
+        );
+
+        // Nothing listens on a port whose listener was dropped
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap();
+        let url = format!(
+            "http://{}",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+        let error = validate_api_key("key", &url)
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .downcast_ref::<HttpStatusError>()
+                .is_none()
+        );
+        assert!(
+            error
+                .to_string()
+                .starts_with("ZhiPu API network error")
+        );
+    }
+
+    fn request(
+        model: &str,
+        temperature: Option<f32>,
+    ) -> ZhiPuRequest {
+        ZhiPuRequest {
+            model: model.to_string(),
+            messages: vec![ChatMessage::user("猫").into()],
+            stream: None,
+            temperature,
+            max_tokens: None,
+            thinking: None,
+        }
+    }
+
+    #[test]
+    fn test_thinking_is_serialized_only_when_set() {
+        let plain =
+            serde_json::to_value(request("glm-4.7", None))
+                .unwrap();
+        assert!(plain.get("thinking").is_none());
+
+        for (thinking, expected) in [
+            (ThinkingConfig::enabled(), "enabled"),
+            (ThinkingConfig::disabled(), "disabled"),
+        ] {
+            let body = serde_json::to_value(
+                request("glm-4.7", None)
+                    .with_thinking(thinking),
+            )
+            .unwrap();
+            assert_eq!(
+                body["thinking"],
+                json!({ "type": expected })
+            );
+        }
+    }
+
+    #[test]
+    fn test_per_call_model_overrides_client_default() {
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(json!({
+                    "default_model": "glm-4.7-air",
+                })))
+                .with_default_model("glm-4.7");
+        assert_eq!(client.default_model(), "glm-4.7");
+
+        let messages = vec![ChatMessage::user("猫").into()];
+        let simple = client.apply_defaults(
+            ZhiPuRequest::new(messages.clone()),
+        );
+        assert_eq!(simple.model, "glm-4.7");
+
+        let complex = client.apply_defaults(
+            ZhiPuRequest::new(messages)
+                .with_model("glm-4.7-flash"),
+        );
+        assert_eq!(complex.model, "glm-4.7-flash");
+        assert_eq!(client.default_model(), "glm-4.7");
+    }
+
+    #[test]
+    fn test_system_prompt_is_prepended_once() {
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_system_prompt("你是Anki制卡助手");
+
+        let request =
+            client.apply_defaults(ZhiPuRequest::new(vec![
+                ChatMessage::user("猫").into(),
+            ]));
+        let roles: Vec<&str> = request
+            .messages
+            .iter()
+            .map(|message| message.role.as_str())
+            .collect();
+        assert_eq!(roles, ["system", "user"]);
+        assert_eq!(
+            request.messages[0].content,
+            "你是Anki制卡助手"
+        );
+
+        let request = client.apply_defaults(request);
+        assert_eq!(request.messages.len(), 2);
+
+        let own =
+            client.apply_defaults(ZhiPuRequest::new(vec![
+                ChatMessage::system("只回答日语").into(),
+                ChatMessage::user("猫").into(),
+            ]));
+        assert_eq!(own.messages.len(), 2);
+        assert_eq!(own.messages[0].content, "只回答日语");
+    }
+
+    fn defaults(
+        section: serde_json::Value,
+    ) -> ProviderDefaults {
+        serde_json::from_value(section).unwrap()
+    }
+
+    #[test]
+    fn test_defaults_fill_unspecified_values() {
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(json!({
+                    "default_model": "glm-4.7",
+                    "temperature": 0.2,
+                    "max_tokens": 256,
+                })));
+        let applied =
+            client.apply_defaults(request("", None));
+        assert_eq!(applied.model, "glm-4.7");
+        assert_eq!(applied.temperature, Some(0.2));
+        assert_eq!(applied.max_tokens, Some(256));
+    }
+
+    #[test]
+    fn test_explicit_values_win_over_defaults() {
+        let client =
+            ZhiPuClient::new(KeyPool::new(["key"]))
+                .with_defaults(defaults(json!({
+                    "default_model": "glm-4.7",
+                    "temperature": 0.2,
+                })));
+        let applied = client.apply_defaults(request(
+            "glm-4.7-flash",
+            Some(1.0),
+        ));
+        assert_eq!(applied.model, "glm-4.7-flash");
+        assert_eq!(applied.temperature, Some(1.0));
+        assert_eq!(applied.max_tokens, None);
+
+        let bare = ZhiPuClient::new(KeyPool::new(["key"]))
+            .apply_defaults(request("", None));
+        assert_eq!(bare.model, DEFAULT_ZHI_PU_MODEL);
+        assert_eq!(bare.temperature, None);
+    }
 }
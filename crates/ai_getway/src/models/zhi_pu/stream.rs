@@ -0,0 +1,702 @@
+//! 智谱AI流式（SSE）补全接口
+
+use std::collections::VecDeque;
+
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{HttpStatusError, StreamInterrupted};
+
+use super::{
+    ZHI_PU_API_URL, ZhiPuRequest, ZhiPuUsage,
+    execute_zhi_pu_request, format_error_response,
+};
+
+/// 流式响应中的单个数据块
+///
+/// 每个 SSE `data:` 事件对应一个数据块，最后一个数据块携带 `usage`。
+///
+/// # 字段
+/// - `id`: 响应的唯一标识符
+/// - `created`: 响应生成的时间戳（Unix时间戳）
+/// - `model`: 实际使用的模型名称
+/// - `choices`: 增量选项列表
+/// - `usage`: Token使用统计信息，仅在最后一个数据块中出现
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ZhiPuStreamChunk {
+    pub id: String,
+    #[serde(default)]
+    pub created: i64,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub choices: Vec<ZhiPuStreamChoice>,
+    #[serde(default)]
+    pub usage: Option<ZhiPuUsage>,
+}
+
+/// 流式响应中的增量选项
+///
+/// # 字段
+/// - `index`: 选项的索引号，从0开始
+/// - `delta`: 本次新增的内容
+/// - `finish_reason`: 完成原因，仅在最后一个增量中出现
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ZhiPuStreamChoice {
+    pub index: i32,
+    pub delta: ZhiPuDelta,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+/// 增量消息内容
+///
+/// # 字段
+/// - `role`: 消息发送者的角色，通常只在第一个增量中出现
+/// - `content`: 新增的文本内容
+/// - `reasoning_content`: 新增的推理过程内容
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ZhiPuDelta {
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub reasoning_content: Option<String>,
+}
+
+/// Incremental decoder for a `text/event-stream` body.
+///
+/// Bytes are buffered until a blank line terminates an event; the
+/// `data:` lines of each complete event are returned joined by `\n`.
+#[derive(Debug, Default)]
+struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Feeds raw bytes and returns the payloads of completed events
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+
+        while let Some((end, sep_len)) =
+            find_event_end(&self.buffer)
+        {
+            let raw: Vec<u8> = self
+                .buffer
+                .drain(..end + sep_len)
+                .collect();
+            let text = String::from_utf8_lossy(&raw[..end]);
+            let data: Vec<&str> = text
+                .lines()
+                .filter_map(|line| {
+                    line.strip_prefix("data:")
+                })
+                .map(|data| {
+                    data.strip_prefix(' ').unwrap_or(data)
+                })
+                .collect();
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+        events
+    }
+}
+
+/// Position and length of the first event separator in `buffer`
+fn find_event_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    let lf = buffer.windows(2).position(|w| w == b"\n\n");
+    let crlf =
+        buffer.windows(4).position(|w| w == b"\r\n\r\n");
+    match (lf, crlf) {
+        (Some(a), Some(b)) if b < a => Some((b, 4)),
+        (Some(a), _) => Some((a, 2)),
+        (None, Some(b)) => Some((b, 4)),
+        (None, None) => None,
+    }
+}
+
+/// Parses one SSE payload; `None` marks the `[DONE]` sentinel
+fn parse_event(
+    payload: &str,
+) -> Option<anyhow::Result<ZhiPuStreamChunk>> {
+    let payload = payload.trim();
+    if payload == "[DONE]" {
+        return None;
+    }
+    Some(serde_json::from_str(payload).map_err(|e| {
+        anyhow::anyhow!(
+            "ZhiPu API invalid stream chunk: {} ({})",
+            e,
+            payload
+        )
+    }))
+}
+
+/// 调用智谱AI的流式Completion API。
+///
+/// 请求会被强制设置为 `stream: true`，返回的流逐个产出增量数据块，
+/// 在收到 `[DONE]` 时终止。与 [`super::zhi_pu_completion`] 不同，
+/// 流式请求不做自动重试；需要重试时使用
+/// [`zhi_pu_completion_stream_with_retry`]。
+///
+/// 连接建立后中途断开（包括在 `[DONE]` 和结束原因之前关闭连接）
+/// 时，流产出以 [`StreamInterrupted`] 为根因的错误并结束。
+///
+/// # 参数
+/// - `api_key`: 用于认证的API密钥。
+/// - `request`: 智谱AI请求体。
+///
+/// # 返回
+/// 成功建立连接时返回增量数据块流，HTTP 错误时返回 `anyhow::Error`。
+pub async fn zhi_pu_completion_stream(
+    api_key: &str,
+    request: ZhiPuRequest,
+) -> anyhow::Result<
+    impl Stream<Item = anyhow::Result<ZhiPuStreamChunk>>,
+> {
+    open_stream(api_key, request).await
+}
+
+/// 调用智谱AI的流式Completion API，中途断开时自动重新请求。
+///
+/// 断开后会以相同的请求重新生成，并丢弃新响应中已经产出过的文本
+/// （按字符数计算），因此调用方看到的是一段连续的输出。重新生成的
+/// 内容不保证与之前一致，`temperature` 较高时衔接处可能不连贯；
+/// 需要严格一致时请使用 [`zhi_pu_completion_stream`] 并自行处理
+/// [`StreamInterrupted`] 中的部分结果。
+///
+/// 推理内容（`reasoning_content`）在跳过阶段同样被丢弃。
+///
+/// # 参数
+/// - `api_key`: 用于认证的API密钥。
+/// - `request`: 智谱AI请求体。
+/// - `max_retries`: 中途断开后最多重新请求的次数。
+///
+/// # 返回
+/// 增量数据块流。连接失败或 HTTP 错误作为流中的错误产出；重试次数
+/// 用尽后产出的 [`StreamInterrupted`] 包含到断开为止产出的全部文本。
+pub fn zhi_pu_completion_stream_with_retry(
+    api_key: String,
+    request: ZhiPuRequest,
+    max_retries: u32,
+) -> impl Stream<Item = anyhow::Result<ZhiPuStreamChunk>>
++ Send
++ 'static {
+    let state = Resume {
+        api_key,
+        request,
+        max_retries,
+        retries: 0,
+        inner: None,
+        emitted: String::new(),
+        skip: 0,
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return None;
+            }
+            let inner = match &mut state.inner {
+                Some(inner) => inner,
+                None => match open_stream(
+                    &state.api_key,
+                    state.request.clone(),
+                )
+                .await
+                {
+                    Ok(inner) => state.inner.insert(inner),
+                    Err(e) => {
+                        state.done = true;
+                        return Some((Err(e), state));
+                    }
+                },
+            };
+            match inner.next().await {
+                None => return None,
+                Some(Ok(chunk)) => {
+                    let Some(chunk) = skip_emitted(
+                        chunk,
+                        &mut state.skip,
+                    ) else {
+                        continue;
+                    };
+                    push_content(
+                        &mut state.emitted,
+                        &chunk,
+                    );
+                    return Some((Ok(chunk), state));
+                }
+                Some(Err(e))
+                    if StreamInterrupted::is_cause_of(
+                        &e,
+                    ) && state.retries
+                        < state.max_retries =>
+                {
+                    state.retries += 1;
+                    state.inner = None;
+                    state.skip =
+                        state.emitted.chars().count();
+                    super::wait_before_retry(
+                        state.retries,
+                        &format!("{:#}", e),
+                    )
+                    .await;
+                }
+                Some(Err(e)) => {
+                    state.done = true;
+                    let e = match e
+                        .downcast::<StreamInterrupted>()
+                    {
+                        Ok(interrupted) => {
+                            StreamInterrupted {
+                                message: interrupted
+                                    .message,
+                                partial: state
+                                    .emitted
+                                    .clone(),
+                            }
+                            .into()
+                        }
+                        Err(e) => e,
+                    };
+                    return Some((Err(e), state));
+                }
+            }
+        }
+    })
+}
+
+type ChunkStream = futures::stream::BoxStream<
+    'static,
+    anyhow::Result<ZhiPuStreamChunk>,
+>;
+
+/// State of [`zhi_pu_completion_stream_with_retry`]
+struct Resume {
+    api_key: String,
+    request: ZhiPuRequest,
+    max_retries: u32,
+    retries: u32,
+    inner: Option<ChunkStream>,
+    /// Content handed to the caller so far
+    emitted: String,
+    /// Characters of content still to drop after a reconnect
+    skip: usize,
+    done: bool,
+}
+
+/// Sends the streaming request and decodes the response body
+async fn open_stream(
+    api_key: &str,
+    mut request: ZhiPuRequest,
+) -> anyhow::Result<ChunkStream> {
+    request.stream = Some(true);
+    let client = reqwest::Client::new();
+    let response = execute_zhi_pu_request(
+        &client,
+        ZHI_PU_API_URL,
+        api_key,
+        &request,
+    )
+    .await
+    .map_err(|e| {
+        anyhow::anyhow!("ZhiPu API network error: {}", e)
+    })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(HttpStatusError {
+            status: status.as_u16(),
+            message: format_error_response(
+                response, status,
+            )
+            .await?,
+        }
+        .into());
+    }
+
+    Ok(decode_stream(response.bytes_stream()).boxed())
+}
+
+/// State of [`decode_stream`]
+struct Decode<S> {
+    bytes: std::pin::Pin<Box<S>>,
+    decoder: SseDecoder,
+    pending: VecDeque<String>,
+    /// Content decoded so far, reported on interruption
+    partial: String,
+    /// Whether a chunk carried a finish reason
+    finished: bool,
+    done: bool,
+}
+
+/// Turns a response body into chunks.
+///
+/// A body error, or a body ending before `[DONE]` and before any
+/// finish reason, ends the stream with [`StreamInterrupted`].
+/// Invalid chunks are reported without ending the stream.
+fn decode_stream<S, B, E>(
+    bytes: S,
+) -> impl Stream<Item = anyhow::Result<ZhiPuStreamChunk>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: std::fmt::Display,
+{
+    let state = Decode {
+        bytes: Box::pin(bytes),
+        decoder: SseDecoder::default(),
+        pending: VecDeque::new(),
+        partial: String::new(),
+        finished: false,
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(payload) = state.pending.pop_front()
+            {
+                match parse_event(&payload) {
+                    None => return None,
+                    Some(Ok(chunk)) => {
+                        state.finished |=
+                            chunk.choices.iter().any(|c| {
+                                c.finish_reason.is_some()
+                            });
+                        push_content(
+                            &mut state.partial,
+                            &chunk,
+                        );
+                        return Some((Ok(chunk), state));
+                    }
+                    Some(Err(e)) => {
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+            if state.done {
+                return None;
+            }
+            let message = match state.bytes.next().await {
+                Some(Ok(bytes)) => {
+                    state.pending.extend(
+                        state.decoder.push(bytes.as_ref()),
+                    );
+                    continue;
+                }
+                Some(Err(e)) => {
+                    format!("ZhiPu API stream error: {}", e)
+                }
+                None if state.finished => return None,
+                None => "ZhiPu API stream closed before the response was complete".to_string(),
+            };
+            state.done = true;
+            let error = StreamInterrupted {
+                message,
+                partial: std::mem::take(&mut state.partial),
+            };
+            return Some((Err(error.into()), state));
+        }
+    })
+}
+
+/// Appends the content of the first choice to `content`
+fn push_content(
+    content: &mut String,
+    chunk: &ZhiPuStreamChunk,
+) {
+    for choice in
+        chunk.choices.iter().filter(|c| c.index == 0)
+    {
+        if let Some(delta) = &choice.delta.content {
+            content.push_str(delta);
+        }
+    }
+}
+
+/// Drops the first `skip` characters of content from `chunk`.
+///
+/// Returns `None` when nothing is left worth passing on.
+fn skip_emitted(
+    mut chunk: ZhiPuStreamChunk,
+    skip: &mut usize,
+) -> Option<ZhiPuStreamChunk> {
+    if *skip == 0 {
+        return Some(chunk);
+    }
+    for choice in
+        chunk.choices.iter_mut().filter(|c| c.index == 0)
+    {
+        choice.delta.reasoning_content = None;
+        if let Some(content) = &mut choice.delta.content {
+            let dropped =
+                content.chars().count().min(*skip);
+            *skip -= dropped;
+            *content =
+                content.chars().skip(dropped).collect();
+        }
+    }
+    let keep = chunk.usage.is_some()
+        || chunk.choices.iter().any(|c| {
+            c.finish_reason.is_some()
+                || c.delta.content.as_ref().is_some_and(
+                    |content| !content.is_empty(),
+                )
+        });
+    keep.then_some(chunk)
+}
+
+/// 消费增量数据块流，拼接完整文本。
+///
+/// 相当于非流式调用的便利性，同时保留流式调用的进度优势。
+///
+/// # 返回
+/// `(完整文本, Token使用情况)`，智谱在最后一个数据块中发送 `usage`，
+/// 若流中没有出现则为 `None`。流中出现的第一个错误会直接返回。
+pub async fn collect_stream<S>(
+    stream: S,
+) -> anyhow::Result<(String, Option<ZhiPuUsage>)>
+where
+    S: Stream<Item = anyhow::Result<ZhiPuStreamChunk>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut content = String::new();
+    let mut usage = None;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        push_content(&mut content, &chunk);
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+    }
+    Ok((content, usage))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(
+        content: &str,
+        usage: Option<ZhiPuUsage>,
+    ) -> ZhiPuStreamChunk {
+        ZhiPuStreamChunk {
+            id: "chatcmpl-1".to_string(),
+            created: 1,
+            model: "glm-4.7-flash".to_string(),
+            choices: vec![ZhiPuStreamChoice {
+                index: 0,
+                delta: ZhiPuDelta {
+                    content: Some(content.to_string()),
+                    ..Default::default()
+                },
+                finish_reason: usage
+                    .as_ref()
+                    .map(|_| "stop".to_string()),
+            }],
+            usage,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_stream_assembles_content()
+    -> anyhow::Result<()> {
+        let usage = ZhiPuUsage {
+            prompt_tokens: 5,
+            completion_tokens: 3,
+            total_tokens: 8,
+        };
+        let chunks = vec![
+            Ok(chunk("Anki ", None)),
+            Ok(chunk("是", None)),
+            Ok(chunk("好工具", Some(usage.clone()))),
+        ];
+
+        let (content, collected) =
+            collect_stream(futures::stream::iter(chunks))
+                .await?;
+        assert_eq!(content, "Anki 是好工具");
+        assert_eq!(collected, Some(usage));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collect_stream_propagates_errors() {
+        let chunks = vec![
+            Ok(chunk("partial", None)),
+            Err(anyhow::anyhow!("connection reset")),
+        ];
+        let result =
+            collect_stream(futures::stream::iter(chunks))
+                .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_sse_decoder_handles_split_events() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"a\":").is_empty());
+        let events =
+            decoder.push(b"1}\n\ndata: [DONE]\r\n\r\n");
+        assert_eq!(events, vec!["{\"a\":1}", "[DONE]"]);
+        assert!(parse_event(&events[1]).is_none());
+    }
+
+    fn sse(content: &str, finish: bool) -> String {
+        let finish = if finish {
+            r#","finish_reason":"stop""#
+        } else {
+            ""
+        };
+        format!(
+            "data: {{\"id\":\"1\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}}{}}}]}}\n\n",
+            content, finish
+        )
+    }
+
+    async fn decode(
+        body: Vec<Result<String, &'static str>>,
+    ) -> Vec<anyhow::Result<ZhiPuStreamChunk>> {
+        decode_stream(futures::stream::iter(body))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_mid_stream_error_is_interrupted() {
+        let items = decode(vec![
+            Ok(sse("Anki ", false)),
+            Ok(sse("是", false)),
+            Err("connection reset"),
+            Ok(sse("never read", false)),
+        ])
+        .await;
+        assert_eq!(items.len(), 3);
+        let error = items[2].as_ref().unwrap_err();
+        let interrupted = error
+            .downcast_ref::<StreamInterrupted>()
+            .unwrap();
+        assert_eq!(
+            interrupted.message,
+            "ZhiPu API stream error: connection reset"
+        );
+        assert_eq!(interrupted.partial, "Anki 是");
+        assert_eq!(
+            error.to_string(),
+            "ZhiPu API stream error: connection reset (after 6 characters)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_early_close_is_interrupted() {
+        let items =
+            decode(vec![Ok(sse("partial", false))]).await;
+        assert!(StreamInterrupted::is_cause_of(
+            items[1].as_ref().unwrap_err()
+        ));
+
+        // A finish reason or [DONE] marks a complete response
+        let finished =
+            decode(vec![Ok(sse("done", true))]).await;
+        assert_eq!(finished.len(), 1);
+        let done = decode(vec![Ok(format!(
+            "{}data: [DONE]\n\n",
+            sse("done", false)
+        ))])
+        .await;
+        assert_eq!(done.len(), 1);
+        assert!(done[0].is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_chunk_is_not_interrupted() {
+        let items = decode(vec![
+            Ok("data: {oops}\n\n".to_string()),
+            Ok(sse("fine", true)),
+        ])
+        .await;
+        assert_eq!(items.len(), 2);
+        let error = items[0].as_ref().unwrap_err();
+        assert!(!StreamInterrupted::is_cause_of(error));
+        assert!(items[1].is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_collect_stream_keeps_partial_on_interrupt()
+     {
+        let error = collect_stream(decode_stream(
+            futures::stream::iter(vec![
+                Ok(sse("半", false)),
+                Err("timeout"),
+            ]),
+        ))
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error
+                .downcast_ref::<StreamInterrupted>()
+                .unwrap()
+                .partial,
+            "半"
+        );
+    }
+
+    #[test]
+    fn test_skip_emitted_drops_resent_content() {
+        let mut skip = 5;
+        assert!(
+            skip_emitted(chunk("Anki", None), &mut skip)
+                .is_none()
+        );
+        let rest =
+            skip_emitted(chunk("是好", None), &mut skip)
+                .unwrap();
+        assert_eq!(
+            rest.choices[0].delta.content.as_deref(),
+            Some("好")
+        );
+        assert_eq!(skip, 0);
+        let next =
+            skip_emitted(chunk("工具", None), &mut skip)
+                .unwrap();
+        assert_eq!(
+            next.choices[0].delta.content.as_deref(),
+            Some("工具")
+        );
+    }
+
+    #[test]
+    fn test_skip_emitted_keeps_final_chunk() {
+        let usage = ZhiPuUsage {
+            prompt_tokens: 1,
+            completion_tokens: 1,
+            total_tokens: 2,
+        };
+        let mut skip = 10;
+        let last = skip_emitted(
+            chunk("end", Some(usage)),
+            &mut skip,
+        )
+        .unwrap();
+        assert_eq!(
+            last.choices[0].delta.content.as_deref(),
+            Some("")
+        );
+        assert!(last.usage.is_some());
+    }
+
+    #[test]
+    fn test_parse_event_chunk() {
+        let payload = r#"{"id":"1","created":1,"model":"glm-4.7","choices":[{"index":0,"delta":{"role":"assistant","content":"你好"}}]}"#;
+        let parsed = parse_event(payload).unwrap().unwrap();
+        assert_eq!(
+            parsed.choices[0].delta.content.as_deref(),
+            Some("你好")
+        );
+        assert!(parsed.usage.is_none());
+    }
+}
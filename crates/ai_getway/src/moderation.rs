@@ -0,0 +1,491 @@
+//! 生成卡片的内容审核
+//!
+//! 生成的卡片在写入 Anki 之前交给 [`ContentFilter`] 逐张判定：
+//! [`Verdict::Flag`] 的卡片照常添加但记入报告，[`Verdict::Block`]
+//! 的卡片不会被添加。[`LocalFilter`] 按词表和正则匹配去掉 HTML 后的
+//! 文本；不需要审核时显式使用 [`NoFilter`]。
+
+use std::future::Future;
+
+use anki_connect::convert::strip_html;
+use regex::Regex;
+
+use crate::structured::{GeneratedCard, GeneratedCardSet};
+
+/// 默认屏蔽的不雅词汇，按整词匹配
+pub const DEFAULT_BLOCKED_WORDS: [&str; 10] = [
+    "fuck",
+    "fucking",
+    "motherfucker",
+    "shit",
+    "bitch",
+    "bastard",
+    "asshole",
+    "cunt",
+    "dick",
+    "whore",
+];
+
+/// 默认屏蔽的个人信息：电子邮件地址
+pub const DEFAULT_BLOCKED_PATTERNS: [&str; 1] =
+    [r"[\w.+-]+@[\w-]+(\.[\w-]+)+"];
+
+/// 默认标记的个人信息：至少 10 位数字、像电话号码的数字串
+pub const DEFAULT_FLAGGED_PATTERNS: [&str; 1] =
+    [r"\+?\d(?:[ ().-]{0,2}\d){9,}"];
+
+/// 对一张卡片的判定
+///
+/// 原因以触发判定的字段名开头，如
+/// `back contains the blocked word "..."`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// 可以添加
+    Allow,
+    /// 可以添加，但需要人工查看
+    Flag(String),
+    /// 不能添加
+    Block(String),
+}
+
+impl Verdict {
+    /// 卡片是否可以添加到 Anki
+    pub fn is_addable(&self) -> bool {
+        !matches!(self, Verdict::Block(_))
+    }
+
+    /// Keeps the more severe of the two verdicts, the first on a tie
+    fn worst(self, other: Verdict) -> Verdict {
+        match (&self, &other) {
+            (Verdict::Block(_), _) => self,
+            (_, Verdict::Block(_)) => other,
+            (Verdict::Flag(_), _) => self,
+            _ => other,
+        }
+    }
+}
+
+/// 卡片内容审核
+///
+/// 实现需要检查卡片的所有字段（正面、背面、`extra` 和标签）。
+/// 调用服务商审核接口的实现在调用失败时返回错误，不应当默认放行。
+pub trait ContentFilter {
+    /// 判定一张卡片
+    fn check(
+        &self,
+        card: &GeneratedCard,
+    ) -> impl Future<Output = anyhow::Result<Verdict>> + Send;
+}
+
+/// 不做任何审核，所有卡片都判定为 [`Verdict::Allow`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFilter;
+
+impl ContentFilter for NoFilter {
+    async fn check(
+        &self,
+        _card: &GeneratedCard,
+    ) -> anyhow::Result<Verdict> {
+        Ok(Verdict::Allow)
+    }
+}
+
+/// 按词表和正则在本地审核
+///
+/// 字段先去掉 HTML 再转为小写后匹配。词汇按整词匹配，`class` 不会
+/// 命中 `ass`；含中日韩文字的词汇没有词边界，按子串匹配。
+#[derive(Debug, Clone, Default)]
+pub struct LocalFilter {
+    blocked_words: Vec<String>,
+    flagged_words: Vec<String>,
+    blocked_patterns: Vec<Regex>,
+    flagged_patterns: Vec<Regex>,
+}
+
+impl LocalFilter {
+    /// 不含任何规则的过滤器
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用默认规则：[`DEFAULT_BLOCKED_WORDS`]、
+    /// [`DEFAULT_BLOCKED_PATTERNS`] 和 [`DEFAULT_FLAGGED_PATTERNS`]
+    pub fn with_defaults() -> Self {
+        let mut filter = Self::new();
+        for word in DEFAULT_BLOCKED_WORDS {
+            filter = filter.block_word(word);
+        }
+        for pattern in DEFAULT_BLOCKED_PATTERNS {
+            filter = filter
+                .block_pattern(pattern)
+                .expect("default patterns are valid");
+        }
+        for pattern in DEFAULT_FLAGGED_PATTERNS {
+            filter = filter
+                .flag_pattern(pattern)
+                .expect("default patterns are valid");
+        }
+        filter
+    }
+
+    /// 出现该词时屏蔽卡片
+    pub fn block_word(mut self, word: &str) -> Self {
+        self.blocked_words.push(word.to_lowercase());
+        self
+    }
+
+    /// 出现该词时标记卡片
+    pub fn flag_word(mut self, word: &str) -> Self {
+        self.flagged_words.push(word.to_lowercase());
+        self
+    }
+
+    /// 匹配该正则时屏蔽卡片
+    ///
+    /// # 返回
+    /// 正则无效时返回错误
+    pub fn block_pattern(
+        mut self,
+        pattern: &str,
+    ) -> anyhow::Result<Self> {
+        self.blocked_patterns.push(compile(pattern)?);
+        Ok(self)
+    }
+
+    /// 匹配该正则时标记卡片
+    ///
+    /// # 返回
+    /// 正则无效时返回错误
+    pub fn flag_pattern(
+        mut self,
+        pattern: &str,
+    ) -> anyhow::Result<Self> {
+        self.flagged_patterns.push(compile(pattern)?);
+        Ok(self)
+    }
+
+    /// 同步判定一张卡片，见 [`ContentFilter::check`]
+    pub fn verdict(&self, card: &GeneratedCard) -> Verdict {
+        card_texts(card).into_iter().fold(
+            Verdict::Allow,
+            |verdict, (field, text)| {
+                verdict
+                    .worst(self.text_verdict(&field, text))
+            },
+        )
+    }
+
+    fn text_verdict(
+        &self,
+        field: &str,
+        text: &str,
+    ) -> Verdict {
+        let text = strip_html(text).to_lowercase();
+        if let Some(word) = self
+            .blocked_words
+            .iter()
+            .find(|word| contains_word(&text, word))
+        {
+            return Verdict::Block(format!(
+                "{} contains the blocked word \"{}\"",
+                field, word
+            ));
+        }
+        if let Some(pattern) = self
+            .blocked_patterns
+            .iter()
+            .find(|pattern| pattern.is_match(&text))
+        {
+            return Verdict::Block(format!(
+                "{} matches the blocked pattern {}",
+                field, pattern
+            ));
+        }
+        if let Some(word) = self
+            .flagged_words
+            .iter()
+            .find(|word| contains_word(&text, word))
+        {
+            return Verdict::Flag(format!(
+                "{} contains the flagged word \"{}\"",
+                field, word
+            ));
+        }
+        if let Some(pattern) = self
+            .flagged_patterns
+            .iter()
+            .find(|pattern| pattern.is_match(&text))
+        {
+            return Verdict::Flag(format!(
+                "{} matches the flagged pattern {}",
+                field, pattern
+            ));
+        }
+        Verdict::Allow
+    }
+}
+
+impl ContentFilter for LocalFilter {
+    async fn check(
+        &self,
+        card: &GeneratedCard,
+    ) -> anyhow::Result<Verdict> {
+        Ok(self.verdict(card))
+    }
+}
+
+/// 一批卡片的审核结果
+///
+/// # 字段
+/// - `verdicts`: 每张卡片及其判定，顺序与输入一致
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModerationReport {
+    pub verdicts: Vec<(GeneratedCard, Verdict)>,
+}
+
+impl ModerationReport {
+    /// 可以添加的卡片（包括被标记的）
+    pub fn addable(&self) -> Vec<&GeneratedCard> {
+        self.verdicts
+            .iter()
+            .filter(|(_, verdict)| verdict.is_addable())
+            .map(|(card, _)| card)
+            .collect()
+    }
+
+    /// 被标记的卡片及原因
+    pub fn flagged(&self) -> Vec<(&GeneratedCard, &str)> {
+        self.verdicts
+            .iter()
+            .filter_map(|(card, verdict)| match verdict {
+                Verdict::Flag(reason) => {
+                    Some((card, reason.as_str()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 被屏蔽的卡片及原因
+    pub fn blocked(&self) -> Vec<(&GeneratedCard, &str)> {
+        self.verdicts
+            .iter()
+            .filter_map(|(card, verdict)| match verdict {
+                Verdict::Block(reason) => {
+                    Some((card, reason.as_str()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// 逐张审核生成的卡片
+///
+/// # 返回
+/// 全部卡片的判定；过滤器出错时返回其错误，不会放行未审核的卡片。
+pub async fn moderate(
+    filter: &impl ContentFilter,
+    cards: GeneratedCardSet,
+) -> anyhow::Result<ModerationReport> {
+    let mut report = ModerationReport::default();
+    for card in cards.cards {
+        let verdict = filter.check(&card).await?;
+        report.verdicts.push((card, verdict));
+    }
+    Ok(report)
+}
+
+fn compile(pattern: &str) -> anyhow::Result<Regex> {
+    // Text is lowercased before matching, so patterns ignore case
+    // as the word lists do
+    Regex::new(&format!("(?i){}", pattern)).map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid moderation pattern {}: {}",
+            pattern,
+            e
+        )
+    })
+}
+
+/// Every generated field with its name, tags included
+fn card_texts(card: &GeneratedCard) -> Vec<(String, &str)> {
+    let mut texts = vec![
+        ("front".to_string(), card.front.as_str()),
+        ("back".to_string(), card.back.as_str()),
+    ];
+    let mut extra: Vec<_> = card.extra.iter().collect();
+    extra.sort();
+    texts.extend(extra.into_iter().map(|(name, value)| {
+        (format!("extra.{}", name), value.as_str())
+    }));
+    texts.extend(
+        card.tags
+            .iter()
+            .map(|tag| ("tags".to_string(), tag.as_str())),
+    );
+    texts
+}
+
+/// Whether `word` occurs in `text` as a whole word. Sides of `word`
+/// that are CJK characters need no boundary, as those scripts do not
+/// separate words with spaces.
+fn contains_word(text: &str, word: &str) -> bool {
+    let (Some(first), Some(last)) =
+        (word.chars().next(), word.chars().next_back())
+    else {
+        return false;
+    };
+    text.match_indices(word).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after =
+            text[start + word.len()..].chars().next();
+        (is_cjk(first) || !before.is_some_and(is_word_char))
+            && (is_cjk(last)
+                || !after.is_some_and(is_word_char))
+    })
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() && !is_cjk(c)
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3040}'..='\u{30FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{AC00}'..='\u{D7AF}'
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn card(front: &str, back: &str) -> GeneratedCard {
+        GeneratedCard {
+            front: front.to_string(),
+            back: back.to_string(),
+            tags: Vec::new(),
+            extra: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_words_match_whole_words_only() {
+        assert!(contains_word("kick his ass", "ass"));
+        assert!(contains_word("ass.", "ass"));
+        assert!(!contains_word("first class", "ass"));
+        assert!(!contains_word("assassin", "ass"));
+        assert!(!contains_word("passé", "ass"));
+        assert!(contains_word("it's ass-backwards", "ass"));
+        // CJK words match inside running text
+        assert!(contains_word("これは馬鹿です", "馬鹿"));
+        assert!(!contains_word("", "ass"));
+        assert!(!contains_word("ass", ""));
+
+        let filter = LocalFilter::new().block_word("ASS");
+        assert_eq!(
+            filter.verdict(&card("class", "assess")),
+            Verdict::Allow
+        );
+        assert!(matches!(
+            filter.verdict(&card("a", "<b>Ass</b>")),
+            Verdict::Block(reason) if reason.starts_with("back ")
+        ));
+    }
+
+    #[test]
+    fn test_html_is_stripped_and_all_fields_checked() {
+        let filter = LocalFilter::with_defaults();
+        // Markup splitting a word does not hide it
+        assert!(
+            !filter
+                .verdict(&card("<i>sh</i>it", "x"))
+                .is_addable()
+        );
+        // ...and attributes are not text
+        assert_eq!(
+            filter.verdict(&card(
+                "<img src=\"dick.png\">",
+                "cat"
+            )),
+            Verdict::Allow
+        );
+
+        let mut tagged = card("猫", "cat");
+        tagged.tags.push("shit".to_string());
+        assert!(!filter.verdict(&tagged).is_addable());
+
+        let mut with_extra = card("猫", "cat");
+        with_extra.extra.insert(
+            "example".to_string(),
+            "Mail me at kim@example.com".to_string(),
+        );
+        assert!(matches!(
+            filter.verdict(&with_extra),
+            Verdict::Block(reason)
+                if reason.starts_with("extra.example ")
+        ));
+    }
+
+    #[test]
+    fn test_block_wins_over_flag() {
+        let filter = LocalFilter::with_defaults()
+            .flag_word("exam")
+            .flag_pattern(r"\bq\d+\b")
+            .unwrap();
+        assert!(matches!(
+            filter.verdict(&card("the exam", "cat")),
+            Verdict::Flag(_)
+        ));
+        assert!(matches!(
+            filter.verdict(&card(
+                "call +81 90 1234 5678",
+                ""
+            )),
+            Verdict::Flag(_)
+        ));
+        assert_eq!(
+            filter
+                .verdict(&card("2024-05-01", "1990-2000")),
+            Verdict::Allow
+        );
+        assert!(matches!(
+            filter.verdict(&card("the exam", "Q12 bitch")),
+            Verdict::Block(_)
+        ));
+        assert!(
+            LocalFilter::new().block_pattern("(").is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_moderate_reports_and_drops_blocked() {
+        let cards = GeneratedCardSet {
+            cards: vec![
+                card("猫", "cat"),
+                card("犬", "dog"),
+                card("shit", "x"),
+            ],
+        };
+        let filter =
+            LocalFilter::with_defaults().flag_word("dog");
+        let report =
+            moderate(&filter, cards.clone()).await.unwrap();
+        assert_eq!(
+            report.addable(),
+            vec![&cards.cards[0], &cards.cards[1]]
+        );
+        assert_eq!(report.flagged().len(), 1);
+        assert_eq!(report.blocked()[0].0, &cards.cards[2]);
+
+        let report =
+            moderate(&NoFilter, cards).await.unwrap();
+        assert_eq!(report.addable().len(), 3);
+    }
+}
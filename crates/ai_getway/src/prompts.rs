@@ -0,0 +1,263 @@
+//! 提示词模板及其查找
+//!
+//! 内置模板见 [`library`]。用户可以在配置目录的 `prompts/` 下放置
+//! `<名称>.txt` 覆盖同名模板（如 `prompts/vocab_card_v1.txt`），不存在
+//! 时使用内置模板；生成器只按名称引用模板，因此替换提示词不需要改代码。
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use utils::paths::AppDir;
+
+pub mod library;
+
+/// 配置目录下存放自定义模板的子目录
+pub const PROMPTS_DIR_NAME: &str = "prompts";
+
+/// 带 `{{变量}}` 占位符的提示词模板
+///
+/// # 字段
+/// - `name`: 查找用的名称，包含版本后缀，如 `vocab_card_v1`
+/// - `version`: 模板版本，修改内置模板的措辞时递增并使用新名称
+/// - `output_schema`: 模板要求模型输出的结构说明
+/// - `text`: 模板正文
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptTemplate {
+    pub name: Cow<'static, str>,
+    pub version: u32,
+    pub output_schema: Cow<'static, str>,
+    pub text: Cow<'static, str>,
+}
+
+impl PromptTemplate {
+    /// 渲染时必须提供的变量，即正文中出现的占位符，按首次出现的顺序
+    pub fn placeholders(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        let mut rest = self.text.as_ref();
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                break;
+            };
+            let name = after[..end].trim();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+            rest = &after[end + 2..];
+        }
+        names
+    }
+
+    /// 用变量替换占位符
+    ///
+    /// # 参数
+    /// - `variables`: 变量名到值的映射
+    ///
+    /// # 返回
+    /// 渲染后的提示词；缺少任何占位符对应的变量时返回错误。
+    pub fn render(
+        &self,
+        variables: &HashMap<&str, &str>,
+    ) -> anyhow::Result<String> {
+        let missing: Vec<&str> = self
+            .placeholders()
+            .into_iter()
+            .filter(|name| !variables.contains_key(name))
+            .collect();
+        anyhow::ensure!(
+            missing.is_empty(),
+            "Prompt {} is missing variables: {}",
+            self.name,
+            missing.join(", ")
+        );
+
+        let mut out =
+            String::with_capacity(self.text.len());
+        let mut rest = self.text.as_ref();
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                out.push_str(&rest[start..]);
+                rest = "";
+                break;
+            };
+            out.push_str(variables[after[..end].trim()]);
+            rest = &after[end + 2..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// 按名称查找模板
+///
+/// 配置目录的 `prompts/<name>.txt` 存在时优先使用，否则使用
+/// [`library`] 中的内置模板。
+///
+/// # 返回
+/// 找不到模板，或自定义模板无法读取时返回错误。
+pub fn get(name: &str) -> anyhow::Result<PromptTemplate> {
+    let dir = AppDir::Config
+        .path()
+        .map(|dir| dir.join(PROMPTS_DIR_NAME));
+    get_from(dir.as_deref(), name)
+}
+
+/// 查找并渲染模板，见 [`get`] 和 [`PromptTemplate::render`]
+pub fn render(
+    name: &str,
+    variables: &HashMap<&str, &str>,
+) -> anyhow::Result<String> {
+    get(name)?.render(variables)
+}
+
+/// Looks `name` up in `override_dir` first, then in the library
+fn get_from(
+    override_dir: Option<&Path>,
+    name: &str,
+) -> anyhow::Result<PromptTemplate> {
+    let built_in = library::BUILT_IN
+        .iter()
+        .find(|template| template.name == name);
+    let path = override_dir
+        .filter(|_| is_plain_name(name))
+        .map(|dir| template_path(dir, name));
+    if let Some(path) = path.filter(|path| path.is_file()) {
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| {
+                format!(
+                    "Failed to read prompt template {}",
+                    path.display()
+                )
+            })?;
+        let base = built_in.cloned().unwrap_or_else(|| {
+            PromptTemplate {
+                name: Cow::Owned(name.to_string()),
+                version: 0,
+                output_schema: Cow::Borrowed(""),
+                text: Cow::Borrowed(""),
+            }
+        });
+        return Ok(PromptTemplate {
+            text: Cow::Owned(text),
+            ..base
+        });
+    }
+    built_in.cloned().with_context(|| {
+        format!("Unknown prompt template {}", name)
+    })
+}
+
+fn template_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.txt", name))
+}
+
+/// Whether `name` can be used as a file name without leaving the
+/// prompts directory
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || c == '_'
+                || c == '-'
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_prompts_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_render_consumes_every_placeholder() {
+        let template = &library::VOCAB_CARD_V1;
+        let variables = HashMap::from([
+            ("language", "Japanese"),
+            ("native_language", "English"),
+            ("words", "猫, 犬"),
+        ]);
+        let prompt = template.render(&variables).unwrap();
+        assert!(!prompt.contains("{{"));
+        assert!(prompt.contains("猫, 犬"));
+
+        let error = template
+            .render(&HashMap::from([(
+                "language", "Japanese",
+            )]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Prompt vocab_card_v1 is missing variables: native_language, words"
+        );
+    }
+
+    #[test]
+    fn test_file_overrides_built_in() {
+        let dir = temp_dir("override");
+        std::fs::write(
+            template_path(&dir, "vocab_card_v1"),
+            "Make {{ language }} cards for {{words}}.",
+        )
+        .unwrap();
+
+        let template =
+            get_from(Some(&dir), "vocab_card_v1").unwrap();
+        assert_eq!(template.version, 1);
+        assert_eq!(
+            template.output_schema,
+            library::VOCAB_CARD_V1.output_schema
+        );
+        assert_eq!(
+            template.placeholders(),
+            ["language", "words"]
+        );
+        assert_eq!(
+            template
+                .render(&HashMap::from([
+                    ("language", "Korean"),
+                    ("words", "고양이"),
+                ]))
+                .unwrap(),
+            "Make Korean cards for 고양이."
+        );
+
+        // Other names still come from the library
+        assert_eq!(
+            get_from(Some(&dir), "translate_gloss_v1")
+                .unwrap(),
+            library::TRANSLATE_GLOSS_V1
+        );
+    }
+
+    #[test]
+    fn test_unknown_and_custom_names() {
+        let dir = temp_dir("custom");
+        assert!(get_from(Some(&dir), "nope_v1").is_err());
+        assert!(get_from(None, "nope_v1").is_err());
+
+        std::fs::write(
+            template_path(&dir, "mnemonic_v1"),
+            "Invent a mnemonic for {{word}}.",
+        )
+        .unwrap();
+        let custom =
+            get_from(Some(&dir), "mnemonic_v1").unwrap();
+        assert_eq!(custom.placeholders(), ["word"]);
+        assert!(
+            get_from(Some(&dir), "../mnemonic_v1").is_err()
+        );
+    }
+}
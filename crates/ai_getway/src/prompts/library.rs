@@ -0,0 +1,209 @@
+//! 内置的语言学习提示词模板
+//!
+//! 模板名称带版本后缀；修改已有模板的措辞时新增 `_v2` 等模板，
+//! 而不是改动旧模板，保证同一名称的输出结构保持不变。
+
+use std::borrow::Cow;
+
+use super::PromptTemplate;
+
+/// 词汇卡片
+///
+/// # 变量
+/// - `language`: 学习的语言，如 `Japanese`
+/// - `native_language`: 释义使用的语言
+/// - `words`: 逗号分隔的单词列表
+///
+/// # 输出
+/// [`crate::structured::GeneratedCardSet`]，`extra` 中包含 `reading`
+/// 和 `example`
+pub const VOCAB_CARD_V1: PromptTemplate = PromptTemplate {
+    name: Cow::Borrowed("vocab_card_v1"),
+    version: 1,
+    output_schema: Cow::Borrowed(
+        r#"{"cards": [{"front": string, "back": string, "tags": [string], "extra": {"reading": string, "example": string}}]}"#,
+    ),
+    text: Cow::Borrowed(
+        "You write flashcards for a learner of {{language}} whose native language is {{native_language}}.\n\
+         Create exactly one card for each of these words: {{words}}\n\
+         - front: the word as written in {{language}}\n\
+         - back: a short, precise definition in {{native_language}}; list distinct senses separated by \"; \"\n\
+         - extra.reading: the pronunciation (kana, pinyin or IPA as usual for {{language}})\n\
+         - extra.example: one natural example sentence in {{language}} using the word\n\
+         - tags: the part of speech in lowercase, e.g. noun\n\
+         Do not add words that were not requested.",
+    ),
+};
+
+/// 例句
+///
+/// # 变量
+/// - `language`: 例句的语言
+/// - `word`: 要使用的单词或短语
+/// - `count`: 例句数量
+/// - `level`: 学习者水平，如 `A2` 或 `JLPT N4`
+///
+/// # 输出
+/// `{"sentences": [{"sentence", "translation"}]}`
+pub const EXAMPLE_SENTENCES_V1: PromptTemplate =
+    PromptTemplate {
+        name: Cow::Borrowed("example_sentences_v1"),
+        version: 1,
+        output_schema: Cow::Borrowed(
+            r#"{"sentences": [{"sentence": string, "translation": string}]}"#,
+        ),
+        text: Cow::Borrowed(
+            "Write {{count}} example sentences in {{language}} that use \"{{word}}\".\n\
+         The learner's level is {{level}}: keep vocabulary and grammar at or below that level, apart from the word itself.\n\
+         Each sentence must show a different typical use of the word and be under 25 words.\n\
+         Give an English translation for each sentence.",
+        ),
+    };
+
+/// 挑选填空位置
+///
+/// # 变量
+/// - `language`: 文本的语言
+/// - `text`: 原文
+/// - `max_clozes`: 最多挑选的填空数
+///
+/// # 输出
+/// `{"clozes": [{"text", "hint"}]}`，`text` 必须是原文中出现的片段
+pub const CLOZE_SELECT_V1: PromptTemplate =
+    PromptTemplate {
+        name: Cow::Borrowed("cloze_select_v1"),
+        version: 1,
+        output_schema: Cow::Borrowed(
+            r#"{"clozes": [{"text": string, "hint": string}]}"#,
+        ),
+        text: Cow::Borrowed(
+            "Select up to {{max_clozes}} spans of the following {{language}} text that are most worth memorising as cloze deletions.\n\
+         Prefer key vocabulary, collocations and grammar points over names and numbers.\n\
+         Each span must be copied exactly from the text, must not overlap another span, and should be at most a few words.\n\
+         Give a short hint for each span that does not reveal it.\n\n\
+         Text:\n{{text}}",
+        ),
+    };
+
+/// 翻译并给出词汇注释
+///
+/// # 变量
+/// - `source_language`: 原文语言
+/// - `target_language`: 译文语言
+/// - `text`: 原文
+///
+/// # 输出
+/// `{"translation", "glossary": [{"term", "gloss"}]}`
+pub const TRANSLATE_GLOSS_V1: PromptTemplate =
+    PromptTemplate {
+        name: Cow::Borrowed("translate_gloss_v1"),
+        version: 1,
+        output_schema: Cow::Borrowed(
+            r#"{"translation": string, "glossary": [{"term": string, "gloss": string}]}"#,
+        ),
+        text: Cow::Borrowed(
+            "Translate the following {{source_language}} text into {{target_language}}.\n\
+         Keep the meaning and register; prefer a natural translation over a literal one.\n\
+         Then list the words and expressions a learner is least likely to know, each with a brief gloss in {{target_language}}, in the order they appear.\n\n\
+         Text:\n{{text}}",
+        ),
+    };
+
+/// 卡片质量评审
+///
+/// # 变量
+/// - `language`: 卡片学习的语言
+/// - `front`: 卡片正面
+/// - `back`: 卡片背面
+///
+/// # 输出
+/// `{"score": 1-5, "issues": [string], "suggestion": string}`
+pub const AUDIT_RUBRIC_V1: PromptTemplate =
+    PromptTemplate {
+        name: Cow::Borrowed("audit_rubric_v1"),
+        version: 1,
+        output_schema: Cow::Borrowed(
+            r#"{"score": integer 1-5, "issues": [string], "suggestion": string}"#,
+        ),
+        text: Cow::Borrowed(
+            "Review this flashcard for a learner of {{language}}.\n\
+         Front: {{front}}\n\
+         Back: {{back}}\n\n\
+         Score it from 1 (unusable) to 5 (excellent) against these criteria:\n\
+         - correct: no factual, spelling or grammar errors\n\
+         - atomic: tests exactly one thing\n\
+         - unambiguous: the front has a single acceptable answer\n\
+         - concise: no text that does not help recall\n\
+         List every problem found, and suggest an improved card, or an empty string if none is needed.",
+        ),
+    };
+
+/// 全部内置模板
+pub const BUILT_IN: [PromptTemplate; 5] = [
+    VOCAB_CARD_V1,
+    EXAMPLE_SENTENCES_V1,
+    CLOZE_SELECT_V1,
+    TRANSLATE_GLOSS_V1,
+    AUDIT_RUBRIC_V1,
+];
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_every_built_in_renders() {
+        let samples = HashMap::from([
+            ("language", "Japanese"),
+            ("native_language", "English"),
+            ("words", "猫, 犬"),
+            ("word", "猫"),
+            ("count", "3"),
+            ("level", "JLPT N4"),
+            ("text", "猫が好きです。"),
+            ("max_clozes", "2"),
+            ("source_language", "Japanese"),
+            ("target_language", "English"),
+            ("front", "猫"),
+            ("back", "cat"),
+        ]);
+        for template in &BUILT_IN {
+            let needed = template.placeholders();
+            assert!(
+                !needed.is_empty(),
+                "{}",
+                template.name
+            );
+            let variables: HashMap<&str, &str> = needed
+                .iter()
+                .map(|name| (*name, samples[name]))
+                .collect();
+            let prompt =
+                template.render(&variables).unwrap();
+            assert!(
+                !prompt.contains("{{")
+                    && !prompt.contains("}}"),
+                "{}",
+                template.name
+            );
+            assert!(template.name.ends_with(&format!(
+                "_v{}",
+                template.version
+            )));
+            assert!(!template.output_schema.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_names_are_unique() {
+        let mut names: Vec<&str> = BUILT_IN
+            .iter()
+            .map(|t| t.name.as_ref())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), BUILT_IN.len());
+    }
+}
@@ -0,0 +1,336 @@
+//! 服务商响应头中的限流信息
+//!
+//! 智谱等服务商在响应中返回 `X-RateLimit-Remaining` 等响应头。
+//! 客户端记录最近一次看到的值，调度方可以在剩余额度较低时主动放慢，
+//! 而不是等到收到 429 再退避。
+
+use std::sync::{Arc, Mutex};
+use std::time::{
+    Duration, Instant, SystemTime, UNIX_EPOCH,
+};
+
+use reqwest::header::HeaderMap;
+
+/// 大于此值的重置时间按 Unix 时间戳（秒）解释
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+/// 一次响应中的限流信息
+///
+/// # 字段
+/// - `limit`: 窗口内允许的请求数
+/// - `remaining`: 窗口内剩余的请求数
+/// - `remaining_tokens`: 窗口内剩余的 token 数
+/// - `reset`: 距离额度重置的时间
+/// - `observed_at`: 收到响应的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset: Option<Duration>,
+    pub observed_at: Instant,
+}
+
+impl RateLimitStatus {
+    /// 从响应头中解析限流信息
+    ///
+    /// 识别 `X-RateLimit-Limit`/`-Remaining`/`-Reset`，以及带
+    /// `-Requests`、`-Tokens` 后缀的形式；重置时间可以是秒数、Unix
+    /// 时间戳或 `1m30s` 这样的时长。
+    ///
+    /// # 返回
+    /// 没有任何可识别的限流响应头时返回 `None`
+    pub fn from_headers(
+        headers: &HeaderMap,
+    ) -> Option<Self> {
+        let number = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                header(headers, name)?.parse::<u64>().ok()
+            })
+        };
+        let status = Self {
+            limit: number(&[
+                "x-ratelimit-limit",
+                "x-ratelimit-limit-requests",
+            ]),
+            remaining: number(&[
+                "x-ratelimit-remaining",
+                "x-ratelimit-remaining-requests",
+            ]),
+            remaining_tokens: number(&[
+                "x-ratelimit-remaining-tokens",
+            ]),
+            reset: [
+                "x-ratelimit-reset",
+                "x-ratelimit-reset-requests",
+            ]
+            .iter()
+            .find_map(|name| {
+                parse_reset(header(headers, name)?)
+            }),
+            observed_at: Instant::now(),
+        };
+        (status.limit.is_some()
+            || status.remaining.is_some()
+            || status.remaining_tokens.is_some()
+            || status.reset.is_some())
+        .then_some(status)
+    }
+
+    /// 剩余请求数占总数的比例，两者都已知时才有值
+    pub fn remaining_fraction(&self) -> Option<f64> {
+        match (self.remaining, self.limit) {
+            (Some(remaining), Some(limit)) if limit > 0 => {
+                Some(remaining as f64 / limit as f64)
+            }
+            _ => None,
+        }
+    }
+
+    /// 按剩余额度建议的发送间隔
+    ///
+    /// 剩余请求数为 0 时等待到重置；否则把距重置的时间平均分给
+    /// 剩余请求。缺少信息或数值无法使用时返回 `None`，表示无需放慢。
+    pub fn suggested_delay(&self) -> Option<Duration> {
+        let reset = self.reset?;
+        let left = reset
+            .saturating_sub(self.observed_at.elapsed());
+        match self.remaining? {
+            0 => Some(left),
+            remaining => left.checked_div(
+                u32::try_from(remaining).ok()?,
+            ),
+        }
+    }
+}
+
+/// 最近一次看到的限流信息，克隆之间共享
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitTracker {
+    latest: Arc<Mutex<Option<RateLimitStatus>>>,
+}
+
+impl RateLimitTracker {
+    /// 记录响应头中的限流信息，没有限流响应头时保留原值
+    pub fn record(&self, headers: &HeaderMap) {
+        if let Some(status) =
+            RateLimitStatus::from_headers(headers)
+        {
+            *self.latest.lock().unwrap() = Some(status);
+        }
+    }
+
+    /// 最近一次看到的限流信息
+    pub fn latest(&self) -> Option<RateLimitStatus> {
+        *self.latest.lock().unwrap()
+    }
+}
+
+fn header<'a>(
+    headers: &'a HeaderMap,
+    name: &str,
+) -> Option<&'a str> {
+    headers
+        .get(name)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Seconds (possibly fractional), a Unix timestamp, or a duration
+/// such as `1m30s` or `250ms`.
+///
+/// The value comes from the server, so anything that does not fit a
+/// `Duration` or `SystemTime` gives `None` instead of panicking.
+fn parse_reset(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        let seconds =
+            Duration::try_from_secs_f64(seconds).ok()?;
+        if seconds.as_secs() > EPOCH_THRESHOLD {
+            let at = UNIX_EPOCH.checked_add(seconds)?;
+            return Some(
+                at.duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            );
+        }
+        return Some(seconds);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|&end| end > 0)?;
+        let amount: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_end] {
+            "ms" => amount / 1000.0,
+            "s" => amount,
+            "m" => amount * 60.0,
+            "h" => amount * 3600.0,
+            _ => return None,
+        };
+        total = total.checked_add(
+            Duration::try_from_secs_f64(seconds).ok()?,
+        )?;
+        rest = &rest[unit_end..];
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        map
+    }
+
+    #[test]
+    fn test_parse_sample_headers() {
+        let status =
+            RateLimitStatus::from_headers(&headers(&[
+                ("X-RateLimit-Limit", "60"),
+                ("X-RateLimit-Remaining", "15"),
+                ("X-RateLimit-Reset", "30"),
+                ("Content-Type", "application/json"),
+            ]))
+            .unwrap();
+        assert_eq!(status.limit, Some(60));
+        assert_eq!(status.remaining, Some(15));
+        assert_eq!(status.remaining_tokens, None);
+        assert_eq!(
+            status.reset,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(status.remaining_fraction(), Some(0.25));
+        let delay = status.suggested_delay().unwrap();
+        assert!(delay <= Duration::from_secs(2));
+        assert!(delay > Duration::from_millis(1900));
+
+        assert_eq!(
+            RateLimitStatus::from_headers(&headers(&[(
+                "Content-Type",
+                "application/json"
+            )])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_suffixed_headers_and_reset_formats() {
+        let status =
+            RateLimitStatus::from_headers(&headers(&[
+                ("x-ratelimit-limit-requests", "100"),
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-remaining-tokens", "9000"),
+                ("x-ratelimit-reset-requests", "1m30s"),
+            ]))
+            .unwrap();
+        assert_eq!(status.remaining, Some(0));
+        assert_eq!(status.remaining_tokens, Some(9000));
+        assert_eq!(
+            status.reset,
+            Some(Duration::from_secs(90))
+        );
+        assert!(
+            status.suggested_delay().unwrap()
+                > Duration::from_secs(89)
+        );
+
+        assert_eq!(
+            parse_reset("250ms"),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_reset("1.5"),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(parse_reset("soon"), None);
+        assert_eq!(parse_reset("5x"), None);
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let reset =
+            parse_reset(&epoch.to_string()).unwrap();
+        assert!(reset > Duration::from_secs(58));
+        assert!(reset <= Duration::from_secs(60));
+        // Garbage values are ignored rather than failing the call
+        assert_eq!(
+            RateLimitStatus::from_headers(&headers(&[(
+                "X-RateLimit-Remaining",
+                "many"
+            )])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unusable_headers_do_not_panic() {
+        for value in [
+            "1e30",
+            "-1",
+            "NaN",
+            "inf",
+            "1e30h",
+            "99999999999999999999h",
+            "18446744073709551615s18446744073709551615s",
+        ] {
+            assert_eq!(
+                parse_reset(value),
+                None,
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            RateLimitStatus::from_headers(&headers(&[(
+                "X-RateLimit-Reset",
+                "1e30"
+            )])),
+            None
+        );
+
+        // More remaining requests than fit a u32 divisor
+        let status =
+            RateLimitStatus::from_headers(&headers(&[
+                ("X-RateLimit-Remaining", "4294967296"),
+                ("X-RateLimit-Reset", "30"),
+            ]))
+            .unwrap();
+        assert_eq!(status.suggested_delay(), None);
+    }
+
+    #[test]
+    fn test_tracker_keeps_latest_status() {
+        let tracker = RateLimitTracker::default();
+        let shared = tracker.clone();
+        assert_eq!(tracker.latest(), None);
+        shared.record(&headers(&[(
+            "X-RateLimit-Remaining",
+            "3",
+        )]));
+        shared.record(&headers(&[("Server", "nginx")]));
+        assert_eq!(
+            tracker.latest().unwrap().remaining,
+            Some(3)
+        );
+    }
+}
@@ -0,0 +1,861 @@
+//! 为中文、日文笔记生成读音字段
+//!
+//! 日文使用 Anki 的振假名语法 `漢字[かんじ]`，中文使用带声调符号的
+//! 拼音。读音由 [`ReadingAnnotator`] 生成，可以是模型
+//! （[`ModelAnnotator`]），也可以是基于词典的本地工具；写入前逐条
+//! 校验格式，只写入目标字段为空的笔记。
+
+use std::future::Future;
+
+use anki_connect::anki::client::AnkiClient;
+use anki_connect::convert::strip_html;
+use anyhow::Context;
+
+use crate::chat::ChatMessage;
+use crate::language::Lang;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::structured::strip_outer_fence;
+
+/// 拼音的韵母，声调符号已去掉
+const PINYIN_FINALS: &[&str] = &[
+    "a", "o", "e", "ê", "i", "u", "ü", "ai", "ei", "ao",
+    "ou", "an", "en", "ang", "eng", "ong", "er", "ia",
+    "ie", "iao", "iu", "ian", "in", "iang", "ing", "iong",
+    "ua", "uo", "uai", "ui", "uan", "un", "uang", "ueng",
+    "ue", "üe", "üan", "ün",
+];
+
+/// 拼音的声母，零声母不在其中
+const PINYIN_INITIALS: &[&str] = &[
+    "zh", "ch", "sh", "b", "p", "m", "f", "d", "t", "n",
+    "l", "g", "k", "h", "j", "q", "x", "r", "z", "c", "s",
+    "y", "w",
+];
+
+/// 读音的写法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadingStyle {
+    /// 原文加注音：日文 `日本語[にほんご]を 読[よ]む`，中文
+    /// `你好[nǐ hǎo]`
+    #[default]
+    Ruby,
+    /// 只有读音：日文为平假名，中文为空格分隔的拼音音节
+    Plain,
+}
+
+/// [`annotate_readings`] 的选项
+///
+/// # 字段
+/// - `language`: [`Lang::Japanese`] 或 [`Lang::Chinese`]
+/// - `source_field`: 读取原文的字段
+/// - `target_field`: 写入读音的字段，已有内容的笔记会被跳过
+/// - `style`: 读音的写法
+/// - `batch_size`: 每次交给 [`ReadingAnnotator`] 的笔记数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadingOptions {
+    pub language: Lang,
+    pub source_field: String,
+    pub target_field: String,
+    pub style: ReadingStyle,
+    pub batch_size: usize,
+}
+
+impl Default for ReadingOptions {
+    fn default() -> Self {
+        Self {
+            language: Lang::Japanese,
+            source_field: "Front".to_string(),
+            target_field: "Reading".to_string(),
+            style: ReadingStyle::default(),
+            batch_size: 20,
+        }
+    }
+}
+
+/// 生成读音
+///
+/// 按 `options` 的语言和写法为每段文本生成读音，返回的列表与
+/// `texts` 一一对应。基于词典的本地工具可以实现此 trait 替代模型。
+pub trait ReadingAnnotator {
+    /// 为一批文本生成读音
+    fn annotate(
+        &self,
+        texts: &[String],
+        options: &ReadingOptions,
+    ) -> impl Future<Output = anyhow::Result<Vec<String>>>;
+}
+
+/// 用模型生成读音
+///
+/// 一批文本作为 JSON 数组发送，要求模型按顺序返回同样长度的 JSON
+/// 字符串数组。
+pub struct ModelAnnotator<F> {
+    complete: F,
+}
+
+impl<F> ModelAnnotator<F>
+where
+    F: AsyncFn(Vec<ChatMessage>) -> anyhow::Result<String>,
+{
+    /// # 参数
+    /// - `complete`: 发送完整对话并返回模型回复的函数
+    pub fn new(complete: F) -> Self {
+        Self { complete }
+    }
+}
+
+impl<F> ReadingAnnotator for ModelAnnotator<F>
+where
+    F: AsyncFn(Vec<ChatMessage>) -> anyhow::Result<String>,
+{
+    async fn annotate(
+        &self,
+        texts: &[String],
+        options: &ReadingOptions,
+    ) -> anyhow::Result<Vec<String>> {
+        let instructions = instructions(options)?;
+        let reply = (self.complete)(vec![
+            ChatMessage::system(format!(
+                "{}\nThe user sends a JSON array of texts. Reply with only a JSON array of strings holding the result for each text, in the same order.",
+                instructions
+            )),
+            ChatMessage::user(serde_json::to_string(texts)?),
+        ])
+        .await?;
+        serde_json::from_str(strip_outer_fence(&reply))
+            .context("The model reply is not a JSON array of strings")
+    }
+}
+
+/// [`annotate_readings`] 的结果
+///
+/// # 字段
+/// - `written`: 写入了读音的笔记
+/// - `already_filled`: 目标字段已有内容而跳过的笔记
+/// - `rejected`: 缺少字段、原文为空、生成失败或读音未通过校验的
+///   笔记及原因
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadingReport {
+    pub written: Vec<u64>,
+    pub already_filled: Vec<u64>,
+    pub rejected: Vec<(u64, String)>,
+}
+
+/// 为 `query` 匹配的笔记生成读音并写入目标字段
+///
+/// 目标字段去掉 HTML 后不为空的笔记不会被修改；原文去掉 HTML 后
+/// 分批交给 `annotator`，每条结果经 [`validate_reading`] 校验后才写入。
+/// 一批生成失败只影响该批笔记。
+///
+/// # 参数
+/// - `annotator`: 生成读音的实现
+/// - `anki_client`: Anki 客户端
+/// - `query`: Anki 搜索语句
+/// - `options`: 语言、字段和写法
+///
+/// # 返回
+/// 语言不是中文或日文、查询笔记或写入 Anki 失败时返回错误。
+pub async fn annotate_readings(
+    annotator: &impl ReadingAnnotator,
+    anki_client: &AnkiClient,
+    query: &str,
+    options: &ReadingOptions,
+) -> anyhow::Result<ReadingReport> {
+    instructions(options)?;
+    let mut report = ReadingReport::default();
+    let note_ids = anki_client.find_notes(query).await?;
+    let notes =
+        anki_client.notes_info_chunked(&note_ids).await?;
+
+    let mut pending = Vec::new();
+    for note in notes {
+        let field = |name: &str| {
+            note.fields
+                .get(name)
+                .map(|field| strip_html(&field.value))
+        };
+        let (Some(source), Some(target)) = (
+            field(&options.source_field),
+            field(&options.target_field),
+        ) else {
+            report.rejected.push((
+                note.note_id,
+                format!(
+                    "Model {} lacks the field {} or {}",
+                    note.model_name,
+                    options.source_field,
+                    options.target_field
+                ),
+            ));
+            continue;
+        };
+        if !target.trim().is_empty() {
+            report.already_filled.push(note.note_id);
+        } else if source.trim().is_empty() {
+            report.rejected.push((
+                note.note_id,
+                format!(
+                    "{} is empty",
+                    options.source_field
+                ),
+            ));
+        } else {
+            pending.push((
+                note.note_id,
+                source.trim().to_string(),
+            ));
+        }
+    }
+
+    for batch in pending.chunks(options.batch_size.max(1)) {
+        let texts: Vec<String> = batch
+            .iter()
+            .map(|(_, text)| text.clone())
+            .collect();
+        let readings =
+            match annotator.annotate(&texts, options).await
+            {
+                Ok(readings)
+                    if readings.len() == texts.len() =>
+                {
+                    readings
+                }
+                Ok(readings) => {
+                    let reason = format!(
+                        "Expected {} readings, got {}",
+                        texts.len(),
+                        readings.len()
+                    );
+                    reject_all(&mut report, batch, &reason);
+                    continue;
+                }
+                Err(e) => {
+                    reject_all(
+                        &mut report,
+                        batch,
+                        &format!("{:#}", e),
+                    );
+                    continue;
+                }
+            };
+
+        for ((note_id, source), reading) in
+            batch.iter().zip(readings)
+        {
+            let reading = reading.trim();
+            if let Err(e) = validate_reading(
+                source,
+                reading,
+                options.language,
+                options.style,
+            ) {
+                report
+                    .rejected
+                    .push((*note_id, format!("{:#}", e)));
+                continue;
+            }
+            anki_client
+                .update_note_field(
+                    *note_id,
+                    &options.target_field,
+                    reading,
+                )
+                .await?;
+            report.written.push(*note_id);
+        }
+    }
+    Ok(report)
+}
+
+/// 使用智谱客户端执行 [`annotate_readings`]
+pub async fn annotate_readings_with_zhi_pu(
+    client: &ZhiPuClient,
+    anki_client: &AnkiClient,
+    query: &str,
+    options: &ReadingOptions,
+) -> anyhow::Result<ReadingReport> {
+    let annotator = ModelAnnotator::new(async |messages| {
+        let request = ZhiPuRequest::new(
+            messages.into_iter().map(Into::into).collect(),
+        );
+        client.complete(request).await?.into_content()
+    });
+    annotate_readings(
+        &annotator,
+        anki_client,
+        query,
+        options,
+    )
+    .await
+}
+
+/// 校验读音的格式
+///
+/// - 振假名语法：每个 `[读音]` 前面的注音对象从上一个空格、`]` 或
+///   开头算起，只能由汉字组成，所以前面紧跟假名或标点的汉字词必须
+///   先加一个半角空格，否则 Anki 会把假名一起当作注音对象；括号必须
+///   成对且不嵌套，读音不能为空，原文中的每个汉字都要有读音，去掉
+///   注音和空格后要与原文一致。日文读音只能是假名；中文读音是拼音，
+///   音节数等于汉字数。
+/// - 只有读音：日文不能含汉字；中文每个音节都要是合法拼音。
+///
+/// 拼音音节之间用空格分隔，每个音节最多一个声调符号，不接受数字
+/// 声调。
+///
+/// # 返回
+/// 第一个问题的说明
+pub fn validate_reading(
+    source: &str,
+    reading: &str,
+    language: Lang,
+    style: ReadingStyle,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !reading.is_empty(),
+        "The reading is empty"
+    );
+    match style {
+        ReadingStyle::Ruby => {
+            let (plain, groups) = parse_ruby(reading)?;
+            for (base, ruby) in &groups {
+                match language {
+                    Lang::Japanese => anyhow::ensure!(
+                        ruby.chars().all(is_kana),
+                        "The reading of {} must be kana, got {}",
+                        base,
+                        ruby
+                    ),
+                    _ => {
+                        let syllables =
+                            validate_pinyin(ruby)?;
+                        let chars = base.chars().count();
+                        anyhow::ensure!(
+                            syllables == chars,
+                            "{} has {} characters but {} syllables ({})",
+                            base,
+                            chars,
+                            syllables,
+                            ruby
+                        );
+                    }
+                }
+            }
+            let squeeze = |text: &str| -> String {
+                text.chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect()
+            };
+            anyhow::ensure!(
+                squeeze(&plain) == squeeze(source),
+                "The annotated text {} does not match the source {}",
+                plain,
+                source
+            );
+        }
+        ReadingStyle::Plain => {
+            if let Some(c) =
+                reading.chars().find(|&c| is_han(c))
+            {
+                anyhow::bail!(
+                    "The reading still contains the character {}",
+                    c
+                );
+            }
+            if language == Lang::Chinese {
+                validate_pinyin(reading)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 校验以空格分隔的带声调拼音，忽略标点和纯数字
+///
+/// # 返回
+/// 音节数
+pub fn validate_pinyin(
+    text: &str,
+) -> anyhow::Result<usize> {
+    let mut syllables = 0;
+    for word in
+        text.split(|c: char| c.is_whitespace() || c == '\'')
+    {
+        let word = word
+            .trim_matches(|c: char| !c.is_alphanumeric());
+        if word.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        anyhow::ensure!(
+            is_pinyin_syllable(word),
+            "{} is not a pinyin syllable with a tone mark",
+            word
+        );
+        syllables += 1;
+    }
+    Ok(syllables)
+}
+
+/// Splits `text` in Anki's furigana syntax into the text without
+/// readings and the `(base, reading)` groups
+fn parse_ruby(
+    text: &str,
+) -> anyhow::Result<(String, Vec<(String, String)>)> {
+    let mut plain = String::new();
+    let mut groups = Vec::new();
+    let mut unread = None;
+    // Anki takes everything since the last space, `>` or reading as
+    // the base of the next reading
+    let mut base_start = 0;
+    let mut rest_start = 0;
+    while let Some(offset) =
+        text[rest_start..].find(['[', ']', ' ', '>'])
+    {
+        let at = rest_start + offset;
+        match text.as_bytes()[at] {
+            b'[' => {
+                let base = &text[base_start..at];
+                anyhow::ensure!(
+                    !base.is_empty(),
+                    "A reading has nothing before it at byte {}",
+                    at
+                );
+                let close = text[at + 1..]
+                    .find(']')
+                    .map(|end| at + 1 + end)
+                    .with_context(|| {
+                        format!("Unclosed [ after {}", base)
+                    })?;
+                let ruby = &text[at + 1..close];
+                anyhow::ensure!(
+                    !ruby.contains('['),
+                    "Nested [ in the reading of {}",
+                    base
+                );
+                anyhow::ensure!(
+                    !ruby.trim().is_empty(),
+                    "Empty reading for {}",
+                    base
+                );
+                if let Some(c) =
+                    base.chars().find(|&c| !is_han(c))
+                {
+                    anyhow::bail!(
+                        "The reading of {} also covers {}; put a space before the word it belongs to",
+                        base,
+                        c
+                    );
+                }
+                plain.push_str(base);
+                groups.push((
+                    base.to_string(),
+                    ruby.to_string(),
+                ));
+                base_start = close + 1;
+                rest_start = close + 1;
+            }
+            b']' => {
+                anyhow::bail!("Unmatched ] at byte {}", at)
+            }
+            _ => {
+                let piece = &text[base_start..at];
+                unread = unread
+                    .or(piece.chars().find(|&c| is_han(c)));
+                plain.push_str(piece);
+                // The space before a base is swallowed by Anki; keep
+                // other separators in the text
+                if text.as_bytes()[at] == b'>' {
+                    plain.push('>');
+                }
+                base_start = at + 1;
+                rest_start = at + 1;
+            }
+        }
+    }
+    let piece = &text[base_start..];
+    unread = unread.or(piece.chars().find(|&c| is_han(c)));
+    plain.push_str(piece);
+
+    if let Some(c) = unread {
+        anyhow::bail!("{} has no reading", c);
+    }
+    Ok((plain, groups))
+}
+
+fn is_pinyin_syllable(word: &str) -> bool {
+    let mut marks = 0;
+    let mut bare = String::with_capacity(word.len());
+    for c in word.to_lowercase().chars() {
+        let base = match c {
+            'ā' | 'á' | 'ǎ' | 'à' => 'a',
+            'ē' | 'é' | 'ě' | 'è' => 'e',
+            'ī' | 'í' | 'ǐ' | 'ì' => 'i',
+            'ō' | 'ó' | 'ǒ' | 'ò' => 'o',
+            'ū' | 'ú' | 'ǔ' | 'ù' => 'u',
+            'ǖ' | 'ǘ' | 'ǚ' | 'ǜ' => 'ü',
+            'a'..='z' | 'ü' | 'ê' => {
+                bare.push(c);
+                continue;
+            }
+            _ => return false,
+        };
+        marks += 1;
+        bare.push(base);
+    }
+    // Erhua adds a trailing r to any syllable but er itself
+    marks <= 1
+        && (is_bare_syllable(&bare)
+            || bare.strip_suffix('r').is_some_and(|stem| {
+                stem != "e" && is_bare_syllable(stem)
+            }))
+}
+
+fn is_bare_syllable(bare: &str) -> bool {
+    let final_part = PINYIN_INITIALS
+        .iter()
+        .find_map(|initial| {
+            bare.strip_prefix(initial)
+                .filter(|rest| !rest.is_empty())
+        })
+        .unwrap_or(bare);
+    PINYIN_FINALS.contains(&final_part)
+}
+
+fn is_han(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '々'
+            | '〆'
+            | 'ヶ'
+    )
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}')
+}
+
+/// The task description for the model
+fn instructions(
+    options: &ReadingOptions,
+) -> anyhow::Result<&'static str> {
+    Ok(match (options.language, options.style) {
+        (Lang::Japanese, ReadingStyle::Ruby) => {
+            "Add furigana to each Japanese text in Anki's bracket syntax: write the hiragana reading in square brackets right after each word written in kanji, and put a half-width space before every such word unless it starts the text, e.g. 日本語[にほんご]を 勉強[べんきょう]する. Every kanji needs a reading; keep kana, punctuation and everything else unchanged."
+        }
+        (Lang::Japanese, ReadingStyle::Plain) => {
+            "Write the full reading of each Japanese text in hiragana, keeping punctuation."
+        }
+        (Lang::Chinese, ReadingStyle::Ruby) => {
+            "Add pinyin to each Chinese text in Anki's bracket syntax: write the pinyin with tone marks in square brackets right after each word, one space-separated syllable per character, and put a half-width space before every word unless it starts the text, e.g. 我[wǒ] 喜欢[xǐ huan] 你[nǐ]. Keep punctuation unchanged."
+        }
+        (Lang::Chinese, ReadingStyle::Plain) => {
+            "Write the pinyin of each Chinese text with tone marks, one space-separated syllable per character, keeping punctuation."
+        }
+        (language, _) => anyhow::bail!(
+            "Readings are only generated for Chinese and Japanese, not {:?}",
+            language
+        ),
+    })
+}
+
+fn reject_all(
+    report: &mut ReadingReport,
+    batch: &[(u64, String)],
+    reason: &str,
+) {
+    report.rejected.extend(batch.iter().map(
+        |(note_id, _)| (*note_id, reason.to_string()),
+    ));
+}
+
+#[cfg(test)]
+mod test {
+    use anki_connect::anki::mock::{MockAnki, ok};
+    use serde_json::json;
+
+    use super::*;
+
+    fn furigana(
+        source: &str,
+        reading: &str,
+    ) -> anyhow::Result<()> {
+        validate_reading(
+            source,
+            reading,
+            Lang::Japanese,
+            ReadingStyle::Ruby,
+        )
+    }
+
+    #[test]
+    fn test_furigana_syntax() {
+        furigana(
+            "日本語を勉強する",
+            "日本語[にほんご]を 勉強[べんきょう]する",
+        )
+        .unwrap();
+        furigana("読む", "読[よ]む").unwrap();
+        furigana("人々", "人々[ひとびと]").unwrap();
+        // Adjacent kanji words need no space between them
+        furigana("東京駅", "東京[とうきょう]駅[えき]")
+            .unwrap();
+        furigana("ひらがな", "ひらがな").unwrap();
+
+        // Without the space Anki puts を under the reading too
+        let error = furigana(
+            "日本語を勉強する",
+            "日本語[にほんご]を勉強[べんきょう]する",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("put a space"));
+        assert!(furigana("お茶", "お茶[ちゃ]").is_err());
+
+        for broken in [
+            "日本語[にほんご",
+            "日本語]にほんご[",
+            "日本語[にほ[ん]ご]",
+            "日本語[]",
+            "[にほんご]",
+            "日本語[nihongo]",
+        ] {
+            assert!(
+                furigana("日本語", broken).is_err(),
+                "{}",
+                broken
+            );
+        }
+        // Every kanji needs a reading and nothing may be dropped
+        assert!(
+            furigana(
+                "日本語を勉強する",
+                "日本語[にほんご]を勉強する"
+            )
+            .is_err()
+        );
+        assert!(
+            furigana("日本語を", "日本語[にほんご]")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_pinyin_syntax() {
+        assert_eq!(validate_pinyin("nǐ hǎo").unwrap(), 2);
+        assert_eq!(
+            validate_pinyin("Wǒ xǐ huan nǚ ér, lǜ!")
+                .unwrap(),
+            6
+        );
+        assert_eq!(
+            validate_pinyin("huār zhuàng").unwrap(),
+            2
+        );
+        assert_eq!(validate_pinyin("Xī'ān").unwrap(), 2);
+        for broken in
+            ["ni3 hao3", "nǐǎ", "hello", "xǐhuan", "qǔng"]
+        {
+            assert!(
+                validate_pinyin(broken).is_err(),
+                "{}",
+                broken
+            );
+        }
+
+        let chinese = |source, reading| {
+            validate_reading(
+                source,
+                reading,
+                Lang::Chinese,
+                ReadingStyle::Ruby,
+            )
+        };
+        chinese(
+            "我喜欢你。",
+            "我[wǒ] 喜欢[xǐ huan] 你[nǐ]。",
+        )
+        .unwrap();
+        assert!(chinese("喜欢", "喜欢[xǐ]").is_err());
+        assert!(chinese("喜欢", "喜欢[xi3 huan]").is_err());
+
+        let plain = |language, source, reading| {
+            validate_reading(
+                source,
+                reading,
+                language,
+                ReadingStyle::Plain,
+            )
+        };
+        plain(Lang::Chinese, "你好！", "nǐ hǎo!").unwrap();
+        plain(Lang::Japanese, "日本語", "にほんご")
+            .unwrap();
+        assert!(
+            plain(Lang::Japanese, "日本語", "日本ご")
+                .is_err()
+        );
+        assert!(
+            plain(Lang::Chinese, "你好", "ni hao").is_ok()
+        );
+        assert!(
+            plain(Lang::Chinese, "你好", "nǐhǎo").is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_empty_fields_are_written() {
+        let mock = MockAnki::start(|action, _| match action {
+            "findNotes" => ok(json!([1, 2, 3, 4])),
+            "notesInfo" => {
+                let note = |id: u64, front: &str, reading: &str| {
+                    json!({
+                        "noteId": id,
+                        "modelName": "Japanese",
+                        "tags": [],
+                        "fields": {
+                            "Front": {"value": front, "order": 0},
+                            "Reading": {"value": reading, "order": 1},
+                        },
+                        "cards": [],
+                    })
+                };
+                ok(json!([
+                    note(1, "日本語", ""),
+                    note(2, "勉強", "べんきょう"),
+                    note(3, "<b>お茶</b>", "<br>"),
+                    {
+                        "noteId": 4,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {"Front": {"value": "猫", "order": 0}},
+                        "cards": [],
+                    },
+                ]))
+            }
+            "updateNoteFields" => ok(json!(null)),
+            other => panic!("unexpected action {}", other),
+        })
+        .await;
+
+        let annotator = ModelAnnotator::new(
+            async |messages: Vec<ChatMessage>| {
+                assert!(
+                    messages[0]
+                        .content
+                        .contains("furigana")
+                );
+                let texts: Vec<String> =
+                    serde_json::from_str(
+                        &messages[1].content,
+                    )
+                    .unwrap();
+                Ok(serde_json::to_string(
+                    &texts
+                        .iter()
+                        .map(|text| match text.as_str() {
+                            "日本語" => {
+                                "日本語[にほんご]"
+                            }
+                            // Missing space before the base
+                            _ => "お茶[ちゃ]",
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .unwrap())
+            },
+        );
+        let report = annotate_readings(
+            &annotator,
+            &mock.client(),
+            "deck:Japanese",
+            &ReadingOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.written, [1]);
+        assert_eq!(report.already_filled, [2]);
+        let rejected: Vec<u64> = report
+            .rejected
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(rejected, [4, 3]);
+        assert!(
+            report.rejected[1].1.contains("put a space")
+        );
+        assert_eq!(
+            mock.actions(),
+            ["findNotes", "notesInfo", "updateNoteFields"]
+        );
+        assert_eq!(
+            mock.requests()[2]["params"]["note"],
+            json!({"id": 1, "fields": {"Reading": "日本語[にほんご]"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_writes_nothing() {
+        let mock = MockAnki::start(|action, _| match action {
+            "findNotes" => ok(json!([1])),
+            "notesInfo" => ok(json!([{
+                "noteId": 1,
+                "modelName": "Chinese",
+                "tags": [],
+                "fields": {
+                    "Front": {"value": "你好", "order": 0},
+                    "Reading": {"value": "", "order": 1},
+                },
+                "cards": [],
+            }])),
+            other => panic!("unexpected action {}", other),
+        })
+        .await;
+        let options = ReadingOptions {
+            language: Lang::Chinese,
+            ..Default::default()
+        };
+
+        for reply in ["not json", r#"["nǐ hǎo", "extra"]"#]
+        {
+            let annotator =
+                ModelAnnotator::new(async |_| {
+                    Ok(reply.to_string())
+                });
+            let report = annotate_readings(
+                &annotator,
+                &mock.client(),
+                "deck:Chinese",
+                &options,
+            )
+            .await
+            .unwrap();
+            assert!(report.written.is_empty());
+            assert_eq!(report.rejected.len(), 1);
+        }
+        assert!(
+            !mock
+                .actions()
+                .iter()
+                .any(|a| a == "updateNoteFields")
+        );
+
+        let english = ReadingOptions {
+            language: Lang::English,
+            ..Default::default()
+        };
+        let annotator = ModelAnnotator::new(async |_| {
+            Ok("[]".to_string())
+        });
+        assert!(
+            annotate_readings(
+                &annotator,
+                &mock.client(),
+                "",
+                &english
+            )
+            .await
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! 在一批调用之间共享的重试次数上限
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// 一批调用共享的重试预算
+///
+/// 每次重试前从预算中扣除一次；预算用完后，剩余的调用在第一次失败时
+/// 直接返回错误，不再等待重试，避免服务整体不可用时整批任务被逐个
+/// 拖慢。克隆之间共享同一份预算，可以在并发调用中安全使用。
+#[derive(Debug, Clone)]
+pub struct RetryBudget {
+    remaining: Arc<AtomicU32>,
+}
+
+impl RetryBudget {
+    /// 创建预算
+    ///
+    /// # 参数
+    /// - `retries`: 整批调用合计允许的重试次数
+    pub fn new(retries: u32) -> Self {
+        Self {
+            remaining: Arc::new(AtomicU32::new(retries)),
+        }
+    }
+
+    /// 剩余的重试次数
+    pub fn remaining(&self) -> u32 {
+        self.remaining.load(Ordering::SeqCst)
+    }
+
+    /// 是否已经用完
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// 扣除一次重试
+    ///
+    /// # 返回
+    /// 预算已用完时返回 `false`，此时不应重试。
+    pub fn try_take(&self) -> bool {
+        let mut left = self.remaining();
+        while left > 0 {
+            match self.remaining.compare_exchange(
+                left,
+                left - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => left = actual,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_budget_is_shared_and_never_negative() {
+        let budget = RetryBudget::new(3);
+        let clone = budget.clone();
+        let taken: usize = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let budget = clone.clone();
+                    scope.spawn(move || {
+                        (0..4)
+                            .filter(|_| budget.try_take())
+                            .count()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum()
+        });
+        assert_eq!(taken, 3);
+        assert!(budget.is_exhausted());
+        assert!(!budget.try_take());
+    }
+}
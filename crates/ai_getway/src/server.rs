@@ -0,0 +1,931 @@
+//! 接收卡片提交的本地 HTTP 服务
+//!
+//! 手机、浏览器扩展和脚本把候选卡片 POST 到这里，由服务完成校验、
+//! 可选的模型润色和添加到 Anki：
+//! - `POST /notes`: 请求体与 [`Note`] 相同，另可带 `"enrich": true`
+//! - `POST /generate`: 请求体为原文，交给 [`generate_cards`] 生成卡片
+//! - `GET /healthz`: 报告 Anki 和模型服务商是否可达
+//!
+//! 添加笔记一律经过 [`QueueingClient`]，Anki 不可达时笔记进入离线
+//! 队列并返回 `202 Accepted`，不会丢失。
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anki_connect::anki::client::Note;
+use anki_connect::anki::note::NoteFields;
+use anki_connect::anki::queue::{
+    QueuedAdd, QueueingClient,
+};
+use anyhow::Context;
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::catalog::list_models;
+use crate::chat::ChatMessage;
+use crate::error::HttpStatusError;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::structured::{
+    CardSpec, GeneratedCard, generate_cards,
+    strip_outer_fence,
+};
+
+/// 默认监听地址，只接受本机连接
+///
+/// 端口紧挨着 AnkiConnect 的 8765。
+pub const DEFAULT_BIND_ADDR: &str = "127.0.0.1:8766";
+
+/// `/generate` 默认使用的生成任务说明
+pub const DEFAULT_GENERATE_TASK: &str = "Create flashcards covering the key facts of the text. Put the question on the front and a short answer on the back.";
+
+/// `enrich` 默认使用的系统提示词
+pub const DEFAULT_ENRICH_PROMPT: &str = "You improve flashcards. The user sends the fields of one note as a JSON object. Fix mistakes, make the wording clear and concise, and fill empty fields when the other fields make their content obvious. Reply with a JSON object with exactly the same keys and nothing else.";
+
+/// 发送完整对话并返回模型回复的函数
+type CompleteFn = dyn Fn(
+        Vec<ChatMessage>,
+    ) -> BoxFuture<'static, anyhow::Result<String>>
+    + Send
+    + Sync;
+
+/// 检查服务商是否可达的函数
+type CheckFn = dyn Fn() -> BoxFuture<'static, anyhow::Result<()>>
+    + Send
+    + Sync;
+
+/// 服务使用的模型服务商
+///
+/// 由补全函数和可达性检查组成，可以接入任意服务商；智谱见
+/// [`Provider::zhi_pu`]。
+#[derive(Clone)]
+pub struct Provider {
+    complete: Arc<CompleteFn>,
+    check: Arc<CheckFn>,
+}
+
+impl Provider {
+    /// 创建服务商
+    ///
+    /// # 参数
+    /// - `complete`: 发送完整对话并返回模型回复的函数
+    /// - `check`: 服务商可达时返回 `Ok(())` 的函数，供 `/healthz` 使用
+    pub fn new<C, CF, K, KF>(complete: C, check: K) -> Self
+    where
+        C: Fn(Vec<ChatMessage>) -> CF
+            + Send
+            + Sync
+            + 'static,
+        CF: Future<Output = anyhow::Result<String>>
+            + Send
+            + 'static,
+        K: Fn() -> KF + Send + Sync + 'static,
+        KF: Future<Output = anyhow::Result<()>>
+            + Send
+            + 'static,
+    {
+        Self {
+            complete: Arc::new(move |messages| {
+                Box::pin(complete(messages))
+            }),
+            check: Arc::new(move || Box::pin(check())),
+        }
+    }
+
+    /// 使用智谱客户端的服务商
+    ///
+    /// 可达性检查请求 `GET {base_url}/models`，不消耗 token；接口
+    /// 不存在（HTTP 404/405/501）也说明服务商可达。
+    pub fn zhi_pu(client: ZhiPuClient) -> Self {
+        let checked = client.clone();
+        Self::new(
+            move |messages: Vec<ChatMessage>| {
+                let client = client.clone();
+                async move {
+                    let request = ZhiPuRequest::new(
+                        messages
+                            .into_iter()
+                            .map(Into::into)
+                            .collect(),
+                    );
+                    client
+                        .complete(request)
+                        .await?
+                        .into_content()
+                }
+            },
+            move || {
+                let client = checked.clone();
+                async move {
+                    let key = client.keys().acquire()?;
+                    match list_models(
+                        key.expose(),
+                        client.base_url(),
+                    )
+                    .await
+                    {
+                        Err(e)
+                            if e
+                                .downcast_ref::<HttpStatusError>()
+                                .is_some_and(|e| {
+                                    matches!(
+                                        e.status,
+                                        404 | 405 | 501
+                                    )
+                                }) =>
+                        {
+                            Ok(())
+                        }
+                        other => other.map(|_| ()),
+                    }
+                }
+            },
+        )
+    }
+
+    async fn complete(
+        &self,
+        messages: Vec<ChatMessage>,
+    ) -> anyhow::Result<String> {
+        (self.complete)(messages).await
+    }
+}
+
+impl std::fmt::Debug for Provider {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("Provider").finish_non_exhaustive()
+    }
+}
+
+/// 卡片提交服务
+///
+/// 用 `with_*` 方法配置后调用 [`Server::serve`]。
+///
+/// # 字段
+/// - `anki`: 添加笔记使用的客户端
+/// - `provider`: 模型服务商；未设置时 `/generate` 和 `enrich` 返回 503
+/// - `token`: 设置后每个请求都必须带 `Authorization: Bearer <token>`
+/// - `bind`: 监听地址，默认 [`DEFAULT_BIND_ADDR`]
+/// - `spec`: `/generate` 的卡片要求
+/// - `deck_name` / `model_name`: `/generate` 生成的笔记所在的牌组和
+///   笔记类型，卡片的 `front`、`back` 和额外字段按同名字段写入
+/// - `enrich_prompt`: `enrich` 使用的系统提示词
+#[derive(Debug, Clone)]
+pub struct Server {
+    anki: QueueingClient,
+    provider: Option<Provider>,
+    token: Option<String>,
+    bind: SocketAddr,
+    spec: CardSpec,
+    deck_name: String,
+    model_name: String,
+    enrich_prompt: String,
+}
+
+impl Server {
+    /// 使用默认配置创建服务
+    pub fn new(anki: QueueingClient) -> Self {
+        Self {
+            anki,
+            provider: None,
+            token: None,
+            bind: DEFAULT_BIND_ADDR
+                .parse()
+                .expect("valid default address"),
+            spec: CardSpec::new(DEFAULT_GENERATE_TASK),
+            deck_name: "Default".to_string(),
+            model_name: "Basic".to_string(),
+            enrich_prompt: DEFAULT_ENRICH_PROMPT
+                .to_string(),
+        }
+    }
+
+    /// 设置模型服务商
+    pub fn with_provider(
+        mut self,
+        provider: Provider,
+    ) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// 要求请求携带的 Bearer token，`None` 表示不校验
+    pub fn with_token(
+        mut self,
+        token: Option<String>,
+    ) -> Self {
+        self.token = token.filter(|t| !t.is_empty());
+        self
+    }
+
+    /// 设置监听地址
+    pub fn with_bind(mut self, bind: SocketAddr) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// 设置 `/generate` 的卡片要求
+    pub fn with_card_spec(
+        mut self,
+        spec: CardSpec,
+    ) -> Self {
+        self.spec = spec;
+        self
+    }
+
+    /// 设置 `/generate` 生成的笔记所在的牌组和笔记类型
+    pub fn with_generate_target(
+        mut self,
+        deck_name: impl Into<String>,
+        model_name: impl Into<String>,
+    ) -> Self {
+        self.deck_name = deck_name.into();
+        self.model_name = model_name.into();
+        self
+    }
+
+    /// 设置 `enrich` 使用的系统提示词
+    pub fn with_enrich_prompt(
+        mut self,
+        prompt: impl Into<String>,
+    ) -> Self {
+        self.enrich_prompt = prompt.into();
+        self
+    }
+
+    /// 服务的路由，可以嵌入其他 axum 应用
+    pub fn router(self) -> Router {
+        let state = Arc::new(self);
+        Router::new()
+            .route("/notes", post(submit_note))
+            .route("/generate", post(generate))
+            .route("/healthz", get(healthz))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize,
+            ))
+            .with_state(state)
+    }
+
+    /// 在配置的地址上监听并处理请求，直到出错
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(self.bind)
+            .await
+            .with_context(|| {
+            format!("Failed to bind {}", self.bind)
+        })?;
+        self.serve_on(listener).await
+    }
+
+    /// 在已绑定的 `listener` 上处理请求，忽略配置的地址
+    pub async fn serve_on(
+        self,
+        listener: TcpListener,
+    ) -> anyhow::Result<()> {
+        log::info!(
+            "Listening for card submissions on {}",
+            listener.local_addr()?
+        );
+        axum::serve(listener, self.router())
+            .await
+            .context("Server stopped")
+    }
+
+    fn provider(&self) -> Result<&Provider, ApiError> {
+        self.provider.as_ref().ok_or_else(|| {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No model provider is configured"
+                    .to_string(),
+            )
+        })
+    }
+
+    /// Adds `note`, queueing it if Anki is unreachable
+    async fn add(&self, note: Note) -> AddResult {
+        match self.anki.add_note(note).await {
+            Ok(QueuedAdd::Added(note_id)) => {
+                AddResult::Added { note_id }
+            }
+            Ok(QueuedAdd::Queued(seq)) => {
+                AddResult::Queued { seq }
+            }
+            Err(e) => AddResult::Failed {
+                error: format!("{:#}", e),
+            },
+        }
+    }
+
+    /// Rewrites the fields of `note` with the provider
+    async fn enrich(
+        &self,
+        note: &mut Note,
+    ) -> anyhow::Result<()> {
+        let provider = self
+            .provider
+            .as_ref()
+            .context("No model provider is configured")?;
+        let reply = provider
+            .complete(vec![
+                ChatMessage::system(
+                    self.enrich_prompt.clone(),
+                ),
+                ChatMessage::user(serde_json::to_string(
+                    &note.fields,
+                )?),
+            ])
+            .await?;
+        let fields: HashMap<String, String> =
+            serde_json::from_str(strip_outer_fence(&reply))
+                .context(
+                    "The model did not reply with a JSON object of fields",
+                )?;
+        for (name, value) in fields {
+            if let Some(field) = note.fields.get_mut(&name)
+                && !value.trim().is_empty()
+            {
+                *field = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// Note for a generated card
+    fn to_note(&self, card: GeneratedCard) -> Note {
+        let mut fields = NoteFields::from([
+            ("Front", card.front),
+            ("Back", card.back),
+        ]);
+        for (name, value) in NoteFields::from(card.extra) {
+            fields.entry(name).or_insert(value);
+        }
+        Note {
+            model_name: self.model_name.clone(),
+            deck_name: self.deck_name.clone(),
+            fields,
+            tags: card.tags,
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        }
+    }
+}
+
+/// Body of `POST /notes`
+#[derive(Debug, Deserialize)]
+struct NoteSubmission {
+    #[serde(flatten)]
+    note: Note,
+    /// Rewrite the fields with the provider before adding
+    #[serde(default)]
+    enrich: bool,
+}
+
+/// Outcome of adding one note
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum AddResult {
+    Added { note_id: u64 },
+    Queued { seq: u64 },
+    Failed { error: String },
+}
+
+/// Response of `POST /notes`
+#[derive(Debug, Serialize)]
+struct NoteResponse {
+    #[serde(flatten)]
+    result: AddResult,
+    /// Whether the requested enrichment was applied
+    enriched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enrich_error: Option<String>,
+}
+
+/// Reachability of one dependency in `GET /healthz`
+#[derive(Debug, Serialize)]
+struct Reachability {
+    reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<anyhow::Result<()>> for Reachability {
+    fn from(result: anyhow::Result<()>) -> Self {
+        Self {
+            reachable: result.is_ok(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+/// Response of `GET /healthz`
+#[derive(Debug, Serialize)]
+struct Health {
+    anki: Reachability,
+    /// `None` when no provider is configured
+    provider: Option<Reachability>,
+    /// Notes waiting in the offline queue
+    queued: usize,
+}
+
+/// Error answered as `{"error": message}`
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.0,
+            Json(serde_json::json!({ "error": self.1 })),
+        )
+            .into_response()
+    }
+}
+
+/// Rejects requests without the configured bearer token
+async fn authorize(
+    State(server): State<Arc<Server>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = &server.token else {
+        return next.run(request).await;
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if given.is_some_and(|given| {
+        constant_time_eq(given.as_bytes(), token.as_bytes())
+    }) {
+        next.run(request).await
+    } else {
+        ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid bearer token".to_string(),
+        )
+        .into_response()
+    }
+}
+
+/// Compares without stopping at the first difference, so the time
+/// taken does not reveal the matching prefix of the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+async fn submit_note(
+    State(server): State<Arc<Server>>,
+    Json(submission): Json<NoteSubmission>,
+) -> Result<(StatusCode, Json<NoteResponse>), ApiError> {
+    let mut note = submission.note;
+    validate(&note).map_err(|e| {
+        ApiError(StatusCode::BAD_REQUEST, e)
+    })?;
+
+    let mut enrich_error = None;
+    if submission.enrich {
+        server.provider()?;
+        if let Err(e) = server.enrich(&mut note).await {
+            // The note is still added as submitted
+            log::warn!("Enrichment failed: {:#}", e);
+            enrich_error = Some(format!("{:#}", e));
+        }
+    }
+
+    let result = server.add(note).await;
+    let status = match &result {
+        AddResult::Added { .. } => StatusCode::CREATED,
+        AddResult::Queued { .. } => StatusCode::ACCEPTED,
+        AddResult::Failed { .. } => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+    };
+    Ok((
+        status,
+        Json(NoteResponse {
+            result,
+            enriched: submission.enrich
+                && enrich_error.is_none(),
+            enrich_error,
+        }),
+    ))
+}
+
+async fn generate(
+    State(server): State<Arc<Server>>,
+    text: String,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if text.trim().is_empty() {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            "The request body is empty".to_string(),
+        ));
+    }
+    let provider = server.provider()?;
+    let cards =
+        generate_cards(&server.spec, &text, |messages| {
+            provider.complete(messages)
+        })
+        .await
+        .map_err(|e| {
+            ApiError(
+                StatusCode::BAD_GATEWAY,
+                format!("{:#}", e),
+            )
+        })?;
+
+    let mut results = Vec::with_capacity(cards.cards.len());
+    for card in cards.cards {
+        results
+            .push(server.add(server.to_note(card)).await);
+    }
+    Ok(Json(serde_json::json!({ "results": results })))
+}
+
+async fn healthz(
+    State(server): State<Arc<Server>>,
+) -> Result<Json<Health>, ApiError> {
+    let anki = server.anki.version().await.map(|_| ());
+    let provider = match &server.provider {
+        Some(provider) => {
+            Some((provider.check)().await.into())
+        }
+        None => None,
+    };
+    let queued = server
+        .anki
+        .queue()
+        .pending()
+        .map_err(|e| {
+            ApiError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{:#}", e),
+            )
+        })?
+        .len();
+    Ok(Json(Health {
+        anki: anki.into(),
+        provider,
+        queued,
+    }))
+}
+
+/// Problems that would make Anki reject `note` for sure
+fn validate(note: &Note) -> Result<(), String> {
+    if note.model_name.trim().is_empty() {
+        return Err("modelName is empty".to_string());
+    }
+    if note.deck_name.trim().is_empty() {
+        return Err("deckName is empty".to_string());
+    }
+    if note.fields.values().all(|v| v.trim().is_empty()) {
+        return Err("Every field is empty".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anki_connect::anki::client::AnkiClient;
+    use anki_connect::anki::mock::{MockAnki, err, ok};
+    use anki_connect::anki::queue::OfflineQueue;
+    use serde_json::{Value, json};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_server_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Client for a port nothing listens on
+    fn unreachable_client() -> AnkiClient {
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        AnkiClient::with_url(format!("http://{}", addr))
+    }
+
+    /// Starts `server` on an ephemeral port; returns its base URL
+    async fn start(server: Server) -> String {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(server.serve_on(listener));
+        url
+    }
+
+    fn server(client: AnkiClient, name: &str) -> Server {
+        let queue =
+            OfflineQueue::open(temp_dir(name)).unwrap();
+        Server::new(QueueingClient::new(client, queue))
+    }
+
+    /// Provider answering every request with `reply`
+    fn replying(reply: &'static str) -> Provider {
+        Provider::new(
+            move |_| async move { Ok(reply.to_string()) },
+            || async { anyhow::bail!("provider is down") },
+        )
+    }
+
+    fn note_body() -> Value {
+        json!({
+            "modelName": "Basic",
+            "deckName": "Inbox",
+            "fields": {"Front": "猫", "Back": "cat"},
+            "tags": ["phone"]
+        })
+    }
+
+    async fn post(url: &str, body: Value) -> (u16, Value) {
+        let response = reqwest::Client::new()
+            .post(url)
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        (
+            response.status().as_u16(),
+            response.json().await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_notes_are_added_or_queued() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "addNote" => ok(42.into()),
+                _ => err("unsupported action"),
+            })
+            .await;
+        let url = start(server(mock.client(), "up")).await;
+        let (status, body) =
+            post(&format!("{}/notes", url), note_body())
+                .await;
+        assert_eq!(status, 201);
+        assert_eq!(body["status"], "added");
+        assert_eq!(body["note_id"], 42);
+        let params = &mock.requests()[0]["params"]["note"];
+        assert_eq!(params["fields"]["Front"], "猫");
+        assert_eq!(params["tags"], json!(["phone"]));
+
+        let (status, body) = post(
+            &format!("{}/notes", url),
+            json!({"modelName": "Basic", "deckName": "Inbox", "fields": {"Front": " "}}),
+        )
+        .await;
+        assert_eq!(status, 400);
+        assert_eq!(body["error"], "Every field is empty");
+
+        let down = server(unreachable_client(), "down");
+        let queue = down.anki.queue().clone();
+        let url = start(down).await;
+        let (status, body) =
+            post(&format!("{}/notes", url), note_body())
+                .await;
+        assert_eq!(status, 202);
+        assert_eq!(body["status"], "queued");
+        assert_eq!(body["seq"], 1);
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].note.fields["Back"], "cat");
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_is_required() {
+        let mock =
+            MockAnki::start(|_, _| ok(6.into())).await;
+        let url = start(
+            server(mock.client(), "token")
+                .with_token(Some("secret".to_string())),
+        )
+        .await;
+        let http = reqwest::Client::new();
+        for auth in [None, Some("Bearer wrong")] {
+            let mut request =
+                http.get(format!("{}/healthz", url));
+            if let Some(auth) = auth {
+                request =
+                    request.header("Authorization", auth);
+            }
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status().as_u16(), 401);
+        }
+        let response = http
+            .get(format!("{}/healthz", url))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(
+            mock.actions().iter().all(|a| a == "version")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enrich_rewrites_fields_before_adding() {
+        let mock =
+            MockAnki::start(|_, _| ok(7.into())).await;
+        let url = start(
+            server(mock.client(), "enrich").with_provider(
+                replying(
+                    r#"```json
+                    {"Back": "cat (猫, ねこ)", "Extra": "ignored"}
+                    ```"#,
+                ),
+            ),
+        )
+        .await;
+        let mut body = note_body();
+        body["enrich"] = json!(true);
+        let (status, body) =
+            post(&format!("{}/notes", url), body).await;
+        assert_eq!(status, 201);
+        assert_eq!(body["enriched"], true);
+        let fields =
+            &mock.requests()[0]["params"]["note"]["fields"];
+        assert_eq!(
+            fields,
+            &json!({"Front": "猫", "Back": "cat (猫, ねこ)"})
+        );
+
+        let url = start(
+            server(mock.client(), "enrich_bad")
+                .with_provider(replying("not json")),
+        )
+        .await;
+        let mut body = note_body();
+        body["enrich"] = json!(true);
+        let (status, body) =
+            post(&format!("{}/notes", url), body).await;
+        assert_eq!(status, 201);
+        assert_eq!(body["enriched"], false);
+        assert!(body["enrich_error"].is_string());
+        assert_eq!(
+            mock.requests()[1]["params"]["note"]["fields"]
+                ["Back"],
+            "cat"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_adds_cards_and_queues_when_down()
+    {
+        const CARDS: &str = r#"{"cards": [
+            {"front": "What is 猫?", "back": "cat", "tags": ["gen"]},
+            {"front": "What is 犬?", "back": "dog"}
+        ]}"#;
+        let http = reqwest::Client::new();
+
+        let mock =
+            MockAnki::start(|_, params| {
+                match params["note"]["fields"]["Back"]
+                    .as_str()
+                {
+                    Some("cat") => ok(1.into()),
+                    _ => err("cannot create note because it is a duplicate"),
+                }
+            })
+            .await;
+        let url = start(
+            server(mock.client(), "generate")
+                .with_provider(replying(CARDS))
+                .with_generate_target("Reading", "Basic"),
+        )
+        .await;
+        let response = http
+            .post(format!("{}/generate", url))
+            .body("猫 is cat, 犬 is dog.")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["results"][0]["status"], "added");
+        assert_eq!(body["results"][1]["status"], "failed");
+        let note = &mock.requests()[0]["params"]["note"];
+        assert_eq!(note["deckName"], "Reading");
+        assert_eq!(note["fields"]["Front"], "What is 猫?");
+        assert_eq!(note["tags"], json!(["gen"]));
+
+        let down =
+            server(unreachable_client(), "generate_down")
+                .with_provider(replying(CARDS));
+        let queue = down.anki.queue().clone();
+        let url = start(down).await;
+        let body: Value = http
+            .post(format!("{}/generate", url))
+            .body("猫 is cat, 犬 is dog.")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            body["results"],
+            json!([
+                {"status": "queued", "seq": 1},
+                {"status": "queued", "seq": 2}
+            ])
+        );
+        assert_eq!(queue.pending().unwrap().len(), 2);
+
+        let url =
+            start(server(mock.client(), "no_provider"))
+                .await;
+        let response = http
+            .post(format!("{}/generate", url))
+            .body("text")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 503);
+    }
+
+    #[tokio::test]
+    async fn test_healthz_reports_reachability() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "version" => ok(6.into()),
+                _ => err("unsupported action"),
+            })
+            .await;
+        let up = server(mock.client(), "health_up")
+            .with_provider(replying(""));
+        up.anki
+            .queue()
+            .enqueue(Note {
+                model_name: "Basic".to_string(),
+                deck_name: "Inbox".to_string(),
+                fields: NoteFields::from([("Front", "x")]),
+                tags: Vec::new(),
+                audio: None,
+                picture: None,
+                video: None,
+                options: None,
+            })
+            .unwrap();
+        let url = start(up).await;
+        let body: Value =
+            reqwest::get(format!("{}/healthz", url))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        assert_eq!(
+            body["anki"],
+            json!({"reachable": true})
+        );
+        assert_eq!(
+            body["provider"],
+            json!({"reachable": false, "error": "provider is down"})
+        );
+        assert_eq!(body["queued"], 1);
+
+        let url = start(server(
+            unreachable_client(),
+            "health_down",
+        ))
+        .await;
+        let body: Value =
+            reqwest::get(format!("{}/healthz", url))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        assert_eq!(body["anki"]["reachable"], false);
+        assert_eq!(body["provider"], Value::Null);
+    }
+}
@@ -0,0 +1,766 @@
+//! 按固定结构从模型输出中解析卡片
+//!
+//! 各类卡片生成器（词汇、填空、翻译等）只需用 [`CardSpec`] 描述任务、
+//! 额外字段和附加检查，提示词中的结构说明、严格解析、内容校验以及
+//! 失败后的修复对话都由本模块统一完成。
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::chat::ChatMessage;
+use crate::error::InvalidStructuredOutput;
+use crate::few_shot::FewShotExamples;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::summarize::{SummarizeOptions, fit_source};
+
+/// 单个字段允许的默认最大字符数
+pub const DEFAULT_MAX_FIELD_CHARS: usize = 2000;
+
+/// 默认的修复次数（不含第一次生成）
+pub const DEFAULT_MAX_REPAIRS: u32 = 2;
+
+/// 模型生成的一张卡片
+///
+/// # 字段
+/// - `front`: 正面内容
+/// - `back`: 背面内容
+/// - `tags`: 标签，可省略
+/// - `extra`: [`CardSpec::extra_field`] 声明的额外字段
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct GeneratedCard {
+    pub front: String,
+    pub back: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub extra: HashMap<String, String>,
+}
+
+/// 一次生成的全部卡片
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(deny_unknown_fields)]
+pub struct GeneratedCardSet {
+    pub cards: Vec<GeneratedCard>,
+}
+
+/// 卡片的一个额外字段
+///
+/// # 字段
+/// - `name`: `extra` 中的键名
+/// - `description`: 写入提示词的字段说明
+/// - `required`: 是否必须给出非空值
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraField {
+    pub name: String,
+    pub description: String,
+    pub required: bool,
+}
+
+/// 生成器自定义的卡片检查，返回发现的问题
+pub type CardCheck = Arc<
+    dyn Fn(&GeneratedCard) -> Vec<String> + Send + Sync,
+>;
+
+/// 一类卡片的生成要求
+///
+/// 生成器通过它接入共享的解析和修复流程：
+/// - `task` 写入系统提示词，说明要生成什么
+/// - [`CardSpec::extra_field`] 声明额外字段
+/// - [`CardSpec::check`] 添加额外的内容检查
+#[derive(Clone)]
+pub struct CardSpec {
+    task: String,
+    extra_fields: Vec<ExtraField>,
+    max_field_chars: usize,
+    max_repairs: u32,
+    checks: Vec<CardCheck>,
+}
+
+impl fmt::Debug for CardSpec {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("CardSpec")
+            .field("task", &self.task)
+            .field("extra_fields", &self.extra_fields)
+            .field("max_field_chars", &self.max_field_chars)
+            .field("max_repairs", &self.max_repairs)
+            .field("checks", &self.checks.len())
+            .finish()
+    }
+}
+
+impl CardSpec {
+    /// 创建生成要求
+    ///
+    /// # 参数
+    /// - `task`: 生成任务的说明，如“为下列单词各生成一张词汇卡”
+    pub fn new(task: impl Into<String>) -> Self {
+        Self {
+            task: task.into(),
+            extra_fields: Vec::new(),
+            max_field_chars: DEFAULT_MAX_FIELD_CHARS,
+            max_repairs: DEFAULT_MAX_REPAIRS,
+            checks: Vec::new(),
+        }
+    }
+
+    /// 声明一个额外字段
+    pub fn extra_field(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        required: bool,
+    ) -> Self {
+        self.extra_fields.push(ExtraField {
+            name: name.into(),
+            description: description.into(),
+            required,
+        });
+        self
+    }
+
+    /// 设置单个字段的最大字符数
+    pub fn max_field_chars(mut self, chars: usize) -> Self {
+        self.max_field_chars = chars;
+        self
+    }
+
+    /// 设置修复次数，0 表示输出无效时直接失败
+    pub fn max_repairs(mut self, repairs: u32) -> Self {
+        self.max_repairs = repairs;
+        self
+    }
+
+    /// 添加自定义检查
+    pub fn check(
+        mut self,
+        check: impl Fn(&GeneratedCard) -> Vec<String>
+        + Send
+        + Sync
+        + 'static,
+    ) -> Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    /// 输出结构的JSON Schema描述
+    pub fn schema(&self) -> Value {
+        let text = json!({
+            "type": "string",
+            "minLength": 1,
+            "maxLength": self.max_field_chars,
+        });
+        let mut card_required = vec!["front", "back"];
+        let extra_required: Vec<&str> = self
+            .extra_fields
+            .iter()
+            .filter(|field| field.required)
+            .map(|field| field.name.as_str())
+            .collect();
+        if !extra_required.is_empty() {
+            card_required.push("extra");
+        }
+        let extra_properties: serde_json::Map<
+            String,
+            Value,
+        > = self
+            .extra_fields
+            .iter()
+            .map(|field| {
+                let mut schema = text.clone();
+                schema["description"] =
+                    json!(field.description);
+                (field.name.clone(), schema)
+            })
+            .collect();
+        json!({
+            "type": "object",
+            "required": ["cards"],
+            "additionalProperties": false,
+            "properties": {
+                "cards": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": card_required,
+                        "additionalProperties": false,
+                        "properties": {
+                            "front": text,
+                            "back": text,
+                            "tags": {
+                                "type": "array",
+                                "items": {"type": "string", "pattern": "^\\S+$"},
+                            },
+                            "extra": {
+                                "type": "object",
+                                "required": extra_required,
+                                "additionalProperties": false,
+                                "properties": extra_properties,
+                            },
+                        },
+                    },
+                },
+            },
+        })
+    }
+
+    /// 包含任务说明和输出结构的系统提示词
+    pub fn system_prompt(&self) -> String {
+        let schema =
+            serde_json::to_string_pretty(&self.schema())
+                .unwrap_or_default();
+        format!(
+            "{}\n\n\
+             Reply with one JSON object and nothing else. It must match this JSON Schema:\n\
+             {}\n\n\
+             Rules:\n\
+             - front and back must not be empty\n\
+             - no field may exceed {} characters\n\
+             - do not use markdown code fences (```) anywhere",
+            self.task, schema, self.max_field_chars
+        )
+    }
+
+    /// 严格解析并校验模型输出
+    ///
+    /// 整个输出被一对代码围栏包裹时先去掉围栏；除此之外不做任何
+    /// 宽松处理，字段名错误或缺失都算作问题。
+    ///
+    /// # 返回
+    /// 全部卡片都合格时返回卡片集合，否则返回所有问题（如
+    /// `cards[1].front is empty`），用于反馈给模型。
+    pub fn parse(
+        &self,
+        output: &str,
+    ) -> Result<GeneratedCardSet, Vec<String>> {
+        let value: Value =
+            serde_json::from_str(strip_outer_fence(output))
+                .map_err(|e| {
+                    vec![format!(
+                        "The reply is not valid JSON: {}",
+                        e
+                    )]
+                })?;
+        let Some(object) = value.as_object() else {
+            return Err(vec![
+                "The reply must be a JSON object with a \"cards\" array"
+                    .to_string(),
+            ]);
+        };
+        let mut issues: Vec<String> = object
+            .keys()
+            .filter(|key| *key != "cards")
+            .map(|key| {
+                format!(
+                    "Unknown top-level field \"{}\"",
+                    key
+                )
+            })
+            .collect();
+        let Some(items) =
+            object.get("cards").and_then(Value::as_array)
+        else {
+            issues.push(
+                "The \"cards\" array is missing"
+                    .to_string(),
+            );
+            return Err(issues);
+        };
+
+        let mut cards = Vec::with_capacity(items.len());
+        for (index, item) in items.iter().enumerate() {
+            match GeneratedCard::deserialize(item) {
+                Ok(card) => {
+                    issues.extend(
+                        self.validate(&card)
+                            .into_iter()
+                            .map(|issue| {
+                                format!(
+                                    "cards[{}].{}",
+                                    index, issue
+                                )
+                            }),
+                    );
+                    cards.push(card);
+                }
+                Err(e) => issues.push(format!(
+                    "cards[{}]: {}",
+                    index, e
+                )),
+            }
+        }
+        if issues.is_empty() {
+            Ok(GeneratedCardSet { cards })
+        } else {
+            Err(issues)
+        }
+    }
+
+    /// Whether `name` was declared with [`CardSpec::extra_field`]
+    pub(crate) fn expects_extra(&self, name: &str) -> bool {
+        self.extra_fields
+            .iter()
+            .any(|field| field.name == name)
+    }
+
+    /// Content rules for one card, each issue starting with the
+    /// offending field
+    fn validate(
+        &self,
+        card: &GeneratedCard,
+    ) -> Vec<String> {
+        let mut issues = Vec::new();
+        self.check_text("front", &card.front, &mut issues);
+        self.check_text("back", &card.back, &mut issues);
+        for field in &self.extra_fields {
+            match card.extra.get(&field.name) {
+                Some(value) => self.check_text(
+                    &format!("extra.{}", field.name),
+                    value,
+                    &mut issues,
+                ),
+                None if field.required => {
+                    issues.push(format!(
+                        "extra.{} is missing",
+                        field.name
+                    ))
+                }
+                None => {}
+            }
+        }
+        let mut unknown: Vec<&String> = card
+            .extra
+            .keys()
+            .filter(|name| !self.expects_extra(name))
+            .collect();
+        unknown.sort();
+        for name in unknown {
+            issues.push(format!(
+                "extra.{} is not an expected field",
+                name
+            ));
+        }
+        for tag in &card.tags {
+            if tag.is_empty()
+                || tag.contains(char::is_whitespace)
+            {
+                issues.push(format!(
+                    "tags contains \"{}\"; tags must be single words",
+                    tag
+                ));
+            }
+        }
+        for check in &self.checks {
+            issues.extend(check(card));
+        }
+        issues
+    }
+
+    /// Shared rules for every text field
+    fn check_text(
+        &self,
+        name: &str,
+        value: &str,
+        issues: &mut Vec<String>,
+    ) {
+        if value.trim().is_empty() {
+            issues.push(format!("{} is empty", name));
+        }
+        let chars = value.chars().count();
+        if chars > self.max_field_chars {
+            issues.push(format!(
+                "{} is {} characters long, the limit is {}",
+                name, chars, self.max_field_chars
+            ));
+        }
+        if value.contains("```") {
+            issues.push(format!(
+                "{} contains a markdown code fence",
+                name
+            ));
+        }
+    }
+}
+
+/// The text inside a fence wrapping the whole reply, or the trimmed
+/// reply itself
+pub(crate) fn strip_outer_fence(output: &str) -> &str {
+    let trimmed = output.trim();
+    let Some(body) = trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+    else {
+        return trimmed;
+    };
+    // Drop the language tag, e.g. ```json
+    match body.find('\n') {
+        Some(newline) => body[newline + 1..].trim(),
+        None => body.trim(),
+    }
+}
+
+/// 让模型按 `spec` 生成卡片，输出无效时要求模型修复
+///
+/// 第一次请求包含 [`CardSpec::system_prompt`] 和 `prompt`；此后每次
+/// 输出无效时，把输出和全部问题作为对话的下一轮发给模型，最多修复
+/// [`CardSpec::max_repairs`] 次。
+///
+/// # 参数
+/// - `spec`: 卡片要求
+/// - `prompt`: 本次生成的用户输入
+/// - `complete`: 发送完整对话并返回模型回复的函数，用于接入任意服务商；
+///   返回的 future 须为 `Send`，生成过程因此可以直接在多线程运行时
+///   （例如 HTTP 处理函数）中 `.await`
+///
+/// # 返回
+/// 合格的卡片集合；修复次数用完时返回以
+/// [`InvalidStructuredOutput`] 为根因的错误，调用失败时原样返回其错误。
+pub async fn generate_cards<F, Fut>(
+    spec: &CardSpec,
+    prompt: &str,
+    complete: F,
+) -> anyhow::Result<GeneratedCardSet>
+where
+    F: FnMut(Vec<ChatMessage>) -> Fut,
+    Fut: Future<Output = anyhow::Result<String>> + Send,
+{
+    generate_cards_with_examples(
+        spec,
+        prompt,
+        &FewShotExamples::default(),
+        complete,
+    )
+    .await
+}
+
+/// 在第一次请求中加入示例后执行 [`generate_cards`]
+///
+/// 示例以交替的用户/助手消息插在系统提示词和 `prompt` 之间，见
+/// [`FewShotExamples::messages`]。
+pub async fn generate_cards_with_examples<F, Fut>(
+    spec: &CardSpec,
+    prompt: &str,
+    examples: &FewShotExamples,
+    mut complete: F,
+) -> anyhow::Result<GeneratedCardSet>
+where
+    F: FnMut(Vec<ChatMessage>) -> Fut,
+    Fut: Future<Output = anyhow::Result<String>> + Send,
+{
+    let mut messages = examples.messages(spec, prompt);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let output = complete(messages.clone()).await?;
+        let issues = match spec.parse(&output) {
+            Ok(cards) => return Ok(cards),
+            Err(issues) => issues,
+        };
+        if attempts > spec.max_repairs {
+            return Err(InvalidStructuredOutput {
+                attempts,
+                issues,
+                output,
+            }
+            .into());
+        }
+        log::debug!(
+            "Structured output rejected ({} issues), asking for a repair",
+            issues.len()
+        );
+        messages.push(ChatMessage::assistant(output));
+        messages.push(ChatMessage::user(repair_prompt(
+            &issues,
+        )));
+    }
+}
+
+/// 根据一篇原文生成卡片，原文过长时先摘要
+///
+/// 原文的 token 估算超过 `max_source_tokens` 时，用
+/// [`crate::summarize::summarize_long`] 换成摘要后再放入提示词，
+/// 不会截断原文。
+///
+/// # 参数
+/// - `spec`: 卡片要求
+/// - `instructions`: 放在原文之前的用户输入
+/// - `source`: 原文
+/// - `max_source_tokens`: 原文在提示词中允许的 token 数
+/// - `summarize`: 原文过长时的摘要选项
+/// - `complete`: 见 [`generate_cards`]；摘要时会被并发调用
+pub async fn generate_cards_from_source<F, Fut>(
+    spec: &CardSpec,
+    instructions: &str,
+    source: &str,
+    max_source_tokens: usize,
+    summarize: &SummarizeOptions,
+    complete: F,
+) -> anyhow::Result<GeneratedCardSet>
+where
+    F: Fn(Vec<ChatMessage>) -> Fut,
+    Fut: Future<Output = anyhow::Result<String>> + Send,
+{
+    let source = fit_source(
+        async |messages| complete(messages).await,
+        source,
+        max_source_tokens,
+        summarize,
+    )
+    .await?;
+    let prompt =
+        format!("{}\n\nSource:\n{}", instructions, source);
+    generate_cards(spec, &prompt, complete).await
+}
+
+/// Follow-up message listing what was wrong with the last reply
+fn repair_prompt(issues: &[String]) -> String {
+    format!(
+        "Your reply could not be used:\n- {}\n\nReply again with the complete, corrected JSON object only.",
+        issues.join("\n- ")
+    )
+}
+
+/// 使用智谱客户端执行 [`generate_cards`]
+pub async fn generate_cards_with_zhi_pu(
+    client: &ZhiPuClient,
+    spec: &CardSpec,
+    prompt: &str,
+) -> anyhow::Result<GeneratedCardSet> {
+    generate_cards(spec, prompt, |messages| async move {
+        let request = ZhiPuRequest::new(
+            messages.into_iter().map(Into::into).collect(),
+        );
+        client.complete(request).await?.into_content()
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VALID: &str = r#"{"cards": [
+        {"front": "猫", "back": "cat", "tags": ["n5"], "extra": {"reading": "ねこ"}},
+        {"front": "犬", "back": "dog", "extra": {"reading": "いぬ"}}
+    ]}"#;
+
+    /// Wrong field name, missing reading and a fenced back side
+    const FIXABLE: &str = r#"```json
+    {"cards": [
+        {"question": "猫", "back": "cat", "extra": {"reading": "ねこ"}},
+        {"front": "犬", "back": "```dog```", "extra": {}}
+    ]}
+    ```"#;
+
+    fn vocab_spec() -> CardSpec {
+        CardSpec::new(
+            "Create one vocabulary card per word.",
+        )
+        .extra_field("reading", "Kana reading", true)
+        .extra_field("example", "Example sentence", false)
+        .max_field_chars(20)
+    }
+
+    #[test]
+    fn test_parse_valid_output() {
+        let cards = vocab_spec().parse(VALID).unwrap();
+        assert_eq!(cards.cards.len(), 2);
+        assert_eq!(cards.cards[0].tags, vec!["n5"]);
+        assert_eq!(cards.cards[1].extra["reading"], "いぬ");
+        let fenced = format!("```json\n{}\n```", VALID);
+        assert_eq!(
+            vocab_spec().parse(&fenced).unwrap(),
+            cards
+        );
+    }
+
+    #[test]
+    fn test_parse_reports_every_issue() {
+        let issues =
+            vocab_spec().parse(FIXABLE).unwrap_err();
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        assert!(issues[0].starts_with(
+            "cards[0]: unknown field `question`"
+        ));
+        assert_eq!(
+            issues[1],
+            "cards[1].back contains a markdown code fence"
+        );
+        assert_eq!(
+            issues[2],
+            "cards[1].extra.reading is missing"
+        );
+
+        let issues = vocab_spec()
+            .parse(r#"{"cards": [{"front": " ", "back": "a very long answer indeed", "tags": ["two words"], "extra": {"reading": "x", "note": "y"}}], "comment": "hi"}"#)
+            .unwrap_err();
+        assert_eq!(
+            issues,
+            vec![
+                "Unknown top-level field \"comment\"",
+                "cards[0].front is empty",
+                "cards[0].back is 25 characters long, the limit is 20",
+                "cards[0].extra.note is not an expected field",
+                "cards[0].tags contains \"two words\"; tags must be single words",
+            ]
+        );
+        assert!(vocab_spec().parse("Sure! Here").is_err());
+        assert!(vocab_spec().parse("[]").is_err());
+    }
+
+    #[test]
+    fn test_custom_checks_and_schema() {
+        let spec = vocab_spec().check(|card| {
+            if card.front == card.back {
+                vec![
+                    "front and back are identical"
+                        .to_string(),
+                ]
+            } else {
+                Vec::new()
+            }
+        });
+        let issues = spec
+            .parse(r#"{"cards": [{"front": "a", "back": "a", "extra": {"reading": "a"}}]}"#)
+            .unwrap_err();
+        assert_eq!(
+            issues,
+            vec!["cards[0].front and back are identical"]
+        );
+
+        let schema = spec.schema();
+        let card = &schema["properties"]["cards"]["items"];
+        assert_eq!(
+            card["required"],
+            json!(["front", "back", "extra"])
+        );
+        assert_eq!(
+            card["properties"]["extra"]["required"],
+            json!(["reading"])
+        );
+        assert_eq!(
+            card["properties"]["front"]["maxLength"],
+            20
+        );
+        assert!(
+            spec.system_prompt().contains("\"example\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fixable_output_is_repaired() {
+        let mut replies = vec![FIXABLE, VALID].into_iter();
+        let mut seen = Vec::new();
+        let cards = generate_cards(
+            &vocab_spec(),
+            "猫, 犬",
+            |messages| {
+                seen.push(messages);
+                let reply = replies.next().unwrap();
+                async move { Ok(reply.to_string()) }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(cards.cards.len(), 2);
+        assert_eq!(seen.len(), 2);
+        let repair = &seen[1];
+        assert_eq!(repair.len(), 4);
+        assert_eq!(
+            repair[2],
+            ChatMessage::assistant(FIXABLE)
+        );
+        assert!(repair[3].content.contains(
+            "- cards[1].extra.reading is missing"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_long_source_is_summarized_first() {
+        let source = "A cat sat on the mat. ".repeat(50);
+        let mut prompts = Vec::new();
+        let seen = std::sync::Mutex::new(&mut prompts);
+        let cards = generate_cards_from_source(
+            &vocab_spec(),
+            "Make cards from this text.",
+            &source,
+            100,
+            &SummarizeOptions {
+                chunk_tokens: 200,
+                ..Default::default()
+            },
+            |messages: Vec<ChatMessage>| {
+                let last = messages.last().unwrap();
+                seen.lock()
+                    .unwrap()
+                    .push(last.content.clone());
+                let reply = if messages[0]
+                    .content
+                    .contains("summarize")
+                {
+                    "Cats like mats."
+                } else if messages[0]
+                    .content
+                    .contains("summaries")
+                {
+                    "All about cats."
+                } else {
+                    VALID
+                };
+                async move { Ok(reply.to_string()) }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(cards.cards.len(), 2);
+        let prompt = prompts.last().unwrap();
+        assert_eq!(
+            prompt,
+            "Make cards from this text.\n\nSource:\nAll about cats."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unfixable_output_gives_up() {
+        let mut calls = 0;
+        let error = generate_cards(
+            &vocab_spec().max_repairs(1),
+            "猫",
+            |_| {
+                calls += 1;
+                async {
+                    Ok("I cannot do that.".to_string())
+                }
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(calls, 2);
+        let invalid = error
+            .downcast_ref::<InvalidStructuredOutput>()
+            .unwrap();
+        assert_eq!(invalid.attempts, 2);
+        assert_eq!(invalid.output, "I cannot do that.");
+        assert!(
+            invalid.issues[0].contains("not valid JSON")
+        );
+    }
+}
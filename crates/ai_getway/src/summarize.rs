@@ -0,0 +1,592 @@
+//! 超出上下文长度的长文档摘要
+//!
+//! 采用 map-reduce：按 token 估算把原文在句子边界切成相互重叠的
+//! 分段，并发摘要每一段，再按原文顺序合并各段摘要得到最终摘要。
+//! 某一段失败时在合并输入中注明缺失的位置，而不是让整篇文档失败。
+
+use futures::StreamExt;
+use utils::text::chunker::{
+    Boundary, ChunkConfig, Chunker,
+};
+
+use crate::chat::ChatMessage;
+use crate::models::zhi_pu::{ZhiPuClient, ZhiPuRequest};
+use crate::tokens::estimate_tokens;
+
+/// 段落摘要中要点列表的标题行
+const KEY_POINTS_HEADING: &str = "Key points:";
+
+/// [`summarize_long`] 的选项
+///
+/// # 字段
+/// - `chunk_tokens`: 每个分段的最大 token 数，也是一次合并的输入上限
+/// - `overlap_tokens`: 相邻分段重叠的最大 token 数
+/// - `target_length`: 最终摘要的目标词数
+/// - `language`: 摘要使用的语言
+/// - `concurrency`: 同时进行的分段摘要数，0 视为 1
+/// - `key_points`: 是否同时输出每个分段的要点
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SummarizeOptions {
+    pub chunk_tokens: usize,
+    pub overlap_tokens: usize,
+    pub target_length: usize,
+    pub language: String,
+    pub concurrency: usize,
+    pub key_points: bool,
+}
+
+impl Default for SummarizeOptions {
+    fn default() -> Self {
+        Self {
+            chunk_tokens: 3000,
+            overlap_tokens: 200,
+            target_length: 300,
+            language: "English".to_string(),
+            concurrency: 4,
+            key_points: false,
+        }
+    }
+}
+
+/// 一个分段的摘要
+///
+/// # 字段
+/// - `index`: 分段在原文中的序号，从 0 开始
+/// - `summary`: 分段摘要；该分段摘要失败时为 `None`
+/// - `key_points`: 分段要点，仅在 [`SummarizeOptions::key_points`] 时填写
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkSummary {
+    pub index: usize,
+    pub summary: Option<String>,
+    pub key_points: Vec<String>,
+}
+
+/// 长文档摘要的结果
+///
+/// # 字段
+/// - `summary`: 最终摘要
+/// - `chunks`: 按原文顺序排列的分段摘要
+/// - `gaps`: 摘要失败、未能覆盖的分段序号
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LongSummary {
+    pub summary: String,
+    pub chunks: Vec<ChunkSummary>,
+    pub gaps: Vec<usize>,
+}
+
+/// 在句子边界把文本切成分段
+///
+/// 使用 [`Chunker`] 和 [`estimate_tokens`]：每段尽量装入不超过
+/// `chunk_tokens` 的整句，下一段从上一段末尾不超过 `overlap_tokens`
+/// 的若干整句开始，保证上下文连贯；单句超过上限时在句中切开。
+///
+/// # 返回
+/// 原文中的分段切片，按原文顺序排列，保留段内的换行。
+pub fn split_chunks(
+    text: &str,
+    chunk_tokens: usize,
+    overlap_tokens: usize,
+) -> Vec<&str> {
+    Chunker::new(
+        estimate_tokens,
+        ChunkConfig {
+            max_tokens: chunk_tokens,
+            overlap_tokens,
+            boundary: Boundary::Sentence,
+        },
+    )
+    .split(text)
+    .into_iter()
+    .map(|chunk| chunk.text)
+    .collect()
+}
+
+/// 用 map-reduce 摘要任意长度的文本
+///
+/// 文本不超过一个分段时直接摘要；否则按 [`split_chunks`] 分段后
+/// 并发摘要，再按原文顺序合并，合并输入过长时先分组合并。失败的
+/// 分段记入 [`LongSummary::gaps`]，并在合并时注明该位置缺失。
+///
+/// # 参数
+/// - `complete`: 发送完整对话并返回模型回复的函数，会被并发调用
+/// - `text`: 原文
+/// - `options`: 分段和摘要选项
+///
+/// # 返回
+/// 最终摘要和各分段摘要；所有分段都失败或合并失败时返回错误。
+pub async fn summarize_long(
+    complete: impl AsyncFn(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    text: &str,
+    options: &SummarizeOptions,
+) -> anyhow::Result<LongSummary> {
+    let chunks = split_chunks(
+        text,
+        options.chunk_tokens,
+        options.overlap_tokens,
+    );
+    let total = chunks.len();
+    let complete = &complete;
+    let results: Vec<anyhow::Result<String>> =
+        futures::stream::iter(
+            chunks.into_iter().enumerate(),
+        )
+        .map(|(index, chunk)| async move {
+            complete(map_messages(
+                chunk, index, total, options,
+            ))
+            .await
+        })
+        .buffered(options.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut summaries = Vec::with_capacity(total);
+    let mut gaps = Vec::new();
+    let mut last_error = None;
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(reply) => {
+                let (summary, key_points) =
+                    split_key_points(&reply);
+                summaries.push(ChunkSummary {
+                    index,
+                    summary: Some(summary),
+                    key_points: if options.key_points {
+                        key_points
+                    } else {
+                        Vec::new()
+                    },
+                });
+            }
+            Err(e) => {
+                log::warn!(
+                    "Summary of part {} of {} failed: {:#}",
+                    index + 1,
+                    total,
+                    e
+                );
+                gaps.push(index);
+                summaries.push(ChunkSummary {
+                    index,
+                    summary: None,
+                    key_points: Vec::new(),
+                });
+                last_error = Some(e);
+            }
+        }
+    }
+    if let Some(e) = last_error
+        && gaps.len() == total
+    {
+        return Err(e.context("Every part of the document failed to summarize"));
+    }
+
+    let summary = if total == 1 {
+        summaries[0].summary.clone().unwrap_or_default()
+    } else {
+        reduce(complete, &summaries, options).await?
+    };
+    Ok(LongSummary {
+        summary,
+        chunks: summaries,
+        gaps,
+    })
+}
+
+/// 原文超过 `max_tokens` 时换成摘要，否则原样返回
+///
+/// 生成器在把原文放进提示词之前调用它，避免截断长文档。
+pub async fn fit_source(
+    complete: impl AsyncFn(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    text: &str,
+    max_tokens: usize,
+    options: &SummarizeOptions,
+) -> anyhow::Result<String> {
+    if estimate_tokens(text) <= max_tokens {
+        return Ok(text.to_string());
+    }
+    let summary =
+        summarize_long(complete, text, options).await?;
+    if !summary.gaps.is_empty() {
+        log::warn!(
+            "Source summary is missing parts {:?}",
+            summary.gaps
+        );
+    }
+    Ok(summary.summary)
+}
+
+/// 使用智谱客户端执行 [`summarize_long`]
+pub async fn summarize_long_with_zhi_pu(
+    client: &ZhiPuClient,
+    text: &str,
+    options: &SummarizeOptions,
+) -> anyhow::Result<LongSummary> {
+    summarize_long(
+        async |messages| {
+            let request = ZhiPuRequest::new(
+                messages
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            );
+            client.complete(request).await?.into_content()
+        },
+        text,
+        options,
+    )
+    .await
+}
+
+/// Merges the part summaries in document order, first in groups if
+/// they do not fit into one request
+async fn reduce(
+    complete: &impl AsyncFn(
+        Vec<ChatMessage>,
+    ) -> anyhow::Result<String>,
+    summaries: &[ChunkSummary],
+    options: &SummarizeOptions,
+) -> anyhow::Result<String> {
+    let mut sections: Vec<String> = summaries
+        .iter()
+        .map(|chunk| match &chunk.summary {
+            Some(summary) => format!(
+                "Part {}:\n{}",
+                chunk.index + 1,
+                summary
+            ),
+            None => format!(
+                "Part {}: [missing: this part could not be summarized]",
+                chunk.index + 1
+            ),
+        })
+        .collect();
+
+    while sections.len() > 1
+        && estimate_tokens(&sections.join("\n\n"))
+            > options.chunk_tokens
+    {
+        let groups =
+            group_sections(&sections, options.chunk_tokens);
+        if groups.len() == sections.len() {
+            // Every section is too long on its own
+            break;
+        }
+        let mut merged = Vec::with_capacity(groups.len());
+        for group in groups {
+            merged.push(
+                complete(reduce_messages(
+                    &group.join("\n\n"),
+                    options.target_length,
+                    options,
+                ))
+                .await?,
+            );
+        }
+        sections = merged;
+    }
+    complete(reduce_messages(
+        &sections.join("\n\n"),
+        options.target_length,
+        options,
+    ))
+    .await
+}
+
+/// Consecutive runs of sections fitting into `max_tokens`
+fn group_sections(
+    sections: &[String],
+    max_tokens: usize,
+) -> Vec<Vec<&str>> {
+    let mut groups: Vec<Vec<&str>> = Vec::new();
+    let mut total = 0;
+    for section in sections {
+        let tokens = estimate_tokens(section);
+        match groups.last_mut() {
+            Some(group) if total + tokens <= max_tokens => {
+                group.push(section);
+                total += tokens;
+            }
+            _ => {
+                groups.push(vec![section]);
+                total = tokens;
+            }
+        }
+    }
+    groups
+}
+
+fn map_messages(
+    chunk: &str,
+    index: usize,
+    total: usize,
+    options: &SummarizeOptions,
+) -> Vec<ChatMessage> {
+    let words = if total == 1 {
+        options.target_length
+    } else {
+        (options.target_length * 2 / total).max(60)
+    };
+    let mut system = format!(
+        "You summarize part {} of {} of a longer document. Write in {}, in at most {} words. Keep names, numbers and the order of events; do not add anything that is not in the text.",
+        index + 1,
+        total,
+        options.language,
+        words
+    );
+    if options.key_points {
+        system.push_str(&format!(
+            "\nAfter the summary, write a line \"{}\" followed by one \"- \" line per key point.",
+            KEY_POINTS_HEADING
+        ));
+    }
+    vec![
+        ChatMessage::system(system),
+        ChatMessage::user(chunk),
+    ]
+}
+
+fn reduce_messages(
+    sections: &str,
+    words: usize,
+    options: &SummarizeOptions,
+) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::system(format!(
+            "These are summaries of consecutive parts of one document. Combine them into a single summary in {} of at most {} words, keeping the order of the parts. Where a part is marked missing, say briefly that part of the document is not covered.",
+            options.language, words
+        )),
+        ChatMessage::user(sections),
+    ]
+}
+
+/// Separates the key-point list from a part summary
+fn split_key_points(reply: &str) -> (String, Vec<String>) {
+    let Some(at) = reply.find(KEY_POINTS_HEADING) else {
+        return (reply.trim().to_string(), Vec::new());
+    };
+    let points = reply[at + KEY_POINTS_HEADING.len()..]
+        .lines()
+        .filter_map(|line| {
+            line.trim()
+                .strip_prefix("- ")
+                .map(|point| point.trim().to_string())
+        })
+        .filter(|point| !point.is_empty())
+        .collect();
+    (reply[..at].trim().to_string(), points)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Ten sentences of 5 tokens each (20 chars)
+    fn document() -> String {
+        (0..10)
+            .map(|i| format!("Sentence number {:02}x.", i))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_split_chunks_with_overlap() {
+        let text = document();
+        assert_eq!(
+            estimate_tokens("Sentence number 00x."),
+            5
+        );
+        let chunks = split_chunks(&text, 16, 5);
+        // 3 sentences per chunk, 1 repeated at each seam
+        assert_eq!(chunks.len(), 5);
+        assert!(
+            chunks[0].starts_with("Sentence number 00")
+        );
+        assert!(chunks[0].ends_with("number 02x."));
+        assert!(
+            chunks[1].starts_with("Sentence number 02")
+        );
+        assert!(chunks[4].ends_with("number 09x."));
+        for chunk in &chunks {
+            assert!(estimate_tokens(chunk) <= 16);
+        }
+
+        // No overlap: plain partition
+        let chunks = split_chunks(&text, 11, 0);
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks.join(" "), text);
+
+        // Overlap as large as a chunk still makes progress
+        assert_eq!(split_chunks(&text, 11, 11).len(), 9);
+        // Oversized sentences are cut to the limit
+        assert!(
+            split_chunks(&text, 3, 0)
+                .iter()
+                .all(|chunk| estimate_tokens(chunk) <= 3)
+        );
+        assert_eq!(
+            split_chunks(&text, 1000, 50),
+            [text.as_str()]
+        );
+        assert!(split_chunks("  ", 10, 0).is_empty());
+    }
+
+    #[test]
+    fn test_key_points_are_split_off() {
+        let (summary, points) = split_key_points(
+            "A cat sat.\n\nKey points:\n- cats sit\n-  mats \n",
+        );
+        assert_eq!(summary, "A cat sat.");
+        assert_eq!(points, ["cats sit", "mats"]);
+        assert_eq!(
+            split_key_points(" Only text "),
+            ("Only text".to_string(), Vec::new())
+        );
+    }
+
+    fn options() -> SummarizeOptions {
+        SummarizeOptions {
+            chunk_tokens: 11,
+            overlap_tokens: 0,
+            target_length: 50,
+            concurrency: 3,
+            ..Default::default()
+        }
+    }
+
+    /// Summarizes the part starting with sentence N as "sN",
+    /// finishing later parts first, and echoes reduce input back
+    async fn mock(
+        messages: Vec<ChatMessage>,
+    ) -> anyhow::Result<String> {
+        let input = &messages[1].content;
+        if let Some(rest) =
+            input.strip_prefix("Sentence number ")
+        {
+            let n: u64 = rest[..2].parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(
+                50 - n * 5,
+            ))
+            .await;
+            // The third part (sentences 4 and 5) always fails
+            if n == 4 {
+                anyhow::bail!("rate limited");
+            }
+            return Ok(format!(
+                "s{}\n{}\n- p{}",
+                n, KEY_POINTS_HEADING, n
+            ));
+        }
+        Ok(format!("reduced[{}]", input))
+    }
+
+    #[tokio::test]
+    async fn test_parts_are_reduced_in_order() {
+        let summary = summarize_long(
+            mock,
+            &document(),
+            &SummarizeOptions {
+                key_points: true,
+                chunk_tokens: 1000,
+                ..options()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.chunks.len(), 1);
+        assert_eq!(summary.summary, "s0");
+        assert_eq!(summary.chunks[0].key_points, ["p0"]);
+
+        // Five parts, finishing in reverse order; the summaries are
+        // too long to reduce at once, so they are merged in groups
+        let summary =
+            summarize_long(mock, &document(), &options())
+                .await
+                .unwrap();
+        let order: Vec<usize> = summary
+            .chunks
+            .iter()
+            .map(|c| c.index)
+            .collect();
+        assert_eq!(order, [0, 1, 2, 3, 4]);
+        assert!(summary.chunks[1].key_points.is_empty());
+        let reduced = &summary.summary;
+        let positions: Vec<usize> =
+            ["s0", "s2", "s6", "s8"]
+                .iter()
+                .map(|s| reduced.find(s).unwrap())
+                .collect();
+        assert!(positions.is_sorted(), "{}", reduced);
+    }
+
+    #[tokio::test]
+    async fn test_failed_part_leaves_a_gap() {
+        let text = document();
+        let error = summarize_long(
+            async |messages: Vec<ChatMessage>| {
+                if messages[1].content.contains("04x") {
+                    anyhow::bail!("rate limited");
+                }
+                mock(messages).await
+            },
+            &text,
+            &SummarizeOptions {
+                chunk_tokens: 1000,
+                ..options()
+            },
+        )
+        .await
+        .unwrap_err();
+        // A single part failing is the whole document failing
+        assert!(error.to_string().contains("Every part"));
+
+        let summary =
+            summarize_long(mock, &text, &options())
+                .await
+                .unwrap();
+        assert_eq!(summary.gaps, [2]);
+        assert_eq!(summary.chunks[2].summary, None);
+        assert!(summary.summary.contains(
+            "Part 3: [missing: this part could not be summarized]"
+        ));
+        assert!(summary.summary.contains("s6"));
+
+        let error = summarize_long(
+            async |_| anyhow::bail!("offline"),
+            &text,
+            &options(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Every part of the document failed to summarize: offline"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fit_source_only_summarizes_long_text() {
+        let text = document();
+        assert_eq!(
+            fit_source(mock, "Short.", 10, &options())
+                .await
+                .unwrap(),
+            "Short."
+        );
+        let fitted =
+            fit_source(mock, &text, 10, &options())
+                .await
+                .unwrap();
+        assert!(
+            fitted.starts_with("reduced["),
+            "{}",
+            fitted
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! 通过 `metrics` 门面输出的模型调用指标
+//!
+//! 应用安装记录器（如 Prometheus 导出器）之前不记录任何数据。标签只有
+//! 服务商、模型、结果类别和 token 种类，从不包含提示词或回复内容，
+//! 因此标签组合的数量是有限的。
+//!
+//! | 指标 | 类型 | 标签 |
+//! |---|---|---|
+//! | [`REQUESTS`] | counter | `provider`、`model`、`outcome` |
+//! | [`REQUEST_DURATION`] | histogram（秒） | `provider`、`model`、`outcome` |
+//! | [`TOKENS`] | counter | `provider`、`model`、`kind` |
+//! | [`RETRIES`] | counter | `provider`、`model`、`reason` |
+
+use std::time::Duration;
+
+use crate::usage_journal::CallOutcome;
+
+/// 完成的模型调用次数，重试不单独计数
+pub const REQUESTS: &str = "ai_requests_total";
+
+/// 一次调用从发出到结束的耗时，包括重试
+pub const REQUEST_DURATION: &str =
+    "ai_request_duration_seconds";
+
+/// 消耗的 token 数，`kind` 为 `prompt` 或 `completion`
+pub const TOKENS: &str = "ai_tokens_total";
+
+/// 重试次数，`reason` 为 `network` 或 `http_status`
+pub const RETRIES: &str = "ai_retries_total";
+
+/// Records one finished call
+pub(crate) fn record_completion(
+    provider: &str,
+    model: &str,
+    outcome: &CallOutcome,
+    elapsed: Duration,
+    (prompt_tokens, completion_tokens): (u64, u64),
+) {
+    let outcome = match outcome {
+        CallOutcome::Success => "success".to_string(),
+        CallOutcome::HttpStatus(status) => {
+            format!("http_{}", status)
+        }
+        CallOutcome::Failed => "failed".to_string(),
+    };
+    let labels = [
+        ("provider", provider.to_string()),
+        ("model", model.to_string()),
+        ("outcome", outcome),
+    ];
+    metrics::counter!(REQUESTS, &labels).increment(1);
+    metrics::histogram!(REQUEST_DURATION, &labels)
+        .record(elapsed.as_secs_f64());
+    for (kind, count) in [
+        ("prompt", prompt_tokens),
+        ("completion", completion_tokens),
+    ] {
+        if count > 0 {
+            metrics::counter!(
+                TOKENS,
+                "provider" => provider.to_string(),
+                "model" => model.to_string(),
+                "kind" => kind,
+            )
+            .increment(count);
+        }
+    }
+}
+
+/// Records one retry; `reason` is `network` or `http_status`
+pub(crate) fn record_retry(
+    provider: &str,
+    model: &str,
+    reason: &'static str,
+) {
+    metrics::counter!(
+        RETRIES,
+        "provider" => provider.to_string(),
+        "model" => model.to_string(),
+        "reason" => reason,
+    )
+    .increment(1);
+}
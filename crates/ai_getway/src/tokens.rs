@@ -0,0 +1,72 @@
+//! 粗略估算提示词的 token 数
+//!
+//! 各服务商的分词器不同，这里只给出偏保守的估计，用于在发送前控制
+//! 提示词长度：CJK 字符按每字一个 token 计，其余文本按每 4 个字符
+//! 一个 token 计。
+
+use crate::chat::ChatMessage;
+
+/// 每条消息在正文之外额外占用的 token（角色、分隔符等）
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// 估算一段文本的 token 数
+pub fn estimate_tokens(text: &str) -> usize {
+    let mut cjk = 0;
+    let mut other: usize = 0;
+    for c in text.chars() {
+        if is_cjk(c) {
+            cjk += 1;
+        } else {
+            other += 1;
+        }
+    }
+    cjk + other.div_ceil(4)
+}
+
+/// 估算整段对话的 token 数，包括每条消息的额外开销
+pub fn estimate_message_tokens(
+    messages: &[ChatMessage],
+) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            MESSAGE_OVERHEAD_TOKENS
+                + estimate_tokens(&message.content)
+        })
+        .sum()
+}
+
+/// Han, kana, hangul and full-width punctuation, which tokenizers
+/// rarely merge
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3000}'..='\u{30FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{AC00}'..='\u{D7AF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{FF00}'..='\u{FFEF}'
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("cat"), 1);
+        assert_eq!(estimate_tokens("a cat!"), 2);
+        assert_eq!(estimate_tokens("猫が好き"), 4);
+        assert_eq!(estimate_tokens("猫 cat"), 2);
+        assert_eq!(
+            estimate_message_tokens(&[
+                ChatMessage::system("cat"),
+                ChatMessage::user("猫"),
+            ]),
+            2 * MESSAGE_OVERHEAD_TOKENS + 2
+        );
+    }
+}
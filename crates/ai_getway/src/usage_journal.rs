@@ -0,0 +1,746 @@
+//! 模型调用的持久化用量日志
+//!
+//! [`UsageJournal`] 在数据目录下为每次完成的调用追加一行 JSON，
+//! 只记录时间、服务商、模型、token 数、耗时、结果和调用方给的标签，
+//! 从不记录提示词、回复或密钥。文件超过大小上限时轮换为
+//! `usage.1.jsonl`、`usage.2.jsonl`……，超出保留数量的旧文件被删除。
+//! [`UsageJournal::summarize`] 读回所有文件，按天和模型汇总。
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use utils::paths::AppDir;
+
+use crate::error::HttpStatusError;
+
+/// 数据目录下存放用量日志的子目录
+pub const USAGE_DIR_NAME: &str = "usage";
+
+/// 当前日志文件名，轮换后的文件为 `usage.<n>.jsonl`
+pub const USAGE_FILE_NAME: &str = "usage.jsonl";
+
+/// 何时把日志刷到磁盘
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// 交给操作系统，进程崩溃不丢数据，断电可能丢最后几条
+    #[default]
+    Never,
+    /// 每条记录后 fsync，最可靠也最慢
+    EveryRecord,
+    /// 每 n 条记录 fsync 一次，0 视为 1
+    EveryN(usize),
+}
+
+/// [`UsageJournal`] 的选项
+///
+/// # 字段
+/// - `max_file_bytes`: 单个文件的大小上限，写入会超过时先轮换
+/// - `max_files`: 保留的轮换文件数，不含当前文件
+/// - `fsync`: 刷盘策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalOptions {
+    pub max_file_bytes: u64,
+    pub max_files: usize,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for JournalOptions {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: 5 * 1024 * 1024,
+            max_files: 10,
+            fsync: FsyncPolicy::default(),
+        }
+    }
+}
+
+/// 一次调用的结果
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CallOutcome {
+    Success,
+    /// 服务商返回了非成功的 HTTP 状态码
+    HttpStatus(u16),
+    /// 连接失败、响应无法解析等其他错误
+    Failed,
+}
+
+impl CallOutcome {
+    /// 由调用结果得到，错误只保留状态码，不保留错误信息
+    pub fn of<T>(result: &anyhow::Result<T>) -> Self {
+        match result {
+            Ok(_) => CallOutcome::Success,
+            Err(e) => {
+                match e.downcast_ref::<HttpStatusError>() {
+                    Some(error) => CallOutcome::HttpStatus(
+                        error.status,
+                    ),
+                    None => CallOutcome::Failed,
+                }
+            }
+        }
+    }
+}
+
+/// 调用方给调用加的标签
+///
+/// # 字段
+/// - `run_id`: 一次运行的标识，用于把同一批调用归在一起
+/// - `purpose`: 调用的用途，如 `cards`、`summarize`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageLabels {
+    pub run_id: Option<String>,
+    pub purpose: Option<String>,
+}
+
+/// 日志中的一行
+///
+/// 字段是固定的，没有可以放入提示词或回复的位置。
+///
+/// # 字段
+/// - `timestamp`: 调用完成的时间（UTC）
+/// - `provider`: 服务商名称
+/// - `model`: 模型名称
+/// - `prompt_tokens`/`completion_tokens`: 服务商报告的 token 数，
+///   失败时为 0
+/// - `latency_ms`: 调用耗时，包括重试和切换密钥
+/// - `outcome`: 调用结果
+/// - `run_id`/`purpose`: 见 [`UsageLabels`]
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub latency_ms: u64,
+    pub outcome: CallOutcome,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub run_id: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub purpose: Option<String>,
+}
+
+impl UsageRecord {
+    /// 记录刚刚完成的一次调用
+    pub fn completed(
+        provider: &str,
+        model: &str,
+        tokens: (u64, u64),
+        latency: Duration,
+        outcome: CallOutcome,
+        labels: &UsageLabels,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            provider: provider.to_string(),
+            model: model.to_string(),
+            prompt_tokens: tokens.0,
+            completion_tokens: tokens.1,
+            latency_ms: latency.as_millis() as u64,
+            outcome,
+            run_id: labels.run_id.clone(),
+            purpose: labels.purpose.clone(),
+        }
+    }
+}
+
+/// 一天内一个模型的用量
+///
+/// # 字段
+/// - `day`: 日期（UTC）
+/// - `provider`/`model`: 服务商和模型
+/// - `calls`: 调用次数，包括失败的调用
+/// - `failures`: 失败的调用次数
+/// - `prompt_tokens`/`completion_tokens`: token 合计
+/// - `total_latency_ms`: 耗时合计
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageRow {
+    pub day: NaiveDate,
+    pub provider: String,
+    pub model: String,
+    pub calls: u64,
+    pub failures: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_latency_ms: u64,
+}
+
+impl UsageRow {
+    /// 平均每次调用的耗时（毫秒）
+    pub fn average_latency_ms(&self) -> u64 {
+        self.total_latency_ms
+            .checked_div(self.calls)
+            .unwrap_or(0)
+    }
+}
+
+/// [`UsageJournal::summarize`] 的结果
+///
+/// # 字段
+/// - `rows`: 按日期、服务商、模型排序的汇总
+/// - `skipped_lines`: 无法解析而跳过的行数
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageReport {
+    pub rows: Vec<UsageRow>,
+    pub skipped_lines: usize,
+}
+
+impl UsageReport {
+    /// 所有行的 token 合计
+    pub fn total_tokens(&self) -> u64 {
+        self.rows
+            .iter()
+            .map(|row| {
+                row.prompt_tokens + row.completion_tokens
+            })
+            .sum()
+    }
+}
+
+/// 追加写入的用量日志，克隆之间共享同一个文件
+///
+/// 多个任务可以同时追加：每条记录在锁内以一次写入完成，轮换也在
+/// 锁内进行，不会出现交错或残缺的行。写入是同步的文件操作，
+/// 使用 [`FsyncPolicy::EveryRecord`] 时每次调用会多一次磁盘同步。
+#[derive(Debug, Clone)]
+pub struct UsageJournal {
+    dir: PathBuf,
+    options: JournalOptions,
+    state: Arc<Mutex<JournalState>>,
+}
+
+#[derive(Debug, Default)]
+struct JournalState {
+    file: Option<File>,
+    size: u64,
+    unsynced: usize,
+}
+
+impl UsageJournal {
+    /// 在 `dir` 下记录用量，目录不存在时创建
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        options: JournalOptions,
+    ) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(
+            || {
+                format!(
+                    "Failed to create {}",
+                    dir.display()
+                )
+            },
+        )?;
+        Ok(Self {
+            dir,
+            options,
+            state: Arc::new(Mutex::new(
+                JournalState::default(),
+            )),
+        })
+    }
+
+    /// 在数据目录的 [`USAGE_DIR_NAME`] 子目录下记录用量
+    pub fn open_default(
+        options: JournalOptions,
+    ) -> anyhow::Result<Self> {
+        Self::open(
+            AppDir::Data.ensure()?.join(USAGE_DIR_NAME),
+            options,
+        )
+    }
+
+    /// 日志所在的目录
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 追加一条记录
+    pub fn append(
+        &self,
+        record: &UsageRecord,
+    ) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        if state.file.is_none() {
+            self.open_current(&mut state)?;
+        }
+        if state.size > 0
+            && state.size + line.len() as u64
+                > self.options.max_file_bytes
+        {
+            self.rotate(&mut state)?;
+        }
+        let file =
+            state.file.as_mut().expect("opened above");
+        file.write_all(line.as_bytes())?;
+        state.size += line.len() as u64;
+        state.unsynced += 1;
+
+        let every = match self.options.fsync {
+            FsyncPolicy::Never => None,
+            FsyncPolicy::EveryRecord => Some(1),
+            FsyncPolicy::EveryN(n) => Some(n.max(1)),
+        };
+        if every.is_some_and(|n| state.unsynced >= n) {
+            state
+                .file
+                .as_ref()
+                .expect("opened above")
+                .sync_data()?;
+            state.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// 按天和模型汇总 `days` 范围内（UTC 日期）的记录
+    ///
+    /// 读取当前文件和所有保留的轮换文件；无法解析的行计入
+    /// [`UsageReport::skipped_lines`]。
+    pub fn summarize(
+        &self,
+        days: impl RangeBounds<NaiveDate>,
+    ) -> anyhow::Result<UsageReport> {
+        // Hold the lock so no rotation renames files under the reader
+        let _state = self
+            .state
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        let mut rows: BTreeMap<
+            (NaiveDate, String, String),
+            UsageRow,
+        > = BTreeMap::new();
+        let mut skipped_lines = 0;
+
+        for path in self.files() {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e)
+                    if e.kind()
+                        == std::io::ErrorKind::NotFound =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!(
+                            "Failed to read {}",
+                            path.display()
+                        )
+                    });
+                }
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let Ok(record) = serde_json::from_str::<
+                    UsageRecord,
+                >(&line) else {
+                    skipped_lines += 1;
+                    continue;
+                };
+                let day = record.timestamp.date_naive();
+                if !days.contains(&day) {
+                    continue;
+                }
+                let row = rows
+                    .entry((
+                        day,
+                        record.provider.clone(),
+                        record.model.clone(),
+                    ))
+                    .or_insert_with(|| UsageRow {
+                        day,
+                        provider: record.provider,
+                        model: record.model,
+                        ..Default::default()
+                    });
+                row.calls += 1;
+                if record.outcome != CallOutcome::Success {
+                    row.failures += 1;
+                }
+                row.prompt_tokens += record.prompt_tokens;
+                row.completion_tokens +=
+                    record.completion_tokens;
+                row.total_latency_ms += record.latency_ms;
+            }
+        }
+        Ok(UsageReport {
+            rows: rows.into_values().collect(),
+            skipped_lines,
+        })
+    }
+
+    /// The current file followed by the rotated ones, newest first
+    fn files(&self) -> Vec<PathBuf> {
+        std::iter::once(self.dir.join(USAGE_FILE_NAME))
+            .chain(
+                (1..=self.options.max_files)
+                    .map(|index| self.rotated(index)),
+            )
+            .collect()
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        self.dir.join(format!("usage.{}.jsonl", index))
+    }
+
+    fn open_current(
+        &self,
+        state: &mut JournalState,
+    ) -> anyhow::Result<()> {
+        let path = self.dir.join(USAGE_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| {
+                format!("Failed to open {}", path.display())
+            })?;
+        state.size = file.metadata()?.len();
+        state.file = Some(file);
+        Ok(())
+    }
+
+    /// Shifts every file up one index, dropping the oldest, and
+    /// starts an empty current file
+    fn rotate(
+        &self,
+        state: &mut JournalState,
+    ) -> anyhow::Result<()> {
+        if let Some(file) = state.file.take() {
+            file.sync_data()?;
+        }
+        state.unsynced = 0;
+        let current = self.dir.join(USAGE_FILE_NAME);
+        if self.options.max_files == 0 {
+            std::fs::remove_file(&current)?;
+        } else {
+            let oldest =
+                self.rotated(self.options.max_files);
+            if oldest.exists() {
+                std::fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.options.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    std::fs::rename(
+                        &from,
+                        self.rotated(index + 1),
+                    )?;
+                }
+            }
+            std::fs::rename(&current, self.rotated(1))?;
+        }
+        self.open_current(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_usage_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(
+        timestamp: &str,
+        model: &str,
+        tokens: (u64, u64),
+        latency_ms: u64,
+        outcome: CallOutcome,
+    ) -> UsageRecord {
+        UsageRecord {
+            timestamp: timestamp.parse().unwrap(),
+            provider: "ZhiPu".to_string(),
+            model: model.to_string(),
+            prompt_tokens: tokens.0,
+            completion_tokens: tokens.1,
+            latency_ms,
+            outcome,
+            run_id: None,
+            purpose: Some("cards".to_string()),
+        }
+    }
+
+    fn day(text: &str) -> NaiveDate {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files() {
+        let dir = temp_dir("rotation");
+        let sample = record(
+            "2026-10-01T08:00:00Z",
+            "glm-4.7",
+            (10, 5),
+            100,
+            CallOutcome::Success,
+        );
+        let line_len = serde_json::to_string(&sample)
+            .unwrap()
+            .len() as u64
+            + 1;
+        let journal = UsageJournal::open(
+            &dir,
+            JournalOptions {
+                max_file_bytes: line_len * 3,
+                max_files: 2,
+                fsync: FsyncPolicy::EveryN(2),
+            },
+        )
+        .unwrap();
+
+        for _ in 0..10 {
+            journal.append(&sample).unwrap();
+        }
+        let mut names: Vec<String> =
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| {
+                    entry
+                        .unwrap()
+                        .file_name()
+                        .into_string()
+                        .unwrap()
+                })
+                .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "usage.1.jsonl",
+                "usage.2.jsonl",
+                "usage.jsonl"
+            ]
+        );
+        for name in &names {
+            let size = std::fs::metadata(dir.join(name))
+                .unwrap()
+                .len();
+            assert!(
+                size <= line_len * 3,
+                "{} has {} bytes",
+                name,
+                size
+            );
+        }
+        // 3 + 3 rotated lines and 1 current survive of 10
+        let report = journal.summarize(..).unwrap();
+        assert_eq!(report.rows[0].calls, 7);
+
+        // A reopened journal continues the current file
+        let reopened =
+            UsageJournal::open(&dir, journal.options)
+                .unwrap();
+        reopened.append(&sample).unwrap();
+        assert_eq!(
+            reopened.summarize(..).unwrap().rows[0].calls,
+            8
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_summarize_per_model_and_day() {
+        let dir = temp_dir("summary");
+        let journal = UsageJournal::open(
+            &dir,
+            JournalOptions::default(),
+        )
+        .unwrap();
+        for entry in [
+            record(
+                "2026-10-01T08:00:00Z",
+                "glm-4.7",
+                (100, 40),
+                900,
+                CallOutcome::Success,
+            ),
+            record(
+                "2026-10-01T23:59:59Z",
+                "glm-4.7",
+                (50, 10),
+                300,
+                CallOutcome::Success,
+            ),
+            record(
+                "2026-10-01T12:00:00Z",
+                "glm-4.7",
+                (0, 0),
+                60,
+                CallOutcome::HttpStatus(429),
+            ),
+            record(
+                "2026-10-01T12:00:00Z",
+                "glm-4.7-flash",
+                (20, 20),
+                100,
+                CallOutcome::Success,
+            ),
+            record(
+                "2026-10-02T00:00:00Z",
+                "glm-4.7",
+                (7, 3),
+                50,
+                CallOutcome::Failed,
+            ),
+            record(
+                "2026-10-05T00:00:00Z",
+                "glm-4.7",
+                (1000, 1000),
+                10,
+                CallOutcome::Success,
+            ),
+        ] {
+            journal.append(&entry).unwrap();
+        }
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(USAGE_FILE_NAME))
+            .unwrap()
+            .write_all(b"{not json\n\n")
+            .unwrap();
+
+        let report = journal
+            .summarize(
+                day("2026-10-01")..=day("2026-10-02"),
+            )
+            .unwrap();
+        assert_eq!(report.skipped_lines, 1);
+        assert_eq!(
+            report.rows,
+            [
+                UsageRow {
+                    day: day("2026-10-01"),
+                    provider: "ZhiPu".to_string(),
+                    model: "glm-4.7".to_string(),
+                    calls: 3,
+                    failures: 1,
+                    prompt_tokens: 150,
+                    completion_tokens: 50,
+                    total_latency_ms: 1260,
+                },
+                UsageRow {
+                    day: day("2026-10-01"),
+                    provider: "ZhiPu".to_string(),
+                    model: "glm-4.7-flash".to_string(),
+                    calls: 1,
+                    failures: 0,
+                    prompt_tokens: 20,
+                    completion_tokens: 20,
+                    total_latency_ms: 100,
+                },
+                UsageRow {
+                    day: day("2026-10-02"),
+                    provider: "ZhiPu".to_string(),
+                    model: "glm-4.7".to_string(),
+                    calls: 1,
+                    failures: 1,
+                    prompt_tokens: 7,
+                    completion_tokens: 3,
+                    total_latency_ms: 50,
+                },
+            ]
+        );
+        assert_eq!(
+            report.rows[0].average_latency_ms(),
+            420
+        );
+        assert_eq!(report.total_tokens(), 250);
+        assert_eq!(
+            journal
+                .summarize(day("2026-10-03")..)
+                .unwrap()
+                .total_tokens(),
+            2000
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_appends_stay_whole() {
+        let dir = temp_dir("concurrent");
+        let journal = UsageJournal::open(
+            &dir,
+            JournalOptions {
+                max_file_bytes: 4096,
+                max_files: 100,
+                fsync: FsyncPolicy::Never,
+            },
+        )
+        .unwrap();
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let journal = journal.clone();
+                tokio::spawn(async move {
+                    for call in 0..25 {
+                        journal
+                            .append(&record(
+                                "2026-10-01T08:00:00Z",
+                                &format!("model-{}", task),
+                                (call, 1),
+                                1,
+                                CallOutcome::Success,
+                            ))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let report = journal.summarize(..).unwrap();
+        assert_eq!(report.skipped_lines, 0);
+        assert_eq!(report.rows.len(), 8);
+        for row in &report.rows {
+            assert_eq!(row.calls, 25);
+            assert_eq!(
+                row.prompt_tokens,
+                (0..25).sum::<u64>()
+            );
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1 +1,10 @@
 pub mod client;
+pub mod error;
+pub mod events;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod note;
+pub mod queue;
+#[cfg(any(test, feature = "metrics"))]
+pub mod telemetry;
+pub mod transport;
@@ -1,11 +1,68 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
+use reqwest::header::{
+    AUTHORIZATION, HeaderMap, HeaderName, HeaderValue,
+};
+use reqwest::{Certificate, Client};
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::{OnceCell, Semaphore};
+use tokio_util::sync::CancellationToken;
+use utils::config::handle::SettingsHandle;
+use utils::config::secret::Secret;
+use utils::config::settings::Settings;
+
+use super::error::{AnkiError, ConnectionFailure};
+use super::events::{
+    ClientEvent, EventCallback, EventHook, RequestOutcome,
+};
+use super::note::NoteFields;
+use super::transport::{
+    HttpTransport, ResponseTooLarge, Transport,
+};
+use crate::convert::{media_references, strip_html};
 
 /// Default Anki-Connect endpoint URL
 const DEFAULT_ANKI_CONNECT_URL: &str =
     "http://localhost:8765";
 
+/// Default number of IDs sent per `notesInfo`/`cardsInfo` request
+/// by [`AnkiClient::find_notes_detailed`] and
+/// [`AnkiClient::find_cards_detailed`]; at a few KB per note this
+/// keeps each response to a few MB however large the collection
+pub const DEFAULT_INFO_CHUNK: usize = 500;
+
+/// Default number of note IDs per `addTags`/`removeTags` request
+/// of [`AnkiClient::tag_query`]
+pub const DEFAULT_TAG_BATCH: usize = 1000;
+
+/// Default cap on simultaneous requests from one client; the
+/// Anki-Connect server handles requests on a single thread
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+/// Oldest Anki-Connect API version [`AnkiClient::ensure_compatible`]
+/// accepts
+pub const MIN_ANKI_CONNECT_VERSION: u32 = 5;
+
+/// API version the client speaks unless an older server is detected
+const CURRENT_ANKI_CONNECT_VERSION: u32 = 6;
+
+/// Actions added after API version 5, refused without a request
+/// once a version 5 server is detected
+const ACTIONS_MISSING_IN_V5: &[&str] = &[
+    "answerCards",
+    "getNoteTags",
+    "getReviewsOfCards",
+    "requestPermission",
+    "setDueDate",
+    "updateNote",
+    "updateNoteTags",
+];
+
 /// Anki-Connect request structure following JSON-RPC 2.0 specification
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -17,6 +74,9 @@ struct AnkiRequest<T> {
     /// Action-specific parameters
     #[serde(skip_serializing_if = "Option::is_none")]
     params: Option<T>,
+    /// API key, when Anki-Connect is configured to require one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
 }
 
 impl<T> AnkiRequest<T> {
@@ -30,22 +90,27 @@ impl<T> AnkiRequest<T> {
             action: action.to_string(),
             version,
             params,
+            key: None,
         }
     }
 }
 
 /// Anki-Connect response structure
+///
+/// Anki-Connect always sends both `result` and `error`; the error
+/// variant is tried first so a `null` result paired with an error
+/// message is never mistaken for success.
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 enum AnkiResponse<T> {
-    /// Successful response containing the result
-    Success { result: T },
     /// Error response containing error details
     Error {
         error: String,
         #[serde(default)]
         detail: Option<String>,
     },
+    /// Successful response containing the result
+    Success { result: T },
 }
 
 /// Represents a single note field (key-value pair)
@@ -65,10 +130,13 @@ pub struct Note {
     pub model_name: String,
     /// Deck name where the note should be added
     pub deck_name: String,
-    /// Fields of the note (e.g., Front, Back)
-    pub fields: std::collections::HashMap<String, String>,
+    /// Fields of the note (e.g., Front, Back), in order
+    pub fields: NoteFields,
     /// Tags for the note
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub tags: Vec<String>,
     /// Optional: Audio files to add with the note
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -127,7 +195,7 @@ pub struct NoteVideo {
 }
 
 /// Options for note creation
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct NoteOptions {
     /// Whether to check for duplicates
     #[serde(rename = "allowDuplicate")]
@@ -135,6 +203,37 @@ pub struct NoteOptions {
     /// Scope of duplicate check
     #[serde(rename = "duplicateScope")]
     pub duplicate_scope: Option<String>,
+    /// Fine-grained control over which notes count as duplicates
+    #[serde(
+        rename = "duplicateScopeOptions",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duplicate_scope_options:
+        Option<DuplicateScopeOptions>,
+}
+
+/// Where Anki looks for duplicates when `duplicateScope` is `deck`
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateScopeOptions {
+    /// Deck to check instead of the note's own deck
+    #[serde(default)]
+    pub deck_name: Option<String>,
+    /// Also check the subdecks of that deck
+    #[serde(default)]
+    pub check_children: bool,
+    /// Compare against notes of every model, not just the note's own
+    #[serde(default)]
+    pub check_all_models: bool,
 }
 
 /// Parameters for adding notes in bulk
@@ -151,6 +250,53 @@ pub struct AddNoteParams {
     pub note: Note,
 }
 
+/// Whether one note of a `canAddNotesWithErrorDetail` call can be
+/// added, and why not
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanAddNote {
+    /// Whether the note can be added
+    pub can_add: bool,
+    /// Anki's reason when it cannot, e.g. "cannot create note because
+    /// it is a duplicate"
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl CanAddNote {
+    /// Whether the note is refused for being a duplicate
+    pub fn is_duplicate(&self) -> bool {
+        self.error
+            .as_deref()
+            .is_some_and(|e| e.contains("duplicate"))
+    }
+}
+
+/// Outcome for one note of [`AnkiClient::add_notes_chunked`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkedAdd {
+    /// Added with this note ID
+    Added(u64),
+    /// Sent, but Anki did not add it (e.g. a duplicate)
+    Rejected,
+    /// Never sent because the operation was cancelled first
+    NotAttempted,
+}
+
+/// Outcome for one note of [`AnkiClient::import_notes_json`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonImport {
+    /// Added with this note ID
+    Added(u64),
+    /// Never sent because the collection has no model of this name
+    Skipped {
+        /// The missing model
+        model: String,
+    },
+    /// Sent, but Anki did not add it (e.g. a duplicate)
+    Rejected,
+}
+
 /// Parameters for getting deck names
 #[derive(Debug, Clone, Serialize)]
 pub struct GetDeckNamesParams {
@@ -159,6 +305,121 @@ pub struct GetDeckNamesParams {
     pub cards: Option<bool>,
 }
 
+/// Parameters for moving cards to another deck
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeDeckParams {
+    /// List of card IDs
+    pub cards: Vec<u64>,
+    /// Full name of the target deck, created if missing
+    pub deck: String,
+}
+
+/// Options for [`AnkiClient::rename_deck`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenameOptions {
+    /// Give each new deck the options group of its old deck
+    pub copy_options: bool,
+    /// Delete the old decks once they are verified empty
+    pub delete_old: bool,
+    /// Accept target decks that already exist, to continue a rename
+    /// that stopped part-way (see [`RenameReport::failure`])
+    pub resume: bool,
+}
+
+impl Default for RenameOptions {
+    fn default() -> Self {
+        Self {
+            copy_options: true,
+            delete_old: true,
+            resume: false,
+        }
+    }
+}
+
+/// Outcome of [`AnkiClient::rename_deck`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenameReport {
+    /// Old and new name of every deck in the subtree, parents first
+    pub renamed: Vec<(String, String)>,
+    /// New decks that were created; targets that already existed
+    /// when resuming are left out
+    pub created: Vec<String>,
+    /// New decks that were given the options group of their old deck
+    pub configured: Vec<String>,
+    /// Cards moved out of each old deck, for the decks done so far
+    pub moved: Vec<(String, usize)>,
+    /// Whether the old decks were deleted
+    pub deleted_old: bool,
+    /// The step that failed and why. Everything recorded above is
+    /// done; calling [`AnkiClient::rename_deck`] again with
+    /// [`RenameOptions::resume`] continues from there.
+    pub failure: Option<String>,
+}
+
+impl RenameReport {
+    /// Whether every step succeeded
+    pub fn is_complete(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Parameters for creating a deck
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateDeckParams {
+    /// Full deck name, `::`-separated for subdecks
+    pub deck: String,
+}
+
+/// Parameters for getting the options group of a deck
+#[derive(Debug, Clone, Serialize)]
+pub struct GetDeckConfigParams {
+    /// Deck name
+    pub deck: String,
+}
+
+/// Parameters for saving an options group
+#[derive(Debug, Clone, Serialize)]
+pub struct SaveDeckConfigParams {
+    /// The complete options group
+    pub config: DeckConfig,
+}
+
+/// Parameters for assigning an options group to decks
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetDeckConfigIdParams {
+    /// Deck names
+    pub decks: Vec<String>,
+    /// Options group ID
+    pub config_id: u64,
+}
+
+/// Parameters for cloning an options group
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CloneDeckConfigIdParams {
+    /// Name of the new options group
+    pub name: String,
+    /// Options group to copy (the default group when absent)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clone_from: Option<u64>,
+}
+
+/// A deck options group ("deck config")
+#[derive(
+    Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct DeckConfig {
+    /// Options group ID
+    pub id: u64,
+    /// Options group name
+    pub name: String,
+    /// Every other option (`new`, `rev`, `lapse`, ...), kept verbatim
+    /// so the group round-trips through `saveDeckConfig`
+    #[serde(flatten)]
+    pub options: serde_json::Map<String, serde_json::Value>,
+}
+
 /// Parameters for getting model names
 #[derive(Debug, Clone, Serialize)]
 pub struct GetModelNamesParams {}
@@ -175,6 +436,7 @@ pub struct GetModelFieldNamesParams {
 #[derive(Debug, Clone, Deserialize)]
 pub struct NoteInfo {
     /// Note ID
+    #[serde(rename = "noteId")]
     pub note_id: u64,
     /// Tags for the note
     #[serde(default)]
@@ -189,6 +451,59 @@ pub struct NoteInfo {
     pub cards: Vec<u64>,
 }
 
+impl NoteInfo {
+    /// Returns `(name, value)` pairs sorted by the model's field order
+    pub fn ordered_fields(&self) -> Vec<(&str, &str)> {
+        let mut fields: Vec<_> =
+            self.fields.iter().collect();
+        fields.sort_by_key(|(_, field)| field.order);
+        fields
+            .into_iter()
+            .map(|(name, field)| {
+                (name.as_str(), field.value.as_str())
+            })
+            .collect()
+    }
+}
+
+/// One note of [`AnkiClient::export_notes_json`]
+#[derive(Serialize)]
+struct ExportedNote<'a> {
+    model: &'a str,
+    tags: &'a [String],
+    fields: OrderedFields<'a>,
+}
+
+/// One note read by [`AnkiClient::import_notes_json`]
+#[derive(Deserialize)]
+struct ImportedNote {
+    model: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    fields: NoteFields,
+}
+
+/// Field pairs written as a JSON object in the model's field order
+struct OrderedFields<'a>(Vec<(&'a str, &'a str)>);
+
+impl Serialize for OrderedFields<'_> {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+        let mut map =
+            serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
 /// Value of a note field with ordering info
 #[derive(Debug, Clone, Deserialize)]
 pub struct NoteFieldValue {
@@ -212,27 +527,186 @@ pub struct NotesInfoParams {
     pub notes: Vec<u64>,
 }
 
-/// Parameters for updating note fields
+/// Parameters for updating note fields; Anki-Connect wants the note
+/// nested as `{"note": {"id": .., "fields": ..}}`
 #[derive(Debug, Clone, Serialize)]
 pub struct UpdateNoteFieldsParams {
+    /// The note and its new field values
+    pub note: UpdatedNoteFields,
+}
+
+/// Note payload of [`UpdateNoteFieldsParams`]
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdatedNoteFields {
     /// Note ID
-    pub note: u64,
+    pub id: u64,
     /// Fields to update
-    pub fields: std::collections::HashMap<String, String>,
+    pub fields: NoteFields,
     /// Audio files (optional)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub audio: Option<Vec<NoteAudio>>,
 }
 
+/// Parameters for replacing the tags of a note
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateNoteTagsParams {
+    /// Note ID
+    pub note: u64,
+    /// New tags for the note
+    pub tags: Vec<String>,
+}
+
+/// Parameters for `addTags` and `removeTags`
+#[derive(Debug, Clone, Serialize)]
+pub struct ModifyTagsParams {
+    /// Note IDs
+    pub notes: Vec<u64>,
+    /// Space-separated tags, sent as given
+    pub tags: String,
+}
+
+/// Outcome of [`AnkiClient::tag_query`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TagOpReport {
+    /// Notes matching the query
+    pub matched: usize,
+    /// Notes tags were added to or removed from
+    pub touched: usize,
+    /// `addTags`/`removeTags` requests sent
+    pub batches: usize,
+}
+
+/// Outcome of [`AnkiClient::add_notes_tagged`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaggedAddReport {
+    /// ID of each note, `None` where Anki refused it
+    pub note_ids: Vec<Option<u64>>,
+    /// Why tagging the added notes failed. The notes exist either
+    /// way, so a retry should only call [`AnkiClient::add_tags`] on
+    /// them rather than add them again.
+    pub tag_failure: Option<String>,
+}
+
+impl TaggedAddReport {
+    /// Whether the added notes were also tagged
+    pub fn is_complete(&self) -> bool {
+        self.tag_failure.is_none()
+    }
+}
+
+/// Parameters for deleting notes
+#[derive(Debug, Clone, Serialize)]
+pub struct DeleteNotesParams {
+    /// List of note IDs
+    pub notes: Vec<u64>,
+}
+
+/// Parameters for deleting decks
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteDecksParams {
+    /// Names of the decks to delete
+    pub decks: Vec<String>,
+    /// Must be `true`; Anki-Connect refuses to keep the cards
+    pub cards_too: bool,
+}
+
+/// Parameters for resetting cards to new
+#[derive(Debug, Clone, Serialize)]
+pub struct ForgetCardsParams {
+    /// List of card IDs
+    pub cards: Vec<u64>,
+}
+
+/// Parameters for suspending cards
+#[derive(Debug, Clone, Serialize)]
+pub struct SuspendCardsParams {
+    /// List of card IDs
+    pub cards: Vec<u64>,
+}
+
+/// Parameters for exporting a deck as `.apkg`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportPackageParams {
+    /// Deck to export
+    pub deck: String,
+    /// Destination file, as seen by the Anki process
+    pub path: String,
+    /// Whether review history and scheduling are included
+    pub include_sched: bool,
+}
+
+/// Learning stage of a card (`CardInfo::card_type`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CardType {
+    New,
+    Learning,
+    Review,
+    Relearning,
+    /// A value this crate does not know
+    Other(u32),
+}
+
+impl From<u32> for CardType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => CardType::New,
+            1 => CardType::Learning,
+            2 => CardType::Review,
+            3 => CardType::Relearning,
+            other => CardType::Other(other),
+        }
+    }
+}
+
+/// Scheduling queue of a card (`CardInfo::queue`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CardQueue {
+    /// Buried by the user
+    UserBuried,
+    /// Buried automatically as a sibling
+    SchedulerBuried,
+    Suspended,
+    New,
+    /// Intraday learning
+    Learning,
+    Review,
+    /// Learning across days
+    DayLearning,
+    /// Previewed in a filtered deck without rescheduling
+    Preview,
+    /// A value this crate does not know
+    Other(i32),
+}
+
+impl From<i32> for CardQueue {
+    fn from(value: i32) -> Self {
+        match value {
+            -3 => CardQueue::UserBuried,
+            -2 => CardQueue::SchedulerBuried,
+            -1 => CardQueue::Suspended,
+            0 => CardQueue::New,
+            1 => CardQueue::Learning,
+            2 => CardQueue::Review,
+            3 => CardQueue::DayLearning,
+            4 => CardQueue::Preview,
+            other => CardQueue::Other(other),
+        }
+    }
+}
+
 /// Information about a card in Anki
 #[derive(Debug, Clone, Deserialize)]
 pub struct CardInfo {
     /// Card ID
+    #[serde(rename = "cardId")]
     pub card_id: u64,
     /// Note ID
+    #[serde(rename = "note")]
     pub note_id: u64,
     /// Deck name
-    #[serde(rename = "deck")]
+    #[serde(rename = "deckName")]
     pub deck_name: String,
     /// Model name
     #[serde(rename = "modelName")]
@@ -240,17 +714,17 @@ pub struct CardInfo {
     /// Card ordinal
     pub ord: u32,
     /// Card modification time
-    #[serde(rename = "mod")]
+    #[serde(rename = "mod", default)]
     pub modification_time: u64,
     /// Card type (0=new, 1=learning, 2=review, 3=relearning)
     #[serde(rename = "type")]
     pub card_type: u32,
-    /// Card queue
-    pub queue: u32,
+    /// Card queue (negative for suspended and buried cards)
+    pub queue: i32,
     /// Card due time
-    pub due: u64,
-    /// Card interval
-    pub interval: u32,
+    pub due: i64,
+    /// Card interval (days, or negative seconds while learning)
+    pub interval: i64,
     /// Card ease factor
     pub factor: u32,
     /// Number of repetitions
@@ -258,17 +732,31 @@ pub struct CardInfo {
     /// Number of lapses
     pub lapses: u32,
     /// Card left value
+    #[serde(default)]
     pub left: u32,
     /// Original due time
-    #[serde(rename = "odue")]
-    pub original_due: u64,
+    #[serde(rename = "odue", default)]
+    pub original_due: i64,
     /// Original card queue
-    #[serde(rename = "oqueue")]
-    pub original_queue: u32,
+    #[serde(rename = "oqueue", default)]
+    pub original_queue: i32,
     /// Card flags
+    #[serde(default)]
     pub flags: u32,
 }
 
+impl CardInfo {
+    /// Typed [`CardInfo::card_type`]
+    pub fn kind(&self) -> CardType {
+        CardType::from(self.card_type)
+    }
+
+    /// Typed [`CardInfo::queue`]
+    pub fn queue_kind(&self) -> CardQueue {
+        CardQueue::from(self.queue)
+    }
+}
+
 /// Parameters for getting cards info
 #[derive(Debug, Clone, Serialize)]
 pub struct CardsInfoParams {
@@ -276,6 +764,83 @@ pub struct CardsInfoParams {
     pub cards: Vec<u64>,
 }
 
+/// Parameters for getting the reviews of a deck
+#[derive(Debug, Clone, Serialize)]
+pub struct CardReviewsParams {
+    /// Deck name
+    pub deck: String,
+    /// Only reviews with a greater ID are returned
+    #[serde(rename = "startID")]
+    pub start_id: i64,
+}
+
+/// One entry of the review log, as returned by `cardReviews`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "CardReviewRow")]
+pub struct CardReview {
+    /// Review ID, the review time in milliseconds since the epoch
+    pub review_time: i64,
+    /// Card ID
+    pub card_id: u64,
+    /// Update sequence number
+    pub usn: i64,
+    /// Answer button (1=again, 2=hard, 3=good, 4=easy)
+    pub button: u32,
+    /// Interval after the review (days, or negative seconds)
+    pub interval: i64,
+    /// Interval before the review
+    pub last_interval: i64,
+    /// Ease factor after the review
+    pub factor: u32,
+    /// Time spent answering, in milliseconds
+    pub duration: u64,
+    /// Review type (0=learning, 1=review, 2=relearning,
+    /// 3=filtered, 4=manual)
+    pub review_type: u32,
+}
+
+type CardReviewRow =
+    (i64, u64, i64, u32, i64, i64, u32, u64, u32);
+
+impl From<CardReviewRow> for CardReview {
+    fn from(row: CardReviewRow) -> Self {
+        let (
+            review_time,
+            card_id,
+            usn,
+            button,
+            interval,
+            last_interval,
+            factor,
+            duration,
+            review_type,
+        ) = row;
+        Self {
+            review_time,
+            card_id,
+            usn,
+            button,
+            interval,
+            last_interval,
+            factor,
+            duration,
+            review_type,
+        }
+    }
+}
+
+impl CardReview {
+    /// Whether the card was in the review stage when answered
+    pub fn is_review(&self) -> bool {
+        self.review_type == 1
+    }
+
+    /// Whether the answer was "Again"
+    pub fn is_again(&self) -> bool {
+        self.button == 1
+    }
+}
+
 /// Parameters for finding cards
 #[derive(Debug, Clone, Serialize)]
 pub struct FindCardsParams {
@@ -283,477 +848,6941 @@ pub struct FindCardsParams {
     pub query: String,
 }
 
-/// Anki-Connect client for interacting with Anki
-#[derive(Debug, Clone)]
-pub struct AnkiClient {
-    /// HTTP client for making requests
-    client: Client,
-    /// Anki-Connect endpoint URL
-    url: String,
-    /// API version
-    version: u8,
+/// How [`AnkiClient::find_note_by_field`] compares field values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FieldMatch {
+    /// The stored value must equal the searched one, as Anki's
+    /// `field:value` search does
+    #[default]
+    Exact,
+    /// Markup and entities are stripped from both values (see
+    /// [`strip_html`]), so `<div>cat</div>` matches `cat`
+    IgnoreHtml,
 }
 
-impl Default for AnkiClient {
-    fn default() -> Self {
-        Self::new()
+/// Normalizes a deck or model name for comparison.
+///
+/// Applies Unicode NFC composition and trims surrounding whitespace,
+/// so names typed by users match the names Anki stores.
+pub fn normalize_name(name: &str) -> String {
+    name.trim().nfc().collect()
+}
+
+/// Builds an exact `deck:` search query for the given deck name.
+///
+/// Quotes the name and escapes Anki's search wildcards, so decks
+/// containing spaces, `*` or `_` are matched literally. As with any
+/// `deck:` search, subdecks are included.
+pub fn deck_query(deck_name: &str) -> String {
+    format!("deck:\"{}\"", escape_search(deck_name))
+}
+
+/// Splits a deck name after its first `depth` `::`-separated parts,
+/// e.g. `("Japanese", "::Kanji")` for `Japanese::Kanji` at depth 1;
+/// `None` if the name is shallower
+pub(crate) fn split_deck_root(
+    name: &str,
+    depth: usize,
+) -> Option<(&str, &str)> {
+    match name.match_indices("::").nth(depth - 1) {
+        Some((at, _)) => Some(name.split_at(at)),
+        None if name.split("::").count() == depth => {
+            Some((name, ""))
+        }
+        None => None,
     }
 }
 
-impl AnkiClient {
-    /// Creates a new AnkiClient with default settings
-    pub fn new() -> Self {
-        Self {
-            client: Client::new(),
-            url: DEFAULT_ANKI_CONNECT_URL.to_string(),
-            version: 6,
+/// Like [`deck_query`], but without the deck's subdecks
+pub fn deck_only_query(deck_name: &str) -> String {
+    let deck = escape_search(deck_name);
+    format!("deck:\"{0}\" -deck:\"{0}::*\"", deck)
+}
+
+/// Escapes quotes, backslashes and Anki's search wildcards
+pub(crate) fn escape_search(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '*' | '_' | '\\') {
+            escaped.push('\\');
         }
+        escaped.push(c);
     }
+    escaped
+}
 
-    /// Creates a new AnkiClient with a custom endpoint URL
-    pub fn with_url(url: impl Into<String>) -> Self {
-        Self {
-            client: Client::new(),
-            url: url.into(),
-            version: 6,
+/// Parses an Anki-Connect response body into its result.
+///
+/// Besides the usual `{"result": ..., "error": ...}` envelope, some
+/// setups send the error as a list of messages, describe it inside
+/// `result`, or wrap the whole envelope in a one-element array; all
+/// of these are reported as [`AnkiError`]s.
+fn parse_response<R>(body: &[u8]) -> Result<R>
+where
+    R: for<'de> Deserialize<'de>,
+{
+    if let Some(parsed) = parse_usual_response(body) {
+        return parsed;
+    }
+
+    let value: serde_json::Value =
+        serde_json::from_slice(body)
+            .map_err(parse_failed)?;
+    let value = match value {
+        serde_json::Value::Array(mut items)
+            if items.len() == 1 && items[0].is_object() =>
+        {
+            items.remove(0)
         }
+        value => value,
+    };
+
+    if let Some(error) = fallback_error(&value) {
+        return Err(error.into());
     }
 
-    /// Creates a new AnkiClient with custom HTTP client
-    pub fn with_client(client: Client) -> Self {
-        Self {
-            client,
-            url: DEFAULT_ANKI_CONNECT_URL.to_string(),
-            version: 6,
+    match serde_json::from_value::<AnkiResponse<R>>(
+        value.clone(),
+    ) {
+        Ok(AnkiResponse::Success { result }) => Ok(result),
+        Ok(AnkiResponse::Error { error, detail }) => {
+            Err(AnkiError::from_message(error, detail)
+                .into())
+        }
+        Err(e) => {
+            let mut shown = value.to_string();
+            if shown.len() > 200 {
+                let end = shown.floor_char_boundary(200);
+                shown.truncate(end);
+                shown.push_str("...");
+            }
+            Err(AnkiError::invalid_response(
+                format!(
+                    "Failed to parse Anki-Connect response: unexpected shape {}",
+                    shown
+                ),
+                e,
+            )
+            .into())
         }
     }
+}
 
-    /// Invokes an Anki-Connect action with the given parameters
-    async fn invoke<T, R>(
-        &self,
-        action: &str,
-        params: Option<T>,
-    ) -> Result<R>
-    where
-        T: Serialize,
-        R: for<'de> Deserialize<'de>,
-    {
-        let request =
-            AnkiRequest::new(action, self.version, params);
-        let response = self
-            .client
-            .post(&self.url)
-            .json(&request)
-            .send()
-            .await
-            .context(
-                "Failed to send request to Anki-Connect",
-            )?;
+/// The usual envelope with `result` left unparsed, borrowed from
+/// the response body
+#[derive(Deserialize)]
+struct RawResponse<'a> {
+    #[serde(borrow, default)]
+    result: Option<&'a serde_json::value::RawValue>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    detail: Option<String>,
+}
 
-        let text = response.text().await.context(
-            "Failed to read response from Anki-Connect",
-        )?;
+/// A `result` that may be an error in disguise; see
+/// [`fallback_error`]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResultError {
+    #[serde(rename = "error")]
+    _error: String,
+    #[serde(rename = "detail", default)]
+    _detail: serde::de::IgnoredAny,
+}
 
-        let anki_response: AnkiResponse<R> =
-            serde_json::from_str(&text).context(
-                "Failed to parse Anki-Connect response",
-            )?;
+/// Parses the usual `{"result": ..., "error": ...}` envelope
+/// straight from the body, without an intermediate
+/// [`serde_json::Value`], so a large result is held in memory only
+/// as the body and the parsed `R`.
+///
+/// # Returns
+/// `None` for anything else, which [`parse_response`] then handles
+/// the slow way.
+fn parse_usual_response<R>(body: &[u8]) -> Option<Result<R>>
+where
+    R: for<'de> Deserialize<'de>,
+{
+    let response: RawResponse =
+        serde_json::from_slice(body).ok()?;
+    if let Some(error) = response.error {
+        return Some(Err(AnkiError::from_message(
+            error,
+            response.detail,
+        )
+        .into()));
+    }
+    let result =
+        response.result.map_or("null", |r| r.get());
+    if result.starts_with('{')
+        && serde_json::from_str::<ResultError>(result)
+            .is_ok()
+    {
+        return None;
+    }
+    serde_json::from_str(result).ok().map(Ok)
+}
 
-        match anki_response {
-            AnkiResponse::Success { result } => Ok(result),
-            AnkiResponse::Error { error, detail } => {
-                let error_msg = if let Some(detail) = detail
-                {
-                    format!("{}: {}", error, detail)
-                } else {
-                    error
-                };
-                Err(anyhow::anyhow!(
-                    "Anki-Connect error: {}",
-                    error_msg
-                ))
-            }
+/// Parses the response of a server older than API version 6.
+///
+/// Such servers may send the bare result without the envelope, e.g.
+/// `["Default"]` for `deckNames`; anything that looks like an
+/// envelope goes through [`parse_response`].
+fn parse_legacy_response<R>(body: &[u8]) -> Result<R>
+where
+    R: for<'de> Deserialize<'de>,
+{
+    let value: serde_json::Value =
+        serde_json::from_slice(body)
+            .map_err(parse_failed)?;
+    let is_envelope = |v: &serde_json::Value| {
+        v.as_object().is_some_and(|object| {
+            object.contains_key("result")
+                || object.contains_key("error")
+        })
+    };
+    let enveloped = match &value {
+        serde_json::Value::Array(items) => {
+            items.len() == 1 && is_envelope(&items[0])
         }
+        value => is_envelope(value),
+    };
+    if enveloped {
+        return parse_response(body);
     }
+    serde_json::from_value(value).map_err(|e| {
+        AnkiError::invalid_response(
+            "Failed to parse Anki-Connect response: unexpected bare result",
+            e,
+        )
+        .into()
+    })
+}
 
-    /// Gets the Anki-Connect API version
-    pub async fn version(&self) -> Result<u32> {
-        self.invoke::<(), u32>("version", None).await
+/// Error for a response body that is not JSON
+fn parse_failed(source: serde_json::Error) -> AnkiError {
+    AnkiError::invalid_response(
+        "Failed to parse Anki-Connect response",
+        source,
+    )
+}
+
+/// Errors in the alternate response shapes, which the
+/// [`AnkiResponse`] enum cannot represent
+fn fallback_error(
+    value: &serde_json::Value,
+) -> Option<AnkiError> {
+    let detail = |v: &serde_json::Value| {
+        v.get("detail")
+            .and_then(|d| d.as_str())
+            .map(str::to_string)
+    };
+
+    // {"result": null, "error": ["message", ...]}
+    if let Some(messages) =
+        value.get("error").and_then(|e| e.as_array())
+    {
+        let messages: Vec<String> = messages
+            .iter()
+            .map(|m| match m.as_str() {
+                Some(text) => text.to_string(),
+                None => m.to_string(),
+            })
+            .collect();
+        if !messages.is_empty() {
+            return Some(AnkiError::from_message(
+                messages.join("; "),
+                detail(value),
+            ));
+        }
     }
 
-    /// Gets the names of all decks in the collection
-    pub async fn get_deck_names(
-        &self,
-        cards: Option<bool>,
-    ) -> Result<Vec<String>> {
-        let params = if cards.is_some() {
-            Some(GetDeckNamesParams { cards })
-        } else {
-            None
-        };
-        self.invoke("getDeckNames", params).await
+    // {"result": {"error": "message"}, "error": null}
+    let result = value.get("result")?.as_object()?;
+    let only_error_keys = result
+        .keys()
+        .all(|key| key == "error" || key == "detail");
+    let error = result.get("error")?.as_str()?;
+    if only_error_keys
+        && value.get("error").is_none_or(|e| e.is_null())
+    {
+        return Some(AnkiError::from_message(
+            error.to_string(),
+            detail(value.get("result")?),
+        ));
     }
+    None
+}
 
-    /// Gets the names of all models in the collection
-    pub async fn get_model_names(
+/// Quoted `key:value` search term with wildcards escaped
+fn search_term(key: &str, value: &str) -> String {
+    format!("\"{}:{}\"", key, escape_search(value))
+}
+
+/// Whether an `invoke` error is Anki-Connect's duplicate-note error
+fn is_duplicate_error(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<AnkiError>()
+        .is_some_and(AnkiError::is_duplicate)
+}
+
+/// Whether an `invoke` error is Anki-Connect refusing access
+fn is_access_error(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<AnkiError>()
+        .is_some_and(AnkiError::is_access_denied)
+}
+
+/// Whether an `invoke` error means Anki is busy (e.g. syncing)
+/// rather than refusing the request
+fn is_still_busy(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) =
+            cause.downcast_ref::<reqwest::Error>()
+        {
+            return e.is_connect()
+                || e.is_timeout()
+                || e.is_request();
+        }
+        cause
+            .downcast_ref::<AnkiError>()
+            .is_some_and(AnkiError::is_busy)
+    })
+}
+
+/// Whether an `invoke` error means the server hung up after the
+/// request had been delivered: the transport reported
+/// [`ConnectionFailure::Disconnected`]. Failing to connect,
+/// timeouts, invalid requests and any response that arrived, even
+/// an empty one, do not count.
+fn is_disconnect_after_send(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<AnkiError>()
+            .and_then(AnkiError::connection_failure)
+            == Some(ConnectionFailure::Disconnected)
+    })
+}
+
+/// Answer to `requestPermission`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PermissionStatus {
+    /// `"granted"` or `"denied"`
+    pub permission: String,
+    /// Whether requests must carry Anki-Connect's API key
+    #[serde(
+        rename = "requireApikey",
+        alias = "requireApiKey",
+        default
+    )]
+    pub require_api_key: bool,
+    /// API version, only sent when permission is granted
+    #[serde(default)]
+    pub version: Option<u32>,
+}
+
+impl PermissionStatus {
+    /// Whether Anki allowed the client
+    pub fn is_granted(&self) -> bool {
+        self.permission == "granted"
+    }
+}
+
+/// Parameters for mapping cards to their decks
+#[derive(Debug, Clone, Serialize)]
+pub struct GetDecksParams {
+    /// List of card IDs
+    pub cards: Vec<u64>,
+}
+
+/// Parameters for answering the card shown in the reviewer
+#[derive(Debug, Clone, Serialize)]
+pub struct GuiAnswerCardParams {
+    /// 1 (Again) to 4 (Easy)
+    pub ease: u8,
+}
+
+/// One answer for `answerCards`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CardAnswer {
+    /// Card to answer
+    #[serde(rename = "cardId")]
+    pub card_id: u64,
+    /// 1 (Again) to 4 (Easy)
+    pub ease: u8,
+}
+
+/// Parameters for answering cards outside the reviewer
+#[derive(Debug, Clone, Serialize)]
+pub struct AnswerCardsParams {
+    /// Answers in the order they are applied
+    pub answers: Vec<CardAnswer>,
+}
+
+/// How long [`AnkiClient::sync_and_wait`] waits for a sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncWaitOptions {
+    /// Give up after this long
+    pub timeout: Duration,
+    /// Pause between probes, also the time limit of each probe
+    pub poll_interval: Duration,
+}
+
+impl Default for SyncWaitOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// How a sync started by [`AnkiClient::sync_and_wait`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// Anki answers collection requests again
+    Completed,
+    /// Anki is responsive but its collection is not, which is what a
+    /// conflict dialog waiting for the user looks like; a human
+    /// should have a look
+    MaybeConflict,
+    /// Anki was still busy when the timeout ran out
+    TimedOut,
+}
+
+/// Consecutive polls with Anki responsive but its collection blocked
+/// before [`SyncOutcome::MaybeConflict`] is reported
+const CONFLICT_CONFIRMATIONS: u32 = 2;
+
+/// Pause between reviewer steps, letting Anki's GUI catch up
+const GUI_STEP_DELAY: Duration = Duration::from_millis(50);
+
+/// Room for the JSON envelope around a base64 media file
+const MEDIA_RESPONSE_OVERHEAD: usize = 256;
+
+/// Parameters for listing media files
+#[derive(Debug, Clone, Serialize)]
+pub struct GetMediaFilesNamesParams {
+    /// Glob pattern the file names must match
+    pub pattern: String,
+}
+
+/// Parameters naming one media file
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaFileParams {
+    /// File name in the media folder
+    pub filename: String,
+}
+
+/// Builder for [`AnkiClient`]
+#[derive(Debug, Clone, Default)]
+pub struct AnkiClientBuilder {
+    url: Option<String>,
+    key: Option<Secret<String>>,
+    timeout: Option<Duration>,
+    client: Option<Client>,
+    normalize_names: bool,
+    trim_fields: bool,
+    max_concurrent_requests: Option<usize>,
+    auto_backup: Option<PathBuf>,
+    info_chunk: Option<usize>,
+    tag_batch: Option<usize>,
+    max_response_size: Option<usize>,
+    negotiate_permission: bool,
+    events: Option<EventHook>,
+    basic_auth: Option<(String, Secret<String>)>,
+    headers: Vec<(String, Secret<String>)>,
+    root_certificates: Vec<Vec<u8>>,
+    danger_accept_invalid_certs: bool,
+}
+
+impl AnkiClientBuilder {
+    /// Sets the endpoint URL (default `http://localhost:8765`)
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// Sets the Anki-Connect API key
+    pub fn key(
+        mut self,
+        key: Option<Secret<String>>,
+    ) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sets the per-request timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Uses a preconfigured HTTP client
+    pub fn http_client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// See [`AnkiClient::with_name_normalization`]
+    pub fn name_normalization(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.normalize_names = enabled;
+        self
+    }
+
+    /// See [`AnkiClient::with_field_trimming`]
+    pub fn field_trimming(mut self, enabled: bool) -> Self {
+        self.trim_fields = enabled;
+        self
+    }
+
+    /// Caps simultaneous in-flight requests (default
+    /// [`DEFAULT_MAX_CONCURRENT_REQUESTS`])
+    pub fn max_concurrent_requests(
+        mut self,
+        max: usize,
+    ) -> Self {
+        self.max_concurrent_requests = Some(max);
+        self
+    }
+
+    /// See [`AnkiClient::with_auto_backup`]
+    pub fn auto_backup(
+        mut self,
+        dir: Option<PathBuf>,
+    ) -> Self {
+        self.auto_backup = dir;
+        self
+    }
+
+    /// See [`AnkiClient::with_info_chunk`]
+    pub fn info_chunk(mut self, size: usize) -> Self {
+        self.info_chunk = Some(size);
+        self
+    }
+
+    /// See [`AnkiClient::with_tag_batch`]
+    pub fn tag_batch(mut self, size: usize) -> Self {
+        self.tag_batch = Some(size);
+        self
+    }
+
+    /// See [`AnkiClient::with_max_response_size`]
+    pub fn max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = Some(max);
+        self
+    }
+
+    /// See [`AnkiClient::with_permission_negotiation`]
+    pub fn permission_negotiation(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.negotiate_permission = enabled;
+        self
+    }
+
+    /// See [`AnkiClient::with_event_callback`]
+    pub fn on_event(
+        mut self,
+        callback: EventCallback,
+    ) -> Self {
+        self.events = Some(EventHook(callback));
+        self
+    }
+
+    /// Sends HTTP basic auth credentials with every request, e.g. for
+    /// a reverse proxy in front of Anki-Connect.
+    ///
+    /// This is independent of [`AnkiClientBuilder::key`], which
+    /// Anki-Connect reads from the request body.
+    pub fn basic_auth(
+        mut self,
+        username: impl Into<String>,
+        password: Secret<String>,
+    ) -> Self {
+        self.basic_auth = Some((username.into(), password));
+        self
+    }
+
+    /// Sends an extra header with every request, e.g. a proxy
+    /// access token; may be called repeatedly.
+    ///
+    /// Values are treated as secrets and never printed.
+    pub fn header(
+        mut self,
+        name: impl Into<String>,
+        value: Secret<String>,
+    ) -> Self {
+        self.headers.push((name.into(), value));
+        self
+    }
+
+    /// Trusts the PEM-encoded root certificates (one or a bundle) in
+    /// addition to the system roots, e.g. a self-signed certificate
+    /// of a proxy; may be called repeatedly
+    pub fn root_certificate_pem(
+        mut self,
+        pem: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Accepts any TLS certificate, including expired ones and
+    /// ones for other hosts.
+    ///
+    /// This makes HTTPS no safer than plain HTTP against anyone on
+    /// the network path; prefer
+    /// [`AnkiClientBuilder::root_certificate_pem`].
+    pub fn danger_accept_invalid_certs(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.danger_accept_invalid_certs = enabled;
+        self
+    }
+
+    /// Whether an option applying to the HTTP client is set, which a
+    /// custom client cannot take
+    fn has_transport_options(&self) -> bool {
+        self.basic_auth.is_some()
+            || !self.headers.is_empty()
+            || !self.root_certificates.is_empty()
+            || self.danger_accept_invalid_certs
+    }
+
+    /// Headers sent with every request: the extra headers and the
+    /// basic auth credentials
+    fn default_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name =
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| {
+                        format!(
+                            "Invalid header name '{}'",
+                            name
+                        )
+                    })?;
+            let mut value =
+                HeaderValue::from_str(value.expose())
+                    .with_context(|| {
+                        format!(
+                            "Invalid value for header {}",
+                            name
+                        )
+                    })?;
+            value.set_sensitive(true);
+            headers.append(name, value);
+        }
+        if let Some((username, password)) = &self.basic_auth
+        {
+            use base64::Engine;
+
+            anyhow::ensure!(
+                !username.contains(':'),
+                "Basic auth username must not contain ':'"
+            );
+            anyhow::ensure!(
+                !headers.contains_key(AUTHORIZATION),
+                "Basic auth cannot be combined with an extra Authorization header"
+            );
+            let credentials =
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!(
+                        "{}:{}",
+                        username,
+                        password.expose()
+                    ));
+            let mut value = HeaderValue::from_str(
+                &format!("Basic {}", credentials),
+            )
+            .context("Invalid basic auth credentials")?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        Ok(headers)
+    }
+
+    /// Validates the configuration and builds the client
+    pub fn build(self) -> Result<AnkiClient> {
+        let url = self.url.clone().unwrap_or_else(|| {
+            DEFAULT_ANKI_CONNECT_URL.to_string()
+        });
+        validate_url(&url)?;
+
+        let client = match (&self.client, self.timeout) {
+            (Some(_), Some(_)) => anyhow::bail!(
+                "A timeout cannot be applied to a custom HTTP client; configure it on the client instead"
+            ),
+            (Some(_), None)
+                if self.has_transport_options() =>
+            {
+                anyhow::bail!(
+                    "Basic auth, extra headers and TLS options cannot be applied to a custom HTTP client; configure it on the client instead"
+                )
+            }
+            (Some(client), None) => client.clone(),
+            (None, timeout) => {
+                let mut builder = Client::builder()
+                    .default_headers(
+                        self.default_headers()?,
+                    );
+                if let Some(timeout) = timeout {
+                    builder = builder.timeout(timeout);
+                }
+                if !self.root_certificates.is_empty() {
+                    let mut certificates = Vec::new();
+                    for pem in &self.root_certificates {
+                        let bundle =
+                            Certificate::from_pem_bundle(pem)
+                                .context(
+                                    "Invalid PEM root certificate",
+                                )?;
+                        anyhow::ensure!(
+                            !bundle.is_empty(),
+                            "Invalid PEM root certificate: no certificate found"
+                        );
+                        certificates.extend(bundle);
+                    }
+                    builder = builder
+                        .tls_certs_merge(certificates);
+                }
+                if self.danger_accept_invalid_certs {
+                    builder = builder
+                        .tls_danger_accept_invalid_certs(
+                            true,
+                        );
+                }
+                builder.build().context(
+                    "Failed to build HTTP client",
+                )?
+            }
+        };
+
+        let mut anki_client =
+            AnkiClient::from_parts(client, url);
+        anki_client.key = self.key;
+        anki_client.normalize_names = self.normalize_names;
+        anki_client.trim_fields = self.trim_fields;
+        anki_client.auto_backup = self.auto_backup;
+        anki_client.negotiate_permission =
+            self.negotiate_permission;
+        anki_client.events = self.events;
+        if let Some(size) = self.info_chunk {
+            anyhow::ensure!(
+                size > 0,
+                "info_chunk must be at least 1"
+            );
+            anki_client.info_chunk = size;
+        }
+        if let Some(size) = self.tag_batch {
+            anyhow::ensure!(
+                size > 0,
+                "tag_batch must be at least 1"
+            );
+            anki_client.tag_batch = size;
+        }
+        if let Some(max) = self.max_response_size {
+            anyhow::ensure!(
+                max > 0,
+                "max_response_size must be at least 1"
+            );
+            anki_client.max_response_size = Some(max);
+        }
+        if let Some(max) = self.max_concurrent_requests {
+            anyhow::ensure!(
+                max > 0,
+                "max_concurrent_requests must be at least 1"
+            );
+            anki_client.limiter =
+                Arc::new(Semaphore::new(max));
+        }
+        Ok(anki_client)
+    }
+}
+
+/// Groups cards by deck name, keeping their order within each deck
+pub fn group_by_deck(
+    cards: impl IntoIterator<Item = CardInfo>,
+) -> std::collections::BTreeMap<String, Vec<CardInfo>> {
+    let mut decks = std::collections::BTreeMap::<
+        String,
+        Vec<CardInfo>,
+    >::new();
+    for card in cards {
+        decks
+            .entry(card.deck_name.clone())
+            .or_default()
+            .push(card);
+    }
+    decks
+}
+
+/// A deck and its subdecks
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Serialize,
+)]
+pub struct DeckNode {
+    /// Last component of the name, e.g. `Verbs`; empty for the root
+    pub name: String,
+    /// Full `::`-separated name, e.g. `Lang::Verbs`; empty for the
+    /// root
+    pub full_name: String,
+    /// Subdecks, in the order their names were given
+    pub children: Vec<DeckNode>,
+}
+
+impl DeckNode {
+    /// Finds a node by its full name
+    pub fn find(
         &self,
-    ) -> Result<Vec<String>> {
-        self.invoke(
-            "getModelNames",
-            None::<GetModelNamesParams>,
+        full_name: &str,
+    ) -> Option<&DeckNode> {
+        if self.full_name == full_name {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find(full_name))
+    }
+}
+
+/// Builds the deck hierarchy from flat `::`-separated names.
+///
+/// The returned root is unnamed and holds the top-level decks.
+/// Parents missing from `names` are created, so `A::B` alone still
+/// gives `A` with child `B`.
+pub fn build_deck_tree<S: AsRef<str>>(
+    names: impl IntoIterator<Item = S>,
+) -> DeckNode {
+    let mut root = DeckNode::default();
+    for name in names {
+        let mut node = &mut root;
+        for part in name.as_ref().split("::") {
+            let index = match node
+                .children
+                .iter()
+                .position(|child| child.name == part)
+            {
+                Some(index) => index,
+                None => {
+                    let full_name =
+                        if node.full_name.is_empty() {
+                            part.to_string()
+                        } else {
+                            format!(
+                                "{}::{}",
+                                node.full_name, part
+                            )
+                        };
+                    node.children.push(DeckNode {
+                        name: part.to_string(),
+                        full_name,
+                        children: Vec::new(),
+                    });
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[index];
+        }
+    }
+    root
+}
+
+/// Usual auto-backup location: `backups` in the application's
+/// data directory
+pub fn default_backup_dir() -> Option<PathBuf> {
+    utils::paths::AppDir::Data
+        .path()
+        .map(|dir| dir.join("backups"))
+}
+
+/// File-name-safe form of a deck name (`A::B` becomes `A__B`)
+fn backup_file_stem(deck: &str) -> String {
+    deck.replace("::", "__")
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '_' | '-')
+            {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Reads a version reported as a number or a numeric string
+fn parse_version(
+    version: &serde_json::Value,
+) -> Result<u32> {
+    let parsed = match version {
+        serde_json::Value::Number(number) => number
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok()),
+        serde_json::Value::String(text) => {
+            text.trim().parse().ok()
+        }
+        _ => None,
+    };
+    parsed.with_context(|| {
+        format!(
+            "Invalid Anki-Connect version {}: expected a non-negative integer",
+            version
+        )
+    })
+}
+
+/// Anki separates tags with spaces, so a tag cannot contain any
+fn validate_tags(tags: &[String]) -> Result<()> {
+    if let Some(tag) = tags.iter().find(|tag| {
+        tag.is_empty() || tag.contains(char::is_whitespace)
+    }) {
+        anyhow::bail!(
+            "Invalid tag '{}': tags must be non-empty and contain no whitespace",
+            tag
+        );
+    }
+    Ok(())
+}
+
+/// Checks that `url` is an absolute http(s) URL with a host
+fn validate_url(url: &str) -> Result<()> {
+    let hint = "expected e.g. http://localhost:8765";
+    let parsed =
+        reqwest::Url::parse(url).with_context(|| {
+            format!(
+                "Invalid Anki-Connect URL '{}' ({})",
+                url, hint
+            )
+        })?;
+    anyhow::ensure!(
+        matches!(parsed.scheme(), "http" | "https"),
+        "Invalid Anki-Connect URL '{}': scheme must be http or https ({})",
+        url,
+        hint
+    );
+    anyhow::ensure!(
+        parsed.host_str().is_some_and(|h| !h.is_empty()),
+        "Invalid Anki-Connect URL '{}': missing host ({})",
+        url,
+        hint
+    );
+    Ok(())
+}
+
+/// Anki-Connect client for interacting with Anki.
+///
+/// Methods taking a list of IDs, notes or decks return an empty
+/// result for an empty list without contacting Anki.
+#[derive(Debug, Clone)]
+pub struct AnkiClient {
+    /// Carries the serialized requests; shared by clones
+    transport: Arc<dyn Transport>,
+    /// Anki-Connect endpoint URL, empty for a custom transport
+    url: String,
+    /// API version
+    version: u8,
+    /// Anki-Connect API key sent with every request
+    key: Option<Secret<String>>,
+    /// Whether deck/model names are normalized before comparison
+    normalize_names: bool,
+    /// Whether field values are trimmed before notes are added
+    trim_fields: bool,
+    /// Caps in-flight requests; shared by clones of the client
+    limiter: Arc<Semaphore>,
+    /// Reloadable settings the API key is read from on every request
+    settings: Option<SettingsHandle>,
+    /// Directory receiving deck backups before destructive calls
+    auto_backup: Option<PathBuf>,
+    /// IDs per `notesInfo`/`cardsInfo` request of the detailed
+    /// searches
+    info_chunk: usize,
+    /// Note IDs per `addTags`/`removeTags` request of `tag_query`
+    tag_batch: usize,
+    /// Longest response body accepted, in bytes
+    max_response_size: Option<usize>,
+    /// Whether access errors trigger `requestPermission`
+    negotiate_permission: bool,
+    /// Outcome of the single permission request, shared by clones;
+    /// `None` inside if the request itself failed
+    permission: Arc<OnceCell<Option<PermissionStatus>>>,
+    /// Version detected by `ensure_compatible`, shared by clones;
+    /// 0 until then
+    server_version: Arc<AtomicU32>,
+    /// Observer of requests, retries and chunk progress
+    events: Option<EventHook>,
+}
+
+impl Default for AnkiClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnkiClient {
+    /// Creates a new AnkiClient with default settings
+    pub fn new() -> Self {
+        Self::from_parts(
+            Client::new(),
+            DEFAULT_ANKI_CONNECT_URL.to_string(),
+        )
+    }
+
+    /// Creates a new AnkiClient with a custom endpoint URL
+    pub fn with_url(url: impl Into<String>) -> Self {
+        Self::from_parts(Client::new(), url.into())
+    }
+
+    /// Creates a new AnkiClient with custom HTTP client
+    pub fn with_client(client: Client) -> Self {
+        Self::from_parts(
+            client,
+            DEFAULT_ANKI_CONNECT_URL.to_string(),
+        )
+    }
+
+    /// Sends every request through `transport`.
+    ///
+    /// [`AnkiClient::url`] is empty for such a client.
+    pub fn with_transport(
+        transport: Box<dyn Transport>,
+    ) -> Self {
+        Self::from_transport(
+            transport.into(),
+            String::new(),
+        )
+    }
+
+    fn from_parts(client: Client, url: String) -> Self {
+        Self::from_transport(
+            Arc::new(HttpTransport::new(
+                client,
+                url.clone(),
+            )),
+            url,
+        )
+    }
+
+    fn from_transport(
+        transport: Arc<dyn Transport>,
+        url: String,
+    ) -> Self {
+        Self {
+            transport,
+            url,
+            version: CURRENT_ANKI_CONNECT_VERSION as u8,
+            key: None,
+            normalize_names: false,
+            trim_fields: false,
+            limiter: Arc::new(Semaphore::new(
+                DEFAULT_MAX_CONCURRENT_REQUESTS,
+            )),
+            settings: None,
+            auto_backup: None,
+            info_chunk: DEFAULT_INFO_CHUNK,
+            tag_batch: DEFAULT_TAG_BATCH,
+            max_response_size: None,
+            negotiate_permission: false,
+            permission: Arc::new(OnceCell::new()),
+            server_version: Arc::new(AtomicU32::new(0)),
+            events: None,
+        }
+    }
+
+    /// Starts a builder for a validated, fully configured client
+    pub fn builder() -> AnkiClientBuilder {
+        AnkiClientBuilder::default()
+    }
+
+    /// Creates a client from the Anki-Connect URL, key and timeout
+    /// of the application settings
+    pub fn from_settings(
+        settings: &Settings,
+    ) -> Result<Self> {
+        Self::builder()
+            .url(&settings.anki_connect_url)
+            .key(settings.anki_connect_key.clone())
+            .timeout(Duration::from_secs(
+                settings.anki_request_timeout_secs,
+            ))
+            .build()
+    }
+
+    /// Creates a client from reloadable settings.
+    ///
+    /// The API key is read from `settings` on every request, so a
+    /// rotated key takes effect after the next reload. The URL and
+    /// timeout are captured here; changing them needs a new client.
+    pub fn from_handle(
+        settings: &SettingsHandle,
+    ) -> Result<Self> {
+        let mut client =
+            Self::from_settings(&settings.get())?;
+        client.settings = Some(settings.clone());
+        Ok(client)
+    }
+
+    /// The Anki-Connect endpoint URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Enables NFC + trim normalization when the client compares
+    /// deck or model names internally (e.g. in `deck_exists`)
+    pub fn with_name_normalization(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.normalize_names = enabled;
+        self
+    }
+
+    /// Exports every affected deck to `dir` before `delete_notes`,
+    /// `delete_decks` and `forget_cards`.
+    ///
+    /// Backups are named `<deck>-<UTC timestamp>.apkg` and include
+    /// scheduling. `dir` is written by the Anki process, so it must
+    /// be reachable from the machine running Anki. The destructive
+    /// call is not made if a backup fails. See
+    /// [`default_backup_dir`] for the usual location.
+    pub fn with_auto_backup(
+        mut self,
+        dir: Option<PathBuf>,
+    ) -> Self {
+        self.auto_backup = dir;
+        self
+    }
+
+    /// Sets how many IDs one `notesInfo`/`cardsInfo` request of
+    /// the detailed searches carries (default
+    /// [`DEFAULT_INFO_CHUNK`]; 0 counts as 1)
+    pub fn with_info_chunk(mut self, size: usize) -> Self {
+        self.info_chunk = size.max(1);
+        self
+    }
+
+    /// Sets how many note IDs one `addTags`/`removeTags` request
+    /// of [`AnkiClient::tag_query`] carries (default
+    /// [`DEFAULT_TAG_BATCH`]; 0 counts as 1)
+    pub fn with_tag_batch(mut self, size: usize) -> Self {
+        self.tag_batch = size.max(1);
+        self
+    }
+
+    /// Fails any request whose response body is longer than
+    /// `max` bytes (default: no limit).
+    ///
+    /// Over HTTP the body stops being read as soon as the limit is
+    /// passed, so a runaway `notesInfo` cannot exhaust memory; the
+    /// error names the action and carries a [`ResponseTooLarge`].
+    pub fn with_max_response_size(
+        mut self,
+        max: Option<usize>,
+    ) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
+    /// Asks Anki for permission when a request is refused for
+    /// lack of it.
+    ///
+    /// On an access error (see [`AnkiError::is_access_denied`]) the
+    /// client calls `requestPermission`, which shows a popup in
+    /// Anki, and retries the request once if the user allows it.
+    /// The popup is requested at most once per client, clones
+    /// included; later access errors reuse the first answer. If
+    /// Anki-Connect requires an API key, the error names the
+    /// builder setting to add instead.
+    pub fn with_permission_negotiation(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.negotiate_permission = enabled;
+        self
+    }
+
+    /// Reports requests, retries and the progress of the batched
+    /// helpers to `callback` (default: none).
+    ///
+    /// See [`crate::anki::events`]; clones of the client report to
+    /// the same callback.
+    pub fn with_event_callback(
+        mut self,
+        callback: Option<EventCallback>,
+    ) -> Self {
+        self.events = callback.map(EventHook);
+        self
+    }
+
+    /// Passes the event built by `event` to the callback, if any;
+    /// the event is not built otherwise
+    fn emit(&self, event: impl FnOnce() -> ClientEvent) {
+        if let Some(EventHook(callback)) = &self.events {
+            callback(event());
+        }
+    }
+
+    /// Trims the edges of every field value before notes are sent
+    /// by `add_note` and `add_notes`
+    pub fn with_field_trimming(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.trim_fields = enabled;
+        self
+    }
+
+    /// Compares two deck/model names, honoring the normalization flag
+    fn names_match(&self, left: &str, right: &str) -> bool {
+        if self.normalize_names {
+            normalize_name(left) == normalize_name(right)
+        } else {
+            left == right
+        }
+    }
+
+    /// Invokes an Anki-Connect action with the given parameters
+    async fn invoke<T, R>(
+        &self,
+        action: &str,
+        params: Option<T>,
+    ) -> Result<R>
+    where
+        T: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let started = std::time::Instant::now();
+        self.emit(|| ClientEvent::RequestStarted {
+            action: action.to_string(),
+        });
+        let result =
+            self.invoke_unrecorded(action, params).await;
+        let outcome = RequestOutcome::of(&result);
+        #[cfg(any(test, feature = "metrics"))]
+        super::telemetry::record_request(
+            action,
+            outcome,
+            started.elapsed(),
+        );
+        self.emit(|| ClientEvent::RequestFinished {
+            action: action.to_string(),
+            duration: started.elapsed(),
+            outcome,
+        });
+        result
+    }
+
+    /// [`AnkiClient::invoke`] without metrics or events
+    async fn invoke_unrecorded<T, R>(
+        &self,
+        action: &str,
+        params: Option<T>,
+    ) -> Result<R>
+    where
+        T: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let server_version = self.server_version();
+        if server_version.is_some_and(|v| v < 6)
+            && ACTIONS_MISSING_IN_V5.contains(&action)
+        {
+            return Err(AnkiError::UnsupportedByServer {
+                action: action.to_string(),
+                server_version,
+            }
+            .into());
+        }
+        let mut request = AnkiRequest::new(
+            action,
+            self.request_version(),
+            params,
+        );
+        request.key = self.current_key();
+        let result = match self.send(&request).await {
+            Err(e)
+                if self.negotiate_permission
+                    && is_access_error(&e) =>
+            {
+                self.negotiate_permission(e).await?;
+                self.emit(|| ClientEvent::RetryScheduled {
+                    action: action.to_string(),
+                    attempt: 1,
+                    delay: Duration::ZERO,
+                    reason: "permission granted"
+                        .to_string(),
+                });
+                self.send(&request).await
+            }
+            result => result,
+        };
+        result.map_err(|e| {
+            if e.downcast_ref::<AnkiError>().is_some_and(
+                AnkiError::is_unsupported_action,
+            ) {
+                AnkiError::UnsupportedByServer {
+                    action: action.to_string(),
+                    server_version,
+                }
+                .into()
+            } else {
+                e
+            }
+        })
+    }
+
+    /// The `version` sent in requests: the detected version for a
+    /// server older than 6, otherwise 6
+    fn request_version(&self) -> u8 {
+        match self.server_version() {
+            Some(version) if version < 6 => version as u8,
+            _ => self.version,
+        }
+    }
+
+    /// The API key sent with the next request
+    fn current_key(&self) -> Option<String> {
+        match &self.settings {
+            Some(settings) => settings
+                .get()
+                .anki_connect_key
+                .as_ref()
+                .map(|key| key.expose().clone()),
+            None => self
+                .key
+                .as_ref()
+                .map(|key| key.expose().clone()),
+        }
+    }
+
+    /// Sends one request and parses its response
+    async fn send<T, R>(
+        &self,
+        request: &AnkiRequest<T>,
+    ) -> Result<R>
+    where
+        T: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let _permit =
+            self.limiter.acquire().await.context(
+                "Anki-Connect client was shut down",
+            )?;
+        let body = serde_json::to_vec(request).context(
+            "Failed to serialize Anki-Connect request",
+        )?;
+        let response = match self.max_response_size {
+            Some(limit) => self
+                .transport
+                .call_limited(body.into(), limit)
+                .await
+                .map_err(|e| {
+                    match e.downcast_ref::<ResponseTooLarge>() {
+                        Some(too_large) => {
+                            let message = format!(
+                                "Anki-Connect response to {} is at least {} bytes, over the limit of {}; request fewer items at once or raise max_response_size",
+                                request.action,
+                                too_large.size,
+                                too_large.limit
+                            );
+                            e.context(message)
+                        }
+                        None => e,
+                    }
+                })?,
+            None => self.transport.call(body.into()).await?,
+        };
+
+        // `version` is how an old server is detected, so its answer
+        // must parse before the server version is known
+        if request.action == "version"
+            || self.server_version().is_some_and(|v| v < 6)
+        {
+            parse_legacy_response(&response)
+        } else {
+            parse_response(&response)
+        }
+    }
+
+    /// Resolves an access error through `requestPermission`.
+    ///
+    /// # Returns
+    /// `Ok` if the request should be retried, otherwise `error`
+    /// with an explanation of what to change.
+    async fn negotiate_permission(
+        &self,
+        error: anyhow::Error,
+    ) -> Result<()> {
+        let status = self
+            .permission
+            .get_or_init(|| async {
+                self.request_permission().await.ok()
+            })
+            .await;
+        match status {
+            Some(status) if !status.is_granted() => {
+                Err(error.context(
+                    "Anki denied this client permission; allow it in the popup Anki shows, or add its origin to webCorsOriginList in Anki-Connect's config",
+                ))
+            }
+            Some(status)
+                if status.require_api_key
+                    && self.current_key().is_none() =>
+            {
+                Err(error.context(
+                    "Anki-Connect requires an API key: pass the apiKey value of Anki-Connect's config to AnkiClient::builder().key(...), or set anki_connect_key in the settings",
+                ))
+            }
+            Some(status) if status.require_api_key => {
+                Err(error.context(
+                    "Anki-Connect rejected the configured API key: it must equal the apiKey value of Anki-Connect's config",
+                ))
+            }
+            Some(_) => Ok(()),
+            None => Err(error.context(
+                "Failed to request permission from Anki-Connect",
+            )),
+        }
+    }
+
+    /// Asks Anki to allow this client; Anki shows a popup unless
+    /// the client is already trusted
+    pub async fn request_permission(
+        &self,
+    ) -> Result<PermissionStatus> {
+        let mut request = AnkiRequest::<()>::new(
+            "requestPermission",
+            self.request_version(),
+            None,
+        );
+        request.key = self.current_key();
+        self.send(&request).await
+    }
+
+    /// Calls any Anki-Connect action and returns its raw result.
+    ///
+    /// This is the low-level escape hatch for actions the client does
+    /// not wrap yet; prefer the typed methods where they exist. The
+    /// request goes through the same path as every other call (API
+    /// key, permission negotiation, error parsing), but nothing about
+    /// `params` or the result is checked. `Value::Null` sends no
+    /// `params` at all.
+    pub async fn invoke_raw(
+        &self,
+        action: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let params = (!params.is_null()).then_some(params);
+        self.invoke(action, params).await
+    }
+
+    /// Gets the Anki-Connect API version.
+    ///
+    /// Some releases report the version as a string, so `6` and
+    /// `"6"` are both accepted; anything else is an error naming the
+    /// value received.
+    pub async fn version(&self) -> Result<u32> {
+        let version: serde_json::Value =
+            self.invoke::<(), _>("version", None).await?;
+        parse_version(&version)
+    }
+
+    /// Detects the Anki-Connect API version and adapts the client to
+    /// it.
+    ///
+    /// Against a version 5 server, requests carry `"version": 5`,
+    /// bare results without the `{"result", "error"}` envelope are
+    /// accepted, and actions that server lacks fail with
+    /// [`AnkiError::UnsupportedByServer`] without a request. The
+    /// detected version is shared by clones of the client.
+    ///
+    /// # Returns
+    /// The server version; an error if it is older than
+    /// [`MIN_ANKI_CONNECT_VERSION`]
+    pub async fn ensure_compatible(&self) -> Result<u32> {
+        let version = self.version().await?;
+        anyhow::ensure!(
+            version >= MIN_ANKI_CONNECT_VERSION,
+            "Anki-Connect API version {} is not supported: version {} or newer is required; update the Anki-Connect add-on",
+            version,
+            MIN_ANKI_CONNECT_VERSION
+        );
+        self.server_version
+            .store(version, Ordering::Relaxed);
+        Ok(version)
+    }
+
+    /// The version detected by [`AnkiClient::ensure_compatible`],
+    /// `None` before it succeeds
+    pub fn server_version(&self) -> Option<u32> {
+        match self.server_version.load(Ordering::Relaxed) {
+            0 => None,
+            version => Some(version),
+        }
+    }
+
+    /// Gets the names of all decks in the collection
+    pub async fn get_deck_names(
+        &self,
+        cards: Option<bool>,
+    ) -> Result<Vec<String>> {
+        let params = if cards.is_some() {
+            Some(GetDeckNamesParams { cards })
+        } else {
+            None
+        };
+        self.invoke("deckNames", params).await
+    }
+
+    /// Gets the names of all decks with their IDs
+    pub async fn deck_names_and_ids(
+        &self,
+    ) -> Result<std::collections::HashMap<String, u64>>
+    {
+        self.invoke::<(), _>("deckNamesAndIds", None).await
+    }
+
+    /// Gets all decks as a tree, see [`build_deck_tree`]
+    pub async fn deck_tree(&self) -> Result<DeckNode> {
+        Ok(build_deck_tree(
+            self.get_deck_names(None).await?,
+        ))
+    }
+
+    /// Creates a deck (and missing parents), returning its ID.
+    ///
+    /// Creating a deck that already exists returns the existing ID.
+    pub async fn create_deck(
+        &self,
+        deck: &str,
+    ) -> Result<u64> {
+        let params = CreateDeckParams {
+            deck: deck.to_string(),
+        };
+        self.invoke("createDeck", Some(params)).await
+    }
+
+    /// Moves cards to `deck`, creating it if needed
+    pub async fn change_deck(
+        &self,
+        card_ids: Vec<u64>,
+        deck: &str,
+    ) -> Result<()> {
+        if card_ids.is_empty() {
+            return Ok(());
+        }
+        let params = ChangeDeckParams {
+            cards: card_ids,
+            deck: deck.to_string(),
+        };
+        self.invoke::<_, Option<bool>>(
+            "changeDeck",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Gets the options group assigned to a deck
+    pub async fn get_deck_config(
+        &self,
+        deck: &str,
+    ) -> Result<DeckConfig> {
+        let params = GetDeckConfigParams {
+            deck: deck.to_string(),
+        };
+        self.invoke("getDeckConfig", Some(params)).await
+    }
+
+    /// Saves an options group; its `id` selects the group to update
+    pub async fn save_deck_config(
+        &self,
+        config: DeckConfig,
+    ) -> Result<()> {
+        let params = SaveDeckConfigParams { config };
+        let saved: bool = self
+            .invoke("saveDeckConfig", Some(params))
+            .await?;
+        anyhow::ensure!(
+            saved,
+            "Failed to save deck config"
+        );
+        Ok(())
+    }
+
+    /// Assigns an options group to the given decks
+    pub async fn set_deck_config_id(
+        &self,
+        decks: Vec<String>,
+        config_id: u64,
+    ) -> Result<()> {
+        if decks.is_empty() {
+            return Ok(());
+        }
+        let params =
+            SetDeckConfigIdParams { decks, config_id };
+        let assigned: bool = self
+            .invoke("setDeckConfigId", Some(params))
+            .await?;
+        anyhow::ensure!(
+            assigned,
+            "Failed to assign deck config {}",
+            config_id
+        );
+        Ok(())
+    }
+
+    /// Clones an options group under a new name, returning the new ID
+    pub async fn clone_deck_config_id(
+        &self,
+        name: &str,
+        clone_from: Option<u64>,
+    ) -> Result<u64> {
+        let params = CloneDeckConfigIdParams {
+            name: name.to_string(),
+            clone_from,
+        };
+        self.invoke("cloneDeckConfigId", Some(params)).await
+    }
+
+    /// Checks whether a deck with the given name exists
+    pub async fn deck_exists(
+        &self,
+        deck_name: &str,
+    ) -> Result<bool> {
+        let decks = self.get_deck_names(None).await?;
+        Ok(decks
+            .iter()
+            .any(|name| self.names_match(name, deck_name)))
+    }
+
+    /// Renames `old` and all its subdecks to `new`, e.g. `Japanese`
+    /// to `Languages::Japanese`.
+    ///
+    /// Anki-Connect cannot rename decks, so the subtree is rebuilt:
+    /// the new decks are created parents first and given the options
+    /// group of their old deck, each old deck's own cards are moved
+    /// with `changeDeck`, and the old decks are deleted once a search
+    /// confirms that no card is left in them.
+    ///
+    /// `old` is matched like Anki matches deck names, ignoring case,
+    /// and the subdecks keep their own names below `new`. Anki would
+    /// hand back the old deck when asked to create a name differing
+    /// only in case, so a case-only rename such as `french` to
+    /// `French` goes through a temporary `<new> (renaming)` deck and
+    /// always deletes the old decks.
+    ///
+    /// # Errors
+    /// Fails before changing anything if `old` does not exist, if
+    /// `new` lies inside `old`, or if one of the new names is already
+    /// taken (unless [`RenameOptions::resume`] is set). Failures after
+    /// the first change are not returned as errors but recorded in
+    /// [`RenameReport::failure`], so the report shows how far the
+    /// rename got.
+    pub async fn rename_deck(
+        &self,
+        old: &str,
+        new: &str,
+        options: RenameOptions,
+    ) -> Result<RenameReport> {
+        let same = |left: &str, right: &str| {
+            self.names_match(
+                &left.to_lowercase(),
+                &right.to_lowercase(),
+            )
+        };
+        let new = new.trim_end_matches("::");
+        anyhow::ensure!(
+            !new.is_empty(),
+            "The new deck name is empty"
+        );
+        let existing: Vec<String> = self
+            .deck_names_and_ids()
+            .await?
+            .into_keys()
+            .collect();
+        let depth = old.split("::").count();
+        let in_old = |name: &str| {
+            split_deck_root(name, depth)
+                .is_some_and(|(root, _)| same(root, old))
+        };
+        let mut subtree: Vec<&String> = existing
+            .iter()
+            .filter(|name| in_old(name))
+            .collect();
+        subtree.sort();
+        let Some((old_root, _)) = subtree
+            .first()
+            .and_then(|name| split_deck_root(name, depth))
+        else {
+            return Err(
+                AnkiError::deck_not_found(old).into()
+            );
+        };
+        let old_root = old_root.to_string();
+        anyhow::ensure!(
+            new != old_root
+                && (same(new, old) || !in_old(new)),
+            "Cannot rename {} to {}, which is inside it",
+            old,
+            new
+        );
+        if same(new, old) {
+            return self
+                .rename_case_only(&old_root, new, options)
+                .await;
+        }
+
+        let mut report = RenameReport {
+            renamed: subtree
+                .iter()
+                .filter_map(|name| {
+                    let (_, rest) =
+                        split_deck_root(name, depth)?;
+                    Some((
+                        name.to_string(),
+                        format!("{}{}", new, rest),
+                    ))
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let taken: Vec<String> = report
+            .renamed
+            .iter()
+            .map(|(_, target)| target.clone())
+            .filter(|target| {
+                existing.iter().any(|name| {
+                    !subtree.contains(&name)
+                        && same(name, target)
+                })
+            })
+            .collect();
+        anyhow::ensure!(
+            options.resume || taken.is_empty(),
+            "Cannot rename {} to {}: {} already exist(s)",
+            old,
+            new,
+            taken.join(", ")
+        );
+
+        let mut config_ids = Vec::new();
+        if options.copy_options {
+            for (old_deck, _) in &report.renamed {
+                config_ids.push(
+                    self.get_deck_config(old_deck)
+                        .await?
+                        .id,
+                );
+            }
+        }
+
+        if let Err(e) = self
+            .rebuild_subtree(
+                &mut report,
+                &config_ids,
+                &taken,
+            )
+            .await
+        {
+            report.failure = Some(format!("{:#}", e));
+            return Ok(report);
+        }
+        if options.delete_old {
+            let result = async {
+                let left = self
+                    .find_cards(&deck_query(&old_root))
+                    .await?;
+                anyhow::ensure!(
+                    left.is_empty(),
+                    "{} card(s) are still in {}; the old decks were kept",
+                    left.len(),
+                    old_root
+                );
+                self.delete_decks(
+                    subtree.iter().map(|d| d.to_string()).collect(),
+                )
+                .await
+            }
+            .await;
+            match result {
+                Ok(()) => report.deleted_old = true,
+                Err(e) => {
+                    report.failure =
+                        Some(format!("{:#}", e))
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Renames `old` to `new`, which differs only in case, through a
+    /// temporary deck. A failure in either step returns that step's
+    /// report.
+    async fn rename_case_only(
+        &self,
+        old: &str,
+        new: &str,
+        options: RenameOptions,
+    ) -> Result<RenameReport> {
+        let options = RenameOptions {
+            delete_old: true,
+            ..options
+        };
+        let temp = format!("{} (renaming)", new);
+        let first =
+            Box::pin(self.rename_deck(old, &temp, options))
+                .await?;
+        if !first.is_complete() {
+            return Ok(first);
+        }
+        let second = match Box::pin(
+            self.rename_deck(&temp, new, options),
+        )
+        .await
+        {
+            Ok(second) if second.is_complete() => second,
+            Ok(second) => return Ok(second),
+            Err(e) => {
+                return Ok(RenameReport {
+                    failure: Some(format!("{:#}", e)),
+                    ..first
+                });
+            }
+        };
+        Ok(RenameReport {
+            renamed: first
+                .renamed
+                .iter()
+                .zip(&second.renamed)
+                .map(|((from, _), (_, to))| {
+                    (from.clone(), to.clone())
+                })
+                .collect(),
+            moved: first.moved,
+            ..second
+        })
+    }
+
+    /// Creates, configures and fills the new decks of
+    /// [`AnkiClient::rename_deck`], recording each finished step
+    async fn rebuild_subtree(
+        &self,
+        report: &mut RenameReport,
+        config_ids: &[u64],
+        taken: &[String],
+    ) -> Result<()> {
+        let renamed = report.renamed.clone();
+        for (_, new_deck) in &renamed {
+            if !taken.contains(new_deck) {
+                self.create_deck(new_deck)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to create {}",
+                            new_deck
+                        )
+                    })?;
+                report.created.push(new_deck.clone());
+            }
+        }
+        for ((_, new_deck), config_id) in
+            renamed.iter().zip(config_ids)
+        {
+            self.set_deck_config_id(
+                vec![new_deck.clone()],
+                *config_id,
+            )
+            .await
+            .with_context(|| {
+                format!("Failed to configure {}", new_deck)
+            })?;
+            report.configured.push(new_deck.clone());
+        }
+        for (old_deck, new_deck) in &renamed {
+            let cards = self
+                .find_cards(&deck_only_query(old_deck))
+                .await?;
+            let count = cards.len();
+            self.change_deck(cards, new_deck).await.with_context(
+                || {
+                    format!(
+                        "Failed to move the cards of {} to {}",
+                        old_deck, new_deck
+                    )
+                },
+            )?;
+            report.moved.push((old_deck.clone(), count));
+        }
+        Ok(())
+    }
+
+    /// Gets the names of all models in the collection
+    pub async fn get_model_names(
+        &self,
+    ) -> Result<Vec<String>> {
+        self.invoke(
+            "modelNames",
+            None::<GetModelNamesParams>,
+        )
+        .await
+    }
+
+    /// Gets the field names for a specific model
+    pub async fn get_model_field_names(
+        &self,
+        model_name: &str,
+    ) -> Result<Vec<String>> {
+        let params = GetModelFieldNamesParams {
+            model_name: model_name.to_string(),
+        };
+        self.invoke("modelFieldNames", Some(params)).await
+    }
+
+    /// Checks whether a model with the given name exists
+    pub async fn model_exists(
+        &self,
+        model_name: &str,
+    ) -> Result<bool> {
+        let models = self.get_model_names().await?;
+        Ok(models
+            .iter()
+            .any(|name| self.names_match(name, model_name)))
+    }
+
+    /// Fails unless a model with the given name exists.
+    ///
+    /// The error is an [`AnkiError::ModelNotFound`], so callers can
+    /// tell a missing model from a failed request.
+    pub async fn ensure_model(
+        &self,
+        model_name: &str,
+    ) -> Result<()> {
+        if self.model_exists(model_name).await? {
+            return Ok(());
+        }
+        Err(AnkiError::model_not_found(model_name).into())
+    }
+
+    /// Adds a single note to Anki
+    ///
+    /// A duplicate is reported as [`AnkiError::Duplicate`] carrying
+    /// the ID of the existing note when it can be found.
+    pub async fn add_note(
+        &self,
+        mut note: Note,
+    ) -> Result<u64> {
+        if self.trim_fields {
+            note.trim_fields();
+        }
+        let params = AddNoteParams { note };
+        let result =
+            self.invoke("addNote", Some(&params)).await;
+        match result {
+            Err(e) if is_duplicate_error(&e) => {
+                let existing_note_id = self
+                    .find_duplicate(&params.note)
+                    .await
+                    .ok()
+                    .flatten();
+                Err(AnkiError::Duplicate {
+                    existing_note_id,
+                }
+                .into())
+            }
+            other => other,
+        }
+    }
+
+    /// Looks up the note a duplicate `note` collides with.
+    ///
+    /// Anki detects duplicates by the first field within the same
+    /// model, so the search uses the model's first field.
+    async fn find_duplicate(
+        &self,
+        note: &Note,
+    ) -> Result<Option<u64>> {
+        let field_names = self
+            .get_model_field_names(&note.model_name)
+            .await?;
+        let Some(first_value) = field_names
+            .first()
+            .and_then(|name| note.fields.get(name))
+        else {
+            return Ok(None);
+        };
+        let query = format!(
+            "{} {}",
+            search_term("note", &note.model_name),
+            search_term(&field_names[0], first_value)
+        );
+        let ids = self.find_notes(&query).await?;
+        Ok(ids.into_iter().min())
+    }
+
+    /// Adds multiple notes to Anki in a single request
+    ///
+    /// # Returns
+    /// One ID per note, `None` for notes Anki did not add
+    pub async fn add_notes(
+        &self,
+        mut notes: Vec<Note>,
+    ) -> Result<Vec<Option<u64>>> {
+        if notes.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.trim_fields {
+            notes.iter_mut().for_each(Note::trim_fields);
+        }
+        let params = AddNotesParams { notes };
+        // Each entry is a note ID or `null`; newer Anki-Connect
+        // versions may send an error object instead of `null`
+        let entries: Vec<serde_json::Value> =
+            self.invoke("addNotes", Some(params)).await?;
+        Ok(entries
+            .iter()
+            .map(serde_json::Value::as_u64)
+            .collect())
+    }
+
+    /// Adds notes like [`AnkiClient::add_notes`], then applies the
+    /// space-separated `extra_tags` to the added ones in a single
+    /// [`AnkiClient::add_tags`] request.
+    ///
+    /// # Errors
+    /// Fails only if adding fails. A tagging failure is recorded in
+    /// [`TaggedAddReport::tag_failure`] next to the IDs of the notes
+    /// already created.
+    pub async fn add_notes_tagged(
+        &self,
+        notes: Vec<Note>,
+        extra_tags: &str,
+    ) -> Result<TaggedAddReport> {
+        let note_ids = self.add_notes(notes).await?;
+        let added: Vec<u64> =
+            note_ids.iter().flatten().copied().collect();
+        let count = added.len();
+        let tag_failure = self
+            .add_tags(added, extra_tags)
+            .await
+            .err()
+            .map(|e| {
+                format!(
+                    "Added {} notes but failed to tag them: {:#}",
+                    count, e
+                )
+            });
+        Ok(TaggedAddReport {
+            note_ids,
+            tag_failure,
+        })
+    }
+
+    /// Adds notes in requests of `chunk_size` notes, one request at a
+    /// time.
+    ///
+    /// Once `cancel` is tripped no further chunk is sent; the chunk in
+    /// flight still completes.
+    ///
+    /// # Returns
+    /// One outcome per note, in order; notes of unsent chunks are
+    /// [`ChunkedAdd::NotAttempted`]
+    pub async fn add_notes_chunked(
+        &self,
+        notes: Vec<Note>,
+        chunk_size: usize,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Vec<ChunkedAdd>> {
+        let mut outcomes = Vec::with_capacity(notes.len());
+        for chunk in notes.chunks(chunk_size.max(1)) {
+            if cancel.is_some_and(|c| c.is_cancelled()) {
+                break;
+            }
+            let ids =
+                self.add_notes(chunk.to_vec()).await?;
+            outcomes.extend(ids.into_iter().map(|id| {
+                id.map_or(
+                    ChunkedAdd::Rejected,
+                    ChunkedAdd::Added,
+                )
+            }));
+            self.emit(|| ClientEvent::ChunkProgress {
+                operation: "add_notes_chunked",
+                done: outcomes.len(),
+                total: notes.len(),
+            });
+        }
+        outcomes
+            .resize(notes.len(), ChunkedAdd::NotAttempted);
+        Ok(outcomes)
+    }
+
+    /// Checks which notes could be added, with Anki's reason for
+    /// each one that could not
+    pub async fn can_add_notes_detailed(
+        &self,
+        mut notes: Vec<Note>,
+    ) -> Result<Vec<CanAddNote>> {
+        if notes.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.trim_fields {
+            notes.iter_mut().for_each(Note::trim_fields);
+        }
+        let params = AddNotesParams { notes };
+        self.invoke(
+            "canAddNotesWithErrorDetail",
+            Some(params),
+        )
+        .await
+    }
+
+    /// Finds notes matching the given query
+    pub async fn find_notes(
+        &self,
+        query: &str,
+    ) -> Result<Vec<u64>> {
+        let params = FindNotesParams {
+            query: query.to_string(),
+        };
+        self.invoke("findNotes", Some(params)).await
+    }
+
+    /// Like [`AnkiClient::find_notes`], optionally dropping empty
+    /// notes.
+    ///
+    /// With `exclude_empty`, notes whose first field is blank are
+    /// left out: no text once HTML is stripped (so `<br>` counts as
+    /// blank) and no media. This needs `notesInfo` for every match.
+    pub async fn find_notes_filtered(
+        &self,
+        query: &str,
+        exclude_empty: bool,
+    ) -> Result<Vec<u64>> {
+        let note_ids = self.find_notes(query).await?;
+        if !exclude_empty {
+            return Ok(note_ids);
+        }
+        let notes =
+            self.notes_info_chunked(&note_ids).await?;
+        Ok(notes
+            .iter()
+            .filter(|note| {
+                note.ordered_fields().first().is_some_and(
+                    |(_, value)| {
+                        !strip_html(value).is_empty()
+                            || !media_references(value)
+                                .is_empty()
+                    },
+                )
+            })
+            .map(|note| note.note_id)
+            .collect())
+    }
+
+    /// Finds the note of `model` whose `field` holds `value`.
+    ///
+    /// With [`FieldMatch::IgnoreHtml`], Anki is searched for the
+    /// longest word of the plain value and the candidates are
+    /// compared after stripping HTML on both sides, so formatted
+    /// fields written by earlier runs are still found.
+    ///
+    /// # Returns
+    /// The lowest matching note ID, or `None` if no note matches
+    pub async fn find_note_by_field(
+        &self,
+        model: &str,
+        field: &str,
+        value: &str,
+        mode: FieldMatch,
+    ) -> Result<Option<u64>> {
+        let model_term = search_term("note", model);
+        if mode == FieldMatch::Exact {
+            let query = format!(
+                "{} {}",
+                model_term,
+                search_term(field, value)
+            );
+            let ids = self.find_notes(&query).await?;
+            return Ok(ids.into_iter().min());
+        }
+
+        let plain = strip_html(value);
+        let query = match plain
+            .split_whitespace()
+            .max_by_key(|word| word.chars().count())
+        {
+            Some(word) => format!(
+                "{} \"{}:*{}*\"",
+                model_term,
+                escape_search(field),
+                escape_search(word)
+            ),
+            None => model_term,
+        };
+        let ids = self.find_notes(&query).await?;
+        let notes = self.notes_info_chunked(&ids).await?;
+        Ok(notes
+            .into_iter()
+            .filter(|note| {
+                note.fields.get(field).is_some_and(
+                    |stored| {
+                        strip_html(&stored.value) == plain
+                    },
+                )
+            })
+            .map(|note| note.note_id)
+            .min())
+    }
+
+    /// Finds notes matching `query` and fetches their details.
+    ///
+    /// `notesInfo` is requested in chunks (see
+    /// [`AnkiClient::with_info_chunk`]), one chunk at a time; notes come back in search-result
+    /// order. With a `limit`, fetching stops once that many notes
+    /// have been gathered.
+    pub async fn find_notes_detailed(
+        &self,
+        query: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<NoteInfo>> {
+        let note_ids = self.find_notes(query).await?;
+        let wanted = limit.unwrap_or(usize::MAX);
+        let mut notes =
+            Vec::with_capacity(note_ids.len().min(wanted));
+        let total = note_ids.len().min(wanted);
+        let mut remaining = note_ids.as_slice();
+        while notes.len() < wanted && !remaining.is_empty()
+        {
+            let size = self
+                .info_chunk
+                .min(wanted - notes.len())
+                .min(remaining.len());
+            let (chunk, rest) = remaining.split_at(size);
+            notes.extend(
+                self.notes_info(chunk.to_vec()).await?,
+            );
+            remaining = rest;
+            self.emit(|| ClientEvent::ChunkProgress {
+                operation: "find_notes_detailed",
+                done: note_ids.len() - remaining.len(),
+                total,
+            });
+        }
+        notes.truncate(wanted);
+        Ok(notes)
+    }
+
+    /// The `n` newest notes matching `query`, newest first.
+    ///
+    /// Note IDs are creation timestamps in milliseconds, so the IDs
+    /// are sorted descending and only the first `n` are fetched.
+    pub async fn recent_notes(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<NoteInfo>> {
+        let mut note_ids = self.find_notes(query).await?;
+        note_ids.sort_unstable_by(|a, b| b.cmp(a));
+        note_ids.truncate(n);
+        self.notes_info_chunked(&note_ids).await
+    }
+
+    /// Dumps the notes matching `query` as a JSON array.
+    ///
+    /// Each note becomes `{"model", "tags", "fields"}`, with the
+    /// fields in the model's field order and no IDs, so the dump
+    /// can be re-imported into any collection. Notes come in
+    /// search-result order.
+    pub async fn export_notes_json(
+        &self,
+        query: &str,
+    ) -> Result<String> {
+        let notes =
+            self.find_notes_detailed(query, None).await?;
+        let exported: Vec<ExportedNote> = notes
+            .iter()
+            .map(|note| ExportedNote {
+                model: &note.model_name,
+                tags: &note.tags,
+                fields: OrderedFields(
+                    note.ordered_fields(),
+                ),
+            })
+            .collect();
+        serde_json::to_string_pretty(&exported)
+            .context("Failed to serialize exported notes")
+    }
+
+    /// Adds the notes of a [`AnkiClient::export_notes_json`] dump to
+    /// `deck` in a single `addNotes` request.
+    ///
+    /// Every model is checked with [`AnkiClient::ensure_model`]
+    /// first; notes of missing models are skipped without being
+    /// sent.
+    ///
+    /// # Returns
+    /// One outcome per note of the dump, in order
+    pub async fn import_notes_json(
+        &self,
+        json: &str,
+        deck: &str,
+    ) -> Result<Vec<JsonImport>> {
+        let imported: Vec<ImportedNote> =
+            serde_json::from_str(json).context(
+                "Failed to parse the notes JSON: expected an array of {model, tags, fields}",
+            )?;
+
+        let mut missing = Vec::new();
+        let mut checked = Vec::new();
+        for note in &imported {
+            if checked.contains(&note.model) {
+                continue;
+            }
+            checked.push(note.model.clone());
+            match self.ensure_model(&note.model).await {
+                Ok(()) => {}
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<AnkiError>(),
+                        Some(
+                            AnkiError::ModelNotFound { .. }
+                        )
+                    ) =>
+                {
+                    missing.push(note.model.clone())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut outcomes =
+            Vec::with_capacity(imported.len());
+        let mut positions = Vec::new();
+        let mut notes = Vec::new();
+        for (index, note) in
+            imported.into_iter().enumerate()
+        {
+            if missing.contains(&note.model) {
+                outcomes.push(JsonImport::Skipped {
+                    model: note.model,
+                });
+                continue;
+            }
+            outcomes.push(JsonImport::Rejected);
+            positions.push(index);
+            notes.push(Note {
+                model_name: note.model,
+                deck_name: deck.to_string(),
+                fields: note.fields,
+                tags: note.tags,
+                audio: None,
+                picture: None,
+                video: None,
+                options: None,
+            });
+        }
+        for (index, note_id) in positions
+            .into_iter()
+            .zip(self.add_notes(notes).await?)
+        {
+            if let Some(note_id) = note_id {
+                outcomes[index] =
+                    JsonImport::Added(note_id);
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Number of notes matching `query`.
+    ///
+    /// Anki-Connect has no count action, so this still transfers the
+    /// matching IDs; callers that only need the count should use it
+    /// anyway so a cheaper implementation can be dropped in later.
+    pub async fn count_notes(
+        &self,
+        query: &str,
+    ) -> Result<usize> {
+        Ok(self.find_notes(query).await?.len())
+    }
+
+    /// Gets detailed information about notes
+    pub async fn notes_info(
+        &self,
+        note_ids: Vec<u64>,
+    ) -> Result<Vec<NoteInfo>> {
+        if note_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let params = NotesInfoParams { notes: note_ids };
+        self.invoke("notesInfo", Some(params)).await
+    }
+
+    /// Updates fields of an existing note
+    pub async fn update_note_fields(
+        &self,
+        note_id: u64,
+        fields: impl Into<NoteFields>,
+        audio: Option<Vec<NoteAudio>>,
+    ) -> Result<()> {
+        let params = UpdateNoteFieldsParams {
+            note: UpdatedNoteFields {
+                id: note_id,
+                fields: fields.into(),
+                audio,
+            },
+        };
+        // Current versions answer `null`, older ones a boolean
+        let result: Option<bool> = self
+            .invoke("updateNoteFields", Some(params))
+            .await?;
+        if result == Some(false) {
+            return Err(anyhow::anyhow!(
+                "Failed to update note fields"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sets a single field of an existing note, leaving the others
+    /// untouched
+    pub async fn update_note_field(
+        &self,
+        note_id: u64,
+        field: &str,
+        value: &str,
+    ) -> Result<()> {
+        let fields = NoteFields::from([(field, value)]);
+        self.update_note_fields(note_id, fields, None).await
+    }
+
+    /// Replaces all tags of a note
+    pub async fn update_note_tags(
+        &self,
+        note_id: u64,
+        tags: Vec<String>,
+    ) -> Result<()> {
+        let params = UpdateNoteTagsParams {
+            note: note_id,
+            tags,
+        };
+        self.invoke::<_, Option<bool>>(
+            "updateNoteTags",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Adds the space-separated `tags`, e.g. `"vocab ai::glm"`, to
+    /// many notes at once.
+    ///
+    /// The string is sent to Anki-Connect unchanged. Nothing is sent
+    /// when there are no notes or `tags` is blank.
+    pub async fn add_tags(
+        &self,
+        note_ids: Vec<u64>,
+        tags: &str,
+    ) -> Result<()> {
+        self.change_tags("addTags", note_ids, tags).await
+    }
+
+    /// Removes the space-separated `tags` from many notes at once,
+    /// sent like [`AnkiClient::add_tags`]
+    pub async fn remove_tags(
+        &self,
+        note_ids: Vec<u64>,
+        tags: &str,
+    ) -> Result<()> {
+        self.change_tags("removeTags", note_ids, tags).await
+    }
+
+    /// Adds a list of tags to many notes at once.
+    ///
+    /// The tags are joined with single spaces for
+    /// [`AnkiClient::add_tags`]; a tag containing whitespace is
+    /// rejected rather than split.
+    pub async fn add_tag_list(
+        &self,
+        note_ids: Vec<u64>,
+        tags: &[String],
+    ) -> Result<()> {
+        validate_tags(tags)?;
+        self.add_tags(note_ids, &tags.join(" ")).await
+    }
+
+    /// Removes a list of tags from many notes at once, sent like
+    /// [`AnkiClient::add_tag_list`]
+    pub async fn remove_tag_list(
+        &self,
+        note_ids: Vec<u64>,
+        tags: &[String],
+    ) -> Result<()> {
+        validate_tags(tags)?;
+        self.remove_tags(note_ids, &tags.join(" ")).await
+    }
+
+    async fn change_tags(
+        &self,
+        action: &str,
+        note_ids: Vec<u64>,
+        tags: &str,
+    ) -> Result<()> {
+        if note_ids.is_empty() || tags.trim().is_empty() {
+            return Ok(());
+        }
+        let params = ModifyTagsParams {
+            notes: note_ids,
+            tags: tags.to_string(),
+        };
+        self.invoke::<_, Option<bool>>(
+            action,
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Adds and removes tags on every note matching `query`.
+    ///
+    /// Note IDs are sent in batches (see
+    /// [`AnkiClient::with_tag_batch`]); each batch gets its
+    /// additions before its removals. A tag may not be both added
+    /// and removed. With `dry_run`, only the matches are counted.
+    pub async fn tag_query(
+        &self,
+        query: &str,
+        add: &[String],
+        remove: &[String],
+        dry_run: bool,
+    ) -> Result<TagOpReport> {
+        validate_tags(add)?;
+        validate_tags(remove)?;
+        if let Some(tag) = add.iter().find(|tag| {
+            remove
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(tag))
+        }) {
+            anyhow::bail!(
+                "Tag '{}' cannot be both added and removed",
+                tag
+            );
+        }
+
+        let note_ids = self.find_notes(query).await?;
+        let mut report = TagOpReport {
+            matched: note_ids.len(),
+            ..Default::default()
+        };
+        if dry_run
+            || note_ids.is_empty()
+            || (add.is_empty() && remove.is_empty())
+        {
+            return Ok(report);
+        }
+        for chunk in note_ids.chunks(self.tag_batch) {
+            for (action, tags) in
+                [("addTags", add), ("removeTags", remove)]
+            {
+                if !tags.is_empty() {
+                    self.change_tags(
+                        action,
+                        chunk.to_vec(),
+                        &tags.join(" "),
+                    )
+                    .await?;
+                    report.batches += 1;
+                }
+            }
+            report.touched += chunk.len();
+            self.emit(|| ClientEvent::ChunkProgress {
+                operation: "tag_query",
+                done: report.touched,
+                total: report.matched,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Deletes notes (and all their cards) from the collection
+    pub async fn delete_notes(
+        &self,
+        note_ids: Vec<u64>,
+    ) -> Result<()> {
+        if note_ids.is_empty() {
+            return Ok(());
+        }
+        if self.auto_backup.is_some() {
+            let ids: Vec<String> = note_ids
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            let cards = self
+                .find_cards(&format!(
+                    "nid:{}",
+                    ids.join(",")
+                ))
+                .await?;
+            let decks = self.get_decks(cards).await?;
+            self.backup_decks(decks.into_keys().collect())
+                .await?;
+        }
+        let params = DeleteNotesParams { notes: note_ids };
+        self.invoke::<_, Option<bool>>(
+            "deleteNotes",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes every note matching `query`, e.g. the notes of a bad
+    /// import tagged `import::2024-03-12`
+    ///
+    /// # Returns
+    /// How many notes were deleted
+    pub async fn delete_notes_matching(
+        &self,
+        query: &str,
+    ) -> Result<usize> {
+        let note_ids = self.find_notes(query).await?;
+        let count = note_ids.len();
+        self.delete_notes(note_ids).await?;
+        Ok(count)
+    }
+
+    /// Deletes the notes Anki considers empty. The notes are not
+    /// known in advance, so [`AnkiClient::with_auto_backup`] does not
+    /// apply.
+    pub async fn remove_empty_notes(&self) -> Result<()> {
+        self.invoke::<(), Option<bool>>(
+            "removeEmptyNotes",
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes decks together with all their cards
+    pub async fn delete_decks(
+        &self,
+        decks: Vec<String>,
+    ) -> Result<()> {
+        if decks.is_empty() {
+            return Ok(());
+        }
+        self.backup_decks(decks.clone()).await?;
+        let params = DeleteDecksParams {
+            decks,
+            cards_too: true,
+        };
+        self.invoke::<_, Option<bool>>(
+            "deleteDecks",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Resets cards to new, discarding their review progress
+    pub async fn forget_cards(
+        &self,
+        card_ids: Vec<u64>,
+    ) -> Result<()> {
+        if card_ids.is_empty() {
+            return Ok(());
+        }
+        if self.auto_backup.is_some() {
+            let decks =
+                self.get_decks(card_ids.clone()).await?;
+            self.backup_decks(decks.into_keys().collect())
+                .await?;
+        }
+        let params = ForgetCardsParams { cards: card_ids };
+        self.invoke::<_, Option<bool>>(
+            "forgetCards",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Suspends cards, so they are left out of reviews until
+    /// unsuspended; already suspended cards are left alone
+    pub async fn suspend_cards(
+        &self,
+        card_ids: Vec<u64>,
+    ) -> Result<()> {
+        if card_ids.is_empty() {
+            return Ok(());
+        }
+        let params = SuspendCardsParams { cards: card_ids };
+        self.invoke::<_, Option<bool>>(
+            "suspend",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Answers cards as if reviewed, without the reviewer.
+    ///
+    /// # Returns
+    /// One flag per answer, `false` for cards Anki could not find
+    pub async fn answer_cards(
+        &self,
+        answers: Vec<CardAnswer>,
+    ) -> Result<Vec<bool>> {
+        if answers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let params = AnswerCardsParams { answers };
+        self.invoke("answerCards", Some(params)).await
+    }
+
+    /// Like [`AnkiClient::answer_cards`], from `(card ID, ease)`
+    /// pairs such as grades assigned by a model.
+    ///
+    /// Every ease must be 1 (Again) to 4 (Easy); otherwise nothing is
+    /// sent and the error lists each offending card.
+    pub async fn answer_cards_graded(
+        &self,
+        grades: Vec<(u64, u8)>,
+    ) -> Result<Vec<bool>> {
+        let invalid: Vec<String> = grades
+            .iter()
+            .filter(|(_, ease)| !(1..=4).contains(ease))
+            .map(|(card_id, ease)| {
+                format!(
+                    "card {} has ease {}",
+                    card_id, ease
+                )
+            })
+            .collect();
+        anyhow::ensure!(
+            invalid.is_empty(),
+            "Invalid ease, expected 1 to 4: {}",
+            invalid.join(", ")
+        );
+        self.answer_cards(
+            grades
+                .into_iter()
+                .map(|(card_id, ease)| CardAnswer {
+                    card_id,
+                    ease,
+                })
+                .collect(),
+        )
+        .await
+    }
+
+    /// Exports a deck (and its subdecks) as an `.apkg` package at
+    /// `path`, which is written by the Anki process
+    pub async fn export_package(
+        &self,
+        deck: &str,
+        path: &Path,
+        include_sched: bool,
+    ) -> Result<()> {
+        let params = ExportPackageParams {
+            deck: deck.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            include_sched,
+        };
+        let exported: bool = self
+            .invoke("exportPackage", Some(params))
+            .await?;
+        anyhow::ensure!(
+            exported,
+            "Failed to export deck '{}' to {}",
+            deck,
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Backs up `decks` if auto-backup is enabled
+    async fn backup_decks(
+        &self,
+        mut decks: Vec<String>,
+    ) -> Result<()> {
+        let Some(dir) = &self.auto_backup else {
+            return Ok(());
+        };
+        std::fs::create_dir_all(dir).with_context(
+            || {
+                format!(
+                    "Failed to create backup directory {}",
+                    dir.display()
+                )
+            },
+        )?;
+        decks.sort();
+        let stamp =
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ");
+        for deck in decks {
+            let path = dir.join(format!(
+                "{}-{}.apkg",
+                backup_file_stem(&deck),
+                stamp
+            ));
+            self.export_package(&deck, &path, true)
+                .await
+                .context(
+                    "Backup before a destructive operation failed; nothing was changed",
+                )?;
+        }
+        Ok(())
+    }
+
+    /// Gets detailed information about cards
+    pub async fn cards_info(
+        &self,
+        card_ids: Vec<u64>,
+    ) -> Result<Vec<CardInfo>> {
+        if card_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let params = CardsInfoParams { cards: card_ids };
+        self.invoke("cardsInfo", Some(params)).await
+    }
+
+    /// Finds cards matching the given query
+    pub async fn find_cards(
+        &self,
+        query: &str,
+    ) -> Result<Vec<u64>> {
+        let params = FindCardsParams {
+            query: query.to_string(),
+        };
+        self.invoke("findCards", Some(params)).await
+    }
+
+    /// Number of cards matching `query`; see
+    /// [`AnkiClient::count_notes`]
+    pub async fn count_cards(
+        &self,
+        query: &str,
+    ) -> Result<usize> {
+        Ok(self.find_cards(query).await?.len())
+    }
+
+    /// Cards of `deck` (subdecks included) due on each of the next
+    /// `days` days, today first.
+    ///
+    /// Today's count includes overdue cards; suspended cards are left
+    /// out. Each day is one `prop:due` search.
+    pub async fn review_forecast(
+        &self,
+        deck: &str,
+        days: usize,
+    ) -> Result<Vec<usize>> {
+        let mut forecast = Vec::with_capacity(days);
+        for day in 0..days {
+            let due = if day == 0 {
+                "prop:due<=0".to_string()
+            } else {
+                format!("prop:due={}", day)
+            };
+            let query = format!(
+                "{} {} -is:suspended",
+                deck_query(deck),
+                due
+            );
+            forecast.push(self.count_cards(&query).await?);
+        }
+        Ok(forecast)
+    }
+
+    /// Finds cards matching `query` and fetches their details.
+    ///
+    /// `cardsInfo` is requested in chunks (see
+    /// [`AnkiClient::with_info_chunk`]), one chunk at a time, so
+    /// huge result sets never become one giant request. Cards come
+    /// back in search-result order; see [`group_by_deck`].
+    pub async fn find_cards_detailed(
+        &self,
+        query: &str,
+    ) -> Result<Vec<CardInfo>> {
+        let card_ids = self.find_cards(query).await?;
+        self.cards_info_chunked(&card_ids).await
+    }
+
+    /// Like [`AnkiClient::cards_info`], requested in chunks (see
+    /// [`AnkiClient::with_info_chunk`]) one chunk at a time
+    pub async fn cards_info_chunked(
+        &self,
+        card_ids: &[u64],
+    ) -> Result<Vec<CardInfo>> {
+        let mut cards = Vec::with_capacity(card_ids.len());
+        for chunk in card_ids.chunks(self.info_chunk) {
+            cards.extend(
+                self.cards_info(chunk.to_vec()).await?,
+            );
+            self.emit(|| ClientEvent::ChunkProgress {
+                operation: "cards_info_chunked",
+                done: cards.len(),
+                total: card_ids.len(),
+            });
+        }
+        Ok(cards)
+    }
+
+    /// Like [`AnkiClient::notes_info`], requested in chunks (see
+    /// [`AnkiClient::with_info_chunk`]) one chunk at a time
+    pub async fn notes_info_chunked(
+        &self,
+        note_ids: &[u64],
+    ) -> Result<Vec<NoteInfo>> {
+        let mut notes = Vec::with_capacity(note_ids.len());
+        for chunk in note_ids.chunks(self.info_chunk) {
+            notes.extend(
+                self.notes_info(chunk.to_vec()).await?,
+            );
+            self.emit(|| ClientEvent::ChunkProgress {
+                operation: "notes_info_chunked",
+                done: notes.len(),
+                total: note_ids.len(),
+            });
+        }
+        Ok(notes)
+    }
+
+    /// Reviews of the cards in `deck` logged after `start_id`.
+    ///
+    /// Review IDs are their timestamps in milliseconds, so
+    /// `start_id` selects reviews done after that instant.
+    pub async fn card_reviews(
+        &self,
+        deck: &str,
+        start_id: i64,
+    ) -> Result<Vec<CardReview>> {
+        let params = CardReviewsParams {
+            deck: deck.to_string(),
+            start_id,
+        };
+        self.invoke("cardReviews", Some(params)).await
+    }
+
+    /// Number of answers logged on each day with any, newest first,
+    /// as `("YYYY-MM-DD", count)` pairs.
+    ///
+    /// Counts the whole collection's review log. Anki-Connect groups
+    /// the answers by the local date, shifted by the collection's
+    /// rollover hour.
+    pub async fn num_cards_reviewed_by_day(
+        &self,
+    ) -> Result<Vec<(String, u64)>> {
+        self.invoke::<(), _>(
+            "getNumCardsReviewedByDay",
+            None,
+        )
+        .await
+    }
+
+    /// Maps the given cards to the decks containing them
+    pub async fn get_decks(
+        &self,
+        card_ids: Vec<u64>,
+    ) -> Result<std::collections::HashMap<String, Vec<u64>>>
+    {
+        if card_ids.is_empty() {
+            return Ok(std::collections::HashMap::new());
+        }
+        let params = GetDecksParams { cards: card_ids };
+        self.invoke("getDecks", Some(params)).await
+    }
+
+    /// Lists media file names matching a glob pattern (e.g. `*.mp3`)
+    pub async fn get_media_files_names(
+        &self,
+        pattern: &str,
+    ) -> Result<Vec<String>> {
+        let params = GetMediaFilesNamesParams {
+            pattern: pattern.to_string(),
+        };
+        self.invoke("getMediaFilesNames", Some(params))
+            .await
+    }
+
+    /// Downloads a media file, `None` if it does not exist
+    pub async fn retrieve_media_file(
+        &self,
+        filename: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        use base64::Engine;
+
+        let params = MediaFileParams {
+            filename: filename.to_string(),
+        };
+        // Anki-Connect answers `false` for a missing file
+        let data: serde_json::Value = self
+            .invoke("retrieveMediaFile", Some(params))
+            .await?;
+        let Some(encoded) = data.as_str() else {
+            return Ok(None);
+        };
+        let bytes =
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .with_context(|| {
+                    format!(
+                        "Invalid media data for {}",
+                        filename
+                    )
+                })?;
+        Ok(Some(bytes))
+    }
+
+    /// Like [`AnkiClient::retrieve_media_file`], but stops reading
+    /// the response once the file is known to be over `max_size`
+    /// bytes, failing with a [`ResponseTooLarge`].
+    ///
+    /// The response is measured before decoding, so a file slightly
+    /// over `max_size` may still be returned.
+    pub async fn retrieve_media_file_limited(
+        &self,
+        filename: &str,
+        max_size: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let encoded =
+            max_size.div_ceil(3).saturating_mul(4);
+        let limit = usize::try_from(encoded)
+            .unwrap_or(usize::MAX)
+            .saturating_add(MEDIA_RESPONSE_OVERHEAD);
+        let limit = self
+            .max_response_size
+            .map_or(limit, |max| max.min(limit));
+        self.clone()
+            .with_max_response_size(Some(limit))
+            .retrieve_media_file(filename)
+            .await
+    }
+
+    /// Deletes a file from the media folder
+    pub async fn delete_media_file(
+        &self,
+        filename: &str,
+    ) -> Result<()> {
+        let params = MediaFileParams {
+            filename: filename.to_string(),
+        };
+        self.invoke::<_, Option<bool>>(
+            "deleteMediaFile",
+            Some(params),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Reports which of the given media files already exist.
+    ///
+    /// Uses a single listing of the media folder rather than one
+    /// request per file; no request is made for an empty list.
+    pub async fn media_files_exist(
+        &self,
+        filenames: Vec<String>,
+    ) -> Result<std::collections::HashMap<String, bool>>
+    {
+        if filenames.is_empty() {
+            return Ok(std::collections::HashMap::new());
+        }
+        let existing: std::collections::HashSet<String> =
+            self.get_media_files_names("*")
+                .await?
+                .into_iter()
+                .collect();
+        Ok(filenames
+            .into_iter()
+            .map(|name| {
+                let exists = existing.contains(&name);
+                (name, exists)
+            })
+            .collect())
+    }
+
+    /// Counts the cards due today (`is:due`) in each deck
+    pub async fn due_counts_by_deck(
+        &self,
+    ) -> Result<std::collections::HashMap<String, usize>>
+    {
+        let due = self.find_cards("is:due").await?;
+        let decks = self.get_decks(due).await?;
+        Ok(decks
+            .into_iter()
+            .map(|(deck, cards)| (deck, cards.len()))
+            .filter(|(_, count)| *count > 0)
+            .collect())
+    }
+
+    /// Syncs the collection and waits until Anki is usable again.
+    ///
+    /// `sync` returns before the sync is done, and requests sent
+    /// meanwhile fail or hang. After triggering it, `deckNames` is
+    /// polled: refused connections, timeouts and busy errors mean
+    /// the sync is still running. When `deckNames` keeps failing
+    /// while `getActiveProfile` answers, Anki is most likely showing
+    /// a conflict dialog, and [`SyncOutcome::MaybeConflict`] is
+    /// returned instead of waiting for the timeout.
+    ///
+    /// A failing `sync` request is returned as an error, whatever
+    /// the reason: Anki being unreachable, sync not being set up, or
+    /// a sync already running. Only the polls afterwards treat
+    /// failures as a sync in progress.
+    pub async fn sync_and_wait(
+        &self,
+        options: SyncWaitOptions,
+    ) -> Result<SyncOutcome> {
+        let deadline =
+            tokio::time::Instant::now() + options.timeout;
+        self.invoke::<(), serde_json::Value>("sync", None)
+            .await?;
+
+        let mut blocked_polls = 0;
+        let mut polls = 0;
+        loop {
+            if self
+                .probe("deckNames", options.poll_interval)
+                .await?
+            {
+                return Ok(SyncOutcome::Completed);
+            }
+            if self
+                .probe(
+                    "getActiveProfile",
+                    options.poll_interval,
+                )
+                .await?
+            {
+                blocked_polls += 1;
+                if blocked_polls >= CONFLICT_CONFIRMATIONS {
+                    return Ok(SyncOutcome::MaybeConflict);
+                }
+            } else {
+                blocked_polls = 0;
+            }
+            if tokio::time::Instant::now()
+                + options.poll_interval
+                > deadline
+            {
+                return Ok(SyncOutcome::TimedOut);
+            }
+            polls += 1;
+            self.emit(|| ClientEvent::RetryScheduled {
+                action: "deckNames".to_string(),
+                attempt: polls,
+                delay: options.poll_interval,
+                reason: "Anki is still syncing".to_string(),
+            });
+            tokio::time::sleep(options.poll_interval).await;
+        }
+    }
+
+    /// Sends a parameterless `action` within `limit`, returning
+    /// whether Anki answered normally; busy Anki gives `false`
+    async fn probe(
+        &self,
+        action: &str,
+        limit: Duration,
+    ) -> Result<bool> {
+        let request = self
+            .invoke::<(), serde_json::Value>(action, None);
+        match tokio::time::timeout(limit, request).await {
+            Err(_) => Ok(false),
+            Ok(Ok(_)) => Ok(true),
+            Ok(Err(e)) if is_still_busy(&e) => Ok(false),
+            Ok(Err(e)) => Err(e),
+        }
+    }
+
+    /// Answers the card currently shown in Anki's reviewer.
+    ///
+    /// The reviewer only accepts an answer after the timer was
+    /// started and the answer side is shown, and Anki-Connect merely
+    /// returns `false` when a step is out of order. This runs
+    /// `guiStartCardTimer`, `guiShowQuestion`, `guiShowAnswer` and
+    /// `guiAnswerCard` in that order, pausing briefly between them,
+    /// and fails at the first step Anki refuses.
+    ///
+    /// # Arguments
+    /// * `ease` - 1 (Again), 2 (Hard), 3 (Good) or 4 (Easy)
+    pub async fn review_current_card(
+        &self,
+        ease: u8,
+    ) -> Result<()> {
+        anyhow::ensure!(
+            (1..=4).contains(&ease),
+            "Invalid ease {}: expected 1 to 4",
+            ease
+        );
+        let current: serde_json::Value = self
+            .invoke::<(), _>("guiCurrentCard", None)
+            .await?;
+        anyhow::ensure!(
+            !current.is_null(),
+            "No card is being reviewed; open a deck in the reviewer first"
+        );
+        for action in [
+            "guiStartCardTimer",
+            "guiShowQuestion",
+            "guiShowAnswer",
+        ] {
+            let done: bool =
+                self.invoke::<(), _>(action, None).await?;
+            anyhow::ensure!(
+                done,
+                "{} was refused by Anki",
+                action
+            );
+            tokio::time::sleep(GUI_STEP_DELAY).await;
+        }
+        let answered: bool = self
+            .invoke(
+                "guiAnswerCard",
+                Some(GuiAnswerCardParams { ease }),
+            )
+            .await?;
+        anyhow::ensure!(
+            answered,
+            "guiAnswerCard was refused by Anki"
+        );
+        Ok(())
+    }
+
+    /// Closes Anki, e.g. when tearing down an integration test.
+    ///
+    /// Anki shuts down while answering, so the connection usually drops
+    /// after the request was sent. Such a disconnect counts as
+    /// success; failing to connect at all, or a response that is not
+    /// a valid answer, does not.
+    pub async fn gui_exit_anki(&self) -> Result<()> {
+        match self
+            .invoke::<(), serde_json::Value>(
+                "guiExitAnki",
+                None,
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) if is_disconnect_after_send(&e) => {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{MockAnki, err, ok};
+    use super::*;
+    use serde_json::json;
+
+    /// "Français" with a precomposed "ç"
+    const COMPOSED: &str = "Fran\u{e7}ais";
+    /// "Français" with "c" + combining cedilla
+    const DECOMPOSED: &str = "Franc\u{327}ais";
+
+    #[test]
+    fn test_anki_request_serialization() {
+        let request =
+            AnkiRequest::new("version", 6, None::<()>);
+        let json = serde_json::to_string(&request)
+            .expect("Failed to serialize request");
+        assert_eq!(
+            json,
+            r#"{"action":"version","version":6}"#
+        );
+    }
+
+    #[test]
+    fn test_anki_request_with_params_serialization() {
+        let params =
+            GetDeckNamesParams { cards: Some(true) };
+        let request = AnkiRequest::new(
+            "getDeckNames",
+            6,
+            Some(params),
+        );
+        let json = serde_json::to_string(&request)
+            .expect("Failed to serialize request");
+        assert!(json.contains(r#"action":"getDeckNames""#));
+        assert!(json.contains(r#"version":6"#));
+        assert!(json.contains(r#"cards":true"#));
+    }
+
+    #[test]
+    fn test_anki_response_success_deserialization() {
+        let json = r#"{"result":12345}"#;
+        let response: AnkiResponse<u64> =
+            serde_json::from_str(json)
+                .expect("Failed to deserialize response");
+        match response {
+            AnkiResponse::Success { result } => {
+                assert_eq!(result, 12345)
+            }
+            AnkiResponse::Error { .. } => {
+                panic!("Expected success response")
+            }
+        }
+    }
+
+    #[test]
+    fn test_anki_response_error_deserialization() {
+        let json = r#"{"error":"Test error","detail":"Test detail"}"#;
+        let response: AnkiResponse<u64> =
+            serde_json::from_str(json)
+                .expect("Failed to deserialize response");
+        match response {
+            AnkiResponse::Success { .. } => {
+                panic!("Expected error response")
+            }
+            AnkiResponse::Error { error, detail } => {
+                assert_eq!(error, "Test error");
+                assert_eq!(
+                    detail,
+                    Some("Test detail".to_string())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_response_usual_shapes() {
+        let result: u64 = parse_response(
+            r#"{"result":6,"error":null}"#.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(result, 6);
+
+        let error = parse_response::<u64>(
+            r#"{"result":null,"error":"collection is not available"}"#.as_bytes(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Anki-Connect error: collection is not available"
+        );
+    }
+
+    #[test]
+    fn test_parse_response_alternate_error_shapes() {
+        let shapes = [
+            r#"{"result":null,"error":["model was not found: Cloze2"]}"#,
+            r#"{"result":{"error":"model was not found: Cloze2"},"error":null}"#,
+            r#"[{"result":null,"error":"model was not found: Cloze2"}]"#,
+            r#"[{"result":null,"error":["model was not found: Cloze2"]}]"#,
+        ];
+        for shape in shapes {
+            let error = parse_response::<Vec<u64>>(
+                shape.as_bytes(),
+            )
+            .unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<AnkiError>(),
+                Some(&AnkiError::model_not_found("Cloze2")),
+                "{}",
+                shape
+            );
+        }
+
+        let duplicate = parse_response::<u64>(
+            r#"{"result":null,"error":["cannot create note because it is a duplicate"]}"#.as_bytes(),
+        )
+        .unwrap_err();
+        assert!(
+            duplicate
+                .downcast_ref::<AnkiError>()
+                .unwrap()
+                .is_duplicate()
+        );
+    }
+
+    #[test]
+    fn test_parse_response_keeps_results_with_error_keys() {
+        let result: serde_json::Value = parse_response(
+            r#"{"result":{"error":"x","count":1},"error":null}"#.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(result["count"], 1);
+    }
+
+    #[test]
+    fn test_parse_response_unrecognized_shape() {
+        let error = parse_response::<u64>(
+            r#"{"status":"ok"}"#.as_bytes(),
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains(
+                "unexpected shape {\"status\":\"ok\"}"
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_usual_responses_skip_the_value_path() {
+        let parsed = parse_usual_response::<Vec<u64>>(
+            br#"{"result":[1,2],"error":null}"#,
+        );
+        assert_eq!(parsed.unwrap().unwrap(), [1, 2]);
+        let parsed = parse_usual_response::<u64>(
+            br#"{"result":null,"error":"busy","detail":"x"}"#,
+        );
+        assert_eq!(
+            parsed.unwrap().unwrap_err().to_string(),
+            "Anki-Connect error: busy: x"
+        );
+
+        // Shapes only the Value path understands
+        for shape in [
+            r#"{"result":{"error":"x"},"error":null}"#,
+            r#"{"result":null,"error":["x"]}"#,
+            r#"[{"result":1,"error":null}]"#,
+            r#"{"result":"six","error":null}"#,
+        ] {
+            assert!(
+                parse_usual_response::<u64>(
+                    shape.as_bytes()
+                )
+                .is_none(),
+                "{}",
+                shape
+            );
+        }
+    }
+
+    #[test]
+    fn test_note_serialization() {
+        let mut fields = NoteFields::new();
+        fields.insert(
+            "Front".to_string(),
+            "Question".to_string(),
+        );
+        fields.insert(
+            "Back".to_string(),
+            "Answer".to_string(),
+        );
+
+        let note = Note {
+            model_name: "Basic".to_string(),
+            deck_name: "Default".to_string(),
+            fields,
+            tags: vec!["test".to_string()],
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        };
+
+        let json = serde_json::to_string(&note)
+            .expect("Failed to serialize note");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json)
+                .expect("Failed to parse JSON");
+        assert_eq!(parsed["modelName"], "Basic");
+        assert_eq!(parsed["deckName"], "Default");
+        assert_eq!(parsed["tags"][0], "test");
+        // Fields keep insertion order, so the body is byte-stable
+        assert!(json.contains(
+            r#""fields":{"Front":"Question","Back":"Answer"}"#
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_scope_options_serialization() {
+        let options = NoteOptions {
+            allow_duplicate: false,
+            duplicate_scope: Some("deck".to_string()),
+            duplicate_scope_options: Some(
+                DuplicateScopeOptions {
+                    deck_name: Some(
+                        "Japanese::Vocab".to_string(),
+                    ),
+                    check_children: true,
+                    check_all_models: false,
+                },
+            ),
+        };
+        assert_eq!(
+            serde_json::to_value(&options).unwrap(),
+            serde_json::json!({
+                "allowDuplicate": false,
+                "duplicateScope": "deck",
+                "duplicateScopeOptions": {
+                    "deckName": "Japanese::Vocab",
+                    "checkChildren": true,
+                    "checkAllModels": false,
+                },
+            })
+        );
+
+        // Left out entirely when unset
+        let plain = serde_json::to_value(NoteOptions {
+            allow_duplicate: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            plain,
+            serde_json::json!({
+                "allowDuplicate": true,
+                "duplicateScope": null,
+            })
+        );
+
+        let parsed: NoteOptions = serde_json::from_value(
+            serde_json::json!({
+                "allowDuplicate": false,
+                "duplicateScope": "deck",
+                "duplicateScopeOptions": {"checkAllModels": true},
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.duplicate_scope_options,
+            Some(DuplicateScopeOptions {
+                check_all_models: true,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_note_with_audio_serialization() {
+        let mut fields = NoteFields::new();
+        fields.insert(
+            "Front".to_string(),
+            "Question".to_string(),
+        );
+        fields.insert(
+            "Back".to_string(),
+            "Answer".to_string(),
+        );
+
+        let audio = NoteAudio {
+            path: "/path/to/audio.mp3".to_string(),
+            filename: "audio.mp3".to_string(),
+            fields: vec!["Back".to_string()],
+            hash: Some("abc123".to_string()),
+        };
+
+        let note = Note {
+            model_name: "Basic".to_string(),
+            deck_name: "Default".to_string(),
+            fields,
+            tags: vec![],
+            audio: Some(vec![audio]),
+            picture: None,
+            video: None,
+            options: None,
+        };
+
+        let json = serde_json::to_string(&note)
+            .expect("Failed to serialize note");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json)
+                .expect("Failed to parse JSON");
+        assert!(parsed["audio"].is_array());
+        assert_eq!(
+            parsed["audio"][0]["filename"],
+            "audio.mp3"
+        );
+    }
+
+    #[test]
+    fn test_client_creation() {
+        let client = AnkiClient::new();
+        assert_eq!(client.url, DEFAULT_ANKI_CONNECT_URL);
+        assert_eq!(client.version, 6);
+    }
+
+    #[test]
+    fn test_client_with_custom_url() {
+        let client =
+            AnkiClient::with_url("http://custom:8765");
+        assert_eq!(client.url, "http://custom:8765");
+    }
+
+    #[tokio::test]
+    async fn test_from_settings_applies_url_and_key() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!(6))).await;
+        let mut settings = Settings::load_with_env(
+            &["/nonexistent/config.toml".into()],
+            std::collections::HashMap::new(),
+        )
+        .unwrap();
+        settings.anki_connect_url =
+            mock.client().url().to_string();
+        settings.anki_connect_key =
+            Some(Secret::from("s3cret"));
+        settings.anki_request_timeout_secs = 5;
+
+        let client =
+            AnkiClient::from_settings(&settings).unwrap();
+        assert_eq!(client.url(), settings.anki_connect_url);
+        assert!(
+            !format!("{:?}", client).contains("s3cret")
+        );
+        assert_eq!(client.version().await.unwrap(), 6);
+        assert_eq!(mock.requests()[0]["key"], "s3cret");
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_urls() {
+        for url in
+            ["localhost:8765", "ftp://host", "http//x", ""]
+        {
+            let error = AnkiClient::builder()
+                .url(url)
+                .build()
+                .unwrap_err();
+            assert!(
+                error.to_string().starts_with(
+                    "Invalid Anki-Connect URL"
+                ),
+                "{}: {}",
+                url,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let client = AnkiClient::builder().build().unwrap();
+        assert_eq!(client.url(), DEFAULT_ANKI_CONNECT_URL);
+        assert!(client.key.is_none());
+        assert!(
+            AnkiClient::builder()
+                .http_client(Client::new())
+                .timeout(Duration::from_secs(1))
+                .build()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_sends_auth_and_extra_headers() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!(6))).await;
+        let client = AnkiClient::builder()
+            .url(mock.client().url())
+            .key(Some(Secret::from("anki-key")))
+            .basic_auth("me", Secret::from("pa55"))
+            .header("X-Proxy-Token", Secret::from("t0ken"))
+            .header("X-Trace", Secret::from("1"))
+            .build()
+            .unwrap();
+        assert!(!format!("{:?}", client).contains("pa55"));
+
+        client.version().await.unwrap();
+        client.get_deck_names(None).await.unwrap_err();
+        let headers = mock.headers();
+        assert_eq!(headers.len(), 2);
+        for headers in headers {
+            // base64("me:pa55")
+            assert_eq!(
+                headers["authorization"],
+                "Basic bWU6cGE1NQ=="
+            );
+            assert_eq!(headers["x-proxy-token"], "t0ken");
+            assert_eq!(headers["x-trace"], "1");
+        }
+        assert!(
+            mock.requests()
+                .iter()
+                .all(|r| r["key"] == "anki-key")
+        );
+    }
+
+    #[test]
+    fn test_builder_rejects_bad_transport_options() {
+        let error = |builder: AnkiClientBuilder| {
+            builder.build().unwrap_err().to_string()
+        };
+        assert_eq!(
+            error(
+                AnkiClient::builder()
+                    .basic_auth("me", Secret::from("x"))
+                    .header(
+                        "authorization",
+                        Secret::from("Bearer y")
+                    )
+            ),
+            "Basic auth cannot be combined with an extra Authorization header"
+        );
+        assert_eq!(
+            error(
+                AnkiClient::builder()
+                    .basic_auth("a:b", Secret::from("x"))
+            ),
+            "Basic auth username must not contain ':'"
+        );
+        assert_eq!(
+            error(
+                AnkiClient::builder().header(
+                    "bad header",
+                    Secret::from("x")
+                )
+            ),
+            "Invalid header name 'bad header'"
+        );
+        assert!(
+            error(
+                AnkiClient::builder()
+                    .http_client(Client::new())
+                    .danger_accept_invalid_certs(true)
+            )
+            .starts_with("Basic auth, extra headers and TLS options cannot be applied")
+        );
+        assert_eq!(
+            error(
+                AnkiClient::builder().root_certificate_pem(
+                    "not a certificate"
+                )
+            ),
+            "Invalid PEM root certificate: no certificate found"
+        );
+        assert!(
+            AnkiClient::builder()
+                .url("https://anki.example.com")
+                .danger_accept_invalid_certs(true)
+                .build()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_add_notes_params_serialization() {
+        let mut fields = NoteFields::new();
+        fields
+            .insert("Front".to_string(), "Q1".to_string());
+        fields.insert("Back".to_string(), "A1".to_string());
+
+        let note = Note {
+            model_name: "Basic".to_string(),
+            deck_name: "Default".to_string(),
+            fields,
+            tags: vec![],
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        };
+
+        let params = AddNotesParams { notes: vec![note] };
+        let json = serde_json::to_string(&params)
+            .expect("Failed to serialize");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json)
+                .expect("Failed to parse JSON");
+        assert!(parsed["notes"].is_array());
+        assert_eq!(
+            parsed["notes"].as_array().unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_update_note_fields_params_serialization() {
+        let mut fields = NoteFields::new();
+        fields.insert(
+            "Front".to_string(),
+            "New Question".to_string(),
+        );
+
+        let params = UpdateNoteFieldsParams {
+            note: UpdatedNoteFields {
+                id: 12345,
+                fields,
+                audio: None,
+            },
+        };
+
+        let json = serde_json::to_value(&params)
+            .expect("Failed to serialize");
+        assert_eq!(
+            json,
+            json!({
+                "note": {
+                    "id": 12345,
+                    "fields": {"Front": "New Question"},
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_note_fields_sends_nested_note() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!(null))).await;
+        mock.client()
+            .update_note_fields(7, [("Back", "ねこ")], None)
+            .await
+            .unwrap();
+        assert_eq!(
+            mock.requests()[0]["params"],
+            json!({"note": {"id": 7, "fields": {"Back": "ねこ"}}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deck_tree() {
+        let mock = MockAnki::start(|_, _| {
+            ok(json!(["A", "A::B", "A::B::C", "D", "E::F"]))
+        })
+        .await;
+        let tree = mock.client().deck_tree().await.unwrap();
+        let node =
+            |name: &str, full: &str, children| DeckNode {
+                name: name.to_string(),
+                full_name: full.to_string(),
+                children,
+            };
+        assert_eq!(
+            tree,
+            node(
+                "",
+                "",
+                vec![
+                    node(
+                        "A",
+                        "A",
+                        vec![node(
+                            "B",
+                            "A::B",
+                            vec![node(
+                                "C",
+                                "A::B::C",
+                                vec![]
+                            )]
+                        )]
+                    ),
+                    node("D", "D", vec![]),
+                    node(
+                        "E",
+                        "E",
+                        vec![node("F", "E::F", vec![])]
+                    ),
+                ]
+            )
+        );
+        assert_eq!(
+            tree.find("A::B").unwrap().children.len(),
+            1
+        );
+        assert!(tree.find("A::C").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_update_note_field_sends_one_field() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!(null))).await;
+        mock.client()
+            .update_note_field(42, "Back", "猫")
+            .await
+            .unwrap();
+        assert_eq!(
+            mock.requests()[0]["params"],
+            json!({"note": {"id": 42, "fields": {"Back": "猫"}}})
+        );
+    }
+
+    #[test]
+    fn test_find_notes_params_serialization() {
+        let params = FindNotesParams {
+            query: "deck:Default".to_string(),
+        };
+
+        let json = serde_json::to_string(&params)
+            .expect("Failed to serialize");
+        assert_eq!(json, r#"{"query":"deck:Default"}"#);
+    }
+
+    #[test]
+    fn test_notes_info_params_serialization() {
+        let params = NotesInfoParams {
+            notes: vec![123, 456, 789],
+        };
+
+        let json = serde_json::to_string(&params)
+            .expect("Failed to serialize");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json)
+                .expect("Failed to parse JSON");
+        assert_eq!(parsed["notes"][0], 123);
+        assert_eq!(parsed["notes"][1], 456);
+        assert_eq!(parsed["notes"][2], 789);
+    }
+
+    #[test]
+    fn test_note_info_deserialization() {
+        // One entry of a `notesInfo` result as Anki-Connect sends it
+        let json = r#"{
+            "noteId": 1502298033753,
+            "modelName": "Basic",
+            "tags": ["vocab"],
+            "fields": {
+                "Front": {"value": "猫", "order": 0},
+                "Back": {"value": "cat", "order": 1}
+            },
+            "cards": [1498938915662]
+        }"#;
+        let info: NoteInfo = serde_json::from_str(json)
+            .expect("Failed to parse notesInfo entry");
+        assert_eq!(info.note_id, 1502298033753);
+        assert_eq!(info.model_name, "Basic");
+        assert_eq!(info.tags, ["vocab"]);
+        assert_eq!(info.fields["Back"].value, "cat");
+        assert_eq!(info.cards, [1498938915662]);
+    }
+
+    #[test]
+    fn test_cards_info_params_serialization() {
+        let params = CardsInfoParams {
+            cards: vec![111, 222, 333],
+        };
+
+        let json = serde_json::to_string(&params)
+            .expect("Failed to serialize");
+        let parsed: serde_json::Value =
+            serde_json::from_str(&json)
+                .expect("Failed to parse JSON");
+        assert_eq!(parsed["cards"][0], 111);
+        assert_eq!(parsed["cards"][1], 222);
+        assert_eq!(parsed["cards"][2], 333);
+    }
+
+    #[test]
+    fn test_deck_query_escaping() {
+        assert_eq!(
+            deck_query("Default"),
+            r#"deck:"Default""#
+        );
+        assert_eq!(
+            deck_query(r#"My "deck"::a_b*"#),
+            r#"deck:"My \"deck\"::a\_b\*""#
+        );
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_ne!(COMPOSED, DECOMPOSED);
+        assert_eq!(
+            normalize_name(&format!(" {} ", DECOMPOSED)),
+            COMPOSED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deck_exists_with_normalization() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "deckNames" => {
+                    ok(json!(["Default", COMPOSED]))
+                }
+                _ => ok(json!(null)),
+            })
+            .await;
+
+        let strict = mock.client();
+        assert!(
+            !strict.deck_exists(DECOMPOSED).await.unwrap()
+        );
+
+        let lenient =
+            mock.client().with_name_normalization(true);
+        assert!(
+            lenient.deck_exists(DECOMPOSED).await.unwrap()
+        );
+        assert!(
+            lenient
+                .deck_exists(&format!("{}  ", COMPOSED))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !lenient.deck_exists("Deutsch").await.unwrap()
+        );
+        assert_eq!(mock.actions(), vec!["deckNames"; 4]);
+    }
+
+    #[tokio::test]
+    async fn test_invoke_raw_passes_action_through() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "getActiveProfile" => {
+                        ok(json!("User 1"))
+                    }
+                    "guiSelectCard" => {
+                        ok(json!(params["card"] == 7))
+                    }
+                    "futureAction" => {
+                        err("unsupported action")
+                    }
+                    other => panic!(
+                        "unexpected action {}",
+                        other
+                    ),
+                },
+            )
+            .await;
+        let client = mock.client();
+
+        assert_eq!(
+            client
+                .invoke_raw("getActiveProfile", json!(null))
+                .await
+                .unwrap(),
+            json!("User 1")
+        );
+        assert_eq!(
+            client
+                .invoke_raw(
+                    "guiSelectCard",
+                    json!({"card": 7})
+                )
+                .await
+                .unwrap(),
+            json!(true)
+        );
+        let error = client
+            .invoke_raw("futureAction", json!({}))
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", error)
+                .contains("unsupported action")
+        );
+
+        let requests = mock.requests();
+        assert!(requests[0].get("params").is_none());
+        assert_eq!(
+            requests[1]["params"],
+            json!({"card": 7})
+        );
+        assert_eq!(requests[1]["version"], json!(6));
+    }
+
+    #[tokio::test]
+    async fn test_answer_cards_graded() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "answerCards" => ok(params["answers"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|answer| {
+                            answer["cardId"] != 404
+                        })
+                        .collect()),
+                    other => panic!(
+                        "unexpected action {}",
+                        other
+                    ),
+                },
+            )
+            .await;
+        let client = mock.client();
+
+        let error = client
+            .answer_cards_graded(vec![
+                (1, 3),
+                (2, 0),
+                (3, 5),
+            ])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid ease, expected 1 to 4: card 2 has ease 0, card 3 has ease 5"
+        );
+        assert!(mock.actions().is_empty());
+
+        assert_eq!(
+            client
+                .answer_cards_graded(vec![
+                    (11, 1),
+                    (404, 2),
+                    (12, 4)
+                ])
+                .await
+                .unwrap(),
+            [true, false, true]
+        );
+        assert_eq!(
+            mock.requests()[0]["params"],
+            json!({"answers": [
+                {"cardId": 11, "ease": 1},
+                {"cardId": 404, "ease": 2},
+                {"cardId": 12, "ease": 4},
+            ]})
+        );
+        assert!(
+            client
+                .answer_cards_graded(Vec::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(mock.actions(), ["answerCards"]);
+    }
+
+    #[tokio::test]
+    async fn test_recent_notes_newest_first() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "findNotes" => ok(json!([
+                        1_700_000_000_003u64,
+                        1_700_000_000_001u64,
+                        1_700_000_000_005u64,
+                        1_700_000_000_002u64,
+                    ])),
+                    "notesInfo" => ok(params["notes"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|id| {
+                            json!({
+                                "noteId": id,
+                                "modelName": "Basic",
+                                "tags": [],
+                                "fields": {},
+                                "cards": [],
+                            })
+                        })
+                        .collect()),
+                    other => panic!(
+                        "unexpected action {}",
+                        other
+                    ),
+                },
+            )
+            .await;
+        let client = mock.client();
+
+        let ids = |notes: Vec<NoteInfo>| {
+            notes
+                .into_iter()
+                .map(|note| note.note_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids(client
+                .recent_notes("deck:Japanese", 2)
+                .await
+                .unwrap()),
+            [1_700_000_000_005, 1_700_000_000_003]
+        );
+        assert_eq!(
+            mock.requests()[1]["params"]["notes"],
+            json!([
+                1_700_000_000_005u64,
+                1_700_000_000_003u64
+            ])
+        );
+        assert_eq!(
+            ids(client
+                .recent_notes("deck:Japanese", 10)
+                .await
+                .unwrap()),
+            [
+                1_700_000_000_005,
+                1_700_000_000_003,
+                1_700_000_000_002,
+                1_700_000_000_001
+            ]
+        );
+        assert!(
+            client
+                .recent_notes("deck:Japanese", 0)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            mock.actions(),
+            [
+                "findNotes",
+                "notesInfo",
+                "findNotes",
+                "notesInfo",
+                "findNotes"
+            ]
+        );
+    }
+
+    /// Serves one connection by reading the request and hanging up
+    async fn hang_up_server() -> String {
+        use tokio::io::AsyncReadExt;
+
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) =
+                listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_gui_exit_anki_disconnect_is_success() {
+        let client =
+            AnkiClient::with_url(hang_up_server().await);
+        client.gui_exit_anki().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gui_exit_anki_classifies_other_transports()
+     {
+        let failing = |kind| {
+            AnkiClient::with_transport(Box::new(
+                super::super::transport::InProcessTransport::new(
+                    move |_| {
+                        Err(AnkiError::transport_failed(
+                            kind,
+                            "pipe closed",
+                            std::io::Error::from(
+                                std::io::ErrorKind::BrokenPipe,
+                            ),
+                        )
+                        .into())
+                    },
+                ),
+            ))
+        };
+        failing(ConnectionFailure::Disconnected)
+            .gui_exit_anki()
+            .await
+            .unwrap();
+        assert!(
+            failing(ConnectionFailure::Connect)
+                .gui_exit_anki()
+                .await
+                .is_err()
+        );
+    }
+
+    /// Serves one connection by answering with an empty response of
+    /// the given status
+    async fn empty_reply_server(
+        status: &'static str,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) =
+                listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            let reply = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            let _ =
+                stream.write_all(reply.as_bytes()).await;
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_gui_exit_anki_reports_bad_replies() {
+        let client = AnkiClient::with_url(
+            empty_reply_server("500 Internal Server Error")
+                .await,
+        );
+        assert!(client.gui_exit_anki().await.is_err());
+
+        // reqwest refuses the URL before sending anything
+        let client = AnkiClient::with_url("not a url");
+        let error =
+            client.gui_exit_anki().await.unwrap_err();
+        assert!(error.chain().any(|cause| {
+            cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(reqwest::Error::is_builder)
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_gui_exit_anki_reports_unreachable_anki() {
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap();
+        let url = format!(
+            "http://{}",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+
+        let client = AnkiClient::with_url(url);
+        assert!(client.gui_exit_anki().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gui_exit_anki_reports_api_errors() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "guiExitAnki" => err("unsupported action"),
+                _ => ok(json!(null)),
+            })
+            .await;
+        assert!(
+            mock.client().gui_exit_anki().await.is_err()
+        );
+
+        let mock =
+            MockAnki::start(|_, _| ok(json!(null))).await;
+        mock.client().gui_exit_anki().await.unwrap();
+    }
+
+    const FAST_SYNC: SyncWaitOptions = SyncWaitOptions {
+        timeout: Duration::from_millis(500),
+        poll_interval: Duration::from_millis(20),
+    };
+
+    /// Mock where `deckNames` is busy for its first `busy_polls`
+    /// calls and `getActiveProfile` answers when `profile_up`
+    async fn syncing_mock(
+        busy_polls: usize,
+        profile_up: bool,
+    ) -> MockAnki {
+        let polls = Arc::new(
+            std::sync::atomic::AtomicUsize::new(0),
+        );
+        MockAnki::start(move |action, _| match action {
+            "sync" => ok(json!(null)),
+            "deckNames" => {
+                let n = polls.fetch_add(
+                    1,
+                    std::sync::atomic::Ordering::SeqCst,
+                );
+                if n < busy_polls {
+                    err("collection is not available")
+                } else {
+                    ok(json!(["Default"]))
+                }
+            }
+            "getActiveProfile" if profile_up => {
+                ok(json!("User 1"))
+            }
+            "getActiveProfile" => err("busy syncing"),
+            other => panic!("unexpected action {}", other),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_sync_and_wait_completes_after_busy_polls()
+    {
+        let mock = syncing_mock(3, false).await;
+        let outcome = mock
+            .client()
+            .sync_and_wait(FAST_SYNC)
+            .await
+            .unwrap();
+        assert_eq!(outcome, SyncOutcome::Completed);
+        let actions = mock.actions();
+        assert_eq!(actions[0], "sync");
+        assert_eq!(
+            actions
+                .iter()
+                .filter(|a| *a == "deckNames")
+                .count(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_and_wait_reports_maybe_conflict() {
+        let mock = syncing_mock(usize::MAX, true).await;
+        let outcome = mock
+            .client()
+            .sync_and_wait(FAST_SYNC)
+            .await
+            .unwrap();
+        assert_eq!(outcome, SyncOutcome::MaybeConflict);
+        assert_eq!(
+            mock.actions(),
+            [
+                "sync",
+                "deckNames",
+                "getActiveProfile",
+                "deckNames",
+                "getActiveProfile"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_and_wait_times_out_while_busy() {
+        let mock = syncing_mock(usize::MAX, false).await;
+        let outcome = mock
+            .client()
+            .sync_and_wait(FAST_SYNC)
+            .await
+            .unwrap();
+        assert_eq!(outcome, SyncOutcome::TimedOut);
+    }
+
+    #[tokio::test(
+        flavor = "multi_thread",
+        worker_threads = 2
+    )]
+    async fn test_sync_and_wait_treats_hanging_probe_as_busy()
+     {
+        let started = std::time::Instant::now();
+        let mock = MockAnki::start(move |action, _| {
+            if action != "sync"
+                && started.elapsed()
+                    < Duration::from_millis(150)
+            {
+                std::thread::sleep(Duration::from_millis(
+                    60,
+                ));
+            }
+            ok(json!(null))
+        })
+        .await;
+        let outcome = mock
+            .client()
+            .sync_and_wait(FAST_SYNC)
+            .await
+            .unwrap();
+        assert_eq!(outcome, SyncOutcome::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_sync_and_wait_unreachable_and_failing_sync()
+     {
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap();
+        let url = format!(
+            "http://{}",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+        let error = AnkiClient::with_url(url)
+            .sync_and_wait(FAST_SYNC)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AnkiError>(),
+            Some(AnkiError::ConnectionFailed { .. })
+        ));
+
+        let mock =
+            MockAnki::start(|_, _| err("auth not set up"))
+                .await;
+        assert!(
+            mock.client()
+                .sync_and_wait(FAST_SYNC)
+                .await
+                .is_err()
+        );
+        assert_eq!(mock.actions(), ["sync"]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_and_wait_fails_when_sync_is_not_set_up()
+     {
+        // Anki answers every other request, so probing would
+        // report a finished sync that never ran
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "sync" => err("sync: auth not configured"),
+                _ => ok(json!(["Default"])),
+            })
+            .await;
+        let error = mock
+            .client()
+            .sync_and_wait(FAST_SYNC)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Anki-Connect error: sync: auth not configured"
+        );
+        assert_eq!(mock.actions(), ["sync"]);
+    }
+
+    #[tokio::test]
+    async fn test_review_current_card_sequence() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "guiCurrentCard" => ok(
+                    json!({"cardId": 1, "question": "q"}),
+                ),
+                _ => ok(json!(true)),
+            })
+            .await;
+        mock.client().review_current_card(3).await.unwrap();
+        assert_eq!(
+            mock.actions(),
+            [
+                "guiCurrentCard",
+                "guiStartCardTimer",
+                "guiShowQuestion",
+                "guiShowAnswer",
+                "guiAnswerCard"
+            ]
+        );
+        assert_eq!(
+            mock.requests()[4]["params"],
+            json!({"ease": 3})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_review_current_card_stops_at_refused_step()
+     {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "guiCurrentCard" => {
+                    ok(json!({"cardId": 1}))
+                }
+                "guiShowAnswer" => ok(json!(false)),
+                _ => ok(json!(true)),
+            })
+            .await;
+        let client = mock.client();
+        let error = client
+            .review_current_card(1)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("guiShowAnswer"),
+            "{}",
+            error
+        );
+        assert!(
+            !mock
+                .actions()
+                .iter()
+                .any(|a| a == "guiAnswerCard")
+        );
+
+        assert!(
+            client.review_current_card(5).await.is_err()
+        );
+        let idle =
+            MockAnki::start(|_, _| ok(json!(null))).await;
+        assert!(
+            idle.client()
+                .review_current_card(3)
+                .await
+                .is_err()
+        );
+        assert_eq!(idle.actions(), ["guiCurrentCard"]);
+    }
+
+    #[test]
+    fn test_empty_reply_is_not_a_disconnect() {
+        let eof =
+            serde_json::from_str::<serde_json::Value>("")
+                .unwrap_err();
+        let error =
+            anyhow::Error::new(eof).context("parse");
+        assert!(!is_disconnect_after_send(&error));
+        assert!(!is_disconnect_after_send(
+            &anyhow::anyhow!("Anki-Connect error: boom")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_due_counts_by_deck() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "findCards" => {
+                        assert_eq!(
+                            params["query"],
+                            "is:due"
+                        );
+                        ok(json!([1, 2, 3, 4]))
+                    }
+                    "getDecks" => {
+                        assert_eq!(
+                            params["cards"],
+                            json!([1, 2, 3, 4])
+                        );
+                        ok(json!({
+                            "Japanese::Vocab": [1, 3, 4],
+                            "Default": [2],
+                            "Empty": [],
+                        }))
+                    }
+                    _ => ok(json!(null)),
+                },
+            )
+            .await;
+
+        let counts = mock
+            .client()
+            .due_counts_by_deck()
+            .await
+            .unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["Japanese::Vocab"], 3);
+        assert_eq!(counts["Default"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_due_counts_by_deck_without_due_cards() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!([]))).await;
+        let counts = mock
+            .client()
+            .due_counts_by_deck()
+            .await
+            .unwrap();
+        assert!(counts.is_empty());
+        assert_eq!(mock.actions(), vec!["findCards"]);
+    }
+
+    #[tokio::test]
+    async fn test_from_handle_uses_reloaded_key() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!(6))).await;
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_client_handle_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let write_key = |key: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "anki_connect_url = \"{}\"\nanki_connect_key = \"{}\"\n",
+                    mock.client().url(),
+                    key
+                ),
+            )
+            .unwrap()
+        };
+        write_key("old-key");
+        let settings = SettingsHandle::from_paths(
+            vec![path.clone()],
+            None,
+            Some(std::collections::HashMap::new()),
+        )
+        .unwrap();
+        let client =
+            AnkiClient::from_handle(&settings).unwrap();
+
+        client.version().await.unwrap();
+        write_key("new-key");
+        settings.reload().unwrap();
+        client.version().await.unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(requests[0]["key"], "old-key");
+        assert_eq!(requests[1]["key"], "new-key");
+    }
+
+    /// Mock whose search finds `ids` and whose notesInfo echoes the
+    /// requested IDs
+    async fn detailed_mock(ids: Vec<u64>) -> MockAnki {
+        MockAnki::start(
+            move |action, params| match action {
+                "findNotes" => ok(json!(ids)),
+                "notesInfo" => ok(params["notes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| {
+                        json!({
+                            "noteId": id,
+                            "tags": [],
+                            "fields": {},
+                            "modelName": "Basic",
+                            "cards": [],
+                        })
+                    })
+                    .collect()),
+                _ => ok(json!(null)),
+            },
         )
         .await
     }
 
-    /// Gets the field names for a specific model
-    pub async fn get_model_field_names(
-        &self,
-        model_name: &str,
-    ) -> Result<Vec<String>> {
-        let params = GetModelFieldNamesParams {
-            model_name: model_name.to_string(),
-        };
-        self.invoke("getModelFieldNames", Some(params))
+    fn chunk_sizes(mock: &MockAnki) -> Vec<usize> {
+        mock.requests()
+            .iter()
+            .filter(|r| r["action"] == "notesInfo")
+            .map(|r| {
+                r["params"]["notes"]
+                    .as_array()
+                    .unwrap()
+                    .len()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_find_notes_detailed_chunks_in_order() {
+        let ids: Vec<u64> = (1..=1201).rev().collect();
+        let mock = detailed_mock(ids.clone()).await;
+        let notes = mock
+            .client()
+            .find_notes_detailed("deck:Big", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            notes
+                .iter()
+                .map(|n| n.note_id)
+                .collect::<Vec<_>>(),
+            ids
+        );
+        assert_eq!(chunk_sizes(&mock), vec![500, 500, 201]);
+        assert_eq!(
+            mock.requests()[2]["params"]["notes"][0],
+            701
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_notes_detailed_limit_stops_early() {
+        let mock =
+            detailed_mock((1..=1201).collect()).await;
+        let notes = mock
+            .client()
+            .find_notes_detailed("deck:Big", Some(50))
+            .await
+            .unwrap();
+        assert_eq!(notes.len(), 50);
+        assert_eq!(notes[49].note_id, 50);
+        assert_eq!(chunk_sizes(&mock), vec![50]);
+
+        let mock = detailed_mock((1..=600).collect()).await;
+        let notes = mock
+            .client()
+            .find_notes_detailed("deck:Big", Some(0))
+            .await
+            .unwrap();
+        assert!(notes.is_empty());
+        assert_eq!(mock.actions(), vec!["findNotes"]);
+    }
+
+    #[tokio::test]
+    async fn test_find_notes_detailed_without_matches() {
+        let mock = detailed_mock(Vec::new()).await;
+        assert!(
+            mock.client()
+                .find_notes_detailed("deck:Empty", Some(10))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(mock.actions(), vec!["findNotes"]);
+    }
+
+    #[tokio::test]
+    async fn test_count_notes_and_cards() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "findNotes" => ok(json!([4, 5, 6])),
+                "findCards" => ok(json!([])),
+                _ => ok(json!(null)),
+            })
+            .await;
+        let client = mock.client();
+        assert_eq!(
+            client.count_notes("deck:A").await.unwrap(),
+            3
+        );
+        assert_eq!(
+            client.count_cards("is:due").await.unwrap(),
+            0
+        );
+        assert_eq!(
+            mock.actions(),
+            vec!["findNotes", "findCards"]
+        );
+        assert_eq!(
+            mock.requests()[1]["params"]["query"],
+            "is:due"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_review_forecast_buckets_by_day() {
+        let mock = MockAnki::start(|action, params| {
+            assert_eq!(action, "findCards");
+            let query = params["query"].as_str().unwrap();
+            let due = query
+                .split_whitespace()
+                .find_map(|term| {
+                    term.strip_prefix("prop:due")
+                })
+                .unwrap();
+            let cards: Vec<u64> = match due {
+                "<=0" => vec![1, 2, 3],
+                "=2" => vec![4],
+                "=3" => vec![5, 6],
+                _ => vec![],
+            };
+            ok(json!(cards))
+        })
+        .await;
+
+        let forecast = mock
+            .client()
+            .review_forecast("Japanese", 4)
+            .await
+            .unwrap();
+        assert_eq!(forecast, [3, 0, 1, 2]);
+        assert_eq!(
+            mock.requests()[0]["params"]["query"],
+            "deck:\"Japanese\" prop:due<=0 -is:suspended"
+        );
+        assert!(
+            mock.client()
+                .review_forecast("Japanese", 0)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(mock.actions().len(), 4);
+    }
+
+    fn card_json(id: u64, deck: &str) -> serde_json::Value {
+        json!({
+            "cardId": id,
+            "note": id * 10,
+            "deckName": deck,
+            "modelName": "Basic",
+            "ord": 0,
+            "type": 2,
+            "queue": 2,
+            "due": 100,
+            "interval": 3,
+            "factor": 2500,
+            "reps": 1,
+            "lapses": 0,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_find_cards_detailed_chunks_in_order() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "findCards" => {
+                        ok(json!([5, 3, 9, 1, 7]))
+                    }
+                    "cardsInfo" => ok(params["cards"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|id| {
+                            let id = id.as_u64().unwrap();
+                            card_json(
+                                id,
+                                if id > 4 {
+                                    "B"
+                                } else {
+                                    "A"
+                                },
+                            )
+                        })
+                        .collect()),
+                    _ => ok(json!(null)),
+                },
+            )
+            .await;
+        let client = mock.client().with_info_chunk(2);
+        let cards = client
+            .find_cards_detailed("is:due")
+            .await
+            .unwrap();
+        assert_eq!(
+            cards
+                .iter()
+                .map(|c| c.card_id)
+                .collect::<Vec<_>>(),
+            vec![5, 3, 9, 1, 7]
+        );
+        let chunks: Vec<serde_json::Value> = mock
+            .requests()
+            .iter()
+            .filter(|r| r["action"] == "cardsInfo")
+            .map(|r| r["params"]["cards"].clone())
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![json!([5, 3]), json!([9, 1]), json!([7])]
+        );
+
+        let grouped = group_by_deck(cards);
+        let ids = |deck: &str| {
+            grouped[deck]
+                .iter()
+                .map(|c| c.card_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            grouped.keys().collect::<Vec<_>>(),
+            vec!["A", "B"]
+        );
+        assert_eq!(ids("A"), vec![3, 1]);
+        assert_eq!(ids("B"), vec![5, 9, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_max_response_size_guard() {
+        let mock =
+            MockAnki::start(
+                |action, params| {
+                    match action {
+                "findNotes" => ok(json!([1, 2, 3])),
+                "notesInfo" => ok(params["notes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| {
+                        json!({
+                            "noteId": id,
+                            "modelName": "Basic",
+                            "tags": [],
+                            "fields": {"Front": {
+                                "value": "x".repeat(4000),
+                                "order": 0
+                            }},
+                            "cards": [],
+                        })
+                    })
+                    .collect()),
+                _ => err("unsupported action"),
+            }
+                },
+            )
+            .await;
+        let client = AnkiClient::builder()
+            .url(mock.client().url())
+            .max_response_size(6000)
+            .info_chunk(1)
+            .build()
+            .unwrap();
+        assert_eq!(
+            client
+                .find_notes_detailed("deck:Default", None)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let client = client.with_info_chunk(2);
+        let error = client
+            .find_notes_detailed("deck:Default", None)
+            .await
+            .unwrap_err();
+        let too_large = error
+            .downcast_ref::<ResponseTooLarge>()
+            .unwrap();
+        assert!(
+            too_large.size > 8000,
+            "{}",
+            too_large.size
+        );
+        assert_eq!(too_large.limit, 6000);
+        assert!(
+            error.to_string().starts_with(&format!(
+                "Anki-Connect response to notesInfo is at least {} bytes, over the limit of 6000",
+                too_large.size
+            )),
+            "{}",
+            error
+        );
+
+        assert!(
+            AnkiClient::builder()
+                .max_response_size(0)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_info_chunk_must_be_positive() {
+        assert!(
+            AnkiClient::builder()
+                .info_chunk(0)
+                .build()
+                .is_err()
+        );
+        assert!(
+            AnkiClient::builder()
+                .info_chunk(50)
+                .build()
+                .is_ok()
+        );
+    }
+
+    /// Mock answering the lookups of the auto-backup flow
+    async fn backup_mock() -> MockAnki {
+        MockAnki::start(|action, _| match action {
+            "findCards" => ok(json!([11, 12])),
+            "getDecks" => ok(json!({
+                "Japanese::Vocab": [11],
+                "Default": [12],
+            })),
+            "exportPackage" => ok(json!(true)),
+            _ => ok(json!(null)),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_delete_notes_matching_and_remove_empty_notes()
+     {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "findNotes"
+                        if params["query"]
+                            == "tag:import::bad" =>
+                    {
+                        ok(json!([4, 5]))
+                    }
+                    "findNotes" => ok(json!([])),
+                    "deleteNotes" | "removeEmptyNotes" => {
+                        ok(json!(null))
+                    }
+                    _ => err("unsupported action"),
+                },
+            )
+            .await;
+        let client = mock.client();
+
+        assert_eq!(
+            client
+                .delete_notes_matching("tag:import::bad")
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            client
+                .delete_notes_matching("tag:import::good")
+                .await
+                .unwrap(),
+            0
+        );
+        client.remove_empty_notes().await.unwrap();
+
+        assert_eq!(
+            mock.actions(),
+            [
+                "findNotes",
+                "deleteNotes",
+                "findNotes",
+                "removeEmptyNotes"
+            ]
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[1]["params"],
+            json!({"notes": [4, 5]})
+        );
+        assert!(requests[3].get("params").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auto_backup_exports_before_delete() {
+        let mock = backup_mock().await;
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_backup_{}",
+            std::process::id()
+        ));
+        let client = mock
+            .client()
+            .with_auto_backup(Some(dir.clone()));
+
+        client.delete_notes(vec![1, 2]).await.unwrap();
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "findCards",
+                "getDecks",
+                "exportPackage",
+                "exportPackage",
+                "deleteNotes"
+            ]
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[0]["params"]["query"],
+            "nid:1,2"
+        );
+        assert_eq!(
+            requests[2]["params"]["deck"],
+            "Default"
+        );
+        assert_eq!(
+            requests[3]["params"]["deck"],
+            "Japanese::Vocab"
+        );
+        assert_eq!(
+            requests[3]["params"]["includeSched"],
+            true
+        );
+        let path =
+            requests[3]["params"]["path"].as_str().unwrap();
+        assert!(path.starts_with(
+            dir.join("Japanese__Vocab-").to_str().unwrap()
+        ));
+        assert!(path.ends_with(".apkg"));
+
+        client
+            .delete_decks(vec!["Old".to_string()])
+            .await
+            .unwrap();
+        client.forget_cards(vec![11]).await.unwrap();
+        assert_eq!(
+            mock.actions()[5..],
+            [
+                "exportPackage",
+                "deleteDecks",
+                "getDecks",
+                "exportPackage",
+                "exportPackage",
+                "forgetCards"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_backup_prevents_delete() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "exportPackage" => ok(json!(false)),
+                _ => ok(json!(null)),
+            })
+            .await;
+        let error = mock
+            .client()
+            .with_auto_backup(Some(std::env::temp_dir()))
+            .delete_decks(vec!["Old".to_string()])
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", error)
+                .contains("nothing was changed")
+        );
+        assert_eq!(mock.actions(), vec!["exportPackage"]);
+    }
+
+    #[tokio::test]
+    async fn test_no_backup_by_default() {
+        let mock = backup_mock().await;
+        let client = mock.client();
+        client.delete_notes(vec![1]).await.unwrap();
+        client
+            .delete_decks(vec!["Old".to_string()])
+            .await
+            .unwrap();
+        client.forget_cards(vec![11]).await.unwrap();
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "deleteNotes",
+                "deleteDecks",
+                "forgetCards"
+            ]
+        );
+        assert_eq!(
+            mock.requests()[1]["params"]["cardsToo"],
+            true
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_lists_send_no_request() {
+        let mock = MockAnki::start(|action, _| {
+            panic!("unexpected request {}", action)
+        })
+        .await;
+        let client = mock.client();
+
+        assert!(
+            client
+                .notes_info(vec![])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            client
+                .cards_info(vec![])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            client
+                .get_decks(vec![])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            client
+                .add_notes(vec![])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        client.delete_notes(vec![]).await.unwrap();
+        client.delete_decks(vec![]).await.unwrap();
+        client.forget_cards(vec![]).await.unwrap();
+        client.suspend_cards(vec![]).await.unwrap();
+        client.set_deck_config_id(vec![], 1).await.unwrap();
+        assert!(
+            client
+                .media_files_exist(vec![])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(mock.requests().is_empty());
+    }
+
+    fn vocab_note() -> Note {
+        Note {
+            model_name: "Basic".to_string(),
+            deck_name: "Default".to_string(),
+            fields: NoteFields::from([
+                ("Front", "猫_1"),
+                ("Back", "cat"),
+            ]),
+            tags: vec![],
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_notes_tagged_tags_only_added_notes() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "addNotes" => ok(json!([7, null, 9])),
+                "addTags" => ok(json!(null)),
+                other => {
+                    panic!("unexpected action {}", other)
+                }
+            })
+            .await;
+
+        let report = mock
+            .client()
+            .add_notes_tagged(
+                vec![vocab_note(); 3],
+                " ai::generated  review ",
+            )
+            .await
+            .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(
+            report.note_ids,
+            vec![Some(7), None, Some(9)]
+        );
+        assert_eq!(mock.actions(), ["addNotes", "addTags"]);
+        assert_eq!(
+            mock.requests()[1]["params"],
+            json!({
+                "notes": [7, 9],
+                "tags": " ai::generated  review ",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_notes_tagged_keeps_ids_when_tagging_fails()
+     {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "addNotes" => ok(json!([7, 9])),
+                _ => err("collection is not available"),
+            })
+            .await;
+
+        let report = mock
+            .client()
+            .add_notes_tagged(
+                vec![vocab_note(); 2],
+                "review",
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.note_ids, vec![Some(7), Some(9)]);
+        assert_eq!(
+            report.tag_failure.as_deref(),
+            Some(
+                "Added 2 notes but failed to tag them: Anki-Connect error: collection is not available"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_note_duplicate_reports_existing_note()
+    {
+        let mock = MockAnki::start(|action, _| match action {
+            "addNote" => err(
+                "cannot create note because it is a duplicate",
+            ),
+            "modelFieldNames" => ok(json!(["Front", "Back"])),
+            "findNotes" => ok(json!([42, 17])),
+            _ => ok(serde_json::Value::Null),
+        })
+        .await;
+
+        let error = mock
+            .client()
+            .add_note(vocab_note())
             .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AnkiError>(),
+            Some(&AnkiError::Duplicate {
+                existing_note_id: Some(17)
+            })
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[2]["params"]["query"],
+            "\"note:Basic\" \"Front:猫\\_1\""
+        );
     }
 
-    /// Adds a single note to Anki
-    pub async fn add_note(
-        &self,
-        note: Note,
-    ) -> Result<u64> {
-        let params = AddNoteParams { note };
-        self.invoke("addNote", Some(params)).await
+    #[tokio::test]
+    async fn test_add_note_duplicate_without_lookup() {
+        let mock = MockAnki::start(|action, _| match action {
+            "addNote" => err(
+                "cannot create note because it is a duplicate",
+            ),
+            _ => err("model was not found: Basic"),
+        })
+        .await;
+
+        let error = mock
+            .client()
+            .add_note(vocab_note())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AnkiError>(),
+            Some(&AnkiError::Duplicate {
+                existing_note_id: None
+            })
+        );
     }
 
-    /// Adds multiple notes to Anki in a single request
-    pub async fn add_notes(
-        &self,
-        notes: Vec<Note>,
-    ) -> Result<Vec<Option<u64>>> {
-        let params = AddNotesParams { notes };
-        self.invoke("addNotes", Some(params)).await
+    #[tokio::test]
+    async fn test_add_note_other_errors_pass_through() {
+        let mock = MockAnki::start(|_, _| {
+            err("deck was not found: Missing")
+        })
+        .await;
+
+        let error = mock
+            .client()
+            .add_note(vocab_note())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AnkiError>(),
+            Some(&AnkiError::deck_not_found("Missing"))
+        );
+        assert_eq!(mock.actions(), vec!["addNote"]);
     }
 
-    /// Finds notes matching the given query
-    pub async fn find_notes(
-        &self,
-        query: &str,
-    ) -> Result<Vec<u64>> {
-        let params = FindNotesParams {
-            query: query.to_string(),
+    #[tokio::test]
+    async fn test_invoke_errors_are_typed() {
+        let mock = MockAnki::start(|action, params| {
+            match (action, params["query"].as_str()) {
+                ("findNotes", Some("deck")) => {
+                    err("deck was not found: Missing")
+                }
+                ("findNotes", Some("model")) => {
+                    err("model was not found: Cloze2")
+                }
+                ("findNotes", Some("shape")) => {
+                    json!({"unexpected": true})
+                }
+                ("addNote", _) => err(
+                    "cannot create note because it is a duplicate",
+                ),
+                _ => err("collection is not available"),
+            }
+        })
+        .await;
+        let client = mock.client();
+        let typed = |error: anyhow::Error| {
+            error.downcast_ref::<AnkiError>().cloned()
         };
-        self.invoke("findNotes", Some(params)).await
+
+        let error =
+            client.find_notes("deck").await.unwrap_err();
+        assert_eq!(
+            typed(error),
+            Some(AnkiError::deck_not_found("Missing"))
+        );
+        let error =
+            client.find_notes("model").await.unwrap_err();
+        assert_eq!(
+            typed(error),
+            Some(AnkiError::model_not_found("Cloze2"))
+        );
+        let error =
+            client.find_notes("shape").await.unwrap_err();
+        assert!(error.chain().any(|cause| {
+            cause
+                .downcast_ref::<serde_json::Error>()
+                .is_some()
+        }));
+        assert!(matches!(
+            typed(error),
+            Some(AnkiError::InvalidResponse { .. })
+        ));
+        let error = client
+            .add_note(vocab_note())
+            .await
+            .unwrap_err();
+        assert!(typed(error).unwrap().is_duplicate());
+        let error = client
+            .update_note_fields(1, NoteFields::new(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            typed(error),
+            Some(AnkiError::Api {
+                error: "collection is not available"
+                    .to_string(),
+                detail: None
+            })
+        );
+
+        let client = AnkiClient::with_transport(Box::new(
+            super::super::transport::InProcessTransport::new(
+                |_| Ok(bytes::Bytes::from_static(b"<html>")),
+            ),
+        ));
+        let error =
+            client.find_notes("a").await.unwrap_err();
+        assert!(matches!(
+            typed(error),
+            Some(AnkiError::InvalidResponse { message, .. })
+                if message == "Failed to parse Anki-Connect response"
+        ));
+
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap();
+        let url = format!(
+            "http://{}",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+        let error = AnkiClient::with_url(url)
+            .find_notes("a")
+            .await
+            .unwrap_err();
+        assert!(error.chain().any(|cause| {
+            cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(reqwest::Error::is_connect)
+        }));
+        let Some(AnkiError::ConnectionFailed {
+            message,
+            kind,
+            source,
+        }) = typed(error)
+        else {
+            panic!("expected a connection failure");
+        };
+        assert_eq!(
+            message,
+            "Failed to send request to Anki-Connect"
+        );
+        assert_eq!(kind, ConnectionFailure::Connect);
+        assert!(
+            source
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(reqwest::Error::is_connect)
+        );
     }
 
-    /// Gets detailed information about notes
-    pub async fn notes_info(
-        &self,
-        note_ids: Vec<u64>,
-    ) -> Result<Vec<NoteInfo>> {
-        let params = NotesInfoParams { notes: note_ids };
-        self.invoke("notesInfo", Some(params)).await
+    #[tokio::test]
+    async fn test_version_accepts_numbers_and_strings() {
+        for (reply, expected) in [
+            (json!(6), 6),
+            (json!("6"), 6),
+            (json!(" 5 "), 5),
+        ] {
+            let mock = MockAnki::start(move |_, _| {
+                ok(reply.clone())
+            })
+            .await;
+            assert_eq!(
+                mock.client().version().await.unwrap(),
+                expected
+            );
+        }
+
+        for bad in [
+            json!("six"),
+            json!(-1),
+            json!(6.5),
+            serde_json::Value::Null,
+        ] {
+            assert_eq!(
+                parse_version(&bad)
+                    .unwrap_err()
+                    .to_string(),
+                format!(
+                    "Invalid Anki-Connect version {}: expected a non-negative integer",
+                    bad
+                )
+            );
+        }
     }
 
-    /// Updates fields of an existing note
-    pub async fn update_note_fields(
-        &self,
-        note_id: u64,
-        fields: std::collections::HashMap<String, String>,
-        audio: Option<Vec<NoteAudio>>,
-    ) -> Result<()> {
-        let params = UpdateNoteFieldsParams {
-            note: note_id,
-            fields,
-            audio,
+    #[tokio::test]
+    async fn test_import_notes_json_skips_missing_models() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "modelNames" => {
+                        ok(json!(["Basic", "Vocab"]))
+                    }
+                    "addNotes" => {
+                        assert_eq!(
+                            params["notes"]
+                                .as_array()
+                                .unwrap()
+                                .len(),
+                            2
+                        );
+                        ok(json!([100, null]))
+                    }
+                    _ => err("unsupported action"),
+                },
+            )
+            .await;
+        let dump = r#"[
+            {"model": "Vocab", "tags": ["n5"], "fields": {"Word": "猫", "Meaning": "cat"}},
+            {"model": "Cloze++", "tags": [], "fields": {"Text": "{{c1::x}}"}},
+            {"model": "Basic", "fields": {"Front": "a", "Back": "b"}},
+            {"model": "Cloze++", "tags": [], "fields": {"Text": "{{c1::y}}"}}
+        ]"#;
+
+        let outcomes = mock
+            .client()
+            .import_notes_json(dump, "Imported")
+            .await
+            .unwrap();
+        let skipped = JsonImport::Skipped {
+            model: "Cloze++".to_string(),
         };
-        let result: bool = self
-            .invoke("updateNoteFields", Some(params))
-            .await?;
-        if result {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!(
-                "Failed to update note fields"
-            ))
-        }
+        assert_eq!(
+            outcomes,
+            [
+                JsonImport::Added(100),
+                skipped.clone(),
+                JsonImport::Rejected,
+                skipped
+            ]
+        );
+        assert_eq!(
+            mock.actions(),
+            [
+                "modelNames",
+                "modelNames",
+                "modelNames",
+                "addNotes"
+            ]
+        );
+        let sent = &mock.requests()[3]["params"]["notes"];
+        assert_eq!(sent[0]["deckName"], "Imported");
+        assert_eq!(sent[0]["modelName"], "Vocab");
+        assert_eq!(sent[0]["fields"]["Word"], "猫");
+        assert_eq!(sent[0]["tags"], json!(["n5"]));
+        assert_eq!(sent[1]["fields"]["Back"], "b");
+
+        let error = mock
+            .client()
+            .import_notes_json(
+                r#"{"model": "Basic"}"#,
+                "Imported",
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with(
+                "Failed to parse the notes JSON"
+            )
+        );
+        assert_eq!(mock.requests().len(), 4);
     }
 
-    /// Gets detailed information about cards
-    pub async fn cards_info(
-        &self,
-        card_ids: Vec<u64>,
-    ) -> Result<Vec<CardInfo>> {
-        let params = CardsInfoParams { cards: card_ids };
-        self.invoke("cardsInfo", Some(params)).await
+    #[tokio::test]
+    async fn test_export_notes_json_keeps_field_order() {
+        let mock = MockAnki::start(|action, params| match action {
+            "findNotes" => ok(json!([2, 1])),
+            "notesInfo" => {
+                assert_eq!(params["notes"], json!([2, 1]));
+                ok(json!([
+                    {
+                        "noteId": 2,
+                        "modelName": "Vocab",
+                        "tags": ["n5", "animal"],
+                        "fields": {
+                            "Word": {"value": "猫", "order": 0},
+                            "Reading": {"value": "ねこ", "order": 2},
+                            "Meaning": {"value": "cat", "order": 1}
+                        },
+                        "cards": [20]
+                    },
+                    {
+                        "noteId": 1,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {
+                            "Front": {"value": "a", "order": 0},
+                            "Back": {"value": "<b>b</b>", "order": 1}
+                        },
+                        "cards": [10]
+                    }
+                ]))
+            }
+            _ => err("unsupported action"),
+        })
+        .await;
+
+        let dump = mock
+            .client()
+            .export_notes_json("deck:Japanese")
+            .await
+            .unwrap();
+        assert_eq!(
+            dump,
+            r#"[
+  {
+    "model": "Vocab",
+    "tags": [
+      "n5",
+      "animal"
+    ],
+    "fields": {
+      "Word": "猫",
+      "Meaning": "cat",
+      "Reading": "ねこ"
     }
+  },
+  {
+    "model": "Basic",
+    "tags": [],
+    "fields": {
+      "Front": "a",
+      "Back": "<b>b</b>"
+    }
+  }
+]"#
+        );
+        assert_eq!(
+            mock.requests()[0]["params"]["query"],
+            "deck:Japanese"
+        );
 
-    /// Finds cards matching the given query
-    pub async fn find_cards(
-        &self,
-        query: &str,
-    ) -> Result<Vec<u64>> {
-        let params = FindCardsParams {
-            query: query.to_string(),
-        };
-        self.invoke("findCards", Some(params)).await
+        let empty =
+            MockAnki::start(|_, _| ok(json!([]))).await;
+        assert_eq!(
+            empty
+                .client()
+                .export_notes_json("deck:None")
+                .await
+                .unwrap(),
+            "[]"
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Mock of an Anki-Connect server reporting `version`, which
+    /// answers `deckNames` bare when it is older than 6 and knows
+    /// nothing but `version`, `deckNames` and `answerCards`
+    async fn versioned_anki(version: u32) -> MockAnki {
+        MockAnki::start(move |action, _| match action {
+            "version" if version < 6 => json!(version),
+            "version" => ok(version.into()),
+            "deckNames" if version < 6 => {
+                json!(["Default"])
+            }
+            "deckNames" => ok(json!(["Default"])),
+            "answerCards" => ok(json!([true])),
+            _ => err("unsupported action"),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_v5_compatibility_mode() {
+        let mock = versioned_anki(5).await;
+        let client = mock.client();
+        assert_eq!(client.server_version(), None);
+        assert!(client.get_deck_names(None).await.is_err());
 
-    #[test]
-    fn test_anki_request_serialization() {
-        let request =
-            AnkiRequest::new("version", 6, None::<()>);
-        let json = serde_json::to_string(&request)
-            .expect("Failed to serialize request");
         assert_eq!(
-            json,
-            r#"{"action":"version","version":6}"#
+            client.ensure_compatible().await.unwrap(),
+            5
+        );
+        let clone = client.clone();
+        assert_eq!(clone.server_version(), Some(5));
+        assert_eq!(
+            clone.get_deck_names(None).await.unwrap(),
+            ["Default"]
+        );
+        assert_eq!(
+            mock.requests().last().unwrap()["version"],
+            5
+        );
+
+        let sent = mock.requests().len();
+        let error = client
+            .answer_cards(vec![CardAnswer {
+                card_id: 1,
+                ease: 3,
+            }])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AnkiError>(),
+            Some(&AnkiError::UnsupportedByServer {
+                action: "answerCards".to_string(),
+                server_version: Some(5),
+            })
+        );
+        assert_eq!(mock.requests().len(), sent);
+
+        let error = client
+            .get_deck_config("Default")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Anki-Connect error: unsupported action getDeckConfig (API version 5)"
         );
     }
 
-    #[test]
-    fn test_anki_request_with_params_serialization() {
-        let params =
-            GetDeckNamesParams { cards: Some(true) };
-        let request = AnkiRequest::new(
-            "getDeckNames",
-            6,
-            Some(params),
+    #[tokio::test]
+    async fn test_v6_keeps_current_behavior() {
+        let mock = versioned_anki(6).await;
+        let client = mock.client();
+        assert_eq!(
+            client.ensure_compatible().await.unwrap(),
+            6
         );
-        let json = serde_json::to_string(&request)
-            .expect("Failed to serialize request");
-        assert!(json.contains(r#"action":"getDeckNames""#));
-        assert!(json.contains(r#"version":6"#));
-        assert!(json.contains(r#"cards":true"#));
+        assert_eq!(
+            client.get_deck_names(None).await.unwrap(),
+            ["Default"]
+        );
+        assert_eq!(
+            client
+                .answer_cards(vec![CardAnswer {
+                    card_id: 1,
+                    ease: 3,
+                }])
+                .await
+                .unwrap(),
+            [true]
+        );
+        assert!(
+            mock.requests()
+                .iter()
+                .all(|r| r["version"] == 6)
+        );
+
+        let error = client
+            .get_deck_config("Default")
+            .await
+            .unwrap_err();
+        assert!(
+            error.downcast_ref::<AnkiError>().is_some_and(
+                AnkiError::is_unsupported_action
+            )
+        );
+
+        let old = versioned_anki(4).await.client();
+        let error =
+            old.ensure_compatible().await.unwrap_err();
+        assert!(error.to_string().starts_with(
+            "Anki-Connect API version 4 is not supported"
+        ));
+        assert_eq!(old.server_version(), None);
     }
 
-    #[test]
-    fn test_anki_response_success_deserialization() {
-        let json = r#"{"result":12345}"#;
-        let response: AnkiResponse<u64> =
-            serde_json::from_str(json)
-                .expect("Failed to deserialize response");
-        match response {
-            AnkiResponse::Success { result } => {
-                assert_eq!(result, 12345)
-            }
-            AnkiResponse::Error { .. } => {
-                panic!("Expected success response")
-            }
+    #[tokio::test]
+    async fn test_add_notes_accepts_error_objects() {
+        let mock = MockAnki::start(|_, _| {
+            ok(json!([
+                1,
+                null,
+                {"error": "cannot create note because it is a duplicate"},
+                "cannot create note because it is empty",
+                4
+            ]))
+        })
+        .await;
+
+        let note_ids = mock
+            .client()
+            .add_notes(vec![vocab_note(); 5])
+            .await
+            .unwrap();
+        assert_eq!(
+            note_ids,
+            vec![Some(1), None, None, None, Some(4)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_notes_trims_fields_when_enabled() {
+        let mock =
+            MockAnki::start(|_, _| ok(json!([1]))).await;
+        let mut note = vocab_note();
+        note.fields.insert(
+            "Back".to_string(),
+            "\n a  cat \n".to_string(),
+        );
+
+        mock.client()
+            .with_field_trimming(true)
+            .add_notes(vec![note.clone()])
+            .await
+            .unwrap();
+        mock.client().add_notes(vec![note]).await.unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(
+            requests[0]["params"]["notes"][0]["fields"]["Back"],
+            "a  cat"
+        );
+        assert_eq!(
+            requests[1]["params"]["notes"][0]["fields"]["Back"],
+            "\n a  cat \n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_after_current_chunk() {
+        let cancel = CancellationToken::new();
+        let trip = cancel.clone();
+        let mock = MockAnki::start(move |_, _| {
+            // The stop button is pressed while the first chunk is
+            // being added
+            trip.cancel();
+            ok(json!([1, null]))
+        })
+        .await;
+
+        let outcomes = mock
+            .client()
+            .add_notes_chunked(
+                vec![vocab_note(); 5],
+                2,
+                Some(&cancel),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                ChunkedAdd::Added(1),
+                ChunkedAdd::Rejected,
+                ChunkedAdd::NotAttempted,
+                ChunkedAdd::NotAttempted,
+                ChunkedAdd::NotAttempted,
+            ]
+        );
+        assert_eq!(mock.actions(), vec!["addNotes"]);
+
+        let outcomes = mock
+            .client()
+            .add_notes_chunked(
+                vec![vocab_note(); 3],
+                2,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcomes[2], ChunkedAdd::Added(1));
+        assert_eq!(mock.actions().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_media_files_exist() {
+        let mock = MockAnki::start(|_, _| {
+            ok(json!(["cat.mp3", "ねこ.png", "dog.jpg"]))
+        })
+        .await;
+        let exists = mock
+            .client()
+            .media_files_exist(vec![
+                "cat.mp3".to_string(),
+                "ねこ.png".to_string(),
+                "Cat.mp3".to_string(),
+                "missing.ogg".to_string(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(exists.len(), 4);
+        assert!(exists["cat.mp3"]);
+        assert!(exists["ねこ.png"]);
+        assert!(!exists["Cat.mp3"]);
+        assert!(!exists["missing.ogg"]);
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0]["action"],
+            "getMediaFilesNames"
+        );
+        assert_eq!(requests[0]["params"]["pattern"], "*");
+
+        let none = mock
+            .client()
+            .media_files_exist(vec![])
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test(
+        flavor = "multi_thread",
+        worker_threads = 8
+    )]
+    async fn test_concurrent_requests_are_capped() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (current, max_seen) =
+            (in_flight.clone(), peak.clone());
+        let mock = MockAnki::start(move |_, _| {
+            let now =
+                current.fetch_add(1, Ordering::SeqCst) + 1;
+            max_seen.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(30));
+            current.fetch_sub(1, Ordering::SeqCst);
+            ok(json!(6))
+        })
+        .await;
+
+        let client = AnkiClient::builder()
+            .url(mock.client().url())
+            .max_concurrent_requests(2)
+            .build()
+            .unwrap();
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    client.version().await
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap(), 6);
         }
+        assert_eq!(mock.requests().len(), 10);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert!(
+            AnkiClient::builder()
+                .max_concurrent_requests(0)
+                .build()
+                .is_err()
+        );
     }
 
-    #[test]
-    fn test_anki_response_error_deserialization() {
-        let json = r#"{"error":"Test error","detail":"Test detail"}"#;
-        let response: AnkiResponse<u64> =
-            serde_json::from_str(json)
-                .expect("Failed to deserialize response");
-        match response {
-            AnkiResponse::Success { .. } => {
-                panic!("Expected error response")
+    /// Mock that refuses `deckNames` until `requestPermission` was
+    /// answered with `answer`
+    async fn permission_mock(
+        answer: serde_json::Value,
+    ) -> MockAnki {
+        let granted = Arc::new(
+            std::sync::atomic::AtomicBool::new(false),
+        );
+        MockAnki::start(move |action, _| {
+            use std::sync::atomic::Ordering;
+            match action {
+                "requestPermission" => {
+                    if answer["permission"] == "granted"
+                        && answer["requireApikey"] != true
+                    {
+                        granted
+                            .store(true, Ordering::SeqCst);
+                    }
+                    ok(answer.clone())
+                }
+                "deckNames"
+                    if granted.load(Ordering::SeqCst) =>
+                {
+                    ok(json!(["Default"]))
+                }
+                _ => err("valid api key must be provided"),
             }
-            AnkiResponse::Error { error, detail } => {
-                assert_eq!(error, "Test error");
-                assert_eq!(
-                    detail,
-                    Some("Test detail".to_string())
-                );
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_granted_permission_retries_once() {
+        let mock = permission_mock(json!({
+            "permission": "granted",
+            "requireApikey": false,
+            "version": 6,
+        }))
+        .await;
+        let client = AnkiClient::builder()
+            .url(mock.client().url())
+            .permission_negotiation(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            client.get_deck_names(None).await.unwrap(),
+            vec!["Default"]
+        );
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "deckNames",
+                "requestPermission",
+                "deckNames"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denied_permission_is_asked_once() {
+        let mock = permission_mock(
+            json!({ "permission": "denied" }),
+        )
+        .await;
+        let client =
+            mock.client().with_permission_negotiation(true);
+        let clone = client.clone();
+
+        let error =
+            client.get_deck_names(None).await.unwrap_err();
+        assert!(
+            error.to_string().starts_with(
+                "Anki denied this client permission"
+            ),
+            "{}",
+            error
+        );
+        assert!(is_access_error(&error));
+        assert!(clone.get_deck_names(None).await.is_err());
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "deckNames",
+                "requestPermission",
+                "deckNames"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_required_key_names_the_builder_setting() {
+        let mock = permission_mock(json!({
+            "permission": "granted",
+            "requireApikey": true,
+            "version": 6,
+        }))
+        .await;
+        let error = mock
+            .client()
+            .with_permission_negotiation(true)
+            .get_deck_names(None)
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("AnkiClient::builder().key(...)"),
+            "{}",
+            error
+        );
+
+        let keyed = AnkiClient::builder()
+            .url(mock.client().url())
+            .key(Some(Secret::from("wrong")))
+            .permission_negotiation(true)
+            .build()
+            .unwrap();
+        let error =
+            keyed.get_deck_names(None).await.unwrap_err();
+        assert!(error.to_string().starts_with(
+            "Anki-Connect rejected the configured API key"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_permission_negotiation_is_opt_in() {
+        let mock = permission_mock(
+            json!({ "permission": "granted" }),
+        )
+        .await;
+        assert!(
+            mock.client()
+                .get_deck_names(None)
+                .await
+                .is_err()
+        );
+        assert_eq!(mock.actions(), vec!["deckNames"]);
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    async fn tag_mock(matches: u64) -> MockAnki {
+        MockAnki::start(move |action, _| match action {
+            "findNotes" => {
+                ok(json!((1..=matches).collect::<Vec<_>>()))
             }
+            "addTags" | "removeTags" => ok(json!(null)),
+            other => panic!("unexpected action {}", other),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_tag_query_batches_at_boundaries() {
+        for (matches, batches) in [(4, 2), (5, 3)] {
+            let mock = tag_mock(matches).await;
+            let client = mock.client().with_tag_batch(2);
+            let report = client
+                .tag_query(
+                    "deck:A",
+                    &tags(&["leech"]),
+                    &[],
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                report,
+                TagOpReport {
+                    matched: matches as usize,
+                    touched: matches as usize,
+                    batches,
+                }
+            );
+            let sizes: Vec<usize> = mock.requests()[1..]
+                .iter()
+                .map(|r| {
+                    r["params"]["notes"]
+                        .as_array()
+                        .unwrap()
+                        .len()
+                })
+                .collect();
+            assert_eq!(
+                sizes.iter().sum::<usize>(),
+                matches as usize
+            );
+            assert!(sizes.iter().all(|size| *size <= 2));
         }
     }
 
-    #[test]
-    fn test_note_serialization() {
-        let mut fields = std::collections::HashMap::new();
-        fields.insert(
-            "Front".to_string(),
-            "Question".to_string(),
+    #[tokio::test]
+    async fn test_tag_query_adds_and_removes_per_batch() {
+        let mock = tag_mock(3).await;
+        let client = mock.client().with_tag_batch(2);
+        let report = client
+            .tag_query(
+                "tag:old",
+                &tags(&["new", "reviewed"]),
+                &tags(&["old"]),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.batches, 4);
+        assert_eq!(report.touched, 3);
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "findNotes",
+                "addTags",
+                "removeTags",
+                "addTags",
+                "removeTags"
+            ]
         );
-        fields.insert(
-            "Back".to_string(),
-            "Answer".to_string(),
+        let requests = mock.requests();
+        assert_eq!(
+            requests[1]["params"],
+            json!({ "notes": [1, 2], "tags": "new reviewed" })
+        );
+        assert_eq!(
+            requests[4]["params"],
+            json!({ "notes": [3], "tags": "old" })
         );
+    }
 
-        let note = Note {
-            model_name: "Basic".to_string(),
-            deck_name: "Default".to_string(),
-            fields,
-            tags: vec!["test".to_string()],
-            audio: None,
-            picture: None,
-            video: None,
-            options: None,
-        };
+    #[tokio::test]
+    async fn test_tag_query_dry_run_and_empty_matches() {
+        let mock = tag_mock(7).await;
+        let report = mock
+            .client()
+            .tag_query("deck:A", &tags(&["x"]), &[], true)
+            .await
+            .unwrap();
+        assert_eq!(report.matched, 7);
+        assert_eq!(report.batches, 0);
 
-        let json = serde_json::to_string(&note)
-            .expect("Failed to serialize note");
-        let parsed: serde_json::Value =
-            serde_json::from_str(&json)
-                .expect("Failed to parse JSON");
-        assert_eq!(parsed["modelName"], "Basic");
-        assert_eq!(parsed["deckName"], "Default");
-        assert_eq!(parsed["tags"][0], "test");
+        let empty = tag_mock(0).await;
+        let report = empty
+            .client()
+            .tag_query(
+                "deck:A",
+                &tags(&["x"]),
+                &tags(&["y"]),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(report, TagOpReport::default());
+        assert_eq!(empty.actions(), vec!["findNotes"]);
+        assert_eq!(mock.actions(), vec!["findNotes"]);
     }
 
-    #[test]
-    fn test_note_with_audio_serialization() {
-        let mut fields = std::collections::HashMap::new();
-        fields.insert(
-            "Front".to_string(),
-            "Question".to_string(),
+    #[tokio::test]
+    async fn test_add_and_remove_tags_send_one_string() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "addTags" | "removeTags" => ok(json!(null)),
+                _ => err("unsupported action"),
+            })
+            .await;
+        let client = mock.client();
+        let tags = [
+            "vocab::n5".to_string(),
+            "ai::generated".to_string(),
+            "日本語".to_string(),
+        ];
+
+        client
+            .add_tag_list(vec![1, 2], &tags)
+            .await
+            .unwrap();
+        client
+            .remove_tag_list(vec![3], &tags[..1])
+            .await
+            .unwrap();
+        assert!(
+            client
+                .add_tag_list(
+                    vec![1],
+                    &["two words".to_string()]
+                )
+                .await
+                .is_err()
         );
-        fields.insert(
-            "Back".to_string(),
-            "Answer".to_string(),
+        client
+            .add_tag_list(Vec::new(), &tags)
+            .await
+            .unwrap();
+        client.remove_tag_list(vec![1], &[]).await.unwrap();
+
+        // The caller's string goes out as it is
+        client
+            .add_tags(vec![4], "  vocab::n5  日本語 ")
+            .await
+            .unwrap();
+        client
+            .remove_tags(vec![5], "ai::generated")
+            .await
+            .unwrap();
+        client.add_tags(vec![6], " ").await.unwrap();
+
+        assert_eq!(
+            mock.actions(),
+            [
+                "addTags",
+                "removeTags",
+                "addTags",
+                "removeTags"
+            ]
+        );
+        assert_eq!(
+            mock.requests()[2]["params"],
+            json!({"notes": [4], "tags": "  vocab::n5  日本語 "})
+        );
+        assert_eq!(
+            mock.requests()[3]["params"],
+            json!({"notes": [5], "tags": "ai::generated"})
+        );
+        assert_eq!(
+            mock.requests()[0]["params"],
+            json!({
+                "notes": [1, 2],
+                "tags": "vocab::n5 ai::generated 日本語",
+            })
+        );
+        assert_eq!(
+            mock.requests()[1]["params"],
+            json!({"notes": [3], "tags": "vocab::n5"})
         );
+    }
 
-        let audio = NoteAudio {
-            path: "/path/to/audio.mp3".to_string(),
-            filename: "audio.mp3".to_string(),
-            fields: vec!["Back".to_string()],
-            hash: Some("abc123".to_string()),
-        };
+    #[tokio::test]
+    async fn test_tag_query_rejects_bad_tags() {
+        let mock = tag_mock(1).await;
+        let client = mock.client();
+        assert!(
+            client
+                .tag_query(
+                    "",
+                    &tags(&["two words"]),
+                    &[],
+                    false
+                )
+                .await
+                .is_err()
+        );
+        assert!(
+            client
+                .tag_query(
+                    "",
+                    &tags(&["a"]),
+                    &tags(&["A"]),
+                    false
+                )
+                .await
+                .is_err()
+        );
+        assert!(mock.actions().is_empty());
+    }
 
-        let note = Note {
-            model_name: "Basic".to_string(),
-            deck_name: "Default".to_string(),
-            fields,
-            tags: vec![],
-            audio: Some(vec![audio]),
-            picture: None,
-            video: None,
-            options: None,
+    #[tokio::test]
+    async fn test_find_notes_filtered_excludes_empty() {
+        let note = |id: u64, front: &str| {
+            json!({
+                "noteId": id,
+                "modelName": "Basic",
+                "tags": [],
+                "fields": {
+                    "Back": {"value": "back", "order": 1},
+                    "Front": {"value": front, "order": 0},
+                },
+                "cards": [],
+            })
         };
+        let notes = vec![
+            note(1, "cat"),
+            note(2, ""),
+            note(3, " <br> &nbsp;"),
+            note(4, "<img src=\"a.png\">dog"),
+            note(5, "<div><img src=\"b.png\"></div>"),
+        ];
+        let mock =
+            MockAnki::start(
+                move |action, _| match action {
+                    "findNotes" => {
+                        ok(json!([1, 2, 3, 4, 5]))
+                    }
+                    "notesInfo" => ok(json!(notes)),
+                    other => panic!(
+                        "unexpected action {}",
+                        other
+                    ),
+                },
+            )
+            .await;
+        let client = mock.client();
 
-        let json = serde_json::to_string(&note)
-            .expect("Failed to serialize note");
-        let parsed: serde_json::Value =
-            serde_json::from_str(&json)
-                .expect("Failed to parse JSON");
-        assert!(parsed["audio"].is_array());
         assert_eq!(
-            parsed["audio"][0]["filename"],
-            "audio.mp3"
+            client
+                .find_notes_filtered("deck:X", true)
+                .await
+                .unwrap(),
+            vec![1, 4, 5]
+        );
+        assert_eq!(
+            client
+                .find_notes_filtered("deck:X", false)
+                .await
+                .unwrap(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(
+            mock.actions(),
+            ["findNotes", "notesInfo", "findNotes"]
         );
     }
 
-    #[test]
-    fn test_client_creation() {
-        let client = AnkiClient::new();
-        assert_eq!(client.url, DEFAULT_ANKI_CONNECT_URL);
-        assert_eq!(client.version, 6);
+    #[tokio::test]
+    async fn test_find_note_by_field_ignoring_html() {
+        let mock = MockAnki::start(|action, _| {
+            match action {
+                "findNotes" => ok(json!([7, 3])),
+                "notesInfo" => ok(json!([
+                    {
+                        "noteId": 7,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {"Front": {"value": "<div>Hello&nbsp;<b>world</b></div>", "order": 0}},
+                        "cards": [],
+                    },
+                    {
+                        "noteId": 3,
+                        "modelName": "Basic",
+                        "tags": [],
+                        "fields": {"Front": {"value": "<div>Hello worlds</div>", "order": 0}},
+                        "cards": [],
+                    },
+                ])),
+                other => panic!("unexpected action {}", other),
+            }
+        })
+        .await;
+        let client = mock.client();
+
+        let found = client
+            .find_note_by_field(
+                "Basic",
+                "Front",
+                "Hello world",
+                FieldMatch::IgnoreHtml,
+            )
+            .await
+            .unwrap();
+        assert_eq!(found, Some(7));
+        assert_eq!(
+            mock.requests()[0]["params"]["query"],
+            "\"note:Basic\" \"Front:*world*\""
+        );
+
+        let exact = client
+            .find_note_by_field(
+                "Basic",
+                "Front",
+                "Hello world",
+                FieldMatch::Exact,
+            )
+            .await
+            .unwrap();
+        assert_eq!(exact, Some(3));
+        let requests = mock.requests();
+        let last = requests.last().unwrap();
+        assert_eq!(
+            last["params"]["query"],
+            "\"note:Basic\" \"Front:Hello world\""
+        );
     }
 
-    #[test]
-    fn test_client_with_custom_url() {
-        let client =
-            AnkiClient::with_url("http://custom:8765");
-        assert_eq!(client.url, "http://custom:8765");
+    type MockDecks = Arc<
+        std::sync::Mutex<
+            std::collections::BTreeMap<String, Vec<u64>>,
+        >,
+    >;
+
+    /// Mock collection for deck renames, holding each deck's cards.
+    /// Like Anki, it matches deck names ignoring case. Decks named
+    /// `*Kanji` use options group 5, others group 1; the first
+    /// `changeDeck` to `fail_move_to` fails.
+    async fn deck_mock(
+        decks: &[(&str, &[u64])],
+        fail_move_to: Option<&'static str>,
+    ) -> (MockAnki, MockDecks) {
+        let state: MockDecks =
+            Arc::new(std::sync::Mutex::new(
+                decks
+                    .iter()
+                    .map(|(name, cards)| {
+                        (name.to_string(), cards.to_vec())
+                    })
+                    .collect(),
+            ));
+        let decks = state.clone();
+        let failed =
+            std::sync::atomic::AtomicBool::new(false);
+        let mock = MockAnki::start(move |action, params| {
+            let mut decks = decks.lock().unwrap();
+            match action {
+                "deckNamesAndIds" => ok(decks
+                    .keys()
+                    .enumerate()
+                    .map(|(id, name)| (name.clone(), json!(id)))
+                    .collect()),
+                "getDeckConfig" => {
+                    let deck = params["deck"].as_str().unwrap();
+                    let id =
+                        if deck.ends_with("Kanji") { 5 } else { 1 };
+                    ok(json!({"id": id, "name": "Group"}))
+                }
+                "createDeck" => {
+                    let deck = params["deck"].as_str().unwrap();
+                    let deck = mock_deck_key(&decks, deck);
+                    decks.entry(deck).or_default();
+                    ok(json!(1))
+                }
+                "setDeckConfigId" => ok(json!(true)),
+                "findCards" => {
+                    let query = params["query"].as_str().unwrap();
+                    let deck = query
+                        .split('"')
+                        .nth(1)
+                        .unwrap()
+                        .to_lowercase();
+                    let prefix = format!("{}::", deck);
+                    let only = query.contains("-deck:");
+                    ok(json!(
+                        decks
+                            .iter()
+                            .filter(|(name, _)| {
+                                let name = name.to_lowercase();
+                                name == deck
+                                    || (!only
+                                        && name.starts_with(&prefix))
+                            })
+                            .flat_map(|(_, cards)| cards.clone())
+                            .collect::<Vec<_>>()
+                    ))
+                }
+                "changeDeck" => {
+                    let target = params["deck"].as_str().unwrap();
+                    if Some(target) == fail_move_to
+                        && !failed.swap(true, Ordering::SeqCst)
+                    {
+                        return err("collection is locked");
+                    }
+                    let cards: Vec<u64> = serde_json::from_value(
+                        params["cards"].clone(),
+                    )
+                    .unwrap();
+                    for held in decks.values_mut() {
+                        held.retain(|card| !cards.contains(card));
+                    }
+                    let target = mock_deck_key(&decks, target);
+                    decks.entry(target).or_default().extend(cards);
+                    ok(json!(null))
+                }
+                "deleteDecks" => {
+                    for deck in params["decks"].as_array().unwrap() {
+                        let deck =
+                            mock_deck_key(&decks, deck.as_str().unwrap());
+                        decks.remove(&deck);
+                    }
+                    ok(json!(null))
+                }
+                _ => err("unsupported action"),
+            }
+        })
+        .await;
+        (mock, state)
     }
 
-    #[test]
-    fn test_add_notes_params_serialization() {
-        let mut fields = std::collections::HashMap::new();
-        fields
-            .insert("Front".to_string(), "Q1".to_string());
-        fields.insert("Back".to_string(), "A1".to_string());
+    /// The existing deck matching `name` ignoring case, or `name`
+    fn mock_deck_key(
+        decks: &std::collections::BTreeMap<
+            String,
+            Vec<u64>,
+        >,
+        name: &str,
+    ) -> String {
+        decks
+            .keys()
+            .find(|deck| deck.eq_ignore_ascii_case(name))
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
 
-        let note = Note {
-            model_name: "Basic".to_string(),
-            deck_name: "Default".to_string(),
-            fields,
-            tags: vec![],
-            audio: None,
-            picture: None,
-            video: None,
-            options: None,
-        };
+    fn deck_state(
+        decks: &MockDecks,
+    ) -> Vec<(String, Vec<u64>)> {
+        decks.lock().unwrap().clone().into_iter().collect()
+    }
 
-        let params = AddNotesParams { notes: vec![note] };
-        let json = serde_json::to_string(&params)
-            .expect("Failed to serialize");
-        let parsed: serde_json::Value =
-            serde_json::from_str(&json)
-                .expect("Failed to parse JSON");
-        assert!(parsed["notes"].is_array());
+    fn pairs(
+        items: &[(&str, &str)],
+    ) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
+
+    const JAPANESE: &[(&str, &[u64])] = &[
+        ("Default", &[]),
+        ("Japanese", &[1, 2]),
+        ("Japanese::Kanji", &[3]),
+        ("Languages", &[]),
+    ];
+
+    #[tokio::test]
+    async fn test_rename_deck_moves_the_subtree() {
+        let (mock, decks) = deck_mock(JAPANESE, None).await;
+        let report = mock
+            .client()
+            .rename_deck(
+                "Japanese",
+                "Languages::Japanese",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert!(report.is_complete());
         assert_eq!(
-            parsed["notes"].as_array().unwrap().len(),
-            1
+            report.renamed,
+            pairs(&[
+                ("Japanese", "Languages::Japanese"),
+                (
+                    "Japanese::Kanji",
+                    "Languages::Japanese::Kanji"
+                ),
+            ])
+        );
+        assert_eq!(
+            report.created,
+            [
+                "Languages::Japanese",
+                "Languages::Japanese::Kanji"
+            ]
+        );
+        assert_eq!(report.configured, report.created);
+        assert_eq!(
+            report.moved,
+            [
+                ("Japanese".to_string(), 2),
+                ("Japanese::Kanji".to_string(), 1)
+            ]
+        );
+        assert!(report.deleted_old);
+        assert_eq!(
+            deck_state(&decks),
+            [
+                ("Default".to_string(), vec![]),
+                ("Languages".to_string(), vec![]),
+                (
+                    "Languages::Japanese".to_string(),
+                    vec![1, 2]
+                ),
+                (
+                    "Languages::Japanese::Kanji"
+                        .to_string(),
+                    vec![3]
+                ),
+            ]
         );
-    }
 
-    #[test]
-    fn test_update_note_fields_params_serialization() {
-        let mut fields = std::collections::HashMap::new();
-        fields.insert(
-            "Front".to_string(),
-            "New Question".to_string(),
+        // Everything is created before cards move, and the old decks
+        // are only deleted after the emptiness check
+        assert_eq!(
+            mock.actions(),
+            [
+                "deckNamesAndIds",
+                "getDeckConfig",
+                "getDeckConfig",
+                "createDeck",
+                "createDeck",
+                "setDeckConfigId",
+                "setDeckConfigId",
+                "findCards",
+                "changeDeck",
+                "findCards",
+                "changeDeck",
+                "findCards",
+                "deleteDecks",
+            ]
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[6]["params"],
+            json!({
+                "decks": ["Languages::Japanese::Kanji"],
+                "configId": 5,
+            })
+        );
+        assert_eq!(
+            requests[11]["params"]["query"],
+            "deck:\"Japanese\""
+        );
+        assert_eq!(
+            requests[12]["params"]["decks"],
+            json!(["Japanese", "Japanese::Kanji"])
         );
+    }
 
-        let params = UpdateNoteFieldsParams {
-            note: 12345,
-            fields,
-            audio: None,
-        };
+    #[tokio::test]
+    async fn test_rename_deck_refuses_before_changing_anything()
+     {
+        let mut taken = JAPANESE.to_vec();
+        taken.push(("languages::japanese::kanji", &[]));
+        let (mock, _) = deck_mock(&taken, None).await;
+        let client = mock.client();
 
-        let json = serde_json::to_string(&params)
-            .expect("Failed to serialize");
-        let parsed: serde_json::Value =
-            serde_json::from_str(&json)
-                .expect("Failed to parse JSON");
-        assert_eq!(parsed["note"], 12345);
+        let error = client
+            .rename_deck(
+                "Japanese",
+                "Languages::Japanese",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot rename Japanese to Languages::Japanese: Languages::Japanese::Kanji already exist(s)"
+        );
+        let error = client
+            .rename_deck(
+                "Japanese",
+                "Japanese::Old",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("inside it"));
+        let error = client
+            .rename_deck(
+                "French",
+                "Languages::French",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap_err();
         assert_eq!(
-            parsed["fields"]["Front"],
-            "New Question"
+            error.downcast_ref::<AnkiError>(),
+            Some(&AnkiError::deck_not_found("French"))
         );
+        assert_eq!(mock.actions(), ["deckNamesAndIds"; 3]);
     }
 
-    #[test]
-    fn test_find_notes_params_serialization() {
-        let params = FindNotesParams {
-            query: "deck:Default".to_string(),
-        };
+    #[tokio::test]
+    async fn test_rename_deck_resumes_after_a_failed_move()
+    {
+        let (mock, decks) = deck_mock(
+            JAPANESE,
+            Some("Languages::Japanese::Kanji"),
+        )
+        .await;
+        let client = mock.client();
 
-        let json = serde_json::to_string(&params)
-            .expect("Failed to serialize");
-        assert_eq!(json, r#"{"query":"deck:Default"}"#);
+        let report = client
+            .rename_deck(
+                "Japanese",
+                "Languages::Japanese",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!report.is_complete());
+        assert!(report.failure.as_ref().unwrap().starts_with(
+            "Failed to move the cards of Japanese::Kanji to Languages::Japanese::Kanji"
+        ));
+        assert_eq!(report.created.len(), 2);
+        assert_eq!(
+            report.moved,
+            [("Japanese".to_string(), 2)]
+        );
+        assert!(!report.deleted_old);
+        assert!(
+            !mock
+                .actions()
+                .contains(&"deleteDecks".to_string())
+        );
+        assert_eq!(
+            deck_state(&decks)[2],
+            ("Japanese::Kanji".to_string(), vec![3])
+        );
+
+        // The half-built targets now count as collisions...
+        client
+            .rename_deck(
+                "Japanese",
+                "Languages::Japanese",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        // ...unless the rename is resumed
+        let report = client
+            .rename_deck(
+                "Japanese",
+                "Languages::Japanese",
+                RenameOptions {
+                    resume: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(report.is_complete());
+        assert!(report.created.is_empty());
+        assert_eq!(
+            report.moved,
+            [
+                ("Japanese".to_string(), 0),
+                ("Japanese::Kanji".to_string(), 1)
+            ]
+        );
+        assert!(report.deleted_old);
+        assert_eq!(
+            deck_state(&decks),
+            [
+                ("Default".to_string(), vec![]),
+                ("Languages".to_string(), vec![]),
+                (
+                    "Languages::Japanese".to_string(),
+                    vec![1, 2]
+                ),
+                (
+                    "Languages::Japanese::Kanji"
+                        .to_string(),
+                    vec![3]
+                ),
+            ]
+        );
     }
 
-    #[test]
-    fn test_notes_info_params_serialization() {
-        let params = NotesInfoParams {
-            notes: vec![123, 456, 789],
-        };
+    #[tokio::test]
+    async fn test_rename_deck_matches_old_ignoring_case() {
+        let (mock, decks) = deck_mock(JAPANESE, None).await;
+        let report = mock
+            .client()
+            .rename_deck(
+                "japanese",
+                "Languages::Japanese",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap();
 
-        let json = serde_json::to_string(&params)
-            .expect("Failed to serialize");
-        let parsed: serde_json::Value =
-            serde_json::from_str(&json)
-                .expect("Failed to parse JSON");
-        assert_eq!(parsed["notes"][0], 123);
-        assert_eq!(parsed["notes"][1], 456);
-        assert_eq!(parsed["notes"][2], 789);
+        assert!(report.is_complete());
+        assert_eq!(
+            report.renamed,
+            pairs(&[
+                ("Japanese", "Languages::Japanese"),
+                (
+                    "Japanese::Kanji",
+                    "Languages::Japanese::Kanji"
+                ),
+            ])
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[11]["params"]["query"],
+            "deck:\"Japanese\""
+        );
+        assert_eq!(
+            requests[12]["params"]["decks"],
+            json!(["Japanese", "Japanese::Kanji"])
+        );
+        assert_eq!(
+            deck_state(&decks)[3],
+            (
+                "Languages::Japanese::Kanji".to_string(),
+                vec![3]
+            )
+        );
     }
 
-    #[test]
-    fn test_cards_info_params_serialization() {
-        let params = CardsInfoParams {
-            cards: vec![111, 222, 333],
-        };
+    #[tokio::test]
+    async fn test_rename_deck_changes_only_the_case() {
+        let (mock, decks) = deck_mock(
+            &[
+                ("Default", &[]),
+                ("french", &[1, 2]),
+                ("french::verbs", &[3]),
+            ],
+            None,
+        )
+        .await;
+        let report = mock
+            .client()
+            .rename_deck(
+                "french",
+                "French",
+                RenameOptions {
+                    delete_old: false,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
 
-        let json = serde_json::to_string(&params)
-            .expect("Failed to serialize");
-        let parsed: serde_json::Value =
-            serde_json::from_str(&json)
-                .expect("Failed to parse JSON");
-        assert_eq!(parsed["cards"][0], 111);
-        assert_eq!(parsed["cards"][1], 222);
-        assert_eq!(parsed["cards"][2], 333);
+        assert!(report.is_complete());
+        assert_eq!(
+            report.renamed,
+            pairs(&[
+                ("french", "French"),
+                ("french::verbs", "French::verbs"),
+            ])
+        );
+        assert_eq!(
+            report.created,
+            ["French", "French::verbs"]
+        );
+        assert_eq!(
+            report.moved,
+            [
+                ("french".to_string(), 2),
+                ("french::verbs".to_string(), 1)
+            ]
+        );
+        assert!(report.deleted_old);
+        assert_eq!(
+            deck_state(&decks),
+            [
+                ("Default".to_string(), vec![]),
+                ("French".to_string(), vec![1, 2]),
+                ("French::verbs".to_string(), vec![3]),
+            ]
+        );
+
+        let error = mock
+            .client()
+            .rename_deck(
+                "French",
+                "French",
+                RenameOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("inside it"));
     }
 }
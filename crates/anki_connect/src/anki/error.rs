@@ -0,0 +1,592 @@
+//! Typed errors reported by Anki-Connect or met on the way to it.
+//!
+//! Client methods still return `anyhow::Result`; these errors are the
+//! root cause of the `anyhow::Error` and can be recovered with
+//! `error.downcast_ref::<AnkiError>()`. A failed request or an
+//! unparsable response carries the transport's error or the
+//! `serde_json::Error` behind it as its `source()`.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Error string Anki-Connect returns when `addNote` hits a duplicate
+const DUPLICATE_MESSAGE: &str =
+    "cannot create note because it is a duplicate";
+
+/// Error string Anki-Connect returns for an action it does not know
+const UNSUPPORTED_ACTION_MESSAGE: &str =
+    "unsupported action";
+
+/// Parts of the error strings Anki-Connect sends while Anki is busy
+/// or its collection is closed, lowercase
+const BUSY_MESSAGES: [&str; 4] = [
+    "collection is not available",
+    "collection is not open",
+    "busy",
+    "not ready",
+];
+
+/// Start of the error string for a missing deck, followed by its name
+const DECK_NOT_FOUND_PREFIX: &str = "deck was not found: ";
+
+/// Start of the error string for a missing model, followed by its
+/// name
+const MODEL_NOT_FOUND_PREFIX: &str =
+    "model was not found: ";
+
+/// How far a request got before its transport failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionFailure {
+    /// No connection could be made, so the request was certainly
+    /// not delivered
+    Connect,
+    /// The connection broke after the request was sent, before the
+    /// whole response arrived
+    Disconnected,
+    /// No answer arrived in time
+    TimedOut,
+    /// Any other failure, e.g. a request that could not be built
+    Other,
+}
+
+impl ConnectionFailure {
+    /// Classifies a reqwest error
+    fn of(error: &reqwest::Error) -> Self {
+        if error.is_connect() {
+            ConnectionFailure::Connect
+        } else if error.is_timeout() {
+            ConnectionFailure::TimedOut
+        } else if (error.is_request() || error.is_body())
+            && error.status().is_none()
+        {
+            ConnectionFailure::Disconnected
+        } else {
+            ConnectionFailure::Other
+        }
+    }
+}
+
+/// Error returned by Anki-Connect, or by the client before an answer
+/// could be read.
+///
+/// Equality ignores the source errors.
+#[derive(Debug, Clone)]
+pub enum AnkiError {
+    /// The request did not reach Anki-Connect or the response did
+    /// not come back
+    ConnectionFailed {
+        /// Which of the two went wrong
+        message: String,
+        /// How far the request got
+        kind: ConnectionFailure,
+        /// The underlying error, also returned by `source()`
+        source: Arc<dyn std::error::Error + Send + Sync>,
+    },
+    /// The response is not what Anki-Connect sends
+    InvalidResponse {
+        /// What was wrong with it
+        message: String,
+        /// The underlying error, also returned by `source()`
+        source: Arc<serde_json::Error>,
+    },
+    /// The note duplicates an existing note's first field
+    Duplicate {
+        /// The existing note, when it could be looked up
+        existing_note_id: Option<u64>,
+    },
+    /// No deck has the given name
+    DeckNotFound {
+        /// The missing deck
+        name: String,
+        /// Optional extra detail sent by Anki-Connect
+        detail: Option<String>,
+    },
+    /// No model has the given name
+    ModelNotFound {
+        /// The missing model
+        name: String,
+        /// Optional extra detail sent by Anki-Connect
+        detail: Option<String>,
+    },
+    /// The action does not exist on the connected Anki-Connect
+    UnsupportedByServer {
+        /// The action that was called
+        action: String,
+        /// The server's API version, when it was detected
+        server_version: Option<u32>,
+    },
+    /// Any other error message sent by Anki-Connect
+    Api {
+        /// Error message
+        error: String,
+        /// Optional extra detail
+        detail: Option<String>,
+    },
+}
+
+impl AnkiError {
+    /// A failed HTTP request, described by `message`
+    pub fn connection_failed(
+        message: impl Into<String>,
+        source: reqwest::Error,
+    ) -> Self {
+        let kind = ConnectionFailure::of(&source);
+        Self::transport_failed(kind, message, source)
+    }
+
+    /// A request that failed in any other
+    /// [`Transport`](super::transport::Transport), described by
+    /// `message`
+    pub fn transport_failed(
+        kind: ConnectionFailure,
+        message: impl Into<String>,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        AnkiError::ConnectionFailed {
+            message: message.into(),
+            kind,
+            source: Arc::new(source),
+        }
+    }
+
+    /// How far the request got, if its transport failed
+    pub fn connection_failure(
+        &self,
+    ) -> Option<ConnectionFailure> {
+        match self {
+            AnkiError::ConnectionFailed {
+                kind, ..
+            } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// An unparsable response, described by `message`
+    pub fn invalid_response(
+        message: impl Into<String>,
+        source: serde_json::Error,
+    ) -> Self {
+        AnkiError::InvalidResponse {
+            message: message.into(),
+            source: Arc::new(source),
+        }
+    }
+
+    /// A missing deck reported by the client itself
+    pub fn deck_not_found(name: impl Into<String>) -> Self {
+        AnkiError::DeckNotFound {
+            name: name.into(),
+            detail: None,
+        }
+    }
+
+    /// A missing model reported by the client itself
+    pub fn model_not_found(
+        name: impl Into<String>,
+    ) -> Self {
+        AnkiError::ModelNotFound {
+            name: name.into(),
+            detail: None,
+        }
+    }
+
+    /// Maps an Anki-Connect error message to its typed variant
+    pub fn from_message(
+        error: String,
+        detail: Option<String>,
+    ) -> Self {
+        let message = error.trim();
+        if message.eq_ignore_ascii_case(DUPLICATE_MESSAGE) {
+            AnkiError::Duplicate {
+                existing_note_id: None,
+            }
+        } else if let Some(deck) =
+            strip_prefix(message, DECK_NOT_FOUND_PREFIX)
+        {
+            AnkiError::DeckNotFound {
+                name: deck.to_string(),
+                detail,
+            }
+        } else if let Some(model) =
+            strip_prefix(message, MODEL_NOT_FOUND_PREFIX)
+        {
+            AnkiError::ModelNotFound {
+                name: model.to_string(),
+                detail,
+            }
+        } else {
+            AnkiError::Api { error, detail }
+        }
+    }
+
+    /// Whether this is a duplicate-note error
+    pub fn is_duplicate(&self) -> bool {
+        matches!(self, AnkiError::Duplicate { .. })
+    }
+
+    /// Whether Anki-Connect refused the request for a missing or
+    /// wrong API key, or for lack of permission
+    pub fn is_access_denied(&self) -> bool {
+        match self {
+            AnkiError::Api { error, .. } => {
+                let error = error.to_lowercase();
+                error.contains("api key")
+                    || error.contains("permission")
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether Anki-Connect does not know the action, either as
+    /// [`AnkiError::UnsupportedByServer`] or as the raw message
+    pub fn is_unsupported_action(&self) -> bool {
+        match self {
+            AnkiError::UnsupportedByServer { .. } => true,
+            AnkiError::Api { error, .. } => {
+                error.trim().eq_ignore_ascii_case(
+                    UNSUPPORTED_ACTION_MESSAGE,
+                )
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether Anki-Connect answered but Anki is busy, e.g. while
+    /// syncing or with the collection closed. Other errors that
+    /// merely mention syncing, such as a missing sync login, are not
+    /// busy errors.
+    pub fn is_busy(&self) -> bool {
+        match self {
+            AnkiError::Api { error, .. } => {
+                let error = error.to_lowercase();
+                BUSY_MESSAGES
+                    .iter()
+                    .any(|message| error.contains(message))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `message` without `prefix`, compared ignoring ASCII case
+fn strip_prefix<'a>(
+    message: &'a str,
+    prefix: &str,
+) -> Option<&'a str> {
+    let head = message.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &message[prefix.len()..])
+}
+
+impl fmt::Display for AnkiError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            AnkiError::ConnectionFailed {
+                message, ..
+            }
+            | AnkiError::InvalidResponse {
+                message, ..
+            } => {
+                write!(f, "{}", message)
+            }
+            AnkiError::DeckNotFound { name, detail } => {
+                write!(
+                    f,
+                    "Anki-Connect error: {}{}",
+                    DECK_NOT_FOUND_PREFIX, name
+                )?;
+                write_detail(f, detail)
+            }
+            AnkiError::ModelNotFound { name, detail } => {
+                write!(
+                    f,
+                    "Anki-Connect error: {}{}",
+                    MODEL_NOT_FOUND_PREFIX, name
+                )?;
+                write_detail(f, detail)
+            }
+            AnkiError::Duplicate {
+                existing_note_id: Some(id),
+            } => write!(
+                f,
+                "Anki-Connect error: {} (existing note {})",
+                DUPLICATE_MESSAGE, id
+            ),
+            AnkiError::Duplicate {
+                existing_note_id: None,
+            } => write!(
+                f,
+                "Anki-Connect error: {}",
+                DUPLICATE_MESSAGE
+            ),
+            AnkiError::Api {
+                error,
+                detail: Some(detail),
+            } => write!(
+                f,
+                "Anki-Connect error: {}: {}",
+                error, detail
+            ),
+            AnkiError::UnsupportedByServer {
+                action,
+                server_version: Some(version),
+            } => write!(
+                f,
+                "Anki-Connect error: {} {} (API version {})",
+                UNSUPPORTED_ACTION_MESSAGE, action, version
+            ),
+            AnkiError::UnsupportedByServer {
+                action,
+                server_version: None,
+            } => write!(
+                f,
+                "Anki-Connect error: {} {}",
+                UNSUPPORTED_ACTION_MESSAGE, action
+            ),
+            AnkiError::Api {
+                error,
+                detail: None,
+            } => write!(f, "Anki-Connect error: {}", error),
+        }
+    }
+}
+
+/// Appends `: detail` when there is one
+fn write_detail(
+    f: &mut fmt::Formatter<'_>,
+    detail: &Option<String>,
+) -> fmt::Result {
+    match detail {
+        Some(detail) => write!(f, ": {}", detail),
+        None => Ok(()),
+    }
+}
+
+impl std::error::Error for AnkiError {
+    fn source(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AnkiError::ConnectionFailed {
+                source, ..
+            } => Some(source.as_ref()),
+            AnkiError::InvalidResponse {
+                source, ..
+            } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for AnkiError {
+    fn eq(&self, other: &Self) -> bool {
+        use AnkiError::*;
+        match (self, other) {
+            (
+                ConnectionFailed {
+                    message: a,
+                    kind: a_kind,
+                    ..
+                },
+                ConnectionFailed {
+                    message: b,
+                    kind: b_kind,
+                    ..
+                },
+            ) => a == b && a_kind == b_kind,
+            (
+                InvalidResponse { message: a, .. },
+                InvalidResponse { message: b, .. },
+            ) => a == b,
+            (
+                Duplicate {
+                    existing_note_id: a,
+                },
+                Duplicate {
+                    existing_note_id: b,
+                },
+            ) => a == b,
+            (
+                DeckNotFound {
+                    name: a,
+                    detail: a_detail,
+                },
+                DeckNotFound {
+                    name: b,
+                    detail: b_detail,
+                },
+            )
+            | (
+                ModelNotFound {
+                    name: a,
+                    detail: a_detail,
+                },
+                ModelNotFound {
+                    name: b,
+                    detail: b_detail,
+                },
+            )
+            | (
+                Api {
+                    error: a,
+                    detail: a_detail,
+                },
+                Api {
+                    error: b,
+                    detail: b_detail,
+                },
+            ) => a == b && a_detail == b_detail,
+            (
+                UnsupportedByServer {
+                    action: a,
+                    server_version: a_version,
+                },
+                UnsupportedByServer {
+                    action: b,
+                    server_version: b_version,
+                },
+            ) => a == b && a_version == b_version,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AnkiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_message_is_mapped() {
+        let error = AnkiError::from_message(
+            "cannot create note because it is a duplicate"
+                .to_string(),
+            None,
+        );
+        assert_eq!(
+            error,
+            AnkiError::Duplicate {
+                existing_note_id: None
+            }
+        );
+        assert!(error.is_duplicate());
+    }
+
+    #[test]
+    fn test_other_messages_stay_api_errors() {
+        let error = AnkiError::from_message(
+            "cannot create note because it is empty"
+                .to_string(),
+            Some("trace".to_string()),
+        );
+        assert!(!error.is_duplicate());
+        assert!(!error.is_access_denied());
+        assert_eq!(
+            error.to_string(),
+            "Anki-Connect error: cannot create note because it is empty: trace"
+        );
+    }
+
+    #[test]
+    fn test_missing_decks_and_models_are_mapped() {
+        let error = AnkiError::from_message(
+            "deck was not found: Languages::日本語"
+                .to_string(),
+            Some("trace".to_string()),
+        );
+        assert_eq!(
+            error,
+            AnkiError::DeckNotFound {
+                name: "Languages::日本語".to_string(),
+                detail: Some("trace".to_string()),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Anki-Connect error: deck was not found: Languages::日本語: trace"
+        );
+        assert_eq!(
+            AnkiError::from_message(
+                " Model was not found: Basic (and reversed card)"
+                    .to_string(),
+                None,
+            ),
+            AnkiError::model_not_found(
+                "Basic (and reversed card)"
+            )
+        );
+        // The name is required
+        assert!(matches!(
+            AnkiError::from_message(
+                "deck was not found".to_string(),
+                None
+            ),
+            AnkiError::Api { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors_keep_their_source() {
+        let parse = serde_json::from_str::<u32>("<html>")
+            .unwrap_err();
+        let shown = parse.to_string();
+        let error = AnkiError::invalid_response(
+            "Failed to parse Anki-Connect response",
+            parse,
+        );
+        let source =
+            std::error::Error::source(&error).unwrap();
+        assert!(source.is::<serde_json::Error>());
+        assert_eq!(source.to_string(), shown);
+        assert_eq!(
+            format!("{:#}", anyhow::Error::new(error)),
+            format!(
+                "Failed to parse Anki-Connect response: {}",
+                shown
+            )
+        );
+    }
+
+    #[test]
+    fn test_busy_errors_are_recognized() {
+        for (message, busy) in [
+            ("collection is not available", true),
+            ("Collection is not open", true),
+            ("Anki is busy", true),
+            ("sync: auth not configured", false),
+            ("sync failed: network error", false),
+            ("deck was not found: Sync", false),
+        ] {
+            assert_eq!(
+                AnkiError::from_message(
+                    message.to_string(),
+                    None
+                )
+                .is_busy(),
+                busy,
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_access_errors_are_recognized() {
+        for message in [
+            "valid api key must be provided",
+            "Permission denied",
+        ] {
+            assert!(
+                AnkiError::from_message(
+                    message.to_string(),
+                    None
+                )
+                .is_access_denied()
+            );
+        }
+    }
+}
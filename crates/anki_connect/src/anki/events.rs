@@ -0,0 +1,314 @@
+//! Events an [`AnkiClient`](super::client::AnkiClient) reports to an
+//! observer registered with
+//! [`AnkiClientBuilder::on_event`](super::client::AnkiClientBuilder::on_event).
+//!
+//! Events are built only when an observer is registered, and the
+//! callback is always invoked with no lock or request permit held, so
+//! it may call back into the client.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::error::AnkiError;
+use super::transport::ResponseTooLarge;
+
+/// Something that happened inside the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// An Anki-Connect action is about to be sent
+    RequestStarted {
+        /// Action name, e.g. `addNotes`
+        action: String,
+    },
+    /// An Anki-Connect action finished, successfully or not
+    RequestFinished {
+        /// Action name
+        action: String,
+        /// Time from [`ClientEvent::RequestStarted`] to the parsed
+        /// answer, retries included
+        duration: Duration,
+        /// How it ended
+        outcome: RequestOutcome,
+    },
+    /// A request is about to be repeated
+    RetryScheduled {
+        /// Action that will be sent again
+        action: String,
+        /// Number of this retry, starting at 1
+        attempt: u32,
+        /// Wait before the retry is sent
+        delay: Duration,
+        /// Why the request is repeated
+        reason: String,
+    },
+    /// A batched helper finished one chunk
+    ChunkProgress {
+        /// The helper, e.g. `add_notes_chunked`
+        operation: &'static str,
+        /// Items handled so far
+        done: usize,
+        /// Items the helper will handle in total
+        total: usize,
+    },
+}
+
+/// Fixed category of a finished request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestOutcome {
+    /// Anki-Connect returned a result
+    Ok,
+    /// The note duplicates an existing note
+    Duplicate,
+    /// The server does not know the action
+    Unsupported,
+    /// The request was refused for lack of permission or API key
+    AccessDenied,
+    /// Anki is busy, e.g. syncing
+    Busy,
+    /// Any other error reported by Anki-Connect
+    ApiError,
+    /// The response went over the configured size limit
+    TooLarge,
+    /// The response could not be parsed
+    InvalidResponse,
+    /// The request or response did not get through
+    TransportError,
+}
+
+impl RequestOutcome {
+    /// Categorizes the result of a request
+    pub fn of<R>(result: &anyhow::Result<R>) -> Self {
+        let Err(error) = result else {
+            return RequestOutcome::Ok;
+        };
+        if let Some(error) =
+            error.downcast_ref::<AnkiError>()
+        {
+            return match error {
+                AnkiError::ConnectionFailed { .. } => {
+                    RequestOutcome::TransportError
+                }
+                AnkiError::InvalidResponse { .. } => {
+                    RequestOutcome::InvalidResponse
+                }
+                _ if error.is_duplicate() => {
+                    RequestOutcome::Duplicate
+                }
+                _ if error.is_unsupported_action() => {
+                    RequestOutcome::Unsupported
+                }
+                _ if error.is_access_denied() => {
+                    RequestOutcome::AccessDenied
+                }
+                _ if error.is_busy() => {
+                    RequestOutcome::Busy
+                }
+                _ => RequestOutcome::ApiError,
+            };
+        }
+        if error
+            .downcast_ref::<ResponseTooLarge>()
+            .is_some()
+        {
+            return RequestOutcome::TooLarge;
+        }
+        let parse_failed = error.chain().any(|cause| {
+            cause
+                .downcast_ref::<serde_json::Error>()
+                .is_some()
+        });
+        if parse_failed {
+            RequestOutcome::InvalidResponse
+        } else {
+            RequestOutcome::TransportError
+        }
+    }
+
+    /// Snake-case name, e.g. `transport_error`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestOutcome::Ok => "ok",
+            RequestOutcome::Duplicate => "duplicate",
+            RequestOutcome::Unsupported => "unsupported",
+            RequestOutcome::AccessDenied => "access_denied",
+            RequestOutcome::Busy => "busy",
+            RequestOutcome::ApiError => "api_error",
+            RequestOutcome::TooLarge => "too_large",
+            RequestOutcome::InvalidResponse => {
+                "invalid_response"
+            }
+            RequestOutcome::TransportError => {
+                "transport_error"
+            }
+        }
+    }
+}
+
+impl fmt::Display for RequestOutcome {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Callback receiving [`ClientEvent`]s
+pub type EventCallback =
+    Arc<dyn Fn(ClientEvent) + Send + Sync>;
+
+/// Registered observer; a newtype so the client stays `Debug`
+#[derive(Clone)]
+pub(crate) struct EventHook(pub(crate) EventCallback);
+
+impl fmt::Debug for EventHook {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str("EventHook")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::client::AnkiClient;
+    use super::super::mock::{
+        MockAnki, basic_note, err, ok,
+    };
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Client reporting to the returned list, with every duration
+    /// zeroed so sequences compare exactly
+    fn collecting(
+        client: AnkiClient,
+    ) -> (AnkiClient, Arc<Mutex<Vec<ClientEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let client = client.with_event_callback(Some(
+            Arc::new(move |mut event| {
+                if let ClientEvent::RequestFinished {
+                    duration,
+                    ..
+                } = &mut event
+                {
+                    *duration = Duration::ZERO;
+                }
+                sink.lock().unwrap().push(event);
+            }),
+        ));
+        (client, events)
+    }
+
+    fn started(action: &str) -> ClientEvent {
+        ClientEvent::RequestStarted {
+            action: action.to_string(),
+        }
+    }
+
+    fn finished(
+        action: &str,
+        outcome: RequestOutcome,
+    ) -> ClientEvent {
+        ClientEvent::RequestFinished {
+            action: action.to_string(),
+            duration: Duration::ZERO,
+            outcome,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_of_a_retried_request() {
+        let granted = Arc::new(AtomicBool::new(false));
+        let mock =
+            MockAnki::start(
+                move |action, _| match action {
+                    "requestPermission" => {
+                        granted
+                            .store(true, Ordering::SeqCst);
+                        ok(json!({"permission": "granted"}))
+                    }
+                    "deckNames"
+                        if granted
+                            .load(Ordering::SeqCst) =>
+                    {
+                        ok(json!(["Default"]))
+                    }
+                    "deckNames" => err("permission denied"),
+                    _ => err("unsupported action"),
+                },
+            )
+            .await;
+        let (client, events) = collecting(
+            mock.client().with_permission_negotiation(true),
+        );
+
+        client.get_deck_names(None).await.unwrap();
+        client.get_model_names().await.unwrap_err();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                started("deckNames"),
+                ClientEvent::RetryScheduled {
+                    action: "deckNames".to_string(),
+                    attempt: 1,
+                    delay: Duration::ZERO,
+                    reason: "permission granted"
+                        .to_string(),
+                },
+                finished("deckNames", RequestOutcome::Ok),
+                started("modelNames"),
+                finished(
+                    "modelNames",
+                    RequestOutcome::Unsupported
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_report_chunk_progress() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "addNotes" => ok(params["notes"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|_| json!(1))
+                        .collect()),
+                    _ => err("unsupported action"),
+                },
+            )
+            .await;
+        let (client, events) = collecting(mock.client());
+
+        let notes = ["a", "b", "c"]
+            .map(|front| basic_note("Default", front))
+            .to_vec();
+        client
+            .add_notes_chunked(notes, 2, None)
+            .await
+            .unwrap();
+        let progress = |done| ClientEvent::ChunkProgress {
+            operation: "add_notes_chunked",
+            done,
+            total: 3,
+        };
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                started("addNotes"),
+                finished("addNotes", RequestOutcome::Ok),
+                progress(2),
+                started("addNotes"),
+                finished("addNotes", RequestOutcome::Ok),
+                progress(3),
+            ]
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Minimal in-process Anki-Connect server used by the unit tests,
+//! available to other crates through the `mock` feature.
+//!
+//! Every request is recorded, and the handler decides the full JSON
+//! body that is sent back for a given `(action, params)` pair. The
+//! mock listens on TCP, on a Unix socket, or skips the network with
+//! an [`InProcessTransport`].
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde_json::{Value, json};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use tokio::net::TcpListener;
+
+use super::client::{AnkiClient, Note};
+use super::note::NoteBuilder;
+use super::transport::InProcessTransport;
+
+type Handler = dyn Fn(&str, &Value) -> Value + Send + Sync;
+
+/// Headers of one request, names lowercased
+type Headers = HashMap<String, String>;
+
+/// Requests received so far, with their headers
+type Recorded = Arc<Mutex<Vec<(Value, Headers)>>>;
+
+/// Where the mock is reachable
+enum Endpoint {
+    Http(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    InProcess,
+}
+
+/// Mock Anki-Connect endpoint listening on an ephemeral port
+pub struct MockAnki {
+    endpoint: Endpoint,
+    handler: Arc<Handler>,
+    requests: Recorded,
+}
+
+impl MockAnki {
+    /// Starts a server answering every request through `handler`
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Value
+            + Send
+            + Sync
+            + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind mock server");
+        let addr = listener
+            .local_addr()
+            .expect("Failed to read mock address");
+        let mock = Self::in_process(handler);
+        let (handler, recorded) =
+            (mock.handler.clone(), mock.requests.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) =
+                listener.accept().await
+            {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    serve(stream, handler, recorded).await;
+                });
+            }
+        });
+
+        Self {
+            endpoint: Endpoint::Http(format!(
+                "http://{}",
+                addr
+            )),
+            ..mock
+        }
+    }
+
+    /// Starts a server on the Unix socket at `path`
+    #[cfg(unix)]
+    pub async fn start_unix<F>(
+        path: &std::path::Path,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(&str, &Value) -> Value
+            + Send
+            + Sync
+            + 'static,
+    {
+        let listener = tokio::net::UnixListener::bind(path)
+            .expect("Failed to bind mock socket");
+        let mock = Self::in_process(handler);
+        let (handler, recorded) =
+            (mock.handler.clone(), mock.requests.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) =
+                listener.accept().await
+            {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    serve(stream, handler, recorded).await;
+                });
+            }
+        });
+
+        Self {
+            endpoint: Endpoint::Unix(path.to_path_buf()),
+            ..mock
+        }
+    }
+
+    /// Creates a mock whose clients call `handler` directly through
+    /// an [`InProcessTransport`]; requests carry no headers
+    pub fn in_process<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Value) -> Value
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            endpoint: Endpoint::InProcess,
+            handler: Arc::new(handler),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Creates a client pointing at the mock endpoint
+    pub fn client(&self) -> AnkiClient {
+        match &self.endpoint {
+            Endpoint::Http(url) => {
+                AnkiClient::with_url(url.clone())
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => unix_client(path),
+            Endpoint::InProcess => {
+                let handler = self.handler.clone();
+                let recorded = self.requests.clone();
+                AnkiClient::with_transport(Box::new(
+                    InProcessTransport::new(move |body| {
+                        let response = respond(
+                            handler.as_ref(),
+                            &recorded,
+                            &body,
+                            Headers::new(),
+                        );
+                        Ok(Bytes::from(
+                            response.to_string(),
+                        ))
+                    }),
+                ))
+            }
+        }
+    }
+
+    /// All request bodies received so far, in arrival order
+    pub fn requests(&self) -> Vec<Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(request, _)| request.clone())
+            .collect()
+    }
+
+    /// Headers of the requests received so far, in arrival order;
+    /// header names are lowercased
+    pub fn headers(&self) -> Vec<Headers> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, headers)| headers.clone())
+            .collect()
+    }
+
+    /// Action names received so far, in arrival order
+    pub fn actions(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|r| {
+                r["action"]
+                    .as_str()
+                    .unwrap_or("")
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(unix, any(test, feature = "unix-socket")))]
+fn unix_client(path: &std::path::Path) -> AnkiClient {
+    AnkiClient::with_transport(Box::new(
+        super::transport::HttpTransport::unix_socket(path)
+            .expect("Failed to build mock socket client"),
+    ))
+}
+
+#[cfg(all(unix, not(any(test, feature = "unix-socket"))))]
+fn unix_client(_: &std::path::Path) -> AnkiClient {
+    panic!(
+        "Clients of a Unix socket mock need the unix-socket feature"
+    )
+}
+
+/// Records `body` and answers it through `handler`
+fn respond(
+    handler: &Handler,
+    recorded: &Recorded,
+    body: &[u8],
+    headers: Headers,
+) -> Value {
+    let request: Value =
+        serde_json::from_slice(body).unwrap_or(Value::Null);
+    recorded
+        .lock()
+        .unwrap()
+        .push((request.clone(), headers));
+    let action = request["action"].as_str().unwrap_or("");
+    handler(action, &request["params"])
+}
+
+/// Wraps a value in a successful Anki-Connect response body
+pub fn ok(result: Value) -> Value {
+    json!({ "result": result, "error": null })
+}
+
+/// Builds an Anki-Connect error response body
+pub fn err(message: &str) -> Value {
+    json!({ "result": null, "error": message })
+}
+
+/// A `Basic` note in `deck_name` with only its `Front` set
+pub fn basic_note(deck_name: &str, front: &str) -> Note {
+    NoteBuilder::new(deck_name, "Basic")
+        .field("Front", front)
+        .build()
+}
+
+async fn serve<S>(
+    mut stream: S,
+    handler: Arc<Handler>,
+    recorded: Recorded,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some((head, body)) =
+        read_request(&mut stream).await
+    else {
+        return;
+    };
+    let response =
+        respond(handler.as_ref(), &recorded, &body, head);
+    let payload = response.to_string();
+    let reply = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        payload.len(),
+        payload
+    );
+    let _ = stream.write_all(reply.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Reads one HTTP request and returns its headers and body
+async fn read_request<S>(
+    stream: &mut S,
+) -> Option<(Headers, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) =
+            buf.windows(4).position(|w| w == b"\r\n\r\n")
+        {
+            break pos + 4;
+        }
+    };
+
+    let headers: Headers =
+        String::from_utf8_lossy(&buf[..header_end])
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((
+                    name.trim().to_ascii_lowercase(),
+                    value.trim().to_string(),
+                ))
+            })
+            .collect();
+    let length = headers
+        .get("content-length")
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buf.len() < header_end + length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Some((headers, buf[header_end..].to_vec()))
+}
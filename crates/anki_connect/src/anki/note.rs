@@ -0,0 +1,606 @@
+//! Convenience construction of [`Note`]s.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use super::client::{Note, NoteOptions};
+
+/// Default provenance tag format
+pub const DEFAULT_PROVENANCE_TAG: &str = "ai::{model}";
+
+/// Records which AI model generated a note, and when.
+///
+/// The tag format may use `{model}` and `{date}` (`YYYY-MM-DD`);
+/// characters Anki does not allow in tags become `_`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// Model that generated the note, e.g. `glm-4.7`
+    pub model: String,
+    /// Generation time
+    pub generated_at: DateTime<Utc>,
+    /// Tag format, `None` to skip the tag
+    pub tag_format: Option<String>,
+    /// Field receiving `model @ timestamp`; only written when the
+    /// note's model has a field of that name
+    pub field: Option<String>,
+}
+
+impl Provenance {
+    /// Provenance for `model` generated now, tagged `ai::<model>`
+    pub fn new(model: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            generated_at: Utc::now(),
+            tag_format: Some(
+                DEFAULT_PROVENANCE_TAG.to_string(),
+            ),
+            field: None,
+        }
+    }
+
+    /// Uses a custom tag format, or no tag at all
+    pub fn with_tag_format(
+        mut self,
+        tag_format: Option<impl Into<String>>,
+    ) -> Self {
+        self.tag_format = tag_format.map(Into::into);
+        self
+    }
+
+    /// Also writes the provenance into `field`
+    pub fn with_field(
+        mut self,
+        field: impl Into<String>,
+    ) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+
+    /// Sets the generation time
+    pub fn with_timestamp(
+        mut self,
+        at: DateTime<Utc>,
+    ) -> Self {
+        self.generated_at = at;
+        self
+    }
+
+    /// The rendered tag, if a tag format is set
+    pub fn tag(&self) -> Option<String> {
+        let format = self.tag_format.as_ref()?;
+        let tag =
+            format.replace("{model}", &self.model).replace(
+                "{date}",
+                &self
+                    .generated_at
+                    .format("%Y-%m-%d")
+                    .to_string(),
+            );
+        Some(
+            tag.chars()
+                .map(|c| {
+                    if c.is_whitespace() || c == '"' {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// The field value, e.g. `glm-4.7 @ 2026-01-02T03:04:05Z`
+    pub fn field_value(&self) -> String {
+        format!(
+            "{} @ {}",
+            self.model,
+            self.generated_at.format("%Y-%m-%dT%H:%M:%SZ")
+        )
+    }
+
+    /// Adds the tag to `note`, and the field if it is one of
+    /// `model_fields`, the field names of the note's model
+    pub fn apply(
+        &self,
+        note: &mut Note,
+        model_fields: &[String],
+    ) {
+        if let Some(tag) = self.tag()
+            && !note.tags.contains(&tag)
+        {
+            note.tags.push(tag);
+        }
+        if let Some(field) = &self.field
+            && model_fields.contains(field)
+        {
+            note.fields
+                .insert(field.clone(), self.field_value());
+        }
+    }
+}
+
+/// Field values of a [`Note`] keyed by field name, in insertion
+/// order.
+///
+/// Serializes as a JSON object in that order, so requests and exports
+/// are byte-stable; the first field is also the one Anki's duplicate
+/// check compares. Dereferences to an [`IndexMap`], so lookups,
+/// `insert` and iteration work as on a map.
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct NoteFields(IndexMap<String, String>);
+
+impl NoteFields {
+    /// No fields
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The fields as an unordered map
+    pub fn to_hash_map(&self) -> HashMap<String, String> {
+        self.0
+            .iter()
+            .map(|(name, value)| {
+                (name.clone(), value.clone())
+            })
+            .collect()
+    }
+}
+
+impl Deref for NoteFields {
+    type Target = IndexMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for NoteFields {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A `HashMap` has no order of its own, so its fields are sorted by
+/// name to keep the result deterministic
+impl From<HashMap<String, String>> for NoteFields {
+    fn from(fields: HashMap<String, String>) -> Self {
+        let mut fields: Vec<_> =
+            fields.into_iter().collect();
+        fields.sort();
+        Self(fields.into_iter().collect())
+    }
+}
+
+impl From<IndexMap<String, String>> for NoteFields {
+    fn from(fields: IndexMap<String, String>) -> Self {
+        Self(fields)
+    }
+}
+
+impl<K, V, const N: usize> From<[(K, V); N]> for NoteFields
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from(fields: [(K, V); N]) -> Self {
+        fields.into_iter().collect()
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for NoteFields
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(
+        fields: I,
+    ) -> Self {
+        Self(
+            fields
+                .into_iter()
+                .map(|(name, value)| {
+                    (name.into(), value.into())
+                })
+                .collect(),
+        )
+    }
+}
+
+impl IntoIterator for NoteFields {
+    type Item = (String, String);
+    type IntoIter = indexmap::map::IntoIter<String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a NoteFields {
+    type Item = (&'a String, &'a String);
+    type IntoIter = indexmap::map::Iter<'a, String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl Note {
+    /// Trims leading and trailing whitespace (including stray
+    /// newlines) from every field value; internal formatting is kept
+    pub fn trim_fields(&mut self) {
+        for value in self.fields.values_mut() {
+            let trimmed = value.trim();
+            if trimmed.len() != value.len() {
+                *value = trimmed.to_string();
+            }
+        }
+    }
+}
+
+/// Typed source of note fields.
+///
+/// Implement it for the structs a generator produces, so field names
+/// are written once instead of at every call site:
+///
+/// ```
+/// use anki_connect::anki::note::{
+///     IntoFields, NoteBuilder, NoteFields,
+/// };
+///
+/// struct VocabCard {
+///     word: String,
+///     reading: String,
+///     meaning: String,
+/// }
+///
+/// impl IntoFields for VocabCard {
+///     fn into_fields(self) -> NoteFields {
+///         NoteFields::from([
+///             ("Word", self.word),
+///             ("Reading", self.reading),
+///             ("Meaning", self.meaning),
+///         ])
+///     }
+/// }
+///
+/// let card = VocabCard {
+///     word: "猫".to_string(),
+///     reading: "ねこ".to_string(),
+///     meaning: "cat".to_string(),
+/// };
+/// let note = NoteBuilder::new("Japanese", "Vocab")
+///     .fields_from(card)
+///     .build();
+/// assert_eq!(note.fields["Reading"], "ねこ");
+/// assert_eq!(note.fields.get_index(0).unwrap().0, "Word");
+/// ```
+pub trait IntoFields {
+    /// Field values keyed by field name, in the order they should
+    /// appear on the note
+    fn into_fields(self) -> NoteFields;
+}
+
+impl IntoFields for NoteFields {
+    fn into_fields(self) -> NoteFields {
+        self
+    }
+}
+
+impl IntoFields for HashMap<String, String> {
+    fn into_fields(self) -> NoteFields {
+        self.into()
+    }
+}
+
+/// Builder for [`Note`]
+#[derive(Debug, Clone)]
+pub struct NoteBuilder {
+    note: Note,
+    trim_fields: bool,
+    provenance: Option<Provenance>,
+    model_fields: Vec<String>,
+}
+
+impl NoteBuilder {
+    /// Starts a note of `model_name` in `deck_name`
+    pub fn new(
+        deck_name: impl Into<String>,
+        model_name: impl Into<String>,
+    ) -> Self {
+        Self {
+            note: Note {
+                model_name: model_name.into(),
+                deck_name: deck_name.into(),
+                fields: NoteFields::new(),
+                tags: Vec::new(),
+                audio: None,
+                picture: None,
+                video: None,
+                options: None,
+            },
+            trim_fields: false,
+            provenance: None,
+            model_fields: Vec::new(),
+        }
+    }
+
+    /// Sets a field value
+    pub fn field(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.note.fields.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets every field produced by `fields`, keeping fields
+    /// already set under other names
+    pub fn fields_from(
+        mut self,
+        fields: impl IntoFields,
+    ) -> Self {
+        self.note.fields.extend(fields.into_fields());
+        self
+    }
+
+    /// Adds a tag
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.note.tags.push(tag.into());
+        self
+    }
+
+    /// Sets the duplicate-check options
+    pub fn options(mut self, options: NoteOptions) -> Self {
+        self.note.options = Some(options);
+        self
+    }
+
+    /// Trims the edges of every field value when building
+    pub fn trim_fields(mut self, enabled: bool) -> Self {
+        self.trim_fields = enabled;
+        self
+    }
+
+    /// Declares the field names of the note's model, as returned by
+    /// `get_model_field_names`; the provenance field is only written
+    /// when it is one of them, so without these it is never written
+    pub fn model_fields(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.model_fields =
+            names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Records the AI model that generated the note.
+    ///
+    /// A [`Provenance::field`] needs [`NoteBuilder::model_fields`]
+    /// as well; [`NoteBuilder::try_build`] fails without them.
+    pub fn provenance(
+        mut self,
+        provenance: Provenance,
+    ) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Like [`NoteBuilder::build`], but fails if the provenance has
+    /// a field and no model fields were declared, instead of leaving
+    /// the field out
+    pub fn try_build(self) -> Result<Note> {
+        if let Some(field) = self
+            .provenance
+            .as_ref()
+            .and_then(|p| p.field.as_ref())
+        {
+            anyhow::ensure!(
+                !self.model_fields.is_empty(),
+                "Provenance field {} needs the model's field names",
+                field
+            );
+        }
+        Ok(self.build())
+    }
+
+    /// Builds the note; a provenance field is left out unless it is
+    /// one of the declared [`NoteBuilder::model_fields`]
+    pub fn build(self) -> Note {
+        let mut note = self.note;
+        if self.trim_fields {
+            note.trim_fields();
+        }
+        if let Some(provenance) = &self.provenance {
+            provenance.apply(&mut note, &self.model_fields);
+        }
+        note
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_fields_keeps_internal_whitespace() {
+        let note = NoteBuilder::new("Default", "Basic")
+            .field("Front", "\n  猫  ねこ \n")
+            .field("Back", "line one\n\n  line two\t")
+            .tag("ai")
+            .trim_fields(true)
+            .build();
+        assert_eq!(note.fields["Front"], "猫  ねこ");
+        assert_eq!(
+            note.fields["Back"],
+            "line one\n\n  line two"
+        );
+        assert_eq!(note.tags, vec!["ai"]);
+    }
+
+    fn generated_at() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_provenance_tag_is_added() {
+        let note = NoteBuilder::new("Default", "Basic")
+            .field("Front", "猫")
+            .tag("vocab")
+            .provenance(
+                Provenance::new("glm-4.7")
+                    .with_timestamp(generated_at()),
+            )
+            .build();
+        assert_eq!(note.tags, vec!["vocab", "ai::glm-4.7"]);
+        assert_eq!(note.fields.len(), 1);
+    }
+
+    #[test]
+    fn test_provenance_custom_format_and_field() {
+        let provenance = Provenance::new("glm 4.7 flash")
+            .with_timestamp(generated_at())
+            .with_tag_format(Some("gen::{model}::{date}"))
+            .with_field("Source");
+        let note = NoteBuilder::new("Default", "Basic")
+            .model_fields(["Front", "Back", "Source"])
+            .provenance(provenance.clone())
+            .provenance(provenance)
+            .build();
+        assert_eq!(
+            note.tags,
+            vec!["gen::glm_4.7_flash::2026-01-02"]
+        );
+        assert_eq!(
+            note.fields["Source"],
+            "glm 4.7 flash @ 2026-01-02T03:04:05Z"
+        );
+    }
+
+    #[test]
+    fn test_provenance_field_skipped_when_model_lacks_it() {
+        let provenance = Provenance::new("glm-4.7")
+            .with_timestamp(generated_at())
+            .with_field("Source");
+        let note = NoteBuilder::new("Default", "Basic")
+            .model_fields(["Front", "Back"])
+            .field("Front", "猫")
+            .provenance(provenance.clone())
+            .build();
+        assert_eq!(note.tags, vec!["ai::glm-4.7"]);
+        assert!(!note.fields.contains_key("Source"));
+
+        // Unknown model fields: nothing to check against
+        let builder = NoteBuilder::new("Default", "Basic")
+            .provenance(provenance);
+        assert!(builder.clone().try_build().is_err());
+        assert!(builder.build().fields.is_empty());
+        assert!(
+            NoteBuilder::new("Default", "Basic")
+                .provenance(Provenance::new("glm-4.7"))
+                .try_build()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_fields_untouched_by_default() {
+        let note = NoteBuilder::new("Default", "Basic")
+            .field("Front", " padded ")
+            .build();
+        assert_eq!(note.fields["Front"], " padded ");
+        assert_eq!(note.model_name, "Basic");
+        assert_eq!(note.deck_name, "Default");
+    }
+
+    struct VocabCard {
+        word: &'static str,
+        reading: &'static str,
+        meaning: &'static str,
+    }
+
+    impl IntoFields for VocabCard {
+        fn into_fields(self) -> NoteFields {
+            NoteFields::from([
+                ("Word", self.word),
+                ("Reading", self.reading),
+                ("Meaning", self.meaning),
+            ])
+        }
+    }
+
+    #[test]
+    fn test_fields_from_struct() {
+        let card = VocabCard {
+            word: "猫",
+            reading: "ねこ",
+            meaning: "cat",
+        };
+        let note = NoteBuilder::new("Japanese", "Vocab")
+            .field("Source", "textbook")
+            .fields_from(card)
+            .build();
+        let fields: Vec<_> = note
+            .fields
+            .iter()
+            .map(|(name, value)| {
+                (name.as_str(), value.as_str())
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("Source", "textbook"),
+                ("Word", "猫"),
+                ("Reading", "ねこ"),
+                ("Meaning", "cat"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fields_serialize_in_insertion_order() {
+        let note = NoteBuilder::new("Japanese", "Vocab")
+            .field("Word", "猫")
+            .field("Reading", "ねこ")
+            .field("Meaning", "cat")
+            .build();
+        let json =
+            serde_json::to_string(&note.fields).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Word":"猫","Reading":"ねこ","Meaning":"cat"}"#
+        );
+        let parsed: NoteFields =
+            serde_json::from_str(r#"{"B":"2","A":"1"}"#)
+                .unwrap();
+        assert_eq!(parsed.get_index(0).unwrap().0, "B");
+
+        let unordered = HashMap::from([
+            ("Meaning".to_string(), "cat".to_string()),
+            ("Word".to_string(), "猫".to_string()),
+            ("Reading".to_string(), "ねこ".to_string()),
+        ]);
+        let sorted: Vec<_> = NoteFields::from(unordered)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(sorted, ["Meaning", "Reading", "Word"]);
+    }
+}
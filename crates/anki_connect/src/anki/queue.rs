@@ -0,0 +1,727 @@
+//! Offline queue for notes captured while Anki is unreachable.
+//!
+//! Every queued note is a JSON file named after its sequence number,
+//! so entries survive restarts and are flushed in the order they were
+//! enqueued. Entries that keep failing are moved to the `dead`
+//! subdirectory instead of being retried forever.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utils::paths::AppDir;
+
+use super::client::{AnkiClient, Note};
+use super::error::{AnkiError, ConnectionFailure};
+
+/// Subdirectory of the data directory holding the default queue
+pub const QUEUE_DIR_NAME: &str = "offline_queue";
+
+/// Failed attempts after which an entry is dead-lettered by default
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Subdirectory of the queue holding dead-lettered entries
+const DEAD_DIR_NAME: &str = "dead";
+
+/// File held by the flush in progress
+const LOCK_FILE_NAME: &str = "flush.lock";
+
+/// Why the last attempt to add a queued note failed
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// Anki rejected the note as a duplicate
+    Duplicate,
+    /// Any other error reported by Anki
+    Error,
+}
+
+/// A note waiting in the queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedNote {
+    /// Sequence number, increasing in enqueue order
+    pub seq: u64,
+    /// When the note was enqueued
+    pub enqueued_at: DateTime<Utc>,
+    /// Failed attempts to add the note so far
+    pub attempts: u32,
+    /// Kind of the last failure, if any
+    pub last_failure: Option<FailureKind>,
+    /// Message of the last failure, if any
+    pub last_error: Option<String>,
+    /// The note to add
+    pub note: Note,
+}
+
+/// Outcome of [`OfflineQueue::flush`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlushReport {
+    /// IDs of the notes added, in queue order
+    pub added: Vec<u64>,
+    /// Sequence numbers of entries rejected as duplicates
+    pub duplicates: Vec<u64>,
+    /// Sequence numbers of entries that failed otherwise
+    pub failed: Vec<u64>,
+    /// Sequence numbers of the failed entries that were moved to
+    /// the dead letters
+    pub dead_lettered: Vec<u64>,
+    /// Whether the flush stopped because Anki was unreachable
+    pub unreachable: bool,
+}
+
+/// Result of [`QueueingClient::add_note`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuedAdd {
+    /// The note was added to Anki with this ID
+    Added(u64),
+    /// Anki was unreachable; the note was queued under this
+    /// sequence number
+    Queued(u64),
+}
+
+/// Directory-backed queue of notes to add once Anki is reachable
+#[derive(Debug, Clone)]
+pub struct OfflineQueue {
+    /// Directory holding the pending entries
+    dir: PathBuf,
+    /// Failed attempts after which an entry is dead-lettered
+    max_attempts: u32,
+}
+
+impl OfflineQueue {
+    /// Opens (creating if needed) the queue stored in `dir`
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let dead = dir.join(DEAD_DIR_NAME);
+        fs::create_dir_all(&dead).with_context(|| {
+            format!("Failed to create {}", dead.display())
+        })?;
+        Ok(Self {
+            dir,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        })
+    }
+
+    /// Opens the queue in the [`QUEUE_DIR_NAME`] subdirectory of
+    /// the data directory
+    pub fn open_default() -> Result<Self> {
+        Self::open(
+            AppDir::Data.ensure()?.join(QUEUE_DIR_NAME),
+        )
+    }
+
+    /// Sets the failed attempts after which an entry is
+    /// dead-lettered (at least 1)
+    pub fn with_max_attempts(
+        mut self,
+        attempts: u32,
+    ) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Directory holding the pending entries
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stores `note` at the end of the queue.
+    ///
+    /// # Returns
+    /// The sequence number of the new entry
+    pub fn enqueue(&self, note: Note) -> Result<u64> {
+        let mut entry = QueuedNote {
+            seq: self.next_seq()?,
+            enqueued_at: Utc::now(),
+            attempts: 0,
+            last_failure: None,
+            last_error: None,
+            note,
+        };
+        let tmp = self.write_tmp(&entry)?;
+        // Linking fails if the name is taken, so a concurrent
+        // enqueue can never overwrite an entry
+        let linked = loop {
+            let path = entry_path(&self.dir, entry.seq);
+            match fs::hard_link(&tmp, &path) {
+                Err(e)
+                    if e.kind()
+                        == ErrorKind::AlreadyExists =>
+                {
+                    entry.seq += 1;
+                    fs::write(
+                        &tmp,
+                        serde_json::to_vec(&entry)?,
+                    )?;
+                }
+                other => {
+                    break other.with_context(|| {
+                        format!(
+                            "Failed to write {}",
+                            path.display()
+                        )
+                    });
+                }
+            }
+        };
+        let _ = fs::remove_file(&tmp);
+        self.record_depth();
+        linked.map(|()| entry.seq)
+    }
+
+    /// Entries waiting to be added, in queue order
+    pub fn pending(&self) -> Result<Vec<QueuedNote>> {
+        read_entries(&self.dir)
+    }
+
+    /// Entries that were given up on, in queue order
+    pub fn dead_letters(&self) -> Result<Vec<QueuedNote>> {
+        read_entries(&self.dir.join(DEAD_DIR_NAME))
+    }
+
+    /// Adds the queued notes to Anki in order.
+    ///
+    /// An entry is removed only once Anki confirms the note was
+    /// added. Failed entries stay in place with their attempt
+    /// counter raised until they reach the maximum and are
+    /// dead-lettered. The flush stops at the first connection
+    /// error, leaving that entry and the rest untouched.
+    ///
+    /// Fails without touching the queue if another flush holds
+    /// the lock file.
+    pub async fn flush(
+        &self,
+        anki_client: &AnkiClient,
+    ) -> Result<FlushReport> {
+        let _lock = FlushLock::acquire(&self.dir)?;
+        let mut report = FlushReport::default();
+
+        for mut entry in self.pending()? {
+            let error = match anki_client
+                .add_note(entry.note.clone())
+                .await
+            {
+                Ok(note_id) => {
+                    fs::remove_file(entry_path(
+                        &self.dir, entry.seq,
+                    ))?;
+                    report.added.push(note_id);
+                    continue;
+                }
+                Err(e) if is_unreachable(&e) => {
+                    report.unreachable = true;
+                    break;
+                }
+                Err(e) => e,
+            };
+
+            let kind = if error
+                .downcast_ref::<AnkiError>()
+                .is_some_and(AnkiError::is_duplicate)
+            {
+                report.duplicates.push(entry.seq);
+                FailureKind::Duplicate
+            } else {
+                report.failed.push(entry.seq);
+                FailureKind::Error
+            };
+            entry.attempts += 1;
+            entry.last_failure = Some(kind);
+            entry.last_error = Some(format!("{:#}", error));
+
+            if entry.attempts >= self.max_attempts {
+                self.replace(
+                    &entry,
+                    &self.dir.join(DEAD_DIR_NAME),
+                )?;
+                fs::remove_file(entry_path(
+                    &self.dir, entry.seq,
+                ))?;
+                report.dead_lettered.push(entry.seq);
+            } else {
+                self.replace(&entry, &self.dir)?;
+            }
+        }
+        self.record_depth();
+        Ok(report)
+    }
+
+    /// Publishes the entry counts as metrics; does nothing without
+    /// the `metrics` feature
+    fn record_depth(&self) {
+        #[cfg(any(test, feature = "metrics"))]
+        if let (Ok(pending), Ok(dead)) = (
+            entry_seqs(&self.dir),
+            entry_seqs(&self.dir.join(DEAD_DIR_NAME)),
+        ) {
+            super::telemetry::record_queue(
+                pending.len(),
+                dead.len(),
+            );
+        }
+    }
+
+    /// Next free sequence number, counting dead letters so numbers
+    /// are never reused
+    fn next_seq(&self) -> Result<u64> {
+        let last = [
+            self.dir.clone(),
+            self.dir.join(DEAD_DIR_NAME),
+        ]
+        .iter()
+        .map(|dir| entry_seqs(dir))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .max();
+        Ok(last.map_or(1, |seq| seq + 1))
+    }
+
+    /// Writes `entry` to a temporary file in the queue directory
+    fn write_tmp(
+        &self,
+        entry: &QueuedNote,
+    ) -> Result<PathBuf> {
+        let tmp = self.dir.join(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+        ));
+        fs::write(&tmp, serde_json::to_vec(entry)?)
+            .with_context(|| {
+                format!("Failed to write {}", tmp.display())
+            })?;
+        Ok(tmp)
+    }
+
+    /// Atomically writes `entry` into `dir`, replacing any entry
+    /// with the same sequence number
+    fn replace(
+        &self,
+        entry: &QueuedNote,
+        dir: &Path,
+    ) -> Result<()> {
+        let tmp = self.write_tmp(entry)?;
+        let path = entry_path(dir, entry.seq);
+        fs::rename(&tmp, &path).with_context(|| {
+            format!("Failed to write {}", path.display())
+        })
+    }
+}
+
+/// [`AnkiClient`] that queues notes instead of failing while Anki is
+/// unreachable.
+///
+/// Every other method is the wrapped client's, through `Deref`.
+#[derive(Debug, Clone)]
+pub struct QueueingClient {
+    /// Client used for every request
+    client: AnkiClient,
+    /// Queue receiving notes Anki could not be reached for
+    queue: OfflineQueue,
+}
+
+impl QueueingClient {
+    /// Wraps `client`, queueing into `queue`
+    pub fn new(
+        client: AnkiClient,
+        queue: OfflineQueue,
+    ) -> Self {
+        Self { client, queue }
+    }
+
+    /// The queue notes are stored in
+    pub fn queue(&self) -> &OfflineQueue {
+        &self.queue
+    }
+
+    /// Adds a note, or queues it on a connection error.
+    ///
+    /// Any other error, a duplicate included, is returned as is.
+    pub async fn add_note(
+        &self,
+        note: Note,
+    ) -> Result<QueuedAdd> {
+        match self.client.add_note(note.clone()).await {
+            Ok(note_id) => Ok(QueuedAdd::Added(note_id)),
+            Err(e) if is_unreachable(&e) => self
+                .queue
+                .enqueue(note)
+                .map(QueuedAdd::Queued),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Flushes the queue through the wrapped client
+    pub async fn flush(&self) -> Result<FlushReport> {
+        self.queue.flush(&self.client).await
+    }
+}
+
+impl Deref for QueueingClient {
+    type Target = AnkiClient;
+
+    fn deref(&self) -> &AnkiClient {
+        &self.client
+    }
+}
+
+/// Lock file held for the duration of a flush
+struct FlushLock {
+    path: PathBuf,
+}
+
+impl FlushLock {
+    /// Creates the lock file, failing if it already exists
+    fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE_NAME);
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Err(e)
+                if e.kind() == ErrorKind::AlreadyExists =>
+            {
+                anyhow::bail!(
+                    "Another flush is in progress; remove {} if it was interrupted",
+                    path.display()
+                )
+            }
+            other => other.with_context(|| {
+                format!(
+                    "Failed to create {}",
+                    path.display()
+                )
+            })?,
+        };
+        let _ = write!(file, "{}", std::process::id());
+        Ok(Self { path })
+    }
+}
+
+impl Drop for FlushLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Whether an `add_note` error means Anki could not be reached, so
+/// the note was certainly not added, whatever the transport
+fn is_unreachable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<AnkiError>()
+            .and_then(AnkiError::connection_failure)
+            == Some(ConnectionFailure::Connect)
+    })
+}
+
+fn entry_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:012}.json", seq))
+}
+
+/// Sequence numbers of the entries in `dir`, unordered
+fn entry_seqs(dir: &Path) -> Result<Vec<u64>> {
+    let mut seqs = Vec::new();
+    for item in fs::read_dir(dir).with_context(|| {
+        format!("Failed to read {}", dir.display())
+    })? {
+        let name = item?.file_name();
+        if let Some(seq) = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|stem| stem.parse().ok())
+        {
+            seqs.push(seq);
+        }
+    }
+    Ok(seqs)
+}
+
+/// Entries stored in `dir`, in sequence order
+fn read_entries(dir: &Path) -> Result<Vec<QueuedNote>> {
+    let mut seqs = entry_seqs(dir)?;
+    seqs.sort_unstable();
+    seqs.into_iter()
+        .map(|seq| {
+            let path = entry_path(dir, seq);
+            let text =
+                fs::read(&path).with_context(|| {
+                    format!(
+                        "Failed to read {}",
+                        path.display()
+                    )
+                })?;
+            serde_json::from_slice(&text).with_context(
+                || {
+                    format!(
+                        "Failed to parse {}",
+                        path.display()
+                    )
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{
+        MockAnki, basic_note, err, ok,
+    };
+    use super::*;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_queue_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Client for a port nothing listens on
+    fn unreachable_client() -> AnkiClient {
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        AnkiClient::with_url(format!("http://{}", addr))
+    }
+
+    /// Client whose transport fails every request with `kind`
+    fn failing_client(
+        kind: ConnectionFailure,
+    ) -> AnkiClient {
+        AnkiClient::with_transport(Box::new(
+            super::super::transport::InProcessTransport::new(
+                move |_| {
+                    Err(AnkiError::transport_failed(
+                        kind,
+                        "socket gone",
+                        std::io::Error::from(
+                            ErrorKind::NotFound,
+                        ),
+                    )
+                    .into())
+                },
+            ),
+        ))
+    }
+
+    fn front(params: &Value) -> &str {
+        params["note"]["fields"]["Front"]
+            .as_str()
+            .unwrap_or("")
+    }
+
+    #[tokio::test]
+    async fn test_down_up_cycle_adds_in_order_exactly_once()
+    {
+        let queue =
+            OfflineQueue::open(temp_dir("cycle")).unwrap();
+        let down = QueueingClient::new(
+            unreachable_client(),
+            queue.clone(),
+        );
+        for word in ["一", "二", "三"] {
+            assert!(matches!(
+                down.add_note(basic_note("Inbox", word))
+                    .await
+                    .unwrap(),
+                QueuedAdd::Queued(_)
+            ));
+        }
+        let report = down.flush().await.unwrap();
+        assert!(report.unreachable);
+        assert!(report.added.is_empty());
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 3);
+        assert!(pending.iter().all(|e| e.attempts == 0));
+
+        let next_id = AtomicU64::new(100);
+        let mock =
+            MockAnki::start(
+                move |action, _| match action {
+                    "addNote" => ok(next_id
+                        .fetch_add(1, Ordering::SeqCst)
+                        .into()),
+                    other => panic!(
+                        "unexpected action {}",
+                        other
+                    ),
+                },
+            )
+            .await;
+        let up = QueueingClient::new(mock.client(), queue);
+        let report = up.flush().await.unwrap();
+        assert_eq!(report.added, [100, 101, 102]);
+        assert!(!report.unreachable);
+        assert_eq!(
+            mock.requests()
+                .iter()
+                .map(|r| front(&r["params"]).to_string())
+                .collect::<Vec<_>>(),
+            ["一", "二", "三"]
+        );
+
+        assert_eq!(
+            up.flush().await.unwrap(),
+            FlushReport::default()
+        );
+        assert_eq!(
+            up.add_note(basic_note("Inbox", "四"))
+                .await
+                .unwrap(),
+            QueuedAdd::Added(103)
+        );
+        assert_eq!(mock.actions().len(), 4);
+        assert!(up.queue().pending().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_other_transports_are_classified_by_kind()
+    {
+        let queue =
+            OfflineQueue::open(temp_dir("transport"))
+                .unwrap();
+        let down = QueueingClient::new(
+            failing_client(ConnectionFailure::Connect),
+            queue.clone(),
+        );
+        assert!(matches!(
+            down.add_note(basic_note("Inbox", "一"))
+                .await
+                .unwrap(),
+            QueuedAdd::Queued(_)
+        ));
+        assert!(down.flush().await.unwrap().unreachable);
+
+        // The note may have been added before the connection broke
+        let broken = QueueingClient::new(
+            failing_client(ConnectionFailure::Disconnected),
+            queue.clone(),
+        );
+        assert!(
+            broken
+                .add_note(basic_note("Inbox", "二"))
+                .await
+                .is_err()
+        );
+        assert_eq!(queue.pending().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_kept_then_dead_lettered() {
+        let queue = OfflineQueue::open(temp_dir("dead"))
+            .unwrap()
+            .with_max_attempts(2);
+        for word in ["dup", "ok", "broken"] {
+            queue
+                .enqueue(basic_note("Inbox", word))
+                .unwrap();
+        }
+        let mock = MockAnki::start(|action, params| {
+            match (action, front(params)) {
+                ("addNote", "dup") => err(
+                    "cannot create note because it is a duplicate",
+                ),
+                ("addNote", "ok") => ok(7.into()),
+                ("addNote", _) => err("deck was not found: Inbox"),
+                _ => err("unsupported action"),
+            }
+        })
+        .await;
+        let client = mock.client();
+
+        let report = queue.flush(&client).await.unwrap();
+        assert_eq!(report.added, [7]);
+        assert_eq!(report.duplicates, [1]);
+        assert_eq!(report.failed, [3]);
+        assert!(report.dead_lettered.is_empty());
+        let pending = queue.pending().unwrap();
+        assert_eq!(
+            pending
+                .iter()
+                .map(|e| (
+                    e.seq,
+                    e.attempts,
+                    e.last_failure
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (1, 1, Some(FailureKind::Duplicate)),
+                (3, 1, Some(FailureKind::Error)),
+            ]
+        );
+
+        let report = queue.flush(&client).await.unwrap();
+        assert_eq!(report.dead_lettered, [1, 3]);
+        assert!(queue.pending().unwrap().is_empty());
+        let dead = queue.dead_letters().unwrap();
+        assert_eq!(dead.len(), 2);
+        assert!(
+            dead[1]
+                .last_error
+                .as_deref()
+                .unwrap()
+                .contains("deck was not found")
+        );
+
+        assert_eq!(
+            queue
+                .enqueue(basic_note("Inbox", "new"))
+                .unwrap(),
+            4
+        );
+        let adds = mock
+            .actions()
+            .iter()
+            .filter(|a| *a == "addNote")
+            .count();
+        assert_eq!(adds, 5);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_flush_is_refused() {
+        let queue =
+            OfflineQueue::open(temp_dir("lock")).unwrap();
+        queue.enqueue(basic_note("Inbox", "held")).unwrap();
+        let lock = FlushLock::acquire(queue.dir()).unwrap();
+
+        let error = queue
+            .flush(&unreachable_client())
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Another flush")
+        );
+        assert_eq!(queue.pending().unwrap().len(), 1);
+
+        drop(lock);
+        let report = queue
+            .flush(&unreachable_client())
+            .await
+            .unwrap();
+        assert!(report.unreachable);
+    }
+}
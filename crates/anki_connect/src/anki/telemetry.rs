@@ -0,0 +1,202 @@
+//! Metrics emitted through the `metrics` facade.
+//!
+//! Nothing is recorded until the application installs a recorder,
+//! e.g. a Prometheus exporter. Labels only ever carry action names
+//! and fixed outcome categories, never note IDs or field content, so
+//! their cardinality stays bounded.
+//!
+//! | Metric | Kind | Labels |
+//! |---|---|---|
+//! | [`REQUESTS`] | counter | `action`, `outcome` |
+//! | [`REQUEST_DURATION`] | histogram (seconds) | `action`, `outcome` |
+//! | [`QUEUE_DEPTH`] | gauge | |
+//! | [`QUEUE_DEAD_LETTERS`] | gauge | |
+
+use std::time::Duration;
+
+use super::events::RequestOutcome;
+
+/// Anki-Connect requests made through `AnkiClient::invoke`
+pub const REQUESTS: &str = "anki_connect_requests_total";
+
+/// Time from sending a request to parsing its answer
+pub const REQUEST_DURATION: &str =
+    "anki_connect_request_duration_seconds";
+
+/// Entries waiting in the offline queue
+pub const QUEUE_DEPTH: &str =
+    "anki_connect_offline_queue_depth";
+
+/// Entries the offline queue gave up on
+pub const QUEUE_DEAD_LETTERS: &str =
+    "anki_connect_offline_queue_dead_letters";
+
+/// Records one finished `invoke` of `action`
+pub(crate) fn record_request(
+    action: &str,
+    outcome: RequestOutcome,
+    elapsed: Duration,
+) {
+    let labels = [
+        ("action", action.to_string()),
+        ("outcome", outcome.as_str().to_string()),
+    ];
+    metrics::counter!(REQUESTS, &labels).increment(1);
+    metrics::histogram!(REQUEST_DURATION, &labels)
+        .record(elapsed.as_secs_f64());
+}
+
+/// Publishes the offline queue's entry counts
+pub(crate) fn record_queue(pending: usize, dead: usize) {
+    metrics::gauge!(QUEUE_DEPTH).set(pending as f64);
+    metrics::gauge!(QUEUE_DEAD_LETTERS).set(dead as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::client::AnkiClient;
+    use super::super::mock::{
+        MockAnki, basic_note, err, ok,
+    };
+    use super::super::queue::{
+        OfflineQueue, QueueingClient,
+    };
+    use super::*;
+    use metrics_util::debugging::{
+        DebugValue, DebuggingRecorder, Snapshotter,
+    };
+    use serde_json::json;
+
+    /// Name, labels and value of every metric recorded so far,
+    /// sorted; a histogram's value is its sample count
+    fn recorded(
+        snapshotter: &Snapshotter,
+    ) -> Vec<(String, Vec<String>, f64)> {
+        let mut recorded: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels = key
+                    .labels()
+                    .map(|l| {
+                        format!("{}={}", l.key(), l.value())
+                    })
+                    .collect();
+                let value = match value {
+                    DebugValue::Counter(n) => n as f64,
+                    DebugValue::Gauge(n) => n.into_inner(),
+                    DebugValue::Histogram(samples) => {
+                        samples.len() as f64
+                    }
+                };
+                (key.name().to_string(), labels, value)
+            })
+            .collect();
+        recorded.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        recorded
+    }
+
+    #[tokio::test]
+    async fn test_records_requests_and_queue_depth() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // Current-thread runtime, so every call below records here
+        let _guard =
+            metrics::set_default_local_recorder(&recorder);
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_telemetry_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "addNote"
+                        if params["note"]["fields"]["Front"]
+                            == "busy" =>
+                    {
+                        err("collection is not available")
+                    }
+                    "addNote" => ok(json!(1)),
+                    _ => err("unsupported action"),
+                },
+            )
+            .await;
+        let client = mock.client();
+        client
+            .add_note(basic_note("Default", "猫"))
+            .await
+            .unwrap();
+        client
+            .add_note(basic_note("Default", "猫"))
+            .await
+            .unwrap();
+        client
+            .add_note(basic_note("Default", "busy"))
+            .await
+            .unwrap_err();
+        client.get_deck_names(None).await.unwrap_err();
+
+        // Nothing listens on port 9, so the note is queued
+        let offline = QueueingClient::new(
+            AnkiClient::with_url("http://127.0.0.1:9"),
+            OfflineQueue::open(&dir).unwrap(),
+        );
+        offline
+            .add_note(basic_note("Default", "犬"))
+            .await
+            .unwrap();
+
+        let metric =
+            |name: &str, labels: &[&str], value| {
+                (
+                    name.to_string(),
+                    labels
+                        .iter()
+                        .map(|l| l.to_string())
+                        .collect(),
+                    value,
+                )
+            };
+        let add = |outcome: &'static str| {
+            ["action=addNote", outcome]
+        };
+        let deck_names =
+            ["action=deckNames", "outcome=unsupported"];
+        assert_eq!(
+            recorded(&snapshotter),
+            [
+                metric(QUEUE_DEAD_LETTERS, &[], 0.0),
+                metric(QUEUE_DEPTH, &[], 1.0),
+                metric(
+                    REQUEST_DURATION,
+                    &add("outcome=busy"),
+                    1.0
+                ),
+                metric(
+                    REQUEST_DURATION,
+                    &add("outcome=ok"),
+                    2.0
+                ),
+                metric(
+                    REQUEST_DURATION,
+                    &add("outcome=transport_error"),
+                    1.0
+                ),
+                metric(REQUEST_DURATION, &deck_names, 1.0),
+                metric(REQUESTS, &add("outcome=busy"), 1.0),
+                metric(REQUESTS, &add("outcome=ok"), 2.0),
+                metric(
+                    REQUESTS,
+                    &add("outcome=transport_error"),
+                    1.0
+                ),
+                metric(REQUESTS, &deck_names, 1.0),
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
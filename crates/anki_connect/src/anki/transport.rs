@@ -0,0 +1,389 @@
+//! How request bodies reach Anki-Connect.
+//!
+//! [`AnkiClient`](super::client::AnkiClient) serializes every request
+//! to JSON and hands the bytes to a [`Transport`], which returns the
+//! raw response body. [`HttpTransport`] is the default; a client can
+//! be pointed at any other implementation with
+//! [`AnkiClient::with_transport`](super::client::AnkiClient::with_transport).
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response};
+
+use super::error::AnkiError;
+
+/// Future returned by [`Transport::call`]
+pub type TransportFuture<'a> = Pin<
+    Box<dyn Future<Output = Result<Bytes>> + Send + 'a>,
+>;
+
+/// Carries one Anki-Connect request body to the server and back.
+///
+/// The future is boxed so the trait can be used as
+/// `Box<dyn Transport>`.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Sends one JSON request body and returns the response body
+    fn call(&self, body: Bytes) -> TransportFuture<'_>;
+
+    /// Like [`Transport::call`], but fails with
+    /// [`ResponseTooLarge`] if the response body is longer than
+    /// `limit` bytes.
+    ///
+    /// The default implementation checks the body once it has been
+    /// received in full; [`HttpTransport`] stops reading as soon as
+    /// the limit is passed.
+    fn call_limited(
+        &self,
+        body: Bytes,
+        limit: usize,
+    ) -> TransportFuture<'_> {
+        let call = self.call(body);
+        Box::pin(async move {
+            let response = call.await?;
+            if response.len() > limit {
+                return Err(ResponseTooLarge {
+                    size: response.len(),
+                    limit,
+                }
+                .into());
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// A response body went over the limit given to
+/// [`Transport::call_limited`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseTooLarge {
+    /// Size of the body in bytes, as announced by the server or
+    /// received before reading stopped; a lower bound
+    pub size: usize,
+    /// The limit in bytes
+    pub limit: usize,
+}
+
+impl fmt::Display for ResponseTooLarge {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "response body of at least {} bytes exceeds the {}-byte limit",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+/// Posts request bodies to an HTTP endpoint with reqwest
+#[derive(Debug, Clone)]
+pub struct HttpTransport {
+    /// HTTP client for making requests
+    client: Client,
+    /// Anki-Connect endpoint URL
+    url: String,
+}
+
+impl HttpTransport {
+    /// Posts to `url` through `client`
+    pub fn new(
+        client: Client,
+        url: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            url: url.into(),
+        }
+    }
+
+    /// Talks HTTP over the Unix domain socket at `path`, e.g. one
+    /// forwarded by a sidecar container
+    #[cfg(all(unix, any(test, feature = "unix-socket")))]
+    pub fn unix_socket(
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Self> {
+        use anyhow::Context;
+
+        let client = Client::builder()
+            .unix_socket(path.as_ref().to_path_buf())
+            .build()
+            .context("Failed to build HTTP client")?;
+        // The host is never resolved; it only fills the Host header
+        Ok(Self::new(client, "http://localhost"))
+    }
+
+    /// The endpoint URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Posts `body` and waits for the response headers
+    async fn post(&self, body: Bytes) -> Result<Response> {
+        self.client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| {
+                AnkiError::connection_failed(
+                    "Failed to send request to Anki-Connect",
+                    e,
+                )
+                .into()
+            })
+    }
+}
+
+impl Transport for HttpTransport {
+    fn call(&self, body: Bytes) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let response = self.post(body).await?;
+            Ok(response
+                .bytes()
+                .await
+                .map_err(read_failed)?)
+        })
+    }
+
+    fn call_limited(
+        &self,
+        body: Bytes,
+        limit: usize,
+    ) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let mut response = self.post(body).await?;
+            let announced =
+                response.content_length().map(|size| {
+                    usize::try_from(size)
+                        .unwrap_or(usize::MAX)
+                });
+            if let Some(size) = announced
+                && size > limit
+            {
+                return Err(ResponseTooLarge {
+                    size,
+                    limit,
+                }
+                .into());
+            }
+            let mut received = BytesMut::with_capacity(
+                announced.unwrap_or(0),
+            );
+            while let Some(chunk) = response
+                .chunk()
+                .await
+                .map_err(read_failed)?
+            {
+                let size = received.len() + chunk.len();
+                if size > limit {
+                    return Err(ResponseTooLarge {
+                        size,
+                        limit,
+                    }
+                    .into());
+                }
+                received.extend_from_slice(&chunk);
+            }
+            Ok(received.freeze())
+        })
+    }
+}
+
+/// Error for a response body that broke off
+fn read_failed(source: reqwest::Error) -> AnkiError {
+    AnkiError::connection_failed(
+        "Failed to read response from Anki-Connect",
+        source,
+    )
+}
+
+type Handler = dyn Fn(Bytes) -> Result<Bytes> + Send + Sync;
+
+/// Answers request bodies with a function in the same process,
+/// bypassing the network entirely
+#[derive(Clone)]
+pub struct InProcessTransport {
+    handler: Arc<Handler>,
+}
+
+impl InProcessTransport {
+    /// Answers every request body with `handler`
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(Bytes) -> Result<Bytes>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl fmt::Debug for InProcessTransport {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("InProcessTransport")
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transport for InProcessTransport {
+    fn call(&self, body: Bytes) -> TransportFuture<'_> {
+        Box::pin(std::future::ready((self.handler)(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::client::AnkiClient;
+    use super::super::mock::{
+        MockAnki, basic_note, err, ok,
+    };
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_in_process_transport_end_to_end() {
+        let mock =
+            MockAnki::in_process(|action, params| {
+                match action {
+                    "addNote" => ok(json!(11)),
+                    "findNotes" => {
+                        assert_eq!(
+                            params["query"],
+                            "deck:Default"
+                        );
+                        ok(json!([11]))
+                    }
+                    _ => err("unsupported action"),
+                }
+            });
+        let client = mock.client();
+        assert_eq!(client.url(), "");
+
+        assert_eq!(
+            client
+                .add_note(basic_note("Default", "猫"))
+                .await
+                .unwrap(),
+            11
+        );
+        assert_eq!(
+            client
+                .find_notes("deck:Default")
+                .await
+                .unwrap(),
+            [11]
+        );
+        assert!(client.get_deck_names(None).await.is_err());
+        assert_eq!(
+            mock.actions(),
+            ["addNote", "findNotes", "deckNames"]
+        );
+        assert_eq!(
+            mock.requests()[0]["params"]["note"]["fields"]
+                ["Front"],
+            "猫"
+        );
+        assert!(mock.headers()[0].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transport_errors_are_returned() {
+        let client = AnkiClient::with_transport(Box::new(
+            InProcessTransport::new(|_| {
+                anyhow::bail!("sidecar is gone")
+            }),
+        ));
+        let error = client.version().await.unwrap_err();
+        assert_eq!(error.to_string(), "sidecar is gone");
+
+        let client = AnkiClient::with_transport(Box::new(
+            InProcessTransport::new(|_| {
+                Ok(Bytes::from_static(b"<html>"))
+            }),
+        ));
+        let error = client.version().await.unwrap_err();
+        assert!(error.to_string().starts_with(
+            "Failed to parse Anki-Connect response"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_call_limited_checks_the_whole_body() {
+        let transport = InProcessTransport::new(|_| {
+            Ok(Bytes::from_static(b"0123456789"))
+        });
+        let body = || Bytes::from_static(b"{}");
+        assert_eq!(
+            transport
+                .call_limited(body(), 10)
+                .await
+                .unwrap(),
+            "0123456789"
+        );
+        let error = transport
+            .call_limited(body(), 9)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ResponseTooLarge>(),
+            Some(&ResponseTooLarge { size: 10, limit: 9 })
+        );
+        assert_eq!(
+            error.to_string(),
+            "response body of at least 10 bytes exceeds the 9-byte limit"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_transport() {
+        let path = std::env::temp_dir().join(format!(
+            "anki_learn_transport_{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mock =
+            MockAnki::start_unix(&path, |action, _| {
+                match action {
+                    "version" => ok(json!(6)),
+                    "deckNames" => ok(json!(["Default"])),
+                    _ => err("unsupported action"),
+                }
+            })
+            .await;
+
+        let client = AnkiClient::with_transport(Box::new(
+            HttpTransport::unix_socket(&path).unwrap(),
+        ));
+        assert_eq!(client.version().await.unwrap(), 6);
+        assert_eq!(
+            client.get_deck_names(None).await.unwrap(),
+            ["Default"]
+        );
+        assert_eq!(
+            mock.actions(),
+            ["version", "deckNames"]
+        );
+        assert_eq!(
+            mock.headers()[0]["content-type"],
+            "application/json"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,788 @@
+//! Reading `.apkg` packages without Anki.
+//!
+//! A package is a zip holding the collection database plus a
+//! `media` manifest mapping the numbered zip entries to file names.
+//! The legacy layouts (`collection.anki2`, `collection.anki21`) are
+//! plain SQLite and are read here. The zstd-compressed
+//! `collection.anki21b` of recent Anki exports is detected and
+//! reported as unsupported; re-export with "Support older Anki
+//! versions" to get a readable package.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags, params};
+use serde::Deserialize;
+use zip::ZipArchive;
+
+use crate::anki::client::Note;
+
+pub mod builder;
+
+/// Notes fetched from the database per query while iterating
+const NOTES_PAGE: usize = 256;
+
+/// Separator between the field values of a note row
+const FIELD_SEPARATOR: char = '\u{1f}';
+
+/// Collection layout found in a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectionFormat {
+    /// `collection.anki2`, written by Anki 2.0
+    Anki2,
+    /// `collection.anki21`, written by Anki 2.1 in legacy mode
+    Anki21,
+    /// `collection.anki21b`, zstd-compressed (Anki 2.1.50+)
+    Anki21b,
+}
+
+impl CollectionFormat {
+    /// Name of the collection entry inside the package
+    pub fn entry_name(self) -> &'static str {
+        match self {
+            CollectionFormat::Anki2 => "collection.anki2",
+            CollectionFormat::Anki21 => "collection.anki21",
+            CollectionFormat::Anki21b => {
+                "collection.anki21b"
+            }
+        }
+    }
+
+    /// Detects the layout from the package's entry names.
+    ///
+    /// Newer packages also carry a stub `collection.anki2`, so the
+    /// newest layout present wins.
+    pub fn detect(names: &[&str]) -> Option<Self> {
+        [
+            CollectionFormat::Anki21b,
+            CollectionFormat::Anki21,
+            CollectionFormat::Anki2,
+        ]
+        .into_iter()
+        .find(|format| names.contains(&format.entry_name()))
+    }
+}
+
+/// Note type found in a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApkgModel {
+    /// Model ID
+    pub id: u64,
+    /// Model name
+    pub name: String,
+    /// Field names in the model's order
+    pub fields: Vec<String>,
+}
+
+/// Deck found in a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApkgDeck {
+    /// Deck ID
+    pub id: u64,
+    /// Full deck name (`A::B`)
+    pub name: String,
+}
+
+/// Note read from a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApkgNote {
+    /// Note ID
+    pub id: u64,
+    /// Globally unique ID Anki uses to match notes across
+    /// collections
+    pub guid: String,
+    /// ID of the note's model
+    pub model_id: u64,
+    /// Deck of the note's first card, if it has any
+    pub deck_id: Option<u64>,
+    /// Field values in the model's field order
+    pub fields: Vec<String>,
+    /// Tags
+    pub tags: Vec<String>,
+}
+
+/// Media file listed in a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaEntry {
+    /// Name of the zip entry holding the data (`"0"`, `"1"`, ...)
+    pub entry: String,
+    /// File name the notes refer to
+    pub file_name: String,
+}
+
+/// How the notes of one source model become [`Note`]s
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelMapping {
+    /// Model to create the notes with
+    pub model_name: String,
+    /// Deck to add the notes to; `None` keeps each note's deck
+    pub deck_name: Option<String>,
+    /// Source field → target field; fields not listed keep their
+    /// name, and an empty target drops the field
+    pub fields: HashMap<String, String>,
+    /// Tags added to every note on top of its own
+    pub tags: Vec<String>,
+}
+
+/// Source model name → mapping; notes of unlisted models are skipped
+pub type ModelMap = HashMap<String, ModelMapping>;
+
+/// An opened `.apkg` package
+pub struct Apkg {
+    archive: ZipArchive<File>,
+    format: CollectionFormat,
+    conn: Connection,
+    media: Vec<MediaEntry>,
+    /// Extracted database, removed on drop
+    db_path: PathBuf,
+}
+
+/// `models` column of the `col` table, keyed by model ID
+#[derive(Deserialize)]
+struct ColModel {
+    name: String,
+    flds: Vec<ColField>,
+}
+
+#[derive(Deserialize)]
+struct ColField {
+    name: String,
+    ord: u32,
+}
+
+/// `decks` column of the `col` table, keyed by deck ID
+#[derive(Deserialize)]
+struct ColDeck {
+    name: String,
+}
+
+impl Apkg {
+    /// Opens the package at `path`.
+    ///
+    /// The collection database is extracted to a temporary file,
+    /// which is removed when the package is dropped. Fails with an
+    /// "unsupported" error for the `collection.anki21b` layout.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| {
+            format!("Failed to open {}", path.display())
+        })?;
+        let mut archive = ZipArchive::new(file)
+            .with_context(|| {
+                format!(
+                    "{} is not an .apkg (zip) package",
+                    path.display()
+                )
+            })?;
+
+        let names: Vec<&str> =
+            archive.file_names().collect();
+        let format = CollectionFormat::detect(&names)
+            .with_context(|| {
+                format!(
+                    "{} contains no Anki collection",
+                    path.display()
+                )
+            })?;
+        anyhow::ensure!(
+            format != CollectionFormat::Anki21b,
+            "Unsupported package format in {}: {} is zstd-compressed; re-export it with \"Support older Anki versions\"",
+            path.display(),
+            format.entry_name()
+        );
+
+        let media = read_media_manifest(&mut archive)?;
+        let db_path = std::env::temp_dir().join(format!(
+            "anki_learn_apkg_{}_{}.sqlite",
+            std::process::id(),
+            chrono::Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or_default()
+        ));
+        let mut db =
+            archive.by_name(format.entry_name())?;
+        std::io::copy(
+            &mut db,
+            &mut File::create(&db_path)?,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to extract {}",
+                format.entry_name()
+            )
+        })?;
+        drop(db);
+
+        let conn = Connection::open_with_flags(
+            &db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+        );
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                let _ = std::fs::remove_file(&db_path);
+                return Err(e.into());
+            }
+        };
+        Ok(Self {
+            archive,
+            format,
+            conn,
+            media,
+            db_path,
+        })
+    }
+
+    /// Layout of the package's collection
+    pub fn format(&self) -> CollectionFormat {
+        self.format
+    }
+
+    /// Note types, sorted by name
+    pub fn models(&self) -> Result<Vec<ApkgModel>> {
+        let models: HashMap<String, ColModel> =
+            self.col_json("models")?;
+        let mut models: Vec<ApkgModel> = models
+            .into_iter()
+            .map(|(id, mut model)| {
+                model.flds.sort_by_key(|field| field.ord);
+                Ok(ApkgModel {
+                    id: id.parse().with_context(|| {
+                        format!("Invalid model ID {}", id)
+                    })?,
+                    name: model.name,
+                    fields: model
+                        .flds
+                        .into_iter()
+                        .map(|field| field.name)
+                        .collect(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(models)
+    }
+
+    /// Decks, sorted by name
+    pub fn decks(&self) -> Result<Vec<ApkgDeck>> {
+        let decks: HashMap<String, ColDeck> =
+            self.col_json("decks")?;
+        let mut decks: Vec<ApkgDeck> = decks
+            .into_iter()
+            .map(|(id, deck)| {
+                Ok(ApkgDeck {
+                    id: id.parse().with_context(|| {
+                        format!("Invalid deck ID {}", id)
+                    })?,
+                    name: deck.name,
+                })
+            })
+            .collect::<Result<_>>()?;
+        decks.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(decks)
+    }
+
+    /// Iterates over the notes in ID order, fetching them a page at
+    /// a time
+    pub fn notes(&self) -> ApkgNotes<'_> {
+        ApkgNotes {
+            conn: &self.conn,
+            after: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// Media files listed in the package's manifest, in entry order
+    pub fn media(
+        &self,
+    ) -> impl Iterator<Item = &MediaEntry> {
+        self.media.iter()
+    }
+
+    /// Reads the data of a media file
+    pub fn read_media(
+        &mut self,
+        entry: &MediaEntry,
+    ) -> Result<Vec<u8>> {
+        let mut file = self
+            .archive
+            .by_name(&entry.entry)
+            .with_context(|| {
+            format!(
+                "Media {} ({}) is missing from the package",
+                entry.file_name, entry.entry
+            )
+        })?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Parses a JSON column of the single `col` row
+    fn col_json<T: serde::de::DeserializeOwned>(
+        &self,
+        column: &str,
+    ) -> Result<T> {
+        let text: String = self
+            .conn
+            .query_row(
+                &format!("SELECT {} FROM col", column),
+                [],
+                |row| row.get(0),
+            )
+            .with_context(|| {
+                format!(
+                    "Failed to read {} of the collection",
+                    column
+                )
+            })?;
+        serde_json::from_str(&text).with_context(|| {
+            format!(
+                "Unsupported {} in {}: expected the legacy JSON layout",
+                column,
+                self.format.entry_name()
+            )
+        })
+    }
+}
+
+impl Drop for Apkg {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.db_path);
+    }
+}
+
+/// Iterator over the notes of an [`Apkg`]
+pub struct ApkgNotes<'a> {
+    conn: &'a Connection,
+    /// ID of the last note returned
+    after: Option<u64>,
+    page: std::vec::IntoIter<ApkgNote>,
+    done: bool,
+}
+
+impl ApkgNotes<'_> {
+    fn fetch_page(&self) -> Result<Vec<ApkgNote>> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT n.id, n.guid, n.mid, n.tags, n.flds,
+             (SELECT c.did FROM cards c WHERE c.nid = n.id
+              ORDER BY c.ord LIMIT 1)
+             FROM notes n WHERE n.id > ?1 ORDER BY n.id LIMIT ?2",
+        )?;
+        let after =
+            self.after.map_or(i64::MIN, |id| id as i64);
+        let notes = statement
+            .query_map(params![after, NOTES_PAGE], |row| {
+                let tags: String = row.get(3)?;
+                let fields: String = row.get(4)?;
+                Ok(ApkgNote {
+                    id: row.get(0)?,
+                    guid: row.get(1)?,
+                    model_id: row.get(2)?,
+                    deck_id: row.get(5)?,
+                    fields: fields
+                        .split(FIELD_SEPARATOR)
+                        .map(str::to_string)
+                        .collect(),
+                    tags: tags
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(notes)
+    }
+}
+
+impl Iterator for ApkgNotes<'_> {
+    type Item = Result<ApkgNote>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(note) = self.page.next() {
+            self.after = Some(note.id);
+            return Some(Ok(note));
+        }
+        if self.done {
+            return None;
+        }
+        match self.fetch_page() {
+            Ok(page) => {
+                self.done = page.len() < NOTES_PAGE;
+                self.page = page.into_iter();
+                let note = self.page.next()?;
+                self.after = Some(note.id);
+                Some(Ok(note))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Converts the notes of `apkg` into [`Note`]s ready for
+/// `add_notes`, following `model_map`.
+///
+/// Notes of models missing from the map are skipped. Without a deck
+/// in the mapping, each note keeps the name of its source deck.
+pub fn apkg_notes_to_notes(
+    apkg: &Apkg,
+    model_map: &ModelMap,
+) -> Result<Vec<Note>> {
+    let models: HashMap<u64, ApkgModel> = apkg
+        .models()?
+        .into_iter()
+        .map(|model| (model.id, model))
+        .collect();
+    let decks: HashMap<u64, String> = apkg
+        .decks()?
+        .into_iter()
+        .map(|deck| (deck.id, deck.name))
+        .collect();
+
+    let mut notes = Vec::new();
+    for note in apkg.notes() {
+        let note = note?;
+        let Some(model) = models.get(&note.model_id) else {
+            continue;
+        };
+        let Some(mapping) = model_map.get(&model.name)
+        else {
+            continue;
+        };
+        let deck_name = match &mapping.deck_name {
+            Some(deck) => deck.clone(),
+            None => note
+                .deck_id
+                .and_then(|id| decks.get(&id))
+                .cloned()
+                .with_context(|| {
+                    format!("Note {} has no deck", note.id)
+                })?,
+        };
+        let fields = model
+            .fields
+            .iter()
+            .zip(note.fields)
+            .filter_map(|(name, value)| {
+                let target = mapping
+                    .fields
+                    .get(name)
+                    .unwrap_or(name);
+                (!target.is_empty())
+                    .then(|| (target.clone(), value))
+            })
+            .collect();
+        let mut tags = note.tags;
+        tags.extend(
+            mapping
+                .tags
+                .iter()
+                .filter(|tag| !tags.contains(tag))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        notes.push(Note {
+            model_name: mapping.model_name.clone(),
+            deck_name,
+            fields,
+            tags,
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        });
+    }
+    Ok(notes)
+}
+
+/// Reads the legacy JSON `media` manifest (`{"0": "cat.jpg"}`); a
+/// package without one has no media
+fn read_media_manifest(
+    archive: &mut ZipArchive<File>,
+) -> Result<Vec<MediaEntry>> {
+    let Ok(mut file) = archive.by_name("media") else {
+        return Ok(Vec::new());
+    };
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let manifest: HashMap<String, String> =
+        serde_json::from_slice(&data).context(
+            "Unsupported media manifest: expected the legacy JSON layout",
+        )?;
+    let mut media: Vec<MediaEntry> = manifest
+        .into_iter()
+        .map(|(entry, file_name)| MediaEntry {
+            entry,
+            file_name,
+        })
+        .collect();
+    media.sort_by_key(|entry| {
+        (
+            entry.entry.parse::<u64>().ok(),
+            entry.entry.clone(),
+        )
+    });
+    Ok(media)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::note::NoteFields;
+    use std::io::Write;
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    const MODELS: &str = r#"{"1001": {"name": "Basic", "flds": [
+        {"name": "Back", "ord": 1}, {"name": "Front", "ord": 0}
+    ]}, "1002": {"name": "Cloze", "flds": [
+        {"name": "Text", "ord": 0}
+    ]}}"#;
+
+    const DECKS: &str = r#"{"1": {"name": "Default"},
+        "2001": {"name": "Shared::Kanji"}}"#;
+
+    /// Writes a package with `entries` next to a legacy collection
+    /// holding `notes` Basic notes in `Shared::Kanji` and one Cloze
+    /// note in `Default`
+    fn fixture(
+        name: &str,
+        collection: &str,
+        notes: u64,
+        entries: &[(&str, &[u8])],
+    ) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_apkg_fixture_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let db = dir.join("collection.db");
+        let mut conn = Connection::open(&db).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE col (models TEXT, decks TEXT);
+             CREATE TABLE notes (id INTEGER PRIMARY KEY,
+                 guid TEXT, mid INTEGER, tags TEXT, flds TEXT);
+             CREATE TABLE cards (id INTEGER PRIMARY KEY,
+                 nid INTEGER, did INTEGER, ord INTEGER);",
+        )
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        tx.execute(
+            "INSERT INTO col VALUES (?1, ?2)",
+            [MODELS, DECKS],
+        )
+        .unwrap();
+        for i in 1..=notes {
+            tx.execute(
+                "INSERT INTO notes VALUES (?1, ?2, 1001, ?3, ?4)",
+                params![
+                    i,
+                    format!("g{}", i),
+                    " kanji n5 ",
+                    format!("字{}\u{1f}char {}", i, i)
+                ],
+            )
+            .unwrap();
+            tx.execute(
+                "INSERT INTO cards VALUES (?1, ?1, 2001, 0)",
+                [i],
+            )
+            .unwrap();
+        }
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, 'c', 1002, '', 'a {{c1::b}}')",
+            [notes + 1],
+        )
+        .unwrap();
+        tx.execute(
+            "INSERT INTO cards VALUES (?1, ?1, 1, 0)",
+            [notes + 1],
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        drop(conn);
+
+        let path = dir.join(format!("{}.apkg", name));
+        let mut zip =
+            ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file(
+            collection,
+            SimpleFileOptions::default(),
+        )
+        .unwrap();
+        zip.write_all(&std::fs::read(&db).unwrap())
+            .unwrap();
+        for (entry, data) in entries {
+            zip.start_file(
+                *entry,
+                SimpleFileOptions::default(),
+            )
+            .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_lists_models_decks_and_notes() {
+        let path =
+            fixture("notes", "collection.anki2", 600, &[]);
+        let apkg = Apkg::open(&path).unwrap();
+        assert_eq!(apkg.format(), CollectionFormat::Anki2);
+        assert_eq!(
+            apkg.models().unwrap()[0],
+            ApkgModel {
+                id: 1001,
+                name: "Basic".to_string(),
+                fields: vec![
+                    "Front".to_string(),
+                    "Back".to_string()
+                ],
+            }
+        );
+        assert_eq!(
+            apkg.decks()
+                .unwrap()
+                .into_iter()
+                .map(|deck| deck.name)
+                .collect::<Vec<_>>(),
+            ["Default", "Shared::Kanji"]
+        );
+
+        let notes: Vec<ApkgNote> =
+            apkg.notes().collect::<Result<_>>().unwrap();
+        assert_eq!(notes.len(), 601);
+        assert!(
+            notes.windows(2).all(|w| w[0].id < w[1].id)
+        );
+        assert_eq!(
+            notes[0],
+            ApkgNote {
+                id: 1,
+                guid: "g1".to_string(),
+                model_id: 1001,
+                deck_id: Some(2001),
+                fields: vec![
+                    "字1".to_string(),
+                    "char 1".to_string()
+                ],
+                tags: vec![
+                    "kanji".to_string(),
+                    "n5".to_string()
+                ],
+            }
+        );
+        assert!(apkg.media().next().is_none());
+    }
+
+    #[test]
+    fn test_lists_and_reads_media() {
+        let path = fixture(
+            "media",
+            "collection.anki21",
+            1,
+            &[
+                (
+                    "media",
+                    br#"{"10": "b.mp3", "2": "a.jpg"}"#,
+                ),
+                ("2", b"jpeg"),
+                ("10", b"mp3"),
+            ],
+        );
+        let mut apkg = Apkg::open(&path).unwrap();
+        assert_eq!(apkg.format(), CollectionFormat::Anki21);
+        let media: Vec<MediaEntry> =
+            apkg.media().cloned().collect();
+        assert_eq!(
+            media
+                .iter()
+                .map(|m| m.file_name.as_str())
+                .collect::<Vec<_>>(),
+            ["a.jpg", "b.mp3"]
+        );
+        assert_eq!(
+            apkg.read_media(&media[1]).unwrap(),
+            b"mp3"
+        );
+    }
+
+    #[test]
+    fn test_newer_layout_is_unsupported() {
+        let path = fixture(
+            "anki21b",
+            "collection.anki2",
+            1,
+            &[("collection.anki21b", b"\x28\xb5\x2f\xfd")],
+        );
+        let error = Apkg::open(&path).err().unwrap();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Unsupported package format")
+        );
+        assert!(
+            error
+                .to_string()
+                .contains("collection.anki21b")
+        );
+
+        let empty = fixture("empty", "notes.txt", 0, &[]);
+        let error = Apkg::open(&empty).err().unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("contains no Anki collection")
+        );
+    }
+
+    #[test]
+    fn test_notes_are_mapped_for_import() {
+        let path =
+            fixture("convert", "collection.anki2", 2, &[]);
+        let apkg = Apkg::open(&path).unwrap();
+        let model_map = ModelMap::from([(
+            "Basic".to_string(),
+            ModelMapping {
+                model_name: "Kanji".to_string(),
+                deck_name: None,
+                fields: HashMap::from([
+                    (
+                        "Front".to_string(),
+                        "Kanji".to_string(),
+                    ),
+                    ("Back".to_string(), String::new()),
+                ]),
+                tags: vec![
+                    "shared".to_string(),
+                    "n5".to_string(),
+                ],
+            },
+        )]);
+
+        let notes =
+            apkg_notes_to_notes(&apkg, &model_map).unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[1].model_name, "Kanji");
+        assert_eq!(notes[1].deck_name, "Shared::Kanji");
+        assert_eq!(
+            notes[1].fields,
+            NoteFields::from([("Kanji", "字2")])
+        );
+        assert_eq!(
+            notes[1].tags,
+            ["kanji", "n5", "shared"]
+        );
+    }
+}
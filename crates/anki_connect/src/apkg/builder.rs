@@ -0,0 +1,890 @@
+//! Writing `.apkg` packages without Anki, in the legacy
+//! `collection.anki2` layout every Anki version imports.
+//!
+//! Model and deck IDs are derived from their names, and note GUIDs
+//! from the first field, so importing an updated package updates the
+//! notes of the previous one instead of duplicating them.
+//!
+//! To check a package by hand, import it with File → Import in Anki
+//! and look at the notes in the browser; importing it a second time
+//! should report every note as unchanged.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, params};
+use serde_json::{Value, json};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+use super::CollectionFormat;
+use crate::anki::client::Note;
+use crate::cloze;
+use crate::convert::strip_html;
+
+/// Characters Anki encodes GUIDs with
+const GUID_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// Styling of models created without their own
+pub const DEFAULT_CSS: &str = ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n";
+
+/// Schema of a version 11 collection
+const SCHEMA: &str = "
+    CREATE TABLE col (
+        id integer primary key, crt integer not null,
+        mod integer not null, scm integer not null,
+        ver integer not null, dty integer not null,
+        usn integer not null, ls integer not null,
+        conf text not null, models text not null,
+        decks text not null, dconf text not null,
+        tags text not null
+    );
+    CREATE TABLE notes (
+        id integer primary key, guid text not null,
+        mid integer not null, mod integer not null,
+        usn integer not null, tags text not null,
+        flds text not null, sfld integer not null,
+        csum integer not null, flags integer not null,
+        data text not null
+    );
+    CREATE TABLE cards (
+        id integer primary key, nid integer not null,
+        did integer not null, ord integer not null,
+        mod integer not null, usn integer not null,
+        type integer not null, queue integer not null,
+        due integer not null, ivl integer not null,
+        factor integer not null, reps integer not null,
+        lapses integer not null, left integer not null,
+        odue integer not null, odid integer not null,
+        flags integer not null, data text not null
+    );
+    CREATE TABLE revlog (
+        id integer primary key, cid integer not null,
+        usn integer not null, ease integer not null,
+        ivl integer not null, lastIvl integer not null,
+        factor integer not null, time integer not null,
+        type integer not null
+    );
+    CREATE TABLE graves (
+        usn integer not null, oid integer not null,
+        type integer not null
+    );
+    CREATE INDEX ix_notes_usn ON notes (usn);
+    CREATE INDEX ix_cards_usn ON cards (usn);
+    CREATE INDEX ix_revlog_usn ON revlog (usn);
+    CREATE INDEX ix_cards_nid ON cards (nid);
+    CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+    CREATE INDEX ix_revlog_cid ON revlog (cid);
+    CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+/// Kind of note type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ModelKind {
+    /// One card per template
+    #[default]
+    Standard,
+    /// One card per cloze number of the first template
+    Cloze,
+}
+
+/// Card template of a model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardTemplate {
+    /// Template name
+    pub name: String,
+    /// Front side
+    pub question: String,
+    /// Back side
+    pub answer: String,
+}
+
+/// Note type to write into a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelSpec {
+    /// Model name, which also determines its ID
+    pub name: String,
+    /// Standard or cloze
+    pub kind: ModelKind,
+    /// Field names; the first one is the sort field and determines
+    /// note GUIDs
+    pub fields: Vec<String>,
+    /// Card templates
+    pub templates: Vec<CardTemplate>,
+    /// Styling shared by the templates
+    pub css: String,
+}
+
+impl ModelSpec {
+    /// Standard model with `fields`, no templates and
+    /// [`DEFAULT_CSS`]
+    pub fn new<S: Into<String>>(
+        name: impl Into<String>,
+        fields: impl IntoIterator<Item = S>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            kind: ModelKind::Standard,
+            fields: fields
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            templates: Vec::new(),
+            css: DEFAULT_CSS.to_string(),
+        }
+    }
+
+    /// Makes this a cloze model
+    pub fn cloze(mut self) -> Self {
+        self.kind = ModelKind::Cloze;
+        self
+    }
+
+    /// Adds a card template
+    pub fn template(
+        mut self,
+        name: impl Into<String>,
+        question: impl Into<String>,
+        answer: impl Into<String>,
+    ) -> Self {
+        self.templates.push(CardTemplate {
+            name: name.into(),
+            question: question.into(),
+            answer: answer.into(),
+        });
+        self
+    }
+
+    /// Replaces the styling
+    pub fn css(mut self, css: impl Into<String>) -> Self {
+        self.css = css.into();
+        self
+    }
+
+    /// ID derived from the model name
+    pub fn id(&self) -> u64 {
+        stable_id(&self.name)
+    }
+}
+
+/// Collects models, notes and media and writes them as an `.apkg`
+#[derive(Debug, Clone, Default)]
+pub struct PackageBuilder {
+    models: Vec<ModelSpec>,
+    notes: Vec<Note>,
+    media: BTreeMap<String, Vec<u8>>,
+}
+
+impl PackageBuilder {
+    /// Creates an empty package
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a model notes can use
+    pub fn model(mut self, model: ModelSpec) -> Self {
+        self.models.push(model);
+        self
+    }
+
+    /// Adds a note; its deck is created from its name.
+    ///
+    /// Fails if the note's model was not added or the note has a
+    /// field the model lacks. Missing fields are left empty.
+    pub fn add_note(&mut self, note: Note) -> Result<()> {
+        let model = self.find_model(&note.model_name)?;
+        if let Some(field) = note
+            .fields
+            .keys()
+            .find(|field| !model.fields.contains(field))
+        {
+            anyhow::bail!(
+                "Model '{}' has no field '{}'",
+                model.name,
+                field
+            );
+        }
+        anyhow::ensure!(
+            !note.deck_name.trim().is_empty(),
+            "Note has no deck"
+        );
+        self.notes.push(note);
+        Ok(())
+    }
+
+    /// Adds a media file the notes refer to by `name`
+    pub fn add_media(
+        &mut self,
+        name: impl Into<String>,
+        data: Vec<u8>,
+    ) {
+        self.media.insert(name.into(), data);
+    }
+
+    /// Adds the file at `path` as media under its file name
+    pub fn add_media_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| {
+                format!(
+                    "Invalid media path {}",
+                    path.display()
+                )
+            })?;
+        let data =
+            std::fs::read(path).with_context(|| {
+                format!("Failed to read {}", path.display())
+            })?;
+        self.add_media(name, data);
+        Ok(())
+    }
+
+    /// Writes the package to `path`
+    pub fn write(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let db_path = temp_db_path();
+        let written = self
+            .write_collection(&db_path)
+            .and_then(|()| self.write_zip(path, &db_path));
+        let _ = std::fs::remove_file(&db_path);
+        written.with_context(|| {
+            format!("Failed to write {}", path.display())
+        })
+    }
+
+    fn find_model(&self, name: &str) -> Result<&ModelSpec> {
+        self.models
+            .iter()
+            .find(|model| model.name == name)
+            .with_context(|| {
+                format!("Unknown model '{}'", name)
+            })
+    }
+
+    /// Every deck the notes use, with their parents
+    fn deck_names(&self) -> BTreeSet<String> {
+        let mut decks = BTreeSet::new();
+        for note in &self.notes {
+            let parts: Vec<&str> =
+                note.deck_name.split("::").collect();
+            for depth in 1..=parts.len() {
+                decks.insert(parts[..depth].join("::"));
+            }
+        }
+        decks
+    }
+
+    fn write_collection(
+        &self,
+        db_path: &Path,
+    ) -> Result<()> {
+        let now = chrono::Utc::now();
+        let (secs, millis) =
+            (now.timestamp(), now.timestamp_millis());
+        let mut conn = Connection::open(db_path)?;
+        conn.execute_batch(SCHEMA)?;
+        let tx = conn.transaction()?;
+
+        let models: serde_json::Map<String, Value> = self
+            .models
+            .iter()
+            .map(|model| {
+                (
+                    model.id().to_string(),
+                    model_json(model, secs),
+                )
+            })
+            .collect();
+        let mut decks = serde_json::Map::new();
+        decks.insert(
+            "1".to_string(),
+            deck_json(1, "Default", secs),
+        );
+        for name in self.deck_names() {
+            let id = stable_id(&name);
+            decks.insert(
+                id.to_string(),
+                deck_json(id, &name, secs),
+            );
+        }
+        tx.execute(
+            "INSERT INTO col VALUES
+             (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+            params![
+                secs,
+                millis,
+                collection_conf().to_string(),
+                Value::Object(models).to_string(),
+                Value::Object(decks).to_string(),
+                json!({ "1": deck_conf() }).to_string(),
+            ],
+        )?;
+
+        for (position, note) in
+            self.notes.iter().enumerate()
+        {
+            let model =
+                self.find_model(&note.model_name)?;
+            let values: Vec<&str> = model
+                .fields
+                .iter()
+                .map(|field| {
+                    note.fields
+                        .get(field)
+                        .map_or("", String::as_str)
+                })
+                .collect();
+            let first =
+                values.first().copied().unwrap_or("");
+            let guid = guid_for(first);
+            let note_id =
+                stable_id(&format!("note:{}", guid));
+            let sort_field = strip_html(first);
+            let tags = if note.tags.is_empty() {
+                String::new()
+            } else {
+                format!(" {} ", note.tags.join(" "))
+            };
+            tx.execute(
+                "INSERT INTO notes VALUES
+                 (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+                params![
+                    note_id,
+                    guid,
+                    model.id(),
+                    secs,
+                    tags,
+                    values.join("\u{1f}"),
+                    sort_field,
+                    field_checksum(&sort_field),
+                ],
+            )?;
+
+            let deck_id = stable_id(&note.deck_name);
+            for ord in card_ords(model, &values) {
+                tx.execute(
+                    "INSERT INTO cards VALUES
+                     (?1, ?2, ?3, ?4, ?5, -1, 0, 0, ?6,
+                      0, 0, 0, 0, 0, 0, 0, 0, '')",
+                    params![
+                        stable_id(&format!(
+                            "card:{}:{}",
+                            guid, ord
+                        )),
+                        note_id,
+                        deck_id,
+                        ord,
+                        secs,
+                        position + 1,
+                    ],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn write_zip(
+        &self,
+        path: &Path,
+        db_path: &Path,
+    ) -> Result<()> {
+        let options = SimpleFileOptions::default();
+        let mut zip = ZipWriter::new(File::create(path)?);
+        zip.start_file(
+            CollectionFormat::Anki2.entry_name(),
+            options,
+        )?;
+        std::io::copy(&mut File::open(db_path)?, &mut zip)?;
+
+        let manifest: BTreeMap<String, &str> = self
+            .media
+            .keys()
+            .enumerate()
+            .map(|(index, name)| {
+                (index.to_string(), name.as_str())
+            })
+            .collect();
+        zip.start_file("media", options)?;
+        zip.write_all(
+            serde_json::to_string(&manifest)?.as_bytes(),
+        )?;
+        for (index, data) in self.media.values().enumerate()
+        {
+            zip.start_file(index.to_string(), options)?;
+            zip.write_all(data)?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+/// ID derived from `name`: positive and below 2^52, so it survives
+/// a round trip through JSON numbers
+pub fn stable_id(name: &str) -> u64 {
+    let digest = Sha256::digest(name.as_bytes());
+    let value = u64::from_be_bytes(
+        digest[..8].try_into().expect("8 bytes"),
+    );
+    (value & ((1 << 52) - 1)).max(2)
+}
+
+/// GUID derived from a note's first field, in Anki's base-91 form
+pub fn guid_for(first_field: &str) -> String {
+    let digest = Sha256::digest(first_field.as_bytes());
+    let mut value = u64::from_be_bytes(
+        digest[..8].try_into().expect("8 bytes"),
+    );
+    let base = GUID_ALPHABET.len() as u64;
+    let mut guid = Vec::new();
+    loop {
+        guid.push(GUID_ALPHABET[(value % base) as usize]);
+        value /= base;
+        if value == 0 {
+            break;
+        }
+    }
+    guid.reverse();
+    String::from_utf8(guid).expect("ASCII alphabet")
+}
+
+/// Anki's duplicate checksum: the first 32 bits of the SHA-1 of the
+/// stripped first field
+fn field_checksum(stripped: &str) -> u32 {
+    let digest = Sha1::digest(stripped.as_bytes());
+    u32::from_be_bytes(
+        digest[..4].try_into().expect("4 bytes"),
+    )
+}
+
+/// Template ordinals of the cards a note gets: standard models get
+/// a card per template whose front uses a non-empty field, cloze
+/// models one per cloze number
+fn card_ords(
+    model: &ModelSpec,
+    values: &[&str],
+) -> Vec<usize> {
+    match model.kind {
+        ModelKind::Standard => model
+            .templates
+            .iter()
+            .enumerate()
+            .filter(|(_, template)| {
+                model.fields.iter().zip(values).any(
+                    |(field, value)| {
+                        !value.trim().is_empty()
+                            && template.question.contains(
+                                &format!(
+                                    "{{{{{}}}}}",
+                                    field
+                                ),
+                            )
+                    },
+                )
+            })
+            .map(|(ord, _)| ord)
+            .collect(),
+        ModelKind::Cloze => cloze_numbers(values)
+            .into_iter()
+            .map(|number| number - 1)
+            .collect(),
+    }
+}
+
+/// Cloze numbers (`{{c1::..}}` → 1) used in `values`
+fn cloze_numbers(values: &[&str]) -> BTreeSet<usize> {
+    values
+        .iter()
+        .flat_map(|value| cloze::spans(value))
+        .map(|span| span.index)
+        .filter(|number| *number > 0)
+        .collect()
+}
+
+/// Indices of the fields a template's front refers to
+fn required_fields(
+    model: &ModelSpec,
+    question: &str,
+) -> Vec<usize> {
+    model
+        .fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| {
+            question.contains(&format!("{{{{{}}}}}", field))
+        })
+        .map(|(ord, _)| ord)
+        .collect()
+}
+
+fn model_json(model: &ModelSpec, modified: i64) -> Value {
+    let fields: Vec<Value> = model
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(ord, name)| {
+            json!({
+                "name": name, "ord": ord, "sticky": false,
+                "rtl": false, "font": "Arial", "size": 20,
+                "media": [],
+            })
+        })
+        .collect();
+    let templates: Vec<Value> = model
+        .templates
+        .iter()
+        .enumerate()
+        .map(|(ord, template)| {
+            json!({
+                "name": template.name, "ord": ord,
+                "qfmt": template.question,
+                "afmt": template.answer,
+                "bqfmt": "", "bafmt": "", "did": null,
+            })
+        })
+        .collect();
+    let required: Vec<Value> = model
+        .templates
+        .iter()
+        .enumerate()
+        .map(|(ord, template)| {
+            json!([
+                ord,
+                "any",
+                required_fields(model, &template.question)
+            ])
+        })
+        .collect();
+    json!({
+        "id": model.id(),
+        "name": model.name,
+        "type": match model.kind {
+            ModelKind::Standard => 0,
+            ModelKind::Cloze => 1,
+        },
+        "flds": fields,
+        "tmpls": templates,
+        "req": required,
+        "css": model.css,
+        "sortf": 0,
+        "did": 1,
+        "mod": modified,
+        "usn": -1,
+        "tags": [],
+        "vers": [],
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+    })
+}
+
+fn deck_json(id: u64, name: &str, modified: i64) -> Value {
+    json!({
+        "id": id, "name": name, "desc": "", "dyn": 0,
+        "conf": 1, "collapsed": false,
+        "browserCollapsed": false, "extendNew": 0,
+        "extendRev": 50, "newToday": [0, 0],
+        "revToday": [0, 0], "lrnToday": [0, 0],
+        "timeToday": [0, 0], "mod": modified, "usn": -1,
+    })
+}
+
+fn deck_conf() -> Value {
+    json!({
+        "id": 1, "name": "Default", "dyn": false,
+        "maxTaken": 60, "timer": 0, "autoplay": true,
+        "replayq": true, "mod": 0, "usn": 0,
+        "new": {
+            "delays": [1, 10], "ints": [1, 4, 7],
+            "initialFactor": 2500, "order": 1,
+            "perDay": 20, "bury": true, "separate": true,
+        },
+        "rev": {
+            "perDay": 200, "ease4": 1.3, "fuzz": 0.05,
+            "minSpace": 1, "ivlFct": 1, "maxIvl": 36500,
+            "bury": true,
+        },
+        "lapse": {
+            "delays": [10], "mult": 0, "minInt": 1,
+            "leechFails": 8, "leechAction": 0,
+        },
+    })
+}
+
+fn collection_conf() -> Value {
+    json!({
+        "activeDecks": [1], "curDeck": 1, "newSpread": 0,
+        "collapseTime": 1200, "timeLim": 0,
+        "estTimes": true, "dueCounts": true,
+        "curModel": null, "nextPos": 1,
+        "sortType": "noteFld", "sortBackwards": false,
+        "addToCur": true,
+    })
+}
+
+fn temp_db_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "anki_learn_apkg_build_{}_{}.sqlite",
+        std::process::id(),
+        chrono::Utc::now()
+            .timestamp_nanos_opt()
+            .unwrap_or_default()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Apkg, ApkgNote};
+    use super::*;
+    use std::collections::HashMap;
+
+    fn temp_package(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_apkg_builder_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(format!("{}.apkg", name))
+    }
+
+    fn note(
+        model: &str,
+        deck: &str,
+        fields: &[(&str, &str)],
+    ) -> Note {
+        Note {
+            model_name: model.to_string(),
+            deck_name: deck.to_string(),
+            fields: fields
+                .iter()
+                .map(|(k, v)| {
+                    (k.to_string(), v.to_string())
+                })
+                .collect(),
+            tags: vec!["ci".to_string()],
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        }
+    }
+
+    fn builder() -> PackageBuilder {
+        let mut builder = PackageBuilder::new()
+            .model(
+                ModelSpec::new(
+                    "Vocab",
+                    ["Word", "Meaning"],
+                )
+                .template(
+                    "Recognition",
+                    "{{Word}}",
+                    "{{FrontSide}}<hr>{{Meaning}}",
+                )
+                .template(
+                    "Recall",
+                    "{{Meaning}}",
+                    "{{FrontSide}}<hr>{{Word}}",
+                ),
+            )
+            .model(
+                ModelSpec::new("Cloze", ["Text"])
+                    .cloze()
+                    .template(
+                        "Cloze",
+                        "{{cloze:Text}}",
+                        "{{cloze:Text}}",
+                    ),
+            );
+        builder
+            .add_note(note(
+                "Vocab",
+                "Japanese::N5",
+                &[
+                    ("Word", "<b>猫</b>"),
+                    ("Meaning", "cat"),
+                ],
+            ))
+            .unwrap();
+        builder
+            .add_note(note(
+                "Vocab",
+                "Japanese::N5",
+                &[("Word", "犬")],
+            ))
+            .unwrap();
+        builder
+            .add_note(note(
+                "Cloze",
+                "Grammar",
+                &[("Text", "{{c1::は}} and {{c3::が}}")],
+            ))
+            .unwrap();
+        builder.add_media("neko.mp3", b"mp3".to_vec());
+        builder
+    }
+
+    fn cards(apkg: &Apkg) -> Vec<(u64, u64, u32)> {
+        let mut statement = apkg
+            .conn
+            .prepare("SELECT nid, did, ord FROM cards ORDER BY nid, ord")
+            .unwrap();
+        statement
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_built_package_contents() {
+        let path = temp_package("contents");
+        builder().write(&path).unwrap();
+        let mut apkg = Apkg::open(&path).unwrap();
+
+        let models = apkg.models().unwrap();
+        assert_eq!(
+            models
+                .iter()
+                .map(|m| (m.name.as_str(), m.id))
+                .collect::<Vec<_>>(),
+            [
+                ("Cloze", stable_id("Cloze")),
+                ("Vocab", stable_id("Vocab"))
+            ]
+        );
+        assert_eq!(
+            apkg.decks()
+                .unwrap()
+                .into_iter()
+                .map(|deck| deck.name)
+                .collect::<Vec<_>>(),
+            [
+                "Default",
+                "Grammar",
+                "Japanese",
+                "Japanese::N5"
+            ]
+        );
+
+        let notes: Vec<ApkgNote> =
+            apkg.notes().collect::<Result<_>>().unwrap();
+        let cat = notes
+            .iter()
+            .find(|note| note.guid == guid_for("<b>猫</b>"))
+            .unwrap();
+        assert_eq!(cat.fields, ["<b>猫</b>", "cat"]);
+        assert_eq!(cat.tags, ["ci"]);
+        assert_eq!(
+            cat.deck_id,
+            Some(stable_id("Japanese::N5"))
+        );
+
+        let (sfld, csum): (String, u32) = apkg
+            .conn
+            .query_row(
+                "SELECT sfld, csum FROM notes WHERE id = ?1",
+                [cat.id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(sfld, "猫");
+        assert_eq!(csum, field_checksum("猫"));
+
+        let dog = notes
+            .iter()
+            .find(|note| note.guid == guid_for("犬"))
+            .unwrap();
+        let cloze = notes
+            .iter()
+            .find(|note| {
+                note.model_id == stable_id("Cloze")
+            })
+            .unwrap();
+        let cards = cards(&apkg);
+        let ords = |id: u64| -> Vec<u32> {
+            cards
+                .iter()
+                .filter(|card| card.0 == id)
+                .map(|card| card.2)
+                .collect()
+        };
+        assert_eq!(ords(cat.id), [0, 1]);
+        // The recall card would have an empty front
+        assert_eq!(ords(dog.id), [0]);
+        assert_eq!(ords(cloze.id), [0, 2]);
+
+        let media: Vec<_> = apkg.media().cloned().collect();
+        assert_eq!(media[0].file_name, "neko.mp3");
+        assert_eq!(
+            apkg.read_media(&media[0]).unwrap(),
+            b"mp3"
+        );
+    }
+
+    #[test]
+    fn test_ids_and_guids_are_deterministic() {
+        let first = temp_package("first");
+        let second = temp_package("second");
+        builder().write(&first).unwrap();
+        builder().write(&second).unwrap();
+
+        let ids = |path: &Path| -> HashMap<String, u64> {
+            Apkg::open(path)
+                .unwrap()
+                .notes()
+                .map(|note| {
+                    let note = note.unwrap();
+                    (note.guid, note.id)
+                })
+                .collect()
+        };
+        assert_eq!(ids(&first), ids(&second));
+        assert_eq!(guid_for("猫"), guid_for("猫"));
+        assert_ne!(guid_for("猫"), guid_for("犬"));
+        assert!(
+            guid_for("猫")
+                .bytes()
+                .all(|b| GUID_ALPHABET.contains(&b))
+        );
+    }
+
+    #[test]
+    fn test_notes_must_match_a_model() {
+        let mut builder = builder();
+        let error = builder
+            .add_note(note("Missing", "Deck", &[]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown model 'Missing'"
+        );
+        let error = builder
+            .add_note(note(
+                "Vocab",
+                "Deck",
+                &[("Reading", "ねこ")],
+            ))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Model 'Vocab' has no field 'Reading'"
+        );
+    }
+}
@@ -0,0 +1,1016 @@
+//! Cloze deletions: parsing, checking and editing `{{c1::text}}`
+//! markup, and breaking long passages into cloze cards.
+//!
+//! For [`split_into_clozes`], key terms are the ones the text marks
+//! as important with `**term**`, `<b>term</b>` or
+//! `<strong>term</strong>`, as AI answers usually do. A sentence
+//! without any marked term falls back to its longest word of at
+//! least [`MIN_FALLBACK_TERM_CHARS`] characters.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::{Result, bail};
+use utils::text::split_sentences;
+
+use crate::convert::{media_references, strip_html};
+
+/// One `{{cN::text::hint}}` deletion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClozeSpan<'a> {
+    /// Byte range of the whole deletion, from `{{` to `}}`
+    pub range: Range<usize>,
+    /// Cloze number, `1` for `c1`
+    pub index: usize,
+    /// The hidden text, including the markup of nested deletions
+    pub text: &'a str,
+    /// Hint shown in place of the text
+    pub hint: Option<&'a str>,
+    /// Number of deletions this one is nested in
+    pub depth: usize,
+}
+
+/// Problem found by [`validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClozeIssue {
+    /// `{{cN::` is never closed, so Anki shows it as text
+    Unclosed {
+        /// Byte offset of the opening `{{`
+        position: usize,
+    },
+    /// `}}` closes no deletion
+    UnmatchedClose {
+        /// Byte offset of the `}}`
+        position: usize,
+    },
+    /// `c0` never becomes a card
+    ZeroIndex {
+        /// Byte offset of the deletion
+        position: usize,
+    },
+    /// A deletion inside another one
+    Nested {
+        /// Byte offset of the inner deletion
+        position: usize,
+    },
+    /// The deletion hides nothing visible, so its card is blank
+    EmptyBody {
+        /// Byte offset of the deletion
+        position: usize,
+    },
+    /// Numbers below the highest one are unused, e.g. `c3` without
+    /// `c2`. Anki accepts this, but it usually means a deletion was
+    /// lost, so it is only a warning.
+    MissingIndices {
+        /// The unused numbers, ascending
+        missing: Vec<usize>,
+    },
+}
+
+impl ClozeIssue {
+    /// Whether Anki still renders every card as intended
+    pub fn is_warning(&self) -> bool {
+        matches!(self, ClozeIssue::MissingIndices { .. })
+    }
+}
+
+impl fmt::Display for ClozeIssue {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            ClozeIssue::Unclosed { position } => write!(
+                f,
+                "cloze at byte {} is never closed",
+                position
+            ),
+            ClozeIssue::UnmatchedClose { position } => {
+                write!(
+                    f,
+                    "`}}}}` at byte {} closes no cloze",
+                    position
+                )
+            }
+            ClozeIssue::ZeroIndex { position } => write!(
+                f,
+                "cloze at byte {} is numbered c0",
+                position
+            ),
+            ClozeIssue::Nested { position } => write!(
+                f,
+                "cloze at byte {} is nested in another cloze",
+                position
+            ),
+            ClozeIssue::EmptyBody { position } => write!(
+                f,
+                "cloze at byte {} hides nothing",
+                position
+            ),
+            ClozeIssue::MissingIndices { missing } => {
+                let missing: Vec<String> = missing
+                    .iter()
+                    .map(|index| format!("c{}", index))
+                    .collect();
+                write!(
+                    f,
+                    "cloze numbers {} are unused",
+                    missing.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Deletions found by a lenient scan, with the markup it could not
+/// match
+struct Scan<'a> {
+    /// Every deletion, ordered by start
+    spans: Vec<ClozeSpan<'a>>,
+    /// Offsets of openings that are never closed
+    unclosed: Vec<usize>,
+    /// Offsets of `}}` that close nothing
+    unmatched: Vec<usize>,
+}
+
+/// An opening `{{cN::` whose `}}` has not been seen yet
+struct OpenCloze {
+    start: usize,
+    index: usize,
+    body: usize,
+    /// Start of the text after the last nested deletion, where
+    /// Anki looks for the hint
+    segment: usize,
+}
+
+/// Cloze number and length of the `{{cN::` `text` starts with
+fn opening(text: &str) -> Option<(usize, usize)> {
+    let rest = text.strip_prefix("{{c")?;
+    let digits =
+        rest.bytes().take_while(u8::is_ascii_digit).count();
+    let index = rest[..digits].parse().ok()?;
+    rest[digits..]
+        .starts_with("::")
+        .then_some((index, 3 + digits + 2))
+}
+
+/// Finds deletions the way Anki does: `}}` closes the innermost
+/// open deletion, and the hint follows the first `::` after the
+/// last nested one
+fn scan(text: &str) -> Scan<'_> {
+    let mut scan = Scan {
+        spans: Vec::new(),
+        unclosed: Vec::new(),
+        unmatched: Vec::new(),
+    };
+    let mut open: Vec<OpenCloze> = Vec::new();
+    let mut pos = 0;
+    while let Some(c) = text[pos..].chars().next() {
+        if let Some((index, len)) = opening(&text[pos..]) {
+            open.push(OpenCloze {
+                start: pos,
+                index,
+                body: pos + len,
+                segment: pos + len,
+            });
+            pos += len;
+            continue;
+        }
+        if !text[pos..].starts_with("}}") {
+            pos += c.len_utf8();
+            continue;
+        }
+        let Some(cloze) = open.pop() else {
+            scan.unmatched.push(pos);
+            pos += 2;
+            continue;
+        };
+        let (text_end, hint) = match text
+            [cloze.segment..pos]
+            .find("::")
+        {
+            Some(sep) => (
+                cloze.segment + sep,
+                Some(&text[cloze.segment + sep + 2..pos]),
+            ),
+            None => (pos, None),
+        };
+        scan.spans.push(ClozeSpan {
+            range: cloze.start..pos + 2,
+            index: cloze.index,
+            text: &text[cloze.body..text_end],
+            hint,
+            depth: open.len(),
+        });
+        if let Some(parent) = open.last_mut() {
+            parent.segment = pos + 2;
+        }
+        pos += 2;
+    }
+    scan.unclosed =
+        open.into_iter().map(|cloze| cloze.start).collect();
+    scan.spans.sort_by_key(|span| span.range.start);
+    scan
+}
+
+/// Byte range of a deletion's text within `text`
+fn text_range(
+    text: &str,
+    span: &ClozeSpan,
+) -> Range<usize> {
+    let (_, len) = opening(&text[span.range.start..])
+        .expect("span starts with an opening");
+    let start = span.range.start + len;
+    start..start + span.text.len()
+}
+
+/// Deletions of `text` as Anki sees them, skipping unclosed ones
+#[cfg(any(test, feature = "apkg"))]
+pub(crate) fn spans(text: &str) -> Vec<ClozeSpan<'_>> {
+    scan(text).spans
+}
+
+/// Parses every cloze deletion in `text`, ordered by position.
+///
+/// Nested deletions are returned along with the ones containing
+/// them. Fails if a deletion is never closed; a stray `}}` is
+/// plain text to Anki and is ignored here too.
+pub fn parse(text: &str) -> Result<Vec<ClozeSpan<'_>>> {
+    let scan = scan(text);
+    if let Some(position) = scan.unclosed.first() {
+        bail!("Cloze at byte {} is never closed", position);
+    }
+    Ok(scan.spans)
+}
+
+/// Lists everything in `text` that may make Anki render a cloze card
+/// blank or differently than intended, ordered by position with
+/// [`ClozeIssue::MissingIndices`] last
+pub fn validate(text: &str) -> Vec<ClozeIssue> {
+    let scan = scan(text);
+    let mut issues: Vec<(usize, ClozeIssue)> = Vec::new();
+    for &position in &scan.unclosed {
+        issues.push((
+            position,
+            ClozeIssue::Unclosed { position },
+        ));
+    }
+    for &position in &scan.unmatched {
+        issues.push((
+            position,
+            ClozeIssue::UnmatchedClose { position },
+        ));
+    }
+    for span in &scan.spans {
+        let position = span.range.start;
+        if span.index == 0 {
+            issues.push((
+                position,
+                ClozeIssue::ZeroIndex { position },
+            ));
+        }
+        if span.depth > 0 {
+            issues.push((
+                position,
+                ClozeIssue::Nested { position },
+            ));
+        }
+        // Images and sounds are visible even without text
+        if strip_html(span.text).is_empty()
+            && media_references(span.text).is_empty()
+        {
+            issues.push((
+                position,
+                ClozeIssue::EmptyBody { position },
+            ));
+        }
+    }
+    issues.sort_by_key(|(position, _)| *position);
+    let mut issues: Vec<ClozeIssue> = issues
+        .into_iter()
+        .map(|(_, issue)| issue)
+        .collect();
+
+    let used: BTreeSet<usize> =
+        scan.spans.iter().map(|span| span.index).collect();
+    if let Some(&highest) = used.last() {
+        let missing: Vec<usize> = (1..highest)
+            .filter(|index| !used.contains(index))
+            .collect();
+        if !missing.is_empty() {
+            issues.push(ClozeIssue::MissingIndices {
+                missing,
+            });
+        }
+    }
+    issues
+}
+
+/// Numbers the deletions `c1`, `c2`, ... without gaps.
+///
+/// Distinct numbers keep their order and deletions sharing a number
+/// keep sharing one, so `c2 c5 c2` becomes `c1 c2 c1`. Only the
+/// numbers change; everything else in `text` is kept.
+pub fn renumber(text: &str) -> String {
+    let scan = scan(text);
+    let numbers: BTreeSet<usize> =
+        scan.spans.iter().map(|span| span.index).collect();
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    for span in &scan.spans {
+        let new = numbers
+            .iter()
+            .position(|index| *index == span.index)
+            .expect("index was collected")
+            + 1;
+        let (_, len) = opening(&text[span.range.start..])
+            .expect("span starts with an opening");
+        out.push_str(&text[copied..span.range.start]);
+        out.push_str(&format!("{{{{c{}::", new));
+        copied = span.range.start + len;
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+/// `{{cN::text}}`
+fn wrap(index: usize, text: &str) -> String {
+    format!("{{{{c{}::{}}}}}", index, text)
+}
+
+/// Turns the bytes `range` of `text` into deletion number `index`.
+///
+/// Fails if `index` is 0, or if `range` is empty, out of bounds, not
+/// on character boundaries, or cuts through the markup of an
+/// existing deletion. A range covering whole deletions nests them,
+/// which [`validate`] reports.
+pub fn add_cloze(
+    text: &str,
+    range: Range<usize>,
+    index: usize,
+) -> Result<String> {
+    if index == 0 {
+        bail!("Cloze numbers start at 1");
+    }
+    let Some(hidden) = text
+        .get(range.clone())
+        .filter(|hidden| !hidden.is_empty())
+    else {
+        bail!(
+            "{:?} is empty or not a range of characters in the {}-byte text",
+            range,
+            text.len()
+        );
+    };
+    for span in scan(text).spans {
+        let body = text_range(text, &span);
+        let outside = range.end <= span.range.start
+            || range.start >= span.range.end;
+        let covers = range.start <= span.range.start
+            && range.end >= span.range.end;
+        let within = range.start >= body.start
+            && range.end <= body.end;
+        if !(outside || covers || within) {
+            bail!(
+                "{:?} cuts through the cloze at {:?}",
+                range,
+                span.range
+            );
+        }
+    }
+    Ok(format!(
+        "{}{}{}",
+        &text[..range.start],
+        wrap(index, hidden),
+        &text[range.end..]
+    ))
+}
+
+/// Replaces every deletion with its text, dropping numbers and
+/// hints; markup that does not form a deletion is kept
+pub fn strip_clozes(text: &str) -> String {
+    // Byte ranges of markup to drop, in order
+    let mut markup: Vec<Range<usize>> = Vec::new();
+    for span in scan(text).spans {
+        let body = text_range(text, &span);
+        markup.push(span.range.start..body.start);
+        markup.push(body.end..span.range.end);
+    }
+    markup.sort_by_key(|range| range.start);
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    for range in markup {
+        if range.start >= copied {
+            out.push_str(&text[copied..range.start]);
+            copied = range.end;
+        }
+    }
+    out.push_str(&text[copied..]);
+    out
+}
+
+/// Shortest word picked as a key term of an unmarked sentence
+pub const MIN_FALLBACK_TERM_CHARS: usize = 4;
+
+/// Emphasis markers recognized around key terms
+const MARKERS: [(&str, &str); 3] = [
+    ("**", "**"),
+    ("<b>", "</b>"),
+    ("<strong>", "</strong>"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part<'a> {
+    Text(&'a str),
+    Term(&'a str),
+}
+
+/// Splits `text` into cloze field values with at most
+/// `max_per_card` deletions each.
+///
+/// Whole sentences are packed into a card until the next one would
+/// exceed the cap; deletions are numbered `c1`, `c2`, ... within
+/// each card. Terms beyond the cap within a single sentence stay
+/// plain text, and sentences without any key term are kept as
+/// context of the current card. A `max_per_card` of 0 counts as 1.
+pub fn split_into_clozes(
+    text: &str,
+    max_per_card: usize,
+) -> Vec<String> {
+    let max_per_card = max_per_card.max(1);
+    let mut cards = Vec::new();
+    let mut sentences: Vec<Vec<Part>> = Vec::new();
+    let mut deletions = 0;
+
+    for sentence in split_sentences(text) {
+        let parts = key_terms(sentence);
+        let terms = parts
+            .iter()
+            .filter(|part| matches!(part, Part::Term(_)))
+            .count()
+            .min(max_per_card);
+        if deletions > 0 && deletions + terms > max_per_card
+        {
+            cards.push(render(&sentences, max_per_card));
+            sentences.clear();
+            deletions = 0;
+        }
+        deletions += terms;
+        sentences.push(parts);
+    }
+    if deletions > 0 {
+        cards.push(render(&sentences, max_per_card));
+    }
+    cards
+}
+
+/// Renders sentences as one card, numbering deletions from `c1`
+fn render(
+    sentences: &[Vec<Part>],
+    max_per_card: usize,
+) -> String {
+    let mut next = 1;
+    let rendered: Vec<String> = sentences
+        .iter()
+        .map(|parts| {
+            let mut clozed_in_sentence = 0;
+            parts
+                .iter()
+                .map(|part| match part {
+                    Part::Term(term)
+                        if clozed_in_sentence
+                            < max_per_card =>
+                    {
+                        clozed_in_sentence += 1;
+                        let cloze = wrap(next, term);
+                        next += 1;
+                        cloze
+                    }
+                    Part::Term(term) | Part::Text(term) => {
+                        term.to_string()
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    rendered.join(" ")
+}
+
+/// The sentence cut into text and key terms, markers removed
+fn key_terms(sentence: &str) -> Vec<Part<'_>> {
+    let parts = marked_terms(sentence);
+    if parts
+        .iter()
+        .any(|part| matches!(part, Part::Term(_)))
+    {
+        return parts;
+    }
+    match longest_word(sentence) {
+        Some((start, end)) => [
+            Part::Text(&sentence[..start]),
+            Part::Term(&sentence[start..end]),
+            Part::Text(&sentence[end..]),
+        ]
+        .into_iter()
+        .filter(|part| *part != Part::Text(""))
+        .collect(),
+        None => vec![Part::Text(sentence)],
+    }
+}
+
+/// Splits at emphasis markers; unbalanced markers stay as text
+fn marked_terms(sentence: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = sentence;
+    loop {
+        let next = MARKERS
+            .iter()
+            .filter_map(|&(open, close)| {
+                let start = rest.find(open)?;
+                let inner = start + open.len();
+                let len = rest[inner..].find(close)?;
+                Some((
+                    start,
+                    inner,
+                    inner + len,
+                    close.len(),
+                ))
+            })
+            .min_by_key(|&(start, ..)| start);
+        let Some((start, inner, end, close_len)) = next
+        else {
+            break;
+        };
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        let term = rest[inner..end].trim();
+        if term.is_empty() {
+            parts.push(Part::Text(
+                &rest[start..end + close_len],
+            ));
+        } else {
+            parts.push(Part::Term(term));
+        }
+        rest = &rest[end + close_len..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    parts
+}
+
+/// Byte range of the first longest alphanumeric word
+fn longest_word(sentence: &str) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize, usize)> = None;
+    let mut start: Option<usize> = None;
+    let boundaries = sentence
+        .char_indices()
+        .map(|(i, c)| (i, c.is_alphanumeric()))
+        .chain(std::iter::once((sentence.len(), false)));
+    for (i, in_word) in boundaries {
+        match (start, in_word) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                let chars = sentence[s..i].chars().count();
+                if best.is_none_or(|(_, _, n)| chars > n) {
+                    best = Some((s, i, chars));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    best.filter(|&(_, _, chars)| {
+        chars >= MIN_FALLBACK_TERM_CHARS
+    })
+    .map(|(start, end, _)| (start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marked_terms_become_numbered_deletions() {
+        let text = "The **mitochondria** is the powerhouse of the <b>cell</b>. It makes <strong>ATP</strong>.";
+        assert_eq!(
+            split_into_clozes(text, 3),
+            vec![
+                "The {{c1::mitochondria}} is the powerhouse of the {{c2::cell}}. It makes {{c3::ATP}}."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cap_starts_new_card_at_sentence_boundary() {
+        let text = "**Rust** has **ownership**. Values have one **owner**. The owner **drops** them.";
+        assert_eq!(
+            split_into_clozes(text, 2),
+            vec![
+                "{{c1::Rust}} has {{c2::ownership}}.",
+                "Values have one {{c1::owner}}. The owner {{c2::drops}} them."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_terms_over_the_cap_stay_plain() {
+        let text = "**a1** **b2** **c3**.";
+        assert_eq!(
+            split_into_clozes(text, 2),
+            vec!["{{c1::a1}} {{c2::b2}} c3."]
+        );
+        assert_eq!(
+            split_into_clozes(text, 0),
+            vec!["{{c1::a1}} b2 c3."]
+        );
+    }
+
+    #[test]
+    fn test_unmarked_sentences_use_longest_word() {
+        let text = "Photosynthesis needs light. It is so. 光合作用需要光。";
+        assert_eq!(
+            split_into_clozes(text, 1),
+            vec![
+                "{{c1::Photosynthesis}} needs light. It is so.",
+                "{{c1::光合作用需要光}}。"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sentence_boundaries_keep_decimals() {
+        let text = "Pi is about **3.14** in value. Euler's number is **2.718**!";
+        assert_eq!(
+            split_into_clozes(text, 1),
+            vec![
+                "Pi is about {{c1::3.14}} in value.",
+                "Euler's number is {{c1::2.718}}!"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_terms_no_cards() {
+        assert!(split_into_clozes("", 3).is_empty());
+        assert!(
+            split_into_clozes("A b c. ** **", 3).is_empty()
+        );
+    }
+    fn span<'a>(
+        range: Range<usize>,
+        index: usize,
+        text: &'a str,
+        hint: Option<&'a str>,
+        depth: usize,
+    ) -> ClozeSpan<'a> {
+        ClozeSpan {
+            range,
+            index,
+            text,
+            hint,
+            depth,
+        }
+    }
+
+    #[test]
+    fn test_parse_spans_and_hints() {
+        let text = "{{c1::Paris}} is the capital of {{c2::France::country}}.";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                span(0..13, 1, "Paris", None, 0),
+                span(
+                    32..55,
+                    2,
+                    "France",
+                    Some("country"),
+                    0
+                ),
+            ]
+        );
+        assert_eq!(
+            &text[32..55],
+            "{{c2::France::country}}"
+        );
+        assert_eq!(
+            parse("{{c12::a::b::c}} {{c1::}}").unwrap(),
+            [
+                span(0..16, 12, "a", Some("b::c"), 0),
+                span(17..25, 1, "", None, 0),
+            ]
+        );
+        assert!(
+            parse("no clozes {{c::x}} {{C1::y}} {{c1:z}}")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_byte_boundaries() {
+        let text = "猫{{c1::犬}}ね{{c2::鳥::とり}}";
+        let spans = parse(text).unwrap();
+        assert_eq!(
+            spans,
+            [
+                span(3..14, 1, "犬", None, 0),
+                span(17..36, 2, "鳥", Some("とり"), 0),
+            ]
+        );
+        for span in &spans {
+            assert!(
+                text[span.range.clone()].starts_with("{{c")
+            );
+            assert!(
+                text[span.range.clone()].ends_with("}}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_braces_inside_cloze_text() {
+        assert_eq!(
+            parse("{{c1::a set {1, 2} }}").unwrap(),
+            [span(0..21, 1, "a set {1, 2} ", None, 0)]
+        );
+        // Like Anki, the first `}}` closes the deletion
+        let text = "{{c1::{x}}}";
+        assert_eq!(
+            parse(text).unwrap(),
+            [span(0..10, 1, "{x", None, 0)]
+        );
+        assert_eq!(strip_clozes(text), "{x}");
+        assert_eq!(
+            parse("{{{{c1::x}}}}").unwrap(),
+            [span(2..11, 1, "x", None, 0)]
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_clozes() {
+        let text = "{{c1::outer {{c2::inner::hint}} tail::outer hint}}";
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                span(
+                    0..50,
+                    1,
+                    "outer {{c2::inner::hint}} tail",
+                    Some("outer hint"),
+                    0
+                ),
+                span(12..31, 2, "inner", Some("hint"), 1),
+            ]
+        );
+        // A `::` before the nested deletion is not the hint
+        assert_eq!(
+            parse("{{c1::a::b {{c2::c}} d}}").unwrap()[0]
+                .hint,
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unclosed() {
+        let error =
+            parse("ok {{c1::x}} {{c2::open").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cloze at byte 13 is never closed"
+        );
+        // A stray `}}` is plain text
+        assert_eq!(parse("a }} b").unwrap(), []);
+    }
+
+    #[test]
+    fn test_validate() {
+        let cases: &[(&str, &[ClozeIssue])] = &[
+            ("{{c1::a}} {{c2::b}} {{c1::c}}", &[]),
+            ("plain text", &[]),
+            ("{{c1::<img src=\"a.png\">}}", &[]),
+            ("{{c1::[sound:a.mp3]}}", &[]),
+            (
+                "{{c1::a}} {{c2::open",
+                &[ClozeIssue::Unclosed { position: 10 }],
+            ),
+            (
+                "{{c1::a}}}} b }}",
+                &[
+                    ClozeIssue::UnmatchedClose {
+                        position: 9,
+                    },
+                    ClozeIssue::UnmatchedClose {
+                        position: 14,
+                    },
+                ],
+            ),
+            (
+                "{{c0::a}} {{c1::b}}",
+                &[ClozeIssue::ZeroIndex { position: 0 }],
+            ),
+            (
+                "{{c1::a {{c2::b}}}}",
+                &[ClozeIssue::Nested { position: 8 }],
+            ),
+            (
+                "{{c1::}} {{c2:: <br> }} {{c3::&nbsp;::hint}}",
+                &[
+                    ClozeIssue::EmptyBody { position: 0 },
+                    ClozeIssue::EmptyBody { position: 9 },
+                    ClozeIssue::EmptyBody { position: 24 },
+                ],
+            ),
+            (
+                "{{c1::a}} {{c3::b}} {{c6::c}}",
+                &[ClozeIssue::MissingIndices {
+                    missing: vec![2, 4, 5],
+                }],
+            ),
+            (
+                "{{c3::}} }} {{c2::猫",
+                &[
+                    ClozeIssue::EmptyBody { position: 0 },
+                    ClozeIssue::UnmatchedClose {
+                        position: 9,
+                    },
+                    ClozeIssue::Unclosed { position: 12 },
+                    ClozeIssue::MissingIndices {
+                        missing: vec![1, 2],
+                    },
+                ],
+            ),
+        ];
+        for (text, issues) in cases {
+            assert_eq!(
+                validate(text),
+                *issues,
+                "validating {:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_issue_severity_and_messages() {
+        let issues = validate("{{c2::}}");
+        assert_eq!(
+            issues
+                .iter()
+                .map(|issue| (
+                    issue.is_warning(),
+                    issue.to_string()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    false,
+                    "cloze at byte 0 hides nothing"
+                        .to_string()
+                ),
+                (
+                    true,
+                    "cloze numbers c1 are unused"
+                        .to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            ClozeIssue::UnmatchedClose { position: 3 }
+                .to_string(),
+            "`}}` at byte 3 closes no cloze"
+        );
+    }
+
+    #[test]
+    fn test_renumber() {
+        let cases = [
+            ("{{c1::a}} {{c2::b}}", "{{c1::a}} {{c2::b}}"),
+            (
+                "{{c2::a}} {{c5::b}} {{c2::c}}",
+                "{{c1::a}} {{c2::b}} {{c1::c}}",
+            ),
+            (
+                "{{c3::a}} {{c1::b::hint}}",
+                "{{c2::a}} {{c1::b::hint}}",
+            ),
+            ("{{c0::a}} {{c10::b}}", "{{c1::a}} {{c2::b}}"),
+            (
+                "{{c4::猫 {{c9::犬}}}}です",
+                "{{c1::猫 {{c2::犬}}}}です",
+            ),
+            (
+                "{{c7::a {{c3::x}} }}",
+                "{{c2::a {{c1::x}} }}",
+            ),
+            (
+                // Unclosed openings are left alone
+                "{{c7::open {{c3::x}}",
+                "{{c7::open {{c1::x}}",
+            ),
+            ("no clozes }}", "no clozes }}"),
+        ];
+        for (text, renumbered) in cases {
+            assert_eq!(
+                renumber(text),
+                renumbered,
+                "renumbering {:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_cloze() {
+        let text = "猫は{{c1::動物}}です";
+        assert_eq!(
+            add_cloze(text, 0..3, 2).unwrap(),
+            "{{c2::猫}}は{{c1::動物}}です"
+        );
+        assert_eq!(
+            add_cloze(text, 12..18, 2).unwrap(),
+            "猫は{{c1::{{c2::動物}}}}です"
+        );
+        assert_eq!(
+            add_cloze(text, 3..23, 2).unwrap(),
+            "猫{{c2::は{{c1::動物}}で}}す"
+        );
+        assert_eq!(
+            add_cloze("a b", 2..3, 1).unwrap(),
+            "a {{c1::b}}"
+        );
+    }
+
+    #[test]
+    fn test_add_cloze_rejects_bad_ranges() {
+        let text = "猫は{{c1::動物::hint}}です";
+        let error = |range: Range<usize>, index| {
+            add_cloze(text, range, index)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(0..3, 0),
+            "Cloze numbers start at 1"
+        );
+        assert_eq!(
+            error(1..3, 1),
+            "1..3 is empty or not a range of characters in the 32-byte text"
+        );
+        assert!(error(3..3, 1).contains("is empty"));
+        assert!(error(0..99, 1).contains("not a range"));
+        assert_eq!(
+            error(0..8, 1),
+            "0..8 cuts through the cloze at 6..26"
+        );
+        assert!(error(12..20, 1).contains("cuts through"));
+        // The hint is not part of the hidden text
+        assert!(error(20..24, 1).contains("cuts through"));
+    }
+
+    #[test]
+    fn test_strip_clozes() {
+        let cases = [
+            (
+                "{{c1::Paris}} is in {{c2::France::country}}.",
+                "Paris is in France.",
+            ),
+            ("{{c1::a {{c2::b::h}} c::outer}}", "a b c"),
+            ("{{c1::{x}}}", "{x}"),
+            (
+                "{{c1::open and }} stray }}",
+                "open and  stray }}",
+            ),
+            ("{{c2::open", "{{c2::open"),
+            ("猫{{c1::犬}}鳥", "猫犬鳥"),
+            ("", ""),
+        ];
+        for (text, stripped) in cases {
+            assert_eq!(
+                strip_clozes(text),
+                stripped,
+                "stripping {:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_generated_clozes_validate() {
+        let text = "The **mitochondria** is the powerhouse of the <b>cell</b>. It makes <strong>ATP</strong>. Then {braces} stay.";
+        for card in split_into_clozes(text, 2) {
+            assert_eq!(
+                validate(&card),
+                [],
+                "checking {:?}",
+                card
+            );
+            assert_eq!(
+                parse(&card).unwrap().len(),
+                card.matches("{{c").count()
+            );
+        }
+    }
+}
@@ -0,0 +1,2 @@
+//! Exporters that render Anki content into portable formats
+pub mod markdown;
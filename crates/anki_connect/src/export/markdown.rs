@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+
+use crate::anki::client::{
+    AnkiClient, DEFAULT_INFO_CHUNK, NoteInfo, deck_query,
+};
+use crate::convert::{
+    field_html_to_markdown_opts, strip_html,
+};
+
+/// Longest heading derived from a note's first field
+const MAX_HEADING_CHARS: usize = 80;
+
+/// Options for [`export_deck_markdown`]
+#[derive(Debug, Clone, Default)]
+pub struct MdExportOptions {
+    /// Group notes into one section per tag (untagged notes last)
+    pub group_by_tag: bool,
+    /// Write `![](media/...)` links for images found in fields
+    pub include_media_links: bool,
+}
+
+/// Exports every note of a deck (including subdecks) as Markdown.
+///
+/// Notes are ordered by note id so repeated exports of an unchanged
+/// deck are byte-identical. Models with `Front`/`Back` fields render
+/// as a Q/A pair; any other model renders one section per field.
+///
+/// # Returns
+/// The number of exported notes.
+pub async fn export_deck_markdown<W: Write>(
+    anki_client: &AnkiClient,
+    deck: &str,
+    mut writer: W,
+    options: MdExportOptions,
+) -> Result<usize> {
+    let mut note_ids =
+        anki_client.find_notes(&deck_query(deck)).await?;
+    note_ids.sort_unstable();
+
+    let mut notes = Vec::with_capacity(note_ids.len());
+    for chunk in note_ids.chunks(DEFAULT_INFO_CHUNK) {
+        notes.extend(
+            anki_client.notes_info(chunk.to_vec()).await?,
+        );
+    }
+
+    let markdown =
+        render_deck_markdown(deck, &notes, &options);
+    writer
+        .write_all(markdown.as_bytes())
+        .context("Failed to write Markdown export")?;
+    writer
+        .flush()
+        .context("Failed to flush Markdown export")?;
+    Ok(notes.len())
+}
+
+/// Renders already fetched notes as a Markdown document
+pub fn render_deck_markdown(
+    deck: &str,
+    notes: &[NoteInfo],
+    options: &MdExportOptions,
+) -> String {
+    let mut sorted: Vec<&NoteInfo> = notes.iter().collect();
+    sorted.sort_by_key(|note| note.note_id);
+
+    let mut out = format!("# {}\n", deck);
+    if !options.group_by_tag {
+        for note in sorted {
+            out.push('\n');
+            render_note(&mut out, note, 2, options);
+        }
+        return out;
+    }
+
+    let mut groups: BTreeMap<&str, Vec<&NoteInfo>> =
+        BTreeMap::new();
+    let mut untagged = Vec::new();
+    for note in sorted {
+        if note.tags.is_empty() {
+            untagged.push(note);
+        }
+        for tag in &note.tags {
+            groups
+                .entry(tag.as_str())
+                .or_default()
+                .push(note);
+        }
+    }
+
+    let sections = groups.into_iter().chain(
+        (!untagged.is_empty())
+            .then_some(("Untagged", untagged)),
+    );
+    for (tag, notes) in sections {
+        out.push_str(&format!("\n## {}\n", tag));
+        for note in notes {
+            out.push('\n');
+            render_note(&mut out, note, 3, options);
+        }
+    }
+    out
+}
+
+/// Appends one note, using `level` for the note heading
+fn render_note(
+    out: &mut String,
+    note: &NoteInfo,
+    level: usize,
+    options: &MdExportOptions,
+) {
+    let fields = note.ordered_fields();
+    let to_md = |html: &str| {
+        field_html_to_markdown_opts(
+            html,
+            options.include_media_links,
+        )
+    };
+
+    out.push_str(&format!(
+        "<!-- note {} -->\n",
+        note.note_id
+    ));
+    out.push_str(&format!(
+        "{} {}\n",
+        "#".repeat(level),
+        heading(note, &fields)
+    ));
+
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| *v)
+    };
+    match (field("Front"), field("Back")) {
+        (Some(front), Some(back)) => {
+            out.push_str(&format!(
+                "\n**Q:** {}\n",
+                to_md(front)
+            ));
+            out.push_str(&format!(
+                "\n**A:** {}\n",
+                to_md(back)
+            ));
+        }
+        _ => {
+            for (name, value) in &fields {
+                let body = to_md(value);
+                if body.is_empty() {
+                    continue;
+                }
+                out.push_str(&format!(
+                    "\n{} {}\n\n{}\n",
+                    "#".repeat(level + 1),
+                    name,
+                    body
+                ));
+            }
+        }
+    }
+
+    if !note.tags.is_empty() {
+        let mut tags = note.tags.clone();
+        tags.sort();
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|t| format!("`{}`", t))
+            .collect();
+        out.push_str(&format!(
+            "\nTags: {}\n",
+            tags.join(" ")
+        ));
+    }
+}
+
+/// Single-line heading from the first field, falling back to the id
+fn heading(
+    note: &NoteInfo,
+    fields: &[(&str, &str)],
+) -> String {
+    let text = fields
+        .first()
+        .map(|(_, value)| strip_html(value))
+        .unwrap_or_default();
+    if text.is_empty() {
+        return format!("Note {}", note.note_id);
+    }
+    if text.chars().count() > MAX_HEADING_CHARS {
+        let cut: String =
+            text.chars().take(MAX_HEADING_CHARS).collect();
+        return format!("{}…", cut.trim_end());
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use serde_json::{Value, json};
+
+    fn note(
+        id: u64,
+        model: &str,
+        fields: &[(&str, &str)],
+        tags: &[&str],
+    ) -> Value {
+        let fields: serde_json::Map<String, Value> = fields
+            .iter()
+            .enumerate()
+            .map(|(order, (name, value))| {
+                (name.to_string(), json!({ "value": value, "order": order }))
+            })
+            .collect();
+        json!({
+            "noteId": id,
+            "modelName": model,
+            "tags": tags,
+            "fields": fields,
+            "cards": [id + 1],
+        })
+    }
+
+    fn sample_notes() -> Vec<Value> {
+        vec![
+            note(
+                30,
+                "Japanese",
+                &[
+                    (
+                        "Expression",
+                        "<ruby>猫<rt>ねこ</rt></ruby>",
+                    ),
+                    (
+                        "Meaning",
+                        "<div>cat</div><div><img src=\"neko.png\"></div>",
+                    ),
+                    ("Notes", ""),
+                ],
+                &["animals", "n5"],
+            ),
+            note(
+                10,
+                "Basic",
+                &[
+                    ("Front", "What is <b>2+2</b>?"),
+                    ("Back", "4"),
+                ],
+                &[],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_render_ordered_by_note_id() {
+        let notes: Vec<NoteInfo> = sample_notes()
+            .into_iter()
+            .map(|v| serde_json::from_value(v).unwrap())
+            .collect();
+        let markdown = render_deck_markdown(
+            "Deck",
+            &notes,
+            &MdExportOptions::default(),
+        );
+        assert_eq!(
+            markdown,
+            "# Deck\n\
+             \n<!-- note 10 -->\n## What is 2+2?\n\
+             \n**Q:** What is **2+2**?\n\
+             \n**A:** 4\n\
+             \n<!-- note 30 -->\n## 猫\n\
+             \n### Expression\n\n猫[ねこ]\n\
+             \n### Meaning\n\ncat\n\
+             \nTags: `animals` `n5`\n"
+        );
+    }
+
+    #[test]
+    fn test_render_grouped_by_tag_with_media() {
+        let notes: Vec<NoteInfo> = sample_notes()
+            .into_iter()
+            .map(|v| serde_json::from_value(v).unwrap())
+            .collect();
+        let markdown = render_deck_markdown(
+            "Deck",
+            &notes,
+            &MdExportOptions {
+                group_by_tag: true,
+                include_media_links: true,
+            },
+        );
+        let animals = markdown.find("## animals").unwrap();
+        let n5 = markdown.find("## n5").unwrap();
+        let untagged =
+            markdown.find("## Untagged").unwrap();
+        assert!(animals < n5 && n5 < untagged);
+        assert_eq!(
+            markdown.matches("<!-- note 30 -->").count(),
+            2
+        );
+        assert!(markdown.contains("### 猫"));
+        assert!(
+            markdown.contains("cat\n![](media/neko.png)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_deck_markdown() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "findNotes" => {
+                        assert_eq!(
+                            params["query"],
+                            r#"deck:"Deck""#
+                        );
+                        ok(json!([30, 10]))
+                    }
+                    "notesInfo" => {
+                        assert_eq!(
+                            params["notes"],
+                            json!([10, 30])
+                        );
+                        ok(Value::Array(sample_notes()))
+                    }
+                    _ => ok(Value::Null),
+                },
+            )
+            .await;
+
+        let mut first = Vec::new();
+        let count = export_deck_markdown(
+            &mock.client(),
+            "Deck",
+            &mut first,
+            MdExportOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(count, 2);
+
+        let mut second = Vec::new();
+        export_deck_markdown(
+            &mock.client(),
+            "Deck",
+            &mut second,
+            MdExportOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first, second);
+        assert!(
+            String::from_utf8(first)
+                .unwrap()
+                .starts_with("# Deck\n")
+        );
+    }
+}
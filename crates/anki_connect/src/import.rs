@@ -0,0 +1,2 @@
+//! Importers that bring outside content into Anki
+pub mod manifest;
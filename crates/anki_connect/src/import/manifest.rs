@@ -0,0 +1,652 @@
+//! Import manifests that make re-running an import idempotent.
+//!
+//! Every item of an import source has a stable key, either an
+//! explicit ID column or the source file plus the row position. The
+//! manifest maps each key to the note created for it and a hash of
+//! the content it was created from, so a re-run creates new items,
+//! updates changed ones and skips the rest, regardless of Anki's
+//! duplicate check. It is stored as JSON:
+//!
+//! ```json
+//! {"entries":{"words.csv#3":{"note_id":1700000000000,"content_hash":"9f2c..."}}}
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utils::paths::AppDir;
+pub use utils::state::{ManifestEntry, MappingStore};
+
+use crate::anki::client::{AnkiClient, Note};
+use crate::anki::error::AnkiError;
+use crate::metadata::NoteMetadata;
+
+/// Subdirectory of the data directory holding the manifests
+pub const MANIFEST_DIR_NAME: &str = "import_manifests";
+
+/// Key → note mapping of one import source, saved as JSON.
+///
+/// Fine for small sources; large ones are better served by the
+/// SQLite `StateStore` of `utils` (feature `sqlite`).
+#[derive(Debug, Clone)]
+pub struct ImportManifest {
+    /// File the manifest is saved to
+    path: PathBuf,
+    /// Entries by item key
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+/// On-disk form of [`ImportManifest`]
+#[derive(Default, Serialize, Deserialize)]
+struct ManifestFile {
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl ImportManifest {
+    /// Loads the manifest saved at `path`, or starts an empty one if
+    /// the file does not exist yet
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| {
+                    format!(
+                        "Failed to parse {}",
+                        path.display()
+                    )
+                })?,
+            Err(e)
+                if e.kind()
+                    == std::io::ErrorKind::NotFound =>
+            {
+                ManifestFile::default()
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read {}",
+                        path.display()
+                    )
+                });
+            }
+        };
+        Ok(Self {
+            path,
+            entries: file.entries,
+        })
+    }
+
+    /// Loads the manifest of `source` (e.g. a CSV path) from the
+    /// [`MANIFEST_DIR_NAME`] subdirectory of the data directory
+    pub fn for_source(source: &str) -> Result<Self> {
+        let dir =
+            AppDir::Data.ensure()?.join(MANIFEST_DIR_NAME);
+        Self::load(dir.join(manifest_file_name(source)))
+    }
+
+    /// File the manifest is saved to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Entry recorded for `key`
+    pub fn get(&self, key: &str) -> Option<&ManifestEntry> {
+        self.entries.get(key)
+    }
+
+    /// All entries, sorted by key
+    pub fn entries(
+        &self,
+    ) -> &BTreeMap<String, ManifestEntry> {
+        &self.entries
+    }
+
+    /// Records the note written for `key`
+    pub fn record(
+        &mut self,
+        key: impl Into<String>,
+        entry: ManifestEntry,
+    ) {
+        self.entries.insert(key.into(), entry);
+    }
+
+    /// Writes the manifest to a temporary file and renames it over
+    /// the previous one, so a crash never leaves it half-written
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| {
+                format!(
+                    "Failed to create {}",
+                    dir.display()
+                )
+            })?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        let file = ManifestFile {
+            entries: self.entries.clone(),
+        };
+        fs::write(&tmp, serde_json::to_vec_pretty(&file)?)
+            .with_context(|| {
+                format!("Failed to write {}", tmp.display())
+            })?;
+        fs::rename(&tmp, &self.path).with_context(|| {
+            format!(
+                "Failed to write {}",
+                self.path.display()
+            )
+        })
+    }
+}
+
+impl MappingStore for ImportManifest {
+    fn mapping(
+        &self,
+        key: &str,
+    ) -> Result<Option<ManifestEntry>> {
+        Ok(self.get(key).cloned())
+    }
+
+    fn mappings(
+        &self,
+    ) -> Result<BTreeMap<String, ManifestEntry>> {
+        Ok(self.entries.clone())
+    }
+
+    fn save_mappings(
+        &mut self,
+        entries: Vec<(String, ManifestEntry)>,
+    ) -> Result<()> {
+        let previous = self.entries.clone();
+        self.entries.extend(entries);
+        self.save().inspect_err(|_| self.entries = previous)
+    }
+
+    fn remove_mapping(
+        &mut self,
+        key: &str,
+    ) -> Result<bool> {
+        if self.entries.remove(key).is_none() {
+            return Ok(false);
+        }
+        self.save().map(|()| true)
+    }
+}
+
+/// One item of an import source
+#[derive(Debug, Clone)]
+pub struct ImportItem {
+    /// Stable key of the item within its source
+    pub key: String,
+    /// Note the item maps to
+    pub note: Note,
+}
+
+impl ImportItem {
+    /// Item keyed by an explicit ID, e.g. from an ID column
+    pub fn with_id(
+        id: impl Into<String>,
+        note: Note,
+    ) -> Self {
+        Self {
+            key: id.into(),
+            note,
+        }
+    }
+
+    /// Item keyed by its file and row, for sources without an ID.
+    ///
+    /// Inserting or removing rows above it changes the key.
+    pub fn from_row(
+        file: &str,
+        row: usize,
+        note: Note,
+    ) -> Self {
+        Self {
+            key: format!("{}#{}", file, row),
+            note,
+        }
+    }
+
+    /// Marker linking the note back to this item: the key as
+    /// [`NoteMetadata::SOURCE_ID`] and the [`content_hash`]. Stored
+    /// with [`crate::metadata::write_metadata`], it lets the item's
+    /// note be found with [`crate::metadata::find_by_metadata`] when
+    /// the manifest is lost.
+    pub fn metadata(&self) -> NoteMetadata {
+        NoteMetadata::new()
+            .with(NoteMetadata::SOURCE_ID, self.key.clone())
+            .with(
+                NoteMetadata::CONTENT_HASH,
+                content_hash(&self.note),
+            )
+    }
+}
+
+/// Outcome of [`import_items`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Items without a manifest entry that were added
+    pub created: usize,
+    /// Items whose content changed and whose note was updated
+    pub updated: usize,
+    /// Items unchanged since the last import
+    pub skipped: usize,
+    /// Items whose note had been deleted in Anki and was added
+    /// again
+    pub repaired: usize,
+    /// Keys and errors of the items that could not be imported
+    pub failed: Vec<(String, String)>,
+}
+
+/// Imports `items`, consulting the mappings in `store` to create,
+/// update or skip each one.
+///
+/// An item is updated when its [`content_hash`] changed; only its
+/// fields are written, so deck and tag changes are not carried
+/// over. Entries whose note no longer exists are repaired by adding
+/// the note again. A new item rejected as a duplicate adopts the
+/// existing note when Anki reports it. Each change is saved to
+/// `store` as soon as it is made, and a failing item does not stop
+/// the others.
+pub async fn import_items(
+    anki_client: &AnkiClient,
+    store: &mut impl MappingStore,
+    items: Vec<ImportItem>,
+) -> Result<ImportReport> {
+    let mut known = Vec::new();
+    for item in &items {
+        if let Some(entry) = store.mapping(&item.key)? {
+            known.push(entry.note_id);
+        }
+    }
+    let existing =
+        existing_notes(anki_client, &known).await?;
+    let mut report = ImportReport::default();
+
+    for item in items {
+        let hash = content_hash(&item.note);
+        let result = match store.mapping(&item.key)? {
+            Some(entry)
+                if existing.contains(&entry.note_id)
+                    && entry.content_hash == hash =>
+            {
+                report.skipped += 1;
+                continue;
+            }
+            Some(entry)
+                if existing.contains(&entry.note_id) =>
+            {
+                let note_id = entry.note_id;
+                anki_client
+                    .update_note_fields(
+                        note_id,
+                        item.note.fields.clone(),
+                        None,
+                    )
+                    .await
+                    .map(|()| {
+                        report.updated += 1;
+                        note_id
+                    })
+            }
+            entry => {
+                let repairing = entry.is_some();
+                create_note(anki_client, &item.note)
+                    .await
+                    .map(|(note_id, adopted)| {
+                        if repairing {
+                            report.repaired += 1;
+                        } else if adopted {
+                            report.updated += 1;
+                        } else {
+                            report.created += 1;
+                        }
+                        note_id
+                    })
+            }
+        };
+
+        match result {
+            Ok(note_id) => {
+                store.save_mappings(vec![(
+                    item.key,
+                    ManifestEntry {
+                        note_id,
+                        content_hash: hash,
+                    },
+                )])?;
+            }
+            Err(e) => report
+                .failed
+                .push((item.key, format!("{:#}", e))),
+        }
+    }
+    Ok(report)
+}
+
+/// Adds `note`, or adopts the note it duplicates by overwriting its
+/// fields.
+///
+/// # Returns
+/// The note ID and whether an existing note was adopted
+async fn create_note(
+    anki_client: &AnkiClient,
+    note: &Note,
+) -> Result<(u64, bool)> {
+    match anki_client.add_note(note.clone()).await {
+        Ok(note_id) => Ok((note_id, false)),
+        Err(e) => match e.downcast_ref::<AnkiError>() {
+            Some(AnkiError::Duplicate {
+                existing_note_id: Some(note_id),
+            }) => {
+                let note_id = *note_id;
+                anki_client
+                    .update_note_fields(
+                        note_id,
+                        note.fields.clone(),
+                        None,
+                    )
+                    .await?;
+                Ok((note_id, true))
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Which of `note_ids` still exist in Anki
+async fn existing_notes(
+    anki_client: &AnkiClient,
+    note_ids: &[u64],
+) -> Result<HashSet<u64>> {
+    if note_ids.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let ids: Vec<String> =
+        note_ids.iter().map(ToString::to_string).collect();
+    let found = anki_client
+        .find_notes(&format!("nid:{}", ids.join(",")))
+        .await?;
+    Ok(found.into_iter().collect())
+}
+
+/// SHA-256 over a note's model, deck, fields and tags, lowercase hex.
+///
+/// Fields and tags are sorted first, so their order does not matter.
+pub fn content_hash(note: &Note) -> String {
+    let mut hasher = Sha256::new();
+    let mut part = |text: &str| {
+        hasher.update(text.len().to_le_bytes());
+        hasher.update(text.as_bytes());
+    };
+    part(&note.model_name);
+    part(&note.deck_name);
+    let mut fields: Vec<_> = note.fields.iter().collect();
+    fields.sort();
+    for (name, value) in fields {
+        part(name);
+        part(value);
+    }
+    let mut tags: Vec<_> = note.tags.iter().collect();
+    tags.sort();
+    for tag in tags {
+        part(tag);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// File name of the manifest for `source`: a readable stem plus a
+/// short hash, so distinct sources never share a file
+fn manifest_file_name(source: &str) -> String {
+    let stem: String = source
+        .chars()
+        .rev()
+        .take(48)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '_' | '-')
+            {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let digest = Sha256::digest(source.as_bytes());
+    format!(
+        "{}-{:02x}{:02x}{:02x}{:02x}.json",
+        stem, digest[0], digest[1], digest[2], digest[3]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use crate::anki::note::NoteBuilder;
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    fn temp_manifest(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_manifest_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("words.json")
+    }
+
+    fn note(front: &str, back: &str) -> Note {
+        NoteBuilder::new("Words", "Basic")
+            .field("Front", front)
+            .field("Back", back)
+            .build()
+    }
+
+    fn rows(backs: [&str; 2]) -> Vec<ImportItem> {
+        vec![
+            ImportItem::from_row(
+                "words.csv",
+                1,
+                note("猫", backs[0]),
+            ),
+            ImportItem::from_row(
+                "words.csv",
+                2,
+                note("犬", backs[1]),
+            ),
+        ]
+    }
+
+    /// Mock Anki keeping the IDs of the notes it holds
+    async fn anki(notes: Arc<Mutex<Vec<u64>>>) -> MockAnki {
+        MockAnki::start(move |action, params| {
+            let mut notes = notes.lock().unwrap();
+            match action {
+                "addNote" => {
+                    let id = notes
+                        .iter()
+                        .max()
+                        .map_or(100, |id| id + 1);
+                    notes.push(id);
+                    ok(id.into())
+                }
+                "updateNoteFields" => ok(Value::Null),
+                "findNotes" => {
+                    let query =
+                        params["query"].as_str().unwrap();
+                    let wanted: Vec<u64> = query
+                        .trim_start_matches("nid:")
+                        .split(',')
+                        .map(|id| id.parse().unwrap())
+                        .collect();
+                    ok(json!(
+                            notes
+                                .iter()
+                                .filter(|id| wanted
+                                    .contains(id))
+                                .collect::<Vec<_>>()
+                        ))
+                }
+                other => {
+                    panic!("unexpected action {}", other)
+                }
+            }
+        })
+        .await
+    }
+
+    fn writes(mock: &MockAnki) -> Vec<String> {
+        mock.actions()
+            .into_iter()
+            .filter(|a| a != "findNotes")
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rerun_is_idempotent_and_updates_changed_rows()
+     {
+        let path = temp_manifest("rerun");
+        let mock = anki(Arc::default()).await;
+        let client = mock.client();
+
+        let mut manifest =
+            ImportManifest::load(&path).unwrap();
+        let report = import_items(
+            &client,
+            &mut manifest,
+            rows(["cat", "dog"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.created, 2);
+        assert_eq!(writes(&mock), ["addNote", "addNote"]);
+
+        let mut manifest =
+            ImportManifest::load(&path).unwrap();
+        assert_eq!(
+            manifest.get("words.csv#2").unwrap().note_id,
+            101
+        );
+        let report = import_items(
+            &client,
+            &mut manifest,
+            rows(["cat", "dog"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.skipped, 2);
+        assert_eq!(writes(&mock).len(), 2);
+
+        let report = import_items(
+            &client,
+            &mut manifest,
+            rows(["cat", "a dog"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (report.skipped, report.updated),
+            (1, 1)
+        );
+        let update =
+            mock.requests().last().unwrap().clone();
+        assert_eq!(update["action"], "updateNoteFields");
+        assert_eq!(update["params"]["note"]["id"], 101);
+        assert_eq!(
+            update["params"]["note"]["fields"]["Back"],
+            "a dog"
+        );
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_is_repaired_after_note_deletion()
+    {
+        let path = temp_manifest("repair");
+        let notes = Arc::new(Mutex::new(Vec::new()));
+        let mock = anki(notes.clone()).await;
+        let client = mock.client();
+
+        let mut manifest =
+            ImportManifest::load(&path).unwrap();
+        import_items(
+            &client,
+            &mut manifest,
+            rows(["cat", "dog"]),
+        )
+        .await
+        .unwrap();
+        notes.lock().unwrap().retain(|id| *id != 100);
+
+        let report = import_items(
+            &client,
+            &mut manifest,
+            rows(["cat", "dog"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (report.repaired, report.skipped),
+            (1, 1)
+        );
+        assert_eq!(
+            ImportManifest::load(&path)
+                .unwrap()
+                .get("words.csv#1")
+                .unwrap()
+                .note_id,
+            102
+        );
+    }
+
+    #[test]
+    fn test_content_hash_ignores_order() {
+        let mut tagged = note("猫", "cat");
+        tagged.tags =
+            vec!["b".to_string(), "a".to_string()];
+        let mut reordered = tagged.clone();
+        reordered.tags.reverse();
+        assert_eq!(
+            content_hash(&tagged),
+            content_hash(&reordered)
+        );
+        assert_ne!(
+            content_hash(&tagged),
+            content_hash(&note("猫", "cat"))
+        );
+        assert_ne!(
+            manifest_file_name("a/words.csv"),
+            manifest_file_name("b/words.csv")
+        );
+    }
+
+    #[test]
+    fn test_item_metadata() {
+        let item = ImportItem::from_row(
+            "words.csv",
+            3,
+            note("猫", "cat"),
+        );
+        let metadata = item.metadata();
+        assert_eq!(
+            metadata.source_id(),
+            Some("words.csv#3")
+        );
+        assert_eq!(
+            metadata.content_hash(),
+            Some(content_hash(&item.note).as_str())
+        );
+    }
+}
@@ -1,4 +1,15 @@
-mod anki;
+pub mod anki;
+#[cfg(any(test, feature = "apkg"))]
+pub mod apkg;
+pub mod cloze;
+pub mod convert;
+pub mod export;
+pub mod import;
+pub mod maintenance;
+pub mod metadata;
+pub mod reading;
+pub mod report;
+pub mod simulate;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
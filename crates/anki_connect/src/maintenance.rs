@@ -0,0 +1,6 @@
+//! Collection maintenance workflows built on top of [`crate::anki`]
+pub mod archive;
+pub mod deck_template;
+pub mod media_dedupe;
+pub mod merge;
+pub mod restore;
@@ -0,0 +1,355 @@
+//! Archiving notes to JSON Lines before removing them.
+//!
+//! Each archived note is one line:
+//!
+//! ```json
+//! {"note_id":1700000000000,"created":"2023-11-14T22:13:20Z","model":"Basic","deck":"Inbox","fields":{"Front":"...","Back":"..."},"tags":["later"]}
+//! ```
+//!
+//! `fields` keeps the model's field order, and `created` is derived
+//! from the note ID, which Anki assigns from the creation time in
+//! milliseconds.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde::ser::Serializer;
+
+use crate::anki::client::{AnkiClient, NoteInfo};
+use crate::convert::media_references;
+
+/// Options for [`archive_notes`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    /// Delete the notes once the archive has been written
+    pub delete_after: bool,
+    /// List the media files the archived notes refer to
+    pub include_media_list: bool,
+}
+
+/// Outcome of [`archive_notes`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    /// Notes written to the archive
+    pub archived: usize,
+    /// Notes deleted afterwards
+    pub deleted: usize,
+    /// Media files referenced by the archived notes, sorted; only
+    /// filled with [`ArchiveOptions::include_media_list`]
+    pub media: Vec<String>,
+}
+
+/// One JSONL line of the archive
+#[derive(Serialize)]
+struct ArchivedNote<'a> {
+    note_id: u64,
+    created: Option<DateTime<Utc>>,
+    model: &'a str,
+    deck: Option<&'a str>,
+    #[serde(serialize_with = "ordered_map")]
+    fields: Vec<(&'a str, &'a str)>,
+    tags: &'a [String],
+}
+
+/// Serializes pairs as a JSON object, keeping their order
+fn ordered_map<S: Serializer>(
+    pairs: &[(&str, &str)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(pairs.iter().copied())
+}
+
+/// Writes the notes matching `query` to `writer` as JSON Lines and
+/// optionally deletes them.
+///
+/// The whole archive is written and flushed before anything is
+/// deleted; if writing or flushing fails, the error is returned and
+/// no note is deleted. With auto-backup enabled on the client, the
+/// affected decks are also backed up before the deletion.
+pub async fn archive_notes<W: Write>(
+    anki_client: &AnkiClient,
+    query: &str,
+    mut writer: W,
+    options: ArchiveOptions,
+) -> Result<ArchiveReport> {
+    let note_ids = anki_client.find_notes(query).await?;
+    if note_ids.is_empty() {
+        return Ok(ArchiveReport::default());
+    }
+    let notes =
+        anki_client.notes_info_chunked(&note_ids).await?;
+    let card_ids: Vec<u64> = notes
+        .iter()
+        .flat_map(|note| note.cards.iter().copied())
+        .collect();
+    let mut decks: HashMap<u64, (u32, String)> =
+        HashMap::new();
+    for card in
+        anki_client.cards_info_chunked(&card_ids).await?
+    {
+        let entry = decks
+            .entry(card.note_id)
+            .or_insert((card.ord, card.deck_name.clone()));
+        if card.ord < entry.0 {
+            *entry = (card.ord, card.deck_name);
+        }
+    }
+
+    let mut archive = String::new();
+    for note in &notes {
+        archive.push_str(&serde_json::to_string(
+            &ArchivedNote {
+                note_id: note.note_id,
+                created: created_at(note.note_id),
+                model: &note.model_name,
+                deck: decks
+                    .get(&note.note_id)
+                    .map(|(_, deck)| deck.as_str()),
+                fields: note.ordered_fields(),
+                tags: &note.tags,
+            },
+        )?);
+        archive.push('\n');
+    }
+    writer
+        .write_all(archive.as_bytes())
+        .context("Failed to write note archive; no notes were deleted")?;
+    writer
+        .flush()
+        .context("Failed to flush note archive; no notes were deleted")?;
+
+    let mut report = ArchiveReport {
+        archived: notes.len(),
+        ..Default::default()
+    };
+    if options.include_media_list {
+        report.media = referenced_media(&notes);
+    }
+    if options.delete_after {
+        let archived: Vec<u64> =
+            notes.iter().map(|note| note.note_id).collect();
+        anki_client.delete_notes(archived).await?;
+        report.deleted = notes.len();
+    }
+    Ok(report)
+}
+
+/// Creation time encoded in a note ID, if it is a plausible one
+fn created_at(note_id: u64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(
+        i64::try_from(note_id).ok()?,
+    )
+}
+
+/// Sorted, de-duplicated media file names used by `notes`
+fn referenced_media(notes: &[NoteInfo]) -> Vec<String> {
+    let mut media: Vec<String> = notes
+        .iter()
+        .flat_map(|note| note.fields.values())
+        .flat_map(|field| media_references(&field.value))
+        .collect();
+    media.sort();
+    media.dedup();
+    media
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use serde_json::{Value, json};
+
+    /// Mock collection holding notes 1700000000000 and
+    /// 1700000000001 in the "Inbox" deck
+    async fn inbox_mock() -> MockAnki {
+        MockAnki::start(|action, params| match action {
+            "findNotes" => {
+                ok(json!([1700000000000u64, 1700000000001u64]))
+            }
+            "notesInfo" => ok(Value::Array(
+                params["notes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| {
+                        let id = id.as_u64().unwrap();
+                        json!({
+                            "noteId": id,
+                            "modelName": "Basic",
+                            "tags": ["later"],
+                            "fields": {
+                                "Back": {"value": format!("<img src=\"b{}.png\">", id % 2), "order": 1},
+                                "Front": {"value": "[sound:a.mp3] hi", "order": 0},
+                            },
+                            "cards": [id % 10],
+                        })
+                    })
+                    .collect(),
+            )),
+            "cardsInfo" => ok(Value::Array(
+                params["cards"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| {
+                        let id = id.as_u64().unwrap();
+                        json!({
+                            "cardId": id,
+                            "note": 1700000000000u64 + id,
+                            "deckName": "Inbox",
+                            "modelName": "Basic",
+                            "ord": 0,
+                            "type": 0,
+                            "queue": 0,
+                            "due": 0,
+                            "interval": 0,
+                            "factor": 0,
+                            "reps": 0,
+                            "lapses": 0,
+                        })
+                    })
+                    .collect(),
+            )),
+            "deleteNotes" => ok(json!(null)),
+            other => panic!("unexpected action {}", other),
+        })
+        .await
+    }
+
+    /// Writer failing after `limit` bytes, or on flush
+    struct FailingWriter {
+        written: Vec<u8>,
+        limit: usize,
+        fail_flush: bool,
+    }
+
+    impl Write for FailingWriter {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> std::io::Result<usize> {
+            if self.written.len() >= self.limit {
+                return Err(std::io::Error::other(
+                    "disk full",
+                ));
+            }
+            let n = buf
+                .len()
+                .min(self.limit - self.written.len());
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if self.fail_flush {
+                Err(std::io::Error::other("flush failed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_then_delete() {
+        let mock = inbox_mock().await;
+        let mut out = Vec::new();
+        let report = archive_notes(
+            &mock.client(),
+            "deck:Inbox",
+            &mut out,
+            ArchiveOptions {
+                delete_after: true,
+                include_media_list: true,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report,
+            ArchiveReport {
+                archived: 2,
+                deleted: 2,
+                media: vec![
+                    "a.mp3".to_string(),
+                    "b0.png".to_string(),
+                    "b1.png".to_string()
+                ],
+            }
+        );
+        let text = String::from_utf8(out).unwrap();
+        let first = text.lines().next().unwrap();
+        assert_eq!(
+            first,
+            r#"{"note_id":1700000000000,"created":"2023-11-14T22:13:20Z","model":"Basic","deck":"Inbox","fields":{"Front":"[sound:a.mp3] hi","Back":"<img src=\"b0.png\">"},"tags":["later"]}"#
+        );
+        assert_eq!(text.lines().count(), 2);
+        assert_eq!(
+            mock.actions().last().unwrap(),
+            "deleteNotes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_export_deletes_nothing() {
+        for (limit, fail_flush) in
+            [(40, false), (usize::MAX, true)]
+        {
+            let mock = inbox_mock().await;
+            let writer = FailingWriter {
+                written: Vec::new(),
+                limit,
+                fail_flush,
+            };
+            let error = archive_notes(
+                &mock.client(),
+                "deck:Inbox",
+                writer,
+                ArchiveOptions {
+                    delete_after: true,
+                    include_media_list: false,
+                },
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("no notes were deleted"),
+                "{}",
+                error
+            );
+            assert!(
+                !mock
+                    .actions()
+                    .iter()
+                    .any(|a| a == "deleteNotes")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_without_delete_keeps_notes() {
+        let mock = inbox_mock().await;
+        let report = archive_notes(
+            &mock.client(),
+            "deck:Inbox",
+            Vec::new(),
+            ArchiveOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.archived, 2);
+        assert_eq!(report.deleted, 0);
+        assert!(report.media.is_empty());
+        assert!(
+            !mock
+                .actions()
+                .iter()
+                .any(|a| a == "deleteNotes")
+        );
+    }
+}
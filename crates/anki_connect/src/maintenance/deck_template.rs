@@ -0,0 +1,678 @@
+//! Applying one options group to a whole deck subtree.
+//!
+//! The template is diffed against the collection before anything is
+//! written, so applying an unchanged template a second time makes no
+//! write calls at all.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use serde_json::{Value, json};
+
+use crate::anki::client::{
+    AnkiClient, DeckConfig, split_deck_root,
+};
+
+/// ID of Anki's built-in "Default" options group, which new decks get
+const DEFAULT_CONFIG_ID: u64 = 1;
+
+/// Option values to enforce on the options group; `None` leaves the
+/// current value alone
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeckConfigOverrides {
+    /// New cards per day (`new.perDay`)
+    pub new_per_day: Option<u32>,
+    /// Maximum reviews per day (`rev.perDay`)
+    pub reviews_per_day: Option<u32>,
+    /// Learning steps in minutes (`new.delays`)
+    pub learning_steps: Option<Vec<f64>>,
+    /// Relearning steps in minutes (`lapse.delays`)
+    pub relearning_steps: Option<Vec<f64>>,
+    /// Maximum interval in days (`rev.maxIvl`)
+    pub maximum_interval: Option<u32>,
+    /// Starting ease, e.g. `2.5` (`new.initialFactor`, stored x1000)
+    pub starting_ease: Option<f64>,
+}
+
+impl DeckConfigOverrides {
+    /// `(section, key, value)` triples for every set override
+    fn entries(
+        &self,
+    ) -> Vec<(&'static str, &'static str, Value)> {
+        let mut entries = Vec::new();
+        if let Some(v) = self.new_per_day {
+            entries.push(("new", "perDay", json!(v)));
+        }
+        if let Some(v) = self.reviews_per_day {
+            entries.push(("rev", "perDay", json!(v)));
+        }
+        if let Some(v) = &self.learning_steps {
+            entries.push(("new", "delays", json!(v)));
+        }
+        if let Some(v) = &self.relearning_steps {
+            entries.push(("lapse", "delays", json!(v)));
+        }
+        if let Some(v) = self.maximum_interval {
+            entries.push(("rev", "maxIvl", json!(v)));
+        }
+        if let Some(v) = self.starting_ease {
+            let factor = (v * 1000.0).round() as u64;
+            entries.push((
+                "new",
+                "initialFactor",
+                json!(factor),
+            ));
+        }
+        entries
+    }
+
+    /// Lists the options (as `section.key`) that differ from `config`
+    pub fn diff(&self, config: &DeckConfig) -> Vec<String> {
+        self.entries()
+            .into_iter()
+            .filter(|(section, key, value)| {
+                let current = config
+                    .options
+                    .get(*section)
+                    .and_then(|s| s.get(*key));
+                !current
+                    .is_some_and(|c| same_value(c, value))
+            })
+            .map(|(section, key, _)| {
+                format!("{}.{}", section, key)
+            })
+            .collect()
+    }
+
+    /// Writes the overrides into `config`, returning what changed
+    pub fn apply(
+        &self,
+        config: &mut DeckConfig,
+    ) -> Vec<String> {
+        let changed = self.diff(config);
+        for (section, key, value) in self.entries() {
+            let section = config
+                .options
+                .entry(section)
+                .or_insert_with(|| json!({}));
+            if let Some(section) = section.as_object_mut() {
+                section.insert(key.to_string(), value);
+            }
+        }
+        changed
+    }
+}
+
+/// Numeric-aware equality, so `10` and `10.0` count as unchanged
+fn same_value(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => {
+            a.as_f64() == b.as_f64()
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(x, y)| same_value(x, y))
+        }
+        _ => left == right,
+    }
+}
+
+/// Desired setup of a deck subtree
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeckTemplate {
+    /// Options group every deck in the subtree should use; it is
+    /// cloned from the root deck's current group when missing
+    pub options_group_name: String,
+    /// Option values to enforce on that group
+    pub overrides: DeckConfigOverrides,
+    /// Subdecks to create, relative to the root (e.g. `"Grammar::Verbs"`)
+    pub create_missing: Vec<String>,
+}
+
+/// How the options group is obtained
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupAction {
+    /// A group with the requested name already exists
+    Existing(u64),
+    /// The group is cloned from this one (`None` = Anki's default)
+    Clone { from: Option<u64> },
+}
+
+/// Changes needed (or made) to bring a subtree in line with a template
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeckTemplateReport {
+    /// Whether the changes were only planned
+    pub dry_run: bool,
+    /// Decks that are (or would be) created
+    pub created_decks: Vec<String>,
+    /// Where the options group comes from
+    pub group: GroupAction,
+    /// Options (`section.key`) that are (or would be) changed
+    pub changed_options: Vec<String>,
+    /// Decks that are (or would be) switched to the options group
+    pub assigned_decks: Vec<String>,
+}
+
+impl DeckTemplateReport {
+    /// Whether applying the template needs no write at all
+    pub fn is_noop(&self) -> bool {
+        self.created_decks.is_empty()
+            && matches!(
+                self.group,
+                GroupAction::Existing(_)
+            )
+            && self.changed_options.is_empty()
+            && self.assigned_decks.is_empty()
+    }
+}
+
+/// Whether two deck names are the same deck; Anki ignores case
+fn same_deck(left: &str, right: &str) -> bool {
+    left.to_lowercase() == right.to_lowercase()
+}
+
+/// Returns `root` and all of its descendants from a flat deck list.
+///
+/// Names are matched case-insensitively, as in
+/// [`AnkiClient::rename_deck`], and keep their stored spelling.
+pub fn deck_subtree(
+    deck_names: &[String],
+    root: &str,
+) -> Vec<String> {
+    let depth = root.split("::").count();
+    let subtree: BTreeSet<&String> = deck_names
+        .iter()
+        .filter(|name| {
+            split_deck_root(name, depth).is_some_and(
+                |(top, _)| same_deck(top, root),
+            )
+        })
+        .collect();
+    subtree.into_iter().cloned().collect()
+}
+
+/// Full names of the requested subdecks and their ancestors below root
+fn wanted_decks(
+    root: &str,
+    template: &DeckTemplate,
+) -> BTreeSet<String> {
+    let mut wanted = BTreeSet::from([root.to_string()]);
+    for relative in &template.create_missing {
+        let mut name = root.to_string();
+        for part in
+            relative.split("::").filter(|p| !p.is_empty())
+        {
+            name = format!("{}::{}", name, part);
+            wanted.insert(name.clone());
+        }
+    }
+    wanted
+}
+
+/// Plans the template without writing anything (the dry run)
+pub async fn plan_deck_template(
+    anki_client: &AnkiClient,
+    root_deck: &str,
+    template: &DeckTemplate,
+) -> Result<DeckTemplateReport> {
+    Ok(inspect(anki_client, root_deck, template).await?.0)
+}
+
+/// Ensures the subtree below `root_deck` matches `template`.
+///
+/// Missing decks are created, the options group is found or cloned,
+/// the overrides are saved with `saveDeckConfig`, and every deck in
+/// the subtree is assigned the group. Only differences are written.
+pub async fn apply_deck_template(
+    anki_client: &AnkiClient,
+    root_deck: &str,
+    template: &DeckTemplate,
+) -> Result<DeckTemplateReport> {
+    let (mut report, group_config) =
+        inspect(anki_client, root_deck, template).await?;
+    report.dry_run = false;
+
+    for deck in &report.created_decks {
+        anki_client.create_deck(deck).await?;
+    }
+
+    let group_id = match report.group {
+        GroupAction::Existing(id) => id,
+        GroupAction::Clone { from } => {
+            anki_client
+                .clone_deck_config_id(
+                    &template.options_group_name,
+                    from,
+                )
+                .await?
+        }
+    };
+
+    if !report.assigned_decks.is_empty() {
+        anki_client
+            .set_deck_config_id(
+                report.assigned_decks.clone(),
+                group_id,
+            )
+            .await?;
+    }
+
+    if !report.changed_options.is_empty() {
+        let mut config = match group_config {
+            Some(config) => config,
+            // A fresh clone is only reachable through a deck using it
+            None => {
+                anki_client
+                    .get_deck_config(root_deck)
+                    .await?
+            }
+        };
+        template.overrides.apply(&mut config);
+        anki_client.save_deck_config(config).await?;
+    }
+    Ok(report)
+}
+
+/// Reads the collection and computes the dry-run report, returning
+/// the existing options group when there is one
+async fn inspect(
+    anki_client: &AnkiClient,
+    root_deck: &str,
+    template: &DeckTemplate,
+) -> Result<(DeckTemplateReport, Option<DeckConfig>)> {
+    let all_decks =
+        anki_client.get_deck_names(None).await?;
+    let existing = deck_subtree(&all_decks, root_deck);
+    let created_decks: Vec<String> =
+        wanted_decks(root_deck, template)
+            .into_iter()
+            .filter(|deck| {
+                !existing.iter().any(|d| same_deck(d, deck))
+            })
+            .collect();
+
+    // Current group of each existing subtree deck
+    let mut current = Vec::with_capacity(existing.len());
+    for deck in &existing {
+        current.push((
+            deck,
+            anki_client.get_deck_config(deck).await?,
+        ));
+    }
+
+    let mut group_config = current
+        .iter()
+        .map(|(_, config)| config)
+        .find(|config| {
+            config.name == template.options_group_name
+        })
+        .cloned();
+    if group_config.is_none() {
+        for deck in all_decks
+            .iter()
+            .filter(|d| !existing.contains(d))
+        {
+            let config =
+                anki_client.get_deck_config(deck).await?;
+            if config.name == template.options_group_name {
+                group_config = Some(config);
+                break;
+            }
+        }
+    }
+
+    let root_config = current
+        .iter()
+        .find(|(deck, _)| same_deck(deck, root_deck))
+        .map(|(_, config)| config);
+    let (group, changed_options) = match &group_config {
+        Some(config) => (
+            GroupAction::Existing(config.id),
+            template.overrides.diff(config),
+        ),
+        None => (
+            GroupAction::Clone {
+                from: root_config.map(|config| config.id),
+            },
+            match root_config {
+                Some(source) => {
+                    template.overrides.diff(source)
+                }
+                None => template
+                    .overrides
+                    .entries()
+                    .iter()
+                    .map(|(s, k, _)| format!("{}.{}", s, k))
+                    .collect(),
+            },
+        ),
+    };
+
+    let group_id =
+        group_config.as_ref().map(|config| config.id);
+    let mut assigned_decks: Vec<String> = current
+        .iter()
+        .filter(|(_, config)| Some(config.id) != group_id)
+        .map(|(deck, _)| deck.to_string())
+        .collect();
+    if group_id != Some(DEFAULT_CONFIG_ID) {
+        assigned_decks
+            .extend(created_decks.iter().cloned());
+    }
+    assigned_decks.sort();
+
+    let report = DeckTemplateReport {
+        dry_run: true,
+        created_decks,
+        group,
+        changed_options,
+        assigned_decks,
+    };
+    Ok((report, group_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+
+    fn config(
+        id: u64,
+        name: &str,
+        new_per_day: u32,
+    ) -> Value {
+        json!({
+            "id": id,
+            "name": name,
+            "new": { "perDay": new_per_day, "delays": [1.0, 10.0], "initialFactor": 2500 },
+            "rev": { "perDay": 200, "maxIvl": 36500 },
+            "lapse": { "delays": [10.0] },
+            "maxTaken": 60,
+        })
+    }
+
+    fn template() -> DeckTemplate {
+        DeckTemplate {
+            options_group_name: "Japanese".to_string(),
+            overrides: DeckConfigOverrides {
+                new_per_day: Some(15),
+                learning_steps: Some(vec![1.0, 10.0]),
+                ..Default::default()
+            },
+            create_missing: vec![
+                "Grammar::Verbs".to_string(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_deck_subtree() {
+        let decks: Vec<String> = [
+            "Default",
+            "Japanese",
+            "Japanese::Vocab",
+            "Japanese::Vocab::N5",
+            "Japanese Extra",
+            "Korean",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            deck_subtree(&decks, "Japanese"),
+            vec![
+                "Japanese",
+                "Japanese::Vocab",
+                "Japanese::Vocab::N5"
+            ]
+        );
+        assert_eq!(
+            deck_subtree(&decks, "japanese::VOCAB"),
+            vec!["Japanese::Vocab", "Japanese::Vocab::N5"]
+        );
+        assert!(deck_subtree(&decks, "Missing").is_empty());
+    }
+
+    #[test]
+    fn test_wanted_decks_include_intermediate_parents() {
+        let wanted = wanted_decks("Japanese", &template());
+        assert_eq!(
+            wanted.into_iter().collect::<Vec<_>>(),
+            vec![
+                "Japanese",
+                "Japanese::Grammar",
+                "Japanese::Grammar::Verbs"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overrides_diff_and_apply() {
+        let mut config: DeckConfig =
+            serde_json::from_value(config(
+                5, "Japanese", 20,
+            ))
+            .unwrap();
+        let overrides = DeckConfigOverrides {
+            new_per_day: Some(15),
+            learning_steps: Some(vec![1.0, 10.0]),
+            starting_ease: Some(2.5),
+            maximum_interval: Some(365),
+            ..Default::default()
+        };
+        assert_eq!(
+            overrides.diff(&config),
+            vec!["new.perDay", "rev.maxIvl"]
+        );
+        assert_eq!(
+            overrides.apply(&mut config),
+            vec!["new.perDay", "rev.maxIvl"]
+        );
+        assert!(overrides.diff(&config).is_empty());
+        assert_eq!(config.options["new"]["perDay"], 15);
+        // Untouched options survive the round trip
+        assert_eq!(config.options["maxTaken"], 60);
+    }
+
+    #[test]
+    fn test_integer_and_float_values_compare_equal() {
+        assert!(same_value(
+            &json!([1, 10]),
+            &json!([1.0, 10.0])
+        ));
+        assert!(!same_value(
+            &json!([1, 10]),
+            &json!([1.0])
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rerun_without_changes_makes_no_writes() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "deckNames" => ok(json!([
+                    "Default",
+                    "Japanese",
+                    "Japanese::Grammar",
+                    "Japanese::Grammar::Verbs"
+                ])),
+                "getDeckConfig" => {
+                    ok(config(7, "Japanese", 15))
+                }
+                _ => panic!("unexpected write: {}", action),
+            })
+            .await;
+
+        let report = apply_deck_template(
+            &mock.client(),
+            "Japanese",
+            &template(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_noop());
+        assert!(
+            mock.actions().iter().all(|a| a == "deckNames"
+                || a == "getDeckConfig")
+        );
+        // The root may be typed in any case
+        let report = plan_deck_template(
+            &mock.client(),
+            "japanese",
+            &template(),
+        )
+        .await
+        .unwrap();
+        assert!(report.is_noop());
+    }
+
+    #[tokio::test]
+    async fn test_apply_creates_assigns_and_saves() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "deckNames" => ok(json!([
+                        "Default",
+                        "Japanese",
+                        "Japanese::Vocab"
+                    ])),
+                    "getDeckConfig" => match params["deck"]
+                        .as_str()
+                        .unwrap()
+                    {
+                        "Japanese" => {
+                            ok(config(7, "Japanese", 20))
+                        }
+                        "Japanese::Vocab" => {
+                            ok(config(1, "Default", 20))
+                        }
+                        _ => ok(config(1, "Default", 20)),
+                    },
+                    "createDeck" => ok(json!(100)),
+                    "setDeckConfigId"
+                    | "saveDeckConfig" => ok(json!(true)),
+                    _ => ok(Value::Null),
+                },
+            )
+            .await;
+
+        let plan = plan_deck_template(
+            &mock.client(),
+            "Japanese",
+            &template(),
+        )
+        .await
+        .unwrap();
+        assert!(plan.dry_run);
+        assert_eq!(plan.group, GroupAction::Existing(7));
+        assert_eq!(
+            plan.changed_options,
+            vec!["new.perDay"]
+        );
+        assert_eq!(
+            plan.assigned_decks,
+            vec![
+                "Japanese::Grammar",
+                "Japanese::Grammar::Verbs",
+                "Japanese::Vocab"
+            ]
+        );
+        let reads = mock.actions().len();
+
+        let report = apply_deck_template(
+            &mock.client(),
+            "Japanese",
+            &template(),
+        )
+        .await
+        .unwrap();
+        assert!(!report.dry_run);
+        let writes: Vec<String> = mock.actions()[reads..]
+            .iter()
+            .filter(|a| {
+                *a != "deckNames" && *a != "getDeckConfig"
+            })
+            .cloned()
+            .collect();
+        assert_eq!(
+            writes,
+            vec![
+                "createDeck",
+                "createDeck",
+                "setDeckConfigId",
+                "saveDeckConfig"
+            ]
+        );
+        let requests = mock.requests();
+        let save = requests
+            .iter()
+            .find(|r| r["action"] == "saveDeckConfig")
+            .unwrap();
+        assert_eq!(save["params"]["config"]["id"], 7);
+        assert_eq!(
+            save["params"]["config"]["new"]["perDay"],
+            15
+        );
+        assert_eq!(
+            save["params"]["config"]["maxTaken"],
+            60
+        );
+        let assign = requests
+            .iter()
+            .find(|r| r["action"] == "setDeckConfigId")
+            .unwrap();
+        assert_eq!(assign["params"]["configId"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_missing_group_is_cloned_from_root() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "deckNames" => {
+                    ok(json!(["Default", "Japanese"]))
+                }
+                "getDeckConfig" => {
+                    ok(config(1, "Default", 20))
+                }
+                "cloneDeckConfigId" => ok(json!(42)),
+                "setDeckConfigId" | "saveDeckConfig" => {
+                    ok(json!(true))
+                }
+                _ => ok(Value::Null),
+            })
+            .await;
+
+        let template = DeckTemplate {
+            create_missing: vec![],
+            ..template()
+        };
+        let report = apply_deck_template(
+            &mock.client(),
+            "Japanese",
+            &template,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            report.group,
+            GroupAction::Clone { from: Some(1) }
+        );
+        assert_eq!(report.assigned_decks, vec!["Japanese"]);
+        let requests = mock.requests();
+        let clone = requests
+            .iter()
+            .find(|r| r["action"] == "cloneDeckConfigId")
+            .unwrap();
+        assert_eq!(
+            clone["params"],
+            json!({ "name": "Japanese", "cloneFrom": 1 })
+        );
+        let assign = requests
+            .iter()
+            .find(|r| r["action"] == "setDeckConfigId")
+            .unwrap();
+        assert_eq!(assign["params"]["configId"], 42);
+    }
+}
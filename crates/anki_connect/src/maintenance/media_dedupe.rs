@@ -0,0 +1,540 @@
+//! Removing byte-identical copies from the media folder.
+//!
+//! Files are hashed one at a time, so memory use stays at about one
+//! file whatever the size of the collection. Every group of identical
+//! files keeps one canonical name; notes referring to the other names
+//! are rewritten to it before the copies are deleted.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::anki::client::{AnkiClient, escape_search};
+use crate::anki::note::NoteFields;
+use crate::anki::transport::ResponseTooLarge;
+use crate::convert::rename_media_references;
+
+/// Files larger than this are not hashed by default (64 MiB)
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// File names combined into one `findNotes` query
+const NAME_CHUNK: usize = 50;
+
+/// Options for [`dedupe_media`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedupeMediaOptions {
+    /// Glob pattern selecting the files to compare, e.g. `*.mp3`
+    pub pattern: String,
+    /// Only report what would change
+    pub dry_run: bool,
+    /// Files larger than this many bytes are skipped
+    pub max_file_size: u64,
+}
+
+impl Default for DedupeMediaOptions {
+    fn default() -> Self {
+        Self {
+            pattern: "*".to_string(),
+            dry_run: false,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        }
+    }
+}
+
+/// Progress of the hashing phase, passed to the callback after each
+/// file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaProgress<'a> {
+    /// Files processed so far, including this one
+    pub done: usize,
+    /// Files matching the pattern
+    pub total: usize,
+    /// File just processed
+    pub file: &'a str,
+    /// Bytes downloaded so far; files found too large part way are
+    /// not counted
+    pub bytes: u64,
+}
+
+/// Files with identical content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaGroup {
+    /// SHA-256 of the content, lowercase hex
+    pub hash: String,
+    /// Size of each file in bytes
+    pub size: u64,
+    /// Name every reference is pointed at
+    pub canonical: String,
+    /// Names to be replaced and deleted, sorted
+    pub duplicates: Vec<String>,
+}
+
+/// Fields of one note rewritten to canonical names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteRewrite {
+    pub note_id: u64,
+    /// New values of the changed fields only
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Outcome of [`dedupe_media`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupeMediaReport {
+    /// Files compared
+    pub scanned: usize,
+    /// Files skipped for exceeding the size limit
+    pub too_large: Vec<String>,
+    /// Groups of identical files, ordered by canonical name
+    pub groups: Vec<MediaGroup>,
+    /// Notes to be rewritten
+    pub rewrites: Vec<NoteRewrite>,
+    /// IDs of the notes in `rewrites` already rewritten; empty in a
+    /// dry run
+    pub rewritten: Vec<u64>,
+    /// Files deleted; empty in a dry run
+    pub deleted: Vec<String>,
+    /// The write that failed and why. Everything recorded in
+    /// `rewritten` and `deleted` is done; running again picks up
+    /// the rest.
+    pub failure: Option<String>,
+}
+
+impl DedupeMediaReport {
+    /// Whether every write succeeded
+    pub fn is_complete(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Picks the name kept for a group of identical files.
+///
+/// The shortest name wins, as copies tend to get suffixes such as
+/// `-1` or a hash appended; ties go to the lexicographically smallest
+/// name, so the choice does not depend on listing order.
+pub fn choose_canonical(names: &[String]) -> Option<&str> {
+    names
+        .iter()
+        .min_by(|a, b| {
+            a.chars()
+                .count()
+                .cmp(&b.chars().count())
+                .then_with(|| a.cmp(b))
+        })
+        .map(String::as_str)
+}
+
+/// Finds byte-identical media files and merges each group into one.
+///
+/// Files matching `options.pattern` are downloaded and hashed one by
+/// one, calling `on_progress` after each; a download stops as soon
+/// as the file turns out to exceed `options.max_file_size`. For
+/// every group of identical files, notes referring to a duplicate
+/// through `[sound:...]` or a media tag's `src` are rewritten to the
+/// canonical name, then the duplicates are deleted. A dry run stops
+/// after working out the rewrites.
+///
+/// Failures while rewriting or deleting are not returned as errors
+/// but recorded in [`DedupeMediaReport::failure`], so the report
+/// shows how far the writes got.
+///
+/// # Returns
+/// The groups found, the note rewrites and the deleted files
+pub async fn dedupe_media(
+    anki_client: &AnkiClient,
+    options: DedupeMediaOptions,
+    mut on_progress: impl FnMut(MediaProgress<'_>),
+) -> Result<DedupeMediaReport> {
+    let mut names = anki_client
+        .get_media_files_names(&options.pattern)
+        .await?;
+    names.sort();
+    let mut report = DedupeMediaReport {
+        scanned: names.len(),
+        ..Default::default()
+    };
+
+    let mut by_hash: HashMap<String, (u64, Vec<String>)> =
+        HashMap::new();
+    let mut bytes = 0;
+    for (index, name) in names.iter().enumerate() {
+        let data = match anki_client
+            .retrieve_media_file_limited(
+                name,
+                options.max_file_size,
+            )
+            .await
+        {
+            Ok(data) => data,
+            Err(e)
+                if e.downcast_ref::<ResponseTooLarge>()
+                    .is_some() =>
+            {
+                report.too_large.push(name.clone());
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(data) = data {
+            let size = data.len() as u64;
+            bytes += size;
+            if size > options.max_file_size {
+                report.too_large.push(name.clone());
+            } else {
+                by_hash
+                    .entry(hex_digest(&data))
+                    .or_insert((size, Vec::new()))
+                    .1
+                    .push(name.clone());
+            }
+        }
+        on_progress(MediaProgress {
+            done: index + 1,
+            total: names.len(),
+            file: name,
+            bytes,
+        });
+    }
+
+    for (hash, (size, files)) in by_hash {
+        if files.len() < 2 {
+            continue;
+        }
+        let canonical = choose_canonical(&files)
+            .unwrap_or_default()
+            .to_string();
+        let mut duplicates: Vec<String> = files
+            .into_iter()
+            .filter(|file| *file != canonical)
+            .collect();
+        duplicates.sort();
+        report.groups.push(MediaGroup {
+            hash,
+            size,
+            canonical,
+            duplicates,
+        });
+    }
+    report
+        .groups
+        .sort_by(|a, b| a.canonical.cmp(&b.canonical));
+
+    let renames: HashMap<String, String> = report
+        .groups
+        .iter()
+        .flat_map(|group| {
+            group.duplicates.iter().map(|duplicate| {
+                (duplicate.clone(), group.canonical.clone())
+            })
+        })
+        .collect();
+    report.rewrites =
+        plan_rewrites(anki_client, &renames).await?;
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    if let Err(e) = apply(anki_client, &mut report).await {
+        report.failure = Some(format!("{:#}", e));
+    }
+    Ok(report)
+}
+
+/// Writes the planned rewrites, then deletes the duplicates,
+/// recording each step in `report` as it is done
+async fn apply(
+    anki_client: &AnkiClient,
+    report: &mut DedupeMediaReport,
+) -> Result<()> {
+    for rewrite in &report.rewrites {
+        anki_client
+            .update_note_fields(
+                rewrite.note_id,
+                rewrite
+                    .fields
+                    .clone()
+                    .into_iter()
+                    .collect::<NoteFields>(),
+                None,
+            )
+            .await?;
+        report.rewritten.push(rewrite.note_id);
+    }
+    for group in &report.groups {
+        for duplicate in &group.duplicates {
+            anki_client
+                .delete_media_file(duplicate)
+                .await?;
+            report.deleted.push(duplicate.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Field changes pointing every note that mentions a renamed file
+/// at its new name
+async fn plan_rewrites(
+    anki_client: &AnkiClient,
+    renames: &HashMap<String, String>,
+) -> Result<Vec<NoteRewrite>> {
+    let mut old_names: Vec<&String> =
+        renames.keys().collect();
+    old_names.sort();
+    let mut note_ids = Vec::new();
+    for chunk in old_names.chunks(NAME_CHUNK) {
+        let query = chunk
+            .iter()
+            .map(|name| {
+                format!("\"{}\"", escape_search(name))
+            })
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        note_ids.extend(
+            anki_client
+                .find_notes(&format!("({})", query))
+                .await?,
+        );
+    }
+    note_ids.sort_unstable();
+    note_ids.dedup();
+
+    let mut rewrites = Vec::new();
+    for note in
+        anki_client.notes_info_chunked(&note_ids).await?
+    {
+        let fields: BTreeMap<String, String> = note
+            .fields
+            .iter()
+            .filter_map(|(name, field)| {
+                let renamed = rename_media_references(
+                    &field.value,
+                    renames,
+                );
+                (renamed != field.value)
+                    .then(|| (name.clone(), renamed))
+            })
+            .collect();
+        if !fields.is_empty() {
+            rewrites.push(NoteRewrite {
+                note_id: note.note_id,
+                fields,
+            });
+        }
+    }
+    Ok(rewrites)
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, err, ok};
+    use serde_json::json;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_canonical_choice_is_deterministic() {
+        let files =
+            names(&["meow-1.mp3", "meow.mp3", "mEow.mp3"]);
+        let mut reversed = files.clone();
+        reversed.reverse();
+        assert_eq!(
+            choose_canonical(&files),
+            Some("mEow.mp3")
+        );
+        assert_eq!(
+            choose_canonical(&reversed),
+            Some("mEow.mp3")
+        );
+        assert_eq!(
+            choose_canonical(&names(&[
+                "猫.png", "cat1.png"
+            ])),
+            Some("猫.png")
+        );
+        assert_eq!(choose_canonical(&[]), None);
+    }
+
+    /// Media folder holding "a.mp3" and "a-1.mp3" (same bytes),
+    /// "b.png" and "big.ogg"; note 5 uses "a-1.mp3"
+    async fn media_mock() -> MockAnki {
+        MockAnki::start(|action, params| match action {
+            "getMediaFilesNames" => ok(json!([
+                "b.png", "a-1.mp3", "a.mp3", "big.ogg"
+            ])),
+            "retrieveMediaFile" => {
+                match params["filename"].as_str().unwrap() {
+                    "a.mp3" | "a-1.mp3" => ok(json!("AAEC")),
+                    "b.png" => ok(json!("AAED")),
+                    _ => ok(json!("AAECAwQFBgcICQ==")),
+                }
+            }
+            "findNotes" => ok(json!([5])),
+            "notesInfo" => ok(json!([{
+                "noteId": 5,
+                "modelName": "Basic",
+                "tags": [],
+                "fields": {
+                    "Front": {"value": "<b>cat</b> [sound:a-1.mp3]", "order": 0},
+                    "Back": {"value": "<img src=\"b.png\">", "order": 1},
+                },
+                "cards": [],
+            }])),
+            "updateNoteFields" | "deleteMediaFile" => {
+                ok(json!(null))
+            }
+            other => panic!("unexpected action {}", other),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_changes_nothing() {
+        let mock = media_mock().await;
+        let mut progress = Vec::new();
+        let report = dedupe_media(
+            &mock.client(),
+            DedupeMediaOptions {
+                dry_run: true,
+                max_file_size: 5,
+                ..Default::default()
+            },
+            |p| progress.push((p.done, p.total)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.scanned, 4);
+        assert_eq!(report.too_large, names(&["big.ogg"]));
+        assert_eq!(
+            report.groups,
+            vec![MediaGroup {
+                hash: hex_digest(&[0, 1, 2]),
+                size: 3,
+                canonical: "a.mp3".to_string(),
+                duplicates: names(&["a-1.mp3"]),
+            }]
+        );
+        assert_eq!(
+            report.rewrites,
+            vec![NoteRewrite {
+                note_id: 5,
+                fields: BTreeMap::from([(
+                    "Front".to_string(),
+                    "<b>cat</b> [sound:a.mp3]".to_string()
+                )]),
+            }]
+        );
+        assert!(report.deleted.is_empty());
+        assert_eq!(progress.last(), Some(&(4, 4)));
+        assert!(
+            mock.actions()
+                .iter()
+                .all(|a| a != "updateNoteFields"
+                    && a != "deleteMediaFile")
+        );
+        assert!(mock.requests().iter().any(|request| {
+            request["params"]["query"] == "(\"a-1.mp3\")"
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_before_deleting() {
+        let mock = media_mock().await;
+        let report = dedupe_media(
+            &mock.client(),
+            DedupeMediaOptions::default(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.rewritten, [5]);
+        assert_eq!(report.deleted, names(&["a-1.mp3"]));
+        let actions = mock.actions();
+        let n = actions.len();
+        assert_eq!(
+            actions[n - 2..],
+            ["updateNoteFields", "deleteMediaFile"]
+        );
+        let requests = mock.requests();
+        assert_eq!(
+            requests[n - 2]["params"]["note"]["fields"],
+            json!({"Front": "<b>cat</b> [sound:a.mp3]"})
+        );
+        assert_eq!(
+            requests[n - 1]["params"]["filename"],
+            "a-1.mp3"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_keeps_progress() {
+        let mock =
+            MockAnki::start(|action, params| match action {
+                "getMediaFilesNames" => ok(json!([
+                    "a.mp3", "a-1.mp3", "a-2.mp3", "huge.wav"
+                ])),
+                "retrieveMediaFile" => {
+                    match params["filename"].as_str().unwrap() {
+                        "huge.wav" => ok(json!("A".repeat(4000))),
+                        _ => ok(json!("AAEC")),
+                    }
+                }
+                "findNotes" => ok(json!([5])),
+                "notesInfo" => ok(json!([{
+                    "noteId": 5,
+                    "modelName": "Basic",
+                    "tags": [],
+                    "fields": {
+                        "Front": {"value": "[sound:a-1.mp3]", "order": 0},
+                    },
+                    "cards": [],
+                }])),
+                "deleteMediaFile"
+                    if params["filename"] == "a-2.mp3" =>
+                {
+                    err("permission denied")
+                }
+                "updateNoteFields" | "deleteMediaFile" => {
+                    ok(json!(null))
+                }
+                other => panic!("unexpected action {}", other),
+            })
+            .await;
+        let mut bytes = 0;
+        let report = dedupe_media(
+            &mock.client(),
+            DedupeMediaOptions {
+                max_file_size: 1000,
+                ..Default::default()
+            },
+            |p| bytes = p.bytes,
+        )
+        .await
+        .unwrap();
+
+        // The oversized file is given up on before it is read
+        assert_eq!(report.too_large, names(&["huge.wav"]));
+        assert_eq!(bytes, 9);
+        assert_eq!(report.rewritten, [5]);
+        assert_eq!(report.deleted, names(&["a-1.mp3"]));
+        assert!(!report.is_complete());
+        assert!(
+            report
+                .failure
+                .as_ref()
+                .unwrap()
+                .contains("permission denied")
+        );
+    }
+}
@@ -0,0 +1,1012 @@
+//! Merging duplicate notes.
+//!
+//! Anki-Connect cannot move review history between notes, so merging
+//! works the other way round: the note whose cards carry more review
+//! history survives and receives the merged content, and the other
+//! note is deleted. That choice is only made when both notes use the
+//! same model with the same number of cards; otherwise the note the
+//! caller asked to keep always survives.
+//!
+//! [`merge_duplicates`] is the bulk variant for repeated imports: it
+//! keeps the oldest note of each group of equal key fields and only
+//! merges tags.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use crate::anki::client::{
+    AnkiClient, CardInfo, NoteInfo, normalize_name,
+};
+use crate::convert::strip_html;
+
+/// Which note a field value is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldSource {
+    /// The note the caller asked to keep
+    Keep,
+    /// The note the caller asked to remove
+    Remove,
+}
+
+/// How field values of two notes are combined
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldStrategy {
+    /// Keep the kept note's value, filling only its empty fields
+    PreferKeep,
+    /// Take whichever value has more visible text (ties keep)
+    PreferLonger,
+    /// Explicit source per field; unlisted fields use `PreferKeep`
+    PerField(HashMap<String, FieldSource>),
+}
+
+/// Options for [`merge_notes`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeOptions {
+    /// How field values are merged
+    pub field_strategy: FieldStrategy,
+    /// Give the surviving note the union of both notes' tags
+    pub union_tags: bool,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            field_strategy: FieldStrategy::PreferKeep,
+            union_tags: true,
+        }
+    }
+}
+
+/// Review history of one note, summed over its cards
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReviewHistory {
+    /// Total number of reviews
+    pub reps: u64,
+    /// Total interval in days (learning cards count as zero)
+    pub interval: i64,
+}
+
+impl ReviewHistory {
+    /// Sums the history of the given cards
+    pub fn from_cards<'a>(
+        cards: impl IntoIterator<Item = &'a CardInfo>,
+    ) -> Self {
+        cards.into_iter().fold(
+            Self::default(),
+            |acc, card| Self {
+                reps: acc.reps + u64::from(card.reps),
+                interval: acc.interval
+                    + card.interval.max(0),
+            },
+        )
+    }
+}
+
+/// The outcome of planning a merge, applied by [`merge_notes`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergePlan {
+    /// Note that stays in the collection
+    pub survivor: u64,
+    /// Note that gets deleted
+    pub loser: u64,
+    /// Whether the survivor is the note the caller wanted removed
+    pub swapped: bool,
+    /// Fields written to the survivor
+    pub fields: HashMap<String, String>,
+    /// Survivor fields whose value changes
+    pub changed_fields: Vec<String>,
+    /// Tags the survivor ends up with
+    pub tags: Vec<String>,
+    /// Review history of the survivor and the loser
+    pub history: (ReviewHistory, ReviewHistory),
+}
+
+impl fmt::Display for MergePlan {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "keep {} ({} reps), delete {} ({} reps)",
+            self.survivor,
+            self.history.0.reps,
+            self.loser,
+            self.history.1.reps
+        )?;
+        if self.swapped {
+            write!(f, " [swapped for scheduling]")?;
+        }
+        if self.changed_fields.is_empty() {
+            write!(f, "; fields unchanged")?;
+        } else {
+            write!(
+                f,
+                "; update fields: {}",
+                self.changed_fields.join(", ")
+            )?;
+        }
+        write!(f, "; tags: {}", self.tags.join(" "))
+    }
+}
+
+/// Result of merging one pair in [`merge_note_pairs`]
+#[derive(Debug)]
+pub struct MergeOutcome {
+    /// The `(keep, remove)` pair as requested
+    pub pair: (u64, u64),
+    /// The plan, or why it could not be built or applied
+    pub result: Result<MergePlan>,
+    /// Whether the plan was written to Anki
+    pub applied: bool,
+}
+
+/// Merges `remove_id` into `keep_id` and deletes the loser.
+///
+/// See the module documentation for how the surviving note is
+/// chosen; the merged content always follows `options`, whichever
+/// note survives.
+pub async fn merge_notes(
+    anki_client: &AnkiClient,
+    keep_id: u64,
+    remove_id: u64,
+    options: MergeOptions,
+) -> Result<MergePlan> {
+    let plan = plan_merge_notes(
+        anki_client,
+        keep_id,
+        remove_id,
+        &options,
+    )
+    .await?;
+    apply_plan(anki_client, &plan).await?;
+    Ok(plan)
+}
+
+/// Builds the merge plan for two notes without modifying anything
+pub async fn plan_merge_notes(
+    anki_client: &AnkiClient,
+    keep_id: u64,
+    remove_id: u64,
+    options: &MergeOptions,
+) -> Result<MergePlan> {
+    anyhow::ensure!(
+        keep_id != remove_id,
+        "Cannot merge note {} with itself",
+        keep_id
+    );
+    let notes = anki_client
+        .notes_info(vec![keep_id, remove_id])
+        .await?;
+    let find = |id: u64| {
+        notes
+            .iter()
+            .find(|note| note.note_id == id)
+            .with_context(|| {
+                format!("Note {} not found", id)
+            })
+    };
+    let (keep, remove) = (find(keep_id)?, find(remove_id)?);
+
+    let card_ids: Vec<u64> = keep
+        .cards
+        .iter()
+        .chain(&remove.cards)
+        .copied()
+        .collect();
+    let cards = anki_client.cards_info(card_ids).await?;
+    Ok(plan_merge(keep, remove, &cards, options))
+}
+
+/// Merges every `(keep, remove)` pair, e.g. from a duplicate report.
+///
+/// With `dry_run` nothing is written and each outcome only carries
+/// its plan (printable via `Display`). A failing pair does not stop
+/// the remaining ones.
+pub async fn merge_note_pairs(
+    anki_client: &AnkiClient,
+    pairs: &[(u64, u64)],
+    options: &MergeOptions,
+    dry_run: bool,
+) -> Vec<MergeOutcome> {
+    let mut outcomes = Vec::with_capacity(pairs.len());
+    for &(keep_id, remove_id) in pairs {
+        let planned = plan_merge_notes(
+            anki_client,
+            keep_id,
+            remove_id,
+            options,
+        )
+        .await;
+        let (result, applied) = match planned {
+            Ok(plan) if !dry_run => {
+                match apply_plan(anki_client, &plan).await {
+                    Ok(()) => (Ok(plan), true),
+                    Err(e) => (Err(e), false),
+                }
+            }
+            other => (other, false),
+        };
+        outcomes.push(MergeOutcome {
+            pair: (keep_id, remove_id),
+            result,
+            applied,
+        });
+    }
+    outcomes
+}
+
+/// Writes content to the survivor first, then deletes the loser
+async fn apply_plan(
+    anki_client: &AnkiClient,
+    plan: &MergePlan,
+) -> Result<()> {
+    if !plan.changed_fields.is_empty() {
+        anki_client
+            .update_note_fields(
+                plan.survivor,
+                plan.fields.clone(),
+                None,
+            )
+            .await?;
+    }
+    anki_client
+        .update_note_tags(plan.survivor, plan.tags.clone())
+        .await?;
+    anki_client.delete_notes(vec![plan.loser]).await
+}
+
+/// One group of duplicates handled by [`merge_duplicates`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Normalized key-field value shared by the group
+    pub key: String,
+    /// Oldest note, which is kept
+    pub kept: u64,
+    /// Newer notes, which are deleted
+    pub deleted: Vec<u64>,
+    /// Tags the kept note ends up with
+    pub tags: Vec<String>,
+}
+
+/// Result of [`merge_duplicates`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Every group with more than one note, by kept note ID
+    pub groups: Vec<DuplicateGroup>,
+}
+
+impl MergeReport {
+    /// IDs of the kept notes
+    pub fn kept(&self) -> Vec<u64> {
+        self.groups.iter().map(|group| group.kept).collect()
+    }
+
+    /// IDs of the deleted notes
+    pub fn deleted(&self) -> Vec<u64> {
+        self.groups
+            .iter()
+            .flat_map(|group| group.deleted.iter().copied())
+            .collect()
+    }
+}
+
+/// Collapses notes matching `query` whose `key_field` values are
+/// equal after normalization (see [`duplicate_key`]).
+///
+/// The oldest note of each group (lowest ID) is kept and receives
+/// the tags of the whole group; the others are deleted. Fields are
+/// left as they are; use [`merge_notes`] when content or review
+/// history must be merged. Notes without a non-empty `key_field`
+/// are ignored.
+pub async fn merge_duplicates(
+    anki_client: &AnkiClient,
+    query: &str,
+    key_field: &str,
+) -> Result<MergeReport> {
+    let notes = anki_client
+        .find_notes_detailed(query, None)
+        .await?;
+    let report = plan_duplicates(&notes, key_field);
+
+    for group in &report.groups {
+        let kept = notes
+            .iter()
+            .find(|note| note.note_id == group.kept)
+            .map(|note| &note.tags);
+        if kept != Some(&group.tags) {
+            anki_client
+                .update_note_tags(
+                    group.kept,
+                    group.tags.clone(),
+                )
+                .await?;
+        }
+    }
+    anki_client.delete_notes(report.deleted()).await?;
+    Ok(report)
+}
+
+/// Groups already fetched notes by normalized `key_field` value
+pub fn plan_duplicates(
+    notes: &[NoteInfo],
+    key_field: &str,
+) -> MergeReport {
+    let mut groups: HashMap<String, Vec<&NoteInfo>> =
+        HashMap::new();
+    for note in notes {
+        let Some(field) = note.fields.get(key_field) else {
+            continue;
+        };
+        let key = duplicate_key(&field.value);
+        if !key.is_empty() {
+            groups.entry(key).or_default().push(note);
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .map(|(key, mut group)| {
+            group.sort_by_key(|note| note.note_id);
+            let mut tags = group[0].tags.clone();
+            for note in &group[1..] {
+                for tag in &note.tags {
+                    if !tags.contains(tag) {
+                        tags.push(tag.clone());
+                    }
+                }
+            }
+            DuplicateGroup {
+                key,
+                kept: group[0].note_id,
+                deleted: group[1..]
+                    .iter()
+                    .map(|note| note.note_id)
+                    .collect(),
+                tags,
+            }
+        })
+        .collect();
+    groups.sort_by_key(|group| group.kept);
+    MergeReport { groups }
+}
+
+/// Key-field value as compared by [`merge_duplicates`]: visible
+/// text only, NFC-normalized, lowercased, with whitespace runs
+/// collapsed
+pub fn duplicate_key(value: &str) -> String {
+    normalize_name(&strip_html(value))
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Pure merge planning over already fetched notes and cards/// Pure merge planning over already fetched notes and cards
+pub fn plan_merge(
+    keep: &NoteInfo,
+    remove: &NoteInfo,
+    cards: &[CardInfo],
+    options: &MergeOptions,
+) -> MergePlan {
+    let history =
+        |note: &NoteInfo| {
+            ReviewHistory::from_cards(cards.iter().filter(
+                |card| card.note_id == note.note_id,
+            ))
+        };
+    let (keep_history, remove_history) =
+        (history(keep), history(remove));
+
+    let swappable = keep.model_name == remove.model_name
+        && keep.cards.len() == remove.cards.len();
+    let swapped = swappable
+        && (remove_history.reps, remove_history.interval)
+            > (keep_history.reps, keep_history.interval);
+    let (survivor, loser) = if swapped {
+        (remove, keep)
+    } else {
+        (keep, remove)
+    };
+
+    let fields =
+        merge_fields(keep, remove, &options.field_strategy);
+    let mut changed_fields: Vec<String> = fields
+        .iter()
+        .filter(|(name, value)| {
+            survivor.fields.get(*name).map(|f| &f.value)
+                != Some(value)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    changed_fields.sort();
+
+    let mut tags = keep.tags.clone();
+    if options.union_tags {
+        for tag in &remove.tags {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+    }
+
+    MergePlan {
+        survivor: survivor.note_id,
+        loser: loser.note_id,
+        swapped,
+        fields,
+        changed_fields,
+        tags,
+        history: if swapped {
+            (remove_history, keep_history)
+        } else {
+            (keep_history, remove_history)
+        },
+    }
+}
+
+/// Merges field values of the kept note's model
+fn merge_fields(
+    keep: &NoteInfo,
+    remove: &NoteInfo,
+    strategy: &FieldStrategy,
+) -> HashMap<String, String> {
+    let visible_len =
+        |value: &str| strip_html(value).chars().count();
+
+    keep.fields
+        .iter()
+        .map(|(name, field)| {
+            let kept = field.value.as_str();
+            let removed = remove
+                .fields
+                .get(name)
+                .map(|f| f.value.as_str())
+                .unwrap_or("");
+            let prefer_keep = || {
+                if visible_len(kept) == 0
+                    && visible_len(removed) > 0
+                {
+                    removed
+                } else {
+                    kept
+                }
+            };
+            let value = match strategy {
+                FieldStrategy::PreferKeep => prefer_keep(),
+                FieldStrategy::PreferLonger => {
+                    if visible_len(removed)
+                        > visible_len(kept)
+                    {
+                        removed
+                    } else {
+                        kept
+                    }
+                }
+                FieldStrategy::PerField(sources) => {
+                    match sources.get(name) {
+                        Some(FieldSource::Keep) => kept,
+                        Some(FieldSource::Remove) => {
+                            removed
+                        }
+                        None => prefer_keep(),
+                    }
+                }
+            };
+            (name.clone(), value.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use serde_json::{Value, json};
+
+    fn note_json(
+        id: u64,
+        model: &str,
+        fields: &[(&str, &str)],
+        tags: &[&str],
+        cards: &[u64],
+    ) -> Value {
+        let fields: serde_json::Map<String, Value> = fields
+            .iter()
+            .enumerate()
+            .map(|(order, (name, value))| {
+                (
+                    name.to_string(),
+                    json!({ "value": value, "order": order }),
+                )
+            })
+            .collect();
+        json!({
+            "noteId": id,
+            "modelName": model,
+            "tags": tags,
+            "fields": fields,
+            "cards": cards,
+        })
+    }
+
+    fn note(
+        id: u64,
+        model: &str,
+        fields: &[(&str, &str)],
+        tags: &[&str],
+        cards: &[u64],
+    ) -> NoteInfo {
+        serde_json::from_value(note_json(
+            id, model, fields, tags, cards,
+        ))
+        .unwrap()
+    }
+
+    fn card_json(
+        id: u64,
+        note: u64,
+        reps: u32,
+        interval: i64,
+    ) -> Value {
+        json!({
+            "cardId": id,
+            "note": note,
+            "deckName": "Default",
+            "modelName": "Basic",
+            "ord": 0,
+            "type": 2,
+            "queue": 2,
+            "due": 100,
+            "interval": interval,
+            "factor": 2500,
+            "reps": reps,
+            "lapses": 0,
+            "left": 0,
+            "mod": 1,
+        })
+    }
+
+    fn card(
+        id: u64,
+        note: u64,
+        reps: u32,
+        interval: i64,
+    ) -> CardInfo {
+        serde_json::from_value(card_json(
+            id, note, reps, interval,
+        ))
+        .unwrap()
+    }
+
+    fn pair() -> (NoteInfo, NoteInfo) {
+        (
+            note(
+                1,
+                "Basic",
+                &[("Front", "猫"), ("Back", "")],
+                &["n5"],
+                &[11],
+            ),
+            note(
+                2,
+                "Basic",
+                &[
+                    ("Front", "<b>猫</b> (ねこ)"),
+                    ("Back", "cat"),
+                ],
+                &["animals", "n5"],
+                &[21],
+            ),
+        )
+    }
+
+    #[test]
+    fn test_prefer_keep_fills_empty_fields() {
+        let (keep, remove) = pair();
+        let plan = plan_merge(
+            &keep,
+            &remove,
+            &[],
+            &MergeOptions::default(),
+        );
+        assert_eq!(plan.fields["Front"], "猫");
+        assert_eq!(plan.fields["Back"], "cat");
+        assert_eq!(plan.changed_fields, vec!["Back"]);
+        assert_eq!(plan.tags, vec!["n5", "animals"]);
+    }
+
+    #[test]
+    fn test_prefer_longer() {
+        let (keep, remove) = pair();
+        let options = MergeOptions {
+            field_strategy: FieldStrategy::PreferLonger,
+            union_tags: false,
+        };
+        let plan =
+            plan_merge(&keep, &remove, &[], &options);
+        assert_eq!(
+            plan.fields["Front"],
+            "<b>猫</b> (ねこ)"
+        );
+        assert_eq!(plan.fields["Back"], "cat");
+        assert_eq!(plan.tags, vec!["n5"]);
+    }
+
+    #[test]
+    fn test_per_field_sources() {
+        let (keep, remove) = pair();
+        let sources = HashMap::from([
+            ("Front".to_string(), FieldSource::Remove),
+            ("Back".to_string(), FieldSource::Keep),
+        ]);
+        let options = MergeOptions {
+            field_strategy: FieldStrategy::PerField(
+                sources,
+            ),
+            union_tags: true,
+        };
+        let plan =
+            plan_merge(&keep, &remove, &[], &options);
+        assert_eq!(
+            plan.fields["Front"],
+            "<b>猫</b> (ねこ)"
+        );
+        assert_eq!(plan.fields["Back"], "");
+    }
+
+    #[test]
+    fn test_survivor_has_more_review_history() {
+        let (keep, remove) = pair();
+        let cards =
+            vec![card(11, 1, 2, 3), card(21, 2, 9, 40)];
+        let plan = plan_merge(
+            &keep,
+            &remove,
+            &cards,
+            &MergeOptions::default(),
+        );
+        assert!(plan.swapped);
+        assert_eq!((plan.survivor, plan.loser), (2, 1));
+        // Content still follows the strategy relative to `keep`
+        assert_eq!(plan.fields["Front"], "猫");
+        assert_eq!(plan.changed_fields, vec!["Front"]);
+        assert_eq!(plan.history.0.reps, 9);
+    }
+
+    #[test]
+    fn test_interval_breaks_rep_ties() {
+        let (keep, remove) = pair();
+        let cards =
+            vec![card(11, 1, 5, 30), card(21, 2, 5, 10)];
+        let plan = plan_merge(
+            &keep,
+            &remove,
+            &cards,
+            &MergeOptions::default(),
+        );
+        assert!(!plan.swapped);
+
+        let cards =
+            vec![card(11, 1, 5, 10), card(21, 2, 5, 10)];
+        let plan = plan_merge(
+            &keep,
+            &remove,
+            &cards,
+            &MergeOptions::default(),
+        );
+        assert!(
+            !plan.swapped,
+            "ties keep the requested note"
+        );
+    }
+
+    #[test]
+    fn test_no_swap_when_models_differ() {
+        let (keep, _) = pair();
+        let remove = note(
+            2,
+            "Basic (and reversed card)",
+            &[("Front", "猫"), ("Back", "cat")],
+            &[],
+            &[21, 22],
+        );
+        let cards =
+            vec![card(11, 1, 0, 0), card(21, 2, 50, 200)];
+        let plan = plan_merge(
+            &keep,
+            &remove,
+            &cards,
+            &MergeOptions::default(),
+        );
+        assert!(!plan.swapped);
+        assert_eq!(plan.survivor, 1);
+    }
+
+    #[test]
+    fn test_plan_duplicates_groups_by_normalized_key() {
+        let notes = vec![
+            note(
+                5,
+                "Basic",
+                &[("Front", "猫 ")],
+                &["b"],
+                &[],
+            ),
+            note(
+                3,
+                "Basic",
+                &[("Front", "<b>猫</b>")],
+                &["a"],
+                &[],
+            ),
+            note(
+                9,
+                "Basic",
+                &[("Front", "猫")],
+                &["a", "c"],
+                &[],
+            ),
+            note(
+                4,
+                "Basic",
+                &[("Front", "Dog  house")],
+                &[],
+                &[],
+            ),
+            note(
+                7,
+                "Basic",
+                &[("Front", "dog house")],
+                &[],
+                &[],
+            ),
+            note(
+                8,
+                "Basic",
+                &[("Front", "bird")],
+                &[],
+                &[],
+            ),
+            note(6, "Basic", &[("Front", "")], &[], &[]),
+            note(2, "Basic", &[("Front", " ")], &[], &[]),
+            note(1, "Cloze", &[("Text", "猫")], &[], &[]),
+        ];
+        let report = plan_duplicates(&notes, "Front");
+        assert_eq!(
+            report.groups,
+            vec![
+                DuplicateGroup {
+                    key: "猫".to_string(),
+                    kept: 3,
+                    deleted: vec![5, 9],
+                    tags: vec![
+                        "a".to_string(),
+                        "b".to_string(),
+                        "c".to_string()
+                    ],
+                },
+                DuplicateGroup {
+                    key: "dog house".to_string(),
+                    kept: 4,
+                    deleted: vec![7],
+                    tags: vec![],
+                },
+            ]
+        );
+        assert_eq!(report.kept(), vec![3, 4]);
+        assert_eq!(report.deleted(), vec![5, 9, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_duplicates_tags_then_deletes() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "findNotes" => ok(json!([1, 2, 3])),
+                "notesInfo" => ok(json!([
+                    note_json(
+                        1,
+                        "Basic",
+                        &[("Front", "猫")],
+                        &["n5"],
+                        &[]
+                    ),
+                    note_json(
+                        2,
+                        "Basic",
+                        &[("Front", "猫")],
+                        &["ai"],
+                        &[]
+                    ),
+                    note_json(
+                        3,
+                        "Basic",
+                        &[("Front", "犬")],
+                        &[],
+                        &[]
+                    ),
+                ])),
+                _ => ok(Value::Null),
+            })
+            .await;
+
+        let report = merge_duplicates(
+            &mock.client(),
+            "deck:Vocab",
+            "Front",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.kept(), vec![1]);
+        assert_eq!(report.deleted(), vec![2]);
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "findNotes",
+                "notesInfo",
+                "updateNoteTags",
+                "deleteNotes"
+            ]
+        );
+        let requests = mock.requests();
+        assert_eq!(requests[2]["params"]["note"], 1);
+        assert_eq!(
+            requests[2]["params"]["tags"],
+            json!(["n5", "ai"])
+        );
+        assert_eq!(
+            requests[3]["params"]["notes"],
+            json!([2])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_duplicates_without_duplicates() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "findNotes" => ok(json!([1])),
+                "notesInfo" => ok(json!([note_json(
+                    1,
+                    "Basic",
+                    &[("Front", "猫")],
+                    &[],
+                    &[]
+                )])),
+                _ => ok(Value::Null),
+            })
+            .await;
+        let report = merge_duplicates(
+            &mock.client(),
+            "deck:Vocab",
+            "Front",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report, MergeReport::default());
+        assert_eq!(
+            mock.actions(),
+            vec!["findNotes", "notesInfo"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_notes_applies_in_order() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "notesInfo" => ok(json!([
+                    note_json(
+                        1,
+                        "Basic",
+                        &[("Front", "猫"), ("Back", "")],
+                        &["n5"],
+                        &[11]
+                    ),
+                    note_json(
+                        2,
+                        "Basic",
+                        &[("Front", "猫"), ("Back", "cat")],
+                        &["x"],
+                        &[21]
+                    ),
+                ])),
+                "cardsInfo" => ok(json!([
+                    card_json(11, 1, 1, 1),
+                    card_json(21, 2, 0, 0)
+                ])),
+                _ => ok(Value::Null),
+            })
+            .await;
+
+        let plan = merge_notes(
+            &mock.client(),
+            1,
+            2,
+            MergeOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(plan.survivor, 1);
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "notesInfo",
+                "cardsInfo",
+                "updateNoteFields",
+                "updateNoteTags",
+                "deleteNotes"
+            ]
+        );
+        let requests = mock.requests();
+        assert_eq!(requests[2]["params"]["note"]["id"], 1);
+        assert_eq!(
+            requests[2]["params"]["note"]["fields"]["Back"],
+            "cat"
+        );
+        assert_eq!(
+            requests[3]["params"]["tags"],
+            json!(["n5", "x"])
+        );
+        assert_eq!(
+            requests[4]["params"]["notes"],
+            json!([2])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_note_pairs_dry_run_writes_nothing()
+    {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "notesInfo" => {
+                        let ids = params["notes"]
+                            .as_array()
+                            .unwrap();
+                        let notes: Vec<Value> = ids
+                            .iter()
+                            .map(|id| {
+                                let id =
+                                    id.as_u64().unwrap();
+                                note_json(
+                                    id,
+                                    "Basic",
+                                    &[("Front", "x")],
+                                    &[],
+                                    &[id * 10],
+                                )
+                            })
+                            .collect();
+                        ok(Value::Array(notes))
+                    }
+                    "cardsInfo" => ok(json!([])),
+                    _ => ok(Value::Null),
+                },
+            )
+            .await;
+
+        let outcomes = merge_note_pairs(
+            &mock.client(),
+            &[(1, 2), (3, 3)],
+            &MergeOptions::default(),
+            true,
+        )
+        .await;
+        assert_eq!(outcomes.len(), 2);
+        let plan = outcomes[0].result.as_ref().unwrap();
+        assert!(!outcomes[0].applied);
+        assert_eq!(
+            plan.to_string(),
+            "keep 1 (0 reps), delete 2 (0 reps); fields unchanged; tags: "
+        );
+        assert!(outcomes[1].result.is_err());
+        assert!(
+            mock.actions()
+                .iter()
+                .all(|a| a == "notesInfo"
+                    || a == "cardsInfo")
+        );
+    }
+}
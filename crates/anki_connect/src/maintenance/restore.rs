@@ -0,0 +1,625 @@
+//! Re-adding notes from an archive written by
+//! [`super::archive::archive_notes`].
+//!
+//! Every non-empty line of the archive gets a [`RestoreOutcome`] in
+//! the report, keyed by its line number, so a restore that partially
+//! failed can be retried with just the failed lines.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::BufRead;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::anki::client::{AnkiClient, Note, NoteOptions};
+use crate::anki::note::NoteFields;
+
+/// Notes checked and added per request
+const RESTORE_CHUNK: usize = 100;
+
+/// Options for [`restore_notes`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreOptions {
+    /// Deck to restore every note into instead of its archived deck
+    pub target_deck_override: Option<String>,
+    /// Skip notes Anki considers duplicates instead of adding them
+    /// anyway
+    pub skip_duplicates: bool,
+    /// Create decks that no longer exist instead of failing their
+    /// notes
+    pub recreate_missing_decks: bool,
+}
+
+/// What happened to one line of the archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    /// Added as a new note with this ID
+    Created(u64),
+    /// Not added because an identical note exists
+    SkippedDuplicate,
+    /// Not added, with the reason
+    Failed(String),
+}
+
+/// Outcome of [`restore_notes`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Outcome per line number (1-based) of the archive
+    pub outcomes: BTreeMap<usize, RestoreOutcome>,
+}
+
+impl RestoreReport {
+    /// IDs of the created notes, in line order
+    pub fn created(&self) -> Vec<u64> {
+        self.outcomes
+            .values()
+            .filter_map(|outcome| match outcome {
+                RestoreOutcome::Created(id) => Some(*id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Line numbers skipped as duplicates
+    pub fn skipped_lines(&self) -> Vec<usize> {
+        self.lines_where(|outcome| {
+            *outcome == RestoreOutcome::SkippedDuplicate
+        })
+    }
+
+    /// Line numbers that failed; restoring only these lines retries
+    /// the failures
+    pub fn failed_lines(&self) -> Vec<usize> {
+        self.lines_where(|outcome| {
+            matches!(outcome, RestoreOutcome::Failed(_))
+        })
+    }
+
+    fn lines_where(
+        &self,
+        predicate: impl Fn(&RestoreOutcome) -> bool,
+    ) -> Vec<usize> {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| predicate(outcome))
+            .map(|(line, _)| *line)
+            .collect()
+    }
+}
+
+/// One JSONL line of the archive
+#[derive(Deserialize)]
+struct ArchivedRecord {
+    model: String,
+    #[serde(default)]
+    deck: Option<String>,
+    fields: NoteFields,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Re-adds the notes of a JSON Lines archive.
+///
+/// Each record is checked against the current fields of its model;
+/// records whose model was deleted or whose field names no longer
+/// match are failed rather than added with missing content. Notes
+/// are added in chunks, and a failing chunk only fails its own
+/// lines.
+///
+/// # Arguments
+/// * `reader` - Archive in the format written by
+///   [`super::archive::archive_notes`]
+/// * `options` - Target deck, duplicate and deck handling
+///
+/// # Returns
+/// The outcome of every non-empty line. Only reading the archive
+/// itself or looking up models and decks fails the whole call.
+pub async fn restore_notes<R: BufRead>(
+    anki_client: &AnkiClient,
+    reader: R,
+    options: RestoreOptions,
+) -> Result<RestoreReport> {
+    let mut report = RestoreReport::default();
+    let mut records = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.with_context(|| {
+            format!(
+                "Failed to read note archive at line {}",
+                line_number
+            )
+        })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<ArchivedRecord>(&line)
+        {
+            Ok(record) => {
+                records.push((line_number, record))
+            }
+            Err(e) => fail(
+                &mut report,
+                line_number,
+                format!("Invalid record: {}", e),
+            ),
+        }
+    }
+    if records.is_empty() {
+        return Ok(report);
+    }
+
+    let models: HashSet<String> = anki_client
+        .get_model_names()
+        .await?
+        .into_iter()
+        .collect();
+    let mut model_fields: HashMap<String, Vec<String>> =
+        HashMap::new();
+    let mut pending = Vec::new();
+    for (line_number, record) in records {
+        if !models.contains(&record.model) {
+            fail(
+                &mut report,
+                line_number,
+                format!(
+                    "Model \"{}\" no longer exists",
+                    record.model
+                ),
+            );
+            continue;
+        }
+        if !model_fields.contains_key(&record.model) {
+            let names = anki_client
+                .get_model_field_names(&record.model)
+                .await?;
+            model_fields
+                .insert(record.model.clone(), names);
+        }
+        if let Some(reason) = field_mismatch(
+            &record,
+            &model_fields[&record.model],
+        ) {
+            fail(&mut report, line_number, reason);
+            continue;
+        }
+        let Some(deck) = options
+            .target_deck_override
+            .clone()
+            .or(record.deck)
+        else {
+            fail(
+                &mut report,
+                line_number,
+                "Record has no deck and no target deck was given"
+                    .to_string(),
+            );
+            continue;
+        };
+        pending.push((
+            line_number,
+            Note {
+                model_name: record.model,
+                deck_name: deck,
+                fields: record.fields,
+                tags: record.tags,
+                audio: None,
+                picture: None,
+                video: None,
+                options: Some(NoteOptions {
+                    allow_duplicate: !options
+                        .skip_duplicates,
+                    ..Default::default()
+                }),
+            },
+        ));
+    }
+
+    let mut existing: HashSet<String> = anki_client
+        .get_deck_names(None)
+        .await?
+        .into_iter()
+        .collect();
+    let mut missing = HashSet::new();
+    for (_, note) in &pending {
+        if existing.contains(&note.deck_name)
+            || missing.contains(&note.deck_name)
+        {
+            continue;
+        }
+        if options.recreate_missing_decks {
+            anki_client
+                .create_deck(&note.deck_name)
+                .await?;
+            existing.insert(note.deck_name.clone());
+        } else {
+            missing.insert(note.deck_name.clone());
+        }
+    }
+    let pending: Vec<(usize, Note)> = pending
+        .into_iter()
+        .filter_map(|(line_number, note)| {
+            if missing.contains(&note.deck_name) {
+                fail(
+                    &mut report,
+                    line_number,
+                    format!(
+                        "Deck \"{}\" does not exist",
+                        note.deck_name
+                    ),
+                );
+                None
+            } else {
+                Some((line_number, note))
+            }
+        })
+        .collect();
+
+    for chunk in pending.chunks(RESTORE_CHUNK) {
+        restore_chunk(
+            anki_client,
+            chunk,
+            options.skip_duplicates,
+            &mut report,
+        )
+        .await;
+    }
+    Ok(report)
+}
+
+/// Adds one chunk of notes, recording an outcome for each line
+async fn restore_chunk(
+    anki_client: &AnkiClient,
+    chunk: &[(usize, Note)],
+    skip_duplicates: bool,
+    report: &mut RestoreReport,
+) {
+    let mut to_add: Vec<&(usize, Note)> =
+        chunk.iter().collect();
+    if skip_duplicates {
+        let notes = chunk
+            .iter()
+            .map(|(_, note)| note.clone())
+            .collect();
+        let checks = match anki_client
+            .can_add_notes_detailed(notes)
+            .await
+        {
+            Ok(checks) => checks,
+            Err(e) => {
+                fail_all(report, chunk.iter(), &e);
+                return;
+            }
+        };
+        to_add.clear();
+        for (entry, check) in chunk.iter().zip(checks) {
+            if check.can_add {
+                to_add.push(entry);
+            } else if check.is_duplicate() {
+                report.outcomes.insert(
+                    entry.0,
+                    RestoreOutcome::SkippedDuplicate,
+                );
+            } else {
+                fail(
+                    report,
+                    entry.0,
+                    check.error.unwrap_or_else(|| {
+                        "Anki cannot add the note"
+                            .to_string()
+                    }),
+                );
+            }
+        }
+    }
+
+    let notes = to_add
+        .iter()
+        .map(|(_, note)| note.clone())
+        .collect();
+    match anki_client.add_notes(notes).await {
+        Ok(ids) => {
+            for ((line_number, _), id) in
+                to_add.iter().zip(ids)
+            {
+                let outcome = match id {
+                    Some(id) => RestoreOutcome::Created(id),
+                    None => RestoreOutcome::Failed(
+                        "Anki did not add the note"
+                            .to_string(),
+                    ),
+                };
+                report
+                    .outcomes
+                    .insert(*line_number, outcome);
+            }
+        }
+        Err(e) => fail_all(report, to_add.into_iter(), &e),
+    }
+}
+
+/// Describes how the record's fields differ from the model's
+fn field_mismatch(
+    record: &ArchivedRecord,
+    model_fields: &[String],
+) -> Option<String> {
+    let mut missing: Vec<&str> = model_fields
+        .iter()
+        .filter(|name| !record.fields.contains_key(*name))
+        .map(String::as_str)
+        .collect();
+    let mut unknown: Vec<&str> = record
+        .fields
+        .keys()
+        .filter(|name| !model_fields.contains(name))
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() && unknown.is_empty() {
+        return None;
+    }
+    missing.sort_unstable();
+    unknown.sort_unstable();
+    Some(format!(
+        "Fields of model \"{}\" changed (missing: [{}], unknown: [{}])",
+        record.model,
+        missing.join(", "),
+        unknown.join(", ")
+    ))
+}
+
+fn fail(
+    report: &mut RestoreReport,
+    line_number: usize,
+    reason: String,
+) {
+    report.outcomes.insert(
+        line_number,
+        RestoreOutcome::Failed(reason),
+    );
+}
+
+fn fail_all<'a>(
+    report: &mut RestoreReport,
+    entries: impl Iterator<Item = &'a (usize, Note)>,
+    error: &anyhow::Error,
+) {
+    for (line_number, _) in entries {
+        fail(report, *line_number, format!("{:#}", error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use crate::maintenance::archive::{
+        ArchiveOptions, archive_notes,
+    };
+    use serde_json::{Value, json};
+
+    /// Mock collection with two "Basic" notes in "Inbox"; added
+    /// notes are recorded and get IDs from 9000 on, and notes whose
+    /// front is "dup" are duplicates
+    async fn collection_mock(
+        decks: Arc<Mutex<Vec<String>>>,
+    ) -> MockAnki {
+        MockAnki::start(move |action, params| match action {
+            "findNotes" => ok(json!([1700000000000u64, 1700000000001u64])),
+            "notesInfo" => ok(Value::Array(
+                params["notes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| {
+                        let id = id.as_u64().unwrap();
+                        json!({
+                            "noteId": id,
+                            "modelName": "Basic",
+                            "tags": ["later"],
+                            "fields": {
+                                "Front": {"value": format!("front {}", id % 10), "order": 0},
+                                "Back": {"value": "back", "order": 1},
+                            },
+                            "cards": [id % 10],
+                        })
+                    })
+                    .collect(),
+            )),
+            "cardsInfo" => ok(Value::Array(
+                params["cards"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| {
+                        let id = id.as_u64().unwrap();
+                        json!({
+                            "cardId": id,
+                            "note": 1700000000000u64 + id,
+                            "deckName": "Inbox",
+                            "modelName": "Basic",
+                            "ord": 0,
+                            "type": 0,
+                            "queue": 0,
+                            "due": 0,
+                            "interval": 0,
+                            "factor": 0,
+                            "reps": 0,
+                            "lapses": 0,
+                        })
+                    })
+                    .collect(),
+            )),
+            "modelNames" => ok(json!(["Basic"])),
+            "modelFieldNames" => ok(json!(["Front", "Back"])),
+            "deckNames" => ok(json!(*decks.lock().unwrap())),
+            "createDeck" => {
+                decks.lock().unwrap().push(
+                    params["deck"].as_str().unwrap().to_string(),
+                );
+                ok(json!(1))
+            }
+            "canAddNotesWithErrorDetail" => ok(Value::Array(
+                params["notes"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|note| {
+                        if note["fields"]["Front"] == "dup" {
+                            json!({"canAdd": false, "error": "cannot create note because it is a duplicate"})
+                        } else {
+                            json!({"canAdd": true})
+                        }
+                    })
+                    .collect(),
+            )),
+            "addNotes" => ok(Value::Array(
+                (0..params["notes"].as_array().unwrap().len())
+                    .map(|i| json!(9000 + i))
+                    .collect(),
+            )),
+            other => panic!("unexpected action {}", other),
+        })
+        .await
+    }
+
+    fn added_notes(mock: &MockAnki) -> Vec<Value> {
+        mock.requests()
+            .iter()
+            .filter(|request| {
+                request["action"] == "addNotes"
+            })
+            .flat_map(|request| {
+                request["params"]["notes"]
+                    .as_array()
+                    .unwrap()
+                    .clone()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_into_other_deck() {
+        let decks =
+            Arc::new(Mutex::new(vec!["Inbox".to_string()]));
+        let mock = collection_mock(decks.clone()).await;
+        let client = mock.client();
+        let mut archive = Vec::new();
+        archive_notes(
+            &client,
+            "deck:Inbox",
+            &mut archive,
+            ArchiveOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let report = restore_notes(
+            &client,
+            Cursor::new(archive),
+            RestoreOptions {
+                target_deck_override: Some(
+                    "Restored".to_string(),
+                ),
+                skip_duplicates: false,
+                recreate_missing_decks: true,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.created(), vec![9000, 9001]);
+        assert!(report.failed_lines().is_empty());
+        assert_eq!(
+            decks.lock().unwrap().as_slice(),
+            ["Inbox", "Restored"]
+        );
+        let added = added_notes(&mock);
+        assert_eq!(added.len(), 2);
+        assert_eq!(added[0]["deckName"], "Restored");
+        assert_eq!(added[0]["modelName"], "Basic");
+        assert_eq!(
+            added[0]["fields"],
+            json!({"Front": "front 0", "Back": "back"})
+        );
+        assert_eq!(added[1]["fields"]["Front"], "front 1");
+        assert_eq!(added[0]["tags"], json!(["later"]));
+        assert_eq!(
+            added[0]["options"]["allowDuplicate"],
+            true
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outcome_per_line() {
+        let decks =
+            Arc::new(Mutex::new(vec!["Inbox".to_string()]));
+        let mock = collection_mock(decks.clone()).await;
+        let archive = [
+            r#"{"model":"Basic","deck":"Inbox","fields":{"Front":"a","Back":"b"},"tags":[]}"#,
+            "",
+            r#"{"model":"Cloze","deck":"Inbox","fields":{"Text":"x"},"tags":[]}"#,
+            r#"{"model":"Basic","deck":"Inbox","fields":{"Front":"a","Extra":"b"},"tags":[]}"#,
+            "not json",
+            r#"{"model":"Basic","deck":"Gone","fields":{"Front":"a","Back":"b"},"tags":[]}"#,
+            r#"{"model":"Basic","deck":"Inbox","fields":{"Front":"dup","Back":"b"},"tags":[]}"#,
+            r#"{"model":"Basic","deck":"Inbox","fields":{"Front":"c","Back":"d"},"tags":["x"]}"#,
+        ]
+        .join("\n");
+
+        let report = restore_notes(
+            &mock.client(),
+            Cursor::new(archive),
+            RestoreOptions {
+                skip_duplicates: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report
+                .outcomes
+                .keys()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![1, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(
+            report.outcomes[&1],
+            RestoreOutcome::Created(9000)
+        );
+        assert_eq!(
+            report.outcomes[&8],
+            RestoreOutcome::Created(9001)
+        );
+        assert_eq!(report.skipped_lines(), vec![7]);
+        assert_eq!(report.failed_lines(), vec![3, 4, 5, 6]);
+        let reason = |line| match &report.outcomes[&line] {
+            RestoreOutcome::Failed(reason) => {
+                reason.clone()
+            }
+            other => panic!(
+                "line {} not failed: {:?}",
+                line, other
+            ),
+        };
+        assert_eq!(
+            reason(3),
+            "Model \"Cloze\" no longer exists"
+        );
+        assert_eq!(
+            reason(4),
+            "Fields of model \"Basic\" changed (missing: [Back], unknown: [Extra])"
+        );
+        assert!(reason(5).starts_with("Invalid record"));
+        assert_eq!(
+            reason(6),
+            "Deck \"Gone\" does not exist"
+        );
+        assert_eq!(decks.lock().unwrap().len(), 1);
+        assert_eq!(added_notes(&mock).len(), 2);
+    }
+}
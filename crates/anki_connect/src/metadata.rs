@@ -0,0 +1,983 @@
+//! Machine-readable metadata attached to notes: where a note came
+//! from, which import run wrote it, the hash of its content, and so
+//! on.
+//!
+//! Metadata is a flat map of string keys to string values, stored in
+//! one of two ways (see [`MetadataStorage`]):
+//!
+//! - as a JSON object in a designated field, usually one that no card
+//!   template shows: `{"hash":"9f2c…","source":"words.csv#3"}`
+//! - as one namespaced tag per key: `meta::source=words.csv%233`
+//!
+//! Writing always merges into what the note already holds, so tools
+//! that own different keys do not overwrite each other.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::anki::client::{
+    AnkiClient, NoteInfo, escape_search,
+};
+use crate::convert::decode_entities;
+
+/// First component of every metadata tag
+pub const TAG_NAMESPACE: &str = "meta";
+
+/// Longest metadata tag in bytes, namespace and escapes included
+pub const MAX_TAG_BYTES: usize = 255;
+
+/// Longest JSON blob a metadata field may hold, in bytes
+pub const MAX_FIELD_BYTES: usize = 4096;
+
+/// Where metadata is stored on a note
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataStorage {
+    /// A JSON object in the named field, which every note must have
+    Field(String),
+    /// One `meta::key=value` tag per key. Values are escaped so that
+    /// Anki's tag handling (no whitespace, `::` hierarchy,
+    /// case-insensitive matching) cannot alter them.
+    Tags,
+}
+
+/// Metadata of one note: string values by key.
+///
+/// Keys are made of lowercase ASCII letters, digits, `-`, `.` and
+/// `_`. Values are any non-empty string; when merged, an empty value
+/// removes the key instead.
+#[derive(
+    Debug,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct NoteMetadata(BTreeMap<String, String>);
+
+impl NoteMetadata {
+    /// ID of the item a note was created from
+    pub const SOURCE_ID: &str = "source";
+    /// Import run that last wrote a note
+    pub const IMPORT_RUN: &str = "run";
+    /// Hash of the content a note was written from
+    pub const CONTENT_HASH: &str = "hash";
+    /// Version of the enrichment last applied to a note
+    pub const ENRICHMENT_VERSION: &str = "enrichment";
+
+    /// Empty metadata
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `key`, builder style
+    pub fn with(
+        mut self,
+        key: &str,
+        value: impl Into<String>,
+    ) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Value of `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Sets `key`, replacing its previous value
+    pub fn set(
+        &mut self,
+        key: &str,
+        value: impl Into<String>,
+    ) {
+        self.0.insert(key.to_string(), value.into());
+    }
+
+    /// Removes `key`, returning its value
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    /// Keys and values, sorted by key
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Number of keys
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no key is set
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Applies `update`: its keys replace the ones here, except that
+    /// an empty value removes the key. Keys missing from `update`
+    /// are kept.
+    pub fn merge(&mut self, update: &NoteMetadata) {
+        for (key, value) in &update.0 {
+            if value.is_empty() {
+                self.0.remove(key);
+            } else {
+                self.0.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    /// See [`NoteMetadata::SOURCE_ID`]
+    pub fn source_id(&self) -> Option<&str> {
+        self.get(Self::SOURCE_ID)
+    }
+
+    /// See [`NoteMetadata::IMPORT_RUN`]
+    pub fn import_run(&self) -> Option<&str> {
+        self.get(Self::IMPORT_RUN)
+    }
+
+    /// See [`NoteMetadata::CONTENT_HASH`]
+    pub fn content_hash(&self) -> Option<&str> {
+        self.get(Self::CONTENT_HASH)
+    }
+
+    /// See [`NoteMetadata::ENRICHMENT_VERSION`]; `None` if unset or
+    /// not a number
+    pub fn enrichment_version(&self) -> Option<u32> {
+        self.get(Self::ENRICHMENT_VERSION)?.parse().ok()
+    }
+
+    /// Sets [`NoteMetadata::ENRICHMENT_VERSION`]
+    pub fn set_enrichment_version(&mut self, version: u32) {
+        self.set(
+            Self::ENRICHMENT_VERSION,
+            version.to_string(),
+        );
+    }
+
+    /// Checks every key, and that the metadata fits the limits of
+    /// `storage`
+    pub fn validate(
+        &self,
+        storage: &MetadataStorage,
+    ) -> Result<()> {
+        for key in self.0.keys() {
+            validate_key(key)?;
+        }
+        match storage {
+            MetadataStorage::Field(_) => {
+                let size = to_field_value(self)?.len();
+                anyhow::ensure!(
+                    size <= MAX_FIELD_BYTES,
+                    "Metadata of {} bytes exceeds the {}-byte field limit",
+                    size,
+                    MAX_FIELD_BYTES
+                );
+            }
+            MetadataStorage::Tags => {
+                for (key, value) in self.iter() {
+                    let tag = to_tag(key, value);
+                    anyhow::ensure!(
+                        tag.len() <= MAX_TAG_BYTES,
+                        "Metadata tag for {} is {} bytes, over the {}-byte limit",
+                        key,
+                        tag.len(),
+                        MAX_TAG_BYTES
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for NoteMetadata
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(
+        iter: I,
+    ) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+/// Checks that `key` can be stored both in JSON and in a tag
+fn validate_key(key: &str) -> Result<()> {
+    anyhow::ensure!(
+        !key.is_empty() && key.bytes().all(is_tag_safe),
+        "Invalid metadata key '{}': use lowercase letters, digits, '-', '.' and '_'",
+        key
+    );
+    Ok(())
+}
+
+/// Whether `b` is kept as is in a tag value. Everything else is
+/// percent-encoded, uppercase letters included, because Anki may
+/// change the case of a tag to match an existing one.
+fn is_tag_safe(b: u8) -> bool {
+    b.is_ascii_lowercase()
+        || b.is_ascii_digit()
+        || matches!(b, b'-' | b'.' | b'_')
+}
+
+/// `meta::key=value` with the value percent-encoded
+fn to_tag(key: &str, value: &str) -> String {
+    let mut tag = format!("{}::{}=", TAG_NAMESPACE, key);
+    for b in value.bytes() {
+        if is_tag_safe(b) {
+            tag.push(char::from(b));
+        } else {
+            tag.push_str(&format!("%{:02X}", b));
+        }
+    }
+    tag
+}
+
+/// Key and value of a metadata tag; `None` for other tags. A value
+/// with a broken escape is returned as written.
+fn parse_tag(tag: &str) -> Option<(String, String)> {
+    let (namespace, rest) = tag.split_once("::")?;
+    if !namespace.eq_ignore_ascii_case(TAG_NAMESPACE) {
+        return None;
+    }
+    let (key, value) = rest.split_once('=')?;
+    let key = key.to_ascii_lowercase();
+    validate_key(&key).ok()?;
+    let value = percent_decode(value)
+        .unwrap_or_else(|| value.to_string());
+    Some((key, value))
+}
+
+/// Undoes the escaping of [`to_tag`], accepting either case of hex
+/// digit
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex =
+                std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Whether `tags` holds `tag`, compared as Anki does, ignoring case
+fn contains_tag(tags: &[String], tag: &str) -> bool {
+    tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+}
+
+/// Metadata held in `tags`
+fn from_tags(tags: &[String]) -> NoteMetadata {
+    tags.iter().filter_map(|tag| parse_tag(tag)).collect()
+}
+
+/// The JSON stored in a metadata field. `<`, `>` and `&` are written
+/// as `\u` escapes so the value stays inert HTML.
+fn to_field_value(
+    metadata: &NoteMetadata,
+) -> Result<String> {
+    if metadata.is_empty() {
+        return Ok(String::new());
+    }
+    // The characters can only occur inside JSON strings, where the
+    // escapes decode to the same text
+    Ok(serde_json::to_string(metadata)?
+        .replace('<', "\\u003c")
+        .replace('>', "\\u003e")
+        .replace('&', "\\u0026"))
+}
+
+/// Parses a metadata field; an empty field holds no metadata. The
+/// editor HTML-escapes what it saves, so entities are decoded if
+/// the value is not JSON as is.
+fn from_field_value(value: &str) -> Result<NoteMetadata> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(NoteMetadata::new());
+    }
+    serde_json::from_str(value)
+        .or_else(|_| {
+            serde_json::from_str(&decode_entities(value))
+        })
+        .context("The field does not hold a JSON object of strings")
+}
+
+/// Metadata stored on `note`
+fn from_note(
+    note: &NoteInfo,
+    storage: &MetadataStorage,
+) -> Result<NoteMetadata> {
+    match storage {
+        MetadataStorage::Field(field) => {
+            let value = note
+                .fields
+                .get(field)
+                .with_context(|| {
+                    format!(
+                        "Note {} has no field {}",
+                        note.note_id, field
+                    )
+                })?;
+            from_field_value(&value.value).with_context(|| {
+                format!(
+                    "Failed to read the metadata of note {}",
+                    note.note_id
+                )
+            })
+        }
+        MetadataStorage::Tags => Ok(from_tags(&note.tags)),
+    }
+}
+
+/// Reads the metadata of one note.
+///
+/// # Errors
+/// If the note does not exist, lacks the metadata field, or the
+/// field holds something other than metadata JSON.
+pub async fn read_metadata(
+    anki_client: &AnkiClient,
+    note_id: u64,
+    storage: &MetadataStorage,
+) -> Result<NoteMetadata> {
+    read_metadata_batch(anki_client, &[note_id], storage)
+        .await?
+        .remove(&note_id)
+        .with_context(|| {
+            format!("Note {} not found", note_id)
+        })
+}
+
+/// Reads the metadata of many notes with chunked `notesInfo`
+/// requests (see [`AnkiClient::notes_info_chunked`]).
+///
+/// # Returns
+/// Metadata by note ID; notes that do not exist are left out.
+pub async fn read_metadata_batch(
+    anki_client: &AnkiClient,
+    note_ids: &[u64],
+    storage: &MetadataStorage,
+) -> Result<BTreeMap<u64, NoteMetadata>> {
+    let notes =
+        anki_client.notes_info_chunked(note_ids).await?;
+    notes
+        .iter()
+        .map(|note| {
+            Ok((note.note_id, from_note(note, storage)?))
+        })
+        .collect()
+}
+
+/// Merges `update` into the metadata of one note (see
+/// [`NoteMetadata::merge`]).
+///
+/// # Returns
+/// The note's metadata after the merge
+pub async fn write_metadata(
+    anki_client: &AnkiClient,
+    note_id: u64,
+    storage: &MetadataStorage,
+    update: &NoteMetadata,
+) -> Result<NoteMetadata> {
+    write_metadata_batch(
+        anki_client,
+        storage,
+        vec![(note_id, update.clone())],
+    )
+    .await?
+    .remove(&note_id)
+    .with_context(|| format!("Note {} not found", note_id))
+}
+
+/// Merges an update into the metadata of each note.
+///
+/// The current metadata is read first and every merged result is
+/// validated before anything is written, so an invalid key or an
+/// oversized value changes no note. A field is written with one
+/// `updateNoteFields` request per note. Tags are changed with
+/// `removeTags` and `addTags`, which leave the note's other tags
+/// alone; notes needing the same change share a request.
+///
+/// # Returns
+/// Metadata after the merge by note ID; notes that do not exist are
+/// left out and not written.
+pub async fn write_metadata_batch(
+    anki_client: &AnkiClient,
+    storage: &MetadataStorage,
+    updates: Vec<(u64, NoteMetadata)>,
+) -> Result<BTreeMap<u64, NoteMetadata>> {
+    let note_ids: Vec<u64> =
+        updates.iter().map(|(id, _)| *id).collect();
+    let notes: HashMap<u64, NoteInfo> = anki_client
+        .notes_info_chunked(&note_ids)
+        .await?
+        .into_iter()
+        .map(|note| (note.note_id, note))
+        .collect();
+
+    let mut merged = BTreeMap::new();
+    for (note_id, update) in &updates {
+        let Some(note) = notes.get(note_id) else {
+            continue;
+        };
+        let metadata = merged
+            .entry(*note_id)
+            .or_insert(from_note(note, storage)?);
+        metadata.merge(update);
+        metadata.validate(storage).with_context(|| {
+            format!("Invalid metadata for note {}", note_id)
+        })?;
+    }
+
+    match storage {
+        MetadataStorage::Field(field) => {
+            for (note_id, metadata) in &merged {
+                let value = to_field_value(metadata)?;
+                if notes[note_id].fields[field].value
+                    != value
+                {
+                    anki_client
+                        .update_note_field(
+                            *note_id, field, &value,
+                        )
+                        .await?;
+                }
+            }
+        }
+        MetadataStorage::Tags => {
+            // (tags to remove, tags to add) → notes
+            let mut changes: BTreeMap<
+                (Vec<String>, Vec<String>),
+                Vec<u64>,
+            > = BTreeMap::new();
+            for (note_id, metadata) in &merged {
+                let current = &notes[note_id].tags;
+                let wanted: Vec<String> = metadata
+                    .iter()
+                    .map(|(key, value)| to_tag(key, value))
+                    .collect();
+                let remove: Vec<String> = current
+                    .iter()
+                    .filter(|tag| {
+                        parse_tag(tag).is_some()
+                            && !contains_tag(&wanted, tag)
+                    })
+                    .cloned()
+                    .collect();
+                let add: Vec<String> = wanted
+                    .into_iter()
+                    .filter(|tag| {
+                        !contains_tag(current, tag)
+                    })
+                    .collect();
+                if !remove.is_empty() || !add.is_empty() {
+                    changes
+                        .entry((remove, add))
+                        .or_default()
+                        .push(*note_id);
+                }
+            }
+            for ((remove, add), note_ids) in changes {
+                anki_client
+                    .remove_tag_list(
+                        note_ids.clone(),
+                        &remove,
+                    )
+                    .await?;
+                anki_client
+                    .add_tag_list(note_ids, &add)
+                    .await?;
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Finds the notes whose metadata sets `key` to `value`, e.g. the
+/// note created for a source item.
+///
+/// Tags are found with a single tag search. For a field, a search
+/// narrows the candidates when the value is plain enough to appear
+/// verbatim in the JSON; every candidate's metadata is then read to
+/// confirm the match.
+pub async fn find_by_metadata(
+    anki_client: &AnkiClient,
+    storage: &MetadataStorage,
+    key: &str,
+    value: &str,
+) -> Result<Vec<u64>> {
+    validate_key(key)?;
+    let field = match storage {
+        MetadataStorage::Tags => {
+            let query = format!(
+                "\"tag:{}\"",
+                escape_search(&to_tag(key, value))
+            );
+            return anki_client.find_notes(&query).await;
+        }
+        MetadataStorage::Field(field) => field,
+    };
+    let field_name = escape_search(field);
+    let query = if !value.is_empty()
+        && value.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(b, b'-' | b'.' | b'#')
+        }) {
+        format!(
+            "\"{}:*{}*\"",
+            field_name,
+            escape_search(value)
+        )
+    } else {
+        format!("\"{}:_*\"", field_name)
+    };
+    let candidates = anki_client.find_notes(&query).await?;
+    let found = read_metadata_batch(
+        anki_client,
+        &candidates,
+        storage,
+    )
+    .await?;
+    Ok(found
+        .into_iter()
+        .filter(|(_, metadata)| {
+            metadata.get(key) == Some(value)
+        })
+        .map(|(note_id, _)| note_id)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, err, ok};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    const AWKWARD: &[(&str, &str)] = &[
+        ("source", "words.csv#3"),
+        ("spaces", "two  words\tand a tab"),
+        ("colons", "a::b:c"),
+        ("unicode", "猫 café Ünïcode"),
+        ("html", "<b>&amp;</b> \"quoted\" \\ %41"),
+        ("upper", "ABC"),
+    ];
+
+    fn awkward() -> NoteMetadata {
+        AWKWARD.iter().copied().collect()
+    }
+
+    /// Mock holding note 1 with a `Meta` field and some tags; the
+    /// write actions change it in place
+    async fn note_mock(
+        field: &str,
+        tags: &[&str],
+    ) -> (MockAnki, Arc<Mutex<Value>>) {
+        let note = Arc::new(Mutex::new(json!({
+            "noteId": 1,
+            "modelName": "Basic",
+            "cards": [10],
+            "tags": tags,
+            "fields": {
+                "Front": {"value": "猫", "order": 0},
+                "Meta": {"value": field, "order": 1},
+            },
+        })));
+        let state = note.clone();
+        let mock =
+            MockAnki::start(move |action, params| {
+                let mut note = state.lock().unwrap();
+                match action {
+                    "notesInfo" => ok(json!(
+                        params["notes"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .filter(|id| **id == 1)
+                            .map(|_| note.clone())
+                            .collect::<Vec<_>>()
+                    )),
+                    "updateNoteFields" => {
+                        for (name, value) in params["note"]
+                            ["fields"]
+                            .as_object()
+                            .unwrap()
+                        {
+                            note["fields"][name]["value"] =
+                                value.clone();
+                        }
+                        ok(json!(null))
+                    }
+                    "addTags" | "removeTags" => {
+                        let change: Vec<String> =
+                            params["tags"]
+                                .as_str()
+                                .unwrap()
+                                .split(' ')
+                                .map(str::to_string)
+                                .collect();
+                        let tags = note["tags"]
+                            .as_array_mut()
+                            .unwrap();
+                        if action == "addTags" {
+                            tags.extend(
+                                change
+                                    .into_iter()
+                                    .map(Value::from),
+                            );
+                        } else {
+                            tags.retain(|tag| {
+                                !change
+                                    .iter()
+                                    .any(|c| tag == c)
+                            });
+                        }
+                        ok(json!(null))
+                    }
+                    "findNotes" => ok(json!([1, 2])),
+                    _ => err("unsupported action"),
+                }
+            })
+            .await;
+        (mock, note)
+    }
+
+    fn meta_field() -> MetadataStorage {
+        MetadataStorage::Field("Meta".to_string())
+    }
+
+    #[test]
+    fn test_tag_encoding() {
+        assert_eq!(
+            to_tag("source", "words.csv#3"),
+            "meta::source=words.csv%233"
+        );
+        assert_eq!(
+            to_tag("colons", "a::b c"),
+            "meta::colons=a%3A%3Ab%20c"
+        );
+        assert_eq!(
+            to_tag("upper", "Ab"),
+            "meta::upper=%41b"
+        );
+        for (key, value) in AWKWARD {
+            let tag = to_tag(key, value);
+            assert!(!tag.contains(char::is_whitespace));
+            assert_eq!(tag.matches("::").count(), 1);
+            assert_eq!(
+                parse_tag(&tag),
+                Some((key.to_string(), value.to_string()))
+            );
+            // Anki may change the case of the tag
+            assert_eq!(
+                parse_tag(&tag.to_lowercase()),
+                Some((key.to_string(), value.to_string()))
+            );
+        }
+        assert_eq!(parse_tag("vocab::n5"), None);
+        assert_eq!(parse_tag("meta::no-value"), None);
+        assert_eq!(
+            parse_tag("Meta::Source=50%"),
+            Some(("source".to_string(), "50%".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_field_encoding() {
+        let value = to_field_value(&awkward()).unwrap();
+        assert!(!value.contains(['<', '>', '&']));
+        assert_eq!(
+            from_field_value(&value).unwrap(),
+            awkward()
+        );
+        // As the editor would save it
+        assert_eq!(
+            from_field_value(
+                "{&quot;run&quot;:&quot;7&quot;}"
+            )
+            .unwrap(),
+            NoteMetadata::new().with("run", "7")
+        );
+        assert!(from_field_value(" ").unwrap().is_empty());
+        assert!(from_field_value("notes").is_err());
+        assert_eq!(
+            to_field_value(&NoteMetadata::new()).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_merge_and_typed_keys() {
+        let mut metadata = NoteMetadata::new()
+            .with(NoteMetadata::SOURCE_ID, "a")
+            .with(NoteMetadata::CONTENT_HASH, "h1");
+        metadata.set_enrichment_version(2);
+        metadata.merge(
+            &NoteMetadata::new()
+                .with(NoteMetadata::CONTENT_HASH, "h2")
+                .with(NoteMetadata::IMPORT_RUN, "")
+                .with(NoteMetadata::SOURCE_ID, ""),
+        );
+        assert_eq!(metadata.source_id(), None);
+        assert_eq!(metadata.content_hash(), Some("h2"));
+        assert_eq!(metadata.import_run(), None);
+        assert_eq!(metadata.enrichment_version(), Some(2));
+        assert_eq!(metadata.len(), 2);
+    }
+
+    #[test]
+    fn test_validation() {
+        let bad = NoteMetadata::new().with("Source", "a");
+        assert!(
+            bad.validate(&MetadataStorage::Tags).is_err()
+        );
+        let bad = NoteMetadata::new().with("a=b", "a");
+        assert!(bad.validate(&meta_field()).is_err());
+
+        let long = NoteMetadata::new()
+            .with("note", "é".repeat(50));
+        // Each é takes six bytes as a tag
+        assert!(long.validate(&meta_field()).is_ok());
+        assert!(
+            long.validate(&MetadataStorage::Tags).is_err()
+        );
+        let huge = NoteMetadata::new()
+            .with("note", "x".repeat(4096));
+        assert!(huge.validate(&meta_field()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_field_round_trip_and_merge() {
+        let (mock, note) = note_mock(
+            "{\"run\":\"6\",\"hash\":\"h1\"}",
+            &[],
+        )
+        .await;
+        let client = mock.client();
+
+        let merged = write_metadata(
+            &client,
+            1,
+            &meta_field(),
+            &awkward().with("hash", "h2"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(merged.get("run"), Some("6"));
+        assert_eq!(merged.content_hash(), Some("h2"));
+        assert_eq!(
+            read_metadata(&client, 1, &meta_field())
+                .await
+                .unwrap(),
+            merged
+        );
+        for (key, value) in AWKWARD {
+            assert_eq!(merged.get(key), Some(*value));
+        }
+        // The other fields are not sent
+        assert_eq!(
+            mock.requests()[1]["params"]["note"]["fields"]
+                .as_object()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            note.lock().unwrap()["fields"]["Front"]["value"],
+            "猫"
+        );
+
+        // An update that changes nothing writes nothing
+        write_metadata(
+            &client,
+            1,
+            &meta_field(),
+            &NoteMetadata::new().with("run", "6"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            mock.actions(),
+            [
+                "notesInfo",
+                "updateNoteFields",
+                "notesInfo",
+                "notesInfo"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tag_round_trip_and_merge() {
+        let (mock, note) = note_mock(
+            "",
+            &["vocab", "meta::run=6", "meta::hash=h1"],
+        )
+        .await;
+        let client = mock.client();
+
+        let merged = write_metadata(
+            &client,
+            1,
+            &MetadataStorage::Tags,
+            &awkward().with("hash", "h2"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(merged.get("run"), Some("6"));
+        assert_eq!(
+            read_metadata(
+                &client,
+                1,
+                &MetadataStorage::Tags
+            )
+            .await
+            .unwrap(),
+            merged
+        );
+        let tags = note.lock().unwrap()["tags"].clone();
+        assert!(
+            tags.as_array()
+                .unwrap()
+                .contains(&json!("vocab"))
+        );
+        assert!(
+            tags.as_array()
+                .unwrap()
+                .contains(&json!("meta::run=6"))
+        );
+        assert!(
+            !tags
+                .as_array()
+                .unwrap()
+                .contains(&json!("meta::hash=h1"))
+        );
+        // Only the changed key is removed
+        assert_eq!(
+            mock.requests()[1]["params"],
+            json!({"notes": [1], "tags": "meta::hash=h1"})
+        );
+
+        // Removing a key
+        let merged = write_metadata(
+            &client,
+            1,
+            &MetadataStorage::Tags,
+            &NoteMetadata::new().with("unicode", ""),
+        )
+        .await
+        .unwrap();
+        assert_eq!(merged.get("unicode"), None);
+        assert_eq!(merged.len(), AWKWARD.len() + 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_write_validates_first() {
+        let (mock, _) = note_mock("", &[]).await;
+        let error = write_metadata_batch(
+            &mock.client(),
+            &MetadataStorage::Tags,
+            vec![
+                (1, NoteMetadata::new().with("ok", "1")),
+                (2, NoteMetadata::new().with("ok", "1")),
+                (1, NoteMetadata::new().with("Bad", "1")),
+            ],
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            format!("{:#}", error)
+                .contains("Invalid metadata key")
+        );
+        assert_eq!(mock.actions(), ["notesInfo"]);
+
+        // Note 2 does not exist and is skipped
+        let merged = write_metadata_batch(
+            &mock.client(),
+            &MetadataStorage::Tags,
+            vec![
+                (1, NoteMetadata::new().with("ok", "1")),
+                (2, NoteMetadata::new().with("ok", "1")),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(merged.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[tokio::test]
+    async fn test_find_by_metadata() {
+        let (mock, _) = note_mock(
+            "{\"source\":\"words.csv#3\"}",
+            &["meta::source=words.csv%233"],
+        )
+        .await;
+        let client = mock.client();
+
+        assert_eq!(
+            find_by_metadata(
+                &client,
+                &MetadataStorage::Tags,
+                "source",
+                "words.csv#3"
+            )
+            .await
+            .unwrap(),
+            [1, 2]
+        );
+        assert_eq!(
+            find_by_metadata(
+                &client,
+                &meta_field(),
+                "source",
+                "words.csv#3"
+            )
+            .await
+            .unwrap(),
+            [1]
+        );
+        assert!(
+            find_by_metadata(
+                &client,
+                &meta_field(),
+                "source",
+                "a b"
+            )
+            .await
+            .unwrap()
+            .is_empty()
+        );
+        let queries: Vec<Value> = mock
+            .requests()
+            .iter()
+            .filter(|r| r["action"] == "findNotes")
+            .map(|r| r["params"]["query"].clone())
+            .collect();
+        assert_eq!(
+            queries,
+            [
+                "\"tag:meta::source=words.csv%233\"",
+                "\"Meta:*words.csv#3*\"",
+                "\"Meta:_*\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,536 @@
+//! Incremental reading: splitting an article into extract notes.
+//!
+//! Each extract becomes one note carrying the source title/URL and
+//! its position (`3/12`), tagged `source::<slug>` so the whole article
+//! can be found again and re-imports are detected.
+
+use anyhow::Result;
+use utils::text::{sentence_spans, split_paragraphs};
+
+use crate::anki::client::{AnkiClient, Note};
+use crate::anki::note::NoteFields;
+use crate::convert::escape_html;
+
+/// How an article is cut into extracts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SplitStrategy {
+    /// Keep paragraphs whole and pack them into extracts; only a
+    /// paragraph larger than the cap is cut, at sentence boundaries
+    #[default]
+    Paragraph,
+    /// Fill every extract with as many whole sentences as fit,
+    /// cutting paragraphs wherever needed
+    Sentence,
+}
+
+/// Options for [`create_extracts`]
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    /// Deck receiving the extracts
+    pub deck: String,
+    /// Model of the extract notes
+    pub model: String,
+    /// Field receiving the extract text
+    pub text_field: String,
+    /// Field receiving the source link and sequence number
+    pub source_field: String,
+    /// Soft size cap per extract, in characters; a single sentence
+    /// longer than this is kept whole rather than cut
+    pub max_chars_per_extract: usize,
+    /// Title of the article
+    pub source_title: String,
+    /// Where the article came from
+    pub source_url: Option<String>,
+    /// How the article is cut
+    pub split_strategy: SplitStrategy,
+    /// Import even if extracts with the same source slug exist
+    pub force: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            deck: "Reading".to_string(),
+            model: "Basic".to_string(),
+            text_field: "Front".to_string(),
+            source_field: "Back".to_string(),
+            max_chars_per_extract: 1000,
+            source_title: String::new(),
+            source_url: None,
+            split_strategy: SplitStrategy::default(),
+            force: false,
+        }
+    }
+}
+
+/// Result of [`create_extracts`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtractReport {
+    /// Slug used in the `source::<slug>` tag
+    pub slug: String,
+    /// IDs of the created notes in reading order (`None` if rejected)
+    pub note_ids: Vec<Option<u64>>,
+}
+
+/// Splits `article_text` into extract notes and adds them in order.
+///
+/// Fails without adding anything when notes tagged with the same
+/// source slug already exist, unless `options.force` is set.
+pub async fn create_extracts(
+    anki_client: &AnkiClient,
+    article_text: &str,
+    options: ExtractOptions,
+) -> Result<ExtractReport> {
+    let slug = source_slug(&options);
+    anyhow::ensure!(
+        !slug.is_empty(),
+        "Extracts need a source title or URL"
+    );
+    let tag = format!("source::{}", slug);
+
+    if !options.force {
+        let existing = anki_client
+            .find_notes(&format!("\"tag:{}\"", tag))
+            .await?;
+        anyhow::ensure!(
+            existing.is_empty(),
+            "Source '{}' was already imported ({} notes); set force to import again",
+            slug,
+            existing.len()
+        );
+    }
+
+    let extracts = split_extracts(
+        article_text,
+        options.max_chars_per_extract,
+        options.split_strategy,
+    );
+    let total = extracts.len();
+    let notes = extracts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| Note {
+            model_name: options.model.clone(),
+            deck_name: options.deck.clone(),
+            fields: NoteFields::from([
+                (
+                    options.text_field.clone(),
+                    extract_html(text),
+                ),
+                (
+                    options.source_field.clone(),
+                    source_html(&options, index + 1, total),
+                ),
+            ]),
+            tags: vec![tag.clone()],
+            audio: None,
+            picture: None,
+            video: None,
+            options: None,
+        })
+        .collect::<Vec<_>>();
+
+    let note_ids = if notes.is_empty() {
+        Vec::new()
+    } else {
+        anki_client.add_notes(notes).await?
+    };
+    Ok(ExtractReport { slug, note_ids })
+}
+
+/// Cuts text into extracts of at most `max_chars` characters.
+///
+/// Sentences are never split; one that is longer than `max_chars`
+/// becomes an extract of its own.
+pub fn split_extracts(
+    text: &str,
+    max_chars: usize,
+    strategy: SplitStrategy,
+) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut packer = Packer::new(max_chars);
+
+    for paragraph in split_paragraphs(text) {
+        let fits = char_len(paragraph) <= max_chars;
+        if strategy == SplitStrategy::Paragraph && fits {
+            packer.push_paragraph(paragraph);
+            continue;
+        }
+        if strategy == SplitStrategy::Paragraph {
+            packer.flush();
+        }
+        for (i, (start, end)) in sentence_spans(paragraph)
+            .into_iter()
+            .enumerate()
+        {
+            packer.push_sentence(
+                &paragraph[start..end],
+                i == 0,
+            );
+        }
+        if strategy == SplitStrategy::Paragraph {
+            packer.flush();
+        }
+    }
+    packer.finish()
+}
+
+/// Greedy accumulator behind [`split_extracts`]
+struct Packer {
+    max_chars: usize,
+    current: String,
+    extracts: Vec<String>,
+}
+
+impl Packer {
+    fn new(max_chars: usize) -> Self {
+        Self {
+            max_chars,
+            current: String::new(),
+            extracts: Vec::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.current.is_empty() {
+            self.extracts
+                .push(std::mem::take(&mut self.current));
+        }
+    }
+
+    fn push(&mut self, piece: &str, separator: &str) {
+        if !self.current.is_empty()
+            && char_len(&self.current)
+                + char_len(separator)
+                + char_len(piece)
+                > self.max_chars
+        {
+            self.flush();
+        }
+        if !self.current.is_empty() {
+            self.current.push_str(separator);
+        }
+        self.current.push_str(piece);
+    }
+
+    fn push_paragraph(&mut self, paragraph: &str) {
+        self.push(paragraph, "\n\n");
+    }
+
+    fn push_sentence(
+        &mut self,
+        sentence: &str,
+        starts_paragraph: bool,
+    ) {
+        let separator = if starts_paragraph {
+            "\n\n"
+        } else if is_cjk_boundary(&self.current, sentence) {
+            ""
+        } else {
+            " "
+        };
+        self.push(sentence, separator);
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush();
+        self.extracts
+    }
+}
+
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// CJK sentences are joined without a space
+fn is_cjk_boundary(previous: &str, next: &str) -> bool {
+    let is_cjk = |c: char| {
+        matches!(c as u32,
+            0x3000..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF
+            | 0xAC00..=0xD7AF | 0xFF00..=0xFFEF)
+    };
+    previous.chars().next_back().is_some_and(is_cjk)
+        || next.chars().next().is_some_and(is_cjk)
+}
+
+/// Tag-safe slug of the source title (or URL when untitled)
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+fn source_slug(options: &ExtractOptions) -> String {
+    let title = slugify(&options.source_title);
+    if !title.is_empty() {
+        return title;
+    }
+    options
+        .source_url
+        .as_deref()
+        .map(|url| {
+            let url = url
+                .trim_start_matches("https://")
+                .trim_start_matches("http://");
+            slugify(url)
+        })
+        .unwrap_or_default()
+}
+
+/// Escapes an extract and keeps its line structure
+fn extract_html(text: &str) -> String {
+    escape_html(text).replace('\n', "<br>")
+}
+
+/// Source line such as `<a href="...">Title</a> (3/12)`
+fn source_html(
+    options: &ExtractOptions,
+    sequence: usize,
+    total: usize,
+) -> String {
+    let title = if options.source_title.is_empty() {
+        options.source_url.clone().unwrap_or_default()
+    } else {
+        options.source_title.clone()
+    };
+    let title = match &options.source_url {
+        Some(url) => format!(
+            "<a href=\"{}\">{}</a>",
+            escape_html(url),
+            escape_html(&title)
+        ),
+        None => escape_html(&title),
+    };
+    format!("{} ({}/{})", title, sequence, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use serde_json::{Value, json};
+
+    #[test]
+    fn test_small_paragraphs_are_packed() {
+        let text = "One. Two.\n\nThree.\n\nFour is longer.";
+        assert_eq!(
+            split_extracts(
+                text,
+                20,
+                SplitStrategy::Paragraph
+            ),
+            vec!["One. Two.\n\nThree.", "Four is longer."]
+        );
+    }
+
+    #[test]
+    fn test_huge_paragraph_is_cut_at_sentences() {
+        let text = "Alpha beta. Gamma delta. Epsilon zeta. Eta.\n\nTail.";
+        assert_eq!(
+            split_extracts(
+                text,
+                25,
+                SplitStrategy::Paragraph
+            ),
+            vec![
+                "Alpha beta. Gamma delta.",
+                "Epsilon zeta. Eta.",
+                "Tail."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oversized_sentence_is_never_cut() {
+        let text = "Short. This single sentence is far longer than the cap. End.";
+        let extracts = split_extracts(
+            text,
+            10,
+            SplitStrategy::Sentence,
+        );
+        assert_eq!(
+            extracts,
+            vec![
+                "Short.",
+                "This single sentence is far longer than the cap.",
+                "End."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cjk_without_spaces() {
+        let text = "今日は晴れです。明日は雨です。明後日は雪です。";
+        assert_eq!(
+            split_extracts(
+                text,
+                16,
+                SplitStrategy::Paragraph
+            ),
+            vec![
+                "今日は晴れです。明日は雨です。",
+                "明後日は雪です。"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trailing_fragment_is_kept() {
+        let text = "First sentence. Second sentence. and a fragment";
+        assert_eq!(
+            split_extracts(
+                text,
+                35,
+                SplitStrategy::Sentence
+            ),
+            vec![
+                "First sentence. Second sentence.",
+                "and a fragment"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sentence_strategy_crosses_paragraphs() {
+        let text = "A one.\n\nB two. B three.";
+        assert_eq!(
+            split_extracts(
+                text,
+                14,
+                SplitStrategy::Sentence
+            ),
+            vec!["A one.\n\nB two.", "B three."]
+        );
+        assert!(
+            split_extracts(
+                "  \n\n ",
+                10,
+                SplitStrategy::Sentence
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(
+            slugify("  The Art of War: Ch. 1 "),
+            "the-art-of-war-ch-1"
+        );
+        assert_eq!(
+            slugify("日本語 の 記事"),
+            "日本語-の-記事"
+        );
+    }
+
+    fn options() -> ExtractOptions {
+        ExtractOptions {
+            max_chars_per_extract: 12,
+            source_title: "Tom & Jerry".to_string(),
+            source_url: Some(
+                "https://example.com/a?b=1&c=2".to_string(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_extracts_adds_notes_in_order() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "findNotes" => ok(json!([])),
+                    "addNotes" => {
+                        let count = params["notes"]
+                            .as_array()
+                            .unwrap()
+                            .len();
+                        ok(Value::Array(
+                            (1..=count as u64)
+                                .map(|id| json!(id))
+                                .collect(),
+                        ))
+                    }
+                    _ => ok(Value::Null),
+                },
+            )
+            .await;
+
+        let report = create_extracts(
+            &mock.client(),
+            "<b>Hi</b> there.\n\nBye now.",
+            options(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.slug, "tom-jerry");
+        assert_eq!(report.note_ids, vec![Some(1), Some(2)]);
+
+        let requests = mock.requests();
+        assert_eq!(
+            requests[0]["params"]["query"],
+            "\"tag:source::tom-jerry\""
+        );
+        let notes = &requests[1]["params"]["notes"];
+        assert_eq!(
+            notes[0]["fields"]["Front"],
+            "&lt;b&gt;Hi&lt;/b&gt; there."
+        );
+        assert_eq!(
+            notes[1]["fields"]["Back"],
+            "<a href=\"https://example.com/a?b=1&amp;c=2\">Tom &amp; Jerry</a> (2/2)"
+        );
+        assert_eq!(
+            notes[1]["tags"],
+            json!(["source::tom-jerry"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reimport_is_refused_unless_forced() {
+        let mock =
+            MockAnki::start(|action, _| match action {
+                "findNotes" => ok(json!([5, 6])),
+                "addNotes" => ok(json!([7])),
+                _ => ok(Value::Null),
+            })
+            .await;
+
+        let refused = create_extracts(
+            &mock.client(),
+            "Text.",
+            options(),
+        )
+        .await;
+        assert!(
+            refused
+                .unwrap_err()
+                .to_string()
+                .contains("already imported")
+        );
+        assert_eq!(mock.actions(), vec!["findNotes"]);
+
+        let forced = ExtractOptions {
+            force: true,
+            ..options()
+        };
+        let report = create_extracts(
+            &mock.client(),
+            "Text.",
+            forced,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.note_ids, vec![Some(7)]);
+        assert_eq!(
+            mock.actions(),
+            vec!["findNotes", "addNotes"]
+        );
+    }
+}
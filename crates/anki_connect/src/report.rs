@@ -0,0 +1,4 @@
+//! Collection statistics computed from card and review data
+pub mod heatmap;
+pub mod maturity;
+pub mod retention;
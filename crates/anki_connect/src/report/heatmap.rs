@@ -0,0 +1,557 @@
+//! Answers per calendar day, the data behind a GitHub-style
+//! heatmap.
+//!
+//! The collection-wide counts come from `getNumCardsReviewedByDay`,
+//! which Anki-Connect already groups by study day; per-deck counts
+//! are read from `cardReviews` and grouped here. Every logged answer
+//! counts, learning and manual entries included, so both sources
+//! agree. Days with no answers are present with a count of zero.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::Result;
+use chrono::{
+    Datelike, Local, Months, NaiveDate, TimeZone,
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use super::retention::{
+    Bucket, DEFAULT_ROLLOVER_HOUR, review_id_before,
+    study_day,
+};
+use crate::anki::client::{AnkiClient, CardReview};
+
+/// Side of one day's square in the SVG, in pixels
+const CELL_SIZE: i64 = 10;
+
+/// Distance between the corners of neighbouring squares
+const CELL_PITCH: i64 = 12;
+
+/// Fill colours from no answers to the busiest days
+const COLORS: [&str; 5] =
+    ["#ebedf0", "#9be9a8", "#40c463", "#30a14e", "#216e39"];
+
+/// What [`heatmap_data`] counts
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeatmapQuery {
+    /// Decks to count separately; empty for the collection total
+    /// only. As with `cardReviews`, a deck's count leaves out its
+    /// subdecks.
+    pub decks: Vec<String>,
+    /// First study day, inclusive
+    pub since: NaiveDate,
+    /// Last study day, inclusive
+    pub until: NaiveDate,
+    /// Hour at which a new study day starts; should match the
+    /// collection's setting, which the collection total always uses
+    pub rollover_hour: u32,
+}
+
+impl HeatmapQuery {
+    /// Query using Anki's default rollover
+    pub fn new(
+        decks: Vec<String>,
+        since: NaiveDate,
+        until: NaiveDate,
+    ) -> Self {
+        Self {
+            decks,
+            since,
+            until,
+            rollover_hour: DEFAULT_ROLLOVER_HOUR,
+        }
+    }
+
+    /// The year up to and including `today`, e.g. 2023-03-13 to
+    /// 2024-03-12
+    pub fn past_year(
+        decks: Vec<String>,
+        today: NaiveDate,
+    ) -> Self {
+        let since = today
+            .checked_sub_months(Months::new(12))
+            .and_then(|day| day.succ_opt())
+            .unwrap_or(today);
+        Self::new(decks, since, today)
+    }
+
+    /// Every day of the query with a count of zero
+    fn empty_days(&self) -> BTreeMap<NaiveDate, u64> {
+        let mut days = BTreeMap::new();
+        let mut day = Some(self.since);
+        while let Some(current) =
+            day.filter(|d| *d <= self.until)
+        {
+            days.insert(current, 0);
+            day = current.succ_opt();
+        }
+        days
+    }
+}
+
+/// Result of [`heatmap_data`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Heatmap {
+    /// First study day
+    pub since: NaiveDate,
+    /// Last study day
+    pub until: NaiveDate,
+    /// Answers per day of each requested deck
+    pub decks: BTreeMap<String, BTreeMap<NaiveDate, u64>>,
+    /// Answers per day in the whole collection
+    pub total: BTreeMap<NaiveDate, u64>,
+}
+
+impl Heatmap {
+    /// The heatmap as JSON, days keyed by `YYYY-MM-DD`:
+    /// `{"since", "until", "total": {day: n}, "decks": {deck: {day: n}}}`
+    pub fn to_json(&self) -> Value {
+        json!({
+            "since": self.since,
+            "until": self.until,
+            "total": self.total,
+            "decks": self.decks,
+        })
+    }
+
+    /// Renders one heatmap as a standalone SVG image: a column per
+    /// week (Monday on top), with each day's count in its tooltip.
+    ///
+    /// # Arguments
+    /// * `deck` - Deck to draw, `None` for the collection total
+    ///
+    /// # Returns
+    /// `None` if `deck` was not part of the query.
+    pub fn to_svg(
+        &self,
+        deck: Option<&str>,
+    ) -> Option<String> {
+        let days = match deck {
+            Some(deck) => self.decks.get(deck)?,
+            None => &self.total,
+        };
+        let first = Bucket::Week.start(self.since);
+        let column =
+            |day: NaiveDate| (day - first).num_days() / 7;
+        let weeks = column(self.until).max(0) + 1;
+        let width =
+            weeks * CELL_PITCH - (CELL_PITCH - CELL_SIZE);
+        let height =
+            7 * CELL_PITCH - (CELL_PITCH - CELL_SIZE);
+        let max = days.values().copied().max().unwrap_or(0);
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+            width, height
+        );
+        for (day, count) in days {
+            let x = column(*day) * CELL_PITCH;
+            let y = i64::from(
+                day.weekday().num_days_from_monday(),
+            ) * CELL_PITCH;
+            let fill = COLORS[level(*count, max)];
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{x}\" y=\"{y}\" width=\"{CELL_SIZE}\" height=\"{CELL_SIZE}\" rx=\"2\" fill=\"{fill}\"><title>{day}: {count} answers</title></rect>",
+            );
+        }
+        svg.push_str("</svg>\n");
+        Some(svg)
+    }
+}
+
+/// Colour level of `count` on a scale up to `max`: `0` for no
+/// answers, then `1` to `4` in equal steps
+fn level(count: u64, max: u64) -> usize {
+    if count == 0 || max == 0 {
+        return 0;
+    }
+    (count * 4).div_ceil(max).clamp(1, 4) as usize
+}
+
+/// Counts review log entries per study day of the query, zero-filled.
+///
+/// # Arguments
+/// * `tz` - Time zone the rollover hour applies in
+pub fn daily_counts<Tz: TimeZone>(
+    query: &HeatmapQuery,
+    reviews: &[CardReview],
+    tz: &Tz,
+) -> BTreeMap<NaiveDate, u64> {
+    let mut days = query.empty_days();
+    for review in reviews {
+        let day = study_day(
+            review.review_time,
+            query.rollover_hour,
+            tz,
+        );
+        if let Some(count) =
+            day.and_then(|d| days.get_mut(&d))
+        {
+            *count += 1;
+        }
+    }
+    days
+}
+
+/// Zero-filled counts from the `("YYYY-MM-DD", count)` rows of
+/// [`AnkiClient::num_cards_reviewed_by_day`]; rows outside the
+/// query or with malformed dates are skipped
+pub fn collection_counts(
+    query: &HeatmapQuery,
+    rows: &[(String, u64)],
+) -> BTreeMap<NaiveDate, u64> {
+    let mut days = query.empty_days();
+    for (day, count) in rows {
+        let day =
+            NaiveDate::parse_from_str(day, "%Y-%m-%d");
+        if let Some(total) =
+            day.ok().and_then(|d| days.get_mut(&d))
+        {
+            *total += count;
+        }
+    }
+    days
+}
+
+/// Answers per study day for the whole collection and each deck of
+/// the query.
+///
+/// The collection total is a single `getNumCardsReviewedByDay`
+/// request. Each deck costs one `cardReviews` request, bucketed in
+/// the local time zone with the query's rollover hour.
+pub async fn heatmap_data(
+    anki_client: &AnkiClient,
+    query: HeatmapQuery,
+) -> Result<Heatmap> {
+    let rows =
+        anki_client.num_cards_reviewed_by_day().await?;
+    let total = collection_counts(&query, &rows);
+
+    let start =
+        review_id_before(query.since, query.rollover_hour);
+    let mut decks = BTreeMap::new();
+    for deck in &query.decks {
+        let reviews =
+            anki_client.card_reviews(deck, start).await?;
+        decks.insert(
+            deck.clone(),
+            daily_counts(&query, &reviews, &Local),
+        );
+    }
+
+    Ok(Heatmap {
+        since: query.since,
+        until: query.until,
+        decks,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, err, ok};
+    use chrono::{FixedOffset, Utc};
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    /// Review log entry at the given UTC time
+    fn at(
+        date: NaiveDate,
+        hour: u32,
+        minute: u32,
+        second: u32,
+    ) -> CardReview {
+        CardReview {
+            review_time: date
+                .and_hms_opt(hour, minute, second)
+                .unwrap()
+                .and_utc()
+                .timestamp_millis(),
+            card_id: 1,
+            usn: -1,
+            button: 3,
+            interval: 1,
+            last_interval: 0,
+            factor: 2500,
+            duration: 5000,
+            review_type: 0,
+        }
+    }
+
+    fn query(rollover_hour: u32) -> HeatmapQuery {
+        HeatmapQuery {
+            rollover_hour,
+            ..HeatmapQuery::new(
+                vec!["Words".to_string()],
+                day(2024, 3, 9),
+                day(2024, 3, 11),
+            )
+        }
+    }
+
+    fn counts(
+        days: &BTreeMap<NaiveDate, u64>,
+    ) -> Vec<(NaiveDate, u64)> {
+        days.iter().map(|(d, n)| (*d, *n)).collect()
+    }
+
+    #[test]
+    fn test_rollover_east_of_utc() {
+        let beijing =
+            FixedOffset::east_opt(8 * 3600).unwrap();
+        let reviews = [
+            // 23:59:59 on the 9th in Beijing
+            at(day(2024, 3, 9), 15, 59, 59),
+            // 00:00:00 on the 10th
+            at(day(2024, 3, 9), 16, 0, 0),
+            // 03:59:59 on the 10th
+            at(day(2024, 3, 9), 19, 59, 59),
+            // 04:00:00 on the 10th
+            at(day(2024, 3, 9), 20, 0, 0),
+        ];
+
+        assert_eq!(
+            counts(&daily_counts(
+                &query(4),
+                &reviews,
+                &beijing
+            )),
+            [
+                (day(2024, 3, 9), 3),
+                (day(2024, 3, 10), 1),
+                (day(2024, 3, 11), 0),
+            ]
+        );
+        assert_eq!(
+            counts(&daily_counts(
+                &query(0),
+                &reviews,
+                &beijing
+            )),
+            [
+                (day(2024, 3, 9), 1),
+                (day(2024, 3, 10), 3),
+                (day(2024, 3, 11), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rollover_west_of_utc() {
+        let new_york =
+            FixedOffset::west_opt(5 * 3600).unwrap();
+        let reviews = [
+            // 23:30 on the 8th in New York: before the query
+            at(day(2024, 3, 9), 4, 30, 0),
+            // 00:30 on the 9th: before the 4 a.m. rollover
+            at(day(2024, 3, 9), 5, 30, 0),
+            // 04:00 on the 9th
+            at(day(2024, 3, 9), 9, 0, 0),
+            // 23:59:59 on the 11th, 03:59:59 on the 12th
+            at(day(2024, 3, 12), 4, 59, 59),
+            at(day(2024, 3, 12), 8, 59, 59),
+            // 04:00 on the 12th: after the query
+            at(day(2024, 3, 12), 9, 0, 0),
+        ];
+
+        assert_eq!(
+            counts(&daily_counts(
+                &query(4),
+                &reviews,
+                &new_york
+            )),
+            [
+                (day(2024, 3, 9), 1),
+                (day(2024, 3, 10), 0),
+                (day(2024, 3, 11), 2),
+            ]
+        );
+        assert_eq!(
+            counts(&daily_counts(
+                &query(0),
+                &reviews,
+                &new_york
+            )),
+            [
+                (day(2024, 3, 9), 2),
+                (day(2024, 3, 10), 0),
+                (day(2024, 3, 11), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collection_rows_are_zero_filled() {
+        let rows = [
+            ("2024-03-12".to_string(), 7),
+            ("2024-03-11".to_string(), 5),
+            ("2024-03-09".to_string(), 2),
+            ("03/10/2024".to_string(), 9),
+            ("2024-03-08".to_string(), 4),
+        ];
+        assert_eq!(
+            counts(&collection_counts(&query(4), &rows)),
+            [
+                (day(2024, 3, 9), 2),
+                (day(2024, 3, 10), 0),
+                (day(2024, 3, 11), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_past_year() {
+        let query = HeatmapQuery::past_year(
+            Vec::new(),
+            day(2024, 3, 12),
+        );
+        assert_eq!(query.since, day(2023, 3, 13));
+        assert_eq!(query.until, day(2024, 3, 12));
+        assert_eq!(query.empty_days().len(), 366);
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(level(0, 0), 0);
+        assert_eq!(level(0, 8), 0);
+        assert_eq!(level(1, 8), 1);
+        assert_eq!(level(2, 8), 1);
+        assert_eq!(level(3, 8), 2);
+        assert_eq!(level(6, 8), 3);
+        assert_eq!(level(8, 8), 4);
+    }
+
+    #[test]
+    fn test_json_and_svg() {
+        let since = day(2024, 3, 10);
+        let until = day(2024, 3, 11);
+        let words =
+            BTreeMap::from([(since, 1), (until, 0)]);
+        let heatmap = Heatmap {
+            since,
+            until,
+            decks: BTreeMap::from([(
+                "Words".to_string(),
+                words.clone(),
+            )]),
+            total: BTreeMap::from([(since, 4), (until, 2)]),
+        };
+
+        assert_eq!(
+            heatmap.to_json(),
+            json!({
+                "since": "2024-03-10",
+                "until": "2024-03-11",
+                "total": {"2024-03-10": 4, "2024-03-11": 2},
+                "decks": {
+                    "Words": {"2024-03-10": 1, "2024-03-11": 0},
+                },
+            })
+        );
+
+        // Sunday the 10th ends the first week, Monday the 11th
+        // starts the second
+        assert_eq!(
+            heatmap.to_svg(None).unwrap(),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"22\" height=\"82\" viewBox=\"0 0 22 82\">\n\
+             <rect x=\"0\" y=\"72\" width=\"10\" height=\"10\" rx=\"2\" fill=\"#216e39\"><title>2024-03-10: 4 answers</title></rect>\n\
+             <rect x=\"12\" y=\"0\" width=\"10\" height=\"10\" rx=\"2\" fill=\"#40c463\"><title>2024-03-11: 2 answers</title></rect>\n\
+             </svg>\n"
+        );
+        let words = heatmap.to_svg(Some("Words")).unwrap();
+        assert!(words.contains(
+            "fill=\"#ebedf0\"><title>2024-03-11: 0 answers"
+        ));
+        assert_eq!(heatmap.to_svg(Some("Kanji")), None);
+    }
+
+    #[tokio::test]
+    async fn test_heatmap_data_combines_both_sources() {
+        let today = Utc::now().date_naive();
+        let noon = today
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            .and_utc()
+            .timestamp_millis();
+        let yesterday = today.pred_opt().unwrap();
+        let rows = json!([
+            [today.to_string(), 3],
+            [yesterday.to_string(), 1],
+        ]);
+        let mock =
+            MockAnki::start(move |action, params| {
+                match action {
+                    "getNumCardsReviewedByDay" => {
+                        ok(rows.clone())
+                    }
+                    "cardReviews"
+                        if params["deck"] == "Words" =>
+                    {
+                        ok(json!([
+                            [
+                                noon, 1, -1, 3, 1, 0, 2500,
+                                4000, 0
+                            ],
+                            [
+                                noon + 1,
+                                2,
+                                -1,
+                                3,
+                                1,
+                                0,
+                                2500,
+                                4000,
+                                0
+                            ],
+                        ]))
+                    }
+                    "cardReviews" => ok(json!([])),
+                    _ => err("unsupported action"),
+                }
+            })
+            .await;
+
+        let query = HeatmapQuery::new(
+            vec!["Words".to_string(), "Kanji".to_string()],
+            yesterday.pred_opt().unwrap(),
+            today.succ_opt().unwrap(),
+        );
+        let heatmap = heatmap_data(&mock.client(), query)
+            .await
+            .unwrap();
+
+        assert_eq!(heatmap.total.len(), 4);
+        assert_eq!(heatmap.total[&today], 3);
+        assert_eq!(heatmap.total[&yesterday], 1);
+        assert_eq!(heatmap.decks["Words"].len(), 4);
+        // Which study day noon UTC falls on depends on the local
+        // time zone, but both answers land on the same one
+        assert_eq!(
+            heatmap.decks["Words"].values().max(),
+            Some(&2)
+        );
+        assert_eq!(
+            heatmap.decks["Kanji"].values().sum::<u64>(),
+            0
+        );
+        assert_eq!(
+            mock.actions(),
+            [
+                "getNumCardsReviewedByDay",
+                "cardReviews",
+                "cardReviews"
+            ]
+        );
+        assert!(
+            mock.requests()[1]["params"]
+                .get("startID")
+                .is_some()
+        );
+    }
+}
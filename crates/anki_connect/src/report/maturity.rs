@@ -0,0 +1,369 @@
+//! How far the cards of each deck have progressed.
+//!
+//! Anki's deck list shows due counts only; [`deck_maturity_report`]
+//! breaks every deck down into new, learning, young, mature and
+//! suspended cards, the way Anki's statistics screen does.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::anki::client::{
+    AnkiClient, CardInfo, CardQueue, CardType,
+    deck_only_query, deck_query,
+};
+
+/// Interval in days from which a review card counts as mature
+pub const MATURE_INTERVAL_DAYS: i64 = 21;
+
+/// Progress stage of a card
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Maturity {
+    New,
+    /// Learning or relearning
+    Learning,
+    /// Review card with an interval below 21 days
+    Young,
+    /// Review card with an interval of 21 days or more
+    Mature,
+    /// Suspended, whatever its stage
+    Suspended,
+}
+
+/// Card counts of one deck
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeckMaturity {
+    /// Deck name
+    pub deck: String,
+    /// Number of cards
+    pub total: usize,
+    pub new: usize,
+    pub learning: usize,
+    pub young: usize,
+    pub mature: usize,
+    pub suspended: usize,
+    /// Mean ease of cards past their first review, e.g. `2.5`
+    pub average_ease: Option<f64>,
+    /// Mean interval in days of cards past their first review
+    pub average_interval: Option<f64>,
+}
+
+/// Classifies a card by its queue, type and interval.
+///
+/// Suspension wins over every other stage. Cards in filtered decks
+/// keep the type and interval of their home deck, so they are
+/// classified the same way; buried cards are classified as if they
+/// were not buried.
+pub fn classify(card: &CardInfo) -> Maturity {
+    if card.queue_kind() == CardQueue::Suspended {
+        return Maturity::Suspended;
+    }
+    match card.kind() {
+        CardType::New => Maturity::New,
+        CardType::Learning | CardType::Relearning => {
+            Maturity::Learning
+        }
+        CardType::Review
+            if card.interval >= MATURE_INTERVAL_DAYS =>
+        {
+            Maturity::Mature
+        }
+        CardType::Review => Maturity::Young,
+        // Unknown types are treated by their queue
+        CardType::Other(_) => match card.queue_kind() {
+            CardQueue::New => Maturity::New,
+            CardQueue::Learning
+            | CardQueue::DayLearning => Maturity::Learning,
+            _ if card.interval >= MATURE_INTERVAL_DAYS => {
+                Maturity::Mature
+            }
+            _ => Maturity::Young,
+        },
+    }
+}
+
+/// Counts the given cards as the breakdown of `deck`
+pub fn summarize(
+    deck: &str,
+    cards: &[CardInfo],
+) -> DeckMaturity {
+    let mut report = DeckMaturity {
+        deck: deck.to_string(),
+        total: cards.len(),
+        ..Default::default()
+    };
+    let mut ease_sum = 0.0;
+    let mut ease_count = 0;
+    let mut interval_sum = 0.0;
+    let mut interval_count = 0;
+    for card in cards {
+        match classify(card) {
+            Maturity::New => report.new += 1,
+            Maturity::Learning => report.learning += 1,
+            Maturity::Young => report.young += 1,
+            Maturity::Mature => report.mature += 1,
+            Maturity::Suspended => report.suspended += 1,
+        }
+        let reviewed = matches!(
+            card.kind(),
+            CardType::Review | CardType::Relearning
+        );
+        if reviewed && card.factor > 0 {
+            ease_sum += f64::from(card.factor) / 1000.0;
+            ease_count += 1;
+        }
+        if reviewed && card.interval > 0 {
+            interval_sum += card.interval as f64;
+            interval_count += 1;
+        }
+    }
+    report.average_ease = (ease_count > 0)
+        .then(|| ease_sum / ease_count as f64);
+    report.average_interval = (interval_count > 0)
+        .then(|| interval_sum / interval_count as f64);
+    report
+}
+
+/// Breaks each deck down by [`Maturity`].
+///
+/// # Arguments
+/// * `decks` - Decks to report on, `None` for every deck
+/// * `roll_up_subdecks` - Count the cards of subdecks towards their
+///   parents as well; otherwise each deck only counts its own cards
+///
+/// # Returns
+/// One entry per deck, in the order of `decks` (or Anki's deck
+/// order). Empty decks get an entry with all counts zero.
+pub async fn deck_maturity_report(
+    anki_client: &AnkiClient,
+    decks: Option<Vec<String>>,
+    roll_up_subdecks: bool,
+) -> Result<Vec<DeckMaturity>> {
+    let decks = match decks {
+        Some(decks) => decks,
+        None => anki_client.get_deck_names(None).await?,
+    };
+    let mut reports = Vec::with_capacity(decks.len());
+    for deck in &decks {
+        let query = if roll_up_subdecks {
+            deck_query(deck)
+        } else {
+            deck_only_query(deck)
+        };
+        let cards =
+            anki_client.find_cards_detailed(&query).await?;
+        reports.push(summarize(deck, &cards));
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use serde_json::json;
+
+    fn card(
+        card_type: u32,
+        queue: i32,
+        interval: i64,
+    ) -> CardInfo {
+        serde_json::from_value(json!({
+            "cardId": 1,
+            "note": 10,
+            "deckName": "Default",
+            "modelName": "Basic",
+            "ord": 0,
+            "type": card_type,
+            "queue": queue,
+            "due": 0,
+            "interval": interval,
+            "factor": 2500,
+            "reps": 3,
+            "lapses": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_classify_by_type_and_interval() {
+        assert_eq!(classify(&card(0, 0, 0)), Maturity::New);
+        assert_eq!(
+            classify(&card(1, 1, -600)),
+            Maturity::Learning
+        );
+        assert_eq!(
+            classify(&card(3, 3, 1)),
+            Maturity::Learning
+        );
+        assert_eq!(
+            classify(&card(2, 2, 20)),
+            Maturity::Young
+        );
+        assert_eq!(
+            classify(&card(2, 2, 21)),
+            Maturity::Mature
+        );
+    }
+
+    #[test]
+    fn test_classify_suspended_and_buried() {
+        assert_eq!(
+            classify(&card(2, -1, 90)),
+            Maturity::Suspended
+        );
+        assert_eq!(
+            classify(&card(0, -1, 0)),
+            Maturity::Suspended
+        );
+        assert_eq!(
+            classify(&card(2, -3, 30)),
+            Maturity::Mature
+        );
+        assert_eq!(
+            classify(&card(0, -2, 0)),
+            Maturity::New
+        );
+    }
+
+    #[test]
+    fn test_classify_filtered_deck_cards() {
+        // Rescheduling filtered deck: queue follows the type
+        let mut review = card(2, 2, 40);
+        review.deck_name = "Filtered Deck 1".to_string();
+        review.original_queue = 2;
+        assert_eq!(classify(&review), Maturity::Mature);
+        // Preview without rescheduling keeps the home type
+        let preview = card(0, 4, 0);
+        assert_eq!(classify(&preview), Maturity::New);
+        assert_eq!(
+            classify(&card(2, 4, 5)),
+            Maturity::Young
+        );
+    }
+
+    #[test]
+    fn test_summarize_counts_and_averages() {
+        let mut hard = card(2, 2, 30);
+        hard.factor = 1300;
+        let cards = vec![
+            card(0, 0, 0),
+            card(1, 1, -60),
+            card(2, 2, 10),
+            hard,
+            card(2, -1, 50),
+        ];
+        let report = summarize("Spanish", &cards);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "deck": "Spanish",
+                "total": 5,
+                "new": 1,
+                "learning": 1,
+                "young": 1,
+                "mature": 1,
+                "suspended": 1,
+                "average_ease": 2.1,
+                "average_interval": 30.0,
+            })
+        );
+        let empty = summarize("Empty", &[]);
+        assert_eq!(empty.average_ease, None);
+        assert_eq!(empty.average_interval, None);
+    }
+
+    #[tokio::test]
+    async fn test_report_rolls_up_subdecks_on_request() {
+        let mock =
+            MockAnki::start(
+                |action, params| match action {
+                    "deckNames" => {
+                        ok(json!(["Lang", "Lang::Verbs"]))
+                    }
+                    "findCards" => {
+                        let query = params["query"]
+                            .as_str()
+                            .unwrap();
+                        if query.starts_with(
+                            "deck:\"Lang::Verbs\"",
+                        ) {
+                            ok(json!([2]))
+                        } else if query.contains("-deck:") {
+                            ok(json!([1]))
+                        } else {
+                            ok(json!([1, 2]))
+                        }
+                    }
+                    "cardsInfo" => ok(json!(
+                        params["cards"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|id| {
+                                let id =
+                                    id.as_u64().unwrap();
+                                let deck = if id == 2 {
+                                    "Lang::Verbs"
+                                } else {
+                                    "Lang"
+                                };
+                                json!({
+                                    "cardId": id,
+                                    "note": id,
+                                    "deckName": deck,
+                                    "modelName": "Basic",
+                                    "ord": 0,
+                                    "type": 2,
+                                    "queue": 2,
+                                    "due": 0,
+                                    "interval": id * 20,
+                                    "factor": 2500,
+                                    "reps": 1,
+                                    "lapses": 0,
+                                })
+                            })
+                            .collect::<Vec<_>>()
+                    )),
+                    other => panic!(
+                        "unexpected action {}",
+                        other
+                    ),
+                },
+            )
+            .await;
+        let client = mock.client();
+
+        let own =
+            deck_maturity_report(&client, None, false)
+                .await
+                .unwrap();
+        assert_eq!(
+            own.iter()
+                .map(|r| (
+                    r.deck.as_str(),
+                    r.young,
+                    r.mature
+                ))
+                .collect::<Vec<_>>(),
+            vec![("Lang", 1, 0), ("Lang::Verbs", 0, 1)]
+        );
+
+        let rolled = deck_maturity_report(
+            &client,
+            Some(vec!["Lang".to_string()]),
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rolled.len(), 1);
+        assert_eq!(rolled[0].total, 2);
+        assert_eq!(rolled[0].average_interval, Some(30.0));
+        assert!(mock.requests().iter().any(|request| {
+            request["params"]["query"]
+                == "deck:\"Lang\" -deck:\"Lang::*\""
+        }));
+    }
+}
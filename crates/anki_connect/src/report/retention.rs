@@ -0,0 +1,607 @@
+//! True retention from the review log.
+//!
+//! Retention is the share of answers to cards in the review stage
+//! that were not "Again". Learning, relearning, filtered and manual
+//! entries are left out, as are reviews outside the queried days.
+//! Days follow Anki's scheduler: a day starts at the rollover hour
+//! (4 a.m. by default), not at midnight.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use anyhow::Result;
+use chrono::{
+    Datelike, Duration, Local, Months, NaiveDate, TimeZone,
+};
+use serde::Serialize;
+
+use crate::anki::client::{AnkiClient, CardReview};
+
+/// Hour at which Anki starts a new day unless configured otherwise
+pub const DEFAULT_ROLLOVER_HOUR: u32 = 4;
+
+/// Length of the periods reviews are grouped into
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Day,
+    /// Monday to Sunday
+    Week,
+    /// Calendar month
+    Month,
+}
+
+impl Bucket {
+    /// First day of the period containing `day`
+    pub fn start(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => day,
+            Bucket::Week => {
+                day - Duration::days(i64::from(
+                    day.weekday().num_days_from_monday(),
+                ))
+            }
+            Bucket::Month => day.with_day(1).unwrap_or(day),
+        }
+    }
+
+    /// First day of the period after the one starting at `start`
+    fn next(self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Bucket::Day => start.succ_opt(),
+            Bucket::Week => {
+                start.checked_add_signed(Duration::days(7))
+            }
+            Bucket::Month => {
+                start.checked_add_months(Months::new(1))
+            }
+        }
+    }
+}
+
+/// What [`retention_report`] reports on
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionQuery {
+    /// Deck whose review log is read
+    pub deck: String,
+    /// Tags to break the numbers down by; empty for no breakdown.
+    /// A tag also matches its child tags (`tag::child`).
+    pub tags: Vec<String>,
+    /// First study day, inclusive
+    pub since: NaiveDate,
+    /// Last study day, inclusive
+    pub until: NaiveDate,
+    /// Length of the reported periods
+    pub bucket: Bucket,
+    /// Hour at which a new study day starts, as in Anki's
+    /// preferences; values over 23 count as 23
+    pub rollover_hour: u32,
+}
+
+impl RetentionQuery {
+    /// Query without tag breakdown, using Anki's default rollover
+    pub fn new(
+        deck: impl Into<String>,
+        since: NaiveDate,
+        until: NaiveDate,
+        bucket: Bucket,
+    ) -> Self {
+        Self {
+            deck: deck.into(),
+            tags: Vec::new(),
+            since,
+            until,
+            bucket,
+            rollover_hour: DEFAULT_ROLLOVER_HOUR,
+        }
+    }
+}
+
+/// Review-stage answers within one period
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetentionStats {
+    /// First day of the period
+    pub period: NaiveDate,
+    /// Number of review-stage answers
+    pub reviews: usize,
+    /// Answers other than "Again"
+    pub passed: usize,
+    /// `passed / reviews`, `None` without reviews
+    pub retention: Option<f64>,
+    /// Mean time spent answering, in seconds
+    pub average_answer_seconds: Option<f64>,
+}
+
+/// Result of [`retention_report`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetentionReport {
+    /// Deck the log was read from
+    pub deck: String,
+    /// Length of the periods
+    pub bucket: Bucket,
+    /// Every period from `since` to `until`, oldest first
+    pub periods: Vec<RetentionStats>,
+    /// The same periods per requested tag
+    pub by_tag: BTreeMap<String, Vec<RetentionStats>>,
+}
+
+impl RetentionReport {
+    /// Renders the report as CSV with a header row.
+    ///
+    /// Deck-wide rows have an empty `tag` column; missing values are
+    /// empty cells.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "deck,tag,period,reviews,passed,retention,average_answer_seconds\n",
+        );
+        let rows = std::iter::once(("", &self.periods))
+            .chain(self.by_tag.iter().map(
+                |(tag, periods)| (tag.as_str(), periods),
+            ));
+        for (tag, periods) in rows {
+            for stats in periods {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{},{},{},{},{}",
+                    csv_field(&self.deck),
+                    csv_field(tag),
+                    stats.period,
+                    stats.reviews,
+                    stats.passed,
+                    optional(stats.retention),
+                    optional(stats.average_answer_seconds),
+                );
+            }
+        }
+        csv
+    }
+}
+
+/// Quotes a CSV field when it contains a separator or quote
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn optional(value: Option<f64>) -> String {
+    value.map(|v| format!("{:.4}", v)).unwrap_or_default()
+}
+
+/// Study day of a review logged at `review_time` (milliseconds).
+///
+/// Anki counts reviews before the rollover hour towards the
+/// previous day.
+pub fn study_day<Tz: TimeZone>(
+    review_time: i64,
+    rollover_hour: u32,
+    tz: &Tz,
+) -> Option<NaiveDate> {
+    let local =
+        tz.timestamp_millis_opt(review_time).single()?;
+    let shifted = local.naive_local()
+        - Duration::hours(i64::from(rollover_hour.min(23)));
+    Some(shifted.date())
+}
+
+/// Review ID just before study day `day` starts in the local time
+/// zone, for the `start_id` of [`AnkiClient::card_reviews`]
+pub(crate) fn review_id_before(
+    day: NaiveDate,
+    rollover_hour: u32,
+) -> i64 {
+    day.and_hms_opt(rollover_hour.min(23), 0, 0)
+        .and_then(|start| {
+            Local.from_local_datetime(&start).earliest()
+        })
+        .map(|start| start.timestamp_millis() - 1)
+        .unwrap_or(0)
+}
+
+/// Computes the report from review log entries.
+///
+/// # Arguments
+/// * `card_tags` - Tags of the note of each reviewed card; only
+///   needed when the query asks for a tag breakdown
+/// * `tz` - Time zone the rollover hour applies in
+pub fn build_report<Tz: TimeZone>(
+    query: &RetentionQuery,
+    reviews: &[CardReview],
+    card_tags: &HashMap<u64, Vec<String>>,
+    tz: &Tz,
+) -> RetentionReport {
+    let dated: Vec<(NaiveDate, &CardReview)> = reviews
+        .iter()
+        .filter(|review| review.is_review())
+        .filter_map(|review| {
+            let day = study_day(
+                review.review_time,
+                query.rollover_hour,
+                tz,
+            )?;
+            (query.since <= day && day <= query.until)
+                .then_some((day, review))
+        })
+        .collect();
+
+    let by_tag = query
+        .tags
+        .iter()
+        .map(|tag| {
+            let tagged: Vec<(NaiveDate, &CardReview)> =
+                dated
+                    .iter()
+                    .filter(|(_, review)| {
+                        card_tags
+                            .get(&review.card_id)
+                            .is_some_and(|tags| {
+                                has_tag(tags, tag)
+                            })
+                    })
+                    .copied()
+                    .collect();
+            (tag.clone(), bucketize(query, &tagged))
+        })
+        .collect();
+
+    RetentionReport {
+        deck: query.deck.clone(),
+        bucket: query.bucket,
+        periods: bucketize(query, &dated),
+        by_tag,
+    }
+}
+
+/// Whether `tags` contain `wanted` or one of its children,
+/// ignoring case like Anki does
+fn has_tag(tags: &[String], wanted: &str) -> bool {
+    let wanted = wanted.to_lowercase();
+    let child = format!("{}::", wanted);
+    tags.iter().any(|tag| {
+        let tag = tag.to_lowercase();
+        tag == wanted || tag.starts_with(&child)
+    })
+}
+
+/// Stats for every period of the query, empty ones included
+fn bucketize(
+    query: &RetentionQuery,
+    reviews: &[(NaiveDate, &CardReview)],
+) -> Vec<RetentionStats> {
+    let mut grouped: BTreeMap<NaiveDate, Vec<&CardReview>> =
+        BTreeMap::new();
+    for (day, review) in reviews {
+        grouped
+            .entry(query.bucket.start(*day))
+            .or_default()
+            .push(review);
+    }
+
+    let mut periods = Vec::new();
+    let mut period = Some(query.bucket.start(query.since));
+    while let Some(start) =
+        period.filter(|p| *p <= query.until)
+    {
+        let entries = grouped
+            .get(&start)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        periods.push(stats(start, entries));
+        period = query.bucket.next(start);
+    }
+    periods
+}
+
+fn stats(
+    period: NaiveDate,
+    reviews: &[&CardReview],
+) -> RetentionStats {
+    let count = reviews.len();
+    let passed =
+        reviews.iter().filter(|r| !r.is_again()).count();
+    let duration: u64 =
+        reviews.iter().map(|r| r.duration).sum();
+    RetentionStats {
+        period,
+        reviews: count,
+        passed,
+        retention: (count > 0)
+            .then(|| passed as f64 / count as f64),
+        average_answer_seconds: (count > 0).then(|| {
+            duration as f64 / 1000.0 / count as f64
+        }),
+    }
+}
+
+/// Retention of a deck per period, optionally per tag.
+///
+/// Reads the review log with `cardReviews`, using the local time
+/// zone for the rollover hour. For a tag breakdown the reviewed
+/// cards and their notes are fetched in chunks (see
+/// [`AnkiClient::with_info_chunk`]) to learn their tags.
+pub async fn retention_report(
+    anki_client: &AnkiClient,
+    query: RetentionQuery,
+) -> Result<RetentionReport> {
+    let start =
+        review_id_before(query.since, query.rollover_hour);
+    let reviews = anki_client
+        .card_reviews(&query.deck, start)
+        .await?;
+
+    let mut card_tags = HashMap::new();
+    if !query.tags.is_empty() {
+        let mut seen = HashSet::new();
+        let card_ids: Vec<u64> = reviews
+            .iter()
+            .filter(|review| review.is_review())
+            .map(|review| review.card_id)
+            .filter(|id| seen.insert(*id))
+            .collect();
+        let cards = anki_client
+            .cards_info_chunked(&card_ids)
+            .await?;
+        let mut seen = HashSet::new();
+        let note_ids: Vec<u64> = cards
+            .iter()
+            .map(|card| card.note_id)
+            .filter(|id| seen.insert(*id))
+            .collect();
+        let note_tags: HashMap<u64, Vec<String>> =
+            anki_client
+                .notes_info_chunked(&note_ids)
+                .await?
+                .into_iter()
+                .map(|note| (note.note_id, note.tags))
+                .collect();
+        for card in cards {
+            if let Some(tags) = note_tags.get(&card.note_id)
+            {
+                card_tags
+                    .insert(card.card_id, tags.clone());
+            }
+        }
+    }
+
+    Ok(build_report(&query, &reviews, &card_tags, &Local))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, ok};
+    use chrono::{FixedOffset, Utc};
+    use serde_json::json;
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    /// Review-stage answer at `hour` UTC on the given day
+    fn review(
+        date: NaiveDate,
+        hour: u32,
+        card_id: u64,
+        button: u32,
+        duration: u64,
+    ) -> CardReview {
+        CardReview {
+            review_time: date
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp_millis(),
+            card_id,
+            usn: -1,
+            button,
+            interval: 10,
+            last_interval: 5,
+            factor: 2500,
+            duration,
+            review_type: 1,
+        }
+    }
+
+    #[test]
+    fn test_study_day_respects_rollover() {
+        let at = |hour| {
+            review(day(2024, 3, 10), hour, 1, 3, 0)
+                .review_time
+        };
+        assert_eq!(
+            study_day(at(3), 4, &Utc),
+            Some(day(2024, 3, 9))
+        );
+        assert_eq!(
+            study_day(at(4), 4, &Utc),
+            Some(day(2024, 3, 10))
+        );
+        assert_eq!(
+            study_day(at(3), 0, &Utc),
+            Some(day(2024, 3, 10))
+        );
+        // 23:00 UTC is 07:00 the next day at UTC+8
+        let beijing =
+            FixedOffset::east_opt(8 * 3600).unwrap();
+        assert_eq!(
+            study_day(at(23), 4, &beijing),
+            Some(day(2024, 3, 11))
+        );
+    }
+
+    #[test]
+    fn test_bucket_starts() {
+        // 2024-03-13 is a Wednesday
+        let wednesday = day(2024, 3, 13);
+        assert_eq!(Bucket::Day.start(wednesday), wednesday);
+        assert_eq!(
+            Bucket::Week.start(wednesday),
+            day(2024, 3, 11)
+        );
+        assert_eq!(
+            Bucket::Week.start(day(2024, 3, 17)),
+            day(2024, 3, 11)
+        );
+        assert_eq!(
+            Bucket::Month.start(wednesday),
+            day(2024, 3, 1)
+        );
+        assert_eq!(
+            Bucket::Month.next(day(2024, 1, 1)),
+            Some(day(2024, 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_retention_math_per_bucket() {
+        let mut learning =
+            review(day(2024, 3, 4), 12, 9, 1, 0);
+        learning.review_type = 0;
+        let reviews = vec![
+            review(day(2024, 3, 4), 12, 1, 3, 4000),
+            review(day(2024, 3, 5), 12, 2, 1, 8000),
+            review(day(2024, 3, 6), 12, 3, 4, 3000),
+            review(day(2024, 3, 7), 12, 1, 2, 1000),
+            // Before the rollover: still Sunday 2024-03-10
+            review(day(2024, 3, 11), 2, 2, 1, 2000),
+            // Outside the range
+            review(day(2024, 3, 1), 12, 1, 3, 1000),
+            learning,
+        ];
+        let query = RetentionQuery::new(
+            "Default",
+            day(2024, 3, 4),
+            day(2024, 3, 24),
+            Bucket::Week,
+        );
+        let report = build_report(
+            &query,
+            &reviews,
+            &HashMap::new(),
+            &Utc,
+        );
+
+        assert_eq!(report.periods.len(), 3);
+        let first = &report.periods[0];
+        assert_eq!(first.period, day(2024, 3, 4));
+        assert_eq!((first.reviews, first.passed), (5, 3));
+        assert_eq!(first.retention, Some(0.6));
+        assert_eq!(first.average_answer_seconds, Some(3.6));
+        assert_eq!(report.periods[1].reviews, 0);
+        assert_eq!(report.periods[1].retention, None);
+        assert_eq!(
+            report.periods[2].period,
+            day(2024, 3, 18)
+        );
+    }
+
+    #[test]
+    fn test_tag_breakdown_and_csv() {
+        let reviews = vec![
+            review(day(2024, 3, 4), 12, 1, 3, 2000),
+            review(day(2024, 3, 4), 13, 2, 1, 4000),
+        ];
+        let card_tags = HashMap::from([
+            (1, vec!["Grammar::Verbs".to_string()]),
+            (2, vec!["vocab".to_string()]),
+        ]);
+        let mut query = RetentionQuery::new(
+            "Lang, Spanish",
+            day(2024, 3, 4),
+            day(2024, 3, 4),
+            Bucket::Day,
+        );
+        query.tags = vec!["grammar".to_string()];
+        let report = build_report(
+            &query, &reviews, &card_tags, &Utc,
+        );
+
+        assert_eq!(report.by_tag["grammar"][0].reviews, 1);
+        assert_eq!(
+            report.by_tag["grammar"][0].retention,
+            Some(1.0)
+        );
+        assert_eq!(
+            report.to_csv(),
+            "deck,tag,period,reviews,passed,retention,average_answer_seconds\n\
+             \"Lang, Spanish\",,2024-03-04,2,1,0.5000,3.0000\n\
+             \"Lang, Spanish\",grammar,2024-03-04,1,1,1.0000,2.0000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_joins_tags_in_chunks() {
+        let now = Utc::now().timestamp_millis();
+        let mock = MockAnki::start(move |action, params| {
+            match action {
+                "cardReviews" => ok(json!([
+                    [now, 1, -1, 3, 10, 5, 2500, 3000, 1],
+                    [now + 1, 2, -1, 1, 1, 5, 2300, 5000, 1],
+                    [now + 2, 1, -1, 3, 20, 10, 2500, 1000, 1],
+                ])),
+                "cardsInfo" => {
+                    let id = params["cards"][0].as_u64().unwrap();
+                    ok(json!([{
+                        "cardId": id,
+                        "note": id * 10,
+                        "deckName": "Default",
+                        "modelName": "Basic",
+                        "ord": 0,
+                        "type": 2,
+                        "queue": 2,
+                        "due": 0,
+                        "interval": 10,
+                        "factor": 2500,
+                        "reps": 2,
+                        "lapses": 0,
+                    }]))
+                }
+                "notesInfo" => {
+                    let id = params["notes"][0].as_u64().unwrap();
+                    ok(json!([{
+                        "noteId": id,
+                        "modelName": "Basic",
+                        "tags": if id == 10 { vec!["hard"] } else { vec![] },
+                        "fields": {},
+                        "cards": [id / 10],
+                    }]))
+                }
+                other => panic!("unexpected action {}", other),
+            }
+        })
+        .await;
+        let client = mock.client().with_info_chunk(1);
+        let today = Local::now().date_naive();
+        let mut query = RetentionQuery::new(
+            "Default",
+            today - Duration::days(1),
+            today,
+            Bucket::Month,
+        );
+        query.tags = vec!["hard".to_string()];
+
+        let report =
+            retention_report(&client, query).await.unwrap();
+        assert_eq!(
+            mock.actions(),
+            vec![
+                "cardReviews",
+                "cardsInfo",
+                "cardsInfo",
+                "notesInfo",
+                "notesInfo"
+            ]
+        );
+        let total: usize =
+            report.periods.iter().map(|p| p.reviews).sum();
+        let hard: usize = report.by_tag["hard"]
+            .iter()
+            .map(|p| p.reviews)
+            .sum();
+        assert_eq!((total, hard), (3, 2));
+    }
+}
@@ -0,0 +1,982 @@
+//! Workload forecast from a local, SM-2-style simulation.
+//!
+//! [`forecast_workload`] reads the current cards and options of a
+//! deck and plays the schedule forward day by day. Instead of rolling
+//! dice, every review splits a card into a passed share (the assumed
+//! retention) and a lapsed share, and shares in the same state are
+//! merged; the forecast is therefore the expected workload and the
+//! same inputs always give the same numbers.
+//!
+//! The simulation is deliberately simpler than Anki's scheduler:
+//!
+//! - every passed review is answered "Good": the interval grows by
+//!   the ease (at least one day) and the ease stays the same, with no
+//!   bonus for answering late;
+//! - a lapse lowers the ease by 0.2 (never below 1.3) and resets the
+//!   interval as the lapse options say;
+//! - learning and relearning steps are all answered on the day the
+//!   card is introduced or lapses, and are never failed;
+//! - cards in learning today graduate today;
+//! - the daily review limit, fuzz, load balancing, sibling burying
+//!   and FSRS are ignored, so the forecast shows the full load.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use anyhow::Result;
+use chrono::{Duration, Local, NaiveDate};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::anki::client::{
+    AnkiClient, CardInfo, CardQueue, CardReview, CardType,
+    DeckConfig, deck_query,
+};
+use crate::report::retention::{
+    DEFAULT_ROLLOVER_HOUR, csv_field, study_day,
+};
+
+/// Lowest ease a lapse can leave, in permille
+const MINIMUM_EASE: u32 = 1300;
+
+/// Ease lost on every lapse, in permille
+const LAPSE_EASE_PENALTY: u32 = 200;
+
+/// The options of a deck that drive the simulation
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerParams {
+    /// New cards introduced per day (`new.perDay`)
+    pub new_per_day: usize,
+    /// Number of learning steps (`new.delays`)
+    pub learning_steps: usize,
+    /// Interval in days after graduating (`new.ints[0]`)
+    pub graduating_interval: u32,
+    /// Ease of graduated cards in permille (`new.initialFactor`)
+    pub starting_ease: u32,
+    /// Number of relearning steps (`lapse.delays`)
+    pub relearning_steps: usize,
+    /// Share of the interval kept after a lapse (`lapse.mult`)
+    pub lapse_multiplier: f64,
+    /// Minimum interval in days after a lapse (`lapse.minInt`)
+    pub minimum_lapse_interval: u32,
+    /// Maximum interval in days (`rev.maxIvl`)
+    pub maximum_interval: u32,
+    /// Factor applied to every passed interval (`rev.ivlFct`)
+    pub interval_modifier: f64,
+}
+
+impl Default for SchedulerParams {
+    /// Anki's default options
+    fn default() -> Self {
+        Self {
+            new_per_day: 20,
+            learning_steps: 2,
+            graduating_interval: 1,
+            starting_ease: 2500,
+            relearning_steps: 1,
+            lapse_multiplier: 0.0,
+            minimum_lapse_interval: 1,
+            maximum_interval: 36500,
+            interval_modifier: 1.0,
+        }
+    }
+}
+
+impl SchedulerParams {
+    /// Reads the options from an options group, using Anki's
+    /// defaults for missing values
+    pub fn from_config(config: &DeckConfig) -> Self {
+        let option = |section: &str, key: &str| {
+            config
+                .options
+                .get(section)
+                .and_then(|s| s.get(key))
+        };
+        let number = |section, key| {
+            option(section, key).and_then(Value::as_f64)
+        };
+        let steps = |section, key| {
+            option(section, key)
+                .and_then(Value::as_array)
+                .map(Vec::len)
+        };
+        let defaults = Self::default();
+        Self {
+            new_per_day: number("new", "perDay")
+                .map_or(defaults.new_per_day, |v| {
+                    v.max(0.0) as usize
+                }),
+            learning_steps: steps("new", "delays")
+                .unwrap_or(defaults.learning_steps),
+            graduating_interval: option("new", "ints")
+                .and_then(|ints| ints.get(0))
+                .and_then(Value::as_f64)
+                .map_or(
+                    defaults.graduating_interval,
+                    |v| v.max(1.0) as u32,
+                ),
+            starting_ease: number("new", "initialFactor")
+                .map_or(defaults.starting_ease, |v| {
+                    (v as u32).max(MINIMUM_EASE)
+                }),
+            relearning_steps: steps("lapse", "delays")
+                .unwrap_or(defaults.relearning_steps),
+            lapse_multiplier: number("lapse", "mult")
+                .unwrap_or(defaults.lapse_multiplier),
+            minimum_lapse_interval: number(
+                "lapse", "minInt",
+            )
+            .map_or(defaults.minimum_lapse_interval, |v| {
+                v.max(1.0) as u32
+            }),
+            maximum_interval: number("rev", "maxIvl")
+                .map_or(defaults.maximum_interval, |v| {
+                    v.max(1.0) as u32
+                }),
+            interval_modifier: number("rev", "ivlFct")
+                .unwrap_or(defaults.interval_modifier),
+        }
+    }
+
+    /// Interval after answering "Good" on a review
+    fn passed_interval(
+        &self,
+        interval: u32,
+        ease: u32,
+    ) -> u32 {
+        let grown = (f64::from(interval) * f64::from(ease)
+            / 1000.0
+            * self.interval_modifier)
+            .round() as u32;
+        grown.max(interval + 1).min(self.maximum_interval)
+    }
+
+    /// Interval after a lapse and its relearning steps
+    fn lapsed_interval(&self, interval: u32) -> u32 {
+        let kept = (f64::from(interval)
+            * self.lapse_multiplier)
+            .round() as u32;
+        kept.max(self.minimum_lapse_interval)
+            .max(1)
+            .min(self.maximum_interval)
+    }
+}
+
+/// What [`forecast_workload`] simulates
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimOptions {
+    /// Number of days to forecast, today included
+    pub days: usize,
+    /// Cards added to the deck before the first day, e.g. a planned
+    /// import
+    pub imported_new_cards: usize,
+    /// New cards added to the deck at the start of every day
+    pub new_cards_per_day: usize,
+    /// Assumed share of reviews that are not lapses, from 0 to 1
+    pub retention: f64,
+    /// Days of review log the average answer time is taken from;
+    /// `0` always uses [`SimOptions::default_answer_seconds`]
+    pub history_days: u32,
+    /// Seconds per answer when the review log has no answers
+    pub default_answer_seconds: f64,
+}
+
+impl Default for SimOptions {
+    fn default() -> Self {
+        Self {
+            days: 90,
+            imported_new_cards: 0,
+            new_cards_per_day: 0,
+            retention: 0.9,
+            history_days: 30,
+            default_answer_seconds: 10.0,
+        }
+    }
+}
+
+/// State of a card when the simulation starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimCard {
+    /// Not studied yet
+    New,
+    /// In learning or relearning; answered on the first day, then
+    /// due again after `interval` days
+    Learning {
+        /// Interval in days once the card leaves learning
+        interval: u32,
+        /// Ease in permille, e.g. `2500`
+        ease: u32,
+    },
+    /// In the review stage
+    Review {
+        /// Days until the card is due, `0` for due or overdue
+        due_in: usize,
+        /// Current interval in days
+        interval: u32,
+        /// Ease in permille, e.g. `2500`
+        ease: u32,
+    },
+}
+
+impl SimCard {
+    /// The simulated state of `card`.
+    ///
+    /// # Arguments
+    /// * `due_in` - Days until a review card is due; `None` for
+    ///   review cards due after the forecast ends
+    ///
+    /// # Returns
+    /// `None` for suspended cards, cards of unknown types and review
+    /// cards without `due_in`, none of which are reviewed during the
+    /// forecast.
+    pub fn from_card(
+        card: &CardInfo,
+        due_in: Option<usize>,
+        params: &SchedulerParams,
+    ) -> Option<Self> {
+        if card.queue_kind() == CardQueue::Suspended {
+            return None;
+        }
+        let ease = if card.factor > 0 {
+            card.factor.max(MINIMUM_EASE)
+        } else {
+            params.starting_ease
+        };
+        let interval = u32::try_from(card.interval.max(1))
+            .unwrap_or(1);
+        match card.kind() {
+            CardType::New => Some(SimCard::New),
+            CardType::Learning => Some(SimCard::Learning {
+                interval: params.graduating_interval,
+                ease: params.starting_ease,
+            }),
+            CardType::Relearning => {
+                Some(SimCard::Learning { interval, ease })
+            }
+            CardType::Review => Some(SimCard::Review {
+                due_in: due_in?,
+                interval,
+                ease,
+            }),
+            CardType::Other(_) => None,
+        }
+    }
+}
+
+/// Expected workload of one day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DayForecast {
+    /// Days from today, `0` for today
+    pub day: usize,
+    /// Study day
+    pub date: NaiveDate,
+    /// New cards introduced
+    pub new_cards: usize,
+    /// Answers to learning and relearning steps
+    pub learning: f64,
+    /// Expected answers to review-stage cards
+    pub reviews: f64,
+    /// Expected time spent answering, in seconds
+    pub seconds: f64,
+    /// New cards still waiting at the end of the day
+    pub new_remaining: usize,
+}
+
+/// Result of [`forecast_workload`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Forecast {
+    /// Deck that was simulated
+    pub deck: String,
+    /// Retention the simulation assumed
+    pub retention: f64,
+    /// Seconds per answer used for [`DayForecast::seconds`]
+    pub average_answer_seconds: f64,
+    /// Whether the answer time came from the review log rather than
+    /// [`SimOptions::default_answer_seconds`]
+    pub answer_time_from_history: bool,
+    /// One entry per simulated day, today first
+    pub days: Vec<DayForecast>,
+}
+
+impl Forecast {
+    /// Renders the daily forecast as CSV with a header row
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "deck,date,day,new_cards,learning,reviews,minutes,new_remaining\n",
+        );
+        for day in &self.days {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{:.2},{:.2},{:.2},{}",
+                csv_field(&self.deck),
+                day.date,
+                day.day,
+                day.new_cards,
+                day.learning,
+                day.reviews,
+                day.seconds / 60.0,
+                day.new_remaining,
+            );
+        }
+        csv
+    }
+}
+
+/// Cards in the same state, ordered by due day first
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct Cohort {
+    due: usize,
+    interval: u32,
+    ease: u32,
+}
+
+/// Expected share of cards in each state, due within the forecast
+struct Schedule {
+    cohorts: BTreeMap<Cohort, f64>,
+    days: usize,
+}
+
+impl Schedule {
+    /// Adds `weight` cards due on day `due`; cards due after the
+    /// forecast are dropped
+    fn add(
+        &mut self,
+        due: usize,
+        interval: u32,
+        ease: u32,
+        weight: f64,
+    ) {
+        if due < self.days && weight > 0.0 {
+            let cohort = Cohort {
+                due,
+                interval,
+                ease,
+            };
+            *self.cohorts.entry(cohort).or_default() +=
+                weight;
+        }
+    }
+
+    /// Removes and returns the cohorts due on or before `day`
+    fn take_due(
+        &mut self,
+        day: usize,
+    ) -> BTreeMap<Cohort, f64> {
+        let later = self.cohorts.split_off(&Cohort {
+            due: day + 1,
+            interval: 0,
+            ease: 0,
+        });
+        std::mem::replace(&mut self.cohorts, later)
+    }
+}
+
+/// Simulates the schedule of `cards` day by day.
+///
+/// See the [module documentation](self) for the rules.
+///
+/// # Arguments
+/// * `start` - Date of the first day
+/// * `answer_seconds` - Time spent on every answer
+pub fn simulate(
+    cards: &[SimCard],
+    params: &SchedulerParams,
+    options: &SimOptions,
+    start: NaiveDate,
+    answer_seconds: f64,
+) -> Vec<DayForecast> {
+    let retention = options.retention.clamp(0.0, 1.0);
+    let mut schedule = Schedule {
+        cohorts: BTreeMap::new(),
+        days: options.days,
+    };
+    let mut new_remaining = options.imported_new_cards;
+    let mut learning_today = 0.0;
+    for card in cards {
+        match *card {
+            SimCard::New => new_remaining += 1,
+            SimCard::Learning { interval, ease } => {
+                learning_today += 1.0;
+                schedule.add(
+                    interval as usize,
+                    interval,
+                    ease,
+                    1.0,
+                );
+            }
+            SimCard::Review {
+                due_in,
+                interval,
+                ease,
+            } => schedule.add(due_in, interval, ease, 1.0),
+        }
+    }
+
+    let graduating = params.graduating_interval.max(1);
+    let mut days = Vec::with_capacity(options.days);
+    for day in 0..options.days {
+        new_remaining += options.new_cards_per_day;
+        let new_cards =
+            new_remaining.min(params.new_per_day);
+        new_remaining -= new_cards;
+        let mut learning = (new_cards
+            * params.learning_steps.max(1))
+            as f64;
+        if day == 0 {
+            learning += learning_today;
+        }
+        schedule.add(
+            day + graduating as usize,
+            graduating,
+            params.starting_ease,
+            new_cards as f64,
+        );
+
+        let mut reviews = 0.0;
+        for (cohort, weight) in schedule.take_due(day) {
+            reviews += weight;
+            let passed = weight * retention;
+            let interval = params.passed_interval(
+                cohort.interval,
+                cohort.ease,
+            );
+            schedule.add(
+                day + interval as usize,
+                interval,
+                cohort.ease,
+                passed,
+            );
+
+            let lapsed = weight - passed;
+            learning +=
+                lapsed * params.relearning_steps as f64;
+            let interval =
+                params.lapsed_interval(cohort.interval);
+            let ease = cohort
+                .ease
+                .saturating_sub(LAPSE_EASE_PENALTY)
+                .max(MINIMUM_EASE);
+            schedule.add(
+                day + interval as usize,
+                interval,
+                ease,
+                lapsed,
+            );
+        }
+
+        days.push(DayForecast {
+            day,
+            date: start + Duration::days(day as i64),
+            new_cards,
+            learning,
+            reviews,
+            seconds: (learning + reviews) * answer_seconds,
+            new_remaining,
+        });
+    }
+    days
+}
+
+/// Mean answer time of the logged answers in seconds, `None` if
+/// none has a duration
+fn average_answer_seconds(
+    reviews: &[CardReview],
+) -> Option<f64> {
+    let timed: Vec<u64> = reviews
+        .iter()
+        .map(|review| review.duration)
+        .filter(|duration| *duration > 0)
+        .collect();
+    (!timed.is_empty()).then(|| {
+        timed.iter().sum::<u64>() as f64
+            / 1000.0
+            / timed.len() as f64
+    })
+}
+
+/// Forecasts the daily workload of `deck` (subdecks included).
+///
+/// Reads the deck's options group, its unsuspended cards and the
+/// answer times of the last [`SimOptions::history_days`] days, and
+/// finds how many days away each review card is due with one
+/// `prop:due` search per forecast day (see
+/// [`AnkiClient::review_forecast`]). The first day is today's study
+/// day under Anki's default rollover hour.
+pub async fn forecast_workload(
+    anki_client: &AnkiClient,
+    deck: &str,
+    options: SimOptions,
+) -> Result<Forecast> {
+    let config = anki_client.get_deck_config(deck).await?;
+    let params = SchedulerParams::from_config(&config);
+    let cards = anki_client
+        .find_cards_detailed(&format!(
+            "{} -is:suspended",
+            deck_query(deck)
+        ))
+        .await?;
+
+    let mut due_in = HashMap::new();
+    for day in 0..options.days {
+        let due = if day == 0 {
+            "prop:due<=0".to_string()
+        } else {
+            format!("prop:due={}", day)
+        };
+        let query = format!(
+            "{} is:review -is:suspended {}",
+            deck_query(deck),
+            due
+        );
+        for card_id in
+            anki_client.find_cards(&query).await?
+        {
+            due_in.entry(card_id).or_insert(day);
+        }
+    }
+    let sim_cards: Vec<SimCard> = cards
+        .iter()
+        .filter_map(|card| {
+            SimCard::from_card(
+                card,
+                due_in.get(&card.card_id).copied(),
+                &params,
+            )
+        })
+        .collect();
+
+    let now = Local::now();
+    let history = if options.history_days > 0 {
+        let since = now.timestamp_millis()
+            - i64::from(options.history_days) * 86_400_000;
+        let reviews =
+            anki_client.card_reviews(deck, since).await?;
+        average_answer_seconds(&reviews)
+    } else {
+        None
+    };
+    let answer_seconds =
+        history.unwrap_or(options.default_answer_seconds);
+    let start = study_day(
+        now.timestamp_millis(),
+        DEFAULT_ROLLOVER_HOUR,
+        &Local,
+    )
+    .unwrap_or_else(|| now.date_naive());
+
+    Ok(Forecast {
+        deck: deck.to_string(),
+        retention: options.retention,
+        average_answer_seconds: answer_seconds,
+        answer_time_from_history: history.is_some(),
+        days: simulate(
+            &sim_cards,
+            &params,
+            &options,
+            start,
+            answer_seconds,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::mock::{MockAnki, err, ok};
+    use serde_json::json;
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn options(days: usize, retention: f64) -> SimOptions {
+        SimOptions {
+            days,
+            retention,
+            ..Default::default()
+        }
+    }
+
+    /// `(new_cards, learning, reviews)` of every day, rounded to
+    /// four decimals
+    fn loads(
+        forecast: &[DayForecast],
+    ) -> Vec<(usize, f64, f64)> {
+        let round =
+            |v: f64| (v * 10_000.0).round() / 10_000.0;
+        forecast
+            .iter()
+            .map(|d| {
+                (
+                    d.new_cards,
+                    round(d.learning),
+                    round(d.reviews),
+                )
+            })
+            .collect()
+    }
+
+    fn card(
+        id: u64,
+        card_type: u32,
+        queue: i32,
+        interval: i64,
+        factor: u32,
+    ) -> Value {
+        json!({
+            "cardId": id,
+            "note": id * 10,
+            "deckName": "Words",
+            "modelName": "Basic",
+            "ord": 0,
+            "type": card_type,
+            "queue": queue,
+            "due": 0,
+            "interval": interval,
+            "factor": factor,
+            "reps": 3,
+            "lapses": 0,
+        })
+    }
+
+    #[test]
+    fn test_intervals_grow_by_ease() {
+        let review = SimCard::Review {
+            due_in: 0,
+            interval: 10,
+            ease: 2500,
+        };
+        let forecast = simulate(
+            &[review],
+            &SchedulerParams::default(),
+            &options(90, 1.0),
+            day(2024, 3, 1),
+            8.0,
+        );
+        // 10 days -> 25 -> 63 (62.5 rounded up)
+        let due: Vec<usize> = forecast
+            .iter()
+            .filter(|d| d.reviews > 0.0)
+            .map(|d| d.day)
+            .collect();
+        assert_eq!(due, [0, 25, 88]);
+        assert_eq!(forecast[25].date, day(2024, 3, 26));
+        assert_eq!(forecast[25].seconds, 8.0);
+    }
+
+    #[test]
+    fn test_lapses_split_the_expected_load() {
+        let review = SimCard::Review {
+            due_in: 0,
+            interval: 10,
+            ease: 2500,
+        };
+        let forecast = simulate(
+            &[review],
+            &SchedulerParams::default(),
+            &options(4, 0.8),
+            day(2024, 3, 1),
+            10.0,
+        );
+        // Day 0: 0.8 passes (due in 25 days), 0.2 lapses into one
+        // relearning step and comes back tomorrow at ease 2.3.
+        // Day 1: of the 0.2, 0.16 pass with interval 2 (due day 3)
+        // and 0.04 lapse again (due day 2 at ease 2.1).
+        // Day 2: 0.032 pass with interval 2 (day 4, past the end),
+        // 0.008 lapse (day 3).
+        // Day 3: 0.16 + 0.008 are due.
+        assert_eq!(
+            loads(&forecast),
+            [
+                (0, 0.2, 1.0),
+                (0, 0.04, 0.2),
+                (0, 0.008, 0.04),
+                (0, 0.0336, 0.168),
+            ]
+        );
+        assert!((forecast[0].seconds - 12.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_new_cards_follow_the_daily_limit() {
+        let params = SchedulerParams {
+            new_per_day: 2,
+            ..Default::default()
+        };
+        let sim = SimOptions {
+            imported_new_cards: 2,
+            new_cards_per_day: 1,
+            ..options(4, 1.0)
+        };
+        let forecast = simulate(
+            &[SimCard::New],
+            &params,
+            &sim,
+            day(2024, 3, 1),
+            10.0,
+        );
+        // Two learning steps per new card; graduated cards are due
+        // the next day, then three days later (1 * 2.5, rounded)
+        assert_eq!(
+            loads(&forecast),
+            [
+                (2, 4.0, 0.0),
+                (2, 4.0, 2.0),
+                (2, 4.0, 2.0),
+                (1, 2.0, 2.0),
+            ]
+        );
+        let remaining: Vec<usize> = forecast
+            .iter()
+            .map(|d| d.new_remaining)
+            .collect();
+        assert_eq!(remaining, [2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_learning_cards_graduate_today() {
+        let cards = [
+            SimCard::Learning {
+                interval: 1,
+                ease: 2500,
+            },
+            SimCard::Review {
+                due_in: 2,
+                interval: 4,
+                ease: 1300,
+            },
+        ];
+        let params = SchedulerParams {
+            maximum_interval: 5,
+            ..Default::default()
+        };
+        let forecast = simulate(
+            &cards,
+            &params,
+            &options(8, 1.0),
+            day(2024, 3, 1),
+            10.0,
+        );
+        // The learning card: day 1, then 1 * 2.5 -> 3 days (day 4),
+        // then 3 * 2.5 -> 7.5, capped at 5 (day 9, past the end).
+        // The review card: day 2, then 4 * 1.3 -> 5 (day 7).
+        assert_eq!(
+            loads(&forecast)
+                .iter()
+                .map(|(_, learning, reviews)| {
+                    (*learning, *reviews)
+                })
+                .collect::<Vec<_>>(),
+            [
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (0.0, 1.0),
+                (0.0, 0.0),
+                (0.0, 1.0),
+                (0.0, 0.0),
+                (0.0, 0.0),
+                (0.0, 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_params_from_config() {
+        let config: DeckConfig = serde_json::from_value(json!({
+            "id": 1,
+            "name": "Default",
+            "new": {
+                "delays": [1, 10, 60],
+                "ints": [3, 4, 7],
+                "initialFactor": 2300,
+                "perDay": 15,
+            },
+            "lapse": {"delays": [], "mult": 0.5, "minInt": 2},
+            "rev": {"maxIvl": 365, "ivlFct": 1.2},
+        }))
+        .unwrap();
+        assert_eq!(
+            SchedulerParams::from_config(&config),
+            SchedulerParams {
+                new_per_day: 15,
+                learning_steps: 3,
+                graduating_interval: 3,
+                starting_ease: 2300,
+                relearning_steps: 0,
+                lapse_multiplier: 0.5,
+                minimum_lapse_interval: 2,
+                maximum_interval: 365,
+                interval_modifier: 1.2,
+            }
+        );
+
+        let bare: DeckConfig = serde_json::from_value(
+            json!({"id": 1, "name": "Default"}),
+        )
+        .unwrap();
+        assert_eq!(
+            SchedulerParams::from_config(&bare),
+            SchedulerParams::default()
+        );
+    }
+
+    #[test]
+    fn test_sim_card_from_card_info() {
+        let params = SchedulerParams::default();
+        let info = |value: Value| -> CardInfo {
+            serde_json::from_value(value).unwrap()
+        };
+        assert_eq!(
+            SimCard::from_card(
+                &info(card(1, 0, 0, 0, 0)),
+                None,
+                &params
+            ),
+            Some(SimCard::New)
+        );
+        assert_eq!(
+            SimCard::from_card(
+                &info(card(1, 1, 1, -600, 0)),
+                None,
+                &params
+            ),
+            Some(SimCard::Learning {
+                interval: 1,
+                ease: 2500
+            })
+        );
+        assert_eq!(
+            SimCard::from_card(
+                &info(card(1, 3, 1, 3, 2100)),
+                None,
+                &params
+            ),
+            Some(SimCard::Learning {
+                interval: 3,
+                ease: 2100
+            })
+        );
+        assert_eq!(
+            SimCard::from_card(
+                &info(card(1, 2, 2, 12, 2700)),
+                Some(4),
+                &params
+            ),
+            Some(SimCard::Review {
+                due_in: 4,
+                interval: 12,
+                ease: 2700
+            })
+        );
+        // Due after the forecast, or suspended
+        for (value, due_in) in [
+            (card(1, 2, 2, 12, 2700), None),
+            (card(1, 2, -1, 12, 2700), Some(0)),
+        ] {
+            assert_eq!(
+                SimCard::from_card(
+                    &info(value),
+                    due_in,
+                    &params
+                ),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_csv() {
+        let forecast = Forecast {
+            deck: "Lang, Spanish".to_string(),
+            retention: 0.9,
+            average_answer_seconds: 6.0,
+            answer_time_from_history: true,
+            days: vec![DayForecast {
+                day: 0,
+                date: day(2024, 3, 1),
+                new_cards: 2,
+                learning: 4.0,
+                reviews: 1.25,
+                seconds: 33.0,
+                new_remaining: 7,
+            }],
+        };
+        assert_eq!(
+            forecast.to_csv(),
+            "deck,date,day,new_cards,learning,reviews,minutes,new_remaining\n\
+             \"Lang, Spanish\",2024-03-01,0,2,4.00,1.25,0.55,7\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forecast_workload_reads_the_deck() {
+        let mock =
+            MockAnki::start(|action, params| match action {
+                "getDeckConfig" => ok(json!({
+                    "id": 1,
+                    "name": "Default",
+                    "new": {"delays": [1, 10], "ints": [2, 4]},
+                })),
+                "findCards" => {
+                    let query =
+                        params["query"].as_str().unwrap();
+                    if query.contains("prop:due<=0") {
+                        ok(json!([2]))
+                    } else if query.contains("prop:due") {
+                        ok(json!([]))
+                    } else {
+                        ok(json!([1, 2, 3, 4]))
+                    }
+                }
+                "cardsInfo" => ok(json!([
+                    card(1, 0, 0, 0, 0),
+                    card(2, 2, 2, 10, 2500),
+                    card(3, 1, 1, -600, 0),
+                    // Due after the forecast
+                    card(4, 2, 2, 30, 2500),
+                ])),
+                "cardReviews" => ok(json!([
+                    [1, 2, -1, 3, 10, 4, 2500, 4000, 1],
+                    [2, 3, -1, 3, -600, 0, 0, 8000, 0],
+                ])),
+                _ => err("unsupported action"),
+            })
+            .await;
+
+        let forecast = forecast_workload(
+            &mock.client(),
+            "Words",
+            options(3, 1.0),
+        )
+        .await
+        .unwrap();
+        assert_eq!(forecast.average_answer_seconds, 6.0);
+        assert!(forecast.answer_time_from_history);
+        // Day 0: the new card's two steps, the learning card and the
+        // due review; both graduates come back on day 2
+        assert_eq!(
+            loads(&forecast.days),
+            [(1, 3.0, 1.0), (0, 0.0, 0.0), (0, 0.0, 2.0)]
+        );
+        assert_eq!(forecast.days[0].seconds, 24.0);
+        assert_eq!(
+            forecast.days[1].date,
+            forecast.days[0].date.succ_opt().unwrap()
+        );
+
+        let actions = mock.actions();
+        assert_eq!(actions[0], "getDeckConfig");
+        assert_eq!(actions.last().unwrap(), "cardReviews");
+        let requests = mock.requests();
+        assert_eq!(
+            requests[1]["params"]["query"],
+            "deck:\"Words\" -is:suspended"
+        );
+        assert_eq!(
+            requests[3]["params"]["query"],
+            "deck:\"Words\" is:review -is:suspended prop:due<=0"
+        );
+    }
+}
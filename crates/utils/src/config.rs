@@ -1,2 +1,5 @@
 //! Configuration
 pub mod env;
+pub mod handle;
+pub mod secret;
+pub mod settings;
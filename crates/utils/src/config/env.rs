@@ -2,15 +2,41 @@ use config::{Config, Environment};
 use serde::Deserialize;
 use std::sync::LazyLock;
 
+use super::secret::Secret;
+use super::settings::{
+    ApiKeys, default_dotenv_paths, read_dotenv,
+};
+
 #[derive(Debug, Deserialize)]
 pub struct EnvConfig {
     pub rust_log: Option<String>,
-    pub zhi_pu_api_key: Option<String>,
+    pub zhi_pu_api_key: Option<Secret<String>>,
 }
 
 impl EnvConfig {
-    fn new() -> Result<Self, config::ConfigError> {
-        let s = Config::builder()
+    /// All ZhiPu keys; `ZHI_PU_API_KEY` may hold several keys
+    /// separated by commas
+    pub fn zhi_pu_api_keys(&self) -> ApiKeys {
+        self.zhi_pu_api_key
+            .as_ref()
+            .map(|keys| ApiKeys::parse(keys.expose()))
+            .unwrap_or_default()
+    }
+
+    /// Reads the process environment over `.env.local` over
+    /// `.env`, without copying the files into the process
+    /// environment
+    pub fn load() -> Result<Self, config::ConfigError> {
+        let mut builder = Config::builder();
+        for path in default_dotenv_paths() {
+            if let Some(vars) = read_dotenv(&path)? {
+                builder = builder.add_source(
+                    Environment::default()
+                        .source(Some(vars)),
+                );
+            }
+        }
+        let s = builder
             .add_source(Environment::default())
             .build()?;
         // Deserialize into our struct
@@ -21,8 +47,7 @@ impl EnvConfig {
 /// Global environment configuration
 pub static ENV_SETTINGS: LazyLock<EnvConfig> =
     LazyLock::new(|| {
-        dotenvy::dotenv().ok();
-        EnvConfig::new().expect(
+        EnvConfig::load().expect(
             "Failed to load configuration from environment",
         )
     });
@@ -0,0 +1,404 @@
+//! Settings that can be reloaded while the process runs.
+//!
+//! Daemons such as the folder watcher hold a [`SettingsHandle`]
+//! instead of a [`Settings`] value, so rotating an API key only
+//! needs an edit of the config file (or a call to
+//! [`SettingsHandle::reload`]) rather than a restart.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::settings::{
+    PROFILE_ENV, Settings, default_paths,
+};
+
+type Loader = dyn Fn() -> Result<Settings, config::ConfigError>
+    + Send
+    + Sync;
+type Listener = dyn Fn(&SettingsChange) + Send + Sync;
+
+struct Inner {
+    current: RwLock<Arc<Settings>>,
+    paths: Vec<PathBuf>,
+    loader: Box<Loader>,
+    listeners: Mutex<Vec<Box<Listener>>>,
+}
+
+/// Shared, atomically swappable [`Settings`].
+///
+/// Clones share the same settings. Readers call
+/// [`SettingsHandle::get`] for every use of a value that may
+/// change, e.g. API keys; a reload never exposes a half-updated
+/// state.
+#[derive(Clone)]
+pub struct SettingsHandle {
+    inner: Arc<Inner>,
+}
+
+/// Outcome of a reload that changed at least one value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingsChange {
+    /// Keys whose values changed, in declaration order
+    pub changed: Vec<&'static str>,
+}
+
+impl fmt::Display for SettingsChange {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "reloaded config, {} value{} changed ({})",
+            self.changed.len(),
+            if self.changed.len() == 1 { "" } else { "s" },
+            self.changed.join(", ")
+        )
+    }
+}
+
+impl fmt::Debug for SettingsHandle {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("SettingsHandle")
+            .field("settings", &self.get())
+            .field("paths", &self.inner.paths)
+            .finish()
+    }
+}
+
+impl SettingsHandle {
+    /// Loads settings like [`Settings::load`] and reloads them the
+    /// same way, picking up a changed `ANKI_LEARN_PROFILE` too
+    pub fn load() -> Result<Self, config::ConfigError> {
+        Self::with_loader(default_paths(), move || {
+            let profile = std::env::var(PROFILE_ENV).ok();
+            Settings::load_profile_from(
+                &default_paths(),
+                profile.as_deref(),
+                None,
+            )
+        })
+    }
+
+    /// Loads settings like [`Settings::load_profile_from`]; every
+    /// reload re-reads the same files with the same `profile` and
+    /// `env`
+    pub fn from_paths(
+        paths: Vec<PathBuf>,
+        profile: Option<String>,
+        env: Option<HashMap<String, String>>,
+    ) -> Result<Self, config::ConfigError> {
+        let watched = paths.clone();
+        Self::with_loader(watched, move || {
+            Settings::load_profile_from(
+                &paths,
+                profile.as_deref(),
+                env.clone(),
+            )
+        })
+    }
+
+    fn with_loader(
+        paths: Vec<PathBuf>,
+        loader: impl Fn()
+            -> Result<Settings, config::ConfigError>
+        + Send
+        + Sync
+        + 'static,
+    ) -> Result<Self, config::ConfigError> {
+        let settings = loader()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                current: RwLock::new(Arc::new(settings)),
+                paths,
+                loader: Box::new(loader),
+                listeners: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// The current settings; later reloads do not affect the
+    /// returned snapshot
+    pub fn get(&self) -> Arc<Settings> {
+        self.inner
+            .current
+            .read()
+            .unwrap_or_else(|poisoned| {
+                poisoned.into_inner()
+            })
+            .clone()
+    }
+
+    /// Files whose changes [`SettingsHandle::watch`] reacts to
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.inner.paths
+    }
+
+    /// Calls `listener` after every reload that changed a value
+    pub fn on_change(
+        &self,
+        listener: impl Fn(&SettingsChange)
+        + Send
+        + Sync
+        + 'static,
+    ) {
+        self.inner
+            .listeners
+            .lock()
+            .unwrap_or_else(|poisoned| {
+                poisoned.into_inner()
+            })
+            .push(Box::new(listener));
+    }
+
+    /// Re-runs the layered load and swaps the result in.
+    ///
+    /// # Returns
+    /// The changed keys, `None` if nothing changed. On error the
+    /// previous settings stay in place.
+    pub fn reload(
+        &self,
+    ) -> Result<Option<SettingsChange>, config::ConfigError>
+    {
+        let settings = (self.inner.loader)()?;
+        let change = {
+            let mut current =
+                self.inner.current.write().unwrap_or_else(
+                    |poisoned| poisoned.into_inner(),
+                );
+            let changed = current.changed_keys(&settings);
+            *current = Arc::new(settings);
+            changed
+        };
+        if change.is_empty() {
+            return Ok(None);
+        }
+        let change = SettingsChange { changed: change };
+        for listener in self
+            .inner
+            .listeners
+            .lock()
+            .unwrap_or_else(|poisoned| {
+                poisoned.into_inner()
+            })
+            .iter()
+        {
+            listener(&change);
+        }
+        Ok(Some(change))
+    }
+
+    /// Reloads automatically when one of [`SettingsHandle::paths`]
+    /// is created, modified or removed.
+    ///
+    /// The files are polled; a reload happens once they have been
+    /// unchanged for `debounce`, so an editor writing a file in
+    /// several steps triggers a single reload. Failed reloads are
+    /// logged and keep the previous settings. Watching stops when
+    /// the returned [`SettingsWatcher`] is dropped.
+    pub fn watch(
+        &self,
+        debounce: Duration,
+    ) -> SettingsWatcher {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = self.clone();
+        let stopped = stop.clone();
+        let poll =
+            (debounce / 4).max(Duration::from_millis(10));
+        let thread = std::thread::spawn(move || {
+            let mut seen = handle.fingerprint();
+            let mut pending: Option<Instant> = None;
+            while !stopped.load(Ordering::Relaxed) {
+                std::thread::sleep(poll);
+                let now = handle.fingerprint();
+                if now != seen {
+                    seen = now;
+                    pending = Some(Instant::now());
+                } else if pending.is_some_and(|at| {
+                    at.elapsed() >= debounce
+                }) {
+                    pending = None;
+                    match handle.reload() {
+                        Ok(Some(change)) => {
+                            log::info!("{}", change)
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::warn!(
+                            "Failed to reload config, keeping the previous settings: {}",
+                            e
+                        ),
+                    }
+                }
+            }
+        });
+        SettingsWatcher {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Modification time and size of every watched file
+    fn fingerprint(
+        &self,
+    ) -> Vec<Option<(SystemTime, u64)>> {
+        self.inner
+            .paths
+            .iter()
+            .map(|path| {
+                let meta = std::fs::metadata(path).ok()?;
+                Some((meta.modified().ok()?, meta.len()))
+            })
+            .collect()
+    }
+}
+
+/// Background file watch started by [`SettingsHandle::watch`];
+/// stops when dropped
+#[derive(Debug)]
+pub struct SettingsWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for SettingsWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config_file(name: &str, key: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_handle_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        write_key(&path, key);
+        path
+    }
+
+    fn write_key(path: &std::path::Path, key: &str) {
+        std::fs::write(
+            path,
+            format!(
+                "anki_connect_key = \"{}\"\nmax_retries = 3\n",
+                key
+            ),
+        )
+        .unwrap();
+    }
+
+    fn key_of(handle: &SettingsHandle) -> String {
+        handle
+            .get()
+            .anki_connect_key
+            .as_ref()
+            .unwrap()
+            .expose()
+            .clone()
+    }
+
+    #[test]
+    fn test_reload_swaps_and_reports_changes() {
+        let path = config_file("reload", "old-key");
+        let handle = SettingsHandle::from_paths(
+            vec![path.clone()],
+            None,
+            Some(HashMap::new()),
+        )
+        .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        handle.on_change(move |change| {
+            sink.lock().unwrap().push(change.to_string())
+        });
+        let before = handle.get();
+
+        assert_eq!(handle.reload().unwrap(), None);
+        std::fs::write(
+            &path,
+            "anki_connect_key = \"new-key\"\nmax_retries = 5\n",
+        )
+        .unwrap();
+        let change = handle.reload().unwrap().unwrap();
+        assert_eq!(
+            change.changed,
+            vec!["anki_connect_key", "max_retries"]
+        );
+        assert_eq!(key_of(&handle), "new-key");
+        assert_eq!(
+            before
+                .anki_connect_key
+                .as_ref()
+                .unwrap()
+                .expose(),
+            "old-key"
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "reloaded config, 2 values changed (anki_connect_key, max_retries)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_previous_settings() {
+        let path = config_file("broken", "old-key");
+        let handle = SettingsHandle::from_paths(
+            vec![path.clone()],
+            None,
+            Some(HashMap::new()),
+        )
+        .unwrap();
+        std::fs::write(&path, "max_retries = \"many\"\n")
+            .unwrap();
+        assert!(handle.reload().is_err());
+        assert_eq!(key_of(&handle), "old-key");
+    }
+
+    #[test]
+    fn test_watch_reloads_after_edit() {
+        let path = config_file("watch", "old-key");
+        let handle = SettingsHandle::from_paths(
+            vec![path.clone()],
+            None,
+            Some(HashMap::new()),
+        )
+        .unwrap();
+        let _watcher =
+            handle.watch(Duration::from_millis(40));
+        // Let the watcher take its first snapshot
+        std::thread::sleep(Duration::from_millis(50));
+        write_key(&path, "rotated-key-with-new-length");
+
+        let deadline =
+            Instant::now() + Duration::from_secs(5);
+        while key_of(&handle)
+            != "rotated-key-with-new-length"
+        {
+            assert!(
+                Instant::now() < deadline,
+                "config edit was not picked up"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
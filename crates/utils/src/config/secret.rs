@@ -0,0 +1,162 @@
+//! Secret values that never show up in logs.
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::cell::Cell;
+use std::fmt;
+
+/// Text printed and serialized in place of a secret
+pub const REDACTED: &str = "***redacted***";
+
+thread_local! {
+    static EXPOSE_ON_SERIALIZE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A value, such as an API key, that is redacted in `Debug`,
+/// `Display` and serialization.
+///
+/// There is deliberately no `Deref` or `AsRef`, so the value can
+/// only be reached through [`Secret::expose`]:
+///
+/// ```compile_fail
+/// use utils::config::secret::Secret;
+///
+/// let key = Secret::new("sk-123".to_string());
+/// let header: &str = &key;
+/// ```
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    /// Wraps a secret value
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// The secret value, for the code that genuinely needs it
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl From<String> for Secret<String> {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret<String> {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de>
+    for Secret<T>
+{
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self)
+    }
+}
+
+/// Serializes as [`REDACTED`] unless inside [`expose_secrets`]
+impl<T: Serialize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if EXPOSE_ON_SERIALIZE.with(Cell::get) {
+            self.0.serialize(serializer)
+        } else {
+            serializer.serialize_str(REDACTED)
+        }
+    }
+}
+
+/// Runs `f` with secrets serialized in full, for explicit exports
+pub fn expose_secrets<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset(bool);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            EXPOSE_ON_SERIALIZE
+                .with(|flag| flag.set(self.0));
+        }
+    }
+    let _reset = Reset(
+        EXPOSE_ON_SERIALIZE.with(|flag| flag.replace(true)),
+    );
+    f()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Serialize)]
+    struct Provider {
+        name: String,
+        key: Secret<String>,
+    }
+
+    fn provider() -> Provider {
+        Provider {
+            name: "zhi_pu".to_string(),
+            key: Secret::from("sk-very-secret"),
+        }
+    }
+
+    #[test]
+    fn test_debug_and_display_are_redacted() {
+        let provider = provider();
+        for text in [
+            format!("{:?}", provider),
+            format!("{:#?}", provider),
+            format!("{}", provider.key),
+        ] {
+            assert!(
+                !text.contains("sk-very-secret"),
+                "{}",
+                text
+            );
+            assert!(text.contains(REDACTED));
+        }
+        assert_eq!(provider.key.expose(), "sk-very-secret");
+    }
+
+    #[test]
+    fn test_serialization_is_redacted_unless_exposed() {
+        let provider = provider();
+        let redacted = toml::to_string(&provider).unwrap();
+        assert!(!redacted.contains("sk-very-secret"));
+
+        let exposed = expose_secrets(|| {
+            toml::to_string(&provider).unwrap()
+        });
+        assert!(exposed.contains("sk-very-secret"));
+        assert_eq!(
+            toml::to_string(&provider).unwrap(),
+            redacted
+        );
+    }
+}
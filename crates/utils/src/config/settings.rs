@@ -0,0 +1,1490 @@
+//! Layered application settings.
+//!
+//! Values are resolved, lowest precedence first, from built-in
+//! defaults, the TOML config file (`~/.config/anki_learn/config.toml`,
+//! overridable with `ANKI_LEARN_CONFIG`), the selected profile, `.env`,
+//! `.env.local` and finally the process environment, which always
+//! wins. Keys are the upper-cased field names, e.g. `ZHI_PU_API_KEY`
+//! or `ANKI_CONNECT_URL`. [`Settings::describe`] reports where each
+//! value came from.
+
+use config::{Config, Environment, File, FileFormat};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use super::secret::{Secret, expose_secrets};
+use crate::paths::AppDir;
+
+/// Environment variable overriding the config file location
+pub const CONFIG_PATH_ENV: &str = "ANKI_LEARN_CONFIG";
+
+/// Environment variable selecting a `[profiles.<name>]` table
+pub const PROFILE_ENV: &str = "ANKI_LEARN_PROFILE";
+
+/// Commented example written by [`Settings::write_template`]
+const TEMPLATE: &str = r#"# anki_learn configuration
+#
+# Every key can be overridden by an environment variable with the
+# upper-cased name, e.g. ZHI_PU_API_KEY or ANKI_CONNECT_URL.
+
+# API keys of the AI providers; several keys of one provider are
+# given as a list (or comma-separated) and rotated on quota errors
+# zhi_pu_api_key = ["first-key", "second-key"]
+# openai_api_key = ""
+# deepseek_api_key = ""
+
+# Anki-Connect endpoint and its optional API key
+anki_connect_url = "http://localhost:8765"
+# anki_connect_key = ""
+anki_request_timeout_secs = 30
+
+# Where generated notes go
+default_deck = "Default"
+default_model = "Basic"
+
+# Retry and timeout behaviour of outgoing requests
+max_retries = 3
+retry_delay_ms = 1000
+request_timeout_secs = 60
+
+# Request defaults per AI provider; explicit request values win
+# [providers.zhipu]
+# default_model = "glm-4.7-flash"
+# temperature = 0.7
+# max_tokens = 2048
+# base_url = "https://api.z.ai/api/coding/paas/v4"
+
+# Profiles override the settings above when selected with
+# ANKI_LEARN_PROFILE=<name>
+# [profiles.dev]
+# anki_connect_url = "http://localhost:8766"
+# default_deck = "Test"
+"#;
+
+/// One or more API keys of a provider.
+///
+/// Deserializes from a comma-separated string (as environment
+/// variables are) or from a list; blank entries are dropped.
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Serialize,
+)]
+pub struct ApiKeys(Vec<Secret<String>>);
+
+impl ApiKeys {
+    /// Parses a comma-separated key list
+    pub fn parse(keys: &str) -> Self {
+        Self::from_iter(keys.split(','))
+    }
+
+    /// The first key, for callers that use a single key
+    pub fn first(&self) -> Option<&Secret<String>> {
+        self.0.first()
+    }
+
+    /// All keys in configured order
+    pub fn as_slice(&self) -> &[Secret<String>] {
+        &self.0
+    }
+
+    /// Whether no key is configured
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<S: AsRef<str>> FromIterator<S> for ApiKeys {
+    fn from_iter<I: IntoIterator<Item = S>>(
+        iter: I,
+    ) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|key| key.as_ref().trim().to_string())
+                .filter(|key| !key.is_empty())
+                .map(Secret::new)
+                .collect(),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ApiKeys {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            One(String),
+            Many(Vec<String>),
+        }
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::One(keys) => Self::parse(&keys),
+            Raw::Many(keys) => Self::from_iter(keys),
+        })
+    }
+}
+
+/// Keys accepted in a `[providers.<name>]` section
+pub const PROVIDER_DEFAULT_KEYS: [&str; 4] = [
+    "default_model",
+    "temperature",
+    "max_tokens",
+    "base_url",
+];
+
+/// Request defaults of one AI provider, applied when a request
+/// leaves the value unspecified
+#[derive(
+    Debug, Clone, Default, PartialEq, Deserialize, Serialize,
+)]
+pub struct ProviderDefaults {
+    /// Model used when the request names none
+    pub default_model: Option<String>,
+    /// Sampling temperature
+    pub temperature: Option<f32>,
+    /// Upper bound on generated tokens
+    pub max_tokens: Option<u32>,
+    /// API base URL replacing the provider's public endpoint
+    pub base_url: Option<String>,
+    /// Misspelled or unsupported keys, reported by
+    /// [`Settings::warnings`]
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl ProviderDefaults {
+    /// Keys of the section that are not in
+    /// [`PROVIDER_DEFAULT_KEYS`]
+    pub fn unknown_keys(&self) -> Vec<&str> {
+        self.unknown.keys().map(String::as_str).collect()
+    }
+}
+
+/// Typed application settings
+///
+/// API keys are [`Secret`]s: `Debug` output and [`Settings::to_toml`]
+/// redact them unless an export explicitly asks for them.
+#[derive(
+    Debug, Clone, PartialEq, Deserialize, Serialize,
+)]
+pub struct Settings {
+    /// ZhiPu API keys
+    #[serde(default)]
+    pub zhi_pu_api_key: ApiKeys,
+    /// OpenAI API keys
+    #[serde(default)]
+    pub openai_api_key: ApiKeys,
+    /// DeepSeek API keys
+    #[serde(default)]
+    pub deepseek_api_key: ApiKeys,
+    /// Anki-Connect endpoint URL
+    pub anki_connect_url: String,
+    /// Anki-Connect API key, when the add-on requires one
+    pub anki_connect_key: Option<Secret<String>>,
+    /// Timeout of Anki-Connect requests, in seconds
+    pub anki_request_timeout_secs: u64,
+    /// Deck used when none is given
+    pub default_deck: String,
+    /// Note type used when none is given
+    pub default_model: String,
+    /// Retries after a failed request
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds
+    pub retry_delay_ms: u64,
+    /// Request timeout, in seconds
+    pub request_timeout_secs: u64,
+    /// Per-provider request defaults, keyed by
+    /// [`Provider::section`] (`[providers.zhipu]`)
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub providers: BTreeMap<String, ProviderDefaults>,
+    /// Profile selected at load time
+    #[serde(skip)]
+    active_profile: Option<String>,
+    /// Origin of every value not left at its default
+    #[serde(skip)]
+    sources: BTreeMap<&'static str, SettingSource>,
+}
+
+/// Every settings key, in declaration order
+const SETTING_KEYS: [&str; 12] = [
+    "zhi_pu_api_key",
+    "openai_api_key",
+    "deepseek_api_key",
+    "anki_connect_url",
+    "anki_connect_key",
+    "anki_request_timeout_secs",
+    "default_deck",
+    "default_model",
+    "max_retries",
+    "retry_delay_ms",
+    "request_timeout_secs",
+    "providers",
+];
+
+/// Keys with a built-in default value
+const DEFAULTED_KEYS: [&str; 7] = [
+    "anki_connect_url",
+    "anki_request_timeout_secs",
+    "default_deck",
+    "default_model",
+    "max_retries",
+    "retry_delay_ms",
+    "request_timeout_secs",
+];
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingSource {
+    /// Built-in default
+    Default,
+    /// Not set anywhere
+    Unset,
+    /// A TOML config file
+    File(PathBuf),
+    /// The selected `[profiles.<name>]` table
+    Profile(String),
+    /// A dotenv file
+    DotEnv(PathBuf),
+    /// The process environment
+    Environment,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            SettingSource::Default => {
+                f.write_str("default")
+            }
+            SettingSource::Unset => f.write_str("unset"),
+            SettingSource::File(path) => {
+                write!(f, "config file {}", path.display())
+            }
+            SettingSource::Profile(name) => {
+                write!(f, "profile {}", name)
+            }
+            SettingSource::DotEnv(path) => {
+                write!(f, "dotenv {}", path.display())
+            }
+            SettingSource::Environment => {
+                f.write_str("environment")
+            }
+        }
+    }
+}
+
+impl Settings {
+    /// Loads settings from [`default_paths`] and the process
+    /// environment, applying the profile named by
+    /// `ANKI_LEARN_PROFILE` if set
+    pub fn load() -> Result<Self, config::ConfigError> {
+        Self::load_profile(None)
+    }
+
+    /// Like [`Settings::load`], with an explicit profile that takes
+    /// precedence over `ANKI_LEARN_PROFILE`
+    pub fn load_profile(
+        profile: Option<&str>,
+    ) -> Result<Self, config::ConfigError> {
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| std::env::var(PROFILE_ENV).ok());
+        Self::load_profile_from(
+            &default_paths(),
+            profile.as_deref(),
+            None,
+        )
+    }
+
+    /// Loads settings from the given files and the process
+    /// environment.
+    ///
+    /// Files named `.env*` (or `*.env`) are read as dotenv files,
+    /// all others as TOML; missing files are skipped. Dotenv files
+    /// override TOML files and later files override earlier ones of
+    /// the same kind.
+    pub fn load_from(
+        paths: &[PathBuf],
+    ) -> Result<Self, config::ConfigError> {
+        let profile = std::env::var(PROFILE_ENV).ok();
+        Self::load_profile_from(
+            paths,
+            profile.as_deref(),
+            None,
+        )
+    }
+
+    /// Like [`Settings::load_from`], with `env` standing in for the
+    /// process environment, which keeps tests independent of the
+    /// machine they run on
+    pub fn load_with_env(
+        paths: &[PathBuf],
+        env: HashMap<String, String>,
+    ) -> Result<Self, config::ConfigError> {
+        let profile = env.get(PROFILE_ENV).cloned();
+        Self::load_profile_from(
+            paths,
+            profile.as_deref(),
+            Some(env),
+        )
+    }
+
+    /// Loads settings with the `[profiles.<profile>]` table of the
+    /// TOML files layered between those files and the dotenv files.
+    ///
+    /// `env` replaces the process environment when given.
+    ///
+    /// # Errors
+    /// Fails when `profile` is given but not defined in any file.
+    pub fn load_profile_from(
+        paths: &[PathBuf],
+        profile: Option<&str>,
+        env: Option<HashMap<String, String>>,
+    ) -> Result<Self, config::ConfigError> {
+        let (dotenv_paths, toml_paths): (Vec<_>, Vec<_>) =
+            paths.iter().partition(|path| is_dotenv(path));
+
+        let mut layers = Vec::new();
+        for path in &toml_paths {
+            let file = File::from(path.as_path())
+                .format(FileFormat::Toml)
+                .required(false);
+            layers.push((
+                SettingSource::File(path.to_path_buf()),
+                Config::builder()
+                    .add_source(file)
+                    .build()?,
+            ));
+        }
+        if let Some(name) = profile {
+            let overrides = profile_overrides(
+                &layers,
+                &toml_paths,
+                name,
+            )?;
+            layers.push((
+                SettingSource::Profile(name.to_string()),
+                overrides,
+            ));
+        }
+        for path in &dotenv_paths {
+            if let Some(vars) = read_dotenv(path)? {
+                layers.push((
+                    SettingSource::DotEnv(
+                        path.to_path_buf(),
+                    ),
+                    Config::builder()
+                        .add_source(
+                            Environment::default()
+                                .source(Some(vars)),
+                        )
+                        .build()?,
+                ));
+            }
+        }
+        layers.push((
+            SettingSource::Environment,
+            Config::builder()
+                .add_source(
+                    Environment::default().source(env),
+                )
+                .build()?,
+        ));
+
+        let mut builder = Config::builder()
+            .set_default(
+                "anki_connect_url",
+                "http://localhost:8765",
+            )?
+            .set_default("anki_request_timeout_secs", 30)?
+            .set_default("default_deck", "Default")?
+            .set_default("default_model", "Basic")?
+            .set_default("max_retries", 3)?
+            .set_default("retry_delay_ms", 1000)?
+            .set_default("request_timeout_secs", 60)?;
+        let mut sources = BTreeMap::new();
+        for (source, layer) in layers {
+            for key in SETTING_KEYS {
+                if layer.get::<config::Value>(key).is_ok() {
+                    sources.insert(key, source.clone());
+                }
+            }
+            builder = builder.add_source(layer);
+        }
+
+        let mut settings: Self =
+            builder.build()?.try_deserialize()?;
+        settings.active_profile =
+            profile.map(str::to_string);
+        settings.sources = sources;
+        for warning in settings.warnings() {
+            log::warn!("{}", warning);
+        }
+        Ok(settings)
+    }
+
+    /// Problems that do not prevent loading, such as unknown keys
+    /// in a `[providers.<name>]` section
+    pub fn warnings(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .flat_map(|(name, defaults)| {
+                defaults.unknown_keys().into_iter().map(
+                    move |key| {
+                        format!(
+                            "providers.{}: unknown key `{}` is ignored (valid keys: {})",
+                            name,
+                            key,
+                            PROVIDER_DEFAULT_KEYS.join(", ")
+                        )
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Request defaults configured for the provider section `name`
+    /// (e.g. `zhipu`); all `None` when there is no such section
+    pub fn provider_defaults(
+        &self,
+        name: &str,
+    ) -> ProviderDefaults {
+        self.providers
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Where the value of `key` came from
+    pub fn source_of(&self, key: &str) -> SettingSource {
+        self.sources.get(key).cloned().unwrap_or(
+            if DEFAULTED_KEYS.contains(&key) {
+                SettingSource::Default
+            } else {
+                SettingSource::Unset
+            },
+        )
+    }
+
+    /// Keys whose values differ between `self` and `other`,
+    /// secrets included
+    pub fn changed_keys(
+        &self,
+        other: &Settings,
+    ) -> Vec<&'static str> {
+        let (before, after) = expose_secrets(|| {
+            (
+                toml::Table::try_from(self)
+                    .unwrap_or_default(),
+                toml::Table::try_from(other)
+                    .unwrap_or_default(),
+            )
+        });
+        SETTING_KEYS
+            .into_iter()
+            .filter(|key| {
+                before.get(*key) != after.get(*key)
+            })
+            .collect()
+    }
+
+    /// One line per setting with its value and origin; secrets are
+    /// redacted
+    pub fn describe(&self) -> String {
+        let values =
+            toml::Table::try_from(self).unwrap_or_default();
+        let mut lines = Vec::new();
+        if let Some(profile) = self.active_profile() {
+            lines.push(format!("profile: {}", profile));
+        }
+        for key in SETTING_KEYS {
+            let value = values
+                .get(key)
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "(unset)".to_string());
+            lines.push(format!(
+                "{} = {} ({})",
+                key,
+                value,
+                self.source_of(key)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// The profile these settings were loaded with, if any.
+    ///
+    /// Tools should print it before doing anything destructive.
+    pub fn active_profile(&self) -> Option<&str> {
+        self.active_profile.as_deref()
+    }
+
+    /// Renders the settings as TOML.
+    ///
+    /// Secrets are written as `***redacted***` unless
+    /// `include_secrets` is set, e.g. for a full backup.
+    pub fn to_toml(
+        &self,
+        include_secrets: bool,
+    ) -> Result<String, toml::ser::Error> {
+        if include_secrets {
+            expose_secrets(|| toml::to_string(self))
+        } else {
+            toml::to_string(self)
+        }
+    }
+
+    /// Checks that every setting the current run needs is usable.
+    ///
+    /// All problems are reported at once so they can be fixed in a
+    /// single pass.
+    pub fn validate(
+        &self,
+        required: &Capabilities,
+    ) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        for provider in &required.providers {
+            if self.provider_keys(*provider).is_empty() {
+                errors.push(ConfigError::new(
+                    provider.key_setting(),
+                    format!(
+                        "{} API key is missing",
+                        provider.name()
+                    ),
+                    format!(
+                        "add at least one {} API key (several keys may be comma-separated)",
+                        provider.name()
+                    ),
+                ));
+            }
+        }
+
+        if required.anki {
+            if let Err(problem) =
+                check_url(&self.anki_connect_url)
+            {
+                errors.push(ConfigError::new(
+                    "anki_connect_url",
+                    problem,
+                    "use a full URL such as http://localhost:8765",
+                ));
+            }
+            if self.anki_request_timeout_secs == 0 {
+                errors.push(ConfigError::new(
+                    "anki_request_timeout_secs",
+                    "Anki-Connect timeout is zero",
+                    "use a timeout of at least 1 second, e.g. 30",
+                ));
+            }
+            if self.default_deck.trim().is_empty() {
+                errors.push(ConfigError::new(
+                    "default_deck",
+                    "default deck is empty",
+                    "name the deck new notes go to, e.g. Default",
+                ));
+            }
+            if self.default_model.trim().is_empty() {
+                errors.push(ConfigError::new(
+                    "default_model",
+                    "default note type is empty",
+                    "name the note type of new notes, e.g. Basic",
+                ));
+            }
+        }
+
+        if self.request_timeout_secs == 0 {
+            errors.push(ConfigError::new(
+                "request_timeout_secs",
+                "request timeout is zero",
+                "use a timeout of at least 1 second, e.g. 60",
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Loads the settings and validates them for `required`,
+    /// joining every problem into one error message
+    pub fn load_validated(
+        required: &Capabilities,
+    ) -> anyhow::Result<Self> {
+        let settings = Self::load()?;
+        settings.validate(required).map_err(|errors| {
+            anyhow::anyhow!("{}", format_errors(&errors))
+        })?;
+        Ok(settings)
+    }
+
+    /// Keys configured for `provider`
+    pub fn provider_keys(
+        &self,
+        provider: Provider,
+    ) -> &ApiKeys {
+        match provider {
+            Provider::ZhiPu => &self.zhi_pu_api_key,
+            Provider::OpenAi => &self.openai_api_key,
+            Provider::DeepSeek => &self.deepseek_api_key,
+        }
+    }
+
+    /// Writes a commented example config to `path`, creating parent
+    /// directories. An existing file is never overwritten.
+    pub fn write_template(
+        path: &Path,
+    ) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|mut file| {
+                std::io::Write::write_all(
+                    &mut file,
+                    TEMPLATE.as_bytes(),
+                )
+            })
+    }
+}
+
+/// AI providers with configurable keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Provider {
+    ZhiPu,
+    OpenAi,
+    DeepSeek,
+}
+
+impl Provider {
+    /// Human-readable provider name
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::ZhiPu => "ZhiPu",
+            Provider::OpenAi => "OpenAI",
+            Provider::DeepSeek => "DeepSeek",
+        }
+    }
+
+    /// Name of the provider's `[providers.<name>]` config section
+    pub fn section(self) -> &'static str {
+        match self {
+            Provider::ZhiPu => "zhipu",
+            Provider::OpenAi => "openai",
+            Provider::DeepSeek => "deepseek",
+        }
+    }
+
+    /// Config key holding the provider's API keys
+    pub fn key_setting(self) -> &'static str {
+        match self {
+            Provider::ZhiPu => "zhi_pu_api_key",
+            Provider::OpenAi => "openai_api_key",
+            Provider::DeepSeek => "deepseek_api_key",
+        }
+    }
+}
+
+/// What the current run needs from the settings
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Providers that will be called
+    pub providers: Vec<Provider>,
+    /// Whether Anki-Connect will be used
+    pub anki: bool,
+}
+
+impl Capabilities {
+    /// Requires nothing beyond the always-checked settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires keys for `provider`
+    pub fn provider(mut self, provider: Provider) -> Self {
+        if !self.providers.contains(&provider) {
+            self.providers.push(provider);
+        }
+        self
+    }
+
+    /// Requires Anki-Connect access
+    pub fn anki(mut self) -> Self {
+        self.anki = true;
+        self
+    }
+}
+
+/// A setting that is missing or unusable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Config file key of the setting
+    pub config_key: &'static str,
+    /// Environment variable that also sets it
+    pub env_var: String,
+    /// What is wrong
+    pub problem: String,
+    /// One-line fix
+    pub hint: String,
+}
+
+impl ConfigError {
+    fn new(
+        config_key: &'static str,
+        problem: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            config_key,
+            env_var: config_key.to_uppercase(),
+            problem: problem.into(),
+            hint: hint.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} (set {} or `{}` in the config file; {})",
+            self.config_key,
+            self.problem,
+            self.env_var,
+            self.config_key,
+            self.hint
+        )
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Renders all problems, one per line, under a summary line
+pub fn format_errors(errors: &[ConfigError]) -> String {
+    let mut message = format!(
+        "{} configuration problem(s) in {}:",
+        errors.len(),
+        default_config_path().display()
+    );
+    for error in errors {
+        message.push_str("\n  - ");
+        message.push_str(&error.to_string());
+    }
+    message
+}
+
+/// Why `url` is not a usable Anki-Connect URL
+fn check_url(url: &str) -> Result<(), String> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return Err(format!("URL '{}' has no scheme", url));
+    };
+    if !matches!(scheme, "http" | "https") {
+        return Err(format!(
+            "URL '{}' must use http or https",
+            url
+        ));
+    }
+    let host =
+        rest.split(['/', '?', '#']).next().unwrap_or("");
+    if host.is_empty() {
+        return Err(format!("URL '{}' has no host", url));
+    }
+    Ok(())
+}
+
+/// The `[profiles.<name>]` table of the TOML layers as a source
+fn profile_overrides(
+    layers: &[(SettingSource, Config)],
+    paths: &[&PathBuf],
+    name: &str,
+) -> Result<Config, config::ConfigError> {
+    let mut profiles = HashMap::new();
+    for (_, layer) in layers {
+        profiles.extend(
+            layer.get_table("profiles").unwrap_or_default(),
+        );
+    }
+    let Some(table) = profiles.get(name) else {
+        let mut available: Vec<&str> =
+            profiles.keys().map(String::as_str).collect();
+        available.sort_unstable();
+        let files: Vec<String> = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(config::ConfigError::Message(format!(
+            "profile '{}' is not defined in {} (available: {})",
+            name,
+            files.join(", "),
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        )));
+    };
+    let table = table.clone().into_table()?;
+    table
+        .into_iter()
+        .try_fold(
+            Config::builder(),
+            |builder, (key, value)| {
+                builder.set_default(key, value)
+            },
+        )?
+        .build()
+}
+
+/// Whether `path` names a dotenv file (`.env`, `.env.local`,
+/// `prod.env`)
+fn is_dotenv(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            name.starts_with(".env")
+                || name.ends_with(".env")
+        })
+}
+
+/// Variables of a dotenv file, `None` if it does not exist
+pub(crate) fn read_dotenv(
+    path: &Path,
+) -> Result<
+    Option<HashMap<String, String>>,
+    config::ConfigError,
+> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let parse_error = |e: dotenvy::Error| {
+        config::ConfigError::FileParse {
+            uri: Some(path.display().to_string()),
+            cause: Box::new(e),
+        }
+    };
+    dotenvy::from_path_iter(path)
+        .map_err(parse_error)?
+        .map(|item| item.map_err(parse_error))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Files read by [`Settings::load`], lowest precedence first: the
+/// config file, then `.env` and `.env.local` in the working
+/// directory
+pub fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![default_config_path()];
+    paths.extend(default_dotenv_paths());
+    paths
+}
+
+/// The dotenv files of [`default_paths`], lowest precedence first
+pub fn default_dotenv_paths() -> [PathBuf; 2] {
+    [PathBuf::from(".env"), PathBuf::from(".env.local")]
+}
+
+/// Config file location: `$ANKI_LEARN_CONFIG`, else `config.toml`
+/// in the [`AppDir::Config`] directory
+/// (`~/.config/anki_learn/config.toml` on Linux)
+pub fn default_config_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(CONFIG_PATH_ENV) {
+        return PathBuf::from(path);
+    }
+    AppDir::Config
+        .path()
+        .unwrap_or_default()
+        .join("config.toml")
+}
+
+/// Global settings
+pub static SETTINGS: LazyLock<Settings> =
+    LazyLock::new(|| {
+        Settings::load().unwrap_or_else(|e| {
+            panic!("Failed to load settings: {}", e)
+        })
+    });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::secret::REDACTED;
+    use std::slice;
+
+    /// Fresh directory under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_settings_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn env(
+        pairs: &[(&str, &str)],
+    ) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults_without_file() {
+        let dir = temp_dir("defaults");
+        let settings = Settings::load_with_env(
+            &[dir.join("missing.toml")],
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.anki_connect_url,
+            "http://localhost:8765"
+        );
+        assert_eq!(settings.default_model, "Basic");
+        assert_eq!(settings.max_retries, 3);
+        assert!(settings.zhi_pu_api_key.is_empty());
+    }
+
+    #[test]
+    fn test_env_over_file_over_default() {
+        let dir = temp_dir("precedence");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "default_deck = \"FromFile\"\nmax_retries = 7\nzhi_pu_api_key = \"file-key\"\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_with_env(
+            slice::from_ref(&path),
+            env(&[
+                ("ZHI_PU_API_KEY", "env-key"),
+                ("REQUEST_TIMEOUT_SECS", "5"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            settings
+                .zhi_pu_api_key
+                .first()
+                .map(|key| key.expose().as_str()),
+            Some("env-key")
+        );
+        assert_eq!(settings.default_deck, "FromFile");
+        assert_eq!(settings.max_retries, 7);
+        assert_eq!(settings.request_timeout_secs, 5);
+        assert_eq!(settings.default_model, "Basic");
+    }
+
+    #[test]
+    fn test_key_lists_from_env_and_file() {
+        let dir = temp_dir("key_lists");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "openai_api_key = [\"o1\", \" \", \"o2\"]\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_with_env(
+            slice::from_ref(&path),
+            env(&[("ZHI_PU_API_KEY", "k1, k2,,")]),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.zhi_pu_api_key,
+            ApiKeys::parse("k1,k2")
+        );
+        assert_eq!(
+            settings.openai_api_key,
+            ApiKeys::parse("o1,o2")
+        );
+        assert!(settings.deepseek_api_key.is_empty());
+    }
+
+    #[test]
+    fn test_dotenv_layers_precedence() {
+        let dir = temp_dir("dotenv");
+        let config = dir.join("config.toml");
+        let dotenv = dir.join(".env");
+        let local = dir.join(".env.local");
+        std::fs::write(
+            &config,
+            "default_deck = \"FromFile\"\ndefault_model = \"FromFile\"\nmax_retries = 7\nretry_delay_ms = 5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &dotenv,
+            "DEFAULT_DECK=FromDotEnv\nDEFAULT_MODEL=FromDotEnv\nMAX_RETRIES=8\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &local,
+            "DEFAULT_DECK=FromLocal\nDEFAULT_MODEL=FromLocal\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_with_env(
+            &[
+                config.clone(),
+                dotenv.clone(),
+                local.clone(),
+            ],
+            env(&[("DEFAULT_DECK", "FromEnv")]),
+        )
+        .unwrap();
+        assert_eq!(settings.default_deck, "FromEnv");
+        assert_eq!(settings.default_model, "FromLocal");
+        assert_eq!(settings.max_retries, 8);
+        assert_eq!(settings.retry_delay_ms, 5);
+        assert_eq!(settings.request_timeout_secs, 60);
+
+        assert_eq!(
+            settings.source_of("default_deck"),
+            SettingSource::Environment
+        );
+        assert_eq!(
+            settings.source_of("default_model"),
+            SettingSource::DotEnv(local)
+        );
+        assert_eq!(
+            settings.source_of("max_retries"),
+            SettingSource::DotEnv(dotenv)
+        );
+        assert_eq!(
+            settings.source_of("retry_delay_ms"),
+            SettingSource::File(config)
+        );
+        assert_eq!(
+            settings.source_of("request_timeout_secs"),
+            SettingSource::Default
+        );
+        assert_eq!(
+            settings.source_of("openai_api_key"),
+            SettingSource::Unset
+        );
+    }
+
+    #[test]
+    fn test_malformed_dotenv_names_the_file() {
+        let dir = temp_dir("bad_dotenv");
+        let path = dir.join(".env");
+        std::fs::write(&path, "NOT A VALID LINE\n")
+            .unwrap();
+
+        let message = Settings::load_with_env(
+            slice::from_ref(&path),
+            HashMap::new(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            message.contains(&path.display().to_string()),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_describe_lists_sources_and_redacts() {
+        let dir = temp_dir("describe");
+        let dotenv = dir.join(".env");
+        std::fs::write(&dotenv, "DEFAULT_DECK=Words\n")
+            .unwrap();
+
+        let settings = Settings::load_with_env(
+            slice::from_ref(&dotenv),
+            env(&[("ZHI_PU_API_KEY", "sk-describe")]),
+        )
+        .unwrap();
+        let description = settings.describe();
+        assert!(
+            !description.contains("sk-describe"),
+            "{}",
+            description
+        );
+        let lines: Vec<&str> =
+            description.lines().collect();
+        assert_eq!(lines.len(), SETTING_KEYS.len());
+        assert_eq!(
+            lines[0],
+            format!(
+                "zhi_pu_api_key = [\"{}\"] (environment)",
+                REDACTED
+            )
+        );
+        assert!(lines.contains(
+            &"anki_connect_key = (unset) (unset)"
+        ));
+        assert!(
+            lines.contains(
+                &format!(
+                    "default_deck = \"Words\" (dotenv {})",
+                    dotenv.display()
+                )
+                .as_str()
+            )
+        );
+        assert!(lines.contains(
+            &"default_model = \"Basic\" (default)"
+        ));
+    }
+
+    #[test]
+    fn test_provider_defaults_section() {
+        let dir = temp_dir("providers");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[providers.zhipu]\ndefault_model = \"glm-4.7\"\ntemperature = 0.3\nmax_tokens = 512\ntemprature = 1.0\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_with_env(
+            slice::from_ref(&path),
+            HashMap::new(),
+        )
+        .unwrap();
+        let zhipu = settings
+            .provider_defaults(Provider::ZhiPu.section());
+        assert_eq!(
+            zhipu.default_model.as_deref(),
+            Some("glm-4.7")
+        );
+        assert_eq!(zhipu.temperature, Some(0.3));
+        assert_eq!(zhipu.max_tokens, Some(512));
+        assert_eq!(zhipu.base_url, None);
+        assert_eq!(
+            settings.provider_defaults("openai"),
+            ProviderDefaults::default()
+        );
+        assert_eq!(
+            settings.warnings(),
+            vec![
+                "providers.zhipu: unknown key `temprature` is ignored (valid keys: default_model, temperature, max_tokens, base_url)"
+            ]
+        );
+        assert!(
+            !settings
+                .to_toml(false)
+                .unwrap()
+                .contains("temprature")
+        );
+    }
+
+    fn valid_settings() -> Settings {
+        let mut settings = Settings::load_with_env(
+            &[PathBuf::from("/nonexistent/config.toml")],
+            HashMap::new(),
+        )
+        .unwrap();
+        settings.zhi_pu_api_key = ApiKeys::parse("key");
+        settings
+    }
+
+    fn messages(
+        settings: &Settings,
+        required: &Capabilities,
+    ) -> Vec<String> {
+        settings
+            .validate(required)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_accepts_complete_settings() {
+        let required = Capabilities::new()
+            .provider(Provider::ZhiPu)
+            .anki();
+        assert_eq!(
+            valid_settings().validate(&required),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_validate_missing_provider_keys() {
+        let mut settings = valid_settings();
+        settings.zhi_pu_api_key = ApiKeys::default();
+        let required = Capabilities::new()
+            .provider(Provider::ZhiPu)
+            .provider(Provider::DeepSeek);
+        assert_eq!(
+            messages(&settings, &required),
+            [
+                "zhi_pu_api_key: ZhiPu API key is missing (set ZHI_PU_API_KEY or `zhi_pu_api_key` in the config file; add at least one ZhiPu API key (several keys may be comma-separated))",
+                "deepseek_api_key: DeepSeek API key is missing (set DEEPSEEK_API_KEY or `deepseek_api_key` in the config file; add at least one DeepSeek API key (several keys may be comma-separated))",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_all_anki_problems() {
+        let mut settings = valid_settings();
+        settings.anki_connect_url =
+            "localhost:8765".to_string();
+        settings.default_deck = " ".to_string();
+        settings.request_timeout_secs = 0;
+        let errors = settings
+            .validate(&Capabilities::new().anki())
+            .unwrap_err();
+        let keys: Vec<&str> =
+            errors.iter().map(|e| e.config_key).collect();
+        assert_eq!(
+            keys,
+            [
+                "anki_connect_url",
+                "default_deck",
+                "request_timeout_secs"
+            ]
+        );
+        assert_eq!(errors[0].env_var, "ANKI_CONNECT_URL");
+        assert_eq!(
+            errors[0].problem,
+            "URL 'localhost:8765' has no scheme"
+        );
+    }
+
+    #[test]
+    fn test_validate_skips_unrequired_settings() {
+        let mut settings = valid_settings();
+        settings.zhi_pu_api_key = ApiKeys::default();
+        settings.anki_connect_url =
+            "ftp://host".to_string();
+        assert_eq!(
+            settings.validate(&Capabilities::new()),
+            Ok(())
+        );
+        assert_eq!(
+            messages(
+                &settings,
+                &Capabilities::new().anki()
+            ),
+            [
+                "anki_connect_url: URL 'ftp://host' must use http or https (set ANKI_CONNECT_URL or `anki_connect_url` in the config file; use a full URL such as http://localhost:8765)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_errors_lists_every_problem() {
+        let mut settings = valid_settings();
+        settings.zhi_pu_api_key = ApiKeys::default();
+        settings.request_timeout_secs = 0;
+        let errors = settings
+            .validate(
+                &Capabilities::new()
+                    .provider(Provider::ZhiPu),
+            )
+            .unwrap_err();
+        let message = format_errors(&errors);
+        assert!(
+            message
+                .starts_with("2 configuration problem(s)")
+        );
+        assert_eq!(message.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let mut settings = valid_settings();
+        settings.zhi_pu_api_key =
+            ApiKeys::parse("zp-secret-1,zp-secret-2");
+        settings.anki_connect_key =
+            Some(Secret::from("anki-secret"));
+
+        let debug = format!("{:?}", settings);
+        let exported = settings.to_toml(false).unwrap();
+        for text in [&debug, &exported] {
+            assert!(
+                !text.contains("zp-secret"),
+                "{}",
+                text
+            );
+            assert!(
+                !text.contains("anki-secret"),
+                "{}",
+                text
+            );
+        }
+
+        let full = settings.to_toml(true).unwrap();
+        assert!(full.contains("zp-secret-2"));
+        assert!(full.contains("anki-secret"));
+    }
+
+    const PROFILES: &str = r#"
+default_deck = "Personal"
+default_model = "Basic"
+
+[profiles.dev]
+anki_connect_url = "http://localhost:8766"
+default_deck = "Scratch"
+
+[profiles.work]
+default_model = "Cloze"
+"#;
+
+    #[test]
+    fn test_profile_overrides_base_settings() {
+        let dir = temp_dir("profiles");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, PROFILES).unwrap();
+
+        let base = Settings::load_with_env(
+            slice::from_ref(&path),
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(base.active_profile(), None);
+        assert_eq!(base.default_deck, "Personal");
+
+        let dev = Settings::load_with_env(
+            slice::from_ref(&path),
+            env(&[("ANKI_LEARN_PROFILE", "dev")]),
+        )
+        .unwrap();
+        assert_eq!(dev.active_profile(), Some("dev"));
+        assert_eq!(
+            dev.anki_connect_url,
+            "http://localhost:8766"
+        );
+        assert_eq!(dev.default_deck, "Scratch");
+        assert_eq!(dev.default_model, "Basic");
+    }
+
+    #[test]
+    fn test_argument_profile_and_env_precedence() {
+        let dir = temp_dir("profile_arg");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, PROFILES).unwrap();
+
+        let work = Settings::load_profile_from(
+            slice::from_ref(&path),
+            Some("work"),
+            Some(env(&[("DEFAULT_MODEL", "FromEnv")])),
+        )
+        .unwrap();
+        assert_eq!(work.active_profile(), Some("work"));
+        assert_eq!(work.default_deck, "Personal");
+        assert_eq!(work.default_model, "FromEnv");
+    }
+
+    #[test]
+    fn test_unknown_profile_is_an_error() {
+        let dir = temp_dir("profile_unknown");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, PROFILES).unwrap();
+
+        let message = Settings::load_profile_from(
+            slice::from_ref(&path),
+            Some("prod"),
+            Some(HashMap::new()),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            message
+                .contains("profile 'prod' is not defined"),
+            "{}",
+            message
+        );
+        assert!(message.contains("available: dev, work"));
+    }
+
+    #[test]
+    fn test_malformed_toml_reports_path() {
+        let dir = temp_dir("malformed");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "default_deck = \"unclosed\n",
+        )
+        .unwrap();
+
+        let message = Settings::load_with_env(
+            slice::from_ref(&path),
+            HashMap::new(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            message.contains("config.toml"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_wrong_type_reports_path_and_key() {
+        let dir = temp_dir("wrong_type");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "max_retries = \"many\"\n")
+            .unwrap();
+
+        let message = Settings::load_with_env(
+            slice::from_ref(&path),
+            HashMap::new(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            message.contains("max_retries"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("config.toml"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_template_parses_and_is_not_overwritten() {
+        let dir = temp_dir("template");
+        let path = dir.join("nested").join("config.toml");
+        Settings::write_template(&path).unwrap();
+
+        let settings = Settings::load_with_env(
+            slice::from_ref(&path),
+            HashMap::new(),
+        )
+        .unwrap();
+        let defaults = Settings::load_with_env(
+            &[dir.join("missing.toml")],
+            HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.to_toml(true).unwrap(),
+            defaults.to_toml(true).unwrap()
+        );
+        assert_eq!(
+            settings.source_of("default_deck"),
+            SettingSource::File(path.clone())
+        );
+        assert!(Settings::write_template(&path).is_err());
+    }
+}
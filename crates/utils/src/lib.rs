@@ -1,3 +1,6 @@
 pub mod config;
+pub mod paths;
+pub mod state;
+pub mod text;
 pub mod tools;
-mod utils;
+pub mod utils;
@@ -0,0 +1,180 @@
+//! Per-user directories of the application.
+//!
+//! Each kind lives in the platform's usual place (XDG directories on
+//! Linux, `~/Library` on macOS, `%APPDATA%`/`%LOCALAPPDATA%` on
+//! Windows) below an `anki_learn` folder, unless its environment
+//! variable (`ANKI_LEARN_CONFIG_DIR`, `ANKI_LEARN_CACHE_DIR`,
+//! `ANKI_LEARN_DATA_DIR`) names another directory.
+//!
+//! Anything the application writes to disk (response cache, offline
+//! queues, snapshots) belongs under one of these rather than in the
+//! working directory.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Directory name below the platform base directories
+pub const APP_DIR_NAME: &str = "anki_learn";
+
+/// Kind of per-user directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppDir {
+    /// Configuration files
+    Config,
+    /// Data that can be rebuilt at any time
+    Cache,
+    /// Data the user would miss, such as queues and snapshots
+    Data,
+}
+
+impl AppDir {
+    /// Environment variable replacing the platform location
+    pub fn env_var(self) -> &'static str {
+        match self {
+            AppDir::Config => "ANKI_LEARN_CONFIG_DIR",
+            AppDir::Cache => "ANKI_LEARN_CACHE_DIR",
+            AppDir::Data => "ANKI_LEARN_DATA_DIR",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AppDir::Config => "config",
+            AppDir::Cache => "cache",
+            AppDir::Data => "data",
+        }
+    }
+
+    /// The directory, without creating it; `None` if the platform
+    /// has no home directory and no override is set
+    pub fn path(self) -> Option<PathBuf> {
+        self.resolve(std::env::var_os(self.env_var()))
+    }
+
+    /// The directory, created (with its parents) if missing
+    pub fn ensure(self) -> Result<PathBuf> {
+        let path = self.path().with_context(|| {
+            format!(
+                "No {} directory: the home directory is unknown; set {}",
+                self.name(),
+                self.env_var()
+            )
+        })?;
+        self.create(&path)?;
+        Ok(path)
+    }
+
+    /// The override if set and non-empty, else the platform location
+    fn resolve(
+        self,
+        override_dir: Option<OsString>,
+    ) -> Option<PathBuf> {
+        if let Some(dir) =
+            override_dir.filter(|dir| !dir.is_empty())
+        {
+            return Some(PathBuf::from(dir));
+        }
+        let base = match self {
+            AppDir::Config => dirs::config_dir(),
+            AppDir::Cache => dirs::cache_dir(),
+            AppDir::Data => dirs::data_dir(),
+        };
+        base.map(|base| base.join(APP_DIR_NAME))
+    }
+
+    fn create(self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path).with_context(|| {
+            format!(
+                "Failed to create {} directory {} (set {} to use another location)",
+                self.name(),
+                path.display(),
+                self.env_var()
+            )
+        })
+    }
+}
+
+/// Configuration directory, created if missing
+pub fn config_dir() -> Result<PathBuf> {
+    AppDir::Config.ensure()
+}
+
+/// Cache directory, created if missing
+pub fn cache_dir() -> Result<PathBuf> {
+    AppDir::Cache.ensure()
+}
+
+/// Data directory, created if missing
+pub fn data_dir() -> Result<PathBuf> {
+    AppDir::Data.ensure()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anki_learn_paths_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_override_wins_over_platform_location() {
+        let dir = temp_dir("override");
+        for kind in
+            [AppDir::Config, AppDir::Cache, AppDir::Data]
+        {
+            assert_eq!(
+                kind.resolve(Some(
+                    dir.clone().into_os_string()
+                )),
+                Some(dir.clone())
+            );
+            if let Some(platform) = kind.resolve(None) {
+                assert!(platform.ends_with(APP_DIR_NAME));
+            }
+            assert_eq!(
+                kind.resolve(Some(OsString::new())),
+                kind.resolve(None)
+            );
+        }
+    }
+
+    #[test]
+    fn test_create_makes_nested_directories() {
+        let dir = temp_dir("nested").join("a").join("b");
+        AppDir::Cache.create(&dir).unwrap();
+        assert!(dir.is_dir());
+        // Already existing is fine
+        AppDir::Cache.create(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_creation_failure_names_path_and_override() {
+        let root = temp_dir("blocked");
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("not_a_dir");
+        std::fs::write(&file, "").unwrap();
+        let target = file.join("data");
+
+        let message = format!(
+            "{:#}",
+            AppDir::Data.create(&target).unwrap_err()
+        );
+        assert!(
+            message.starts_with(&format!(
+                "Failed to create data directory {} (set ANKI_LEARN_DATA_DIR",
+                target.display()
+            )),
+            "{}",
+            message
+        );
+    }
+}
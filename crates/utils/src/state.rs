@@ -0,0 +1,52 @@
+//! Local state that outlives a single run: which note each imported
+//! item became, plus caches and history.
+//!
+//! [`MappingStore`] is the interface importers use for item → note
+//! mappings. A JSON manifest per source is enough for small setups;
+//! the SQLite [`sqlite::StateStore`] (feature `sqlite`) scales to
+//! large sources and answers queries across them.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[cfg(any(test, feature = "sqlite"))]
+pub mod sqlite;
+
+/// What is remembered about one imported item
+#[derive(
+    Debug, Clone, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct ManifestEntry {
+    /// Note created for the item
+    pub note_id: u64,
+    /// Hash of the content the note was last written from
+    pub content_hash: String,
+}
+
+/// Item key → note mappings of one import source
+pub trait MappingStore {
+    /// Entry recorded for `key`
+    fn mapping(
+        &self,
+        key: &str,
+    ) -> Result<Option<ManifestEntry>>;
+
+    /// All entries, sorted by key
+    fn mappings(
+        &self,
+    ) -> Result<BTreeMap<String, ManifestEntry>>;
+
+    /// Records `entries`, replacing those with the same key.
+    ///
+    /// Either every entry is stored or, on error, none is.
+    fn save_mappings(
+        &mut self,
+        entries: Vec<(String, ManifestEntry)>,
+    ) -> Result<()>;
+
+    /// Forgets `key`; `false` if it was not recorded
+    fn remove_mapping(&mut self, key: &str)
+    -> Result<bool>;
+}
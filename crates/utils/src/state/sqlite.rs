@@ -0,0 +1,594 @@
+//! SQLite-backed local state.
+//!
+//! One database holds the item → note mappings of every import
+//! source, cached embeddings and the history of import runs. The
+//! schema migrations are embedded and applied when the database is
+//! opened, and every write runs in a transaction, so a crash midway
+//! leaves the previous state intact.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{
+    Connection, OptionalExtension, Transaction, params,
+};
+
+use super::{ManifestEntry, MappingStore};
+use crate::paths::AppDir;
+
+/// File name of the default database in the data directory
+pub const STATE_FILE_NAME: &str = "state.sqlite3";
+
+/// Schema migrations; `PRAGMA user_version` counts those applied.
+///
+/// Append new steps, never edit applied ones.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE mappings (
+        source TEXT NOT NULL,
+        key TEXT NOT NULL,
+        note_id INTEGER NOT NULL,
+        content_hash TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (source, key)
+    );
+    CREATE INDEX mappings_note_id ON mappings (note_id);
+    CREATE TABLE embeddings (
+        model TEXT NOT NULL,
+        text TEXT NOT NULL,
+        vector BLOB NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (model, text)
+    );
+    CREATE TABLE import_runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        source TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER,
+        created INTEGER NOT NULL DEFAULT 0,
+        updated INTEGER NOT NULL DEFAULT 0,
+        skipped INTEGER NOT NULL DEFAULT 0,
+        repaired INTEGER NOT NULL DEFAULT 0,
+        failed INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX import_runs_source ON import_runs (source);
+"];
+
+/// Item counts of an import run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunCounts {
+    /// Items added as new notes
+    pub created: usize,
+    /// Items whose note was updated
+    pub updated: usize,
+    /// Unchanged items
+    pub skipped: usize,
+    /// Items whose deleted note was added again
+    pub repaired: usize,
+    /// Items that could not be imported
+    pub failed: usize,
+}
+
+/// One recorded import run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportRun {
+    /// Run ID, increasing with every run
+    pub id: i64,
+    /// Import source the run read
+    pub source: String,
+    /// When the run started
+    pub started_at: DateTime<Utc>,
+    /// When the run finished; `None` if it never did
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Item counts, zero until the run finished
+    pub counts: RunCounts,
+}
+
+/// Local state database
+#[derive(Debug)]
+pub struct StateStore {
+    conn: Connection,
+}
+
+impl StateStore {
+    /// Opens (creating if needed) the database at `path` and
+    /// applies pending migrations
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let conn =
+            Connection::open(path).with_context(|| {
+                format!("Failed to open {}", path.display())
+            })?;
+        Self::with_connection(conn)
+    }
+
+    /// Opens [`STATE_FILE_NAME`] in the data directory
+    pub fn open_default() -> Result<Self> {
+        Self::open(
+            AppDir::Data.ensure()?.join(STATE_FILE_NAME),
+        )
+    }
+
+    /// Opens a database that lives only as long as the store
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(
+        mut conn: Connection,
+    ) -> Result<Self> {
+        migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Number of migrations applied to the database
+    pub fn schema_version(&self) -> Result<usize> {
+        Ok(self.conn.query_row(
+            "PRAGMA user_version",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Runs `f` in a transaction, committed only if it succeeds
+    pub fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&Transaction<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let tx = self.conn.transaction()?;
+        let value = f(&tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// Mappings of one import source, as a [`MappingStore`]
+    pub fn source<'a>(
+        &'a mut self,
+        source: &'a str,
+    ) -> SourceMappings<'a> {
+        SourceMappings {
+            store: self,
+            source,
+        }
+    }
+
+    /// Sources and keys of the items mapped to `note_id`
+    pub fn items_of_note(
+        &self,
+        note_id: u64,
+    ) -> Result<Vec<(String, String)>> {
+        let mut statement = self.conn.prepare(
+            "SELECT source, key FROM mappings
+             WHERE note_id = ?1 ORDER BY source, key",
+        )?;
+        let items = statement
+            .query_map([note_id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(items)
+    }
+
+    /// Cached embedding of `text` by `model`
+    pub fn embedding(
+        &self,
+        model: &str,
+        text: &str,
+    ) -> Result<Option<Vec<f32>>> {
+        let blob: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT vector FROM embeddings
+                 WHERE model = ?1 AND text = ?2",
+                [model, text],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(blob.map(|blob| {
+            blob.as_chunks::<4>()
+                .0
+                .iter()
+                .map(|bytes| f32::from_le_bytes(*bytes))
+                .collect()
+        }))
+    }
+
+    /// Caches the embedding of `text` by `model`
+    pub fn put_embedding(
+        &mut self,
+        model: &str,
+        text: &str,
+        vector: &[f32],
+    ) -> Result<()> {
+        let blob: Vec<u8> = vector
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO embeddings
+             (model, text, vector, created_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![model, text, blob, now_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// Records the start of an import run of `source`
+    ///
+    /// # Returns
+    /// The run ID to pass to [`StateStore::finish_run`]
+    pub fn start_run(
+        &mut self,
+        source: &str,
+    ) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO import_runs (source, started_at)
+             VALUES (?1, ?2)",
+            params![source, now_millis()],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Records the end of run `run_id` with its counts
+    pub fn finish_run(
+        &mut self,
+        run_id: i64,
+        counts: RunCounts,
+    ) -> Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE import_runs SET finished_at = ?2,
+             created = ?3, updated = ?4, skipped = ?5,
+             repaired = ?6, failed = ?7
+             WHERE id = ?1",
+            params![
+                run_id,
+                now_millis(),
+                counts.created,
+                counts.updated,
+                counts.skipped,
+                counts.repaired,
+                counts.failed,
+            ],
+        )?;
+        anyhow::ensure!(
+            changed == 1,
+            "Unknown import run {}",
+            run_id
+        );
+        Ok(())
+    }
+
+    /// Runs of `source`, oldest first
+    pub fn runs(
+        &self,
+        source: &str,
+    ) -> Result<Vec<ImportRun>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, source, started_at, finished_at, created,
+             updated, skipped, repaired, failed
+             FROM import_runs WHERE source = ?1 ORDER BY id",
+        )?;
+        let runs = statement
+            .query_map([source], |row| {
+                Ok(ImportRun {
+                    id: row.get(0)?,
+                    source: row.get(1)?,
+                    started_at: from_millis(row.get(2)?),
+                    finished_at: row
+                        .get::<_, Option<i64>>(3)?
+                        .map(from_millis),
+                    counts: RunCounts {
+                        created: row.get(4)?,
+                        updated: row.get(5)?,
+                        skipped: row.get(6)?,
+                        repaired: row.get(7)?,
+                        failed: row.get(8)?,
+                    },
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(runs)
+    }
+}
+
+/// The mappings of one source in a [`StateStore`]
+#[derive(Debug)]
+pub struct SourceMappings<'a> {
+    store: &'a mut StateStore,
+    source: &'a str,
+}
+
+impl MappingStore for SourceMappings<'_> {
+    fn mapping(
+        &self,
+        key: &str,
+    ) -> Result<Option<ManifestEntry>> {
+        Ok(self
+            .store
+            .conn
+            .query_row(
+                "SELECT note_id, content_hash FROM mappings
+                 WHERE source = ?1 AND key = ?2",
+                [self.source, key],
+                |row| {
+                    Ok(ManifestEntry {
+                        note_id: row.get(0)?,
+                        content_hash: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn mappings(
+        &self,
+    ) -> Result<BTreeMap<String, ManifestEntry>> {
+        let mut statement = self.store.conn.prepare(
+            "SELECT key, note_id, content_hash FROM mappings
+             WHERE source = ?1",
+        )?;
+        let entries = statement
+            .query_map([self.source], |row| {
+                Ok((
+                    row.get(0)?,
+                    ManifestEntry {
+                        note_id: row.get(1)?,
+                        content_hash: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    fn save_mappings(
+        &mut self,
+        entries: Vec<(String, ManifestEntry)>,
+    ) -> Result<()> {
+        let source = self.source;
+        self.store.transaction(|tx| {
+            let mut statement = tx.prepare(
+                "INSERT OR REPLACE INTO mappings
+                 (source, key, note_id, content_hash, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let now = now_millis();
+            for (key, entry) in &entries {
+                statement.execute(params![
+                    source,
+                    key,
+                    entry.note_id,
+                    entry.content_hash,
+                    now,
+                ])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_mapping(
+        &mut self,
+        key: &str,
+    ) -> Result<bool> {
+        let removed = self.store.conn.execute(
+            "DELETE FROM mappings WHERE source = ?1 AND key = ?2",
+            [self.source, key],
+        )?;
+        Ok(removed > 0)
+    }
+}
+
+/// Applies the migrations not recorded in `user_version`, all in one
+/// transaction
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    let applied: usize =
+        tx.query_row("PRAGMA user_version", [], |row| {
+            row.get(0)
+        })?;
+    anyhow::ensure!(
+        applied <= MIGRATIONS.len(),
+        "State database has schema version {}, newer than this build supports ({})",
+        applied,
+        MIGRATIONS.len()
+    );
+    for (index, migration) in
+        MIGRATIONS.iter().enumerate().skip(applied)
+    {
+        tx.execute_batch(migration).with_context(|| {
+            format!(
+                "Failed to apply state migration {}",
+                index + 1
+            )
+        })?;
+    }
+    tx.pragma_update(
+        None,
+        "user_version",
+        MIGRATIONS.len(),
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn now_millis() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(note_id: u64, hash: &str) -> ManifestEntry {
+        ManifestEntry {
+            note_id,
+            content_hash: hash.to_string(),
+        }
+    }
+
+    fn table_names(store: &StateStore) -> Vec<String> {
+        let mut statement = store
+            .conn
+            .prepare(
+                "SELECT name FROM sqlite_master
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+                 ORDER BY name",
+            )
+            .unwrap();
+        statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let mut store =
+            StateStore::open_in_memory().unwrap();
+        assert_eq!(
+            store.schema_version().unwrap(),
+            MIGRATIONS.len()
+        );
+        assert_eq!(
+            table_names(&store),
+            ["embeddings", "import_runs", "mappings"]
+        );
+
+        migrate(&mut store.conn).unwrap();
+        assert_eq!(
+            store.schema_version().unwrap(),
+            MIGRATIONS.len()
+        );
+
+        store
+            .conn
+            .pragma_update(None, "user_version", 99)
+            .unwrap();
+        let error = migrate(&mut store.conn).unwrap_err();
+        assert!(error.to_string().contains("newer"));
+    }
+
+    #[test]
+    fn test_mapping_crud() {
+        let mut store =
+            StateStore::open_in_memory().unwrap();
+        let mut words = store.source("words.csv");
+        words
+            .save_mappings(vec![
+                ("1".to_string(), entry(100, "a")),
+                ("2".to_string(), entry(101, "b")),
+            ])
+            .unwrap();
+        assert_eq!(
+            words.mapping("1").unwrap(),
+            Some(entry(100, "a"))
+        );
+        words
+            .save_mappings(vec![(
+                "1".to_string(),
+                entry(100, "c"),
+            )])
+            .unwrap();
+        assert_eq!(
+            words.mappings().unwrap(),
+            BTreeMap::from([
+                ("1".to_string(), entry(100, "c")),
+                ("2".to_string(), entry(101, "b")),
+            ])
+        );
+        assert!(words.remove_mapping("2").unwrap());
+        assert!(!words.remove_mapping("2").unwrap());
+
+        let mut notes = store.source("notes.md");
+        assert_eq!(notes.mapping("1").unwrap(), None);
+        notes
+            .save_mappings(vec![(
+                "intro".to_string(),
+                entry(100, "d"),
+            )])
+            .unwrap();
+        assert_eq!(
+            store.items_of_note(100).unwrap(),
+            [
+                (
+                    "notes.md".to_string(),
+                    "intro".to_string()
+                ),
+                ("words.csv".to_string(), "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_writes_roll_back() {
+        let mut store =
+            StateStore::open_in_memory().unwrap();
+        // SQLite integers are signed, so the second row fails
+        // after the first was inserted
+        let error = store
+            .source("words.csv")
+            .save_mappings(vec![
+                ("1".to_string(), entry(100, "a")),
+                ("2".to_string(), entry(u64::MAX, "b")),
+            ])
+            .unwrap_err();
+        assert!(!error.to_string().is_empty());
+        assert!(
+            store
+                .source("words.csv")
+                .mappings()
+                .unwrap()
+                .is_empty()
+        );
+
+        let result: Result<()> = store.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO import_runs (source, started_at)
+                 VALUES ('words.csv', 0)",
+                [],
+            )?;
+            anyhow::bail!("simulated crash")
+        });
+        assert!(result.is_err());
+        assert!(
+            store.runs("words.csv").unwrap().is_empty()
+        );
+    }
+
+    #[test]
+    fn test_embeddings_and_runs() {
+        let mut store =
+            StateStore::open_in_memory().unwrap();
+        store
+            .put_embedding("embed-3", "猫", &[0.5, -1.25])
+            .unwrap();
+        assert_eq!(
+            store.embedding("embed-3", "猫").unwrap(),
+            Some(vec![0.5, -1.25])
+        );
+        assert_eq!(
+            store.embedding("embed-2", "猫").unwrap(),
+            None
+        );
+
+        let run = store.start_run("words.csv").unwrap();
+        let counts = RunCounts {
+            created: 3,
+            failed: 1,
+            ..RunCounts::default()
+        };
+        store.finish_run(run, counts).unwrap();
+        let runs = store.runs("words.csv").unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].counts, counts);
+        assert!(runs[0].finished_at.is_some());
+        assert!(store.finish_run(run + 1, counts).is_err());
+    }
+}
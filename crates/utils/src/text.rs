@@ -0,0 +1,207 @@
+//! Plain-text segmentation helpers shared by the other crates
+
+pub mod chunker;
+
+/// Full-width terminators that end a sentence on their own
+const CJK_TERMINATORS: [char; 5] =
+    ['。', '！', '？', '．', '…'];
+
+/// Latin terminators, which only end a sentence before whitespace
+const LATIN_TERMINATORS: [char; 3] = ['.', '!', '?'];
+
+/// Closing quotes and brackets that belong to the preceding sentence
+const CLOSERS: [char; 12] = [
+    '"', '\'', ')', ']', '”', '’', '」', '』', '）', '】',
+    '》', '〉',
+];
+
+/// Splits text into sentences.
+///
+/// Latin `.`, `!` and `?` end a sentence only when followed by
+/// whitespace (or the end), which keeps `3.14` and `e.g.x` intact;
+/// CJK `。！？` end a sentence immediately since CJK text has no
+/// spaces. Closing quotes after a terminator stay with the sentence,
+/// and a trailing fragment without terminator is returned as the
+/// last sentence. Returned slices are trimmed and never empty.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    sentence_spans(text)
+        .into_iter()
+        .map(|(start, end)| &text[start..end])
+        .collect()
+}
+
+/// Byte ranges of the sentences of [`split_sentences`]
+pub fn sentence_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((_, c)) = chars.next() {
+        let cjk = CJK_TERMINATORS.contains(&c);
+        if !cjk && !LATIN_TERMINATORS.contains(&c) {
+            continue;
+        }
+        // Swallow runs like "?!", "。」" or "..."
+        while let Some(&(_, next)) = chars.peek() {
+            if CJK_TERMINATORS.contains(&next)
+                || LATIN_TERMINATORS.contains(&next)
+                || CLOSERS.contains(&next)
+            {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let end =
+            chars.peek().map_or(text.len(), |&(i, _)| i);
+        let at_boundary = chars
+            .peek()
+            .is_none_or(|&(_, next)| next.is_whitespace());
+        if cjk || at_boundary {
+            push_trimmed(&mut spans, text, start, end);
+            start = end;
+        }
+    }
+    push_trimmed(&mut spans, text, start, text.len());
+    spans
+}
+
+fn push_trimmed(
+    spans: &mut Vec<(usize, usize)>,
+    text: &str,
+    start: usize,
+    end: usize,
+) {
+    let slice = &text[start..end];
+    let trimmed_start =
+        start + (slice.len() - slice.trim_start().len());
+    let trimmed_end = start + slice.trim_end().len();
+    if trimmed_start < trimmed_end {
+        spans.push((trimmed_start, trimmed_end));
+    }
+}
+
+/// Byte ranges of the paragraphs of [`split_paragraphs`]
+pub fn paragraph_spans(text: &str) -> Vec<(usize, usize)> {
+    split_paragraphs(text)
+        .into_iter()
+        .map(|paragraph| {
+            let start = paragraph.as_ptr() as usize
+                - text.as_ptr() as usize;
+            (start, start + paragraph.len())
+        })
+        .collect()
+}
+
+/// Splits text into paragraphs separated by blank lines.
+///
+/// Returned slices are trimmed and never empty.
+pub fn split_paragraphs(text: &str) -> Vec<&str> {
+    let mut paragraphs = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    let mut previous_blank = false;
+
+    for line in text.split_inclusive('\n') {
+        let blank = line.trim().is_empty();
+        if blank && !previous_blank {
+            let paragraph = text[start..offset].trim();
+            if !paragraph.is_empty() {
+                paragraphs.push(paragraph);
+            }
+        }
+        offset += line.len();
+        if blank {
+            start = offset;
+        }
+        previous_blank = blank;
+    }
+    let last = text[start..].trim();
+    if !last.is_empty() {
+        paragraphs.push(last);
+    }
+    paragraphs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latin_sentences() {
+        assert_eq!(
+            split_sentences(
+                "Hello there. Pi is 3.14! Really?  Yes"
+            ),
+            vec![
+                "Hello there.",
+                "Pi is 3.14!",
+                "Really?",
+                "Yes"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quotes_stay_with_sentence() {
+        assert_eq!(
+            split_sentences(
+                "He said \"stop.\" Then left..."
+            ),
+            vec!["He said \"stop.\"", "Then left..."]
+        );
+    }
+
+    #[test]
+    fn test_cjk_sentences_without_spaces() {
+        assert_eq!(
+            split_sentences(
+                "今日は晴れです。「本当？」と聞いた！終わり"
+            ),
+            vec![
+                "今日は晴れです。",
+                "「本当？」",
+                "と聞いた！",
+                "終わり"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mixed_and_empty() {
+        assert!(split_sentences("").is_empty());
+        assert!(split_sentences("   \n ").is_empty());
+        assert_eq!(
+            split_sentences("我爱Anki。It works."),
+            vec!["我爱Anki。", "It works."]
+        );
+    }
+
+    #[test]
+    fn test_sentence_spans_are_byte_offsets() {
+        let text = "中文。 Next";
+        let spans = sentence_spans(text);
+        assert_eq!(spans, vec![(0, 9), (10, 14)]);
+    }
+
+    #[test]
+    fn test_paragraph_spans_are_byte_offsets() {
+        let text = "一段。\n\n  second\n";
+        let spans = paragraph_spans(text);
+        assert_eq!(spans, vec![(0, 9), (13, 19)]);
+        assert_eq!(&text[13..19], "second");
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let text = "\nfirst line\nstill first\n\n  \n\nsecond\r\n\r\nthird\n";
+        assert_eq!(
+            split_paragraphs(text),
+            vec![
+                "first line\nstill first",
+                "second",
+                "third"
+            ]
+        );
+    }
+}
@@ -0,0 +1,457 @@
+//! Token-bounded, overlapping chunks of long text.
+//!
+//! Chunks end at the configured boundary where possible. A paragraph
+//! that does not fit falls back to its sentences, and a sentence that
+//! does not fit (or text with no punctuation at all) is cut between
+//! grapheme clusters, marking the chunk as [`Chunk::hard_cut`]. Token
+//! counts come from the caller, so any tokenizer or estimate can be
+//! plugged in.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::{paragraph_spans, sentence_spans};
+
+/// Preferred place to end a chunk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Boundary {
+    /// After a sentence, see [`super::split_sentences`]
+    #[default]
+    Sentence,
+    /// After a paragraph, falling back to sentences
+    Paragraph,
+    /// After any grapheme cluster
+    Char,
+}
+
+/// Limits for [`Chunker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    /// Most tokens in one chunk, unless a single grapheme is larger
+    pub max_tokens: usize,
+    /// Most tokens repeated from the end of the previous chunk
+    pub overlap_tokens: usize,
+    /// Where chunks should end
+    pub boundary: Boundary,
+}
+
+/// One piece of the split text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    /// `&original[start_offset..end_offset]`
+    pub text: &'a str,
+    /// Byte offset of the first byte in the original text
+    pub start_offset: usize,
+    /// Byte offset just past the last byte in the original text
+    pub end_offset: usize,
+    /// The chunk starts or ends inside a sentence because no
+    /// boundary was close enough
+    pub hard_cut: bool,
+}
+
+/// Splits text into chunks of at most `max_tokens` tokens
+pub struct Chunker<C> {
+    counter: C,
+    config: ChunkConfig,
+}
+
+/// Smallest piece a chunk is built from, with whether its edges are
+/// sentence or paragraph boundaries
+#[derive(Debug, Clone, Copy)]
+struct Unit {
+    start: usize,
+    end: usize,
+    clean_start: bool,
+    clean_end: bool,
+}
+
+impl<C: Fn(&str) -> usize> Chunker<C> {
+    /// Creates a chunker counting tokens with `counter`
+    pub fn new(counter: C, config: ChunkConfig) -> Self {
+        Self { counter, config }
+    }
+
+    /// Splits `text` into chunks in document order.
+    ///
+    /// Each chunk holds as many whole units as fit; the next one
+    /// starts with the trailing units of the previous chunk that fit
+    /// into `overlap_tokens`, but always at least one unit further.
+    /// Leading and trailing whitespace is not part of any chunk.
+    pub fn split<'a>(
+        &self,
+        text: &'a str,
+    ) -> Vec<Chunk<'a>> {
+        let units = self.units(text);
+        let count = |first: usize, last: usize| {
+            (self.counter)(
+                &text[units[first].start..units[last].end],
+            )
+        };
+
+        let mut chunks = Vec::new();
+        let mut first = 0;
+        while first < units.len() {
+            // Longest run from `first` that fits, assuming counts
+            // grow with the text
+            let (mut low, mut high) =
+                (first, units.len() - 1);
+            while low < high {
+                let mid = (low + high).div_ceil(2);
+                if count(first, mid)
+                    <= self.config.max_tokens
+                {
+                    low = mid;
+                } else {
+                    high = mid - 1;
+                }
+            }
+            // Rather end at an earlier sentence than mid-sentence
+            let last = if units[low].clean_end {
+                low
+            } else {
+                (first..low)
+                    .rev()
+                    .find(|&i| units[i].clean_end)
+                    .unwrap_or(low)
+            };
+            let (start, end) =
+                (units[first].start, units[last].end);
+            chunks.push(Chunk {
+                text: &text[start..end],
+                start_offset: start,
+                end_offset: end,
+                hard_cut: !units[first].clean_start
+                    || !units[last].clean_end,
+            });
+            if last + 1 == units.len() {
+                break;
+            }
+
+            let mut next = last + 1;
+            while next > first + 1
+                && count(next - 1, last)
+                    <= self.config.overlap_tokens
+            {
+                next -= 1;
+            }
+            first = next;
+        }
+        chunks
+    }
+
+    fn units(&self, text: &str) -> Vec<Unit> {
+        let mut units = Vec::new();
+        match self.config.boundary {
+            Boundary::Char => {
+                push_graphemes(
+                    &mut units,
+                    text,
+                    (0, text.len()),
+                );
+                for unit in &mut units {
+                    unit.clean_start = true;
+                    unit.clean_end = true;
+                }
+            }
+            Boundary::Sentence => {
+                for span in sentence_spans(text) {
+                    self.push_sentence(
+                        &mut units, text, span,
+                    );
+                }
+            }
+            Boundary::Paragraph => {
+                for span in paragraph_spans(text) {
+                    if self.fits(text, span) {
+                        units.push(clean(span));
+                        continue;
+                    }
+                    let (offset, _) = span;
+                    for (start, end) in sentence_spans(
+                        &text[span.0..span.1],
+                    ) {
+                        self.push_sentence(
+                            &mut units,
+                            text,
+                            (offset + start, offset + end),
+                        );
+                    }
+                }
+            }
+        }
+        units
+    }
+
+    fn push_sentence(
+        &self,
+        units: &mut Vec<Unit>,
+        text: &str,
+        span: (usize, usize),
+    ) {
+        if self.fits(text, span) {
+            units.push(clean(span));
+        } else {
+            push_graphemes(units, text, span);
+        }
+    }
+
+    fn fits(
+        &self,
+        text: &str,
+        (start, end): (usize, usize),
+    ) -> bool {
+        (self.counter)(&text[start..end])
+            <= self.config.max_tokens
+    }
+}
+
+fn clean((start, end): (usize, usize)) -> Unit {
+    Unit {
+        start,
+        end,
+        clean_start: true,
+        clean_end: true,
+    }
+}
+
+/// One unit per non-whitespace grapheme of the span; only the span's
+/// own edges count as clean
+fn push_graphemes(
+    units: &mut Vec<Unit>,
+    text: &str,
+    (start, end): (usize, usize),
+) {
+    let first = units.len();
+    units.extend(
+        text[start..end]
+            .grapheme_indices(true)
+            .filter(|(_, g)| !g.trim().is_empty())
+            .map(|(offset, g)| Unit {
+                start: start + offset,
+                end: start + offset + g.len(),
+                clean_start: false,
+                clean_end: false,
+            }),
+    );
+    if let Some(unit) = units.get_mut(first) {
+        unit.clean_start = true;
+    }
+    if units.len() > first
+        && let Some(unit) = units.last_mut()
+    {
+        unit.clean_end = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chars(text: &str) -> usize {
+        text.chars().count()
+    }
+
+    fn chunker(
+        max_tokens: usize,
+        overlap_tokens: usize,
+        boundary: Boundary,
+    ) -> Chunker<fn(&str) -> usize> {
+        Chunker::new(
+            chars,
+            ChunkConfig {
+                max_tokens,
+                overlap_tokens,
+                boundary,
+            },
+        )
+    }
+
+    fn texts<'a>(chunks: &[Chunk<'a>]) -> Vec<&'a str> {
+        chunks.iter().map(|chunk| chunk.text).collect()
+    }
+
+    /// Offsets match the text and chunks move forward
+    fn assert_consistent(text: &str, chunks: &[Chunk<'_>]) {
+        for chunk in chunks {
+            assert_eq!(
+                &text[chunk.start_offset..chunk.end_offset],
+                chunk.text
+            );
+        }
+        for pair in chunks.windows(2) {
+            assert!(
+                pair[0].start_offset < pair[1].start_offset
+            );
+            assert!(
+                pair[0].end_offset < pair[1].end_offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_input() {
+        for boundary in [
+            Boundary::Sentence,
+            Boundary::Paragraph,
+            Boundary::Char,
+        ] {
+            let chunker = chunker(10, 2, boundary);
+            assert!(chunker.split("").is_empty());
+            assert!(chunker.split(" \n\n\t").is_empty());
+        }
+    }
+
+    #[test]
+    fn test_sentences_fill_chunks() {
+        let text = "Aa. Bb. Cc. Dd. Ee.";
+        let chunks =
+            chunker(7, 0, Boundary::Sentence).split(text);
+        assert_eq!(
+            texts(&chunks),
+            ["Aa. Bb.", "Cc. Dd.", "Ee."]
+        );
+        assert!(chunks.iter().all(|chunk| !chunk.hard_cut));
+        assert_consistent(text, &chunks);
+        assert_eq!(chunks[1].start_offset, 8);
+    }
+
+    #[test]
+    fn test_overlap_repeats_trailing_sentences() {
+        let text = "Aa. Bb. Cc. Dd. Ee.";
+        let chunks =
+            chunker(11, 3, Boundary::Sentence).split(text);
+        assert_eq!(
+            texts(&chunks),
+            ["Aa. Bb. Cc.", "Cc. Dd. Ee."]
+        );
+        assert_consistent(text, &chunks);
+
+        // Overlap never exceeds its budget...
+        let chunks =
+            chunker(11, 6, Boundary::Sentence).split(text);
+        for pair in chunks.windows(2) {
+            let shared = &text
+                [pair[1].start_offset..pair[0].end_offset];
+            assert!(chars(shared) <= 6, "{:?}", shared);
+        }
+        // ...and an overlap as large as a chunk still advances
+        let chunks =
+            chunker(7, 7, Boundary::Sentence).split(text);
+        assert_eq!(
+            texts(&chunks),
+            ["Aa. Bb.", "Bb. Cc.", "Cc. Dd.", "Dd. Ee."]
+        );
+        assert_consistent(text, &chunks);
+    }
+
+    #[test]
+    fn test_cjk_punctuation() {
+        let text = "今日は晴れ。明日は雨！本当？";
+        let chunks =
+            chunker(11, 0, Boundary::Sentence).split(text);
+        assert_eq!(
+            texts(&chunks),
+            ["今日は晴れ。明日は雨！", "本当？"]
+        );
+        assert!(chunks.iter().all(|chunk| !chunk.hard_cut));
+        assert_eq!(chunks[1].start_offset, 33);
+    }
+
+    #[test]
+    fn test_blob_without_punctuation_is_hard_cut() {
+        let text = "a".repeat(25);
+        let chunks =
+            chunker(10, 0, Boundary::Sentence).split(&text);
+        let lengths: Vec<usize> = chunks
+            .iter()
+            .map(|chunk| chunk.text.len())
+            .collect();
+        assert_eq!(lengths, [10, 10, 5]);
+        assert!(chunks.iter().all(|chunk| chunk.hard_cut));
+
+        let chunks = chunker(10, 2, Boundary::Paragraph)
+            .split(&text);
+        let offsets: Vec<(usize, usize)> = chunks
+            .iter()
+            .map(|chunk| {
+                (chunk.start_offset, chunk.end_offset)
+            })
+            .collect();
+        assert_eq!(offsets, [(0, 10), (8, 18), (16, 25)]);
+        assert_consistent(&text, &chunks);
+    }
+
+    #[test]
+    fn test_cuts_keep_graphemes_whole() {
+        // "é" written as e + combining acute: 2 chars, 3 bytes
+        let text = "e\u{301}".repeat(6);
+        let chunks =
+            chunker(5, 0, Boundary::Char).split(&text);
+        assert_eq!(chunks.len(), 3);
+        for chunk in &chunks {
+            assert_eq!(chunk.text, "e\u{301}e\u{301}");
+            assert!(!chunk.hard_cut);
+        }
+        assert_consistent(&text, &chunks);
+
+        // Multi-byte characters are never split
+        let text = "猫".repeat(7);
+        let chunks =
+            chunker(3, 1, Boundary::Sentence).split(&text);
+        for chunk in &chunks {
+            assert!(
+                text.is_char_boundary(chunk.start_offset)
+            );
+            assert!(
+                text.is_char_boundary(chunk.end_offset)
+            );
+        }
+        assert_eq!(
+            texts(&chunks),
+            ["猫猫猫", "猫猫猫", "猫猫猫"]
+        );
+    }
+
+    #[test]
+    fn test_mixed_language_text() {
+        let text = "日本語の文です。An English sentence here. 中文句子！";
+        let whole =
+            chunker(100, 0, Boundary::Sentence).split(text);
+        assert_eq!(texts(&whole), [text]);
+        assert!(!whole[0].hard_cut);
+
+        let chunks =
+            chunker(12, 0, Boundary::Sentence).split(text);
+        assert_consistent(text, &chunks);
+        // The Japanese sentence is not topped up with part of the
+        // English one
+        assert_eq!(chunks[0].text, "日本語の文です。");
+        assert!(!chunks[0].hard_cut);
+        // The long English sentence is cut, without loose spaces
+        assert!(chunks[1].hard_cut);
+        assert!(chunks[1].text.starts_with("An "));
+        assert!(chunks.iter().all(|chunk| {
+            chunk.text.trim() == chunk.text
+                && chars(chunk.text) <= 12
+        }));
+        assert!(
+            chunks
+                .last()
+                .unwrap()
+                .text
+                .ends_with("中文句子！")
+        );
+    }
+
+    #[test]
+    fn test_paragraphs_fall_back_to_sentences() {
+        let text = "Alpha.\n\nBeta.\n\nOne. Two. Three.";
+        let chunks =
+            chunker(13, 0, Boundary::Paragraph).split(text);
+        assert_eq!(
+            texts(&chunks),
+            ["Alpha.\n\nBeta.", "One. Two.", "Three."]
+        );
+        assert!(chunks.iter().all(|chunk| !chunk.hard_cut));
+        assert_consistent(text, &chunks);
+    }
+}
@@ -1,12 +1,23 @@
 use std::io::Write;
 use std::sync::Once;
 
+use crate::config::env::EnvConfig;
+
 static INIT: Once = Once::new();
-/// because logger can only init once, so we use Once to ensure it
+/// because logger can only init once, so we use Once to ensure it.
+/// `RUST_LOG` may also come from `.env` or `.env.local`, which are
+/// read without touching the process environment
 pub fn init_logger() {
     INIT.call_once(|| {
-        dotenvy::dotenv().ok();
-        env_logger::Builder::from_default_env()
+        let mut builder =
+            env_logger::Builder::from_default_env();
+        if let Some(filter) = EnvConfig::load()
+            .ok()
+            .and_then(|env| env.rust_log)
+        {
+            builder.parse_filters(&filter);
+        }
+        builder
             .format(|buf, record| {
                 writeln!(
                     buf,
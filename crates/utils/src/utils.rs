@@ -4,6 +4,7 @@ pub fn print1() -> anyhow::Result<()> {
 }
 #[cfg(test)]
 mod test {
+    #[allow(unused_imports)]
     use super::*;
 
     #[tokio::test]